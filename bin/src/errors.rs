@@ -7,4 +7,7 @@ pub enum MediaServerError {
     MediaResError = 0x00020004,
     NotImplemented = 0x00020005,
     NodeTimeout = 0x00020006,
+    InvalidMigrateDest = 0x00020007,
+    RouteCancelled = 0x00020008,
+    RegionNotAllowed = 0x00020009,
 }
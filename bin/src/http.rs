@@ -19,7 +19,9 @@ use serde::Deserialize;
 use tokio::sync::mpsc::Sender;
 #[cfg(feature = "embed_static")]
 use utils::EmbeddedFilesEndpoint;
+pub use utils::{IceServersConfig, ReconnectLimitConfig};
 
+mod api_admin;
 mod api_console;
 mod api_media;
 mod api_metrics;
@@ -133,13 +135,22 @@ pub async fn run_console_http_server(
 }
 
 #[cfg(feature = "gateway")]
+#[allow(clippy::too_many_arguments)]
 pub async fn run_gateway_http_server<ES: 'static + MediaEdgeSecure + Send + Sync, GS: 'static + MediaGatewaySecure + Send + Sync>(
     port: u16,
     node: NodeApiCtx,
     sender: Sender<crate::rpc::Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
     edge_secure: Arc<ES>,
     gateway_secure: Arc<GS>,
+    admin_secret: String,
+    ice_servers: IceServersConfig,
+    reconnect_limit: ReconnectLimitConfig,
+    whip_connect_timeout_ms: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let admin_service: OpenApiService<_, ()> = OpenApiService::new(api_admin::AdminApis::new(sender.clone()), "Admin APIs", env!("CARGO_PKG_VERSION")).server("/admin/");
+    let admin_ui = admin_service.swagger_ui();
+    let admin_spec = admin_service.spec();
+
     let token_service: OpenApiService<_, ()> = OpenApiService::new(api_token::TokenApis::<GS>::new(), "App APIs", env!("CARGO_PKG_VERSION")).server("/token/");
     let token_ui = token_service.swagger_ui();
     let token_spec = token_service.spec();
@@ -153,8 +164,10 @@ pub async fn run_gateway_http_server<ES: 'static + MediaEdgeSecure + Send + Sync
     let metrics_ui = metrics_service.swagger_ui();
     let metrics_spec = metrics_service.spec();
 
+    // connects of webrtc, whip and whep are counted together for each client
+    let reconnect = Arc::new(utils::ReconnectLimiter::new(reconnect_limit));
     let webrtc_service: OpenApiService<_, ()> = OpenApiService::new(
-        api_media::WebrtcApis::<ES>::new(sender.clone(), edge_secure.clone()),
+        api_media::WebrtcApis::<ES>::new(sender.clone(), edge_secure.clone(), reconnect.clone()),
         "Media Webrtc Gateway APIs",
         env!("CARGO_PKG_VERSION"),
     )
@@ -163,7 +176,7 @@ pub async fn run_gateway_http_server<ES: 'static + MediaEdgeSecure + Send + Sync
     let webrtc_spec = webrtc_service.spec();
 
     let whip_service: OpenApiService<_, ()> = OpenApiService::new(
-        api_media::WhipApis::<ES>::new(sender.clone(), edge_secure.clone()),
+        api_media::WhipApis::<ES>::new(sender.clone(), edge_secure.clone(), ice_servers.clone(), reconnect.clone(), whip_connect_timeout_ms),
         "Media Whip Gateway APIs",
         env!("CARGO_PKG_VERSION"),
     )
@@ -172,7 +185,7 @@ pub async fn run_gateway_http_server<ES: 'static + MediaEdgeSecure + Send + Sync
     let whip_spec = whip_service.spec();
 
     let whep_service: OpenApiService<_, ()> = OpenApiService::new(
-        api_media::WhepApis::<ES>::new(sender.clone(), edge_secure.clone()),
+        api_media::WhepApis::<ES>::new(sender.clone(), edge_secure.clone(), ice_servers, reconnect),
         "Media Whep Gateway APIs",
         env!("CARGO_PKG_VERSION"),
     )
@@ -204,6 +217,10 @@ pub async fn run_gateway_http_server<ES: 'static + MediaEdgeSecure + Send + Sync
         .nest("/token/", token_service.data(api_token::TokenServerCtx { secure: gateway_secure }))
         .nest("/token/ui", token_ui)
         .at("/token/spec", poem::endpoint::make_sync(move |_| token_spec.clone()))
+        //admin
+        .nest("/admin/", admin_service.data(api_admin::AdminApisCtx { secret: admin_secret }))
+        .nest("/admin/ui", admin_ui)
+        .at("/admin/spec", poem::endpoint::make_sync(move |_| admin_spec.clone()))
         //metrics
         .nest("/api/metrics/", metrics_service)
         .nest("/api/metrics/ui", metrics_ui)
@@ -217,6 +234,7 @@ pub async fn run_gateway_http_server<ES: 'static + MediaEdgeSecure + Send + Sync
         .nest("/whip/ui", whip_ui)
         .at("/whip/spec", poem::endpoint::make_sync(move |_| whip_spec.clone()))
         //whep
+        .at("/whep/conn/:conn_id/events", poem::get(api_media::whep_conn_events).data(sender.clone()))
         .nest("/whep/", whep_service)
         .nest("/whep/ui", whep_ui)
         .at("/whep/spec", poem::endpoint::make_sync(move |_| whep_spec.clone()))
@@ -260,12 +278,16 @@ pub async fn run_connector_http_server(port: u16, node: NodeApiCtx) -> Result<()
 }
 
 #[cfg(feature = "media")]
+#[allow(clippy::too_many_arguments)]
 pub async fn run_media_http_server<ES: 'static + MediaEdgeSecure + Send + Sync, GS: 'static + MediaGatewaySecure + Send + Sync>(
     port: u16,
     node: NodeApiCtx,
     sender: Sender<crate::rpc::Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
     edge_secure: Arc<ES>,
     gateway_secure: Option<Arc<GS>>,
+    ice_servers: IceServersConfig,
+    reconnect_limit: ReconnectLimitConfig,
+    whip_connect_timeout_ms: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut route = Route::new();
 
@@ -288,8 +310,10 @@ pub async fn run_media_http_server<ES: 'static + MediaEdgeSecure + Send + Sync,
             .at("/token/spec", poem::endpoint::make_sync(move |_| token_spec.clone()));
     }
 
+    // connects of webrtc, whip and whep are counted together for each client
+    let reconnect = Arc::new(utils::ReconnectLimiter::new(reconnect_limit));
     let webrtc_service: OpenApiService<_, ()> = OpenApiService::new(
-        api_media::WebrtcApis::<ES>::new(sender.clone(), edge_secure.clone()),
+        api_media::WebrtcApis::<ES>::new(sender.clone(), edge_secure.clone(), reconnect.clone()),
         "Media Webrtc Gateway APIs",
         env!("CARGO_PKG_VERSION"),
     )
@@ -298,7 +322,7 @@ pub async fn run_media_http_server<ES: 'static + MediaEdgeSecure + Send + Sync,
     let webrtc_spec = webrtc_service.spec();
 
     let whip_service: OpenApiService<_, ()> = OpenApiService::new(
-        api_media::WhipApis::<ES>::new(sender.clone(), edge_secure.clone()),
+        api_media::WhipApis::<ES>::new(sender.clone(), edge_secure.clone(), ice_servers.clone(), reconnect.clone(), whip_connect_timeout_ms),
         "Media Whip Gateway APIs",
         env!("CARGO_PKG_VERSION"),
     )
@@ -307,7 +331,7 @@ pub async fn run_media_http_server<ES: 'static + MediaEdgeSecure + Send + Sync,
     let whip_spec = whip_service.spec();
 
     let whep_service: OpenApiService<_, ()> = OpenApiService::new(
-        api_media::WhepApis::<ES>::new(sender.clone(), edge_secure.clone()),
+        api_media::WhepApis::<ES>::new(sender.clone(), edge_secure.clone(), ice_servers, reconnect),
         "Media Whep Gateway APIs",
         env!("CARGO_PKG_VERSION"),
     )
@@ -348,6 +372,7 @@ pub async fn run_media_http_server<ES: 'static + MediaEdgeSecure + Send + Sync,
         .nest("/whip/ui", whip_ui)
         .at("/whip/spec", poem::endpoint::make_sync(move |_| whip_spec.clone()))
         //whep
+        .at("/whep/conn/:conn_id/events", poem::get(api_media::whep_conn_events).data(sender.clone()))
         .nest("/whep/", whep_service)
         .nest("/whep/ui", whep_ui)
         .at("/whep/spec", poem::endpoint::make_sync(move |_| whep_spec.clone()))
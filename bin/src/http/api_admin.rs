@@ -0,0 +1,442 @@
+use media_server_protocol::{
+    endpoint::{ClusterConnId, TrackInfo},
+    multi_tenancy::{AppContext, AppId},
+    transport::{
+        admin::{self, CloseSessionsReq, CloseSessionsRes, PendingRoute, RoomTracksReq},
+        webrtc::{self, SessionDump},
+        RpcReq, RpcRes,
+    },
+};
+use poem::Request;
+use poem_openapi::{auth::Bearer, payload::Json, OpenApi, SecurityScheme};
+use subtle::ConstantTimeEq;
+
+use crate::rpc::Rpc;
+
+use super::Response;
+
+#[derive(Clone)]
+pub struct AdminApisCtx {
+    pub(crate) secret: String,
+}
+
+/// Admin authorization, token must be the cluster secret
+#[derive(SecurityScheme)]
+#[oai(rename = "Admin Authorization", ty = "bearer", key_in = "header", key_name = "Authorization", checker = "admin_checker")]
+struct AdminAuthorization(());
+
+async fn admin_checker(req: &Request, bearer: Bearer) -> Option<()> {
+    let data = req.data::<AdminApisCtx>()?;
+    // constant time, so the secret can't be guessed byte by byte from response times
+    bool::from(data.secret.as_bytes().ct_eq(bearer.token.as_bytes())).then_some(())
+}
+
+#[derive(poem_openapi::Object)]
+struct CloseRoomReq {
+    app: Option<String>,
+    room: String,
+}
+
+#[derive(poem_openapi::Object)]
+struct RoomTracksQuery {
+    app: Option<String>,
+    room: String,
+}
+
+#[derive(poem_openapi::Object)]
+struct RoomTrackInfo {
+    peer: String,
+    track: String,
+    kind: String,
+    metadata: Option<String>,
+    muted: bool,
+}
+
+impl From<TrackInfo> for RoomTrackInfo {
+    fn from(value: TrackInfo) -> Self {
+        Self {
+            peer: value.peer.to_string(),
+            track: value.track.to_string(),
+            kind: value.meta.kind.to_string(),
+            metadata: value.meta.metadata,
+            muted: value.meta.muted,
+        }
+    }
+}
+
+#[derive(poem_openapi::Object)]
+struct CloseAppReq {
+    app: Option<String>,
+}
+
+#[derive(poem_openapi::Object)]
+struct MigrateSessionReq {
+    conn_id: String,
+    /// App of the session, root app if not provided
+    app: Option<String>,
+    /// Destination node, selected by gateway inside data residency zones of the app if not provided
+    dest_node: Option<u32>,
+}
+
+#[derive(poem_openapi::Object)]
+struct MigrateSessionInfo {
+    /// Conn id which is sent to client for restart-ice to the destination node
+    conn_id: String,
+}
+
+#[derive(poem_openapi::Object)]
+struct SessionDumpReq {
+    conn_id: String,
+}
+
+#[derive(poem_openapi::Object)]
+struct SessionDumpInfo {
+    /// Offer and answer of the last negotiation, ICE passwords are redacted
+    offer: String,
+    answer: String,
+    ice_state: String,
+    local_candidates: Vec<String>,
+    remote_candidates: Vec<String>,
+    /// Nominated candidate pair, empty before ICE connected
+    selected_pair: Option<String>,
+    codecs: Vec<String>,
+    transport_state: String,
+    /// Authenticated RTP packets which are dropped as malformed or oversized
+    bad_rtp_packets: u64,
+}
+
+impl From<SessionDump> for SessionDumpInfo {
+    fn from(value: SessionDump) -> Self {
+        Self {
+            offer: value.offer,
+            answer: value.answer,
+            ice_state: value.ice_state,
+            local_candidates: value.local_candidates,
+            remote_candidates: value.remote_candidates,
+            selected_pair: value.selected_pair,
+            codecs: value.codecs,
+            transport_state: value.transport_state,
+            bad_rtp_packets: value.bad_rtp_packets,
+        }
+    }
+}
+
+#[derive(poem_openapi::Object)]
+struct PendingRouteInfo {
+    session_id: u64,
+    app: String,
+    kind: String,
+    /// Node which the gateway is waiting for, empty if it is still selecting
+    dest_node: Option<u32>,
+    elapsed_ms: u64,
+}
+
+impl From<PendingRoute> for PendingRouteInfo {
+    fn from(value: PendingRoute) -> Self {
+        Self {
+            session_id: value.session_id,
+            app: value.app.to_string(),
+            kind: value.kind,
+            dest_node: value.dest_node,
+            elapsed_ms: value.elapsed_ms,
+        }
+    }
+}
+
+#[derive(poem_openapi::Object)]
+struct CancelRouteReq {
+    session_id: u64,
+}
+
+#[derive(poem_openapi::Object)]
+struct NodeCloseInfo {
+    node: u32,
+    closed: Option<u32>,
+    error: Option<String>,
+}
+
+#[derive(poem_openapi::Object)]
+struct CloseSessionsInfo {
+    closed: u32,
+    nodes: Vec<NodeCloseInfo>,
+}
+
+impl From<CloseSessionsRes> for CloseSessionsInfo {
+    fn from(value: CloseSessionsRes) -> Self {
+        Self {
+            closed: value.closed(),
+            nodes: value
+                .nodes
+                .into_iter()
+                .map(|n| match n.closed {
+                    Ok(closed) => NodeCloseInfo {
+                        node: n.node,
+                        closed: Some(closed),
+                        error: None,
+                    },
+                    Err(e) => NodeCloseInfo {
+                        node: n.node,
+                        closed: None,
+                        error: Some(e.to_string()),
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+pub struct AdminApis {
+    sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
+}
+
+impl AdminApis {
+    pub fn new(sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>) -> Self {
+        Self { sender }
+    }
+
+    async fn close_sessions(&self, req: CloseSessionsReq) -> Json<Response<CloseSessionsInfo>> {
+        log::info!("[AdminAPIs] close sessions of {} room {:?}", req.app, req.room);
+        let (req, rx) = Rpc::new(RpcReq::Admin(admin::RpcReq::CloseSessions(req)));
+        if self.sender.send(req).await.is_err() {
+            return Json(Response {
+                status: false,
+                error: Some("INTERNAL_QUEUE_ERROR".to_string()),
+                ..Default::default()
+            });
+        }
+        match rx.await {
+            Ok(RpcRes::Admin(admin::RpcRes::CloseSessions(Ok(res)))) => Json(Response {
+                status: true,
+                data: Some(res.into()),
+                ..Default::default()
+            }),
+            Ok(RpcRes::Admin(admin::RpcRes::CloseSessions(Err(e)))) => Json(Response {
+                status: false,
+                error: Some(e.to_string()),
+                ..Default::default()
+            }),
+            _ => Json(Response {
+                status: false,
+                error: Some("INTERNAL_ERROR".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+fn app_ctx(app: Option<String>) -> AppContext {
+    AppContext {
+        app: app.map(AppId::from).unwrap_or_else(AppId::root_app),
+    }
+}
+
+#[OpenApi]
+impl AdminApis {
+    /// close all sessions inside a room, on all nodes
+    #[oai(path = "/room/close", method = "post")]
+    async fn close_room(&self, _auth: AdminAuthorization, body: Json<CloseRoomReq>) -> Json<Response<CloseSessionsInfo>> {
+        let body = body.0;
+        self.close_sessions(CloseSessionsReq {
+            app: app_ctx(body.app),
+            room: Some(body.room.into()),
+            migrated_session: None,
+        })
+        .await
+    }
+
+    /// close all sessions of an app, on all nodes
+    #[oai(path = "/app/close", method = "post")]
+    async fn close_app(&self, _auth: AdminAuthorization, body: Json<CloseAppReq>) -> Json<Response<CloseSessionsInfo>> {
+        self.close_sessions(CloseSessionsReq {
+            app: app_ctx(body.0.app),
+            room: None,
+            migrated_session: None,
+        })
+        .await
+    }
+
+    /// list all tracks which are published in a room, over all nodes. This is read-only and doesn't affect the room
+    #[oai(path = "/room/tracks", method = "post")]
+    async fn room_tracks(&self, _auth: AdminAuthorization, body: Json<RoomTracksQuery>) -> Json<Response<Vec<RoomTrackInfo>>> {
+        let body = body.0;
+        log::info!("[AdminAPIs] list tracks of {:?} room {}", body.app, body.room);
+        let (req, rx) = Rpc::new(RpcReq::Admin(admin::RpcReq::RoomTracks(RoomTracksReq {
+            app: app_ctx(body.app),
+            room: body.room.into(),
+        })));
+        if self.sender.send(req).await.is_err() {
+            return Json(Response {
+                status: false,
+                error: Some("INTERNAL_QUEUE_ERROR".to_string()),
+                ..Default::default()
+            });
+        }
+        match rx.await {
+            Ok(RpcRes::Admin(admin::RpcRes::RoomTracks(Ok(tracks)))) => Json(Response {
+                status: true,
+                data: Some(tracks.into_iter().map(RoomTrackInfo::from).collect()),
+                ..Default::default()
+            }),
+            Ok(RpcRes::Admin(admin::RpcRes::RoomTracks(Err(e)))) => Json(Response {
+                status: false,
+                error: Some(e.to_string()),
+                ..Default::default()
+            }),
+            _ => Json(Response {
+                status: false,
+                error: Some("INTERNAL_ERROR".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// list in-flight routes of this gateway which are still waiting for the destination node, the longest waiting first
+    #[oai(path = "/route/pending", method = "get")]
+    async fn pending_routes(&self, _auth: AdminAuthorization) -> Json<Response<Vec<PendingRouteInfo>>> {
+        let (req, rx) = Rpc::new(RpcReq::Admin(admin::RpcReq::PendingRoutes));
+        if self.sender.send(req).await.is_err() {
+            return Json(Response {
+                status: false,
+                error: Some("INTERNAL_QUEUE_ERROR".to_string()),
+                ..Default::default()
+            });
+        }
+        match rx.await {
+            Ok(RpcRes::Admin(admin::RpcRes::PendingRoutes(Ok(routes)))) => Json(Response {
+                status: true,
+                data: Some(routes.into_iter().map(PendingRouteInfo::from).collect()),
+                ..Default::default()
+            }),
+            Ok(RpcRes::Admin(admin::RpcRes::PendingRoutes(Err(e)))) => Json(Response {
+                status: false,
+                error: Some(e.to_string()),
+                ..Default::default()
+            }),
+            _ => Json(Response {
+                status: false,
+                error: Some("INTERNAL_ERROR".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// cancel an in-flight route of this gateway, the client request fails and the session created late on the node is released
+    #[oai(path = "/route/cancel", method = "post")]
+    async fn cancel_route(&self, _auth: AdminAuthorization, body: Json<CancelRouteReq>) -> Json<Response<bool>> {
+        let session_id = body.0.session_id;
+        log::info!("[AdminAPIs] cancel route of session {session_id}");
+        let (req, rx) = Rpc::new(RpcReq::Admin(admin::RpcReq::CancelRoute(session_id)));
+        if self.sender.send(req).await.is_err() {
+            return Json(Response {
+                status: false,
+                error: Some("INTERNAL_QUEUE_ERROR".to_string()),
+                ..Default::default()
+            });
+        }
+        match rx.await {
+            Ok(RpcRes::Admin(admin::RpcRes::CancelRoute(Ok(true)))) => Json(Response {
+                status: true,
+                data: Some(true),
+                ..Default::default()
+            }),
+            Ok(RpcRes::Admin(admin::RpcRes::CancelRoute(Ok(false)))) => Json(Response {
+                status: false,
+                error: Some("ROUTE_NOT_FOUND".to_string()),
+                ..Default::default()
+            }),
+            Ok(RpcRes::Admin(admin::RpcRes::CancelRoute(Err(e)))) => Json(Response {
+                status: false,
+                error: Some(e.to_string()),
+                ..Default::default()
+            }),
+            _ => Json(Response {
+                status: false,
+                error: Some("INTERNAL_ERROR".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// move a webrtc sdk session to other node, the client is asked to restart-ice to the new node and keeps same session id
+    #[oai(path = "/session/migrate", method = "post")]
+    async fn migrate_session(&self, _auth: AdminAuthorization, body: Json<MigrateSessionReq>) -> Json<Response<MigrateSessionInfo>> {
+        let body = body.0;
+        let conn = match body.conn_id.parse::<ClusterConnId>() {
+            Ok(conn) => conn,
+            Err(_) => {
+                return Json(Response {
+                    status: false,
+                    error: Some("INVALID_CONN_ID".to_string()),
+                    ..Default::default()
+                })
+            }
+        };
+        log::info!("[AdminAPIs] migrate session {conn} to {:?}", body.dest_node);
+        let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Migrate(conn, app_ctx(body.app), body.dest_node)));
+        if self.sender.send(req).await.is_err() {
+            return Json(Response {
+                status: false,
+                error: Some("INTERNAL_QUEUE_ERROR".to_string()),
+                ..Default::default()
+            });
+        }
+        match rx.await {
+            Ok(RpcRes::Webrtc(webrtc::RpcRes::Migrate(Ok(conn)))) => Json(Response {
+                status: true,
+                data: Some(MigrateSessionInfo { conn_id: conn.to_string() }),
+                ..Default::default()
+            }),
+            Ok(RpcRes::Webrtc(webrtc::RpcRes::Migrate(Err(e)))) => Json(Response {
+                status: false,
+                error: Some(e.to_string()),
+                ..Default::default()
+            }),
+            _ => Json(Response {
+                status: false,
+                error: Some("INTERNAL_ERROR".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// dump negotiated SDPs, ICE candidates and transport state of a webrtc, whip or whep session for support diagnostics
+    #[oai(path = "/session/dump", method = "post")]
+    async fn dump_session(&self, _auth: AdminAuthorization, body: Json<SessionDumpReq>) -> Json<Response<SessionDumpInfo>> {
+        let conn = match body.0.conn_id.parse::<ClusterConnId>() {
+            Ok(conn) => conn,
+            Err(_) => {
+                return Json(Response {
+                    status: false,
+                    error: Some("INVALID_CONN_ID".to_string()),
+                    ..Default::default()
+                })
+            }
+        };
+        log::info!("[AdminAPIs] dump session {conn}");
+        let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Dump(conn)));
+        if self.sender.send(req).await.is_err() {
+            return Json(Response {
+                status: false,
+                error: Some("INTERNAL_QUEUE_ERROR".to_string()),
+                ..Default::default()
+            });
+        }
+        match rx.await {
+            Ok(RpcRes::Webrtc(webrtc::RpcRes::Dump(Ok(dump)))) => Json(Response {
+                status: true,
+                data: Some(dump.into()),
+                ..Default::default()
+            }),
+            Ok(RpcRes::Webrtc(webrtc::RpcRes::Dump(Err(e)))) => Json(Response {
+                status: false,
+                error: Some(e.to_string()),
+                ..Default::default()
+            }),
+            _ => Json(Response {
+                status: false,
+                error: Some("INTERNAL_ERROR".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+}
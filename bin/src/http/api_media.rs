@@ -1,3 +1,6 @@
+use media_server_protocol::transport::{webrtc::WebrtcError, RpcError};
+use poem::http::StatusCode;
+
 mod rtpengine;
 mod webrtc;
 mod whep;
@@ -5,5 +8,15 @@ mod whip;
 
 pub use rtpengine::RtpengineApis;
 pub use webrtc::WebrtcApis;
-pub use whep::WhepApis;
+pub use whep::{whep_conn_events, WhepApis};
 pub use whip::WhipApis;
+
+/// Overloaded, starting or shutting down node is a temporary error, so we return 503 for client can retry later.
+/// Offer without compatible codec is well-formed but can't be answered, so we return 422 with offered and supported codecs in body
+fn connect_error_status(err: &RpcError) -> StatusCode {
+    match WebrtcError::try_from(err.code) {
+        Ok(WebrtcError::ConnectOverloaded | WebrtcError::WorkerShuttingDown | WebrtcError::WorkerNotReady) => StatusCode::SERVICE_UNAVAILABLE,
+        Ok(WebrtcError::NoCompatibleCodec) => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
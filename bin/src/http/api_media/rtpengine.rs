@@ -1,17 +1,21 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use media_server_protocol::{
     cluster::gen_cluster_session_id,
-    endpoint::ClusterConnId,
+    endpoint::{ClusterConnId, TrackSource},
+    media::MediaKind,
     tokens::RtpEngineToken,
     transport::{
-        rtpengine::{self, RtpCreateAnswerRequest, RtpCreateOfferRequest, RtpSetAnswerRequest},
+        rtpengine::{self, RtpCreateAnswerRequest, RtpCreateEgressRequest, RtpCreateOfferRequest, RtpSetAnswerRequest},
         RpcReq, RpcRes, RpcResult,
     },
 };
 use media_server_secure::MediaEdgeSecure;
 use poem::{http::StatusCode, web::Path, Result};
-use poem_openapi::{payload::PlainText, OpenApi};
+use poem_openapi::{
+    payload::{Json, PlainText},
+    OpenApi,
+};
 
 use crate::{
     http::utils::{ApplicationSdp, CustomHttpResponse},
@@ -20,6 +24,17 @@ use crate::{
 
 use super::super::utils::{RemoteIpAddr, TokenAuthorization};
 
+#[derive(poem_openapi::Object)]
+struct RtpEgressReq {
+    /// Peer which publishes the source track
+    source_peer: String,
+    source_track: String,
+    /// audio or video
+    kind: String,
+    /// Rtp receiver address, in ip:port format
+    dest: String,
+}
+
 pub struct RtpengineApis<S> {
     sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
     secure: Arc<S>,
@@ -107,6 +122,51 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> RtpengineApis<S> {
         }
     }
 
+    /// create plain rtp egress which forwards a room track to the receiver, response is the sdp for the receiver
+    #[oai(path = "/egress", method = "post")]
+    async fn create_egress(&self, TokenAuthorization(token): TokenAuthorization, body: Json<RtpEgressReq>) -> Result<CustomHttpResponse<ApplicationSdp<String>>> {
+        let session_id = gen_cluster_session_id();
+        let (app_ctx, token) = self.secure.decode_token::<RtpEngineToken>(&token.token).ok_or(poem::Error::from_status(StatusCode::BAD_REQUEST))?;
+        let kind = match body.kind.as_str() {
+            "audio" => MediaKind::Audio,
+            "video" => MediaKind::Video,
+            _ => return Err(poem::Error::from_string("kind must be audio or video", StatusCode::BAD_REQUEST)),
+        };
+        let dest: SocketAddr = body.dest.parse().map_err(|_e| poem::Error::from_string("invalid dest address", StatusCode::BAD_REQUEST))?;
+        log::info!("[MediaAPIs] create rtp egress with token {token:?}, source {}/{} to {dest}", body.source_peer, body.source_track);
+        let (req, rx) = Rpc::new(RpcReq::RtpEngine(rtpengine::RpcReq::CreateEgress(RtpCreateEgressRequest {
+            app: app_ctx,
+            session_id,
+            room: token.room.into(),
+            peer: token.peer.into(),
+            source: TrackSource {
+                peer: body.0.source_peer.into(),
+                track: body.0.source_track.into(),
+            },
+            kind,
+            dest,
+        })));
+        self.sender.send(req).await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+        let res = rx.await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+        match res {
+            RpcRes::RtpEngine(rtpengine::RpcRes::CreateEgress(res)) => match res {
+                RpcResult::Ok((conn, sdp)) => {
+                    log::info!("[MediaAPIs] Rtp egress created with conn_id {conn}");
+                    Ok(CustomHttpResponse {
+                        code: StatusCode::CREATED,
+                        res: ApplicationSdp(sdp),
+                        headers: vec![("location", format!("/rtpengine/conn/{}", conn))],
+                    })
+                }
+                RpcResult::Err(e) => {
+                    log::warn!("[MediaAPIs] Rtp egress creation failed with {e}");
+                    Err(poem::Error::from_string(e.to_string(), StatusCode::BAD_REQUEST))
+                }
+            },
+            _ => Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)),
+        }
+    }
+
     /// patch rtpengine conn for trickle-ice
     #[oai(path = "/conn/:conn_id", method = "patch")]
     async fn conn_whep_patch(&self, conn_id: Path<String>, body: ApplicationSdp<String>) -> Result<PlainText<String>> {
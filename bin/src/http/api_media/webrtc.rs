@@ -3,27 +3,30 @@ use std::sync::Arc;
 use media_server_protocol::{
     cluster::gen_cluster_session_id,
     endpoint::ClusterConnId,
-    protobuf::gateway::{ConnectRequest, ConnectResponse, RemoteIceRequest, RemoteIceResponse},
+    protobuf::gateway::{ConnectRequest, ConnectResponse, RemoteIceRequest, RemoteIceResponse, ValidateOfferResponse},
+    session_tags::validate_session_tags,
     tokens::WebrtcToken,
     transport::{webrtc, RpcReq, RpcRes, RpcResult},
 };
 use media_server_secure::MediaEdgeSecure;
+use media_server_utils::now_ms;
 use poem::{http::StatusCode, Result};
 use poem_openapi::{param::Path, payload::Response as HttpResponse, OpenApi};
 
 use crate::rpc::Rpc;
 
-use super::super::utils::{Protobuf, RemoteIpAddr, TokenAuthorization, UserAgent};
+use super::super::utils::{too_many_reconnects, Protobuf, ReconnectLimiter, RemoteIpAddr, TokenAuthorization, UserAgent};
 
 pub struct WebrtcApis<S> {
     sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
     secure: Arc<S>,
+    reconnect: Arc<ReconnectLimiter>,
 }
 
 #[OpenApi]
 impl<S: 'static + MediaEdgeSecure + Send + Sync> WebrtcApis<S> {
-    pub fn new(sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>, secure: Arc<S>) -> Self {
-        Self { sender, secure }
+    pub fn new(sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>, secure: Arc<S>, reconnect: Arc<ReconnectLimiter>) -> Self {
+        Self { sender, secure, reconnect }
     }
 
     /// connect webrtc
@@ -47,6 +50,9 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WebrtcApis<S> {
                 return Err(poem::Error::from_string("Wrong peer".to_string(), StatusCode::FORBIDDEN));
             }
         }
+        validate_session_tags(&connect.tags).map_err(|e| poem::Error::from_string(e.to_string(), StatusCode::BAD_REQUEST))?;
+        let peer = format!("{}/{}/{}", app_ctx.app, token.room.as_deref().unwrap_or_default(), token.peer.as_deref().unwrap_or_default());
+        self.reconnect.on_connect(ip_addr, &peer, now_ms()).map_err(too_many_reconnects)?;
         let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Connect(
             app_ctx, session_id, ip_addr, user_agent, connect.0, token.extra_data, token.record,
         )));
@@ -64,7 +70,32 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WebrtcApis<S> {
                 }
                 RpcResult::Err(e) => {
                     log::warn!("[MediaAPIs] webrtc endpoint creation failed with {e}");
-                    Err(poem::Error::from_string(e.to_string(), StatusCode::BAD_REQUEST))
+                    Err(poem::Error::from_string(e.to_string(), super::connect_error_status(&e)))
+                }
+            },
+            _ => Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)),
+        }
+    }
+
+    /// dry-run webrtc connect, the offer is negotiated like connect and the answer is returned without creating a session
+    #[oai(path = "/validate", method = "post")]
+    async fn webrtc_validate(
+        &self,
+        RemoteIpAddr(ip_addr): RemoteIpAddr,
+        TokenAuthorization(token): TokenAuthorization,
+        connect: Protobuf<ConnectRequest>,
+    ) -> Result<HttpResponse<Protobuf<ValidateOfferResponse>>> {
+        let (app_ctx, _token) = self.secure.decode_token::<WebrtcToken>(&token.token).ok_or(poem::Error::from_status(StatusCode::BAD_REQUEST))?;
+        log::info!("[MediaAPIs] validate webrtc offer, ip {}", ip_addr);
+        let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Validate(app_ctx, ip_addr, connect.0)));
+        self.sender.send(req).await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+        let res = rx.await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+        match res {
+            RpcRes::Webrtc(webrtc::RpcRes::Validate(res)) => match res {
+                RpcResult::Ok(res) => Ok(HttpResponse::new(Protobuf(res))),
+                RpcResult::Err(e) => {
+                    log::info!("[MediaAPIs] webrtc offer validation failed with {e}");
+                    Err(poem::Error::from_string(e.to_string(), super::connect_error_status(&e)))
                 }
             },
             _ => Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)),
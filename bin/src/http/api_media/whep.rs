@@ -1,36 +1,60 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use media_server_protocol::{
     cluster::gen_cluster_session_id,
     endpoint::ClusterConnId,
     tokens::WhepToken,
     transport::{
-        whep::{self, WhepConnectReq, WhepDeleteReq, WhepRemoteIceReq},
+        whep::{self, WhepConnectReq, WhepDeleteReq, WhepEvent, WhepEventsReq, WhepRemoteIceReq},
         RpcReq, RpcRes, RpcResult,
     },
 };
 use media_server_secure::MediaEdgeSecure;
-use poem::{http::StatusCode, Result};
+use media_server_utils::now_ms;
+use poem::{
+    http::StatusCode,
+    web::{
+        sse::{Event, SSE},
+        Data, Path as PoemPath,
+    },
+    Result,
+};
 use poem_openapi::{
     param::Path,
     payload::{PlainText, Response as HttpResponse},
     OpenApi,
 };
 use rand::random;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 
 use crate::rpc::Rpc;
 
-use super::super::utils::{ApplicationSdp, ApplicationSdpPatch, CustomHttpResponse, RemoteIpAddr, TokenAuthorization, UserAgent};
+use super::super::utils::{
+    too_many_reconnects, ApplicationSdp, ApplicationSdpPatch, CustomHttpResponse, IceServersConfig, ReconnectLimiter, RemoteIpAddr, SessionTagsHeader, TokenAuthorization, UserAgent,
+};
+
+/// Events waiting to be written to the SSE response, the session is not polled again while this is full
+const EVENTS_CHANNEL_SIZE: usize = 16;
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub type WhepRpcSender = tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>;
 
 pub struct WhepApis<S> {
     sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
     secure: Arc<S>,
+    ice_servers: IceServersConfig,
+    reconnect: Arc<ReconnectLimiter>,
 }
 
 #[OpenApi]
 impl<S: 'static + MediaEdgeSecure + Send + Sync> WhepApis<S> {
-    pub fn new(sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>, secure: Arc<S>) -> Self {
-        Self { sender, secure }
+    pub fn new(sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>, secure: Arc<S>, ice_servers: IceServersConfig, reconnect: Arc<ReconnectLimiter>) -> Self {
+        Self {
+            sender,
+            secure,
+            ice_servers,
+            reconnect,
+        }
     }
 
     /// connect whep endpoint
@@ -40,11 +64,14 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhepApis<S> {
         UserAgent(user_agent): UserAgent,
         RemoteIpAddr(ip_addr): RemoteIpAddr,
         TokenAuthorization(token): TokenAuthorization,
+        SessionTagsHeader(tags): SessionTagsHeader,
         body: ApplicationSdp<String>,
     ) -> Result<CustomHttpResponse<ApplicationSdp<String>>> {
         let session_id = gen_cluster_session_id();
         let (app_ctx, token) = self.secure.decode_token::<WhepToken>(&token.token).ok_or(poem::Error::from_status(StatusCode::BAD_REQUEST))?;
         log::info!("[MediaAPIs] create whep endpoint with token {:?}, ip {}, user_agent {}", token, ip_addr, user_agent);
+        let peer = format!("{}/{}/{}", app_ctx.app, token.room, token.peer.as_deref().unwrap_or_default());
+        self.reconnect.on_connect(ip_addr, &peer, now_ms()).map_err(too_many_reconnects)?;
         let (req, rx) = Rpc::new(RpcReq::Whep(whep::RpcReq::Connect(WhepConnectReq {
             app: app_ctx,
             session_id,
@@ -54,6 +81,7 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhepApis<S> {
             peer: token.peer.unwrap_or_else(|| format!("whep-{}", (random::<u64>()))).into(),
             user_agent,
             extra_data: token.extra_data,
+            tags,
         })));
         self.sender.send(req).await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
         let res = rx.await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
@@ -61,15 +89,17 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhepApis<S> {
             RpcRes::Whep(whep::RpcRes::Connect(res)) => match res {
                 RpcResult::Ok(res) => {
                     log::info!("[MediaAPIs] Whep endpoint created with conn_id {}", res.conn_id);
+                    let mut headers = vec![("location", format!("/whep/conn/{}", res.conn_id))];
+                    headers.extend(self.ice_servers.link_headers());
                     Ok(CustomHttpResponse {
                         code: StatusCode::CREATED,
                         res: ApplicationSdp(res.sdp),
-                        headers: vec![("location", format!("/whep/conn/{}", res.conn_id))],
+                        headers,
                     })
                 }
                 RpcResult::Err(e) => {
                     log::warn!("[MediaAPIs] Whep endpoint creation failed with {e}");
-                    Err(poem::Error::from_string(e.to_string(), StatusCode::BAD_REQUEST))
+                    Err(poem::Error::from_string(e.to_string(), super::connect_error_status(&e)))
                 }
             },
             _ => Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)),
@@ -123,3 +153,100 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhepApis<S> {
         }
     }
 }
+
+/// Server-sent events of a whep conn: server candidates, then layer and stats changes. The stream ends when the conn is closed
+#[poem::handler]
+pub async fn whep_conn_events(PoemPath(conn_id): PoemPath<String>, Data(sender): Data<&WhepRpcSender>) -> Result<SSE> {
+    let conn_id: ClusterConnId = conn_id.parse().map_err(|_e| poem::Error::from_status(StatusCode::BAD_REQUEST))?;
+    // first poll is done before responding, so an unknown conn is rejected instead of opening an empty stream
+    let events = poll_events(sender, conn_id, true).await.ok_or(poem::Error::from_status(StatusCode::NOT_FOUND))?;
+    log::info!("[MediaAPIs] open whep events stream for conn {conn_id}");
+    let stream = events_stream(sender.clone(), conn_id, events).map(|(name, data)| Event::message(data).event_type(name));
+    Ok(SSE::new(stream).keep_alive(EVENTS_KEEP_ALIVE))
+}
+
+async fn poll_events(sender: &WhepRpcSender, conn_id: ClusterConnId, initial: bool) -> Option<Vec<WhepEvent>> {
+    let (req, rx) = Rpc::new(RpcReq::Whep(whep::RpcReq::Events(WhepEventsReq { conn_id, initial })));
+    sender.send(req).await.ok()?;
+    match rx.await.ok()? {
+        RpcRes::Whep(whep::RpcRes::Events(res)) => res.ok().map(|res| res.events),
+        _ => None,
+    }
+}
+
+/// Stream of (event name, data). Each request is long-polled by the session, which answers as soon as it has new events,
+/// so they are pushed to the client without a fixed polling delay. The stream ends when the session is gone or the client disconnects
+fn events_stream(sender: WhepRpcSender, conn_id: ClusterConnId, initial: Vec<WhepEvent>) -> ReceiverStream<(&'static str, String)> {
+    let (tx, rx) = tokio::sync::mpsc::channel(EVENTS_CHANNEL_SIZE);
+    tokio::spawn(async move {
+        let mut pending = initial;
+        loop {
+            for event in pending.drain(..) {
+                if tx.send(sse_event(event)).await.is_err() {
+                    return;
+                }
+            }
+            tokio::select! {
+                _ = tx.closed() => return,
+                events = poll_events(&sender, conn_id, false) => match events {
+                    Some(events) => pending = events,
+                    None => return,
+                },
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+fn sse_event(event: WhepEvent) -> (&'static str, String) {
+    match event {
+        WhepEvent::Candidate(candidate) => ("candidate", format!("a={candidate}")),
+        WhepEvent::EndOfCandidates => ("end-of-candidates", "a=end-of-candidates".to_string()),
+        WhepEvent::LayerChanged { spatial, temporal } => ("layer", serde_json::json!({ "spatial": spatial, "temporal": temporal }).to_string()),
+        WhepEvent::Stats { egress_bitrate } => ("stats", serde_json::json!({ "egress_bitrate": egress_bitrate }).to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::transport::{
+        whep::{self, WhepEvent, WhepEventsRes},
+        RpcError, RpcReq, RpcRes,
+    };
+
+    use tokio_stream::StreamExt;
+
+    use super::{events_stream, WhepRpcSender};
+
+    #[tokio::test]
+    async fn events_stream_end_with_session() {
+        let (sender, mut rx): (WhepRpcSender, _) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            // first poll returns a layer change, the next one fails as the session is closed
+            let mut polls = 0;
+            while let Some(req) = rx.recv().await {
+                assert!(matches!(req.req, RpcReq::Whep(whep::RpcReq::Events(ref r)) if !r.initial));
+                let res = if polls == 0 {
+                    Ok(WhepEventsRes {
+                        events: vec![WhepEvent::LayerChanged { spatial: 1, temporal: 2 }],
+                    })
+                } else {
+                    Err(RpcError::new(0u32, "closed"))
+                };
+                polls += 1;
+                req.answer_tx.send(RpcRes::Whep(whep::RpcRes::Events(res))).expect("Should send answer");
+            }
+        });
+
+        let initial = vec![WhepEvent::Candidate("candidate:1 1 udp 2130706431 127.0.0.1 10000 typ host".to_string()), WhepEvent::EndOfCandidates];
+        let events = events_stream(sender, "1-2-3,4".parse().expect("Should parse conn"), initial).collect::<Vec<_>>().await;
+        assert_eq!(
+            events,
+            vec![
+                ("candidate", "a=candidate:1 1 udp 2130706431 127.0.0.1 10000 typ host".to_string()),
+                ("end-of-candidates", "a=end-of-candidates".to_string()),
+                ("layer", r#"{"spatial":1,"temporal":2}"#.to_string()),
+            ]
+        );
+    }
+}
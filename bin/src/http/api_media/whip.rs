@@ -10,6 +10,7 @@ use media_server_protocol::{
     },
 };
 use media_server_secure::MediaEdgeSecure;
+use media_server_utils::now_ms;
 use poem::{http::StatusCode, Result};
 use poem_openapi::{
     param::Path,
@@ -19,17 +20,35 @@ use poem_openapi::{
 
 use crate::rpc::Rpc;
 
-use super::super::utils::{ApplicationSdp, ApplicationSdpPatch, CustomHttpResponse, RemoteIpAddr, TokenAuthorization, UserAgent};
+use super::super::utils::{
+    too_many_reconnects, ApplicationSdp, ApplicationSdpPatch, CustomHttpResponse, IceServersConfig, ReconnectLimiter, RemoteIpAddr, SessionTagsHeader, TokenAuthorization, UserAgent,
+};
 
 pub struct WhipApis<S> {
     sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
     secure: Arc<S>,
+    ice_servers: IceServersConfig,
+    reconnect: Arc<ReconnectLimiter>,
+    /// Budget of a connect from the client request, gateway and edge abort the connect after it
+    connect_timeout_ms: u64,
 }
 
 #[OpenApi]
 impl<S: 'static + MediaEdgeSecure + Send + Sync> WhipApis<S> {
-    pub fn new(sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>, secure: Arc<S>) -> Self {
-        Self { sender, secure }
+    pub fn new(
+        sender: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
+        secure: Arc<S>,
+        ice_servers: IceServersConfig,
+        reconnect: Arc<ReconnectLimiter>,
+        connect_timeout_ms: u64,
+    ) -> Self {
+        Self {
+            sender,
+            secure,
+            ice_servers,
+            reconnect,
+            connect_timeout_ms,
+        }
     }
 
     /// connect whip endpoint
@@ -39,11 +58,14 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhipApis<S> {
         UserAgent(user_agent): UserAgent,
         RemoteIpAddr(ip_addr): RemoteIpAddr,
         TokenAuthorization(token): TokenAuthorization,
+        SessionTagsHeader(tags): SessionTagsHeader,
         body: ApplicationSdp<String>,
     ) -> Result<CustomHttpResponse<ApplicationSdp<String>>> {
         let session_id = gen_cluster_session_id();
         let (app_ctx, token) = self.secure.decode_token::<WhipToken>(&token.token).ok_or(poem::Error::from_status(StatusCode::BAD_REQUEST))?;
         log::info!("[MediaAPIs] create whip endpoint with token {:?}, ip {}, user_agent {}", token, ip_addr, user_agent);
+        let peer = format!("{}/{}/{}", app_ctx.app, token.room, token.peer);
+        self.reconnect.on_connect(ip_addr, &peer, now_ms()).map_err(too_many_reconnects)?;
         let (req, rx) = Rpc::new(RpcReq::Whip(whip::RpcReq::Connect(WhipConnectReq {
             app: app_ctx,
             session_id,
@@ -54,6 +76,8 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhipApis<S> {
             user_agent,
             record: token.record,
             extra_data: token.extra_data,
+            deadline_ms: Some(now_ms() + self.connect_timeout_ms),
+            tags,
         })));
         self.sender.send(req).await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
         let res = rx.await.map_err(|_e| poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
@@ -61,15 +85,17 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhipApis<S> {
             RpcRes::Whip(whip::RpcRes::Connect(res)) => match res {
                 RpcResult::Ok(res) => {
                     log::info!("[MediaAPIs] Whip endpoint created with conn_id {}", res.conn_id);
+                    let mut headers = vec![("location", format!("/whip/conn/{}", res.conn_id))];
+                    headers.extend(self.ice_servers.link_headers());
                     Ok(CustomHttpResponse {
                         code: StatusCode::CREATED,
                         res: ApplicationSdp(res.sdp),
-                        headers: vec![("location", format!("/whip/conn/{}", res.conn_id))],
+                        headers,
                     })
                 }
                 RpcResult::Err(e) => {
                     log::warn!("[MediaAPIs] Whip endpoint creation failed with {e}");
-                    Err(poem::Error::from_string(e.to_string(), StatusCode::BAD_REQUEST))
+                    Err(poem::Error::from_string(e.to_string(), super::connect_error_status(&e)))
                 }
             },
             _ => Err(poem::Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)),
@@ -123,3 +149,165 @@ impl<S: 'static + MediaEdgeSecure + Send + Sync> WhipApis<S> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use media_server_protocol::{
+        endpoint::ClusterConnId,
+        multi_tenancy::AppContext,
+        tokens::WhipToken,
+        transport::{whip, RpcError, RpcRes, RpcResult},
+    };
+    use media_server_secure::{
+        jwt::{MediaEdgeSecureJwt, MediaGatewaySecureJwt},
+        DumpAppStorage, MediaGatewaySecure,
+    };
+    use poem::{http::StatusCode, IntoResponse};
+    use poem_openapi::auth::Bearer;
+
+    use super::WhipApis;
+    use crate::http::utils::{ApplicationSdp, IceServersConfig, ReconnectLimitConfig, ReconnectLimiter, RemoteIpAddr, SessionTagsHeader, TokenAuthorization, UserAgent};
+
+    #[tokio::test]
+    async fn connect_response_has_ice_server_links() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let ice_servers = IceServersConfig {
+            urls: vec!["stun:stun.example.net".to_string(), "turn:turn.example.net".to_string()],
+            turn_username: Some("user".to_string()),
+            turn_credential: Some("pass".to_string()),
+        };
+        let apis = WhipApis::new(tx, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())), ice_servers, Arc::new(ReconnectLimiter::default()), 10_000);
+        tokio::spawn(async move {
+            let rpc = rx.recv().await.expect("Should receive connect rpc");
+            rpc.res(RpcRes::Whip(whip::RpcRes::Connect(RpcResult::Ok(whip::WhipConnectRes {
+                conn_id: ClusterConnId::migrate(1, 1000),
+                sdp: "answer".to_string(),
+            }))));
+        });
+
+        let gateway_secure = MediaGatewaySecureJwt::new(b"secret", Arc::new(DumpAppStorage::default()));
+        let token = WhipToken {
+            room: "room".to_string(),
+            peer: "peer".to_string(),
+            record: false,
+            extra_data: None,
+        };
+        let token = gateway_secure.encode_token(&AppContext::root_app(), token, 60);
+        let res = apis
+            .whip_create(
+                UserAgent("test".to_string()),
+                RemoteIpAddr(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                TokenAuthorization(Bearer { token }),
+                SessionTagsHeader(Default::default()),
+                ApplicationSdp("offer".to_string()),
+            )
+            .await
+            .expect("Should connect")
+            .into_response();
+
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert!(res.headers().get("location").is_some());
+        let links = res.headers().get_all("link").iter().map(|v| v.to_str().expect("Should be ascii").to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            vec![
+                "<stun:stun.example.net>; rel=\"ice-server\"".to_string(),
+                "<turn:turn.example.net>; rel=\"ice-server\"; username=\"user\"; credential=\"pass\"; credential-type=\"password\"".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rapid_reconnects_are_throttled() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let reconnect = ReconnectLimiter::new(ReconnectLimitConfig {
+            max_reconnects: 2,
+            window_ms: 60_000,
+            throttle_ms: 30_000,
+        });
+        let apis = WhipApis::new(tx, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())), IceServersConfig::default(), Arc::new(reconnect), 10_000);
+        tokio::spawn(async move {
+            while let Some(rpc) = rx.recv().await {
+                rpc.res(RpcRes::Whip(whip::RpcRes::Connect(RpcResult::Ok(whip::WhipConnectRes {
+                    conn_id: ClusterConnId::migrate(1, 1000),
+                    sdp: "answer".to_string(),
+                }))));
+            }
+        });
+
+        let gateway_secure = MediaGatewaySecureJwt::new(b"secret", Arc::new(DumpAppStorage::default()));
+        let token = WhipToken {
+            room: "room".to_string(),
+            peer: "peer".to_string(),
+            record: false,
+            extra_data: None,
+        };
+        let token = gateway_secure.encode_token(&AppContext::root_app(), token, 60);
+        let connect = || {
+            apis.whip_create(
+                UserAgent("test".to_string()),
+                RemoteIpAddr(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                TokenAuthorization(Bearer { token: token.clone() }),
+                SessionTagsHeader(Default::default()),
+                ApplicationSdp("offer".to_string()),
+            )
+        };
+
+        // first connect and a reconnect after network flap are allowed
+        assert_eq!(connect().await.expect("Should connect").into_response().status(), StatusCode::CREATED);
+        assert_eq!(connect().await.expect("Should reconnect").into_response().status(), StatusCode::CREATED);
+
+        let res = connect().await.err().expect("Should be throttled").into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("retry-after").and_then(|v| v.to_str().ok()), Some("30"));
+    }
+
+    #[tokio::test]
+    async fn no_compatible_codec_is_unprocessable() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let apis = WhipApis::new(
+            tx,
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+            IceServersConfig::default(),
+            Arc::new(ReconnectLimiter::default()),
+            10_000,
+        );
+        tokio::spawn(async move {
+            let rpc = rx.recv().await.expect("Should receive connect rpc");
+            rpc.res(RpcRes::Whip(whip::RpcRes::Connect(RpcResult::Err(RpcError::new(
+                WebrtcError::NoCompatibleCodec,
+                "no compatible codec, offered [G722], supported [opus, VP8, VP9, H264]",
+            )))));
+        });
+
+        let gateway_secure = MediaGatewaySecureJwt::new(b"secret", Arc::new(DumpAppStorage::default()));
+        let token = WhipToken {
+            room: "room".to_string(),
+            peer: "peer".to_string(),
+            record: false,
+            extra_data: None,
+        };
+        let token = gateway_secure.encode_token(&AppContext::root_app(), token, 60);
+        let res = apis
+            .whip_create(
+                UserAgent("test".to_string()),
+                RemoteIpAddr(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                TokenAuthorization(Bearer { token }),
+                SessionTagsHeader(Default::default()),
+                ApplicationSdp("offer".to_string()),
+            )
+            .await
+            .err()
+            .expect("Should reject")
+            .into_response();
+
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = res.into_body().into_string().await.expect("Should have body");
+        assert!(body.contains("offered [G722], supported [opus, VP8, VP9, H264]"), "{body}");
+    }
+}
@@ -1,8 +1,83 @@
 use std::collections::BTreeMap;
 
-use media_server_utils::get_all_counts;
+use media_server_utils::{get_all_counts, get_all_loop_metrics, node_egress_budget, EgressBudgetSummary, LoopMetricsSummary};
 use poem_openapi::{payload::Json, OpenApi};
 
+/// Processing time in microseconds of a loop, percentiles are rounded up to power of two buckets
+#[derive(poem_openapi::Object)]
+struct LoopMetricsInfo {
+    tick_count: u64,
+    tick_p50_us: u64,
+    tick_p99_us: u64,
+    tick_max_us: u64,
+    event_count: u64,
+    event_p50_us: u64,
+    event_p99_us: u64,
+    event_max_us: u64,
+    max_queue: u64,
+}
+
+impl From<LoopMetricsSummary> for LoopMetricsInfo {
+    fn from(value: LoopMetricsSummary) -> Self {
+        Self {
+            tick_count: value.tick_count,
+            tick_p50_us: value.tick_p50_us,
+            tick_p99_us: value.tick_p99_us,
+            tick_max_us: value.tick_max_us,
+            event_count: value.event_count,
+            event_p50_us: value.event_p50_us,
+            event_p99_us: value.event_p99_us,
+            event_max_us: value.event_max_us,
+            max_queue: value.max_queue as u64,
+        }
+    }
+}
+
+/// Node egress budget, demand is the egress estimate of all sessions and allocated is what they are allowed to send
+#[derive(poem_openapi::Object)]
+struct EgressBudgetInfo {
+    cap_bps: u64,
+    demand_bps: u64,
+    allocated_bps: u64,
+}
+
+impl From<EgressBudgetSummary> for EgressBudgetInfo {
+    fn from(value: EgressBudgetSummary) -> Self {
+        Self {
+            cap_bps: value.cap_bps,
+            demand_bps: value.demand_bps,
+            allocated_bps: value.allocated_bps,
+        }
+    }
+}
+
+/// SDN health of a node which is seen by this gateway, health is healthy, degraded or unhealthy
+#[derive(poem_openapi::Object)]
+struct SdnHealthInfo {
+    health: String,
+    delay_ms: u64,
+}
+
+#[cfg(feature = "gateway")]
+impl From<media_server_gateway::SdnProbe> for SdnHealthInfo {
+    fn from(value: media_server_gateway::SdnProbe) -> Self {
+        Self {
+            health: format!("{:?}", value.health()).to_lowercase(),
+            delay_ms: value.delay_ms(),
+        }
+    }
+}
+
+#[cfg(feature = "gateway")]
+fn sdn_health_infos() -> BTreeMap<String, SdnHealthInfo> {
+    media_server_gateway::sdn_health().into_iter().map(|(node, probe)| (node.to_string(), probe.into())).collect()
+}
+
+#[cfg(not(feature = "gateway"))]
+fn sdn_health_infos() -> BTreeMap<String, SdnHealthInfo> {
+    BTreeMap::new()
+}
+
 pub struct Apis;
 
 #[OpenApi]
@@ -11,4 +86,22 @@ impl Apis {
     async fn get_counts(&self) -> Json<BTreeMap<String, usize>> {
         Json(get_all_counts().into_iter().map(|(k, v)| (k.to_string(), v)).collect())
     }
+
+    /// event-loop timing of webrtc workers and endpoints, only collected when loop metrics is enabled
+    #[oai(path = "/loops", method = "get")]
+    async fn get_loops(&self) -> Json<BTreeMap<String, LoopMetricsInfo>> {
+        Json(get_all_loop_metrics().into_iter().map(|(k, v)| (k.to_string(), v.into())).collect())
+    }
+
+    /// egress budget usage of this node, empty when egress is not capped
+    #[oai(path = "/egress", method = "get")]
+    async fn get_egress(&self) -> Json<Option<EgressBudgetInfo>> {
+        Json(node_egress_budget().map(|budget| budget.summary().into()))
+    }
+
+    /// SDN health of nodes which are routed by this gateway, empty when this node isn't a gateway
+    #[oai(path = "/sdn", method = "get")]
+    async fn get_sdn(&self) -> Json<BTreeMap<String, SdnHealthInfo>> {
+        Json(sdn_health_infos())
+    }
 }
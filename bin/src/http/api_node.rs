@@ -1,6 +1,8 @@
 use std::time::Duration;
 
 use atm0s_sdn::NodeAddr;
+use media_server_utils::{node_degraded, node_ready};
+use poem::http::StatusCode;
 use poem_openapi::{
     payload::{Json, PlainText},
     OpenApi,
@@ -29,6 +31,22 @@ impl Apis {
         PlainText(self.ctx.address.to_string())
     }
 
+    /// 200 when the node is ready for traffic, 503 while it is starting e.g. media workers are binding sockets
+    #[oai(path = "/health", method = "get")]
+    async fn get_health(&self) -> poem::Result<PlainText<String>> {
+        if node_ready() {
+            let degraded = node_degraded();
+            if degraded.is_empty() {
+                Ok(PlainText("ready".to_string()))
+            } else {
+                // degraded components are optional, the node still serves traffic
+                Ok(PlainText(format!("ready, degraded: {}", degraded.join(","))))
+            }
+        } else {
+            Err(poem::Error::from_string("not ready", StatusCode::SERVICE_UNAVAILABLE))
+        }
+    }
+
     #[oai(path = "/router_dump", method = "get")]
     async fn get_router_dump(&self) -> Json<serde_json::Value> {
         let (tx, rx) = oneshot::channel();
@@ -53,6 +53,8 @@ struct WebrtcTokenReq {
     ttl: u64,
     record: Option<bool>,
     extra_data: Option<String>,
+    /// Token can be used as admin token for room control requests of the room
+    room_admin: Option<bool>,
 }
 
 #[derive(poem_openapi::Object)]
@@ -157,6 +159,7 @@ impl<S: 'static + MediaGatewaySecure + Send + Sync> TokenApis<S> {
                             peer: body.peer,
                             record: body.record.unwrap_or(false),
                             extra_data: body.extra_data,
+                            room_admin: body.room_admin.unwrap_or(false),
                         },
                         body.ttl,
                     ),
@@ -0,0 +1,56 @@
+/// ICE servers which are advertised to WHIP/WHEP clients with `Link` headers in connect responses (RFC 9725 section 4.6).
+/// Credentials are only added to `turn:` and `turns:` servers.
+#[derive(Debug, Default, Clone)]
+pub struct IceServersConfig {
+    pub urls: Vec<String>,
+    pub turn_username: Option<String>,
+    pub turn_credential: Option<String>,
+}
+
+impl IceServersConfig {
+    pub fn link_headers(&self) -> Vec<(&'static str, String)> {
+        self.urls.iter().map(|url| ("link", self.link_value(url))).collect()
+    }
+
+    fn link_value(&self, url: &str) -> String {
+        let mut value = format!("<{url}>; rel=\"ice-server\"");
+        if url.starts_with("turn:") || url.starts_with("turns:") {
+            if let Some(username) = &self.turn_username {
+                value.push_str(&format!("; username=\"{}\"", quote(username)));
+            }
+            if let Some(credential) = &self.turn_credential {
+                value.push_str(&format!("; credential=\"{}\"; credential-type=\"password\"", quote(credential)));
+            }
+        }
+        value
+    }
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IceServersConfig;
+
+    #[test]
+    fn link_headers_with_turn_credentials() {
+        let cfg = IceServersConfig {
+            urls: vec!["stun:stun.example.net".to_string(), "turn:turn.example.net?transport=udp".to_string()],
+            turn_username: Some("user".to_string()),
+            turn_credential: Some("pass\"word".to_string()),
+        };
+        assert_eq!(
+            cfg.link_headers(),
+            vec![
+                ("link", "<stun:stun.example.net>; rel=\"ice-server\"".to_string()),
+                (
+                    "link",
+                    "<turn:turn.example.net?transport=udp>; rel=\"ice-server\"; username=\"user\"; credential=\"pass\\\"word\"; credential-type=\"password\"".to_string()
+                ),
+            ]
+        );
+        assert_eq!(IceServersConfig::default().link_headers(), vec![]);
+    }
+}
@@ -1,15 +1,21 @@
 #[cfg(feature = "embed_static")]
 mod embedded_files;
+mod ice_servers;
 mod payload_protobuf;
 mod payload_sdp;
+mod reconnect_limit;
 mod remote_ip;
+mod session_tags;
 mod token;
 mod user_agent;
 
 #[cfg(feature = "embed_static")]
 pub use embedded_files::*;
+pub use ice_servers::*;
 pub use payload_protobuf::*;
 pub use payload_sdp::*;
+pub use reconnect_limit::*;
 pub use remote_ip::*;
+pub use session_tags::*;
 pub use token::*;
 pub use user_agent::*;
@@ -1,9 +1,11 @@
 use std::ops::{Deref, DerefMut};
 
 use poem::{
-    http::{HeaderValue, StatusCode},
-    FromRequest, IntoResponse, Request, RequestBody, Response, Result,
+    error::ReadBodyError,
+    http::{header, HeaderValue, StatusCode},
+    IntoResponse, Request, RequestBody, Response, Result,
 };
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use poem_openapi::{
     impl_apirequest_for_payload,
@@ -13,6 +15,32 @@ use poem_openapi::{
     ApiResponse,
 };
 
+/// Max SDP body size. Offers with many codecs and simulcast layers are still far below this.
+pub const MAX_SDP_BODY_SIZE: usize = 64 * 1024;
+
+/// Read SDP body from stream directly into the result buffer, stop reading as soon as it exceeds `max_size`.
+/// The buffer is pre-allocated with content-length when available, so a valid body is copied only once.
+async fn read_sdp_body<R: AsyncRead + Unpin>(reader: R, size_hint: Option<usize>, max_size: usize) -> Result<String, ReadBodyError> {
+    if size_hint.is_some_and(|size| size > max_size) {
+        return Err(ReadBodyError::PayloadTooLarge);
+    }
+    // one spare byte for reaching eof without growing the buffer
+    let mut buf = Vec::with_capacity(size_hint.map(|size| size + 1).unwrap_or_default());
+    // read one more byte than limit for detecting oversized body without reading all of it
+    reader.take(max_size as u64 + 1).read_to_end(&mut buf).await?;
+    if buf.len() > max_size {
+        return Err(ReadBodyError::PayloadTooLarge);
+    }
+    Ok(String::from_utf8(buf)?)
+}
+
+async fn parse_sdp_payload(request: &Request, body: &mut RequestBody) -> Result<String> {
+    let size_hint = request.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+    let sdp = read_sdp_body(body.take()?.into_async_read(), size_hint, MAX_SDP_BODY_SIZE).await?;
+    log::debug!("[SdpPayload] parsed sdp body with {} bytes", sdp.len());
+    Ok(sdp)
+}
+
 /// A UTF8 string payload.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ApplicationSdp<T>(pub T);
@@ -47,7 +75,7 @@ impl ParsePayload for ApplicationSdp<String> {
     const IS_REQUIRED: bool = true;
 
     async fn from_request(request: &Request, body: &mut RequestBody) -> Result<Self> {
-        Ok(Self(String::from_request(request, body).await?))
+        Ok(Self(parse_sdp_payload(request, body).await?))
     }
 }
 
@@ -109,7 +137,7 @@ impl ParsePayload for ApplicationSdpPatch<String> {
     const IS_REQUIRED: bool = true;
 
     async fn from_request(request: &Request, body: &mut RequestBody) -> Result<Self> {
-        Ok(Self(String::from_request(request, body).await?))
+        Ok(Self(parse_sdp_payload(request, body).await?))
     }
 }
 
@@ -148,7 +176,7 @@ impl<T: IntoResponse> IntoResponse for CustomHttpResponse<T> {
         let mut res = self.res.into_response();
         for (k, v) in self.headers {
             if let Ok(v) = HeaderValue::from_str(&v) {
-                res.headers_mut().insert(k, v);
+                res.headers_mut().append(k, v);
             }
         }
         res.set_status(self.code);
@@ -176,3 +204,42 @@ impl<T: Payload + IntoResponse> ApiResponse for CustomHttpResponse<T> {
 
 impl_apirequest_for_payload!(ApplicationSdp<String>);
 impl_apirequest_for_payload!(ApplicationSdpPatch<String>);
+
+#[cfg(test)]
+mod tests {
+    use poem::error::ReadBodyError;
+
+    use super::read_sdp_body;
+
+    fn large_offer(size: usize) -> String {
+        let mut offer = "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n".to_string();
+        while offer.len() < size {
+            offer.push_str("a=candidate:1 1 udp 2113937151 192.168.1.100 50000 typ host\r\n");
+        }
+        offer
+    }
+
+    #[tokio::test]
+    async fn large_offer_memory_bounded() {
+        let offer = large_offer(60_000);
+
+        // with content-length the buffer is allocated once with exact size
+        let sdp = read_sdp_body(offer.as_bytes(), Some(offer.len()), 64 * 1024).await.expect("Should parse");
+        assert_eq!(sdp, offer);
+        assert!(sdp.capacity() <= offer.len() + 1, "capacity {} should be exact", sdp.capacity());
+
+        // chunked body without length only grows by amortized doubling
+        let sdp = read_sdp_body(offer.as_bytes(), None, 64 * 1024).await.expect("Should parse");
+        assert_eq!(sdp, offer);
+        assert!(sdp.capacity() <= 2 * offer.len(), "capacity {} should be bounded", sdp.capacity());
+    }
+
+    #[tokio::test]
+    async fn oversized_offer_rejected_without_reading_all() {
+        assert!(matches!(read_sdp_body(&[][..], Some(1_000_000), 64 * 1024).await, Err(ReadBodyError::PayloadTooLarge)));
+        // endless stream will never finish if reader dont stop at limit
+        let endless = tokio::io::repeat(b'a');
+        assert!(matches!(read_sdp_body(endless, None, 64 * 1024).await, Err(ReadBodyError::PayloadTooLarge)));
+        assert!(matches!(read_sdp_body(&[0xff, 0xfe][..], None, 64 * 1024).await, Err(ReadBodyError::Utf8(_))));
+    }
+}
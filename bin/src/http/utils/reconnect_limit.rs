@@ -0,0 +1,156 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use poem::{http::StatusCode, Response};
+
+/// Reconnection limit of a client, which is identified by remote ip and the peer in its token.
+/// Up to `max_reconnects` connects inside `window_ms` are allowed for network flaps, a client which connects
+/// more than that is churning resources, so it is rejected with 429 and `Retry-After` for `throttle_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectLimitConfig {
+    /// 0 disables the limit
+    pub max_reconnects: u32,
+    pub window_ms: u64,
+    pub throttle_ms: u64,
+}
+
+impl Default for ReconnectLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_reconnects: 0,
+            window_ms: 60_000,
+            throttle_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClientState {
+    connects: VecDeque<u64>,
+    throttled_until: Option<u64>,
+}
+
+#[derive(Default)]
+struct Clients {
+    clients: HashMap<(IpAddr, String), ClientState>,
+    /// Idle clients are pruned once per window, not on every connect
+    next_prune_ms: u64,
+}
+
+#[derive(Default)]
+pub struct ReconnectLimiter {
+    cfg: ReconnectLimitConfig,
+    clients: Mutex<Clients>,
+}
+
+impl ReconnectLimiter {
+    pub fn new(cfg: ReconnectLimitConfig) -> Self {
+        Self {
+            cfg,
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    /// Count a connect of the client, return Err with retry after seconds if it is throttled
+    pub fn on_connect(&self, ip: IpAddr, peer: &str, now_ms: u64) -> Result<(), u64> {
+        if self.cfg.max_reconnects == 0 {
+            return Ok(());
+        }
+        let mut clients = self.clients.lock().expect("Should lock reconnect clients");
+        if now_ms >= clients.next_prune_ms {
+            // clients which are idle for a whole window are forgotten
+            let window_ms = self.cfg.window_ms;
+            clients.clients.retain(|_, client| {
+                let throttled = client.throttled_until.is_some_and(|until| now_ms < until);
+                throttled || client.connects.back().is_some_and(|last| now_ms < last + window_ms)
+            });
+            clients.next_prune_ms = now_ms + window_ms;
+        }
+
+        let client = clients.clients.entry((ip, peer.to_string())).or_default();
+        if let Some(until) = client.throttled_until {
+            if now_ms < until {
+                return Err((until - now_ms).div_ceil(1000));
+            }
+            client.throttled_until = None;
+            client.connects.clear();
+        }
+        while client.connects.front().is_some_and(|first| now_ms >= first + self.cfg.window_ms) {
+            client.connects.pop_front();
+        }
+        if client.connects.len() as u32 >= self.cfg.max_reconnects {
+            log::warn!(
+                "[ReconnectLimiter] client {ip} peer {peer} connected {} times in {} ms => throttle",
+                client.connects.len() + 1,
+                self.cfg.window_ms
+            );
+            client.throttled_until = Some(now_ms + self.cfg.throttle_ms);
+            return Err(self.cfg.throttle_ms.div_ceil(1000));
+        }
+        client.connects.push_back(now_ms);
+        Ok(())
+    }
+}
+
+pub fn too_many_reconnects(retry_after_secs: u64) -> poem::Error {
+    poem::Error::from_response(
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("retry-after", retry_after_secs.to_string())
+            .body("Too many reconnects"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{ReconnectLimitConfig, ReconnectLimiter};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn rapid_reconnects_are_throttled() {
+        let limiter = ReconnectLimiter::new(ReconnectLimitConfig {
+            max_reconnects: 3,
+            window_ms: 10_000,
+            throttle_ms: 5_000,
+        });
+        assert_eq!(limiter.on_connect(IP, "peer", 0), Ok(()));
+        assert_eq!(limiter.on_connect(IP, "peer", 100), Ok(()));
+        assert_eq!(limiter.on_connect(IP, "peer", 200), Ok(()));
+        assert_eq!(limiter.on_connect(IP, "peer", 300), Err(5));
+        assert_eq!(limiter.on_connect(IP, "peer", 1_300), Err(4));
+        // other peer is not affected
+        assert_eq!(limiter.on_connect(IP, "peer2", 1_300), Ok(()));
+
+        // after throttle the client starts with a fresh window
+        assert_eq!(limiter.on_connect(IP, "peer", 5_300), Ok(()));
+    }
+
+    #[test]
+    fn network_flaps_are_allowed() {
+        let limiter = ReconnectLimiter::new(ReconnectLimitConfig {
+            max_reconnects: 3,
+            window_ms: 10_000,
+            throttle_ms: 5_000,
+        });
+        for i in 0..10 {
+            assert_eq!(limiter.on_connect(IP, "peer", i * 4_000), Ok(()));
+        }
+
+        // idle clients are pruned once per window
+        assert_eq!(limiter.clients.lock().expect("Should lock").clients.len(), 1);
+        assert_eq!(limiter.on_connect(IP, "peer2", 100_000), Ok(()));
+        assert_eq!(limiter.on_connect(IP, "peer3", 100_001), Ok(()));
+        assert_eq!(limiter.clients.lock().expect("Should lock").clients.len(), 2);
+
+        let disabled = ReconnectLimiter::new(ReconnectLimitConfig::default());
+        for _ in 0..10 {
+            assert_eq!(disabled.on_connect(IP, "peer", 0), Ok(()));
+        }
+    }
+}
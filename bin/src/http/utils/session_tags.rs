@@ -0,0 +1,17 @@
+use media_server_protocol::session_tags::{parse_session_tags_header, SessionTags, SESSION_TAGS_HEADER};
+use poem::{http::StatusCode, FromRequest};
+
+/// Optional session tags from `X-Session-Tags` header, empty if the header is missing
+#[derive(Debug)]
+pub struct SessionTagsHeader(pub SessionTags);
+
+impl<'a> FromRequest<'a> for SessionTagsHeader {
+    async fn from_request(req: &'a poem::Request, _body: &mut poem::RequestBody) -> poem::Result<Self> {
+        let value = match req.headers().get(SESSION_TAGS_HEADER) {
+            Some(value) => value.to_str().map_err(|_| poem::Error::from_string("Bad Request", StatusCode::BAD_REQUEST))?,
+            None => return Ok(SessionTagsHeader(SessionTags::new())),
+        };
+        let tags = parse_session_tags_header(value).map_err(|e| poem::Error::from_string(e.to_string(), StatusCode::BAD_REQUEST))?;
+        Ok(SessionTagsHeader(tags))
+    }
+}
@@ -4,7 +4,7 @@ use atm0s_media_server::{fetch_node_addrs_from_api, server, NodeConfig};
 use atm0s_media_server::{fetch_node_ip_alt_from_cloud, CloudProvider};
 use atm0s_sdn::NodeAddr;
 use clap::Parser;
-use media_server_protocol::cluster::ZoneId;
+use media_server_protocol::cluster::{set_session_id_generator, SessionIdScheme, ZoneId};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 const MAX_ZONE_ID: u32 = 1u32 << 24;
@@ -84,6 +84,11 @@ struct Args {
     #[arg(env, long, default_value_t = 1)]
     workers: usize,
 
+    /// Scheme of generated session ids: `random` or `node-time`. `node-time` ids are sortable by creation time and embed
+    /// the low 12 bits of node_id, they are unique while those bits are unique among nodes.
+    #[arg(env, long, default_value = "random")]
+    session_id_scheme: SessionIdScheme,
+
     /// Sentry error reporting endpoint.
     #[arg(env, long)]
     sentry_endpoint: Option<String>,
@@ -182,6 +187,8 @@ async fn main() {
     };
 
     log::info!("Bind addrs {:?}, bind addrs alt {:?}", node.bind_addrs, node.bind_addrs_alt);
+    log::info!("Session id scheme {}", args.session_id_scheme);
+    set_session_id_generator(args.session_id_scheme.generator(node.node_id));
 
     if let Some(url) = args.seeds_from_url {
         log::info!("Generate seeds from node_api {}", url);
@@ -204,7 +211,11 @@ async fn main() {
                 #[cfg(feature = "connector")]
                 server::ServerType::Connector(args) => server::run_media_connector(workers, http_port, node, args).await,
                 #[cfg(feature = "media")]
-                server::ServerType::Media(args) => server::run_media_server(workers, http_port, node, args).await,
+                server::ServerType::Media(args) => {
+                    if let Err(e) = server::run_media_server(workers, http_port, node, args).await {
+                        log::error!("run media server error {:?}", e);
+                    }
+                }
                 #[cfg(feature = "cert_utils")]
                 server::ServerType::Cert(args) => {
                     if let Err(e) = server::run_cert_utils(args).await {
@@ -8,7 +8,11 @@ use atm0s_sdn::{
 };
 use clap::Parser;
 use media_server_connector::agent_service::ConnectorAgentServiceBuilder;
-use media_server_gateway::{store_service::GatewayStoreServiceBuilder, STORE_SERVICE_ID};
+use media_server_gateway::{
+    route_trace::{LogRouteTraceSink, RouteTraceConfig},
+    store_service::GatewayStoreServiceBuilder,
+    AppResidency, ZoneFallback, STORE_SERVICE_ID,
+};
 use media_server_multi_tenancy::{MultiTenancyStorage, MultiTenancySync};
 use media_server_protocol::{
     cluster::{ClusterGatewayInfo, ClusterNodeGenericInfo, ClusterNodeInfo},
@@ -17,24 +21,36 @@ use media_server_protocol::{
     rpc::quinn::{QuinnClient, QuinnServer},
 };
 use media_server_secure::jwt::{MediaEdgeSecureJwt, MediaGatewaySecureJwt};
+use media_server_utils::now_ms;
 use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
 use std::net::SocketAddr;
 use tokio::sync::mpsc::channel;
 
 use crate::{
-    http::{run_gateway_http_server, NodeApiCtx},
+    http::{run_gateway_http_server, IceServersConfig, NodeApiCtx, ReconnectLimitConfig},
     node_metrics::NodeMetricsCollector,
     quinn::{make_quinn_client, make_quinn_server, VirtualNetwork},
     NodeConfig,
 };
 use sans_io_runtime::{backend::PollingBackend, ErrorDebugger2};
 
-use self::{dest_selector::build_dest_selector, ip_location::Ip2Location, local_rpc_handler::MediaLocalRpcHandler};
+use self::{
+    circuit_breaker::CircuitBreakerConfig,
+    connector_queue::{ConnectorAvailabilityConfig, ConnectorQueue},
+    dest_selector::build_dest_selector,
+    ip_location::Ip2Location,
+    local_rpc_handler::MediaLocalRpcHandler,
+};
 
+mod circuit_breaker;
+mod connector_queue;
 mod dest_selector;
 mod ip_location;
 mod local_rpc_handler;
 mod remote_rpc_handler;
+mod route_registry;
+
+pub use connector_queue::ConnectorOverflowPolicy;
 
 #[derive(Clone, Debug, convert_enum::From, convert_enum::TryInto)]
 enum SC {
@@ -78,6 +94,17 @@ pub struct Args {
     #[arg(env, long, default_value_t = 90)]
     pub max_disk: u8,
 
+    /// Fallback zones in proximity order when the zone nearest to the client has no node, format `<zone>:<zone1>,<zone2>`.
+    /// Multiple zones are separated by `;`, zones without fallback are served by the nearest available zone.
+    #[arg(env, long, value_delimiter = ';')]
+    pub zone_fallback: Vec<ZoneFallback>,
+
+    /// Data residency of apps, format `<app>=<zone1>,<zone2>`, multiple apps are separated by `;`.
+    /// Only session placement is restricted: sessions of these apps are placed on nodes of the listed zones, a client nearest
+    /// to other zone is rejected. Media relay between nodes is not restricted and can transit other zones.
+    #[arg(env, long, value_delimiter = ';')]
+    pub app_residency: Vec<AppResidency>,
+
     /// The port for binding the RTPengine command UDP socket.
     #[arg(env, long)]
     pub rtpengine_cmd_addr: Option<SocketAddr>,
@@ -89,6 +116,70 @@ pub struct Args {
     /// multi-tenancy sync endpoint
     #[arg(env, long, default_value_t = 30_000)]
     pub multi_tenancy_sync_interval_ms: u64,
+
+    /// Max number of route feedback messages waiting for delivering to connector
+    #[arg(env, long, default_value_t = 1024)]
+    pub connector_queue_size: usize,
+
+    /// Which message is dropped when connector queue is full, routing is never blocked by connector delivery
+    #[arg(env, long, default_value = "drop-oldest")]
+    pub connector_queue_overflow: ConnectorOverflowPolicy,
+
+    /// Time in milliseconds without any ack from connector while messages are in flight before entering connector-degraded
+    /// mode, which stops sending route feedback until the connector recovers
+    #[arg(env, long, default_value_t = 10_000)]
+    pub connector_degraded_after_ms: u64,
+
+    /// Interval in milliseconds of probe messages to the connector in connector-degraded mode
+    #[arg(env, long, default_value_t = 5_000)]
+    pub connector_probe_interval_ms: u64,
+
+    /// Consecutive routing timeouts of a node which remove it from selection, 0 disables the circuit breaker
+    #[arg(env, long, default_value_t = 5)]
+    pub breaker_failures: u32,
+
+    /// Window in milliseconds which the consecutive timeouts must be in for tripping the circuit breaker
+    #[arg(env, long, default_value_t = 30_000)]
+    pub breaker_window_ms: u64,
+
+    /// Time in milliseconds a tripped node is not selected, after that one route is sent to it as a probe
+    #[arg(env, long, default_value_t = 10_000)]
+    pub breaker_cooldown_ms: u64,
+
+    /// Fraction of routing decisions (0.0-1.0) which are traced to log target `route_trace` with client location,
+    /// candidate nodes, chosen node and outcome. 0 disables tracing
+    #[arg(env, long, default_value_t = 0.0)]
+    pub route_trace_rate: f32,
+
+    /// ICE servers advertised to WHIP/WHEP clients with `Link` headers in connect responses,
+    /// e.g. `stun:stun.example.net,turn:turn.example.net?transport=udp`.
+    #[arg(env, long, value_delimiter = ',')]
+    pub ice_servers: Vec<String>,
+
+    /// Username of `turn:` and `turns:` servers in `ice_servers`.
+    #[arg(env, long)]
+    pub ice_turn_username: Option<String>,
+
+    /// Credential of `turn:` and `turns:` servers in `ice_servers`.
+    #[arg(env, long)]
+    pub ice_turn_credential: Option<String>,
+
+    /// Connects of a client (remote ip and token peer) allowed inside the reconnect window for network flaps,
+    /// a client which connects more than that is rejected with 429 for a while. 0 disables the limit
+    #[arg(env, long, default_value_t = 10)]
+    pub reconnect_limit: u32,
+
+    /// Window in milliseconds which connects of a client are counted in
+    #[arg(env, long, default_value_t = 60_000)]
+    pub reconnect_limit_window_ms: u64,
+
+    /// Time in milliseconds a client which connects too often is rejected
+    #[arg(env, long, default_value_t = 30_000)]
+    pub reconnect_throttle_ms: u64,
+
+    /// Time in milliseconds a whip connect is given, gateway and media node abort it after that because the client already gave up
+    #[arg(env, long, default_value_t = 10_000)]
+    pub whip_connect_timeout_ms: u64,
 }
 
 pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: NodeConfig, args: Args) {
@@ -97,8 +188,15 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
     let default_cluster_cert = CertificateDer::from(default_cluster_cert_buf.to_vec());
     let default_cluster_key = PrivatePkcs8KeyDer::from(default_cluster_key_buf.to_vec());
 
-    // This tx and rx is for sending event to connector in other tasks
-    let (connector_agent_tx, mut connector_agent_rx) = tokio::sync::mpsc::channel::<media_server_connector::agent_service::Control>(1024);
+    // This queue is for sending event to connector in other tasks
+    let connector_queue = ConnectorQueue::new(
+        args.connector_queue_size,
+        args.connector_queue_overflow,
+        ConnectorAvailabilityConfig {
+            degraded_after_ms: args.connector_degraded_after_ms,
+            probe_interval_ms: args.connector_probe_interval_ms,
+        },
+    );
 
     let edge_secure = Arc::new(MediaEdgeSecureJwt::from(node.secret.as_bytes()));
 
@@ -138,7 +236,19 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
 
     builder.set_authorization(StaticKeyAuthorization::new(&node.secret));
     builder.set_manual_discovery(vec!["gateway".to_string(), generate_gateway_zone_tag(node.zone)], vec!["gateway".to_string()]);
-    builder.add_service(Arc::new(GatewayStoreServiceBuilder::new(node.zone, args.lat, args.lon, args.max_cpu, args.max_memory, args.max_disk)));
+    builder.add_service(Arc::new(GatewayStoreServiceBuilder::new(
+        node.zone,
+        args.lat,
+        args.lon,
+        args.max_cpu,
+        args.max_memory,
+        args.max_disk,
+        args.zone_fallback.clone(),
+        (args.route_trace_rate > 0.0).then(|| RouteTraceConfig {
+            rate: args.route_trace_rate,
+            sink: Arc::new(LogRouteTraceSink),
+        }),
+    )));
     builder.add_service(Arc::new(ConnectorAgentServiceBuilder::new()));
 
     for seed in node.seeds {
@@ -146,7 +256,14 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
     }
 
     let mut controller = builder.build::<PollingBackend<SdnOwner, 128, 128>>(workers, node_info);
-    let (selector, mut requester) = build_dest_selector();
+    let (selector, mut requester) = build_dest_selector(
+        CircuitBreakerConfig {
+            failures: args.breaker_failures,
+            window_ms: args.breaker_window_ms,
+            cooldown_ms: args.breaker_cooldown_ms,
+        },
+        args.app_residency.clone(),
+    );
 
     // Setup HTTP server
     let (req_tx, mut req_rx) = tokio::sync::mpsc::channel(1024);
@@ -155,8 +272,32 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
         let req_tx = req_tx.clone();
         let secure2 = edge_secure.clone();
         let node_ctx = NodeApiCtx { address: node_addr.clone(), dump_tx };
+        let admin_secret = node.secret.clone();
+        let ice_servers = IceServersConfig {
+            urls: args.ice_servers,
+            turn_username: args.ice_turn_username,
+            turn_credential: args.ice_turn_credential,
+        };
+        let reconnect_limit = ReconnectLimitConfig {
+            max_reconnects: args.reconnect_limit,
+            window_ms: args.reconnect_limit_window_ms,
+            throttle_ms: args.reconnect_throttle_ms,
+        };
+        let whip_connect_timeout_ms = args.whip_connect_timeout_ms;
         tokio::spawn(async move {
-            if let Err(e) = run_gateway_http_server(http_port, node_ctx, req_tx, secure2, gateway_secure).await {
+            if let Err(e) = run_gateway_http_server(
+                http_port,
+                node_ctx,
+                req_tx,
+                secure2,
+                gateway_secure,
+                admin_secret,
+                ice_servers,
+                reconnect_limit,
+                whip_connect_timeout_ms,
+            )
+            .await
+            {
                 log::error!("HTTP Error: {}", e);
             }
         });
@@ -179,7 +320,7 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
     let mut media_rpc_server = MediaEdgeServiceServer::new(
         QuinnServer::new(make_quinn_server(media_rpc_socket, default_cluster_key, default_cluster_cert.clone()).expect("Should create endpoint for media rpc server")),
         remote_rpc_handler::Ctx {
-            connector_agent_tx: connector_agent_tx.clone(),
+            connector_queue: connector_queue.clone(),
             selector: selector.clone(),
             client: media_rpc_client.clone(),
             ip2location: ip2location.clone(),
@@ -187,7 +328,7 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
         remote_rpc_handler::MediaRemoteRpcHandlerImpl::default(),
     );
 
-    let local_rpc_processor = Arc::new(MediaLocalRpcHandler::new(connector_agent_tx.clone(), selector, media_rpc_client, ip2location));
+    let local_rpc_processor = Arc::new(MediaLocalRpcHandler::new(connector_queue.clone(), selector, media_rpc_client, ip2location));
 
     tokio::task::spawn_local(async move {
         media_rpc_server.run().await;
@@ -248,7 +389,7 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
                 res_tx.send(res).print_err2("[MediaGateway] answer http request error");
             });
         }
-        while let Ok(control) = connector_agent_rx.try_recv() {
+        while let Some(control) = connector_queue.pop() {
             controller.service_control(media_server_connector::AGENT_SERVICE_ID.into(), (), control.into());
         }
 
@@ -266,9 +407,10 @@ pub async fn run_media_gateway(workers: usize, http_port: Option<u16>, node: Nod
                     }
                     media_server_gateway::store_service::Event::FindNodeRes(req_id, res) => requester.on_find_node_res(req_id, res),
                     media_server_gateway::store_service::Event::FindDestRes(req_id, res) => requester.on_find_dest_res(req_id, res),
+                    media_server_gateway::store_service::Event::ListNodesRes(req_id, nodes, gateways) => requester.on_list_nodes_res(req_id, nodes, gateways),
                 },
                 SdnExtOut::ServicesEvent(_, _, SE::Connector(event)) => match event {
-                    media_server_connector::agent_service::Event::Stats { queue: _, inflight: _, acked: _ } => {}
+                    media_server_connector::agent_service::Event::Stats { queue: _, inflight, acked } => connector_queue.on_stats(now_ms(), inflight, acked),
                     media_server_connector::agent_service::Event::Response(_) => {}
                 },
                 SdnExtOut::FeaturesEvent(_, FeaturesEvent::Socket(event)) => {
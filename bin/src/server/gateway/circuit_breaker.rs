@@ -0,0 +1,186 @@
+//! Per-node circuit breaker of gateway routing. A node which fails `failures` calls in a row within `window_ms`
+//! is removed from selection for `cooldown_ms`, after that one route is sent to it as a probe (half-open).
+//! The probe result closes the breaker or opens it again. Number of open and half-open nodes are live counts
+//! of [`BreakerOpen`] and [`BreakerHalfOpen`] in `/api/metrics/counts`.
+
+use std::collections::HashMap;
+
+use atm0s_sdn::NodeId;
+use media_server_utils::Count;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures which trip the breaker, 0 disables the breaker
+    pub failures: u32,
+    pub window_ms: u64,
+    pub cooldown_ms: u64,
+}
+
+/// Marker type for counting nodes which are removed from selection
+pub struct BreakerOpen;
+/// Marker type for counting nodes which are waiting for the probe result
+pub struct BreakerHalfOpen;
+
+enum NodeState {
+    Closed {
+        failures: u32,
+        first_at: u64,
+    },
+    Open {
+        until: u64,
+        _count: Count<BreakerOpen>,
+    },
+    /// Probe is the time the probe route is selected, a probe without result after cooldown is given up
+    HalfOpen {
+        probe: Option<u64>,
+        _count: Count<BreakerHalfOpen>,
+    },
+}
+
+pub struct CircuitBreaker {
+    cfg: CircuitBreakerConfig,
+    nodes: HashMap<NodeId, NodeState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(cfg: CircuitBreakerConfig) -> Self {
+        Self { cfg, nodes: HashMap::new() }
+    }
+
+    /// Nodes which must not be selected now. Open nodes which are cooled down become half-open and can be selected once
+    pub fn excluded(&mut self, now: u64) -> Vec<NodeId> {
+        let cooldown = self.cfg.cooldown_ms;
+        let mut excluded = vec![];
+        for (node, state) in self.nodes.iter_mut() {
+            match state {
+                NodeState::Closed { .. } => {}
+                NodeState::Open { until, .. } if now < *until => excluded.push(*node),
+                NodeState::Open { .. } => {
+                    log::info!("[CircuitBreaker] node {node} cooled down => half-open");
+                    *state = NodeState::HalfOpen {
+                        probe: None,
+                        _count: Count::default(),
+                    };
+                }
+                NodeState::HalfOpen { probe: Some(started_at), .. } if now < *started_at + cooldown => excluded.push(*node),
+                NodeState::HalfOpen { probe, .. } => *probe = None,
+            }
+        }
+        excluded
+    }
+
+    /// Route is sent to the node, if the node is half-open this route is the probe
+    pub fn on_selected(&mut self, node: NodeId, now: u64) {
+        if let Some(NodeState::HalfOpen { probe, .. }) = self.nodes.get_mut(&node) {
+            if probe.is_none() {
+                log::info!("[CircuitBreaker] probe half-open node {node}");
+                *probe = Some(now);
+            }
+        }
+    }
+
+    /// Success resets failures of a closed node, and closes a half-open node when its probe is sent.
+    /// Late successes of routes which are sent before the breaker opened don't close an open node
+    pub fn on_success(&mut self, node: NodeId) {
+        match self.nodes.get(&node) {
+            Some(NodeState::Closed { .. }) => {
+                self.nodes.remove(&node);
+            }
+            Some(NodeState::HalfOpen { probe: Some(_), .. }) => {
+                log::info!("[CircuitBreaker] probe of node {node} success => closed");
+                self.nodes.remove(&node);
+            }
+            Some(NodeState::Open { .. }) | Some(NodeState::HalfOpen { probe: None, .. }) | None => {}
+        }
+    }
+
+    pub fn on_failure(&mut self, node: NodeId, now: u64) {
+        if self.cfg.failures == 0 {
+            return;
+        }
+        let state = self.nodes.entry(node).or_insert(NodeState::Closed { failures: 0, first_at: now });
+        let trip = match state {
+            NodeState::Closed { failures, first_at } => {
+                if now >= *first_at + self.cfg.window_ms {
+                    *failures = 0;
+                    *first_at = now;
+                }
+                *failures += 1;
+                *failures >= self.cfg.failures
+            }
+            NodeState::Open { .. } => false,
+            NodeState::HalfOpen { .. } => true,
+        };
+        if trip {
+            log::warn!("[CircuitBreaker] node {node} failed => open for {} ms", self.cfg.cooldown_ms);
+            *state = NodeState::Open {
+                until: now + self.cfg.cooldown_ms,
+                _count: Count::default(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_utils::get_all_counts;
+
+    use super::{BreakerOpen, CircuitBreaker, CircuitBreakerConfig};
+
+    const CFG: CircuitBreakerConfig = CircuitBreakerConfig {
+        failures: 3,
+        window_ms: 10_000,
+        cooldown_ms: 5_000,
+    };
+
+    #[test]
+    fn trip_skip_and_recover() {
+        let mut breaker = CircuitBreaker::new(CFG);
+        breaker.on_failure(1, 0);
+        breaker.on_failure(1, 100);
+        assert_eq!(breaker.excluded(200), Vec::<u32>::new());
+
+        // third timeout in window trips the breaker
+        breaker.on_failure(1, 200);
+        assert_eq!(breaker.excluded(300), vec![1]);
+        assert_eq!(get_all_counts().get(std::any::type_name::<BreakerOpen>()), Some(&1));
+        assert_eq!(breaker.excluded(5_100), vec![1]);
+
+        // after cooldown only one probe is sent
+        assert_eq!(breaker.excluded(5_200), Vec::<u32>::new());
+        breaker.on_selected(1, 5_200);
+        assert_eq!(breaker.excluded(5_300), vec![1]);
+
+        // failed probe opens again
+        breaker.on_failure(1, 5_400);
+        assert_eq!(breaker.excluded(10_300), vec![1]);
+
+        // late success of a route which is sent before opened keeps it open
+        breaker.on_success(1);
+        assert_eq!(breaker.excluded(10_350), vec![1]);
+
+        // successful probe closes
+        assert_eq!(breaker.excluded(10_400), Vec::<u32>::new());
+        breaker.on_selected(1, 10_400);
+        breaker.on_success(1);
+        assert_eq!(breaker.excluded(10_500), Vec::<u32>::new());
+        breaker.on_failure(1, 10_600);
+        assert_eq!(breaker.excluded(10_700), Vec::<u32>::new());
+        assert_eq!(get_all_counts().get(std::any::type_name::<BreakerOpen>()), Some(&0));
+    }
+
+    #[test]
+    fn failures_outside_window_not_trip() {
+        let mut breaker = CircuitBreaker::new(CFG);
+        breaker.on_failure(1, 0);
+        breaker.on_failure(1, 5_000);
+        breaker.on_failure(1, 10_000);
+        breaker.on_failure(1, 12_000);
+        assert_eq!(breaker.excluded(12_000), Vec::<u32>::new());
+
+        // success between failures resets the count
+        breaker.on_success(1);
+        breaker.on_failure(1, 12_100);
+        assert_eq!(breaker.excluded(12_200), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,242 @@
+//! Bounded queue for delivering route feedback to the connector agent. Producers are the routing handlers
+//! which must never wait for analytics, so push is sync and when the queue is full a message is dropped
+//! by the configured overflow policy. The queue is drained by the gateway main loop.
+//!
+//! When the connector is unavailable (messages are in flight but none is acked for a while), the queue enters
+//! connector-degraded mode: new messages are dropped without queueing, except one probe message per probe interval,
+//! and it recovers when the connector acks again. Routing is not affected, the mode is reported in health and metrics.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use media_server_connector::agent_service::Control as ConnectorControl;
+use media_server_utils::{Count, DegradedGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConnectorOverflowPolicy {
+    /// Drop the oldest queued message for the new one
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectorAvailabilityConfig {
+    /// Time without any ack while messages are in flight before entering degraded mode
+    pub degraded_after_ms: u64,
+    /// Interval of probe messages in degraded mode
+    pub probe_interval_ms: u64,
+}
+
+/// Degraded state, it is counted in metrics
+struct ConnectorDegraded {
+    next_probe: u64,
+    probe: bool,
+    skipped: u64,
+    _guard: DegradedGuard,
+    _count: Count<ConnectorDegraded>,
+}
+
+struct Inner {
+    queue: VecDeque<ConnectorControl>,
+    max_size: usize,
+    policy: ConnectorOverflowPolicy,
+    dropped: u64,
+    availability: ConnectorAvailabilityConfig,
+    last_acked: usize,
+    last_progress: Option<u64>,
+    degraded: Option<ConnectorDegraded>,
+}
+
+#[derive(Clone)]
+pub struct ConnectorQueue {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ConnectorQueue {
+    pub fn new(max_size: usize, policy: ConnectorOverflowPolicy, availability: ConnectorAvailabilityConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                queue: VecDeque::new(),
+                max_size: max_size.max(1),
+                policy,
+                dropped: 0,
+                availability,
+                last_acked: 0,
+                last_progress: None,
+                degraded: None,
+            })),
+        }
+    }
+
+    pub fn push(&self, control: ConnectorControl) {
+        let mut inner = self.inner.lock().expect("Should lock connector queue");
+        if let Some(degraded) = inner.degraded.as_mut() {
+            if !degraded.probe {
+                degraded.skipped += 1;
+                return;
+            }
+            degraded.probe = false;
+            log::info!("[ConnectorQueue] send probe message to degraded connector");
+        }
+        if inner.queue.len() >= inner.max_size {
+            inner.dropped += 1;
+            // only log sometimes for avoiding log flooding when connector is stuck
+            if inner.dropped % 1000 == 1 {
+                log::warn!("[ConnectorQueue] queue full with {} msgs, policy {:?}, dropped {} msgs", inner.queue.len(), inner.policy, inner.dropped);
+            }
+            match inner.policy {
+                ConnectorOverflowPolicy::DropOldest => {
+                    inner.queue.pop_front();
+                }
+                ConnectorOverflowPolicy::DropNewest => return,
+            }
+        }
+        inner.queue.push_back(control);
+    }
+
+    pub fn pop(&self) -> Option<ConnectorControl> {
+        self.inner.lock().expect("Should lock connector queue").queue.pop_front()
+    }
+
+    /// Update availability with stats of connector agent, which is reported each tick
+    pub fn on_stats(&self, now: u64, inflight: usize, acked: usize) {
+        let mut guard = self.inner.lock().expect("Should lock connector queue");
+        let inner = &mut *guard;
+        if acked != inner.last_acked || inflight == 0 {
+            inner.last_acked = acked;
+            inner.last_progress = Some(now);
+            if let Some(degraded) = inner.degraded.take() {
+                log::info!("[ConnectorQueue] connector available again, skipped {} msgs while degraded", degraded.skipped);
+            }
+            return;
+        }
+
+        let last_progress = *inner.last_progress.get_or_insert(now);
+        let availability = inner.availability;
+        match inner.degraded.as_mut() {
+            Some(degraded) => {
+                if now >= degraded.next_probe {
+                    degraded.next_probe = now + availability.probe_interval_ms;
+                    degraded.probe = true;
+                }
+            }
+            None => {
+                if now >= last_progress + availability.degraded_after_ms {
+                    log::warn!("[ConnectorQueue] no ack from connector for {} ms with {inflight} msgs in flight => degraded", now - last_progress);
+                    inner.queue.clear();
+                    inner.degraded = Some(ConnectorDegraded {
+                        next_probe: now + availability.probe_interval_ms,
+                        probe: false,
+                        skipped: 0,
+                        _guard: DegradedGuard::new("connector"),
+                        _count: Default::default(),
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.inner.lock().expect("Should lock connector queue").degraded.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use media_server_connector::agent_service::Control as ConnectorControl;
+    use media_server_protocol::protobuf::cluster_connector::{
+        connector_request::Request,
+        peer_event::{Event as PeerEvent2, RouteBegin},
+        PeerEvent,
+    };
+
+    use media_server_utils::node_degraded;
+
+    use super::{ConnectorAvailabilityConfig, ConnectorOverflowPolicy, ConnectorQueue};
+
+    const AVAILABILITY: ConnectorAvailabilityConfig = ConnectorAvailabilityConfig {
+        degraded_after_ms: 10_000,
+        probe_interval_ms: 5_000,
+    };
+
+    fn route_begin(ts: u64) -> ConnectorControl {
+        ConnectorControl::Request(
+            ts,
+            Request::Peer(PeerEvent {
+                app: "app".to_string(),
+                session_id: ts,
+                tags: Default::default(),
+                event: Some(PeerEvent2::RouteBegin(RouteBegin { remote_ip: "127.0.0.1".to_string() })),
+            }),
+        )
+    }
+
+    fn drain(queue: &ConnectorQueue) -> Vec<u64> {
+        let mut out = vec![];
+        while let Some(control) = queue.pop() {
+            if let ConnectorControl::Request(ts, _) = control {
+                out.push(ts);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn full_queue_never_blocks_producer() {
+        let queue = ConnectorQueue::new(4, ConnectorOverflowPolicy::DropOldest, AVAILABILITY);
+        let started = Instant::now();
+        for ts in 0..10_000 {
+            queue.push(route_begin(ts));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1), "push should not wait for consumer");
+        assert_eq!(drain(&queue), vec![9996, 9997, 9998, 9999]);
+
+        let queue = ConnectorQueue::new(4, ConnectorOverflowPolicy::DropNewest, AVAILABILITY);
+        for ts in 0..10 {
+            queue.push(route_begin(ts));
+        }
+        assert_eq!(drain(&queue), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn connector_down_degraded_and_recover() {
+        let queue = ConnectorQueue::new(100, ConnectorOverflowPolicy::DropOldest, AVAILABILITY);
+        queue.push(route_begin(0));
+        assert_eq!(drain(&queue), vec![0]);
+
+        // connector is down: message is in flight without ack
+        queue.on_stats(1000, 1, 0);
+        queue.push(route_begin(1));
+        queue.on_stats(10_999, 2, 0);
+        assert!(!queue.is_degraded());
+        queue.on_stats(11_000, 2, 0);
+        assert!(queue.is_degraded());
+        assert_eq!(node_degraded(), vec!["connector"]);
+
+        // routing keeps producing feedback, which is dropped without queueing
+        assert_eq!(drain(&queue), Vec::<u64>::new());
+        for ts in 2..100 {
+            queue.push(route_begin(ts));
+        }
+        assert_eq!(drain(&queue), Vec::<u64>::new());
+
+        // one probe message after probe interval
+        queue.on_stats(16_000, 2, 0);
+        queue.push(route_begin(100));
+        queue.push(route_begin(101));
+        assert_eq!(drain(&queue), vec![100]);
+
+        // connector acked => available again
+        queue.on_stats(17_000, 2, 1);
+        assert!(!queue.is_degraded());
+        assert!(node_degraded().is_empty());
+        queue.push(route_begin(102));
+        assert_eq!(drain(&queue), vec![102]);
+    }
+}
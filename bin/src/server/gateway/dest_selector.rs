@@ -1,29 +1,68 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use atm0s_sdn::NodeId;
-use media_server_gateway::ServiceKind;
-use media_server_protocol::protobuf::cluster_gateway::ping_event::gateway_origin::Location;
+use media_server_gateway::{AppResidency, ServiceKind, ZoneConstraint};
+use media_server_protocol::{cluster::ZoneId, multi_tenancy::AppId, protobuf::cluster_gateway::ping_event::gateway_origin::Location};
+use media_server_utils::now_ms;
 use tokio::sync::{
     mpsc::{channel, Receiver, Sender},
     oneshot,
 };
 
+use super::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+
 enum QueryRequest {
-    Select(ServiceKind, Option<(f32, f32)>, oneshot::Sender<Option<NodeId>>),
+    Select(ServiceKind, Option<(f32, f32)>, Vec<NodeId>, Option<ZoneConstraint>, oneshot::Sender<Option<NodeId>>),
     DestFor(ServiceKind, NodeId, oneshot::Sender<Option<NodeId>>),
+    ListNodes(oneshot::Sender<(Vec<NodeId>, Vec<NodeId>)>),
 }
 
 #[derive(Clone)]
 pub struct GatewayDestSelector {
     tx: Sender<QueryRequest>,
+    breaker: Arc<Mutex<CircuitBreaker>>,
+    residency: Arc<HashMap<AppId, Vec<ZoneId>>>,
 }
 
 impl GatewayDestSelector {
-    /// Select best destination, it can be media-node or other gateway node
-    pub async fn select(&self, kind: ServiceKind, location: Option<(f32, f32)>) -> Option<NodeId> {
+    /// Select best destination for a client of the app, it can be media-node or other gateway node.
+    /// Nodes which are tripped by circuit breaker are skipped. If the app has data residency then only nodes in
+    /// its allowed zones are selected and None is returned when the client is not in one of them
+    pub async fn select_for_app(&self, kind: ServiceKind, app: &AppId, location: Option<(f32, f32)>) -> Option<NodeId> {
+        self.select(kind, location, self.residency.get(app).cloned().map(ZoneConstraint::Client)).await
+    }
+
+    /// Select a node for a session of the app when the client location is not known, like migrate or rtpengine.
+    /// If the app has data residency then only nodes in its allowed zones are selected
+    pub async fn select_in_app_zones(&self, kind: ServiceKind, app: &AppId) -> Option<NodeId> {
+        self.select(kind, None, self.residency.get(app).cloned().map(ZoneConstraint::Nodes)).await
+    }
+
+    /// True if the app has data residency, for reporting why no node is selected
+    pub fn is_restricted(&self, app: &AppId) -> bool {
+        self.residency.contains_key(app)
+    }
+
+    async fn select(&self, kind: ServiceKind, location: Option<(f32, f32)>, constraint: Option<ZoneConstraint>) -> Option<NodeId> {
+        let excluded = self.breaker.lock().expect("Should lock circuit breaker").excluded(now_ms());
         let (tx, rx) = oneshot::channel();
-        self.tx.send(QueryRequest::Select(kind, location, tx)).await.ok()?;
-        rx.await.ok()?
+        self.tx.send(QueryRequest::Select(kind, location, excluded, constraint, tx)).await.ok()?;
+        let node = rx.await.ok()??;
+        self.breaker.lock().expect("Should lock circuit breaker").on_selected(node, now_ms());
+        Some(node)
+    }
+
+    /// Report result of a routing call to a selected node, no response (timeout) is counted as failure by circuit breaker
+    pub fn report(&self, node: NodeId, success: bool) {
+        let mut breaker = self.breaker.lock().expect("Should lock circuit breaker");
+        if success {
+            breaker.on_success(node);
+        } else {
+            breaker.on_failure(node, now_ms());
+        }
     }
 
     /// Find forward dest if we need to send request to a node.
@@ -34,12 +73,20 @@ impl GatewayDestSelector {
         self.tx.send(QueryRequest::DestFor(kind, node, tx)).await.ok()?;
         rx.await.ok()?
     }
+
+    /// List all media nodes in current zone and one gateway for each other zone
+    pub async fn list_nodes(&self) -> Option<(Vec<NodeId>, Vec<NodeId>)> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(QueryRequest::ListNodes(tx)).await.ok()?;
+        rx.await.ok()
+    }
 }
 
 pub struct GatewayDestRequester {
     rx: Receiver<QueryRequest>,
     req_seed: u64,
     reqs: HashMap<u64, oneshot::Sender<Option<u32>>>,
+    list_reqs: HashMap<u64, oneshot::Sender<(Vec<u32>, Vec<u32>)>>,
 }
 
 impl GatewayDestRequester {
@@ -59,9 +106,17 @@ impl GatewayDestRequester {
         }
     }
 
+    pub fn on_list_nodes_res(&mut self, req_id: u64, nodes: Vec<u32>, gateways: Vec<u32>) {
+        if let Some(tx) = self.list_reqs.remove(&req_id) {
+            if tx.send((nodes, gateways)).is_err() {
+                log::error!("[GatewayDestRequester] answer for req_id {req_id} error");
+            }
+        }
+    }
+
     pub fn recv(&mut self) -> Option<media_server_gateway::store_service::Control> {
         match self.rx.try_recv().ok()? {
-            QueryRequest::Select(kind, location, tx) => {
+            QueryRequest::Select(kind, location, excluded, constraint, tx) => {
                 let req_id = self.req_seed;
                 self.req_seed += 1;
                 self.reqs.insert(req_id, tx);
@@ -69,6 +124,8 @@ impl GatewayDestRequester {
                     req_id,
                     kind,
                     location.map(|(lat, lon)| Location { lat, lon }),
+                    excluded,
+                    constraint,
                 ))
             }
             QueryRequest::DestFor(kind, dest, tx) => {
@@ -77,18 +134,29 @@ impl GatewayDestRequester {
                 self.reqs.insert(req_id, tx);
                 Some(media_server_gateway::store_service::Control::FindDestReq(req_id, kind, dest))
             }
+            QueryRequest::ListNodes(tx) => {
+                let req_id = self.req_seed;
+                self.req_seed += 1;
+                self.list_reqs.insert(req_id, tx);
+                Some(media_server_gateway::store_service::Control::ListNodesReq(req_id))
+            }
         }
     }
 }
 
-pub fn build_dest_selector() -> (GatewayDestSelector, GatewayDestRequester) {
+pub fn build_dest_selector(breaker: CircuitBreakerConfig, residency: Vec<AppResidency>) -> (GatewayDestSelector, GatewayDestRequester) {
     let (tx, rx) = channel(100);
     (
-        GatewayDestSelector { tx },
+        GatewayDestSelector {
+            tx,
+            breaker: Arc::new(Mutex::new(CircuitBreaker::new(breaker))),
+            residency: Arc::new(residency.into_iter().map(|r| (r.app, r.zones)).collect()),
+        },
         GatewayDestRequester {
             rx,
             req_seed: 0,
             reqs: HashMap::new(),
+            list_reqs: HashMap::new(),
         },
     )
 }
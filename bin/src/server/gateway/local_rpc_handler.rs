@@ -1,6 +1,7 @@
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
+    time::Instant,
 };
 
 use atm0s_sdn::NodeId;
@@ -8,21 +9,27 @@ use media_server_connector::agent_service::Control as ConnectorControl;
 use media_server_gateway::ServiceKind;
 use media_server_protocol::{
     endpoint::ClusterConnId,
+    endpoint::TrackInfo,
     gateway::GATEWAY_RPC_PORT,
-    multi_tenancy::AppContext,
+    multi_tenancy::{AppContext, AppId},
     protobuf::{
         cluster_connector::peer_event::RouteBegin,
-        cluster_gateway::MediaEdgeServiceClient,
-        gateway::{ConnectRequest, ConnectResponse, RemoteIceRequest, RemoteIceResponse},
+        cluster_gateway::{
+            CloseSessionsRequest, MediaEdgeServiceClient, RoomTracksRequest, RtpEngineCreateAnswerRequest, RtpEngineCreateEgressRequest, RtpEngineCreateOfferRequest, RtpEngineDeleteRequest,
+            WhepCloseRequest, WhepConnectRequest, WhipCloseRequest,
+        },
+        gateway::{ConnectRequest, ConnectResponse, RemoteIceRequest, RemoteIceResponse, ValidateOfferResponse},
     },
     rpc::{
         node_vnet_addr,
         quinn::{QuinnClient, QuinnStream},
     },
+    session_tags::SessionTags,
     transport::{
-        rtpengine::{RtpCreateAnswerRequest, RtpCreateOfferRequest},
-        webrtc,
-        whep::{self, WhepConnectReq, WhepConnectRes, WhepDeleteReq, WhepDeleteRes, WhepRemoteIceReq, WhepRemoteIceRes},
+        admin::{self, CloseSessionsReq, CloseSessionsRes, NodeCloseResult, RoomTracksReq},
+        rtpengine::{RtpCreateAnswerRequest, RtpCreateEgressRequest, RtpCreateOfferRequest},
+        webrtc::{self, SessionDump},
+        whep::{self, WhepConnectReq, WhepConnectRes, WhepDeleteReq, WhepDeleteRes, WhepEventsReq, WhepEventsRes, WhepRemoteIceReq, WhepRemoteIceRes},
         whip::{self, WhipConnectReq, WhipConnectRes, WhipDeleteReq, WhipDeleteRes, WhipRemoteIceReq, WhipRemoteIceRes},
         RpcError, RpcReq, RpcRes, RpcResult,
     },
@@ -34,87 +41,99 @@ use media_server_protocol::{
             peer_event::{route_error::ErrorType, Event as PeerEvent2, RouteError, RouteSuccess},
             PeerEvent,
         },
-        cluster_gateway::WhipConnectRequest,
+        cluster_gateway::{WebrtcValidateResponse, WhipConnectRequest},
     },
     transport::rtpengine,
 };
-use media_server_utils::now_ms;
-use tokio::sync::mpsc::Sender;
+use media_server_utils::{elapsed_ms, now_ms};
 
 use crate::errors::MediaServerError;
 
-use super::{dest_selector::GatewayDestSelector, ip_location::Ip2Location};
+use super::{
+    connector_queue::ConnectorQueue,
+    dest_selector::GatewayDestSelector,
+    ip_location::Ip2Location,
+    route_registry::{RouteCancelled, RouteRegistry},
+};
 
 pub struct MediaLocalRpcHandler {
-    connector_agent_tx: Sender<ConnectorControl>,
+    connector_queue: ConnectorQueue,
     selector: GatewayDestSelector,
     client: MediaEdgeServiceClient<SocketAddr, QuinnClient, QuinnStream>,
     ip2location: Arc<Ip2Location>,
+    routes: RouteRegistry,
 }
 
 impl MediaLocalRpcHandler {
-    async fn feedback_route_begin(&self, app: &str, session_id: u64, ip: IpAddr) {
-        self.connector_agent_tx
-            .send(ConnectorControl::Request(
-                now_ms(),
-                ConnectorRequest::Peer(PeerEvent {
-                    app: app.to_owned(),
-                    session_id,
-                    event: Some(PeerEvent2::RouteBegin(RouteBegin { remote_ip: ip.to_string() })),
-                }),
-            ))
-            .await
-            .expect("Should send");
+    fn feedback_route_begin(&self, app: &str, session_id: u64, ip: IpAddr, tags: &SessionTags) {
+        self.connector_queue.push(ConnectorControl::Request(
+            now_ms(),
+            ConnectorRequest::Peer(PeerEvent {
+                app: app.to_owned(),
+                session_id,
+                tags: tags.clone(),
+                event: Some(PeerEvent2::RouteBegin(RouteBegin { remote_ip: ip.to_string() })),
+            }),
+        ));
     }
 
-    async fn feedback_route_success(&self, app: &str, session_id: u64, after_ms: u64, node: NodeId) {
-        self.connector_agent_tx
-            .send(ConnectorControl::Request(
-                now_ms(),
-                ConnectorRequest::Peer(PeerEvent {
-                    app: app.to_owned(),
-                    session_id,
-                    event: Some(PeerEvent2::RouteSuccess(RouteSuccess {
-                        after_ms: after_ms as u32,
-                        dest_node: node,
-                    })),
-                }),
-            ))
-            .await
-            .expect("Should send");
+    fn feedback_route_success(&self, app: &str, session_id: u64, after_ms: u64, node: NodeId) {
+        self.connector_queue.push(ConnectorControl::Request(
+            now_ms(),
+            ConnectorRequest::Peer(PeerEvent {
+                app: app.to_owned(),
+                session_id,
+                tags: Default::default(),
+                event: Some(PeerEvent2::RouteSuccess(RouteSuccess {
+                    after_ms: after_ms as u32,
+                    dest_node: node,
+                })),
+            }),
+        ));
     }
 
-    async fn feedback_route_error(&self, app: &str, session_id: u64, after_ms: u64, node: Option<NodeId>, error: ErrorType) {
-        self.connector_agent_tx
-            .send(ConnectorControl::Request(
-                now_ms(),
-                ConnectorRequest::Peer(PeerEvent {
-                    app: app.to_owned(),
-                    session_id,
-                    event: Some(PeerEvent2::RouteError(RouteError {
-                        after_ms: after_ms as u32,
-                        dest_node: node,
-                        error: error as i32,
-                    })),
-                }),
-            ))
-            .await
-            .expect("Should send");
+    fn feedback_route_error(&self, app: &str, session_id: u64, after_ms: u64, node: Option<NodeId>, error: ErrorType) {
+        self.connector_queue.push(ConnectorControl::Request(
+            now_ms(),
+            ConnectorRequest::Peer(PeerEvent {
+                app: app.to_owned(),
+                session_id,
+                tags: Default::default(),
+                event: Some(PeerEvent2::RouteError(RouteError {
+                    after_ms: after_ms as u32,
+                    dest_node: node,
+                    error: error as i32,
+                })),
+            }),
+        ));
+    }
+
+    /// Route is cancelled by admin while waiting for the node, the edge session is released by the route cleanup
+    fn route_cancelled(&self, app: &str, session_id: u64, started_at: Instant, node: NodeId) -> RpcError {
+        log::warn!("[Gateway] route of session {session_id} to node {node} cancelled");
+        self.feedback_route_error(app, session_id, elapsed_ms(started_at, Instant::now()), Some(node), ErrorType::Cancelled);
+        RpcError::new2(MediaServerError::RouteCancelled)
+    }
+
+    /// Error when no node is selected for a session of the app, with data residency it means the client is not in allowed zones
+    /// or these zones have no node
+    fn no_node_error(&self, app: &AppId) -> RpcError {
+        if self.selector.is_restricted(app) {
+            RpcError::new2(MediaServerError::RegionNotAllowed)
+        } else {
+            RpcError::new2(MediaServerError::NodePoolEmpty)
+        }
     }
 }
 
 impl MediaLocalRpcHandler {
-    pub fn new(
-        connector_agent_tx: Sender<ConnectorControl>,
-        selector: GatewayDestSelector,
-        client: MediaEdgeServiceClient<SocketAddr, QuinnClient, QuinnStream>,
-        ip2location: Arc<Ip2Location>,
-    ) -> Self {
+    pub fn new(connector_queue: ConnectorQueue, selector: GatewayDestSelector, client: MediaEdgeServiceClient<SocketAddr, QuinnClient, QuinnStream>, ip2location: Arc<Ip2Location>) -> Self {
         Self {
-            connector_agent_tx,
+            connector_queue,
             selector,
             client,
             ip2location,
+            routes: RouteRegistry::default(),
         }
     }
 
@@ -129,6 +148,7 @@ impl MediaLocalRpcHandler {
                 whep::RpcReq::Connect(param) => RpcRes::Whep(whep::RpcRes::Connect(self.whep_connect(param).await)),
                 whep::RpcReq::RemoteIce(param) => RpcRes::Whep(whep::RpcRes::RemoteIce(self.whep_remote_ice(conn_part, param).await)),
                 whep::RpcReq::Delete(param) => RpcRes::Whep(whep::RpcRes::Delete(self.whep_delete(conn_part, param).await)),
+                whep::RpcReq::Events(param) => RpcRes::Whep(whep::RpcRes::Events(self.whep_events(conn_part, param).await)),
             },
             RpcReq::Webrtc(param) => match param {
                 webrtc::RpcReq::Connect(app, session_id, ip, user_agent, param, extra_data, record) => {
@@ -142,14 +162,63 @@ impl MediaLocalRpcHandler {
                     //TODO implement delete webrtc conn
                     RpcRes::Webrtc(webrtc::RpcRes::RestartIce(Err(RpcError::new2(MediaServerError::NotImplemented))))
                 }
+                webrtc::RpcReq::Migrate(conn, app, dest) => RpcRes::Webrtc(webrtc::RpcRes::Migrate(self.webrtc_migrate(conn_part, conn, app, dest).await)),
+                webrtc::RpcReq::Dump(conn) => RpcRes::Webrtc(webrtc::RpcRes::Dump(self.webrtc_dump(conn_part, conn).await)),
+                webrtc::RpcReq::Validate(app, ip, req) => RpcRes::Webrtc(webrtc::RpcRes::Validate(self.webrtc_validate(app, ip, req).await)),
             },
             RpcReq::RtpEngine(param) => match param {
                 rtpengine::RpcReq::CreateOffer(param) => RpcRes::RtpEngine(rtpengine::RpcRes::CreateOffer(self.rtpengine_create_offer(param).await)),
                 rtpengine::RpcReq::SetAnswer(conn, param) => RpcRes::RtpEngine(rtpengine::RpcRes::SetAnswer(self.rtpengine_set_answer(conn_part, conn, param.sdp).await)),
                 rtpengine::RpcReq::CreateAnswer(param) => RpcRes::RtpEngine(rtpengine::RpcRes::CreateAnswer(self.rtpengine_create_answer(param).await)),
+                rtpengine::RpcReq::CreateEgress(param) => RpcRes::RtpEngine(rtpengine::RpcRes::CreateEgress(self.rtpengine_create_egress(param).await)),
                 rtpengine::RpcReq::Delete(param) => RpcRes::RtpEngine(rtpengine::RpcRes::Delete(self.rtpengine_delete(conn_part, param).await)),
             },
+            RpcReq::Admin(param) => match param {
+                admin::RpcReq::CloseSessions(param) => RpcRes::Admin(admin::RpcRes::CloseSessions(self.close_sessions(param).await)),
+                admin::RpcReq::RoomTracks(param) => RpcRes::Admin(admin::RpcRes::RoomTracks(self.room_tracks(param).await)),
+                admin::RpcReq::PendingRoutes => RpcRes::Admin(admin::RpcRes::PendingRoutes(Ok(self.routes.list()))),
+                admin::RpcReq::CancelRoute(session_id) => RpcRes::Admin(admin::RpcRes::CancelRoute(Ok(self.routes.cancel(session_id)))),
+            },
+        }
+    }
+
+    /*
+        Admin part
+    */
+
+    /// Send close request to all media nodes in current zone and to a gateway of each other zone.
+    /// Unreachable nodes don't fail the whole request, they are reported in per-node results instead
+    async fn close_sessions(&self, param: CloseSessionsReq) -> RpcResult<CloseSessionsRes> {
+        let (nodes, gateways) = self.selector.list_nodes().await.ok_or(RpcError::new2(MediaServerError::GatewayRpcError))?;
+        log::info!("[Gateway] close sessions of app {} room {:?} on nodes {nodes:?} and gateways {gateways:?}", param.app, param.room);
+        let rpc_req: CloseSessionsRequest = param.into();
+        let mut res = CloseSessionsRes::default();
+        for node in nodes.into_iter().chain(gateways) {
+            let sock_addr = node_vnet_addr(node, GATEWAY_RPC_PORT);
+            let closed = match self.client.close_sessions(sock_addr, rpc_req.clone()).await {
+                Some(node_res) => Ok(node_res.closed),
+                None => {
+                    log::warn!("[Gateway] close sessions on node {node} failed");
+                    Err(RpcError::new2(MediaServerError::GatewayRpcError))
+                }
+            };
+            res.nodes.push(NodeCloseResult { node, closed });
         }
+        Ok(res)
+    }
+
+    /// Tracks map is shared over the cluster, so any media node can answer it
+    async fn room_tracks(&self, param: RoomTracksReq) -> RpcResult<Vec<TrackInfo>> {
+        let node_id = self
+            .selector
+            .select_in_app_zones(ServiceKind::Webrtc, &param.app.app)
+            .await
+            .ok_or_else(|| self.no_node_error(&param.app.app))?;
+        log::info!("[Gateway] query tracks of app {} room {} on node {node_id}", param.app, param.room);
+        let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
+        let rpc_req: RoomTracksRequest = param.into();
+        let res = self.client.room_tracks(sock_addr, rpc_req).await.ok_or(RpcError::new2(MediaServerError::GatewayRpcError))?;
+        Ok(res.tracks.iter().filter_map(|data| TrackInfo::deserialize(data)).collect())
     }
 
     /*
@@ -158,31 +227,55 @@ impl MediaLocalRpcHandler {
 
     async fn whip_connect(&self, param: WhipConnectReq) -> RpcResult<WhipConnectRes<ClusterConnId>> {
         let session_id = param.session_id;
-        let started_at = now_ms();
-        self.feedback_route_begin(&param.app.app, session_id, param.ip).await;
+        let started_at = Instant::now();
+        let mut route = self.routes.begin(session_id, &param.app.app, "whip");
+        self.feedback_route_begin(&param.app.app, session_id, param.ip, &param.tags);
+
+        if param.is_expired(now_ms()) {
+            log::warn!("[Gateway] whip connect deadline {:?} exceeded before routing", param.deadline_ms);
+            self.feedback_route_error(&param.app.app, session_id, 0, None, ErrorType::Timeout);
+            return Err(RpcError::new2(MediaServerError::NodeTimeout));
+        }
 
-        if let Some(node_id) = self.selector.select(ServiceKind::Webrtc, self.ip2location.get_location(&param.ip)).await {
+        if let Some(node_id) = self.selector.select_for_app(ServiceKind::Webrtc, &param.app.app, self.ip2location.get_location(&param.ip)).await {
             let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
             log::info!("[Gateway] selected node {node_id}");
             let mut rpc_req: WhipConnectRequest = param.clone().into();
             rpc_req.session_id = session_id;
 
-            let res = self.client.whip_connect(sock_addr, rpc_req).await;
+            route.set_dest(node_id);
+            let (client, cleanup_client) = (self.client.clone(), self.client.clone());
+            let remaining = param.remaining(now_ms());
+            let res = route
+                .call_within(remaining, async move { client.whip_connect(sock_addr, rpc_req).await }, move |res| async move {
+                    log::info!("[Gateway] close whip conn {} which is created after route cancelled or timed out", res.conn);
+                    cleanup_client.whip_close(sock_addr, WhipCloseRequest { conn: res.conn }).await;
+                })
+                .await;
+            let res = match res {
+                Ok(res) => res,
+                Err(RouteCancelled) => return Err(self.route_cancelled(&param.app.app, session_id, started_at, node_id)),
+            };
             log::info!("[Gateway] response from node {node_id} => {:?}", res);
+            self.selector.report(node_id, res.is_some());
             if let Some(res) = res {
-                self.feedback_route_success(&param.app.app, session_id, now_ms() - started_at, node_id).await;
+                self.feedback_route_success(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
 
                 Ok(whip::WhipConnectRes {
                     sdp: res.sdp,
                     conn_id: res.conn.parse().unwrap(),
                 })
+            } else if param.is_expired(now_ms()) {
+                log::warn!("[Gateway] whip connect deadline {:?} exceeded while waiting node {node_id}", param.deadline_ms);
+                self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
+                Err(RpcError::new2(MediaServerError::NodeTimeout))
             } else {
-                self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 Err(RpcError::new2(MediaServerError::GatewayRpcError))
             }
         } else {
-            self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
-            Err(RpcError::new2(MediaServerError::NodePoolEmpty))
+            self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
+            Err(self.no_node_error(&param.app.app))
         }
     }
 
@@ -226,28 +319,42 @@ impl MediaLocalRpcHandler {
     */
 
     async fn whep_connect(&self, param: WhepConnectReq) -> RpcResult<WhepConnectRes<ClusterConnId>> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = param.session_id;
-        self.feedback_route_begin(&param.app.app, session_id, param.ip).await;
+        let mut route = self.routes.begin(session_id, &param.app.app, "whep");
+        self.feedback_route_begin(&param.app.app, session_id, param.ip, &param.tags);
 
-        if let Some(node_id) = self.selector.select(ServiceKind::Webrtc, self.ip2location.get_location(&param.ip)).await {
+        if let Some(node_id) = self.selector.select_for_app(ServiceKind::Webrtc, &param.app.app, self.ip2location.get_location(&param.ip)).await {
             let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
             log::info!("[Gateway] selected node {node_id}");
-            let res = self.client.whep_connect(sock_addr, param.clone().into()).await;
+            route.set_dest(node_id);
+            let rpc_req: WhepConnectRequest = param.clone().into();
+            let (client, cleanup_client) = (self.client.clone(), self.client.clone());
+            let res = route
+                .call(async move { client.whep_connect(sock_addr, rpc_req).await }, move |res| async move {
+                    log::info!("[Gateway] close whep conn {} which is created after route cancelled or timed out", res.conn);
+                    cleanup_client.whep_close(sock_addr, WhepCloseRequest { conn: res.conn }).await;
+                })
+                .await;
+            let res = match res {
+                Ok(res) => res,
+                Err(RouteCancelled) => return Err(self.route_cancelled(&param.app.app, session_id, started_at, node_id)),
+            };
             log::info!("[Gateway] response from node {node_id} => {:?}", res);
+            self.selector.report(node_id, res.is_some());
             if let Some(res) = res {
-                self.feedback_route_success(&param.app.app, session_id, now_ms() - started_at, node_id).await;
+                self.feedback_route_success(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Ok(whep::WhepConnectRes {
                     sdp: res.sdp,
                     conn_id: res.conn.parse().unwrap(),
                 })
             } else {
-                self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 Err(RpcError::new2(MediaServerError::GatewayRpcError))
             }
         } else {
-            self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
-            Err(RpcError::new2(MediaServerError::NodePoolEmpty))
+            self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
+            Err(self.no_node_error(&param.app.app))
         }
     }
 
@@ -286,6 +393,26 @@ impl MediaLocalRpcHandler {
         }
     }
 
+    async fn whep_events(&self, conn_part: Option<(NodeId, u64)>, param: WhepEventsReq<ClusterConnId>) -> RpcResult<WhepEventsRes> {
+        if let Some((node, _session)) = conn_part {
+            let rpc_req = media_server_protocol::protobuf::cluster_gateway::WhepEventsRequest {
+                conn: param.conn_id.to_string(),
+                initial: param.initial,
+            };
+            let sock_addr = node_vnet_addr(node, GATEWAY_RPC_PORT);
+            let res = self.client.whep_events(sock_addr, rpc_req).await;
+            if let Some(res) = res {
+                Ok(whep::WhepEventsRes {
+                    events: res.events.into_iter().filter_map(|e| e.try_into().ok()).collect(),
+                })
+            } else {
+                Err(RpcError::new2(MediaServerError::GatewayRpcError))
+            }
+        } else {
+            Err(RpcError::new2(MediaServerError::InvalidConnId))
+        }
+    }
+
     /*
     Webrtc part
     */
@@ -301,10 +428,11 @@ impl MediaLocalRpcHandler {
         extra_data: Option<String>,
         record: bool,
     ) -> RpcResult<(ClusterConnId, ConnectResponse)> {
-        let started_at = now_ms();
-        self.feedback_route_begin(&app.app, session_id, ip).await;
+        let started_at = Instant::now();
+        let mut route = self.routes.begin(session_id, &app.app, "webrtc");
+        self.feedback_route_begin(&app.app, session_id, ip, &req.tags);
 
-        if let Some(node_id) = self.selector.select(ServiceKind::Webrtc, self.ip2location.get_location(&ip)).await {
+        if let Some(node_id) = self.selector.select_for_app(ServiceKind::Webrtc, &app.app, self.ip2location.get_location(&ip)).await {
             let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
             log::info!("[Gateway] selected node {node_id}");
             let rpc_req = media_server_protocol::protobuf::cluster_gateway::WebrtcConnectRequest {
@@ -316,28 +444,43 @@ impl MediaLocalRpcHandler {
                 record,
                 extra_data,
             };
-            let res = self.client.webrtc_connect(sock_addr, rpc_req).await;
+            route.set_dest(node_id);
+            let client = self.client.clone();
+            let res = route
+                .call(async move { client.webrtc_connect(sock_addr, rpc_req).await }, move |res| async move {
+                    // webrtc conn can't be closed over rpc, the client never gets the answer so it is released by ice timeout
+                    log::info!(
+                        "[Gateway] webrtc conn {:?} is created after route cancelled or timed out => wait ice timeout",
+                        res.res.map(|res| res.conn_id)
+                    );
+                })
+                .await;
+            let res = match res {
+                Ok(res) => res,
+                Err(RouteCancelled) => return Err(self.route_cancelled(&app.app, session_id, started_at, node_id)),
+            };
             log::info!("[Gateway] response from node {node_id} => {:?}", res);
+            self.selector.report(node_id, res.is_some());
             if let Some(res) = res {
                 if let Some(res) = res.res {
                     if let Ok(conn) = res.conn_id.parse() {
-                        self.feedback_route_success(&app.app, session_id, now_ms() - started_at, node_id).await;
+                        self.feedback_route_success(&app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                         Ok((conn, res))
                     } else {
-                        self.feedback_route_error(&app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::MediaError).await;
+                        self.feedback_route_error(&app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::MediaError);
                         Err(RpcError::new2(MediaServerError::MediaResError))
                     }
                 } else {
-                    self.feedback_route_error(&app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::GatewayError).await;
+                    self.feedback_route_error(&app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::GatewayError);
                     Err(RpcError::new2(MediaServerError::GatewayRpcError))
                 }
             } else {
-                self.feedback_route_error(&app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                self.feedback_route_error(&app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 Err(RpcError::new2(MediaServerError::NodeTimeout))
             }
         } else {
-            self.feedback_route_error(&app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
-            Err(RpcError::new2(MediaServerError::NodePoolEmpty))
+            self.feedback_route_error(&app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
+            Err(self.no_node_error(&app.app))
         }
     }
 
@@ -369,20 +512,20 @@ impl MediaLocalRpcHandler {
         let (node, _session) = conn_part.ok_or(RpcError::new2(MediaServerError::InvalidConnId))?;
         let dest = match self.selector.dest_for(ServiceKind::Webrtc, node).await {
             Some(dest) => dest,
-            None => match self.selector.select(ServiceKind::Webrtc, self.ip2location.get_location(&ip)).await {
+            None => match self.selector.select_for_app(ServiceKind::Webrtc, &app.app, self.ip2location.get_location(&ip)).await {
                 Some(dest) => {
                     log::warn!("[Gateway] not found dest {node} found other node {dest} for restart-ice (reconnect to other server)");
                     dest
                 }
                 None => {
                     log::warn!("[Gateway] node pool empty for restart-ice to dest {node}");
-                    return RpcResult::Err(RpcError::new2(MediaServerError::NodePoolEmpty));
+                    return RpcResult::Err(self.no_node_error(&app.app));
                 }
             },
         };
         log::info!("[Gateway] selected dest node {dest} with provided node {node}");
         let rpc_req = media_server_protocol::protobuf::cluster_gateway::WebrtcRestartIceRequest {
-            app: Some(app.into()),
+            app: Some(app.clone().into()),
             conn: conn.to_string(),
             ip: ip.to_string(),
             user_agent,
@@ -393,35 +536,133 @@ impl MediaLocalRpcHandler {
         let sock_addr = node_vnet_addr(dest, GATEWAY_RPC_PORT);
         let res = self.client.webrtc_restart_ice(sock_addr, rpc_req).await;
         let res = res.ok_or(RpcError::new2(MediaServerError::GatewayRpcError))?;
+        if let (Some(from), Some(session_id)) = (res.migrated_from, conn.migrate_session) {
+            self.close_migrated_session(from, app, session_id).await;
+        }
         let res = res.res.ok_or(RpcError::new2(MediaServerError::MediaResError))?;
         Ok((res.conn_id.parse().unwrap(), res))
     }
 
+    /// Close the old session as soon as the migrated one is created in the new node, so client doesn't keep
+    /// both sessions until the go-away timeout. Failures are only logged, old node still closes it after the timeout
+    async fn close_migrated_session(&self, from: NodeId, app: AppContext, session_id: u64) {
+        let via = match self.selector.dest_for(ServiceKind::Webrtc, from).await {
+            Some(via) => via,
+            None => {
+                log::warn!("[Gateway] not found route to node {from} for closing migrated session {session_id}");
+                return;
+            }
+        };
+        let rpc_req = CloseSessionsRequest {
+            app: Some(app.into()),
+            room: None,
+            migrated_session: Some(session_id),
+        };
+        let sock_addr = node_vnet_addr(via, GATEWAY_RPC_PORT);
+        match self.client.close_sessions(sock_addr, rpc_req).await {
+            Some(res) => log::info!("[Gateway] closed migrated session {session_id} on node {from} via {via} => {} closed", res.closed),
+            None => log::warn!("[Gateway] close migrated session {session_id} on node {from} via {via} failed"),
+        }
+    }
+
+    /// Ask the node which is holding the session to move it to `dest`, or a node selected by gateway if not provided.
+    /// Return the conn id which client will use for restart-ice to the new node.
+    async fn webrtc_migrate(&self, conn_part: Option<(NodeId, u64)>, conn: ClusterConnId, app: AppContext, dest: Option<NodeId>) -> RpcResult<ClusterConnId> {
+        let (node, _session) = conn_part.ok_or(RpcError::new2(MediaServerError::InvalidConnId))?;
+        let dest = match dest {
+            Some(dest) => dest,
+            None => self.selector.select_in_app_zones(ServiceKind::Webrtc, &app.app).await.ok_or_else(|| self.no_node_error(&app.app))?,
+        };
+        if dest == node {
+            log::warn!("[Gateway] migrate conn {conn} to same node {dest} => reject");
+            return Err(RpcError::new2(MediaServerError::InvalidMigrateDest));
+        }
+        let via = self.selector.dest_for(ServiceKind::Webrtc, node).await.ok_or(RpcError::new2(MediaServerError::GatewayRpcError))?;
+        log::info!("[Gateway] migrate conn {conn} from node {node} to {dest} via {via}");
+        let rpc_req = media_server_protocol::protobuf::cluster_gateway::WebrtcMigrateRequest {
+            conn: conn.to_string(),
+            dest_node: dest,
+        };
+        let sock_addr = node_vnet_addr(via, GATEWAY_RPC_PORT);
+        let res = self.client.webrtc_migrate(sock_addr, rpc_req).await;
+        let res = res.ok_or(RpcError::new2(MediaServerError::GatewayRpcError))?;
+        res.conn.parse().map_err(|_| RpcError::new2(MediaServerError::MediaResError))
+    }
+
+    /// Diagnostic state of a session, asked from the node which is holding it
+    async fn webrtc_dump(&self, conn_part: Option<(NodeId, u64)>, conn: ClusterConnId) -> RpcResult<SessionDump> {
+        let (node, _session) = conn_part.ok_or(RpcError::new2(MediaServerError::InvalidConnId))?;
+        let via = self.selector.dest_for(ServiceKind::Webrtc, node).await.ok_or(RpcError::new2(MediaServerError::GatewayRpcError))?;
+        log::info!("[Gateway] dump conn {conn} on node {node} via {via}");
+        let rpc_req = media_server_protocol::protobuf::cluster_gateway::WebrtcDumpRequest { conn: conn.to_string() };
+        let sock_addr = node_vnet_addr(via, GATEWAY_RPC_PORT);
+        let res = self.client.webrtc_dump(sock_addr, rpc_req).await;
+        res.map(SessionDump::from).ok_or(RpcError::new2(MediaServerError::GatewayRpcError))
+    }
+
+    /// Dry-run of connect on a node which would be selected for it, no session or route is created
+    async fn webrtc_validate(&self, app: AppContext, ip: IpAddr, req: ConnectRequest) -> RpcResult<ValidateOfferResponse> {
+        let node_id = self
+            .selector
+            .select_for_app(ServiceKind::Webrtc, &app.app, self.ip2location.get_location(&ip))
+            .await
+            .ok_or_else(|| self.no_node_error(&app.app))?;
+        log::info!("[Gateway] validate offer on node {node_id}");
+        let rpc_req = media_server_protocol::protobuf::cluster_gateway::WebrtcValidateRequest {
+            app: Some(app.into()),
+            ip: ip.to_string(),
+            req: Some(req),
+        };
+        let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
+        let res = self.client.webrtc_validate(sock_addr, rpc_req).await;
+        self.selector.report(node_id, res.is_some());
+        match res {
+            Some(WebrtcValidateResponse { res: Some(res), .. }) => Ok(res),
+            Some(WebrtcValidateResponse { error: Some(e), .. }) => Err(e.into()),
+            Some(_) => Err(RpcError::new2(MediaServerError::MediaResError)),
+            None => Err(RpcError::new2(MediaServerError::NodeTimeout)),
+        }
+    }
+
     /*
         RtpEngine part
     */
 
     async fn rtpengine_create_offer(&self, param: RtpCreateOfferRequest) -> RpcResult<(ClusterConnId, String)> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = param.session_id;
+        let mut route = self.routes.begin(session_id, &param.app.app, "rtpengine");
         // TODO get remote ip
-        self.feedback_route_begin(&param.app.app, session_id, IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        self.feedback_route_begin(&param.app.app, session_id, IpAddr::V4(Ipv4Addr::LOCALHOST), &SessionTags::new());
 
-        if let Some(node_id) = self.selector.select(ServiceKind::RtpEngine, None).await {
+        if let Some(node_id) = self.selector.select_in_app_zones(ServiceKind::RtpEngine, &param.app.app).await {
             let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
             log::info!("[Gateway] selected node {node_id}");
-            let res = self.client.rtp_engine_create_offer(sock_addr, param.clone().into()).await;
+            route.set_dest(node_id);
+            let rpc_req: RtpEngineCreateOfferRequest = param.clone().into();
+            let (client, cleanup_client) = (self.client.clone(), self.client.clone());
+            let res = route
+                .call(async move { client.rtp_engine_create_offer(sock_addr, rpc_req).await }, move |res| async move {
+                    log::info!("[Gateway] delete rtpengine conn {} which is created after route cancelled or timed out", res.conn);
+                    cleanup_client.rtp_engine_delete(sock_addr, RtpEngineDeleteRequest { conn: res.conn }).await;
+                })
+                .await;
+            let res = match res {
+                Ok(res) => res,
+                Err(RouteCancelled) => return Err(self.route_cancelled(&param.app.app, session_id, started_at, node_id)),
+            };
             log::info!("[Gateway] response from node {node_id} => {:?}", res);
+            self.selector.report(node_id, res.is_some());
             if let Some(res) = res {
-                self.feedback_route_success(&param.app.app, session_id, now_ms() - started_at, node_id).await;
+                self.feedback_route_success(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Ok((res.conn.parse().unwrap(), res.sdp))
             } else {
-                self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 Err(RpcError::new2(MediaServerError::GatewayRpcError))
             }
         } else {
-            self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
-            Err(RpcError::new2(MediaServerError::NodePoolEmpty))
+            self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
+            Err(self.no_node_error(&param.app.app))
         }
     }
 
@@ -442,26 +683,77 @@ impl MediaLocalRpcHandler {
     }
 
     async fn rtpengine_create_answer(&self, param: RtpCreateAnswerRequest) -> RpcResult<(ClusterConnId, String)> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = param.session_id;
+        let mut route = self.routes.begin(session_id, &param.app.app, "rtpengine");
         // TODO get remote ip
-        self.feedback_route_begin(&param.app.app, session_id, IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        self.feedback_route_begin(&param.app.app, session_id, IpAddr::V4(Ipv4Addr::LOCALHOST), &SessionTags::new());
 
-        if let Some(node_id) = self.selector.select(ServiceKind::RtpEngine, None).await {
+        if let Some(node_id) = self.selector.select_in_app_zones(ServiceKind::RtpEngine, &param.app.app).await {
             let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
             log::info!("[Gateway] selected node {node_id}");
-            let res = self.client.rtp_engine_create_answer(sock_addr, param.clone().into()).await;
+            route.set_dest(node_id);
+            let rpc_req: RtpEngineCreateAnswerRequest = param.clone().into();
+            let (client, cleanup_client) = (self.client.clone(), self.client.clone());
+            let res = route
+                .call(async move { client.rtp_engine_create_answer(sock_addr, rpc_req).await }, move |res| async move {
+                    log::info!("[Gateway] delete rtpengine conn {} which is created after route cancelled or timed out", res.conn);
+                    cleanup_client.rtp_engine_delete(sock_addr, RtpEngineDeleteRequest { conn: res.conn }).await;
+                })
+                .await;
+            let res = match res {
+                Ok(res) => res,
+                Err(RouteCancelled) => return Err(self.route_cancelled(&param.app.app, session_id, started_at, node_id)),
+            };
+            log::info!("[Gateway] response from node {node_id} => {:?}", res);
+            self.selector.report(node_id, res.is_some());
+            if let Some(res) = res {
+                self.feedback_route_success(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
+                Ok((res.conn.parse().unwrap(), res.sdp))
+            } else {
+                self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
+                Err(RpcError::new2(MediaServerError::GatewayRpcError))
+            }
+        } else {
+            self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
+            Err(self.no_node_error(&param.app.app))
+        }
+    }
+
+    async fn rtpengine_create_egress(&self, param: RtpCreateEgressRequest) -> RpcResult<(ClusterConnId, String)> {
+        let started_at = Instant::now();
+        let session_id = param.session_id;
+        let mut route = self.routes.begin(session_id, &param.app.app, "rtpengine");
+        self.feedback_route_begin(&param.app.app, session_id, IpAddr::V4(Ipv4Addr::LOCALHOST), &SessionTags::new());
+
+        if let Some(node_id) = self.selector.select_in_app_zones(ServiceKind::RtpEngine, &param.app.app).await {
+            let sock_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
+            log::info!("[Gateway] selected node {node_id} for egress to {}", param.dest);
+            route.set_dest(node_id);
+            let rpc_req: RtpEngineCreateEgressRequest = param.clone().into();
+            let (client, cleanup_client) = (self.client.clone(), self.client.clone());
+            let res = route
+                .call(async move { client.rtp_engine_create_egress(sock_addr, rpc_req).await }, move |res| async move {
+                    log::info!("[Gateway] delete rtp egress conn {} which is created after route cancelled or timed out", res.conn);
+                    cleanup_client.rtp_engine_delete(sock_addr, RtpEngineDeleteRequest { conn: res.conn }).await;
+                })
+                .await;
+            let res = match res {
+                Ok(res) => res,
+                Err(RouteCancelled) => return Err(self.route_cancelled(&param.app.app, session_id, started_at, node_id)),
+            };
             log::info!("[Gateway] response from node {node_id} => {:?}", res);
+            self.selector.report(node_id, res.is_some());
             if let Some(res) = res {
-                self.feedback_route_success(&param.app.app, session_id, now_ms() - started_at, node_id).await;
+                self.feedback_route_success(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Ok((res.conn.parse().unwrap(), res.sdp))
             } else {
-                self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 Err(RpcError::new2(MediaServerError::GatewayRpcError))
             }
         } else {
-            self.feedback_route_error(&param.app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
-            Err(RpcError::new2(MediaServerError::NodePoolEmpty))
+            self.feedback_route_error(&param.app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
+            Err(self.no_node_error(&param.app.app))
         }
     }
 
@@ -1,6 +1,7 @@
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
+    time::Instant,
 };
 
 use atm0s_sdn::NodeId;
@@ -17,26 +18,28 @@ use media_server_protocol::{
             PeerEvent,
         },
         cluster_gateway::{
-            MediaEdgeServiceClient, MediaEdgeServiceHandler, RtpEngineCreateAnswerRequest, RtpEngineCreateAnswerResponse, RtpEngineCreateOfferRequest, RtpEngineCreateOfferResponse,
-            RtpEngineDeleteRequest, RtpEngineDeleteResponse, RtpEngineSetAnswerRequest, RtpEngineSetAnswerResponse, WebrtcConnectRequest, WebrtcConnectResponse, WebrtcRemoteIceRequest,
-            WebrtcRemoteIceResponse, WebrtcRestartIceRequest, WebrtcRestartIceResponse, WhepCloseRequest, WhepCloseResponse, WhepConnectRequest, WhepConnectResponse, WhepRemoteIceRequest,
-            WhepRemoteIceResponse, WhipCloseRequest, WhipCloseResponse, WhipConnectRequest, WhipConnectResponse, WhipRemoteIceRequest, WhipRemoteIceResponse,
+            CloseSessionsRequest, CloseSessionsResponse, MediaEdgeServiceClient, MediaEdgeServiceHandler, RoomTracksRequest, RoomTracksResponse, RtpEngineCreateAnswerRequest,
+            RtpEngineCreateAnswerResponse, RtpEngineCreateEgressRequest, RtpEngineCreateEgressResponse, RtpEngineCreateOfferRequest, RtpEngineCreateOfferResponse, RtpEngineDeleteRequest,
+            RtpEngineDeleteResponse, RtpEngineSetAnswerRequest, RtpEngineSetAnswerResponse, WebrtcConnectRequest, WebrtcConnectResponse, WebrtcDumpRequest, WebrtcDumpResponse, WebrtcMigrateRequest,
+            WebrtcMigrateResponse, WebrtcRemoteIceRequest, WebrtcRemoteIceResponse, WebrtcRestartIceRequest, WebrtcRestartIceResponse, WebrtcValidateRequest, WebrtcValidateResponse, WhepCloseRequest,
+            WhepCloseResponse, WhepConnectRequest, WhepConnectResponse, WhepEventsRequest, WhepEventsResponse, WhepRemoteIceRequest, WhepRemoteIceResponse, WhipCloseRequest, WhipCloseResponse,
+            WhipConnectRequest, WhipConnectResponse, WhipRemoteIceRequest, WhipRemoteIceResponse,
         },
     },
     rpc::{
         node_vnet_addr,
         quinn::{QuinnClient, QuinnStream},
     },
+    session_tags::SessionTags,
     transport::ConnLayer,
 };
-use media_server_utils::now_ms;
-use tokio::sync::mpsc::Sender;
+use media_server_utils::{elapsed_ms, now_ms};
 
-use super::{dest_selector::GatewayDestSelector, ip_location::Ip2Location};
+use super::{connector_queue::ConnectorQueue, dest_selector::GatewayDestSelector, ip_location::Ip2Location};
 
 #[derive(Clone)]
 pub struct Ctx {
-    pub(crate) connector_agent_tx: Sender<media_server_connector::agent_service::Control>,
+    pub(crate) connector_queue: ConnectorQueue,
     pub(crate) selector: GatewayDestSelector,
     pub(crate) client: MediaEdgeServiceClient<SocketAddr, QuinnClient, QuinnStream>,
     pub(crate) ip2location: Arc<Ip2Location>,
@@ -46,75 +49,76 @@ pub struct Ctx {
 pub struct MediaRemoteRpcHandlerImpl {}
 
 impl MediaRemoteRpcHandlerImpl {
-    async fn feedback_route_begin(ctx: &Ctx, app: &str, session_id: u64, remote_ip: String) {
-        ctx.connector_agent_tx
-            .send(ConnectorControl::Request(
-                now_ms(),
-                ConnectorRequest::Peer(PeerEvent {
-                    app: app.to_owned(),
-                    session_id,
-                    event: Some(PeerEvent2::RouteBegin(RouteBegin { remote_ip })),
-                }),
-            ))
-            .await
-            .expect("Should send");
+    fn feedback_route_begin(ctx: &Ctx, app: &str, session_id: u64, remote_ip: String, tags: &SessionTags) {
+        ctx.connector_queue.push(ConnectorControl::Request(
+            now_ms(),
+            ConnectorRequest::Peer(PeerEvent {
+                app: app.to_owned(),
+                session_id,
+                tags: tags.clone(),
+                event: Some(PeerEvent2::RouteBegin(RouteBegin { remote_ip })),
+            }),
+        ));
     }
 
-    async fn feedback_route_success(ctx: &Ctx, app: &str, session_id: u64, after_ms: u64, node: NodeId) {
-        ctx.connector_agent_tx
-            .send(ConnectorControl::Request(
-                now_ms(),
-                ConnectorRequest::Peer(PeerEvent {
-                    app: app.to_owned(),
-                    session_id,
-                    event: Some(PeerEvent2::RouteSuccess(RouteSuccess {
-                        after_ms: after_ms as u32,
-                        dest_node: node,
-                    })),
-                }),
-            ))
-            .await
-            .expect("Should send");
+    fn feedback_route_success(ctx: &Ctx, app: &str, session_id: u64, after_ms: u64, node: NodeId) {
+        ctx.connector_queue.push(ConnectorControl::Request(
+            now_ms(),
+            ConnectorRequest::Peer(PeerEvent {
+                app: app.to_owned(),
+                session_id,
+                tags: Default::default(),
+                event: Some(PeerEvent2::RouteSuccess(RouteSuccess {
+                    after_ms: after_ms as u32,
+                    dest_node: node,
+                })),
+            }),
+        ));
     }
 
-    async fn feedback_route_error(ctx: &Ctx, app: &str, session_id: u64, after_ms: u64, node: Option<NodeId>, error: ErrorType) {
-        ctx.connector_agent_tx
-            .send(ConnectorControl::Request(
-                now_ms(),
-                ConnectorRequest::Peer(PeerEvent {
-                    app: app.to_owned(),
-                    session_id,
-                    event: Some(PeerEvent2::RouteError(RouteError {
-                        after_ms: after_ms as u32,
-                        dest_node: node,
-                        error: error as i32,
-                    })),
-                }),
-            ))
-            .await
-            .expect("Should send");
+    fn feedback_route_error(ctx: &Ctx, app: &str, session_id: u64, after_ms: u64, node: Option<NodeId>, error: ErrorType) {
+        ctx.connector_queue.push(ConnectorControl::Request(
+            now_ms(),
+            ConnectorRequest::Peer(PeerEvent {
+                app: app.to_owned(),
+                session_id,
+                tags: Default::default(),
+                event: Some(PeerEvent2::RouteError(RouteError {
+                    after_ms: after_ms as u32,
+                    dest_node: node,
+                    error: error as i32,
+                })),
+            }),
+        ));
     }
 }
 
 impl MediaEdgeServiceHandler<Ctx> for MediaRemoteRpcHandlerImpl {
     async fn whip_connect(&self, ctx: &Ctx, req: WhipConnectRequest) -> Option<WhipConnectResponse> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = req.session_id;
         log::info!("On whip_connect from other gateway");
         let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
-        Self::feedback_route_begin(ctx, &app.app, session_id, req.ip.clone()).await;
+        Self::feedback_route_begin(ctx, &app.app, session_id, req.ip.clone(), &req.tags);
+        if req.deadline_ms.map(|deadline| now_ms() >= deadline).unwrap_or(false) {
+            log::warn!("On whip_connect from other gateway with deadline {:?} exceeded => abort", req.deadline_ms);
+            Self::feedback_route_error(ctx, &app.app, session_id, 0, None, ErrorType::Timeout);
+            return None;
+        }
         let location = req.ip.parse().ok().and_then(|ip| ctx.ip2location.get_location(&ip));
-        if let Some(node_id) = ctx.selector.select(ServiceKind::Webrtc, location).await {
+        if let Some(node_id) = ctx.selector.select_for_app(ServiceKind::Webrtc, &app.app, location).await {
             let node_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
-            if let Some(res) = ctx.client.whip_connect(node_addr, req).await {
-                Self::feedback_route_success(ctx, &app.app, session_id, now_ms() - started_at, node_id).await;
+            let res = ctx.client.whip_connect(node_addr, req).await;
+            ctx.selector.report(node_id, res.is_some());
+            if let Some(res) = res {
+                Self::feedback_route_success(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Some(res)
             } else {
-                Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 None
             }
         } else {
-            Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
+            Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
             None
         }
     }
@@ -136,23 +140,25 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRemoteRpcHandlerImpl {
     }
 
     async fn whep_connect(&self, ctx: &Ctx, req: WhepConnectRequest) -> Option<WhepConnectResponse> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = req.session_id;
         log::info!("On whep_connect from other gateway");
         let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
-        Self::feedback_route_begin(ctx, &app.app, session_id, req.ip.clone()).await;
+        Self::feedback_route_begin(ctx, &app.app, session_id, req.ip.clone(), &req.tags);
         let location = req.ip.parse().ok().and_then(|ip| ctx.ip2location.get_location(&ip));
-        if let Some(node_id) = ctx.selector.select(ServiceKind::Webrtc, location).await {
+        if let Some(node_id) = ctx.selector.select_for_app(ServiceKind::Webrtc, &app.app, location).await {
             let dest_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
-            if let Some(res) = ctx.client.whep_connect(dest_addr, req).await {
-                Self::feedback_route_success(ctx, &app.app, session_id, now_ms() - started_at, node_id).await;
+            let res = ctx.client.whep_connect(dest_addr, req).await;
+            ctx.selector.report(node_id, res.is_some());
+            if let Some(res) = res {
+                Self::feedback_route_success(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Some(res)
             } else {
-                Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 None
             }
         } else {
-            Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
+            Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
             None
         }
     }
@@ -173,24 +179,35 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRemoteRpcHandlerImpl {
         ctx.client.whep_close(dest_addr, req).await
     }
 
+    async fn whep_events(&self, ctx: &Ctx, req: WhepEventsRequest) -> Option<WhepEventsResponse> {
+        log::debug!("On whep_events from other gateway");
+        let conn: ClusterConnId = req.conn.parse().ok()?;
+        let (dest, _session) = conn.get_down_part();
+        let dest_addr = node_vnet_addr(dest, GATEWAY_RPC_PORT);
+        ctx.client.whep_events(dest_addr, req).await
+    }
+
     async fn webrtc_connect(&self, ctx: &Ctx, req: WebrtcConnectRequest) -> Option<WebrtcConnectResponse> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = req.session_id;
         let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
         log::info!("On webrtc_connect from other gateway");
-        Self::feedback_route_begin(ctx, &app.app, session_id, req.ip.clone()).await;
+        let tags = req.req.as_ref().map(|r| r.tags.clone()).unwrap_or_default();
+        Self::feedback_route_begin(ctx, &app.app, session_id, req.ip.clone(), &tags);
         let location = req.ip.parse().ok().and_then(|ip| ctx.ip2location.get_location(&ip));
-        if let Some(node_id) = ctx.selector.select(ServiceKind::Webrtc, location).await {
+        if let Some(node_id) = ctx.selector.select_for_app(ServiceKind::Webrtc, &app.app, location).await {
             let dest_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
-            if let Some(res) = ctx.client.webrtc_connect(dest_addr, req).await {
-                Self::feedback_route_success(ctx, &app.app, session_id, now_ms() - started_at, node_id).await;
+            let res = ctx.client.webrtc_connect(dest_addr, req).await;
+            ctx.selector.report(node_id, res.is_some());
+            if let Some(res) = res {
+                Self::feedback_route_success(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Some(res)
             } else {
-                Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 None
             }
         } else {
-            Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
+            Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
             None
         }
     }
@@ -211,25 +228,54 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRemoteRpcHandlerImpl {
         ctx.client.webrtc_restart_ice(dest_addr, req).await
     }
 
+    async fn webrtc_migrate(&self, ctx: &Ctx, req: WebrtcMigrateRequest) -> Option<WebrtcMigrateResponse> {
+        log::info!("On webrtc_migrate from other gateway");
+        let conn: ClusterConnId = req.conn.parse().ok()?;
+        let (dest, _session) = conn.get_down_part();
+        let dest_addr = node_vnet_addr(dest, GATEWAY_RPC_PORT);
+        ctx.client.webrtc_migrate(dest_addr, req).await
+    }
+
+    async fn webrtc_dump(&self, ctx: &Ctx, req: WebrtcDumpRequest) -> Option<WebrtcDumpResponse> {
+        log::info!("On webrtc_dump from other gateway");
+        let conn: ClusterConnId = req.conn.parse().ok()?;
+        let (dest, _session) = conn.get_down_part();
+        let dest_addr = node_vnet_addr(dest, GATEWAY_RPC_PORT);
+        ctx.client.webrtc_dump(dest_addr, req).await
+    }
+
+    async fn webrtc_validate(&self, ctx: &Ctx, req: WebrtcValidateRequest) -> Option<WebrtcValidateResponse> {
+        log::info!("On webrtc_validate from other gateway");
+        let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
+        let location = req.ip.parse().ok().and_then(|ip| ctx.ip2location.get_location(&ip));
+        let node_id = ctx.selector.select_for_app(ServiceKind::Webrtc, &app.app, location).await?;
+        let dest_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
+        let res = ctx.client.webrtc_validate(dest_addr, req).await;
+        ctx.selector.report(node_id, res.is_some());
+        res
+    }
+
     async fn rtp_engine_create_offer(&self, ctx: &Ctx, req: RtpEngineCreateOfferRequest) -> Option<RtpEngineCreateOfferResponse> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = req.session_id;
         log::info!("On rtp_engine_connect from other gateway");
         let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
         // TODO get ip
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
-        Self::feedback_route_begin(ctx, &app.app, session_id, ip.to_string()).await;
-        if let Some(node_id) = ctx.selector.select(ServiceKind::Webrtc, None).await {
+        Self::feedback_route_begin(ctx, &app.app, session_id, ip.to_string(), &SessionTags::new());
+        if let Some(node_id) = ctx.selector.select_in_app_zones(ServiceKind::Webrtc, &app.app).await {
             let dest_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
-            if let Some(res) = ctx.client.rtp_engine_create_offer(dest_addr, req).await {
-                Self::feedback_route_success(ctx, &app.app, session_id, now_ms() - started_at, node_id).await;
+            let res = ctx.client.rtp_engine_create_offer(dest_addr, req).await;
+            ctx.selector.report(node_id, res.is_some());
+            if let Some(res) = res {
+                Self::feedback_route_success(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Some(res)
             } else {
-                Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 None
             }
         } else {
-            Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
+            Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
             None
         }
     }
@@ -243,24 +289,49 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRemoteRpcHandlerImpl {
     }
 
     async fn rtp_engine_create_answer(&self, ctx: &Ctx, req: RtpEngineCreateAnswerRequest) -> Option<RtpEngineCreateAnswerResponse> {
-        let started_at = now_ms();
+        let started_at = Instant::now();
         let session_id = req.session_id;
         let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
         log::info!("On rtp_engine_connect from other gateway");
         // TODO get ip
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
-        Self::feedback_route_begin(ctx, &app.app, session_id, ip.to_string()).await;
-        if let Some(node_id) = ctx.selector.select(ServiceKind::Webrtc, None).await {
+        Self::feedback_route_begin(ctx, &app.app, session_id, ip.to_string(), &SessionTags::new());
+        if let Some(node_id) = ctx.selector.select_in_app_zones(ServiceKind::Webrtc, &app.app).await {
             let dest_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
-            if let Some(res) = ctx.client.rtp_engine_create_answer(dest_addr, req).await {
-                Self::feedback_route_success(ctx, &app.app, session_id, now_ms() - started_at, node_id).await;
+            let res = ctx.client.rtp_engine_create_answer(dest_addr, req).await;
+            ctx.selector.report(node_id, res.is_some());
+            if let Some(res) = res {
+                Self::feedback_route_success(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
                 Some(res)
             } else {
-                Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, Some(node_id), ErrorType::Timeout).await;
+                Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
                 None
             }
         } else {
-            Self::feedback_route_error(ctx, &app.app, session_id, now_ms() - started_at, None, ErrorType::PoolEmpty).await;
+            Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
+            None
+        }
+    }
+
+    async fn rtp_engine_create_egress(&self, ctx: &Ctx, req: RtpEngineCreateEgressRequest) -> Option<RtpEngineCreateEgressResponse> {
+        let started_at = Instant::now();
+        let session_id = req.session_id;
+        let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
+        log::info!("On rtp_engine_create_egress from other gateway");
+        Self::feedback_route_begin(ctx, &app.app, session_id, IpAddr::V4(Ipv4Addr::LOCALHOST).to_string(), &SessionTags::new());
+        if let Some(node_id) = ctx.selector.select_in_app_zones(ServiceKind::RtpEngine, &app.app).await {
+            let dest_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
+            let res = ctx.client.rtp_engine_create_egress(dest_addr, req).await;
+            ctx.selector.report(node_id, res.is_some());
+            if let Some(res) = res {
+                Self::feedback_route_success(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), node_id);
+                Some(res)
+            } else {
+                Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), Some(node_id), ErrorType::Timeout);
+                None
+            }
+        } else {
+            Self::feedback_route_error(ctx, &app.app, session_id, elapsed_ms(started_at, Instant::now()), None, ErrorType::PoolEmpty);
             None
         }
     }
@@ -272,6 +343,30 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRemoteRpcHandlerImpl {
         let dest_addr = node_vnet_addr(dest, GATEWAY_RPC_PORT);
         ctx.client.rtp_engine_delete(dest_addr, req).await
     }
+
+    /// Other gateway only fan out to media nodes in its zone, which avoids forwarding loops between zones
+    async fn close_sessions(&self, ctx: &Ctx, req: CloseSessionsRequest) -> Option<CloseSessionsResponse> {
+        log::info!("On close_sessions from other gateway");
+        let (nodes, _gateways) = ctx.selector.list_nodes().await?;
+        let mut closed = 0;
+        for node in nodes {
+            let dest_addr = node_vnet_addr(node, GATEWAY_RPC_PORT);
+            if let Some(res) = ctx.client.close_sessions(dest_addr, req.clone()).await {
+                closed += res.closed;
+            } else {
+                log::warn!("[Gateway] close sessions on node {node} failed");
+            }
+        }
+        Some(CloseSessionsResponse { closed })
+    }
+
+    async fn room_tracks(&self, ctx: &Ctx, req: RoomTracksRequest) -> Option<RoomTracksResponse> {
+        log::info!("On room_tracks from other gateway");
+        let app = req.app.clone().map(|a| a.into()).unwrap_or_else(AppContext::root_app);
+        let node_id = ctx.selector.select_in_app_zones(ServiceKind::Webrtc, &app.app).await?;
+        let dest_addr = node_vnet_addr(node_id, GATEWAY_RPC_PORT);
+        ctx.client.room_tracks(dest_addr, req).await
+    }
 }
 
 //TODO test
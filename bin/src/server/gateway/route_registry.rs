@@ -0,0 +1,284 @@
+//! In-flight routing attempts of this gateway, keyed by session id. Admin can list them for finding a stuck
+//! destination node and cancel one, the handler which owns the route then aborts and reports `RouteError(Cancelled)`.
+//! A cancelled edge call is kept running in background, if the edge still creates the session it is released
+//! by the cleanup of the handler, so cancelling never leaks an edge session.
+//!
+//! An entry is removed when its handler finishes or is dropped. Rpc calls have no deadline, so an edge which
+//! never answers would keep the handler and its entry forever; calls are given up after [`DEFAULT_ROUTE_TIMEOUT`]
+//! and treated as no response, with the same background cleanup as cancelling.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn::NodeId;
+use media_server_protocol::{multi_tenancy::AppId, transport::admin::PendingRoute};
+use media_server_utils::elapsed_ms;
+use tokio::sync::oneshot;
+
+struct RouteSlot {
+    /// Distinguish retries with same session id, only the owner guard removes the slot
+    seq: u64,
+    app: AppId,
+    kind: &'static str,
+    dest_node: Option<NodeId>,
+    started_at: Instant,
+    cancel_tx: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Inner {
+    seq: u64,
+    routes: HashMap<u64, RouteSlot>,
+}
+
+/// Max time which a route waits for the edge answer
+pub const DEFAULT_ROUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct RouteRegistry {
+    inner: Arc<Mutex<Inner>>,
+    timeout: Duration,
+}
+
+impl Default for RouteRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_ROUTE_TIMEOUT)
+    }
+}
+
+/// Route was cancelled by admin before the destination answered
+#[derive(Debug, PartialEq, Eq)]
+pub struct RouteCancelled;
+
+impl RouteRegistry {
+    pub fn new(timeout: Duration) -> Self {
+        Self { inner: Default::default(), timeout }
+    }
+
+    /// Register a route, it is in the registry until the returned guard is dropped
+    pub fn begin(&self, session_id: u64, app: &AppId, kind: &'static str) -> RouteGuard {
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let mut inner = self.inner.lock().expect("Should lock route registry");
+        inner.seq += 1;
+        let seq = inner.seq;
+        let slot = RouteSlot {
+            seq,
+            app: app.clone(),
+            kind,
+            dest_node: None,
+            started_at: Instant::now(),
+            cancel_tx,
+        };
+        if inner.routes.insert(session_id, slot).is_some() {
+            log::warn!("[RouteRegistry] session {session_id} is routed again while previous route is pending");
+        }
+        RouteGuard {
+            registry: self.clone(),
+            session_id,
+            seq,
+            cancel_rx,
+        }
+    }
+
+    pub fn list(&self) -> Vec<PendingRoute> {
+        let now = Instant::now();
+        let inner = self.inner.lock().expect("Should lock route registry");
+        let mut routes = inner
+            .routes
+            .iter()
+            .map(|(session_id, slot)| PendingRoute {
+                session_id: *session_id,
+                app: slot.app.clone(),
+                kind: slot.kind.to_string(),
+                dest_node: slot.dest_node,
+                elapsed_ms: elapsed_ms(slot.started_at, now),
+            })
+            .collect::<Vec<_>>();
+        routes.sort_by_key(|route| std::cmp::Reverse(route.elapsed_ms));
+        routes
+    }
+
+    /// Cancel the pending route of session, return false if there is no such route
+    pub fn cancel(&self, session_id: u64) -> bool {
+        let slot = self.inner.lock().expect("Should lock route registry").routes.remove(&session_id);
+        match slot {
+            Some(slot) => {
+                log::warn!(
+                    "[RouteRegistry] cancel route of session {session_id} to {:?} after {} ms",
+                    slot.dest_node,
+                    elapsed_ms(slot.started_at, Instant::now())
+                );
+                slot.cancel_tx.send(()).is_ok()
+            }
+            None => false,
+        }
+    }
+
+    fn set_dest(&self, session_id: u64, seq: u64, node: NodeId) {
+        let mut inner = self.inner.lock().expect("Should lock route registry");
+        if let Some(slot) = inner.routes.get_mut(&session_id).filter(|slot| slot.seq == seq) {
+            slot.dest_node = Some(node);
+        }
+    }
+
+    fn remove(&self, session_id: u64, seq: u64) {
+        let mut inner = self.inner.lock().expect("Should lock route registry");
+        if inner.routes.get(&session_id).is_some_and(|slot| slot.seq == seq) {
+            inner.routes.remove(&session_id);
+        }
+    }
+}
+
+pub struct RouteGuard {
+    registry: RouteRegistry,
+    session_id: u64,
+    seq: u64,
+    cancel_rx: oneshot::Receiver<()>,
+}
+
+impl RouteGuard {
+    pub fn set_dest(&self, node: NodeId) {
+        self.registry.set_dest(self.session_id, self.seq, node);
+    }
+
+    /// Wait for the edge call unless the route is cancelled or timed out. When cancelled, the call continues in background
+    /// and `cleanup` is called with the late response for releasing the created edge session. A timed out call is
+    /// handled in same way and returns `Ok(None)`, same as the edge didn't answer.
+    pub async fn call<Res, C, CF>(&mut self, call: impl Future<Output = Option<Res>> + Send + 'static, cleanup: C) -> Result<Option<Res>, RouteCancelled>
+    where
+        Res: Send + 'static,
+        C: FnOnce(Res) -> CF + Send + 'static,
+        CF: Future<Output = ()> + Send,
+    {
+        self.call_within(None, call, cleanup).await
+    }
+
+    /// Same as [`RouteGuard::call`] but gives up at `limit` if it is shorter than the route timeout, for requests
+    /// which carry a client deadline.
+    pub async fn call_within<Res, C, CF>(&mut self, limit: Option<Duration>, call: impl Future<Output = Option<Res>> + Send + 'static, cleanup: C) -> Result<Option<Res>, RouteCancelled>
+    where
+        Res: Send + 'static,
+        C: FnOnce(Res) -> CF + Send + 'static,
+        CF: Future<Output = ()> + Send,
+    {
+        let timeout = limit.map_or(self.registry.timeout, |limit| limit.min(self.registry.timeout));
+        let mut call = tokio::spawn(call);
+        let res = tokio::select! {
+            res = &mut call => return Ok(res.ok().flatten()),
+            Ok(()) = &mut self.cancel_rx => Err(RouteCancelled),
+            _ = tokio::time::sleep(timeout) => {
+                log::warn!("[RouteRegistry] route of session {} timed out after {:?}", self.session_id, timeout);
+                Ok(None)
+            }
+        };
+        tokio::spawn(async move {
+            if let Ok(Some(res)) = call.await {
+                cleanup(res).await;
+            }
+        });
+        res
+    }
+}
+
+impl Drop for RouteGuard {
+    fn drop(&mut self) {
+        self.registry.remove(self.session_id, self.seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use media_server_protocol::multi_tenancy::AppId;
+    use tokio::sync::oneshot;
+
+    use super::{RouteCancelled, RouteRegistry, DEFAULT_ROUTE_TIMEOUT};
+
+    #[tokio::test]
+    async fn cancel_pending_route() {
+        let registry = RouteRegistry::default();
+        let mut route = registry.begin(100, &AppId::root_app(), "whip");
+        route.set_dest(5);
+
+        let listed = registry.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].session_id, listed[0].kind.as_str(), listed[0].dest_node), (100, "whip", Some(5)));
+
+        let (edge_tx, edge_rx) = oneshot::channel::<&str>();
+        let (cleanup_tx, cleanup_rx) = oneshot::channel();
+        let registry2 = registry.clone();
+        tokio::spawn(async move {
+            tokio::task::yield_now().await;
+            assert!(registry2.cancel(100));
+        });
+        let res = route
+            .call(async move { edge_rx.await.ok() }, move |conn| async move {
+                cleanup_tx.send(conn).expect("Should send cleanup");
+            })
+            .await;
+        assert_eq!(res, Err(RouteCancelled));
+        assert_eq!(registry.list(), vec![]);
+        assert!(!registry.cancel(100));
+
+        // edge answers after cancelled, the created session must be released
+        edge_tx.send("conn-1").expect("Should send edge response");
+        assert_eq!(cleanup_rx.await, Ok("conn-1"));
+    }
+
+    #[tokio::test]
+    async fn finished_route_is_removed() {
+        let registry = RouteRegistry::default();
+        let mut route = registry.begin(100, &AppId::root_app(), "whep");
+        let res = route.call(async { Some(1) }, |_: i32| async {}).await;
+        assert_eq!(res, Ok(Some(1)));
+        drop(route);
+        assert_eq!(registry.list(), vec![]);
+        assert!(!registry.cancel(100));
+    }
+
+    #[tokio::test]
+    async fn timed_out_route_is_removed_and_cleaned() {
+        let registry = RouteRegistry::new(Duration::from_millis(50));
+        let mut route = registry.begin(100, &AppId::root_app(), "whip");
+
+        let (edge_tx, edge_rx) = oneshot::channel::<&str>();
+        let (cleanup_tx, cleanup_rx) = oneshot::channel();
+        let res = route
+            .call(async move { edge_rx.await.ok() }, move |conn| async move {
+                cleanup_tx.send(conn).expect("Should send cleanup");
+            })
+            .await;
+        assert_eq!(res, Ok(None));
+        drop(route);
+        assert_eq!(registry.list(), vec![]);
+
+        // edge answers after timeout, the created session must be released
+        edge_tx.send("conn-1").expect("Should send edge response");
+        assert_eq!(cleanup_rx.await, Ok("conn-1"));
+    }
+
+    #[tokio::test]
+    async fn route_gives_up_at_client_deadline() {
+        let registry = RouteRegistry::default();
+        let mut route = registry.begin(100, &AppId::root_app(), "whip");
+
+        let (edge_tx, edge_rx) = oneshot::channel::<&str>();
+        let (cleanup_tx, cleanup_rx) = oneshot::channel();
+        let started = Instant::now();
+        let res = route
+            .call_within(Some(Duration::from_millis(50)), async move { edge_rx.await.ok() }, move |conn| async move {
+                cleanup_tx.send(conn).expect("Should send cleanup");
+            })
+            .await;
+        assert_eq!(res, Ok(None));
+        assert!(started.elapsed() < DEFAULT_ROUTE_TIMEOUT);
+
+        edge_tx.send("conn-1").expect("Should send edge response");
+        assert_eq!(cleanup_rx.await, Ok("conn-1"));
+    }
+}
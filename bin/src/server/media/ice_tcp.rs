@@ -0,0 +1,179 @@
+//! ICE-TCP listeners of media workers. Workers are sans-io and only handle udp sockets through the runtime, so tcp
+//! connections are handled here: streams are unframed into packets which are sent on the channel of the worker which
+//! owns the listener, the worker polls it directly and writes its packets to the connection which is found by its
+//! local and remote addresses, so packets don't wait for the controller loop.
+//!
+//! Connections are capped per node and closed when no packet is received within the idle timeout, so clients can't
+//! hold connections without doing ICE.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use media_server_runner::{ice_tcp_frame, IceTcpDecoder, IceTcpPacket};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    time::Instant,
+};
+
+const EVENT_QUEUE: usize = 1024;
+const CONN_QUEUE: usize = 256;
+const READ_BUF: usize = 4096;
+/// Accept errors like running out of file descriptors persist for a while, so retries back off up to the max
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+enum Event {
+    Connected(SocketAddr, SocketAddr, Sender<Vec<u8>>),
+    Packet(IceTcpPacket),
+    Closed(SocketAddr, SocketAddr),
+}
+
+/// Listeners of all workers, connections of them are counted together
+pub struct IceTcpServer {
+    permits: Arc<Semaphore>,
+    idle_timeout: Duration,
+}
+
+impl IceTcpServer {
+    pub fn new(max_connections: usize, idle_timeout: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_connections)),
+            idle_timeout,
+        }
+    }
+
+    /// Listen on `addrs` for the worker, return the bound addresses which are advertised in candidates and the
+    /// worker side which is polled by the worker
+    pub fn listen(&self, worker: u16, addrs: impl Iterator<Item = SocketAddr>) -> std::io::Result<(Vec<SocketAddr>, IceTcpWorker)> {
+        let (tx, rx) = channel(EVENT_QUEUE);
+        let mut locals = vec![];
+        for addr in addrs {
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)?;
+            let local = listener.local_addr()?;
+            tokio::spawn(run_listener(worker, local, listener, self.permits.clone(), self.idle_timeout, tx.clone()));
+            locals.push(local);
+        }
+        Ok((locals, IceTcpWorker { rx, conns: HashMap::new() }))
+    }
+}
+
+/// Worker side of the ICE-TCP listeners, it is polled by the media worker
+pub struct IceTcpWorker {
+    rx: Receiver<Event>,
+    conns: HashMap<(SocketAddr, SocketAddr), Sender<Vec<u8>>>,
+}
+
+impl IceTcpWorker {
+    /// Pop a received packet
+    pub fn pop_packet(&mut self) -> Option<IceTcpPacket> {
+        while let Ok(event) = self.rx.try_recv() {
+            match event {
+                Event::Connected(local, remote, tx) => {
+                    self.conns.insert((local, remote), tx);
+                }
+                Event::Closed(local, remote) => {
+                    self.conns.remove(&(local, remote));
+                }
+                Event::Packet(pkt) => return Some(pkt),
+            }
+        }
+        None
+    }
+
+    /// Send a packet to its connection, it is dropped if the connection is closed or its queue is full
+    pub fn send(&mut self, pkt: IceTcpPacket) {
+        let Some(tx) = self.conns.get(&(pkt.local, pkt.remote)) else {
+            log::debug!("[IceTcpWorker] connection {} -> {} not found => drop packet", pkt.remote, pkt.local);
+            return;
+        };
+        let Some(frame) = ice_tcp_frame(&pkt.data) else {
+            log::warn!("[IceTcpWorker] packet to {} is too big to frame, len {} => drop", pkt.remote, pkt.data.len());
+            return;
+        };
+        if let Err(e) = tx.try_send(frame) {
+            log::warn!("[IceTcpWorker] send to {} error {e:?}", pkt.remote);
+        }
+    }
+}
+
+async fn run_listener(worker: u16, local: SocketAddr, listener: TcpListener, permits: Arc<Semaphore>, idle_timeout: Duration, tx: Sender<Event>) {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        match listener.accept().await {
+            Ok((stream, remote)) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                match permits.clone().try_acquire_owned() {
+                    Ok(permit) => {
+                        log::info!("[IceTcpServer] worker {worker} accepted connection {remote} -> {local}");
+                        tokio::spawn(run_connection(local, remote, stream, idle_timeout, tx.clone(), permit));
+                    }
+                    Err(_) => {
+                        log::warn!("[IceTcpServer] worker {worker} reached max connections => reject {remote} -> {local}");
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("[IceTcpServer] accept on {local} error {e:?} => retry after {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+async fn run_connection(local: SocketAddr, remote: SocketAddr, stream: TcpStream, idle_timeout: Duration, tx: Sender<Event>, _permit: OwnedSemaphorePermit) {
+    let (conn_tx, mut conn_rx) = channel::<Vec<u8>>(CONN_QUEUE);
+    if tx.send(Event::Connected(local, remote, conn_tx)).await.is_err() {
+        return;
+    }
+    if let Err(e) = stream.set_nodelay(true) {
+        log::warn!("[IceTcpServer] set nodelay for {remote} error {e:?}");
+    }
+    let (mut reader, mut writer) = stream.into_split();
+    let mut decoder = IceTcpDecoder::default();
+    let mut buf = vec![0; READ_BUF];
+    // only whole packets reset the deadline, partial frames trickled by a client don't keep the connection
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+    loop {
+        tokio::select! {
+            _ = &mut idle => {
+                log::info!("[IceTcpServer] connection {remote} -> {local} idle timeout");
+                break;
+            }
+            read = reader.read(&mut buf) => match read {
+                Ok(0) => break,
+                Ok(len) => {
+                    decoder.push(&buf[..len]);
+                    while let Some(data) = decoder.pop() {
+                        idle.as_mut().reset(Instant::now() + idle_timeout);
+                        if tx.send(Event::Packet(IceTcpPacket { local, remote, data })).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::warn!("[IceTcpServer] read from {remote} error {e:?}");
+                    break;
+                }
+            },
+            frame = conn_rx.recv() => match frame {
+                Some(frame) => {
+                    if let Err(e) = writer.write_all(&frame).await {
+                        log::warn!("[IceTcpServer] write to {remote} error {e:?}");
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+    log::info!("[IceTcpServer] connection {remote} -> {local} closed");
+    let _ = tx.send(Event::Closed(local, remote)).await;
+}
@@ -0,0 +1,71 @@
+//! Nonces of redeemed migrate tickets, so a ticket can only be redeemed once on this node. A nonce is kept longer than
+//! the ticket TTL, because a ticket which is signed by a node with a clock ahead of ours stays valid here for longer.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use media_server_protocol::transport::webrtc::WebrtcMigrateTicket;
+
+const RETENTION: Duration = Duration::from_secs(WebrtcMigrateTicket::TTL_SECONDS * 6);
+
+#[derive(Default)]
+pub struct MigrateNonces {
+    used: HashMap<u64, Instant>,
+    /// Nonces in consume order, so expired ones are dropped from the front
+    expires: VecDeque<(Instant, u64)>,
+}
+
+impl MigrateNonces {
+    /// Consume a nonce, false if it is already used
+    pub fn consume(&mut self, now: Instant, nonce: u64) -> bool {
+        while let Some((expires, old)) = self.expires.front().copied() {
+            if expires > now {
+                break;
+            }
+            self.expires.pop_front();
+            // a released nonce may be consumed again later, only its last expiry removes it
+            if self.used.get(&old) == Some(&expires) {
+                self.used.remove(&old);
+            }
+        }
+        if self.used.contains_key(&nonce) {
+            return false;
+        }
+        let expires = now + RETENTION;
+        self.used.insert(nonce, expires);
+        self.expires.push_back((expires, nonce));
+        true
+    }
+
+    /// Release a nonce whose redeem failed, so client can retry with the same ticket
+    pub fn release(&mut self, nonce: u64) {
+        self.used.remove(&nonce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{MigrateNonces, RETENTION};
+
+    #[test]
+    fn nonce_consumed_once_until_expired() {
+        let now = Instant::now();
+        let mut nonces = MigrateNonces::default();
+        assert!(nonces.consume(now, 1));
+        assert!(!nonces.consume(now, 1));
+        assert!(nonces.consume(now, 2));
+
+        // failed redeem can be retried
+        nonces.release(2);
+        assert!(nonces.consume(now + Duration::from_secs(1), 2));
+
+        // first consume of 2 expires, the retried one is still kept
+        assert!(nonces.consume(now + RETENTION, 1));
+        assert!(!nonces.consume(now + RETENTION, 2));
+        assert!(nonces.consume(now + RETENTION + Duration::from_secs(1), 2));
+    }
+}
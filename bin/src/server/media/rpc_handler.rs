@@ -2,31 +2,49 @@
 //! This file implement forward logic from quic_rpc to worker logic
 //!
 
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
 use media_server_protocol::{
     endpoint::ClusterConnId,
+    multi_tenancy::AppContext,
     protobuf::{
         cluster_gateway::{
-            MediaEdgeServiceHandler, RtpEngineCreateAnswerRequest, RtpEngineCreateAnswerResponse, RtpEngineCreateOfferRequest, RtpEngineCreateOfferResponse, RtpEngineDeleteRequest,
-            RtpEngineDeleteResponse, RtpEngineSetAnswerRequest, RtpEngineSetAnswerResponse, WebrtcConnectRequest, WebrtcConnectResponse, WebrtcRemoteIceRequest, WebrtcRemoteIceResponse,
-            WebrtcRestartIceRequest, WebrtcRestartIceResponse, WhepCloseRequest, WhepCloseResponse, WhepConnectRequest, WhepConnectResponse, WhepRemoteIceRequest, WhepRemoteIceResponse,
-            WhipCloseRequest, WhipCloseResponse, WhipConnectRequest, WhipConnectResponse, WhipRemoteIceRequest, WhipRemoteIceResponse,
+            CloseSessionsRequest, CloseSessionsResponse, MediaEdgeServiceHandler, RoomTracksRequest, RoomTracksResponse, RtpEngineCreateAnswerRequest, RtpEngineCreateAnswerResponse,
+            RtpEngineCreateEgressRequest, RtpEngineCreateEgressResponse, RtpEngineCreateOfferRequest, RtpEngineCreateOfferResponse, RtpEngineDeleteRequest, RtpEngineDeleteResponse,
+            RtpEngineSetAnswerRequest, RtpEngineSetAnswerResponse, WebrtcConnectRequest, WebrtcConnectResponse, WebrtcDumpRequest, WebrtcDumpResponse, WebrtcMigrateRequest, WebrtcMigrateResponse,
+            WebrtcRemoteIceRequest, WebrtcRemoteIceResponse, WebrtcRestartIceRequest, WebrtcRestartIceResponse, WebrtcValidateRequest, WebrtcValidateResponse, WhepCloseRequest, WhepCloseResponse,
+            WhepConnectRequest, WhepConnectResponse, WhepEventsRequest, WhepEventsResponse, WhepRemoteIceRequest, WhepRemoteIceResponse, WhipCloseRequest, WhipCloseResponse, WhipConnectRequest,
+            WhipConnectResponse, WhipRemoteIceRequest, WhipRemoteIceResponse,
         },
         gateway::RemoteIceRequest,
     },
     transport::{
+        admin,
         rtpengine::{self, RtpSetAnswerRequest},
         webrtc,
-        whep::{self, WhepDeleteReq, WhepRemoteIceReq},
-        whip::{self, WhipDeleteReq, WhipRemoteIceReq},
+        whep::{self, WhepDeleteReq, WhepEventsReq, WhepRemoteIceReq},
+        whip::{self, WhipConnectReq, WhipDeleteReq, WhipRemoteIceReq},
         RpcReq, RpcRes,
     },
 };
 
+use media_server_secure::{jwt::MediaEdgeSecureJwt, MediaEdgeSecure};
+use media_server_utils::now_ms;
+
 use crate::rpc::Rpc;
 
+use super::migrate_nonces::MigrateNonces;
+
 #[derive(Clone)]
 pub struct Ctx {
     pub(crate) req_tx: tokio::sync::mpsc::Sender<Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>,
+    /// For reading migrate tickets, which are signed by other nodes with the cluster secret
+    pub(crate) secure: Arc<MediaEdgeSecureJwt>,
+    /// Nonces of redeemed migrate tickets, shared by all rpc calls of the node
+    pub(crate) migrate_nonces: Arc<Mutex<MigrateNonces>>,
 }
 
 #[derive(Default)]
@@ -35,11 +53,34 @@ pub struct MediaRpcHandlerImpl {}
 impl MediaEdgeServiceHandler<Ctx> for MediaRpcHandlerImpl {
     /* Start of whip */
     async fn whip_connect(&self, ctx: &Ctx, req: WhipConnectRequest) -> Option<WhipConnectResponse> {
-        let req = req.try_into().ok()?;
+        let req: WhipConnectReq = req.try_into().ok()?;
         log::info!("On whip_connect from gateway");
-        let (req, rx) = Rpc::new(RpcReq::Whip(whip::RpcReq::Connect(req)));
+        if req.is_expired(now_ms()) {
+            log::warn!("On whip_connect from gateway with deadline {:?} exceeded => abort", req.deadline_ms);
+            return None;
+        }
+        let remaining = req.remaining(now_ms());
+        let (req, mut rx) = Rpc::new(RpcReq::Whip(whip::RpcReq::Connect(req)));
         ctx.req_tx.send(req).await.ok()?;
-        let res = rx.await.ok()?;
+        let res = match remaining {
+            Some(remaining) => match tokio::time::timeout(remaining, &mut rx).await {
+                Ok(res) => res.ok()?,
+                Err(_) => {
+                    log::warn!("On whip_connect from gateway, worker didn't answer before deadline => abort");
+                    // the worker can still create the session after we gave up, nobody would use it so release it
+                    let req_tx = ctx.req_tx.clone();
+                    tokio::spawn(async move {
+                        if let Ok(RpcRes::Whip(whip::RpcRes::Connect(Ok(res)))) = rx.await {
+                            log::info!("Close whip conn {} which is created after deadline", res.conn_id);
+                            let (req, _rx) = Rpc::new(RpcReq::Whip(whip::RpcReq::Delete(WhipDeleteReq { conn_id: res.conn_id })));
+                            let _ = req_tx.send(req).await;
+                        }
+                    });
+                    return None;
+                }
+            },
+            None => rx.await.ok()?,
+        };
         match res {
             RpcRes::Whip(whip::RpcRes::Connect(res)) => res.ok().map(|r| WhipConnectResponse {
                 sdp: r.sdp,
@@ -121,6 +162,20 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRpcHandlerImpl {
         }
     }
 
+    async fn whep_events(&self, ctx: &Ctx, req: WhepEventsRequest) -> Option<WhepEventsResponse> {
+        log::debug!("On whep_events from gateway");
+        let conn_id = req.conn.parse().ok()?;
+        let (req, rx) = Rpc::new(RpcReq::Whep(whep::RpcReq::Events(WhepEventsReq { conn_id, initial: req.initial })));
+        ctx.req_tx.send(req).await.ok()?;
+        let res = rx.await.ok()?;
+        match res {
+            RpcRes::Whep(whep::RpcRes::Events(res)) => res.ok().map(|r| WhepEventsResponse {
+                events: r.events.into_iter().map(Into::into).collect(),
+            }),
+            _ => None,
+        }
+    }
+
     /* Start of sdk */
     async fn webrtc_connect(&self, ctx: &Ctx, req: WebrtcConnectRequest) -> Option<WebrtcConnectResponse> {
         log::info!("On webrtc_connect from gateway");
@@ -157,8 +212,12 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRpcHandlerImpl {
 
     async fn webrtc_restart_ice(&self, ctx: &Ctx, req: WebrtcRestartIceRequest) -> Option<WebrtcRestartIceResponse> {
         log::info!("On webrtc_restart_ice from gateway");
+        let conn: ClusterConnId = req.conn.parse().ok()?;
+        if let Some(session_id) = conn.migrate_session {
+            return self.webrtc_migrate_in(ctx, session_id, req).await;
+        }
         let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::RestartIce(
-            req.conn.parse().ok()?,
+            conn,
             req.app.into(),
             req.ip.parse().ok()?,
             req.user_agent,
@@ -171,12 +230,46 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRpcHandlerImpl {
         match res {
             RpcRes::Webrtc(webrtc::RpcRes::RestartIce(res)) => res.ok().map(|(conn, mut r)| {
                 r.conn_id = conn.to_string();
-                WebrtcRestartIceResponse { res: Some(r) }
+                WebrtcRestartIceResponse { res: Some(r), migrated_from: None }
             }),
             _ => None,
         }
     }
 
+    async fn webrtc_migrate(&self, ctx: &Ctx, req: WebrtcMigrateRequest) -> Option<WebrtcMigrateResponse> {
+        log::info!("On webrtc_migrate from gateway");
+        // dest is already selected by gateway, so app is not needed here
+        let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Migrate(req.conn.parse().ok()?, AppContext::root_app(), Some(req.dest_node))));
+        ctx.req_tx.send(req).await.ok()?;
+        let res = rx.await.ok()?;
+        match res {
+            RpcRes::Webrtc(webrtc::RpcRes::Migrate(res)) => res.ok().map(|conn| WebrtcMigrateResponse { conn: conn.to_string() }),
+            _ => None,
+        }
+    }
+
+    async fn webrtc_dump(&self, ctx: &Ctx, req: WebrtcDumpRequest) -> Option<WebrtcDumpResponse> {
+        log::info!("On webrtc_dump from gateway");
+        let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Dump(req.conn.parse().ok()?)));
+        ctx.req_tx.send(req).await.ok()?;
+        let res = rx.await.ok()?;
+        match res {
+            RpcRes::Webrtc(webrtc::RpcRes::Dump(res)) => res.ok().map(Into::into),
+            _ => None,
+        }
+    }
+
+    async fn webrtc_validate(&self, ctx: &Ctx, req: WebrtcValidateRequest) -> Option<WebrtcValidateResponse> {
+        log::info!("On webrtc_validate from gateway");
+        let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Validate(req.app.into(), req.ip.parse().ok()?, req.req?)));
+        ctx.req_tx.send(req).await.ok()?;
+        let res = rx.await.ok()?;
+        match res {
+            RpcRes::Webrtc(webrtc::RpcRes::Validate(res)) => Some(res.into()),
+            _ => None,
+        }
+    }
+
     /* Start of rtp-engine */
     async fn rtp_engine_create_offer(&self, ctx: &Ctx, req: RtpEngineCreateOfferRequest) -> Option<RtpEngineCreateOfferResponse> {
         let req = req.try_into().ok()?;
@@ -214,6 +307,18 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRpcHandlerImpl {
         }
     }
 
+    async fn rtp_engine_create_egress(&self, ctx: &Ctx, req: RtpEngineCreateEgressRequest) -> Option<RtpEngineCreateEgressResponse> {
+        let req = req.try_into().ok()?;
+        log::info!("On rtp_engine_create_egress from gateway");
+        let (req, rx) = Rpc::new(RpcReq::RtpEngine(rtpengine::RpcReq::CreateEgress(req)));
+        ctx.req_tx.send(req).await.ok()?;
+        let res = rx.await.ok()?;
+        match res {
+            RpcRes::RtpEngine(rtpengine::RpcRes::CreateEgress(res)) => res.ok().map(|(conn, sdp)| RtpEngineCreateEgressResponse { sdp, conn: conn.to_string() }),
+            _ => None,
+        }
+    }
+
     async fn rtp_engine_delete(&self, ctx: &Ctx, req: RtpEngineDeleteRequest) -> Option<RtpEngineDeleteResponse> {
         log::info!("On rtp_engine_delete from gateway");
         let conn_id = req.conn.parse().ok()?;
@@ -227,4 +332,294 @@ impl MediaEdgeServiceHandler<Ctx> for MediaRpcHandlerImpl {
             _ => None,
         }
     }
+
+    /* Start of admin */
+    async fn close_sessions(&self, ctx: &Ctx, req: CloseSessionsRequest) -> Option<CloseSessionsResponse> {
+        log::info!("On close_sessions from gateway");
+        let (req, rx) = Rpc::new(RpcReq::Admin(admin::RpcReq::CloseSessions(req.into())));
+        ctx.req_tx.send(req).await.ok()?;
+        let res = rx.await.ok()?;
+        match res {
+            RpcRes::Admin(admin::RpcRes::CloseSessions(res)) => res.ok().map(|r| CloseSessionsResponse { closed: r.closed() }),
+            _ => None,
+        }
+    }
+
+    async fn room_tracks(&self, ctx: &Ctx, req: RoomTracksRequest) -> Option<RoomTracksResponse> {
+        log::info!("On room_tracks from gateway");
+        let (req, rx) = Rpc::new(RpcReq::Admin(admin::RpcReq::RoomTracks(req.into())));
+        ctx.req_tx.send(req).await.ok()?;
+        let res = rx.await.ok()?;
+        match res {
+            RpcRes::Admin(admin::RpcRes::RoomTracks(res)) => res.ok().map(|tracks| RoomTracksResponse {
+                tracks: tracks.iter().map(|t| t.serialize()).collect(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl MediaRpcHandlerImpl {
+    /// Restart-ice with a migrate conn id, the session is moved from other node so a new endpoint is created with the same session id.
+    /// The request must carry the ticket which is signed by the old node for this session, join and subscriptions are restored
+    /// from it by the worker. The ticket nonce is consumed here, so a ticket is redeemed only once even if the restart-ice is
+    /// replayed, it is released when the session can't be created. The old node is returned so gateway can close the old session right after.
+    async fn webrtc_migrate_in(&self, ctx: &Ctx, session_id: u64, req: WebrtcRestartIceRequest) -> Option<WebrtcRestartIceResponse> {
+        log::info!("On webrtc_restart_ice from gateway with migrated session {session_id}");
+        let token = req.req.as_ref()?.migrate_token.as_deref()?;
+        let ticket = ctx.secure.decode_migrate_ticket(token)?;
+        if ticket.session_id != session_id {
+            log::warn!(
+                "On webrtc_restart_ice from gateway with ticket of session {} for migrated session {session_id} => reject",
+                ticket.session_id
+            );
+            return None;
+        }
+        if !ctx.migrate_nonces.lock().expect("Should lock migrate nonces").consume(Instant::now(), ticket.nonce) {
+            log::warn!("On webrtc_restart_ice from gateway with already redeemed ticket for migrated session {session_id} => reject");
+            return None;
+        }
+        let res = async {
+            let (req, rx) = Rpc::new(RpcReq::Webrtc(webrtc::RpcReq::Connect(
+                req.app.into(),
+                session_id,
+                req.ip.parse().ok()?,
+                req.user_agent,
+                req.req?,
+                req.extra_data,
+                req.record,
+            )));
+            ctx.req_tx.send(req).await.ok()?;
+            match rx.await.ok()? {
+                RpcRes::Webrtc(webrtc::RpcRes::Connect(res)) => res.ok().map(|(conn, mut r)| {
+                    r.conn_id = conn.to_string();
+                    WebrtcRestartIceResponse {
+                        res: Some(r),
+                        migrated_from: Some(ticket.from_node),
+                    }
+                }),
+                _ => None,
+            }
+        }
+        .await;
+        if res.is_none() {
+            ctx.migrate_nonces.lock().expect("Should lock migrate nonces").release(ticket.nonce);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use media_server_protocol::{
+        endpoint::{ClusterConnId, ServerConnId},
+        protobuf::{
+            cluster_gateway::{MediaEdgeServiceHandler, WebrtcRestartIceRequest, WebrtcValidateRequest, WhipConnectRequest},
+            gateway::{ConnectRequest, ConnectResponse, ValidateOfferResponse},
+            session::RoomJoin,
+            shared::AppContext,
+        },
+        transport::{
+            webrtc::{self, WebrtcError, WebrtcMigrateTicket},
+            whip::{self, WhipDeleteReq},
+            RpcError, RpcReq, RpcRes,
+        },
+    };
+    use media_server_secure::{jwt::MediaEdgeSecureJwt, MediaEdgeSecure};
+    use media_server_utils::now_ms;
+
+    use super::{Ctx, MediaRpcHandlerImpl};
+
+    fn create_ctx() -> (Ctx, tokio::sync::mpsc::Receiver<crate::rpc::Rpc<RpcReq<ClusterConnId>, RpcRes<ClusterConnId>>>) {
+        let (req_tx, req_rx) = tokio::sync::mpsc::channel(10);
+        let secure = Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice()));
+        let migrate_nonces = Arc::new(Mutex::new(Default::default()));
+        (Ctx { req_tx, secure, migrate_nonces }, req_rx)
+    }
+
+    fn migrate_req(token: Option<String>) -> WebrtcRestartIceRequest {
+        WebrtcRestartIceRequest {
+            conn: ClusterConnId::migrate(5, 1000).to_string(),
+            user_agent: "test".to_string(),
+            ip: "127.0.0.1".to_string(),
+            req: Some(ConnectRequest {
+                join: Some(RoomJoin {
+                    room: "room".to_string(),
+                    peer: "peer".to_string(),
+                    ..Default::default()
+                }),
+                migrate_token: token,
+                ..Default::default()
+            }),
+            record: false,
+            extra_data: None,
+            app: Some(AppContext { app: None }),
+        }
+    }
+
+    fn whip_req(deadline_ms: Option<u64>) -> WhipConnectRequest {
+        WhipConnectRequest {
+            user_agent: "test".to_string(),
+            ip: "127.0.0.1".to_string(),
+            sdp: "".to_string(),
+            room: "room".to_string(),
+            peer: "peer".to_string(),
+            session_id: 1,
+            record: false,
+            extra_data: None,
+            app: Some(AppContext { app: None }),
+            deadline_ms,
+            tags: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn whip_connect_abort_when_deadline_passed() {
+        let (ctx, mut req_rx) = create_ctx();
+        let handler = MediaRpcHandlerImpl::default();
+
+        assert_eq!(handler.whip_connect(&ctx, whip_req(Some(now_ms() - 1))).await, None);
+        assert!(req_rx.try_recv().is_err(), "should not forward expired request to worker");
+
+        // not expired request should be forwarded to worker, the answer is dropped so the call will return None
+        let (res, _) = tokio::join!(handler.whip_connect(&ctx, whip_req(Some(now_ms() + 10_000))), async {
+            let rpc = req_rx.recv().await.expect("should forward to worker");
+            drop(rpc);
+        });
+        assert_eq!(res, None);
+    }
+
+    #[tokio::test]
+    async fn whip_connect_release_session_created_after_deadline() {
+        let (ctx, mut req_rx) = create_ctx();
+        let handler = MediaRpcHandlerImpl::default();
+
+        let rpc = tokio::spawn(async move {
+            let rpc = req_rx.recv().await.expect("should forward to worker");
+            (rpc, req_rx)
+        });
+        assert_eq!(handler.whip_connect(&ctx, whip_req(Some(now_ms() + 50))).await, None);
+
+        // worker answers after the handler gave up, the created session must be closed
+        let (rpc, mut req_rx) = rpc.await.expect("should join");
+        let conn_id = ClusterConnId {
+            node: 1,
+            node_session: 1,
+            server_conn: ServerConnId { worker: 0, index: 1 },
+            migrate_session: None,
+        };
+        rpc.res(RpcRes::Whip(whip::RpcRes::Connect(Ok(whip::WhipConnectRes { conn_id, sdp: "answer".to_string() }))));
+        let close = req_rx.recv().await.expect("should close late session");
+        assert!(matches!(close.req, RpcReq::Whip(whip::RpcReq::Delete(WhipDeleteReq { conn_id: closed })) if closed == conn_id));
+    }
+
+    #[tokio::test]
+    async fn webrtc_validate_forward_report_and_error() {
+        let (ctx, mut req_rx) = create_ctx();
+        let handler = MediaRpcHandlerImpl::default();
+        let req = WebrtcValidateRequest {
+            app: Some(AppContext { app: None }),
+            ip: "127.0.0.1".to_string(),
+            req: Some(ConnectRequest {
+                sdp: "offer".to_string(),
+                ..Default::default()
+            }),
+        };
+        let report = ValidateOfferResponse {
+            sdp: "answer".to_string(),
+            codecs: vec!["opus".to_string()],
+            ..Default::default()
+        };
+
+        let (res, _) = tokio::join!(handler.webrtc_validate(&ctx, req.clone()), async {
+            let rpc = req_rx.recv().await.expect("should forward to worker");
+            assert!(matches!(&rpc.req, RpcReq::Webrtc(webrtc::RpcReq::Validate(_, _, req)) if req.sdp == "offer"));
+            rpc.res(RpcRes::Webrtc(webrtc::RpcRes::Validate(Ok(report.clone()))));
+        });
+        let res = res.expect("should answer");
+        assert_eq!(res.res, Some(report));
+        assert_eq!(res.error, None);
+
+        // errors keep their code so gateway can answer with the same status as a real connect
+        let (res, _) = tokio::join!(handler.webrtc_validate(&ctx, req), async {
+            let rpc = req_rx.recv().await.expect("should forward to worker");
+            rpc.res(RpcRes::Webrtc(webrtc::RpcRes::Validate(Err(RpcError::new2(WebrtcError::InvalidSdp)))));
+        });
+        let res = res.expect("should answer");
+        assert_eq!(res.res, None);
+        assert_eq!(res.error.map(|e| e.code), Some(WebrtcError::InvalidSdp as u32));
+    }
+
+    #[tokio::test]
+    async fn migrated_restart_ice_keep_session_and_join() {
+        let (ctx, mut req_rx) = create_ctx();
+        let handler = MediaRpcHandlerImpl::default();
+        let token = ctx.secure.encode_migrate_ticket(WebrtcMigrateTicket::new(1000, 4, 5));
+        let req = migrate_req(Some(token.clone()));
+        let new_conn = ClusterConnId {
+            node: 5,
+            node_session: 1,
+            server_conn: ServerConnId { worker: 0, index: 3 },
+            migrate_session: None,
+        };
+
+        let (res, _) = tokio::join!(handler.webrtc_restart_ice(&ctx, req), async {
+            let rpc = req_rx.recv().await.expect("should forward to worker");
+            match &rpc.req {
+                RpcReq::Webrtc(webrtc::RpcReq::Connect(_, session_id, _, _, req, _, _)) => {
+                    assert_eq!(*session_id, 1000);
+                    assert!(req.migrate_token.is_some(), "worker restores join and subscriptions from the ticket");
+                }
+                _ => panic!("migrated restart-ice should create new endpoint"),
+            }
+            rpc.res(RpcRes::Webrtc(webrtc::RpcRes::Connect(Ok((
+                new_conn,
+                ConnectResponse {
+                    conn_id: "".to_string(),
+                    sdp: "answer".to_string(),
+                    ice_lite: false,
+                },
+            )))));
+        });
+        let res = res.expect("should answer");
+        assert_eq!(res.migrated_from, Some(4));
+        assert_eq!(res.res.map(|r| r.conn_id), Some(new_conn.to_string()));
+
+        // a redeemed ticket can't be replayed
+        assert_eq!(handler.webrtc_restart_ice(&ctx, migrate_req(Some(token))).await, None);
+        assert!(req_rx.try_recv().is_err(), "should not forward replayed ticket to worker");
+    }
+
+    #[tokio::test]
+    async fn migrated_restart_ice_retry_after_failed_redeem() {
+        let (ctx, mut req_rx) = create_ctx();
+        let handler = MediaRpcHandlerImpl::default();
+        let token = ctx.secure.encode_migrate_ticket(WebrtcMigrateTicket::new(1000, 4, 5));
+
+        // worker rejects the session, the ticket is still usable
+        for _ in 0..2 {
+            let (res, _) = tokio::join!(handler.webrtc_restart_ice(&ctx, migrate_req(Some(token.clone()))), async {
+                let rpc = req_rx.recv().await.expect("should forward to worker");
+                rpc.res(RpcRes::Webrtc(webrtc::RpcRes::Connect(Err(RpcError::new2(WebrtcError::WorkerNotReady)))));
+            });
+            assert_eq!(res, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn migrated_restart_ice_reject_without_valid_ticket() {
+        let (ctx, mut req_rx) = create_ctx();
+        let handler = MediaRpcHandlerImpl::default();
+        let other_session = ctx.secure.encode_migrate_ticket(WebrtcMigrateTicket::new(1001, 4, 5));
+        let forged = MediaEdgeSecureJwt::from(b"other".as_slice()).encode_migrate_ticket(WebrtcMigrateTicket::new(1000, 4, 5));
+        let conn_id = ctx.secure.encode_conn_id(WebrtcMigrateTicket::new(1000, 4, 5), 10);
+
+        assert_eq!(handler.webrtc_restart_ice(&ctx, migrate_req(None)).await, None);
+        assert_eq!(handler.webrtc_restart_ice(&ctx, migrate_req(Some(other_session))).await, None);
+        assert_eq!(handler.webrtc_restart_ice(&ctx, migrate_req(Some(forged))).await, None);
+        assert_eq!(handler.webrtc_restart_ice(&ctx, migrate_req(Some(conn_id))).await, None);
+        assert!(req_rx.try_recv().is_err(), "should not forward to worker");
+    }
 }
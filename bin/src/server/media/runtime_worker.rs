@@ -9,10 +9,13 @@ use media_server_protocol::{
 };
 use media_server_runner::{Input as WorkerInput, MediaConfig, MediaServerWorker, Output as WorkerOutput, Owner, UserData, SC, SE, TC, TW};
 use media_server_secure::MediaEdgeSecure;
-use sans_io_runtime::{BusChannelControl, BusControl, BusEvent, WorkerInner, WorkerInnerInput, WorkerInnerOutput};
+use media_server_utils::UdpBufferConfig;
+use sans_io_runtime::{backend::BackendOutgoing, BusChannelControl, BusControl, BusEvent, WorkerInner, WorkerInnerInput, WorkerInnerOutput};
 
 use crate::NodeConfig;
 
+use super::{ice_tcp::IceTcpWorker, udp_sockets::UdpSockets};
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum ExtIn {
@@ -40,6 +43,10 @@ pub struct ICfg<ES> {
     pub node: NodeConfig,
     pub session: u64,
     pub media: MediaConfig<ES>,
+    /// Buffer sizes of webrtc media udp sockets, if any is set the sockets are bound by the worker instead of the backend
+    pub udp_buffer: UdpBufferConfig,
+    /// ICE-TCP connections of the worker listeners, None if ICE-TCP is disabled
+    pub ice_tcp: Option<IceTcpWorker>,
 }
 type SCfg = ();
 
@@ -49,6 +56,8 @@ type Output = WorkerInnerOutput<Owner, ExtOut, Channel, Event, SCfg>;
 pub struct MediaRuntimeWorker<ES: 'static + MediaEdgeSecure> {
     index: u16,
     worker: MediaServerWorker<ES>,
+    udp: Option<UdpSockets>,
+    ice_tcp: Option<IceTcpWorker>,
     queue: VecDeque<Output>,
     shutdown: bool,
 }
@@ -76,6 +85,8 @@ impl<ES: 'static + MediaEdgeSecure> WorkerInner<Owner, ExtIn, ExtOut, Channel, E
         MediaRuntimeWorker {
             index,
             worker,
+            udp: (cfg.udp_buffer != UdpBufferConfig::default()).then(|| UdpSockets::new(cfg.udp_buffer)),
+            ice_tcp: cfg.ice_tcp,
             queue,
             shutdown: false,
         }
@@ -98,6 +109,9 @@ impl<ES: 'static + MediaEdgeSecure> WorkerInner<Owner, ExtIn, ExtOut, Channel, E
     }
 
     fn on_tick(&mut self, now: Instant) {
+        if let Some(udp) = &mut self.udp {
+            udp.on_tick();
+        }
         self.worker.on_tick(now);
     }
 
@@ -109,8 +123,18 @@ impl<ES: 'static + MediaEdgeSecure> WorkerInner<Owner, ExtIn, ExtOut, Channel, E
         if !self.queue.is_empty() {
             return self.queue.pop_front();
         }
-        let out = self.worker.pop_output(now)?;
-        Some(self.process_out(out))
+        loop {
+            if let Some(out) = self.worker.pop_output(now) {
+                return Some(self.process_out(out));
+            }
+            // events of own udp sockets and tcp packets are fed one by one after outputs of the previous one are popped
+            if let Some(event) = self.udp.as_mut().and_then(|udp| udp.pop_event()) {
+                self.worker.on_event(now, WorkerInput::Net(Owner::MediaWebrtc, event));
+                continue;
+            }
+            let pkt = self.ice_tcp.as_mut()?.pop_packet()?;
+            self.worker.on_event(now, WorkerInput::IceTcp(pkt));
+        }
     }
 
     fn on_shutdown(&mut self, now: Instant) {
@@ -132,9 +156,28 @@ impl<ES: MediaEdgeSecure> MediaRuntimeWorker<ES> {
                 SdnWorkerBusEvent::Workers(_) => Output::Bus(BusControl::Broadcast(true, event)),
                 SdnWorkerBusEvent::Worker(index, _) => Output::Bus(BusControl::Channel(Owner::Sdn, BusChannelControl::Publish(Channel::Worker(*index), true, event))),
             },
-            WorkerOutput::Net(owner, out) => Output::Net(owner, out),
+            WorkerOutput::Net(owner, out) => match (&mut self.udp, out) {
+                (Some(udp), BackendOutgoing::UdpListen { addr, .. }) if owner == Owner::MediaWebrtc => {
+                    udp.bind(addr);
+                    Output::Continue
+                }
+                (Some(udp), BackendOutgoing::UdpPacket { slot, to, data }) if owner == Owner::MediaWebrtc => {
+                    if udp.send(slot, to, &data) {
+                        Output::Continue
+                    } else {
+                        Output::Net(owner, BackendOutgoing::UdpPacket { slot, to, data })
+                    }
+                }
+                (_, out) => Output::Net(owner, out),
+            },
             WorkerOutput::Continue => Output::Continue,
             WorkerOutput::Record(session_id, ts, event) => Output::Ext(true, ExtOut::Record(session_id, ts, event)),
+            WorkerOutput::IceTcp(pkt) => {
+                if let Some(ice_tcp) = &mut self.ice_tcp {
+                    ice_tcp.send(pkt);
+                }
+                Output::Continue
+            }
         }
     }
 
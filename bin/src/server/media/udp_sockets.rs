@@ -0,0 +1,138 @@
+//! WebRTC media udp sockets which are bound by the worker instead of the runtime backend, the backend can't set buffer
+//! sizes before binding so they would miss the first packets. Like ICE-TCP listeners the sockets get virtual slots,
+//! packets to those slots are sent here and the worker polls the sockets directly.
+
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+};
+
+use media_server_utils::{bind_udp_with_buffer, UdpBufferConfig};
+use sans_io_runtime::backend::BackendIncoming;
+
+/// Virtual slots of worker sockets, below ICE-TCP slots and far above slots of the backend
+pub const UDP_SLOT_BASE: usize = usize::MAX / 4;
+const RECV_BUF: usize = 2048;
+/// Packets read per tick, so a flood can't keep the worker from returning to the runtime
+const RECV_PER_TICK: usize = 1024;
+
+pub struct UdpSockets {
+    cfg: UdpBufferConfig,
+    sockets: Vec<UdpSocket>,
+    binds: VecDeque<BackendIncoming>,
+    next: usize,
+    budget: usize,
+    buf: Vec<u8>,
+}
+
+impl UdpSockets {
+    pub fn new(cfg: UdpBufferConfig) -> Self {
+        Self {
+            cfg,
+            sockets: Vec::new(),
+            binds: VecDeque::new(),
+            next: 0,
+            budget: RECV_PER_TICK,
+            buf: vec![0; RECV_BUF],
+        }
+    }
+
+    /// Bind a socket, the result is queued for the worker like a bind result of the backend
+    pub fn bind(&mut self, addr: SocketAddr) {
+        let result = bind_udp_with_buffer(addr, self.cfg).and_then(|(socket, granted)| {
+            let local = socket.local_addr()?;
+            let slot = UDP_SLOT_BASE + self.sockets.len();
+            log::info!("[UdpSockets] bound {local} slot {slot} with buffer {granted:?}");
+            self.sockets.push(socket);
+            Ok((local, slot))
+        });
+        if let Err(e) = &result {
+            log::error!("[UdpSockets] bind {addr} error {e:?}");
+        }
+        self.binds.push_back(BackendIncoming::UdpListenResult { bind: addr, result });
+    }
+
+    /// Send a packet if the slot is one of these sockets, false if it belongs to the backend
+    pub fn send(&self, slot: usize, to: SocketAddr, data: &[u8]) -> bool {
+        let Some(socket) = slot.checked_sub(UDP_SLOT_BASE).and_then(|i| self.sockets.get(i)) else {
+            return false;
+        };
+        if let Err(e) = socket.send_to(data, to) {
+            log::debug!("[UdpSockets] send to {to} on slot {slot} error {e:?} => drop");
+        }
+        true
+    }
+
+    pub fn on_tick(&mut self) {
+        self.budget = RECV_PER_TICK;
+    }
+
+    /// Pop a bind result or a received packet, sockets are read in turn
+    pub fn pop_event(&mut self) -> Option<BackendIncoming> {
+        if let Some(bind) = self.binds.pop_front() {
+            return Some(bind);
+        }
+        if self.budget == 0 {
+            return None;
+        }
+        for _ in 0..self.sockets.len() {
+            let index = self.next;
+            self.next = (self.next + 1) % self.sockets.len();
+            match self.sockets[index].recv_from(&mut self.buf) {
+                Ok((len, from)) => {
+                    self.budget -= 1;
+                    return Some(BackendIncoming::UdpPacket {
+                        slot: UDP_SLOT_BASE + index,
+                        from,
+                        data: self.buf[..len].to_vec().into(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => log::warn!("[UdpSockets] recv on slot {} error {e:?}", UDP_SLOT_BASE + index),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use media_server_utils::UdpBufferConfig;
+    use sans_io_runtime::backend::BackendIncoming;
+
+    use super::{UdpSockets, UDP_SLOT_BASE};
+
+    #[test]
+    fn bind_send_and_receive() {
+        let mut sockets = UdpSockets::new(UdpBufferConfig {
+            recv: Some(256 * 1024),
+            send: Some(256 * 1024),
+        });
+        sockets.bind("127.0.0.1:0".parse().expect("Should parse addr"));
+        let Some(BackendIncoming::UdpListenResult { result: Ok((local, slot)), .. }) = sockets.pop_event() else {
+            panic!("Should bind");
+        };
+        assert_eq!(slot, UDP_SLOT_BASE);
+        assert!(sockets.pop_event().is_none());
+
+        let client = UdpSocket::bind("127.0.0.1:0").expect("Should bind client");
+        client.set_read_timeout(Some(Duration::from_secs(1))).expect("Should set timeout");
+        client.send_to(&[1, 2, 3], local).expect("Should send");
+        std::thread::sleep(Duration::from_millis(50));
+        let Some(BackendIncoming::UdpPacket { slot: recv_slot, from, data }) = sockets.pop_event() else {
+            panic!("Should receive packet");
+        };
+        assert_eq!(recv_slot, slot);
+        assert_eq!(from, client.local_addr().expect("Should have addr"));
+        assert_eq!(data.to_vec(), vec![1, 2, 3]);
+
+        assert!(sockets.send(slot, from, &[4, 5]));
+        assert!(!sockets.send(1, from, &[4, 5]));
+        let mut buf = [0; 16];
+        let (len, _) = client.recv_from(&mut buf).expect("Should receive");
+        assert_eq!(&buf[..len], &[4, 5]);
+    }
+}
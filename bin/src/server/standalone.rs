@@ -75,6 +75,15 @@ pub struct Args {
     #[arg(env, long, default_value = "protobuf-json")]
     pub hook_body_type: HookBodyType,
 
+    /// Max number of retries when hook delivery failed, each request has X-Hook-Id header which is kept between retries
+    /// and X-Hook-Signature header which is HMAC-SHA256 hex of the body signed with app secret
+    #[arg(env, long, default_value_t = 5)]
+    pub hook_max_retries: usize,
+
+    /// First retry backoff of hook delivery, it is doubled after each retry and capped at 30 seconds
+    #[arg(env, long, default_value_t = 1000)]
+    pub hook_retry_backoff_ms: u64,
+
     /// Destroy room after no-one online, default is 2 minutes
     #[arg(env, long, default_value_t = 120_000)]
     pub destroy_room_after_ms: u64,
@@ -91,6 +100,19 @@ pub struct Args {
     /// Media instance count
     #[arg(env, long, default_value_t = 2)]
     pub media_instance_count: u32,
+
+    /// ICE servers advertised to WHIP/WHEP clients with `Link` headers in connect responses,
+    /// e.g. `stun:stun.example.net,turn:turn.example.net?transport=udp`.
+    #[arg(env, long, value_delimiter = ',')]
+    pub ice_servers: Vec<String>,
+
+    /// Username of `turn:` and `turns:` servers in `ice_servers`.
+    #[arg(env, long)]
+    pub ice_turn_username: Option<String>,
+
+    /// Credential of `turn:` and `turns:` servers in `ice_servers`.
+    #[arg(env, long)]
+    pub ice_turn_credential: Option<String>,
 }
 
 pub async fn run_standalone(workers: usize, node: NodeConfig, args: Args) {
@@ -131,6 +153,9 @@ pub async fn run_standalone(workers: usize, node: NodeConfig, args: Args) {
         let max_cpu = args.max_cpu;
         let max_memory = args.max_memory;
         let max_disk = args.max_disk;
+        let ice_servers = args.ice_servers.clone();
+        let ice_turn_username = args.ice_turn_username.clone();
+        let ice_turn_credential = args.ice_turn_credential.clone();
         tokio::task::spawn_local(async move {
             super::run_media_gateway(
                 workers,
@@ -150,9 +175,26 @@ pub async fn run_standalone(workers: usize, node: NodeConfig, args: Args) {
                     max_cpu,
                     max_memory,
                     max_disk,
+                    zone_fallback: vec![],
+                    app_residency: vec![],
                     rtpengine_cmd_addr: None,
                     multi_tenancy_sync,
                     multi_tenancy_sync_interval_ms,
+                    connector_queue_size: 1024,
+                    connector_queue_overflow: super::gateway::ConnectorOverflowPolicy::DropOldest,
+                    connector_degraded_after_ms: 10_000,
+                    connector_probe_interval_ms: 5_000,
+                    breaker_failures: 5,
+                    breaker_window_ms: 30_000,
+                    breaker_cooldown_ms: 10_000,
+                    route_trace_rate: 0.0,
+                    ice_servers,
+                    ice_turn_username,
+                    ice_turn_credential,
+                    reconnect_limit: 10,
+                    reconnect_limit_window_ms: 60_000,
+                    reconnect_throttle_ms: 30_000,
+                    whip_connect_timeout_ms: 10_000,
                 },
             )
             .await
@@ -169,6 +211,8 @@ pub async fn run_standalone(workers: usize, node: NodeConfig, args: Args) {
         let hook_uri = args.hook_uri.clone();
         let hook_workers = args.hook_workers;
         let hook_body_type = args.hook_body_type;
+        let hook_max_retries = args.hook_max_retries;
+        let hook_retry_backoff_ms = args.hook_retry_backoff_ms;
         let destroy_room_after_ms = args.destroy_room_after_ms;
         let storage_tick_interval_ms = args.storage_tick_interval_ms;
         let multi_tenancy_sync = args.multi_tenancy_sync.clone();
@@ -188,9 +232,12 @@ pub async fn run_standalone(workers: usize, node: NodeConfig, args: Args) {
                 super::connector::Args {
                     db_uri,
                     s3_uri,
+                    s3_app_uri: vec![],
                     hook_uri,
                     hook_workers,
                     hook_body_type,
+                    hook_max_retries,
+                    hook_retry_backoff_ms,
                     destroy_room_after_ms,
                     storage_tick_interval_ms,
                     multi_tenancy_sync,
@@ -211,7 +258,7 @@ pub async fn run_standalone(workers: usize, node: NodeConfig, args: Args) {
         let record_upload_worker = args.record_upload_worker;
         let rtpengine_listen_ip = args.rtpengine_listen_ip;
         tokio::task::spawn_local(async move {
-            super::run_media_server(
+            if let Err(e) = super::run_media_server(
                 workers,
                 None,
                 NodeConfig {
@@ -225,17 +272,93 @@ pub async fn run_standalone(workers: usize, node: NodeConfig, args: Args) {
                 super::media::Args {
                     enable_token_api: false,
                     ice_lite: false,
+                    webrtc_consent_timeout_ms: 10_000,
+                    webrtc_consent_established_timeout_ms: 30_000,
+                    webrtc_consent_keepalive_ms: 3_000,
+                    webrtc_ice_ufrag_len: None,
+                    webrtc_ice_pwd_len: None,
+                    webrtc_candidate_order: vec![],
+                    webrtc_h264_profiles: vec![],
+                    webrtc_video_codecs: vec![],
+                    webrtc_disable_extensions: vec![],
+                    webrtc_dtls_min_version: Default::default(),
+                    webrtc_dtls_cert_policy: Default::default(),
+                    webrtc_dtls_setup: Default::default(),
+                    webrtc_srtp_profiles: vec![],
+                    webrtc_dtls_ciphers: vec![],
+                    webrtc_rtcp_fb: Default::default(),
+                    webrtc_sdp_origin_username: None,
+                    webrtc_sdp_session_name: None,
+                    webrtc_sdp_tool: None,
+                    webrtc_bundle_policy: Default::default(),
+                    webrtc_max_candidates: None,
+                    webrtc_max_media_sections: 64,
+                    webrtc_max_remote_candidates: 256,
+                    webrtc_max_connecting: None,
+                    webrtc_reaper_interval_ms: 5000,
+                    webrtc_reaper_connecting_ms: 60000,
+                    webrtc_reaper_reconnecting_ms: 120000,
                     webrtc_port_seed: 0,
+                    webrtc_ice_tcp_port_seed: 0,
+                    webrtc_ice_tcp_max_connections: 1000,
+                    webrtc_ice_tcp_idle_timeout_ms: 30000,
                     rtpengine_listen_ip,
                     ccu_per_core: 200,
                     record_cache,
                     record_mem_max_size,
                     record_upload_worker,
+                    record_sink: None,
                     disable_gateway_agent: false,
                     disable_connector_agent: false,
+                    udp_recv_buffer: None,
+                    udp_send_buffer: None,
+                    enable_loop_metrics: false,
+                    shutdown_grace_ms: 5000,
+                    message_channel_max_payload: 65536,
+                    room_ttl_secs: None,
+                    room_ttl_apps: vec![],
+                    room_ttl_warning_secs: 60,
+                    session_max_duration_secs: None,
+                    session_max_duration_apps: vec![],
+                    session_max_duration_warning_secs: 60,
+                    rtp_max_size: 1500,
+                    rtp_abusive_per_sec: None,
+                    rtp_egress_allow: vec![],
+                    unknown_feedback: Default::default(),
+                    max_channel_sources: 4,
+                    source_timeout_ms: 5000,
+                    peer_leave_grace_ms: 0,
+                    peer_kv_timeout_ms: 2000,
+                    peer_kv_retries: 3,
+                    room_audit_file: None,
+                    channel_naming: Default::default(),
+                    relay_grace_ms: 200,
+                    relay_grace_key_frame_gap_ms: 500,
+                    relay_grace_max_packets: 64,
+                    playout_audio_ms: 60,
+                    playout_video_ms: 100,
+                    max_publish_tracks: 16,
+                    max_subscribe_tracks: 64,
+                    multi_room_policy: Default::default(),
+                    opus_max_average_bitrate: None,
+                    opus_complexity: None,
+                    opus_clock_rate: 48000,
+                    opus_channels: 2,
+                    opus_apps: vec![],
+                    egress_cap_bps: 0,
+                    ice_servers: vec![],
+                    ice_turn_username: None,
+                    ice_turn_credential: None,
+                    reconnect_limit: 10,
+                    reconnect_limit_window_ms: 60_000,
+                    reconnect_throttle_ms: 30_000,
+                    whip_connect_timeout_ms: 10_000,
                 },
             )
             .await
+            {
+                log::error!("media node {i} error {e:?}");
+            }
         });
     }
     loop {
@@ -1,4 +1,4 @@
-use opus_wrap::{Application, Channels};
+use opus_wrap::{Application, Bitrate, Channels};
 
 use crate::{AudioDecoder, AudioEncodder};
 
@@ -30,10 +30,27 @@ pub struct OpusEncoder {
     encoder: opus_wrap::Encoder,
 }
 
+impl OpusEncoder {
+    /// Create encoder with bitrate in bps and complexity 0-10, None keeps libopus defaults
+    pub fn new(bitrate: Option<u32>, complexity: Option<u8>) -> Self {
+        let mut encoder = opus_wrap::Encoder::new(48000, Channels::Mono, Application::Voip).expect("Should create opus encoder");
+        if let Some(bitrate) = bitrate {
+            if let Err(e) = encoder.set_bitrate(Bitrate::Bits(bitrate as i32)) {
+                log::warn!("[OpusEncoder] set bitrate {bitrate} error {e:?}");
+            }
+        }
+        if let Some(complexity) = complexity {
+            if let Err(e) = encoder.set_complexity(complexity as i32) {
+                log::warn!("[OpusEncoder] set complexity {complexity} error {e:?}");
+            }
+        }
+        Self { encoder }
+    }
+}
+
 impl Default for OpusEncoder {
     fn default() -> Self {
-        let encoder = opus_wrap::Encoder::new(48000, Channels::Mono, Application::Voip).expect("Should create opus encoder");
-        Self { encoder }
+        Self::new(None, None)
     }
 }
 
@@ -32,6 +32,8 @@ const OPUS_SET_BITRATE: c_int = 4002; // in i32
 const OPUS_GET_BITRATE: c_int = 4003; // out *i32
 const OPUS_SET_VBR: c_int = 4006; // in i32
 const OPUS_GET_VBR: c_int = 4007; // out *i32
+const OPUS_SET_COMPLEXITY: c_int = 4010; // in i32
+const OPUS_GET_COMPLEXITY: c_int = 4011; // out *i32
 const OPUS_SET_VBR_CONSTRAINT: c_int = 4020; // in i32
 const OPUS_GET_VBR_CONSTRAINT: c_int = 4021; // out *i32
 const OPUS_SET_INBAND_FEC: c_int = 4012; // in i32
@@ -308,6 +310,19 @@ impl Encoder {
         })
     }
 
+    /// Set the encoder's computational complexity, 0-10.
+    pub fn set_complexity(&mut self, value: i32) -> Result<()> {
+        enc_ctl!(self, OPUS_SET_COMPLEXITY, value);
+        Ok(())
+    }
+
+    /// Get the encoder's computational complexity.
+    pub fn get_complexity(&mut self) -> Result<i32> {
+        let mut value: i32 = 0;
+        enc_ctl!(self, OPUS_GET_COMPLEXITY, &mut value);
+        Ok(value)
+    }
+
     /// Enable or disable variable bitrate.
     pub fn set_vbr(&mut self, vbr: bool) -> Result<()> {
         let value: i32 = if vbr {
@@ -5,7 +5,7 @@ use media_server_protocol::{multi_tenancy::AppId, protobuf::cluster_connector::H
 use tokio::sync::mpsc::UnboundedSender;
 use worker::HookWorker;
 
-pub use worker::HookBodyType;
+pub use worker::{sign, HookBodyType, HookRetryCfg, HOOK_ID_HEADER, HOOK_SIGNATURE_HEADER};
 
 mod worker;
 
@@ -14,10 +14,10 @@ pub struct ConnectorHookSender {
 }
 
 impl ConnectorHookSender {
-    pub fn new(workers: usize, hook_body_type: HookBodyType, app_storage: Arc<MultiTenancyStorage>) -> Self {
+    pub fn new(workers: usize, hook_body_type: HookBodyType, retry: HookRetryCfg, app_storage: Arc<MultiTenancyStorage>) -> Self {
         let mut workers_tx = vec![];
         for id in 0..workers {
-            let (mut worker, tx) = HookWorker::new(hook_body_type, app_storage.clone());
+            let (mut worker, tx) = HookWorker::new(hook_body_type, retry, app_storage.clone());
             workers_tx.push(tx);
 
             tokio::spawn(async move {
@@ -1,10 +1,22 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use clap::ValueEnum;
+use hmac::{Hmac, Mac};
 use media_server_multi_tenancy::MultiTenancyStorage;
 use media_server_protocol::{multi_tenancy::AppId, protobuf::cluster_connector::HookEvent};
 use prost::Message;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use sha2::Sha256;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::Instant,
+};
+
+/// Unique id of each event, it is kept same between retries so receiver can dedup
+pub const HOOK_ID_HEADER: &str = "X-Hook-Id";
+/// Hex encoded HMAC-SHA256 of the body, signed with the app secret
+pub const HOOK_SIGNATURE_HEADER: &str = "X-Hook-Signature";
+
+const MAX_BACKOFF_MS: u64 = 30_000;
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum HookBodyType {
@@ -12,46 +24,258 @@ pub enum HookBodyType {
     ProtobufBinary,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct HookRetryCfg {
+    pub max_retries: usize,
+    pub backoff_ms: u64,
+}
+
+/// Encoded and signed event, it is kept as is between retries
+struct HookDelivery {
+    id: String,
+    url: String,
+    signature: String,
+    content_type: &'static str,
+    body: Vec<u8>,
+    attempt: usize,
+    backoff_ms: u64,
+}
+
 pub struct HookWorker {
     hook_body_type: HookBodyType,
+    retry: HookRetryCfg,
     app_storage: Arc<MultiTenancyStorage>,
+    client: reqwest::Client,
     rx: UnboundedReceiver<(AppId, HookEvent)>,
+    /// Failed deliveries which are waiting for retry, ordered by retry time then schedule order
+    retries: BTreeMap<(Instant, u64), HookDelivery>,
+    retry_seq: u64,
 }
 
 impl HookWorker {
-    pub fn new(hook_body_type: HookBodyType, app_storage: Arc<MultiTenancyStorage>) -> (Self, UnboundedSender<(AppId, HookEvent)>) {
+    pub fn new(hook_body_type: HookBodyType, retry: HookRetryCfg, app_storage: Arc<MultiTenancyStorage>) -> (Self, UnboundedSender<(AppId, HookEvent)>) {
         let (tx, rx) = unbounded_channel();
-        (Self { hook_body_type, app_storage, rx }, tx)
+        (
+            Self {
+                hook_body_type,
+                retry,
+                app_storage,
+                client: reqwest::Client::new(),
+                rx,
+                retries: BTreeMap::new(),
+                retry_seq: 0,
+            },
+            tx,
+        )
     }
 
+    /// Process a new event or a retry which is due. Retries are scheduled instead of waited,
+    /// so a failing hook endpoint doesn't delay other events which are routed to same worker
     pub async fn recv(&mut self) -> Result<(), String> {
-        let (app, event) = self.rx.recv().await.ok_or("Internal queue error".to_string())?;
-        let hook_url = match self.app_storage.get_app(&app) {
-            Some(app) => match app.hook {
-                Some(hook) => hook,
-                None => return Ok(()),
-            },
-            None => return Ok(()),
+        let next_retry = self.retries.keys().next().map(|(at, _)| *at);
+        let delivery = tokio::select! {
+            event = self.rx.recv() => {
+                let (app, event) = event.ok_or("Internal queue error".to_string())?;
+                match self.build_delivery(app, event) {
+                    Some(delivery) => delivery,
+                    None => return Ok(()),
+                }
+            }
+            _ = sleep_until_opt(next_retry) => {
+                let (_, delivery) = self.retries.pop_first().expect("Should have due retry");
+                delivery
+            }
         };
-        let client = reqwest::Client::new();
-        if let Err(e) = match self.hook_body_type {
-            HookBodyType::ProtobufJson => client.post(&hook_url).json(&event).send().await,
-            HookBodyType::ProtobufBinary => {
-                let mut buf = vec![];
-                match event.encode(&mut buf) {
-                    Ok(_) => client.post(&hook_url).body(buf).send().await,
-                    Err(e) => {
-                        log::error!("[HookWorker] encode event to binary error {e:?}");
-                        return Ok(());
-                    }
+        self.deliver(delivery).await;
+        Ok(())
+    }
+
+    fn build_delivery(&self, app: AppId, event: HookEvent) -> Option<HookDelivery> {
+        let app = self.app_storage.get_app(&app)?;
+        let url = app.hook?;
+        let (body, content_type) = match self.hook_body_type {
+            HookBodyType::ProtobufJson => match serde_json::to_vec(&event) {
+                Ok(body) => (body, "application/json"),
+                Err(e) => {
+                    log::error!("[HookWorker] encode event to json error {e:?}");
+                    return None;
                 }
+            },
+            HookBodyType::ProtobufBinary => (event.encode_to_vec(), "application/octet-stream"),
+        };
+        Some(HookDelivery {
+            id: uuid::Uuid::now_v7().to_string(),
+            signature: sign(&app.app_secret, &body),
+            url,
+            content_type,
+            body,
+            attempt: 0,
+            backoff_ms: self.retry.backoff_ms,
+        })
+    }
+
+    async fn deliver(&mut self, mut delivery: HookDelivery) {
+        let id = &delivery.id;
+        match self.send(&delivery.url, id, &delivery.signature, delivery.content_type, delivery.body.clone()).await {
+            Ok(_) => {
+                log::info!("[HookWorker] sent event {id} after {} attempts", delivery.attempt + 1);
+                return;
+            }
+            Err(e) => {
+                log::warn!("[HookWorker] send event {id} attempt {} error {e:?}", delivery.attempt + 1);
             }
-        } {
-            // TODO: put to retry queue here
-            log::error!("[HookWorker] send event error {e:?}");
-        } else {
-            log::info!("[HookWorker] sent event");
         }
+        if delivery.attempt >= self.retry.max_retries {
+            log::error!("[HookWorker] drop event {id} after {} attempts", delivery.attempt + 1);
+            return;
+        }
+        let at = Instant::now() + Duration::from_millis(delivery.backoff_ms);
+        delivery.attempt += 1;
+        delivery.backoff_ms = (delivery.backoff_ms * 2).min(MAX_BACKOFF_MS);
+        self.retry_seq += 1;
+        self.retries.insert((at, self.retry_seq), delivery);
+    }
+
+    async fn send(&self, url: &str, id: &str, signature: &str, content_type: &str, body: Vec<u8>) -> Result<(), reqwest::Error> {
+        self.client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .header(HOOK_ID_HEADER, id)
+            .header(HOOK_SIGNATURE_HEADER, signature)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 }
+
+async fn sleep_until_opt(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC should accept any key size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use httpmock::MockServer;
+    use media_server_multi_tenancy::MultiTenancyStorage;
+    use media_server_protocol::{
+        multi_tenancy::AppId,
+        protobuf::cluster_connector::{hook_event, peer_event, HookEvent, PeerEvent},
+    };
+
+    use super::{sign, HookBodyType, HookRetryCfg, HookWorker, HOOK_ID_HEADER, HOOK_SIGNATURE_HEADER};
+
+    fn event() -> HookEvent {
+        HookEvent {
+            node: 1,
+            ts: 1000,
+            event: Some(hook_event::Event::Peer(PeerEvent {
+                app: "".to_owned(),
+                session_id: 100,
+                tags: Default::default(),
+                event: Some(peer_event::Event::Leave(peer_event::Leave {
+                    room: "room1".to_owned(),
+                    peer: "peer1".to_owned(),
+                })),
+            })),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_signed_event() {
+        let server = MockServer::start_async().await;
+        let body = serde_json::to_vec(&event()).expect("Should encode");
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::POST)
+                    .path("/hook")
+                    .header_exists(HOOK_ID_HEADER)
+                    .header(HOOK_SIGNATURE_HEADER, sign("secret1", &body))
+                    .body(String::from_utf8(body.clone()).expect("Should be utf8"));
+                then.status(200);
+            })
+            .await;
+
+        let storage = Arc::new(MultiTenancyStorage::new_with_single("secret1", Some(&server.url("/hook"))));
+        let (mut worker, tx) = HookWorker::new(HookBodyType::ProtobufJson, HookRetryCfg { max_retries: 2, backoff_ms: 1 }, storage);
+        tx.send((AppId::root_app(), event())).expect("Should send");
+        worker.recv().await.expect("Should process");
+
+        mock.assert_hits_async(1).await;
+    }
+
+    #[tokio::test]
+    async fn retry_failed_event() {
+        let server = MockServer::start_async().await;
+        let mock = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::POST).path("/hook").header_exists(HOOK_ID_HEADER).header_exists(HOOK_SIGNATURE_HEADER);
+                then.status(500);
+            })
+            .await;
+
+        let storage = Arc::new(MultiTenancyStorage::new_with_single("secret1", Some(&server.url("/hook"))));
+        let (mut worker, tx) = HookWorker::new(HookBodyType::ProtobufJson, HookRetryCfg { max_retries: 2, backoff_ms: 1 }, storage);
+        tx.send((AppId::root_app(), event())).expect("Should send");
+        // first attempt and 2 retries
+        for _ in 0..3 {
+            worker.recv().await.expect("Should process");
+        }
+        assert!(worker.retries.is_empty());
+
+        mock.assert_hits_async(3).await;
+    }
+
+    #[tokio::test]
+    async fn retry_not_block_other_events() {
+        let server = MockServer::start_async().await;
+        let failed = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::POST).path("/hook").body_contains("peer1");
+                then.status(500);
+            })
+            .await;
+        let success = server
+            .mock_async(|when, then| {
+                when.method(httpmock::Method::POST).path("/hook").body_contains("peer2");
+                then.status(200);
+            })
+            .await;
+
+        let storage = Arc::new(MultiTenancyStorage::new_with_single("secret1", Some(&server.url("/hook"))));
+        let (mut worker, tx) = HookWorker::new(HookBodyType::ProtobufJson, HookRetryCfg { max_retries: 2, backoff_ms: 60_000 }, storage);
+        let mut event2 = event();
+        if let Some(hook_event::Event::Peer(PeerEvent {
+            event: Some(peer_event::Event::Leave(leave)),
+            ..
+        })) = &mut event2.event
+        {
+            leave.peer = "peer2".to_owned();
+        }
+        tx.send((AppId::root_app(), event())).expect("Should send");
+        tx.send((AppId::root_app(), event2)).expect("Should send");
+
+        worker.recv().await.expect("Should process");
+        assert_eq!(worker.retries.len(), 1);
+        // second event is sent without waiting for the retry backoff of first event
+        tokio::time::timeout(Duration::from_secs(5), worker.recv())
+            .await
+            .expect("Should not wait retry")
+            .expect("Should process");
+
+        failed.assert_hits_async(1).await;
+        success.assert_hits_async(1).await;
+        assert_eq!(worker.retries.len(), 1);
+    }
+}
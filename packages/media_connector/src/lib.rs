@@ -17,7 +17,7 @@ pub mod hooks;
 mod msg_queue;
 mod sql_storage;
 
-pub use hooks::HookBodyType;
+pub use hooks::{HookBodyType, HookRetryCfg};
 
 pub const DATA_PORT: u16 = 10002;
 
@@ -90,8 +90,11 @@ pub struct EventInfo {
 pub struct ConnectorCfg {
     pub sql_uri: String,
     pub s3_uri: String,
+    /// Preferred storage uri of apps as (app, uri), records of other apps are stored with `s3_uri`
+    pub s3_app_uris: Vec<(String, String)>,
     pub hook_workers: usize,
     pub hook_body_type: HookBodyType,
+    pub hook_retry: HookRetryCfg,
     pub room_destroy_after_ms: u64,
 }
 
@@ -120,7 +123,7 @@ impl ConnectorStorage {
     pub async fn new(node: NodeId, app_storage: Arc<MultiTenancyStorage>, cfg: ConnectorCfg) -> Self {
         Self {
             sql_storage: ConnectorSqlStorage::new(node, &cfg).await,
-            hook: ConnectorHookSender::new(cfg.hook_workers, cfg.hook_body_type, app_storage),
+            hook: ConnectorHookSender::new(cfg.hook_workers, cfg.hook_body_type, cfg.hook_retry, app_storage),
         }
     }
 
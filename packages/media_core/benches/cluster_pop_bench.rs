@@ -0,0 +1,82 @@
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use media_server_core::{
+    cluster::{ClusterEndpointControl, ClusterRemoteTrackControl, ClusterRoomHash, MediaCluster},
+    transport::RemoteTrackId,
+};
+use media_server_protocol::{
+    endpoint::{PeerId, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, TrackMeta, TrackName},
+    media::MediaPacket,
+    multi_tenancy::AppId,
+};
+use sans_io_runtime::TaskSwitcherChild;
+
+const PEERS: u16 = 50;
+const PACKETS: u16 = 1000;
+
+/// Room with many peers, each peer publishes an audio track and sends some packets
+fn busy_room() -> MediaCluster<u16> {
+    let now = Instant::now();
+    let mut cluster = MediaCluster::default();
+    let room = ClusterRoomHash::from(1);
+    for endpoint in 0..PEERS {
+        let join = ClusterEndpointControl::Join(
+            AppId::root_app(),
+            "room".into(),
+            PeerId::from(format!("peer{endpoint}")),
+            PeerMeta { metadata: None, extra_data: None },
+            RoomInfoPublish { peer: true, tracks: true },
+            RoomInfoSubscribe { peers: true, tracks: true },
+            None,
+        );
+        cluster.on_endpoint_control(now, endpoint, room, join);
+        let started = ClusterRemoteTrackControl::Started(TrackName::from("audio_main"), TrackMeta::default_audio());
+        cluster.on_endpoint_control(now, endpoint, room, ClusterEndpointControl::RemoteTrack(RemoteTrackId::from(0), started));
+    }
+    while cluster.pop_output(()).is_some() {}
+
+    for seq in 0..PACKETS {
+        let media = ClusterRemoteTrackControl::Media(MediaPacket::build_audio(seq as u32 * 960, seq, None, vec![0; 100]));
+        cluster.on_endpoint_control(now, seq % PEERS, room, ClusterEndpointControl::RemoteTrack(RemoteTrackId::from(0), media));
+    }
+    cluster
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    c.bench_function("cluster::pop_output", |b| {
+        b.iter_batched(
+            busy_room,
+            |mut cluster| {
+                let mut count = 0;
+                while cluster.pop_output(()).is_some() {
+                    count += 1;
+                }
+                count
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    c.bench_function("cluster::pop_outputs_into", |b| {
+        let mut buf = Vec::with_capacity(64);
+        b.iter_batched(
+            busy_room,
+            |mut cluster| {
+                let mut count = 0;
+                loop {
+                    buf.clear();
+                    let popped = cluster.pop_outputs_into(&mut buf, 64);
+                    count += popped;
+                    if popped < 64 {
+                        break count;
+                    }
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
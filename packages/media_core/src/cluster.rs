@@ -9,16 +9,18 @@ use derive_more::{AsRef, Display, From};
 use indexmap::IndexMap;
 use sans_io_runtime::{return_if_none, TaskGroup, TaskGroupOutput, TaskSwitcherChild};
 use std::{
+    collections::{HashMap, VecDeque},
     fmt::Debug,
     hash::{Hash, Hasher},
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use atm0s_sdn::features::{FeaturesControl, FeaturesEvent};
 use media_server_protocol::{
-    endpoint::{AudioMixerConfig, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackMeta, TrackName, TrackSource},
+    endpoint::{AudioMixerConfig, AudioMixerMode, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackInfo, TrackMeta, TrackName, TrackSource},
     media::MediaPacket,
-    multi_tenancy::AppContext,
+    multi_tenancy::{AppContext, AppId},
 };
 
 use crate::{
@@ -26,11 +28,139 @@ use crate::{
     transport::{LocalTrackId, RemoteTrackId},
 };
 
-use self::room::ClusterRoom;
-pub use self::room::RoomUserData;
+pub use self::audit::{verify_chain, AuditChainHead, AuditEvent, AuditRecord, AuditSink, FileAuditSink, RoomAudit, AUDIT_GENESIS_HASH};
+use self::id_generator::TrackChannels;
+pub use self::id_generator::{ChannelNaming, TrackChannelName};
+use self::room::{ClusterRoom, RoomTtl};
+pub use self::room::{KvRetryPolicy, PubDataDropped, RoomUserData, UnknownFeedback, UnknownFeedbackPolicy, DEFAULT_MAX_CHANNEL_SOURCES, DEFAULT_SOURCE_TIMEOUT};
+pub use self::video_codec::RoomVideoCodecs;
 
+mod audit;
 mod id_generator;
 mod room;
+mod video_codec;
+
+/// Default max payload of a message channel publish, bigger messages are rejected instead of fan out over pubsub
+pub const DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD: usize = 64 * 1024;
+
+/// Default time before a room TTL is reached that peers are warned with [`ClusterEndpointEvent::RoomClosingSoon`]
+pub const DEFAULT_ROOM_TTL_WARNING: Duration = Duration::from_secs(60);
+
+/// Time which a room is kept as closed after it is removed, late controls to it are rejected instead of recreating it
+const CLOSED_ROOM_KEEP: Duration = Duration::from_secs(60);
+
+/// Force close policy of rooms, e.g. timed webinars. TTL is counted from the room creation on this node,
+/// a room which becomes empty before TTL is removed as normal and a new room with same id starts a new TTL.
+/// A room which is closed by TTL is kept as closed for a while after removed, so late controls don't recreate it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomTtlConfig {
+    /// TTL of rooms of apps which are not in `apps`, None for no TTL
+    pub default: Option<Duration>,
+    /// TTL per app, zero disables TTL for the app
+    pub apps: HashMap<AppId, Duration>,
+    /// Peers are warned this time before the room is closed
+    pub warning: Duration,
+}
+
+impl Default for RoomTtlConfig {
+    fn default() -> Self {
+        Self {
+            default: None,
+            apps: HashMap::new(),
+            warning: DEFAULT_ROOM_TTL_WARNING,
+        }
+    }
+}
+
+impl RoomTtlConfig {
+    pub fn ttl(&self, app: &AppId) -> Option<Duration> {
+        self.apps.get(app).copied().or(self.default).filter(|ttl| !ttl.is_zero())
+    }
+}
+
+/// Config of the cluster part of a media worker, it is applied to every room which is created in the worker
+#[derive(Clone)]
+pub struct MediaClusterConfig {
+    /// Max payload in bytes of a message channel publish, bigger messages are rejected
+    pub message_max_payload: usize,
+    /// Force close policy of rooms, per app
+    pub room_ttl: RoomTtlConfig,
+    /// How pubsub feedback kinds which this node doesn't know are handled
+    pub unknown_feedback: UnknownFeedbackPolicy,
+    /// Max number of sessions publishing same peer and track in a room, 0 for unlimited
+    pub max_channel_sources: usize,
+    /// How long a subscribed channel can be without media or heartbeat before its source is considered lost, zero for disabled
+    pub source_timeout: Duration,
+    /// How long a remote peer which disconnected is kept present in rooms before PeerLeaved, zero for immediate
+    pub peer_leave_grace: Duration,
+    /// How a join whose peer info is not confirmed stored in the cluster is retried before it fails
+    pub peer_kv_retry: KvRetryPolicy,
+    /// Audit log of room events, shared by all workers. None for disabled
+    pub audit: Option<Arc<RoomAudit>>,
+    pub video_codecs: Arc<RoomVideoCodecs>,
+    /// Naming scheme of track channels, it must be same in all media nodes of the cluster
+    pub channel_naming: ChannelNaming,
+}
+
+impl Default for MediaClusterConfig {
+    fn default() -> Self {
+        Self {
+            message_max_payload: DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            room_ttl: RoomTtlConfig::default(),
+            unknown_feedback: UnknownFeedbackPolicy::default(),
+            max_channel_sources: DEFAULT_MAX_CHANNEL_SOURCES,
+            source_timeout: DEFAULT_SOURCE_TIMEOUT,
+            peer_leave_grace: Duration::ZERO,
+            peer_kv_retry: KvRetryPolicy::default(),
+            audit: None,
+            video_codecs: Default::default(),
+            channel_naming: ChannelNaming::default(),
+        }
+    }
+}
+
+/// Room parameters which are changed by admin while peers are in room, None fields are kept as is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomConfigPatch {
+    /// Egress bitrate ceiling of each endpoint in room, it can only lower the endpoint config
+    pub max_bitrate: Option<u64>,
+    /// Max simulcast spatial layer which is forwarded to subscribers in room
+    pub max_spatial: Option<u8>,
+    /// Recording is decided when the session connects, so changing it requires rejoin
+    pub record: Option<bool>,
+}
+
+impl RoomConfigPatch {
+    /// Fields which can't be applied to joined endpoints, a patch with any of them is rejected
+    pub fn rejoin_fields(&self) -> Vec<&'static str> {
+        let mut fields = vec![];
+        if self.record.is_some() {
+            fields.push("record");
+        }
+        fields
+    }
+}
+
+/// Live config of a room, None fields fallback to endpoint config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoomConfig {
+    pub max_bitrate: Option<u64>,
+    pub max_spatial: Option<u8>,
+}
+
+impl RoomConfig {
+    /// Apply live fields of the patch, return true if config is changed
+    pub fn apply(&mut self, patch: &RoomConfigPatch) -> bool {
+        let before = *self;
+        if let Some(max_bitrate) = patch.max_bitrate {
+            self.max_bitrate = Some(max_bitrate);
+        }
+        if let Some(max_spatial) = patch.max_spatial {
+            self.max_spatial = Some(max_spatial);
+        }
+        before != *self
+    }
+}
 
 #[derive(Clone, Copy, From, AsRef, PartialEq, Eq, Debug, Display, Hash)]
 pub struct ClusterRoomHash(u64);
@@ -54,7 +184,15 @@ pub enum ClusterRemoteTrackControl {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ClusterRemoteTrackEvent {
     RequestKeyFrame,
-    LimitBitrate { min: u64, max: u64 },
+    LimitBitrate {
+        min: u64,
+        max: u64,
+        /// Target bitrate of each spatial layer of a simulcast stream, for publisher to configure each encoding.
+        /// None for single-encoding streams, and a layer is None when it has no consumers
+        layers: Option<[Option<u64>; 3]>,
+    },
+    /// Channel of the track already has max number of sources, media of the track is not published
+    PublishRejected,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -69,6 +207,10 @@ pub enum ClusterLocalTrackControl {
 pub enum ClusterLocalTrackEvent {
     RelayChanged,
     SourceChanged,
+    /// Channel source stopped sending both media and heartbeats, cluster is trying to re-subscribe to a surviving source
+    SourceLost,
+    /// Channel receives data again after SourceLost, it may be from other source
+    SourceRecovered,
     Media(u64, MediaPacket),
 }
 
@@ -93,28 +235,87 @@ pub enum ClusterMessageChannelControl {
     PublishData(PeerId, Vec<u8>),
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClusterMessageChannelError {
+    /// Payload is bigger than configured max payload, the message is not forwarded
+    TooLarge(usize),
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ClusterEndpointControl {
-    Join(PeerId, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, Option<AudioMixerConfig>),
+    /// Join with app and room of the endpoint, app is used for resolving per-app room policies, and both names are used for
+    /// track channels in `named` scheme
+    Join(AppId, RoomId, PeerId, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, Option<AudioMixerConfig>),
     Leave,
     SubscribePeer(PeerId),
     UnsubscribePeer(PeerId),
+    /// Subscribe and unsubscribe single tracks of other peers in one step, it is applied atomically so the private
+    /// peer maps are not unsubscribed and resubscribed when tracks are moved within a peer
+    BatchTrackSubscriptions {
+        subscribe: Vec<(PeerId, TrackName)>,
+        unsubscribe: Vec<(PeerId, TrackName)>,
+    },
+    /// App-level mute state of a published track, it only update metadata and dont stop the track
+    SetTrackMuted(TrackName, bool),
+    /// Stop forwarding media of all published tracks in the room, peers and subscriptions are kept
+    PauseRoom,
+    /// Resume forwarding media, key-frames are requested for all video tracks
+    ResumeRoom,
+    /// Lock or unlock the room. When locked, new joins are held as pending and the locking endpoint is the owner
+    /// which receives JoinPending and decides to admit or reject them. Unlocking admits all pending peers.
+    SetRoomLocked(bool),
+    AdmitPeer(PeerId),
+    RejectPeer(PeerId),
+    /// Change live parameters of the room, changes are applied to all endpoints in room
+    UpdateRoomConfig(RoomConfigPatch),
     AudioMixer(ClusterAudioMixerControl),
     RemoteTrack(RemoteTrackId, ClusterRemoteTrackControl),
     LocalTrack(LocalTrackId, ClusterLocalTrackControl),
     MessageChannel(MessageChannelLabel, ClusterMessageChannelControl),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterJoinRejectReason {
+    /// Peer id is already joined by other endpoint, or the endpoint is already joined with other peer id
+    AlreadyJoined,
+    /// Room is locked and the owner rejected the join, or the owner left before admitting
+    NotAdmitted,
+    /// Room is locked and the join is not admitted in time
+    PendingTimeout,
+    /// Room reached its TTL and is closing
+    RoomClosed,
+    /// Peer info could not be stored in the cluster, see [`KvRetryPolicy`]
+    StorageError,
+    /// Endpoint is already in other room, see [`crate::endpoint::MultiRoomPolicy`]
+    AlreadyInRoom,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ClusterEndpointEvent {
+    /// Join is rejected, the endpoint is not added into room
+    JoinRejected(PeerId, ClusterJoinRejectReason),
+    /// Sent to the room owner when a peer is waiting to join the locked room
+    JoinPending(PeerId),
     PeerJoined(PeerId, PeerMeta),
     PeerLeaved(PeerId, PeerMeta),
     TrackStarted(PeerId, TrackName, TrackMeta),
     TrackStopped(PeerId, TrackName, TrackMeta),
+    TrackMuted(PeerId, TrackName, bool),
+    RoomPaused,
+    RoomResumed,
+    /// Room will be force closed after the duration because of its TTL
+    RoomClosingSoon(Duration),
+    /// Room reached its TTL, all peers must leave and disconnect
+    RoomClosed,
+    /// Live room config is changed, also sent on join when the room config is not default
+    RoomConfigChanged(RoomConfig),
     AudioMixer(ClusterAudioMixerEvent),
+    /// Mixer config of the join conflicts with the room mixer (mode, number of outputs), the endpoint is joined without mixer
+    MixerConfigConflict(AudioMixerMode, usize),
     RemoteTrack(RemoteTrackId, ClusterRemoteTrackEvent),
     LocalTrack(LocalTrackId, ClusterLocalTrackEvent),
     MessageChannelData(MessageChannelLabel, PeerId, Vec<u8>),
+    MessageChannelError(MessageChannelLabel, ClusterMessageChannelError),
 }
 
 pub enum Input<Endpoint> {
@@ -126,6 +327,8 @@ pub enum Input<Endpoint> {
 pub enum Output<Endpoint> {
     Sdn(RoomUserData, FeaturesControl),
     Endpoint(Vec<Endpoint>, ClusterEndpointEvent),
+    /// Answer of [`MediaCluster::query_room_tracks`]
+    RoomTracks(u64, Vec<TrackInfo>),
     OnResourceEmpty,
     Continue,
 }
@@ -133,22 +336,41 @@ pub enum Output<Endpoint> {
 pub struct MediaCluster<Endpoint: Debug + Copy + Clone + Hash + Eq> {
     rooms_map: IndexMap<ClusterRoomHash, usize>,
     rooms: TaskGroup<room::Input<Endpoint>, room::Output<Endpoint>, ClusterRoom<Endpoint>, 16>,
+    /// Rooms which are removed after closed by TTL, with expire time which is set by the next tick after removing
+    closed_rooms: IndexMap<ClusterRoomHash, Option<Instant>>,
+    queue: VecDeque<Output<Endpoint>>,
+    cfg: MediaClusterConfig,
     shutdown: bool,
 }
 
 impl<Endpoint: Debug + Copy + Hash + Eq + Clone> Default for MediaCluster<Endpoint> {
     fn default() -> Self {
+        Self::new(MediaClusterConfig::default())
+    }
+}
+
+impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> MediaCluster<Endpoint> {
+    pub fn new(cfg: MediaClusterConfig) -> Self {
         Self {
             rooms_map: IndexMap::new(),
             rooms: TaskGroup::default(),
+            closed_rooms: IndexMap::new(),
+            queue: VecDeque::new(),
+            cfg,
             shutdown: false,
         }
     }
-}
 
-impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> MediaCluster<Endpoint> {
     pub fn on_tick(&mut self, now: Instant) {
         self.rooms.on_tick(now);
+        self.closed_rooms.retain(|room, expire| {
+            let expire = expire.get_or_insert(now + CLOSED_ROOM_KEEP);
+            if now >= *expire {
+                log::info!("[MediaCluster] closed room {room} expired");
+                return false;
+            }
+            true
+        });
     }
 
     pub fn on_sdn_event(&mut self, now: Instant, userdata: RoomUserData, event: FeaturesEvent) {
@@ -159,14 +381,69 @@ impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> MediaCluster<Endpoint>
     pub fn on_endpoint_control(&mut self, now: Instant, endpoint: Endpoint, room_hash: ClusterRoomHash, control: ClusterEndpointControl) {
         if let Some(index) = self.rooms_map.get(&room_hash) {
             self.rooms.on_event(now, *index, room::Input::Endpoint(endpoint, control));
+        } else if self.closed_rooms.contains_key(&room_hash) {
+            self.reject_closed_room_control(endpoint, room_hash, control);
         } else {
-            log::info!("[MediaCluster] create room {}", room_hash);
-            let index = self.rooms.add_task(ClusterRoom::new(room_hash));
-            self.rooms_map.insert(room_hash, index);
+            let (ttl, track_channels) = match &control {
+                ClusterEndpointControl::Join(app, room, ..) => (
+                    self.cfg.room_ttl.ttl(app).map(|ttl| RoomTtl {
+                        ttl,
+                        warning: self.cfg.room_ttl.warning,
+                    }),
+                    TrackChannels::new(room_hash, self.cfg.channel_naming, app, room),
+                ),
+                _ => (None, TrackChannels::hashed(room_hash)),
+            };
+            log::info!("[MediaCluster] create room {}, ttl {:?}", room_hash, ttl);
+            let index = self.create_room(track_channels, ttl);
             self.rooms.on_event(now, index, room::Input::Endpoint(endpoint, control));
         }
     }
 
+    fn create_room(&mut self, track_channels: TrackChannels, ttl: Option<RoomTtl>) -> usize {
+        let room_hash = track_channels.room();
+        let index = self.rooms.add_task(ClusterRoom::new(
+            track_channels,
+            self.cfg.message_max_payload,
+            ttl,
+            self.cfg.unknown_feedback,
+            self.cfg.max_channel_sources,
+            self.cfg.source_timeout,
+            self.cfg.peer_leave_grace,
+            self.cfg.peer_kv_retry,
+            self.cfg.audit.clone(),
+            self.cfg.video_codecs.clone(),
+        ));
+        self.rooms_map.insert(room_hash, index);
+        index
+    }
+
+    /// Late controls of a closed room are rejected, the room is not recreated until it is expired.
+    /// Leave don't need an answer because the endpoint already left.
+    fn reject_closed_room_control(&mut self, endpoint: Endpoint, room_hash: ClusterRoomHash, control: ClusterEndpointControl) {
+        let event = match control {
+            ClusterEndpointControl::Leave => return,
+            ClusterEndpointControl::Join(_, _, peer, ..) => ClusterEndpointEvent::JoinRejected(peer, ClusterJoinRejectReason::RoomClosed),
+            _ => ClusterEndpointEvent::RoomClosed,
+        };
+        log::warn!("[MediaCluster] control from {endpoint:?} to closed room {room_hash} => reject with {event:?}");
+        self.queue.push_back(Output::Endpoint(vec![endpoint], event));
+    }
+
+    /// Query all tracks which are published in room over the cluster, the answer is [`Output::RoomTracks`] with same query id.
+    /// The room is created if it is not in this node, and it is removed after answered.
+    pub fn query_room_tracks(&mut self, now: Instant, query: u64, app: &AppId, room: &RoomId) {
+        let room_hash = ClusterRoomHash::generate(&AppContext { app: app.clone() }, room);
+        let index = match self.rooms_map.get(&room_hash) {
+            Some(index) => *index,
+            None => {
+                log::info!("[MediaCluster] create room {} for tracks query", room_hash);
+                self.create_room(TrackChannels::new(room_hash, self.cfg.channel_naming, app, room), None)
+            }
+        };
+        self.rooms.on_event(now, index, room::Input::QueryTracks(query));
+    }
+
     pub fn shutdown(&mut self, now: Instant) {
         if self.shutdown {
             return;
@@ -174,13 +451,26 @@ impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> MediaCluster<Endpoint>
         self.rooms.on_shutdown(now);
         self.shutdown = true;
     }
+
+    /// Drain up to `max` outputs into `buf` in one call, same order as repeated [`TaskSwitcherChild::pop_output`].
+    /// Return number of appended outputs, less than `max` means the cluster is drained.
+    pub fn pop_outputs_into(&mut self, buf: &mut Vec<Output<Endpoint>>, max: usize) -> usize {
+        let start = buf.len();
+        while buf.len() - start < max {
+            match self.pop_output(()) {
+                Some(out) => buf.push(out),
+                None => break,
+            }
+        }
+        buf.len() - start
+    }
 }
 
 impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> TaskSwitcherChild<Output<Endpoint>> for MediaCluster<Endpoint> {
     type Time = ();
 
     fn is_empty(&self) -> bool {
-        self.shutdown && self.rooms.is_empty()
+        self.shutdown && self.rooms.is_empty() && self.queue.is_empty()
     }
 
     fn empty_event(&self) -> Output<Endpoint> {
@@ -188,6 +478,9 @@ impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> TaskSwitcherChild<Outpu
     }
 
     fn pop_output(&mut self, _now: Self::Time) -> Option<Output<Endpoint>> {
+        if let Some(out) = self.queue.pop_front() {
+            return Some(out);
+        }
         let (index, out) = match self.rooms.pop_output(())? {
             TaskGroupOutput::TaskOutput(index, out) => (index, out),
             TaskGroupOutput::OnResourceEmpty => return Some(Output::Continue),
@@ -195,10 +488,14 @@ impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> TaskSwitcherChild<Outpu
         match out {
             room::Output::Sdn(userdata, control) => Some(Output::Sdn(userdata, control)),
             room::Output::Endpoint(endpoints, event) => Some(Output::Endpoint(endpoints, event)),
-            room::Output::OnResourceEmpty(room) => {
-                log::info!("[MediaCluster] remove room index {index}, hash {room}");
+            room::Output::Tracks(query, tracks) => Some(Output::RoomTracks(query, tracks)),
+            room::Output::OnResourceEmpty(room, closed) => {
+                log::info!("[MediaCluster] remove room index {index}, hash {room}, closed {closed}");
                 self.rooms_map.swap_remove(&room).expect("Should have room with index");
                 self.rooms.remove_task(index);
+                if closed {
+                    self.closed_rooms.insert(room, None);
+                }
                 Some(Output::Continue)
             }
         }
@@ -207,22 +504,26 @@ impl<Endpoint: Debug + Hash + Copy + Clone + Debug + Eq> TaskSwitcherChild<Outpu
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::time::{Duration, Instant};
 
     use atm0s_sdn::features::{
         dht_kv::{self, MapControl, MapEvent},
         FeaturesControl, FeaturesEvent,
     };
     use media_server_protocol::{
-        endpoint::{PeerId, PeerInfo, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe},
+        endpoint::{PeerId, PeerInfo, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackMeta, TrackName},
+        media::MediaPacket,
         multi_tenancy::{AppContext, AppId},
     };
     use sans_io_runtime::TaskSwitcherChild;
 
-    use crate::cluster::{
-        id_generator,
-        room::{RoomFeature, RoomUserData},
-        ClusterEndpointEvent,
+    use crate::{
+        cluster::{
+            id_generator,
+            room::{RoomFeature, RoomUserData},
+            ClusterEndpointEvent, ClusterJoinRejectReason, ClusterRemoteTrackControl, MediaClusterConfig, RoomTtlConfig, CLOSED_ROOM_KEEP,
+        },
+        transport::RemoteTrackId,
     };
 
     use super::{ClusterEndpointControl, ClusterRoomHash, MediaCluster, Output};
@@ -254,17 +555,21 @@ mod tests {
         let endpoint = 1;
         let userdata = RoomUserData(ClusterRoomHash(1), RoomFeature::MetaData);
         let room_peers_map = id_generator::peers_map(userdata.0);
+        let room_state = RoomUserData(userdata.0, RoomFeature::State);
+        let room_state_map = id_generator::room_state_map(userdata.0);
         let peer = PeerId::from("peer1");
         let peer_key = id_generator::peers_key(&peer);
         let peer_info = PeerInfo::new(peer.clone(), PeerMeta { metadata: None, extra_data: None });
 
         let now = Instant::now();
-        // Not join room with scope (peer true, track false) should Set and Sub
+        // Not join room with scope (peer true, track false) should Set and Sub after room state is synced
         cluster.on_endpoint_control(
             now,
             endpoint,
             userdata.0,
             ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
                 peer.clone(),
                 peer_info.meta.clone(),
                 RoomInfoPublish { peer: true, tracks: false },
@@ -272,6 +577,13 @@ mod tests {
                 None,
             ),
         );
+        assert_eq!(
+            cluster.pop_output(()),
+            Some(Output::Sdn(room_state, FeaturesControl::DhtKv(dht_kv::Control::MapCmd(room_state_map, MapControl::Sub))))
+        );
+        assert_eq!(cluster.pop_output(()), Some(Output::Sdn(room_state, FeaturesControl::DhtKv(dht_kv::Control::MapGet(room_state_map)))));
+        assert_eq!(cluster.pop_output(()), None);
+        sync_room(&mut cluster, userdata.0, now);
         assert_eq!(
             cluster.pop_output(()),
             Some(Output::Sdn(
@@ -309,9 +621,127 @@ mod tests {
             cluster.pop_output(()),
             Some(Output::Sdn(userdata, FeaturesControl::DhtKv(dht_kv::Control::MapCmd(room_peers_map, MapControl::Unsub))))
         );
+        assert_eq!(
+            cluster.pop_output(()),
+            Some(Output::Sdn(room_state, FeaturesControl::DhtKv(dht_kv::Control::MapCmd(room_state_map, MapControl::Unsub))))
+        );
         assert_eq!(cluster.pop_output(()), Some(Output::Continue)); //this is for destroy event
         assert_eq!(cluster.pop_output(()), None);
         assert_eq!(cluster.rooms.tasks(), 0);
         assert_eq!(cluster.rooms_map.len(), 0);
     }
+
+    /// Answer the room state get as a new room in cluster, held joins are processed after that
+    fn sync_room(cluster: &mut MediaCluster<u8>, room: ClusterRoomHash, now: Instant) {
+        let event = dht_kv::Event::MapGetRes(id_generator::room_state_map(room), Ok(vec![]));
+        cluster.on_sdn_event(now, RoomUserData(room, RoomFeature::State), FeaturesEvent::DhtKv(event));
+    }
+
+    fn busy_room(cluster: &mut MediaCluster<u8>, now: Instant) {
+        let room = ClusterRoomHash(1);
+        for endpoint in 0..4 {
+            let join = ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                PeerId::from(format!("peer{endpoint}")),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: true, tracks: true },
+                RoomInfoSubscribe { peers: true, tracks: true },
+                None,
+            );
+            cluster.on_endpoint_control(now, endpoint, room, join);
+            sync_room(cluster, room, now);
+        }
+        let track = RemoteTrackId::from(1);
+        let started = ClusterRemoteTrackControl::Started(TrackName::from("audio_main"), TrackMeta::default_audio());
+        cluster.on_endpoint_control(now, 0, room, ClusterEndpointControl::RemoteTrack(track, started));
+        for seq in 0..10 {
+            let media = ClusterRemoteTrackControl::Media(MediaPacket::build_audio(seq as u32 * 960, seq, None, vec![1, 2, 3]));
+            cluster.on_endpoint_control(now, 0, room, ClusterEndpointControl::RemoteTrack(track, media));
+        }
+    }
+
+    #[test_log::test]
+    fn batched_drain_same_as_single() {
+        let now = Instant::now();
+        let mut single = MediaCluster::<u8>::default();
+        busy_room(&mut single, now);
+        let mut expected = vec![];
+        while let Some(out) = single.pop_output(()) {
+            expected.push(out);
+        }
+        assert!(expected.len() > 10);
+
+        let mut batched = MediaCluster::<u8>::default();
+        busy_room(&mut batched, now);
+        let mut outputs = vec![];
+        assert_eq!(batched.pop_outputs_into(&mut outputs, 3), 3);
+        while batched.pop_outputs_into(&mut outputs, 3) == 3 {}
+        assert_eq!(batched.pop_outputs_into(&mut outputs, 3), 0);
+        assert_eq!(outputs, expected);
+    }
+
+    /// Pop all outputs and return events to endpoints
+    fn endpoint_events(cluster: &mut MediaCluster<u8>) -> Vec<(Vec<u8>, ClusterEndpointEvent)> {
+        std::iter::from_fn(|| cluster.pop_output(()))
+            .filter_map(|out| match out {
+                Output::Endpoint(endpoints, event) => Some((endpoints, event)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test_log::test]
+    fn late_controls_to_closed_room_are_rejected() {
+        let t0 = Instant::now();
+        let room_ttl = RoomTtlConfig {
+            default: Some(Duration::from_secs(10)),
+            warning: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let mut cluster = MediaCluster::<u8>::new(MediaClusterConfig { room_ttl, ..Default::default() });
+        let room = ClusterRoomHash(1);
+        let join = |peer: &str| {
+            ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                peer.into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: true, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+
+        cluster.on_endpoint_control(t0, 1, room, join("peer1"));
+        sync_room(&mut cluster, room, t0);
+        endpoint_events(&mut cluster);
+        cluster.on_tick(t0 + Duration::from_secs(10));
+        assert!(endpoint_events(&mut cluster).contains(&(vec![1], ClusterEndpointEvent::RoomClosed)));
+
+        // endpoint leaves after closed, then the room is removed
+        cluster.on_endpoint_control(t0 + Duration::from_secs(10), 1, room, ClusterEndpointControl::Leave);
+        endpoint_events(&mut cluster);
+        assert_eq!(cluster.rooms.tasks(), 0);
+
+        // late controls are rejected without recreating the room
+        cluster.on_endpoint_control(t0 + Duration::from_secs(10), 1, room, ClusterEndpointControl::SubscribePeer("peer2".into()));
+        assert_eq!(endpoint_events(&mut cluster), vec![(vec![1], ClusterEndpointEvent::RoomClosed)]);
+        cluster.on_endpoint_control(t0 + Duration::from_secs(10), 1, room, ClusterEndpointControl::Leave);
+        assert_eq!(endpoint_events(&mut cluster), vec![]);
+        cluster.on_endpoint_control(t0 + Duration::from_secs(10), 2, room, join("peer2"));
+        assert_eq!(
+            endpoint_events(&mut cluster),
+            vec![(vec![2], ClusterEndpointEvent::JoinRejected("peer2".into(), ClusterJoinRejectReason::RoomClosed))]
+        );
+        assert_eq!(cluster.rooms.tasks(), 0);
+        assert_eq!(cluster.rooms_map.len(), 0);
+
+        // after expired, the room id can be used again
+        cluster.on_tick(t0 + Duration::from_secs(11));
+        cluster.on_tick(t0 + Duration::from_secs(11) + CLOSED_ROOM_KEEP);
+        cluster.on_endpoint_control(t0 + Duration::from_secs(11) + CLOSED_ROOM_KEEP, 2, room, join("peer2"));
+        endpoint_events(&mut cluster);
+        assert_eq!(cluster.rooms.tasks(), 1);
+    }
 }
@@ -0,0 +1,442 @@
+//! Audit log of room membership, track and recording events, for deployments which must keep them for compliance.
+//!
+//! Records are built by rooms from their own join, leave and track publish handlers, and by workers when recording of
+//! a session starts or stops, so only peers which are connected to this node are recorded. A room can exist in many
+//! workers, so the log keeps the state of each room and only records changes. All records of the node are one hash
+//! chain across room lifetimes: a record contains the hash of the previous record, and its own hash is SHA-256 of its
+//! content, so a deleted or modified record breaks the chain, see [`verify_chain`]. The sink keeps the head of the
+//! chain, so a restarted node continues the chain of its existing log.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use media_server_protocol::endpoint::{PeerId, TrackName};
+use sha2::{Digest, Sha256};
+
+use super::ClusterRoomHash;
+
+/// Previous hash of the first record of a chain
+pub const AUDIT_GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// Bytes read from the end of an existing log to find its last record
+const FILE_TAIL_LEN: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEvent {
+    PeerJoined(PeerId),
+    PeerLeaved(PeerId),
+    TrackStarted(PeerId, TrackName),
+    TrackStopped(PeerId, TrackName),
+    /// Recording of the peer session is started or stopped
+    RecordingStateChanged(PeerId, bool),
+}
+
+impl Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PeerJoined(peer) => write!(f, "peer_joined peer={:?}", peer.to_string()),
+            Self::PeerLeaved(peer) => write!(f, "peer_leaved peer={:?}", peer.to_string()),
+            Self::TrackStarted(peer, track) => write!(f, "track_started peer={:?} track={:?}", peer.to_string(), track.to_string()),
+            Self::TrackStopped(peer, track) => write!(f, "track_stopped peer={:?} track={:?}", peer.to_string(), track.to_string()),
+            Self::RecordingStateChanged(peer, recording) => write!(f, "recording_changed peer={:?} recording={recording}", peer.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub room: ClusterRoomHash,
+    /// Index of the record in the chain of the node
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub ts: u64,
+    pub event: AuditEvent,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    /// Content of the record which is hashed, it is same as the written line without hash
+    fn content(&self) -> String {
+        format!("room={} seq={} ts={} prev={} event={}", self.room, self.seq, self.ts, self.prev_hash, self.event)
+    }
+
+    fn compute_hash(&self) -> String {
+        let digest = Sha256::digest(self.content().as_bytes());
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Line which is written to files
+    pub fn line(&self) -> String {
+        format!("{} hash={}", self.content(), self.hash)
+    }
+}
+
+/// Head of a chain, the seq and hash of its last record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditChainHead {
+    pub seq: u64,
+    pub hash: String,
+}
+
+impl AuditChainHead {
+    /// Parse the head from a written line, None if it is not a complete record
+    fn from_line(line: &str) -> Option<Self> {
+        let (content, hash) = line.trim_end().rsplit_once(" hash=")?;
+        let seq = content.split_whitespace().nth(1)?.strip_prefix("seq=")?.parse().ok()?;
+        (hash.len() == AUDIT_GENESIS_HASH.len()).then(|| Self { seq, hash: hash.to_string() })
+    }
+}
+
+/// Return true if records are one unbroken chain, starting from the first record of the node
+pub fn verify_chain(records: &[AuditRecord]) -> bool {
+    let mut prev_hash = AUDIT_GENESIS_HASH.to_string();
+    for (seq, record) in records.iter().enumerate() {
+        if record.seq != seq as u64 || record.prev_hash != prev_hash || record.hash != record.compute_hash() {
+            return false;
+        }
+        prev_hash = record.hash.clone();
+    }
+    true
+}
+
+/// Destination of audit records, it is called in media workers so it should not block for long
+pub trait AuditSink: Send + Sync {
+    fn write(&self, record: &AuditRecord);
+
+    /// Head of the chain which is already written, the first record of this node continues it. None for a new chain
+    fn head(&self) -> Option<AuditChainHead> {
+        None
+    }
+}
+
+/// Append records as lines to a file. Lines are written by a background thread, so workers don't wait for the disk
+pub struct FileAuditSink {
+    tx: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+    head: Option<AuditChainHead>,
+}
+
+impl FileAuditSink {
+    /// Open the file, if it has records their chain is continued. A file whose last line is not a complete record is
+    /// rejected, because the chain can't be continued from it.
+    pub fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let head = read_head(path)?;
+        if let Some(head) = &head {
+            log::info!("[FileAuditSink] continue chain of {} after record {}", path.display(), head.seq);
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::channel();
+        let writer = std::thread::Builder::new().name("room-audit".to_string()).spawn(move || write_lines(rx, BufWriter::new(file)))?;
+        Ok(Self {
+            tx: Some(tx),
+            writer: Some(writer),
+            head,
+        })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn write(&self, record: &AuditRecord) {
+        if self.tx.as_ref().map_or(true, |tx| tx.send(record.line()).is_err()) {
+            log::error!("[FileAuditSink] writer stopped => record {} of room {} lost", record.seq, record.room);
+        }
+    }
+
+    fn head(&self) -> Option<AuditChainHead> {
+        self.head.clone()
+    }
+}
+
+impl Drop for FileAuditSink {
+    /// Wait for queued records to be written
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                log::error!("[FileAuditSink] writer panicked");
+            }
+        }
+    }
+}
+
+/// Read the last record of an existing log, only the tail of the file is read
+fn read_head(path: &Path) -> std::io::Result<Option<AuditChainHead>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(FILE_TAIL_LEN)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let tail = String::from_utf8_lossy(&tail);
+    match tail.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => AuditChainHead::from_line(line)
+            .map(Some)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, format!("last line of audit log is not a record: {line}"))),
+        None => Ok(None),
+    }
+}
+
+/// Write lines until the sink is dropped, a burst of lines is flushed once after all of them are written
+fn write_lines(rx: Receiver<String>, mut writer: BufWriter<File>) {
+    while let Ok(line) = rx.recv() {
+        let mut res = writeln!(writer, "{line}");
+        while let Ok(line) = rx.try_recv() {
+            res = res.and_then(|_| writeln!(writer, "{line}"));
+        }
+        if let Err(e) = res.and_then(|_| writer.flush()) {
+            log::error!("[FileAuditSink] write records error {e}");
+        }
+    }
+}
+
+#[derive(Default)]
+struct RoomMembers {
+    peers: HashSet<PeerId>,
+    tracks: HashSet<(PeerId, TrackName)>,
+    recordings: HashSet<PeerId>,
+}
+
+impl RoomMembers {
+    fn is_empty(&self) -> bool {
+        self.peers.is_empty() && self.tracks.is_empty() && self.recordings.is_empty()
+    }
+}
+
+struct AuditState {
+    next_seq: u64,
+    last_hash: String,
+    rooms: HashMap<ClusterRoomHash, RoomMembers>,
+}
+
+/// Audit state of all rooms, it is shared by media workers so the node has a single chain
+pub struct RoomAudit {
+    sink: Arc<dyn AuditSink>,
+    state: Mutex<AuditState>,
+}
+
+impl RoomAudit {
+    pub fn new(sink: Arc<dyn AuditSink>) -> Self {
+        let (next_seq, last_hash) = match sink.head() {
+            Some(head) => (head.seq + 1, head.hash),
+            None => (0, AUDIT_GENESIS_HASH.to_string()),
+        };
+        Self {
+            sink,
+            state: Mutex::new(AuditState {
+                next_seq,
+                last_hash,
+                rooms: Default::default(),
+            }),
+        }
+    }
+
+    /// Record the event if it changes membership, tracks or recording of the room. When a peer leaves with published
+    /// tracks or an active recording, they are recorded as stopped before it.
+    pub fn on_event(&self, now_ms: u64, room: ClusterRoomHash, event: AuditEvent) {
+        let mut state = self.state.lock().expect("Should lock audit state");
+        let state = &mut *state;
+        let members = state.rooms.entry(room).or_default();
+        let changed = match &event {
+            AuditEvent::PeerJoined(peer) => members.peers.insert(peer.clone()),
+            AuditEvent::PeerLeaved(peer) => members.peers.remove(peer),
+            AuditEvent::TrackStarted(peer, track) => members.tracks.insert((peer.clone(), track.clone())),
+            AuditEvent::TrackStopped(peer, track) => members.tracks.remove(&(peer.clone(), track.clone())),
+            AuditEvent::RecordingStateChanged(peer, true) => members.recordings.insert(peer.clone()),
+            AuditEvent::RecordingStateChanged(peer, false) => members.recordings.remove(peer),
+        };
+        let mut events = vec![];
+        if changed {
+            if let AuditEvent::PeerLeaved(peer) = &event {
+                let mut remain: Vec<_> = members.tracks.iter().filter(|(p, _)| p == peer).cloned().collect();
+                remain.sort_by(|a, b| a.1.to_string().cmp(&b.1.to_string()));
+                for (peer, track) in remain {
+                    members.tracks.remove(&(peer.clone(), track.clone()));
+                    events.push(AuditEvent::TrackStopped(peer, track));
+                }
+                if members.recordings.remove(peer) {
+                    events.push(AuditEvent::RecordingStateChanged(peer.clone(), false));
+                }
+            }
+            events.push(event);
+        }
+        if members.is_empty() {
+            state.rooms.remove(&room);
+        }
+        for event in events {
+            self.write(state, room, now_ms, event);
+        }
+    }
+
+    fn write(&self, state: &mut AuditState, room: ClusterRoomHash, now_ms: u64, event: AuditEvent) {
+        let mut record = AuditRecord {
+            room,
+            seq: state.next_seq,
+            ts: now_ms,
+            event,
+            prev_hash: std::mem::take(&mut state.last_hash),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        state.next_seq += 1;
+        state.last_hash = record.hash.clone();
+        self.sink.write(&record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use media_server_protocol::endpoint::{PeerId, TrackName};
+
+    use crate::cluster::ClusterRoomHash;
+
+    use super::{verify_chain, AuditEvent, AuditRecord, AuditSink, FileAuditSink, RoomAudit, AUDIT_GENESIS_HASH};
+
+    #[derive(Default)]
+    struct VecSink(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for VecSink {
+        fn write(&self, record: &AuditRecord) {
+            self.0.lock().expect("Should lock").push(record.clone());
+        }
+    }
+
+    #[test]
+    fn join_publish_leave_hash_chained() {
+        let sink = Arc::new(VecSink::default());
+        let audit = RoomAudit::new(sink.clone());
+        let room = ClusterRoomHash::from(1);
+        let peer = PeerId::from("peer1");
+        let track = TrackName::from("audio_main");
+        let events = [
+            AuditEvent::PeerJoined(peer.clone()),
+            // same peer joined again without leaving, it is not a change
+            AuditEvent::PeerJoined(peer.clone()),
+            AuditEvent::TrackStarted(peer.clone(), track.clone()),
+            AuditEvent::TrackStarted(peer.clone(), track.clone()),
+            AuditEvent::TrackStopped(peer.clone(), track.clone()),
+            AuditEvent::PeerLeaved(peer.clone()),
+        ];
+        for (i, event) in events.into_iter().enumerate() {
+            audit.on_event(1000 + i as u64, room, event);
+        }
+
+        let records = sink.0.lock().expect("Should lock").clone();
+        assert_eq!(
+            records.iter().map(|r| r.event.clone()).collect::<Vec<_>>(),
+            vec![
+                AuditEvent::PeerJoined(peer.clone()),
+                AuditEvent::TrackStarted(peer.clone(), track.clone()),
+                AuditEvent::TrackStopped(peer.clone(), track.clone()),
+                AuditEvent::PeerLeaved(peer.clone()),
+            ]
+        );
+        assert_eq!(records.iter().map(|r| r.ts).collect::<Vec<_>>(), vec![1000, 1002, 1004, 1005]);
+        assert_eq!(records[0].prev_hash, AUDIT_GENESIS_HASH);
+        assert_eq!(records[1].prev_hash, records[0].hash);
+        assert!(verify_chain(&records));
+
+        // deleted or modified record is detected
+        let mut deleted = records.clone();
+        deleted.remove(1);
+        assert!(!verify_chain(&deleted));
+        let mut modified = records.clone();
+        modified[2].ts += 1;
+        assert!(!verify_chain(&modified));
+
+        // room became empty, its next records continue the chain of the node
+        audit.on_event(2000, room, AuditEvent::PeerJoined(peer.clone()));
+        audit.on_event(2001, ClusterRoomHash::from(2), AuditEvent::PeerJoined(peer.clone()));
+        let records = sink.0.lock().expect("Should lock").clone();
+        assert_eq!(records[4].seq, 4);
+        assert_eq!(records[4].prev_hash, records[3].hash);
+        assert_eq!(records[5].room, ClusterRoomHash::from(2));
+        assert!(verify_chain(&records));
+    }
+
+    #[test]
+    fn leave_with_tracks_stop_tracks_and_recording() {
+        let sink = Arc::new(VecSink::default());
+        let audit = RoomAudit::new(sink.clone());
+        let room = ClusterRoomHash::from(1);
+        let peer1 = PeerId::from("peer1");
+        let peer2 = PeerId::from("peer2");
+        let audio = TrackName::from("audio_main");
+        let video = TrackName::from("video_main");
+        audit.on_event(1000, room, AuditEvent::PeerJoined(peer1.clone()));
+        audit.on_event(1001, room, AuditEvent::PeerJoined(peer2.clone()));
+        audit.on_event(1002, room, AuditEvent::TrackStarted(peer1.clone(), video.clone()));
+        audit.on_event(1003, room, AuditEvent::TrackStarted(peer1.clone(), audio.clone()));
+        audit.on_event(1004, room, AuditEvent::TrackStarted(peer2.clone(), audio.clone()));
+        audit.on_event(1005, room, AuditEvent::RecordingStateChanged(peer1.clone(), true));
+        audit.on_event(1006, room, AuditEvent::RecordingStateChanged(peer1.clone(), true));
+        // peer1 leaves without stopping its tracks and recording
+        audit.on_event(1007, room, AuditEvent::PeerLeaved(peer1.clone()));
+        // track and recording stopped after leaving are already recorded
+        audit.on_event(1008, room, AuditEvent::TrackStopped(peer1.clone(), audio.clone()));
+        audit.on_event(1009, room, AuditEvent::RecordingStateChanged(peer1.clone(), false));
+        audit.on_event(1010, room, AuditEvent::PeerLeaved(peer2.clone()));
+
+        let records = sink.0.lock().expect("Should lock").clone();
+        assert_eq!(
+            records[5..].iter().map(|r| r.event.clone()).collect::<Vec<_>>(),
+            vec![
+                AuditEvent::RecordingStateChanged(peer1.clone(), true),
+                AuditEvent::TrackStopped(peer1.clone(), audio.clone()),
+                AuditEvent::TrackStopped(peer1.clone(), video.clone()),
+                AuditEvent::RecordingStateChanged(peer1.clone(), false),
+                AuditEvent::PeerLeaved(peer1.clone()),
+                AuditEvent::TrackStopped(peer2.clone(), audio.clone()),
+                AuditEvent::PeerLeaved(peer2.clone()),
+            ]
+        );
+        assert!(verify_chain(&records));
+    }
+
+    #[test]
+    fn file_sink_continue_chain_after_restart() {
+        let path = std::env::temp_dir().join(format!("room-audit-{}.log", std::process::id()));
+        let room = ClusterRoomHash::from(1);
+        let peer = PeerId::from("peer1");
+
+        let sink = Arc::new(FileAuditSink::new(&path).expect("Should open"));
+        assert_eq!(sink.head(), None);
+        let audit = RoomAudit::new(sink);
+        audit.on_event(1000, room, AuditEvent::PeerJoined(peer.clone()));
+        audit.on_event(1001, room, AuditEvent::PeerLeaved(peer.clone()));
+        // dropping the sink waits for its records to be written
+        drop(audit);
+
+        let sink = Arc::new(FileAuditSink::new(&path).expect("Should open"));
+        let head = sink.head().expect("Should have head");
+        assert_eq!(head.seq, 1);
+        let audit = RoomAudit::new(sink);
+        audit.on_event(2000, room, AuditEvent::PeerJoined(peer.clone()));
+        drop(audit);
+
+        let content = std::fs::read_to_string(&path).expect("Should read");
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].starts_with(&format!("room={room} seq=2 ts=2000 prev={} ", head.hash)));
+
+        // a log with a partial last record can't be continued
+        std::fs::write(&path, format!("{content}room={room} seq=3")).expect("Should write");
+        assert!(FileAuditSink::new(&path).is_err());
+        std::fs::remove_file(&path).expect("Should remove");
+    }
+}
@@ -1,7 +1,23 @@
-use std::hash::{DefaultHasher, Hash, Hasher};
+//! Ids of cluster keys and pubsub channels.
+//!
+//! Track channels have two naming schemes, which must be the same in all media nodes of a cluster:
+//! - `hashed` (default): hash of room, peer and track, which is not stable between builds
+//! - `named`: the channel name `track:<app>/<room>/<peer>/<track>` with `%` and `/` in each part percent-encoded,
+//!   example `track:app1/room1/alice/video%2Fmain`. The channel id is FNV-1a 64 of the UTF-8 name, so an external
+//!   system can compute it from app and room names for injecting or consuming media, and a name is parsed back with
+//!   [`TrackChannelName`]
+
+use std::{
+    fmt::Display,
+    hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState},
+    str::FromStr,
+};
 
 use atm0s_sdn::features::dht_kv::{Key, Map};
-use media_server_protocol::endpoint::{PeerId, TrackName};
+use media_server_protocol::{
+    endpoint::{PeerId, RoomId, TrackName},
+    multi_tenancy::AppId,
+};
 
 use crate::endpoint::MessageChannelLabel;
 
@@ -28,6 +44,18 @@ pub fn tracks_map(room: ClusterRoomHash) -> Map {
     (room.0 + 1).into()
 }
 
+pub fn room_state_map(room: ClusterRoomHash) -> Map {
+    let mut h = DefaultHasher::new();
+    room.as_ref().hash(&mut h);
+    "room_state".hash(&mut h);
+    h.finish().into()
+}
+
+/// Random key of this node in the room state map, each room instance has its own key
+pub fn room_state_key() -> Key {
+    RandomState::new().build_hasher().finish().into()
+}
+
 pub fn tracks_key(peer: &PeerId, track: &TrackName) -> Key {
     let mut h = DefaultHasher::new();
     peer.as_ref().hash(&mut h);
@@ -35,6 +63,147 @@ pub fn tracks_key(peer: &PeerId, track: &TrackName) -> Key {
     h.finish().into()
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelNaming {
+    #[default]
+    Hashed,
+    Named,
+}
+
+impl FromStr for ChannelNaming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "hashed" => Ok(Self::Hashed),
+            "named" => Ok(Self::Named),
+            _ => Err(format!("unsupported channel naming {s}")),
+        }
+    }
+}
+
+impl Display for ChannelNaming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Hashed => write!(f, "hashed"),
+            Self::Named => write!(f, "named"),
+        }
+    }
+}
+
+/// Reversible name of a track channel in `named` scheme
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackChannelName {
+    pub app: AppId,
+    pub room: RoomId,
+    pub peer: PeerId,
+    pub track: TrackName,
+}
+
+const TRACK_CHANNEL_PREFIX: &str = "track:";
+
+fn encode_part(part: &str) -> String {
+    part.replace('%', "%25").replace('/', "%2F")
+}
+
+fn decode_part(part: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(part.len());
+    let mut rest = part;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        match rest.get(pos + 1..pos + 3) {
+            Some("25") => out.push('%'),
+            Some(code) if code.eq_ignore_ascii_case("2F") => out.push('/'),
+            _ => return Err(format!("invalid escape in channel name part {part}")),
+        }
+        rest = &rest[pos + 3..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl TrackChannelName {
+    /// FNV-1a 64 of the name
+    pub fn channel_id(&self) -> u64 {
+        self.to_string().bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+    }
+}
+
+impl Display for TrackChannelName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{TRACK_CHANNEL_PREFIX}{}/{}/{}/{}",
+            encode_part(&self.app),
+            encode_part(&self.room),
+            encode_part(&self.peer),
+            encode_part(&self.track)
+        )
+    }
+}
+
+impl FromStr for TrackChannelName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s
+            .strip_prefix(TRACK_CHANNEL_PREFIX)
+            .ok_or_else(|| format!("channel name {s} should start with {TRACK_CHANNEL_PREFIX}"))?;
+        let mut parts = name.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(app), Some(room), Some(peer), Some(track), None) => Ok(Self {
+                app: decode_part(app)?.into(),
+                room: decode_part(room)?.into(),
+                peer: decode_part(peer)?.into(),
+                track: decode_part(track)?.into(),
+            }),
+            _ => Err(format!("channel name {s} should be {TRACK_CHANNEL_PREFIX}<app>/<room>/<peer>/<track>")),
+        }
+    }
+}
+
+/// Track channels of a room in the channel naming of the cluster, it is built when the room is created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackChannels {
+    room: ClusterRoomHash,
+    /// App and room names in `named` scheme
+    named: Option<(AppId, RoomId)>,
+}
+
+impl TrackChannels {
+    /// Channels of room with hash `room`, which is generated from `app` and `room_id`
+    pub fn new(room: ClusterRoomHash, naming: ChannelNaming, app: &AppId, room_id: &RoomId) -> Self {
+        Self {
+            room,
+            named: (naming == ChannelNaming::Named).then(|| (app.clone(), room_id.clone())),
+        }
+    }
+
+    /// Channels in `hashed` scheme, for a room whose names are not known
+    pub fn hashed(room: ClusterRoomHash) -> Self {
+        Self { room, named: None }
+    }
+
+    pub fn room(&self) -> ClusterRoomHash {
+        self.room
+    }
+
+    pub fn channel_id<T: From<u64>>(&self, peer: &PeerId, track: &TrackName) -> T {
+        match &self.named {
+            Some((app, room)) => TrackChannelName {
+                app: app.clone(),
+                room: room.clone(),
+                peer: peer.clone(),
+                track: track.clone(),
+            }
+            .channel_id()
+            .into(),
+            None => gen_track_channel_id(self.room, peer, track),
+        }
+    }
+}
+
+/// Channel id in `hashed` scheme
 pub fn gen_track_channel_id<T: From<u64>>(room: ClusterRoomHash, peer: &PeerId, track: &TrackName) -> T {
     let mut h = std::hash::DefaultHasher::new();
     room.as_ref().hash(&mut h);
@@ -57,3 +226,79 @@ pub fn gen_mixer_auto_channel_id<T: From<u64>>(room: ClusterRoomHash) -> T {
     "mixer_auto".hash(&mut h);
     h.finish().into()
 }
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::{
+        endpoint::{PeerId, RoomId, TrackName},
+        multi_tenancy::{AppContext, AppId},
+    };
+
+    use crate::cluster::ClusterRoomHash;
+
+    use super::{gen_track_channel_id, ChannelNaming, TrackChannelName, TrackChannels};
+
+    #[test]
+    fn named_channel_round_trip() {
+        let name = TrackChannelName {
+            app: "app1".into(),
+            room: "room/1".into(),
+            peer: PeerId::from("alice"),
+            track: TrackName::from("video/main%1"),
+        };
+        let encoded = name.to_string();
+        assert_eq!(encoded, "track:app1/room%2F1/alice/video%2Fmain%251");
+        assert_eq!(encoded.parse::<TrackChannelName>(), Ok(name.clone()));
+
+        // channel id is stable, it is FNV-1a 64 of the name
+        assert_eq!(name.channel_id(), name.clone().to_string().parse::<TrackChannelName>().expect("Should parse").channel_id());
+        assert_eq!(
+            TrackChannelName {
+                app: "".into(),
+                room: "".into(),
+                peer: PeerId::from(""),
+                track: TrackName::from(""),
+            }
+            .to_string(),
+            "track:///"
+        );
+        assert_ne!(
+            name.channel_id(),
+            TrackChannelName {
+                track: TrackName::from("video"),
+                ..name.clone()
+            }
+            .channel_id()
+        );
+
+        assert!("track:app1/room1/alice".parse::<TrackChannelName>().is_err());
+        assert!("track:app1/room1/alice/video/main".parse::<TrackChannelName>().is_err());
+        assert!("track:app1/room1/alice/video%2".parse::<TrackChannelName>().is_err());
+        assert_eq!("Named".parse(), Ok(ChannelNaming::Named));
+    }
+
+    #[test]
+    fn track_channels_by_naming() {
+        let app = AppId::from("app1");
+        let room = RoomId::from("room1");
+        let room_hash = ClusterRoomHash::generate(&AppContext { app: app.clone() }, &room);
+        let peer = PeerId::from("bob");
+        let track = TrackName::from("audio_main");
+
+        let named = TrackChannels::new(room_hash, ChannelNaming::Named, &app, &room);
+        let hashed = TrackChannels::new(room_hash, ChannelNaming::Hashed, &app, &room);
+        assert_eq!(named.room(), room_hash);
+
+        // named channel id is computed from names only, so it is same in every node and build
+        let name = TrackChannelName {
+            app,
+            room,
+            peer: peer.clone(),
+            track: track.clone(),
+        };
+        assert_eq!(named.channel_id::<u64>(&peer, &track), name.channel_id());
+        assert_eq!(hashed.channel_id::<u64>(&peer, &track), gen_track_channel_id::<u64>(room_hash, &peer, &track));
+        assert_eq!(TrackChannels::hashed(room_hash), hashed);
+        assert_ne!(named, hashed);
+    }
+}
@@ -6,13 +6,23 @@
 //! - Send/Recv metadata related key-value
 //! - Send/Recv media channel
 //! - AudioMixer feature
+//! - Room-wide state (paused, lock, config, TTL) which is shared with other nodes of the room
 //!
 
-use std::{fmt::Debug, hash::Hash, time::Instant};
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use atm0s_sdn::features::{dht_kv, FeaturesControl, FeaturesEvent};
-use media_server_protocol::message_channel::MessageChannelPacket;
-use media_server_utils::Count;
+use indexmap::IndexMap;
+use media_server_protocol::{
+    endpoint::{AudioMixerConfig, PeerId, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, RoomLockInfo, TrackInfo},
+    message_channel::MessageChannelPacket,
+};
+use media_server_utils::{now_ms, Count};
 use message_channel::RoomMessageChannel;
 use sans_io_runtime::{return_if_none, Task, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
@@ -23,14 +33,34 @@ use crate::{
 
 use audio_mixer::AudioMixer;
 use media_track::MediaTrack;
-use metadata::RoomMetadata;
+use metadata::{JoinKind, RoomMetadata};
+use state::RoomState;
 
-use super::{id_generator, ClusterEndpointControl, ClusterEndpointEvent, ClusterLocalTrackControl, ClusterMessageChannelControl, ClusterRemoteTrackControl, ClusterRoomHash};
+use super::{
+    id_generator::{self, TrackChannels},
+    AuditEvent, ClusterEndpointControl, ClusterEndpointEvent, ClusterJoinRejectReason, ClusterLocalTrackControl, ClusterMessageChannelControl, ClusterRemoteTrackControl, ClusterRoomHash, RoomAudit,
+    RoomConfig, RoomConfigPatch, RoomVideoCodecs, DEFAULT_ROOM_TTL_WARNING,
+};
 
 mod audio_mixer;
 mod media_track;
 mod message_channel;
 mod metadata;
+mod state;
+
+pub use media_track::publisher::{PubDataDropped, UnknownFeedback, UnknownFeedbackPolicy, DEFAULT_MAX_CHANNEL_SOURCES};
+pub use media_track::subscriber::DEFAULT_SOURCE_TIMEOUT;
+pub use metadata::KvRetryPolicy;
+
+/// Pending join in a locked room is rejected if the owner doesn't admit it in time
+const PENDING_JOIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// TTL of a room which is resolved from app policy when the room is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomTtl {
+    pub ttl: Duration,
+    pub warning: Duration,
+}
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum RoomFeature {
@@ -38,6 +68,7 @@ pub enum RoomFeature {
     MediaTrack,
     AudioMixer,
     MessageChannel,
+    State,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -46,13 +77,31 @@ pub struct RoomUserData(pub(crate) ClusterRoomHash, pub(crate) RoomFeature);
 pub enum Input<Endpoint> {
     Sdn(RoomUserData, FeaturesEvent),
     Endpoint(Endpoint, ClusterEndpointControl),
+    /// Read-only query for all tracks of room, with query id
+    QueryTracks(u64),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Output<Endpoint> {
     Sdn(RoomUserData, FeaturesControl),
     Endpoint(Vec<Endpoint>, ClusterEndpointEvent),
-    OnResourceEmpty(ClusterRoomHash),
+    Tracks(u64, Vec<TrackInfo>),
+    /// Room is empty and should be removed, true if it was closed by TTL
+    OnResourceEmpty(ClusterRoomHash, bool),
+}
+
+/// Join which is waiting for room state sync, or for admit in a locked room. The pending peer don't have any media
+/// or presence in room, controls sent by the endpoint meanwhile are kept and replayed after admitted.
+struct PendingJoin {
+    peer: PeerId,
+    /// False while waiting for room state sync, true when waiting for the lock owner
+    waiting_admit: bool,
+    meta: PeerMeta,
+    publish: RoomInfoPublish,
+    subscribe: RoomInfoSubscribe,
+    mixer: Option<AudioMixerConfig>,
+    started_at: Instant,
+    controls: Vec<ClusterEndpointControl>,
 }
 
 #[derive(num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
@@ -62,6 +111,7 @@ enum TaskType {
     MediaTrack,
     AudioMixer,
     MessageChannel,
+    State,
 }
 
 pub struct ClusterRoom<Endpoint: Debug + Copy + Clone + Hash + Eq> {
@@ -71,19 +121,56 @@ pub struct ClusterRoom<Endpoint: Debug + Copy + Clone + Hash + Eq> {
     media_track: TaskSwitcherBranch<MediaTrack<Endpoint>, media_track::Output<Endpoint>>,
     audio_mixer: TaskSwitcherBranch<AudioMixer<Endpoint>, audio_mixer::Output<Endpoint>>,
     message_channel: TaskSwitcherBranch<RoomMessageChannel<Endpoint>, message_channel::Output<Endpoint>>,
+    state: TaskSwitcherBranch<RoomState, state::Output>,
     switcher: TaskSwitcher,
+    /// Paused, config and lock which are applied from the room state
+    paused: bool,
+    config: RoomConfig,
+    lock: RoomLockInfo,
+    pending: IndexMap<Endpoint, PendingJoin>,
+    /// Pending peers which are already sent to the local lock owner
+    pending_notified: Vec<PeerId>,
+    ttl: Option<RoomTtl>,
+    /// Close time and warned state, it is started by the first join in any node
+    deadline: Option<(Instant, bool)>,
+    /// Close time in unix ms which is applied to `deadline`
+    deadline_ms: Option<u64>,
+    /// Room reached TTL, joins are rejected until all peers leaved and the room is removed
+    closed: bool,
+    /// Membership and track events of local peers are recorded here when audit is enabled
+    audit: Option<Arc<RoomAudit>>,
+    /// Video codec which is pinned by sessions of this node, it is synced with the room state
+    video_codecs: Arc<RoomVideoCodecs>,
 }
 
 impl<Endpoint: Debug + Copy + Clone + Hash + Eq> Task<Input<Endpoint>, Output<Endpoint>> for ClusterRoom<Endpoint> {
     fn on_tick(&mut self, now: Instant) {
         self.audio_mixer.input(&mut self.switcher).on_tick(now);
+        self.media_track.input(&mut self.switcher).on_tick(now);
+        self.metadata.input(&mut self.switcher).on_tick(now);
+
+        let timeout_endpoints = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.waiting_admit && now.duration_since(pending.started_at) >= PENDING_JOIN_TIMEOUT)
+            .map(|(endpoint, _)| *endpoint)
+            .collect::<Vec<_>>();
+        for endpoint in timeout_endpoints {
+            self.reject_pending(endpoint, ClusterJoinRejectReason::PendingTimeout);
+        }
+
+        self.state.input(&mut self.switcher).on_tick(now);
+        self.check_ttl(now);
+        self.sync_state(now);
     }
 
     fn on_event(&mut self, now: Instant, input: Input<Endpoint>) {
         match input {
             Input::Endpoint(endpoint, control) => self.on_endpoint_control(now, endpoint, control),
             Input::Sdn(userdata, event) => self.on_sdn_event(now, userdata, event),
+            Input::QueryTracks(query) => self.metadata.input(&mut self.switcher).on_query_tracks(query),
         }
+        self.sync_state(now);
     }
 
     fn on_shutdown(&mut self, _now: Instant) {
@@ -95,11 +182,11 @@ impl<Endpoint: Debug + Copy + Clone + Hash + Eq> TaskSwitcherChild<Output<Endpoi
     type Time = ();
 
     fn is_empty(&self) -> bool {
-        self.metadata.is_empty() && self.media_track.is_empty() && self.audio_mixer.is_empty() && self.message_channel.is_empty()
+        self.metadata.is_empty() && self.media_track.is_empty() && self.audio_mixer.is_empty() && self.message_channel.is_empty() && self.state.is_empty() && self.pending.is_empty()
     }
 
     fn empty_event(&self) -> Output<Endpoint> {
-        Output::OnResourceEmpty(self.room)
+        Output::OnResourceEmpty(self.room, self.closed)
     }
 
     fn pop_output(&mut self, _now: Self::Time) -> Option<Output<Endpoint>> {
@@ -110,6 +197,7 @@ impl<Endpoint: Debug + Copy + Clone + Hash + Eq> TaskSwitcherChild<Output<Endpoi
                         match out {
                             metadata::Output::Kv(control) => break Some(Output::Sdn(RoomUserData(self.room, RoomFeature::MetaData), FeaturesControl::DhtKv(control))),
                             metadata::Output::Endpoint(endpoints, event) => break Some(Output::Endpoint(endpoints, event)),
+                            metadata::Output::Tracks(query, tracks) => break Some(Output::Tracks(query, tracks)),
                             metadata::Output::OnResourceEmpty => {
                                 log::info!("[ClusterRoom] on metadata empty");
                             }
@@ -149,33 +237,71 @@ impl<Endpoint: Debug + Copy + Clone + Hash + Eq> TaskSwitcherChild<Output<Endpoi
                         }
                     }
                 }
+                TaskType::State => {
+                    if let Some(out) = self.state.pop_output((), &mut self.switcher) {
+                        match out {
+                            state::Output::Kv(control) => break Some(Output::Sdn(RoomUserData(self.room, RoomFeature::State), FeaturesControl::DhtKv(control))),
+                            state::Output::OnResourceEmpty => {
+                                log::info!("[ClusterRoom] on room state empty");
+                            }
+                        }
+                    }
+                }
             }
         }
     }
 }
 
 impl<Endpoint: Debug + Copy + Clone + Hash + Eq> ClusterRoom<Endpoint> {
-    pub fn new(room: ClusterRoomHash) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        track_channels: TrackChannels,
+        message_max_payload: usize,
+        ttl: Option<RoomTtl>,
+        unknown_feedback: UnknownFeedbackPolicy,
+        max_channel_sources: usize,
+        source_timeout: Duration,
+        leave_grace: Duration,
+        kv_retry: KvRetryPolicy,
+        audit: Option<Arc<RoomAudit>>,
+        video_codecs: Arc<RoomVideoCodecs>,
+    ) -> Self {
+        let room = track_channels.room();
         let mixer_channel_id = id_generator::gen_mixer_auto_channel_id(room);
         Self {
             _c: Default::default(),
             room,
-            metadata: TaskSwitcherBranch::new(RoomMetadata::new(room), TaskType::Metadata),
-            media_track: TaskSwitcherBranch::new(MediaTrack::new(room), TaskType::MediaTrack),
-            audio_mixer: TaskSwitcherBranch::new(AudioMixer::new(room, mixer_channel_id), TaskType::AudioMixer),
-            message_channel: TaskSwitcherBranch::new(RoomMessageChannel::new(room), TaskType::MessageChannel),
-            switcher: TaskSwitcher::new(4),
+            metadata: TaskSwitcherBranch::new(RoomMetadata::new(room, leave_grace, kv_retry), TaskType::Metadata),
+            media_track: TaskSwitcherBranch::new(MediaTrack::new(track_channels.clone(), unknown_feedback, max_channel_sources, source_timeout), TaskType::MediaTrack),
+            audio_mixer: TaskSwitcherBranch::new(AudioMixer::new(track_channels, mixer_channel_id), TaskType::AudioMixer),
+            message_channel: TaskSwitcherBranch::new(RoomMessageChannel::new(room, message_max_payload), TaskType::MessageChannel),
+            state: TaskSwitcherBranch::new(RoomState::new(room), TaskType::State),
+            switcher: TaskSwitcher::new(5),
+            paused: false,
+            config: RoomConfig::default(),
+            lock: RoomLockInfo::Unlocked,
+            pending: Default::default(),
+            pending_notified: vec![],
+            ttl,
+            deadline: None,
+            deadline_ms: None,
+            closed: false,
+            audit,
+            video_codecs,
         }
     }
 
     fn on_sdn_event(&mut self, now: Instant, userdata: RoomUserData, event: FeaturesEvent) {
         match (userdata.1, event) {
             (RoomFeature::MetaData, FeaturesEvent::DhtKv(event)) => match event {
-                dht_kv::Event::MapEvent(map, event) => self.metadata.input(&mut self.switcher).on_kv_event(map, event),
-                dht_kv::Event::MapGetRes(_, _) => {}
+                dht_kv::Event::MapEvent(map, event) => self.metadata.input(&mut self.switcher).on_kv_event(now, map, event),
+                dht_kv::Event::MapGetRes(map, res) => {
+                    let res = res.map(|entries| entries.into_iter().map(|(_key, _source, _version, data)| data).collect::<Vec<_>>());
+                    self.metadata.input(&mut self.switcher).on_kv_get_res(map, res);
+                }
             },
             (RoomFeature::MediaTrack, FeaturesEvent::PubSub(event)) => {
-                self.media_track.input(&mut self.switcher).on_pubsub_event(event);
+                self.media_track.input(&mut self.switcher).on_pubsub_event(now, event);
             }
             (RoomFeature::AudioMixer, FeaturesEvent::PubSub(event)) => {
                 self.audio_mixer.input(&mut self.switcher).on_pubsub_event(now, event);
@@ -183,20 +309,87 @@ impl<Endpoint: Debug + Copy + Clone + Hash + Eq> ClusterRoom<Endpoint> {
             (RoomFeature::MessageChannel, FeaturesEvent::PubSub(event)) => {
                 self.message_channel.input(&mut self.switcher).on_pubsub_event(event);
             }
+            (RoomFeature::State, FeaturesEvent::DhtKv(event)) => match event {
+                dht_kv::Event::MapEvent(map, event) => self.state.input(&mut self.switcher).on_kv_event(map, event),
+                dht_kv::Event::MapGetRes(map, res) => {
+                    let res = res.map(|entries| entries.into_iter().map(|(key, _source, _version, data)| (key, data)).collect::<Vec<_>>());
+                    self.state.input(&mut self.switcher).on_kv_get_res(map, res);
+                }
+            },
             _ => {}
         }
     }
 
     fn on_endpoint_control(&mut self, now: Instant, endpoint: Endpoint, control: ClusterEndpointControl) {
+        let control = return_if_none!(self.hold_pending_control(endpoint, control));
         match control {
-            ClusterEndpointControl::Join(peer, meta, publish, subscribe, mixer) => {
-                self.audio_mixer.input(&mut self.switcher).on_join(now, endpoint, peer.clone(), mixer);
-                self.metadata.input(&mut self.switcher).on_join(endpoint, peer, meta, publish, subscribe);
+            ClusterEndpointControl::Join(_app, _room, peer, meta, publish, subscribe, mixer) => {
+                let _span = tracing::info_span!("cluster_room", room_hash = %self.room, peer_id = %peer).entered();
+                if self.closed {
+                    tracing::warn!(endpoint = ?endpoint, "[ClusterRoom] room closed by ttl => reject");
+                    self.metadata.input(&mut self.switcher).on_join_rejected(endpoint, peer, ClusterJoinRejectReason::RoomClosed);
+                    return;
+                }
+                if let (Some(ttl), None) = (self.ttl, self.deadline) {
+                    tracing::info!(ttl_ms = ttl.ttl.as_millis() as u64, "[ClusterRoom] room ttl started");
+                    let deadline_ms = now_ms() + ttl.ttl.as_millis() as u64;
+                    self.deadline = Some((now + ttl.ttl, false));
+                    self.deadline_ms = Some(deadline_ms);
+                    self.state.input(&mut self.switcher).set_deadline(deadline_ms);
+                }
+                match self.metadata.join_kind(endpoint, &peer) {
+                    JoinKind::Fresh if self.pending.values().any(|pending| pending.peer == peer) => {
+                        tracing::warn!(endpoint = ?endpoint, "[ClusterRoom] peer already waiting for admit => reject");
+                        self.metadata.input(&mut self.switcher).on_join_rejected(endpoint, peer, ClusterJoinRejectReason::AlreadyJoined);
+                    }
+                    JoinKind::Fresh => {
+                        self.state.input(&mut self.switcher).activate();
+                        let synced = self.state.is_synced();
+                        if !synced || matches!(self.lock, RoomLockInfo::Locked(_)) {
+                            // the owner is notified by room state sync, which includes pending peers of other nodes
+                            tracing::info!(endpoint = ?endpoint, synced, "[ClusterRoom] room state not synced or room locked => peer join pending");
+                            self.pending.insert(
+                                endpoint,
+                                PendingJoin {
+                                    peer,
+                                    waiting_admit: synced,
+                                    meta,
+                                    publish,
+                                    subscribe,
+                                    mixer,
+                                    started_at: now,
+                                    controls: vec![],
+                                },
+                            );
+                        } else {
+                            self.join(now, endpoint, peer, meta, publish, subscribe, mixer);
+                        }
+                    }
+                    JoinKind::Update => {
+                        tracing::info!(endpoint = ?endpoint, "[ClusterRoom] peer join again");
+                        self.metadata.input(&mut self.switcher).on_rejoin(endpoint, meta);
+                    }
+                    JoinKind::Rejected => {
+                        tracing::warn!(endpoint = ?endpoint, "[ClusterRoom] peer already joined => reject");
+                        self.metadata.input(&mut self.switcher).on_join_rejected(endpoint, peer, ClusterJoinRejectReason::AlreadyJoined);
+                    }
+                }
             }
             ClusterEndpointControl::Leave => {
+                let _span = tracing::info_span!("cluster_room", room_hash = %self.room).entered();
+                tracing::info!(endpoint = ?endpoint, "[ClusterRoom] peer leave");
+                let peer = self.metadata.get_peer_from_endpoint(endpoint);
+                if let Some(peer) = &peer {
+                    self.record_audit(AuditEvent::PeerLeaved(peer.clone()));
+                }
                 self.audio_mixer.input(&mut self.switcher).on_leave(now, endpoint);
                 self.metadata.input(&mut self.switcher).on_leave(endpoint);
                 self.message_channel.input(&mut self.switcher).on_leave(endpoint);
+                if peer.is_some_and(|peer| self.lock == RoomLockInfo::Locked(peer)) {
+                    // lock is bound to the owner, nobody can admit pending peers after it left
+                    tracing::info!(endpoint = ?endpoint, "[ClusterRoom] lock owner leave => unlock and reject pending peers");
+                    self.state.input(&mut self.switcher).set_lock(now_ms(), RoomLockInfo::OwnerLeft);
+                }
             }
             ClusterEndpointControl::SubscribePeer(target) => {
                 self.metadata.input(&mut self.switcher).on_subscribe_peer(endpoint, target);
@@ -204,6 +397,18 @@ impl<Endpoint: Debug + Copy + Clone + Hash + Eq> ClusterRoom<Endpoint> {
             ClusterEndpointControl::UnsubscribePeer(target) => {
                 self.metadata.input(&mut self.switcher).on_unsubscribe_peer(endpoint, target);
             }
+            ClusterEndpointControl::BatchTrackSubscriptions { subscribe, unsubscribe } => {
+                self.metadata.input(&mut self.switcher).on_batch_track_subscriptions(endpoint, subscribe, unsubscribe);
+            }
+            ClusterEndpointControl::SetTrackMuted(track, muted) => {
+                self.metadata.input(&mut self.switcher).on_track_muted(endpoint, track, muted);
+            }
+            ClusterEndpointControl::PauseRoom => self.set_paused(endpoint, true),
+            ClusterEndpointControl::ResumeRoom => self.set_paused(endpoint, false),
+            ClusterEndpointControl::SetRoomLocked(locked) => self.set_locked(endpoint, locked),
+            ClusterEndpointControl::UpdateRoomConfig(patch) => self.update_config(endpoint, patch),
+            ClusterEndpointControl::AdmitPeer(peer) => self.decide_pending(now, endpoint, peer, true),
+            ClusterEndpointControl::RejectPeer(peer) => self.decide_pending(now, endpoint, peer, false),
             ClusterEndpointControl::AudioMixer(control) => {
                 self.audio_mixer.input(&mut self.switcher).on_control(now, endpoint, control);
             }
@@ -215,11 +420,270 @@ impl<Endpoint: Debug + Copy + Clone + Hash + Eq> ClusterRoom<Endpoint> {
 }
 
 impl<Endpoint: Debug + Clone + Copy + Hash + Eq> ClusterRoom<Endpoint> {
+    #[allow(clippy::too_many_arguments)]
+    fn join(&mut self, now: Instant, endpoint: Endpoint, peer: PeerId, meta: PeerMeta, publish: RoomInfoPublish, subscribe: RoomInfoSubscribe, mixer: Option<AudioMixerConfig>) {
+        tracing::info!(endpoint = ?endpoint, "[ClusterRoom] peer join");
+        self.record_audit(AuditEvent::PeerJoined(peer.clone()));
+        let subscribe_tracks = subscribe.tracks;
+        let cluster_mixer = self.state.remote_mixer().map(|mixer| (mixer.mode, mixer.outputs));
+        self.audio_mixer.input(&mut self.switcher).on_join(now, endpoint, peer.clone(), mixer, cluster_mixer);
+        self.metadata.input(&mut self.switcher).on_join(endpoint, peer, meta, publish, subscribe);
+        if subscribe_tracks {
+            // new subscriber should decode video of the room immediately
+            self.media_track.input(&mut self.switcher).request_video_key_frames(now);
+        }
+        if self.paused {
+            self.metadata.input(&mut self.switcher).on_room_paused(Some(endpoint), true);
+        }
+        if self.config != RoomConfig::default() {
+            self.metadata
+                .input(&mut self.switcher)
+                .on_endpoint_event(endpoint, ClusterEndpointEvent::RoomConfigChanged(self.config));
+        }
+    }
+
+    fn record_audit(&self, event: AuditEvent) {
+        if let Some(audit) = &self.audit {
+            audit.on_event(now_ms(), self.room, event);
+        }
+    }
+
+    /// Controls from a pending endpoint are kept until it is admitted. Media and periodic feedbacks are dropped
+    /// and room management controls are ignored because the peer is not in room yet.
+    /// Join or Leave cancel the pending state and are processed as normal.
+    fn hold_pending_control(&mut self, endpoint: Endpoint, control: ClusterEndpointControl) -> Option<ClusterEndpointControl> {
+        let pending = match self.pending.get_mut(&endpoint) {
+            Some(pending) => pending,
+            None => return Some(control),
+        };
+        match control {
+            ClusterEndpointControl::Join(..) | ClusterEndpointControl::Leave => {
+                log::info!("[ClusterRoom {}] pending peer ({}) join again or leave => cancel pending", self.room, pending.peer);
+                self.pending.swap_remove(&endpoint);
+                Some(control)
+            }
+            ClusterEndpointControl::RemoteTrack(_, ClusterRemoteTrackControl::Media(_))
+            | ClusterEndpointControl::LocalTrack(_, ClusterLocalTrackControl::RequestKeyFrame | ClusterLocalTrackControl::DesiredBitrate(_))
+            | ClusterEndpointControl::MessageChannel(_, ClusterMessageChannelControl::PublishData(..))
+            | ClusterEndpointControl::PauseRoom
+            | ClusterEndpointControl::ResumeRoom
+            | ClusterEndpointControl::SetRoomLocked(_)
+            | ClusterEndpointControl::UpdateRoomConfig(_)
+            | ClusterEndpointControl::AdmitPeer(_)
+            | ClusterEndpointControl::RejectPeer(_) => None,
+            control => {
+                pending.controls.push(control);
+                None
+            }
+        }
+    }
+
+    /// Any joined peer can lock the room and becomes the owner, any joined peer can unlock it
+    fn set_locked(&mut self, endpoint: Endpoint, locked: bool) {
+        let peer = return_if_none!(self.metadata.get_peer_from_endpoint(endpoint));
+        let _span = tracing::info_span!("cluster_room", room_hash = %self.room, peer_id = %peer).entered();
+        if locked {
+            tracing::info!(endpoint = ?endpoint, "[ClusterRoom] lock room");
+            self.state.input(&mut self.switcher).set_lock(now_ms(), RoomLockInfo::Locked(peer));
+        } else if matches!(self.lock, RoomLockInfo::Locked(_)) {
+            tracing::info!(endpoint = ?endpoint, "[ClusterRoom] unlock room");
+            self.state.input(&mut self.switcher).set_lock(now_ms(), RoomLockInfo::Unlocked);
+        }
+    }
+
+    /// Only the lock owner can admit or reject pending peers. Peers which are pending in this node are decided
+    /// immediately, others are decided by the node which holds them after it receives the decision.
+    fn decide_pending(&mut self, now: Instant, endpoint: Endpoint, peer: PeerId, admit: bool) {
+        let owner = return_if_none!(self.metadata.get_peer_from_endpoint(endpoint));
+        if self.lock != RoomLockInfo::Locked(owner) {
+            log::warn!("[ClusterRoom {}] only lock owner can admit or reject peer ({peer})", self.room);
+            return;
+        }
+        let pending_endpoint = self.pending.iter().find(|(_, pending)| pending.waiting_admit && pending.peer == peer).map(|(endpoint, _)| *endpoint);
+        match pending_endpoint {
+            Some(pending_endpoint) if admit => self.admit_pending(now, pending_endpoint),
+            Some(pending_endpoint) => self.reject_pending(pending_endpoint, ClusterJoinRejectReason::NotAdmitted),
+            None => self.state.input(&mut self.switcher).set_decision(peer, admit),
+        }
+    }
+
+    fn admit_pending(&mut self, now: Instant, endpoint: Endpoint) {
+        let pending = return_if_none!(self.pending.swap_remove(&endpoint));
+        let _span = tracing::info_span!("cluster_room", room_hash = %self.room, peer_id = %pending.peer).entered();
+        tracing::info!(endpoint = ?endpoint, waited_ms = now.duration_since(pending.started_at).as_millis() as u64, "[ClusterRoom] admit pending peer");
+        self.join(now, endpoint, pending.peer, pending.meta, pending.publish, pending.subscribe, pending.mixer);
+        for control in pending.controls {
+            self.on_endpoint_control(now, endpoint, control);
+        }
+    }
+
+    fn reject_pending(&mut self, endpoint: Endpoint, reason: ClusterJoinRejectReason) {
+        let pending = return_if_none!(self.pending.swap_remove(&endpoint));
+        self.metadata.input(&mut self.switcher).on_join_rejected(endpoint, pending.peer, reason);
+    }
+
+    /// Warn peers before the room TTL is reached, then close the room. Peers leave and disconnect by themselves
+    /// after RoomClosed, the room is removed as normal when it is empty.
+    fn check_ttl(&mut self, now: Instant) {
+        // deadline can be started by other nodes which have different ttl config
+        let warning = self.ttl.map_or(DEFAULT_ROOM_TTL_WARNING, |ttl| ttl.warning);
+        let (close_at, warned) = return_if_none!(self.deadline);
+        let _span = tracing::info_span!("cluster_room", room_hash = %self.room).entered();
+        if now >= close_at {
+            tracing::info!(pending = self.pending.len(), "[ClusterRoom] room ttl reached => close room");
+            self.deadline = None;
+            self.closed = true;
+            for endpoint in self.pending.keys().copied().collect::<Vec<_>>() {
+                self.reject_pending(endpoint, ClusterJoinRejectReason::RoomClosed);
+            }
+            self.metadata.input(&mut self.switcher).on_room_event(ClusterEndpointEvent::RoomClosed);
+        } else if !warned && now + warning >= close_at {
+            tracing::info!(remain_ms = (close_at - now).as_millis() as u64, "[ClusterRoom] room ttl nearly reached => warn peers");
+            self.deadline = Some((close_at, true));
+            self.metadata.input(&mut self.switcher).on_room_event(ClusterEndpointEvent::RoomClosingSoon(close_at - now));
+        }
+    }
+
+    fn set_paused(&mut self, endpoint: Endpoint, paused: bool) {
+        let peer = return_if_none!(self.metadata.get_peer_from_endpoint(endpoint));
+        let _span = tracing::info_span!("cluster_room", room_hash = %self.room, peer_id = %peer).entered();
+        tracing::info!(endpoint = ?endpoint, paused, "[ClusterRoom] set room paused");
+        self.state.input(&mut self.switcher).set_paused(now_ms(), paused);
+    }
+
+    /// Only live fields are applied, fields which require rejoin are rejected by the endpoint before sending the patch
+    fn update_config(&mut self, endpoint: Endpoint, patch: RoomConfigPatch) {
+        let peer = return_if_none!(self.metadata.get_peer_from_endpoint(endpoint));
+        let _span = tracing::info_span!("cluster_room", room_hash = %self.room, peer_id = %peer).entered();
+        let rejoin_fields = patch.rejoin_fields();
+        if !rejoin_fields.is_empty() {
+            tracing::warn!(endpoint = ?endpoint, fields = ?rejoin_fields, "[ClusterRoom] room config fields require rejoin => ignore patch");
+            return;
+        }
+        tracing::info!(endpoint = ?endpoint, patch = ?patch, "[ClusterRoom] update room config");
+        self.state.input(&mut self.switcher).set_config(now_ms(), &patch);
+    }
+
+    /// Apply room state which is merged from all nodes to the local room, then write the local entry back.
+    /// It is called after each event and tick, so controls which change the state are applied in the same call.
+    fn sync_state(&mut self, now: Instant) {
+        if !self.metadata.has_peers() && self.pending.is_empty() {
+            self.state.input(&mut self.switcher).deactivate();
+        }
+        if !self.state.is_active() {
+            return;
+        }
+        if let Some(deadline_ms) = self.deadline_ms {
+            self.state.input(&mut self.switcher).set_deadline(deadline_ms);
+        }
+        if !self.state.is_synced() {
+            // room-wide values are unknown until synced, joins are held meanwhile
+            self.state.input(&mut self.switcher).flush();
+            return;
+        }
+
+        let _span = tracing::info_span!("cluster_room", room_hash = %self.room).entered();
+        let merged = self.state.merged();
+        if merged.paused.value != self.paused {
+            tracing::info!(paused = merged.paused.value, "[ClusterRoom] room paused changed");
+            self.paused = merged.paused.value;
+            self.media_track.input(&mut self.switcher).on_room_paused(self.paused);
+            self.metadata.input(&mut self.switcher).on_room_paused(None, self.paused);
+        }
+
+        let config = merged.config();
+        if config != self.config {
+            tracing::info!(config = ?config, "[ClusterRoom] room config changed");
+            self.config = config;
+            self.metadata.input(&mut self.switcher).on_room_event(ClusterEndpointEvent::RoomConfigChanged(config));
+        }
+
+        if let Some(deadline_ms) = merged.deadline.filter(|deadline_ms| !self.closed && self.deadline_ms != Some(*deadline_ms)) {
+            tracing::info!(deadline_ms, "[ClusterRoom] room deadline changed by other node");
+            let warned = self.deadline.is_some_and(|(_, warned)| warned);
+            self.deadline = Some((now + Duration::from_millis(deadline_ms.saturating_sub(now_ms())), warned));
+            self.deadline_ms = Some(deadline_ms);
+        }
+
+        if merged.lock.value != self.lock {
+            tracing::info!(lock = ?merged.lock.value, "[ClusterRoom] room lock changed");
+            self.lock = merged.lock.value.clone();
+            self.pending_notified.clear();
+            let waiting = self.pending.iter().filter(|(_, pending)| pending.waiting_admit).map(|(endpoint, _)| *endpoint).collect::<Vec<_>>();
+            match self.lock {
+                RoomLockInfo::Unlocked => {
+                    for endpoint in waiting {
+                        self.admit_pending(now, endpoint);
+                    }
+                }
+                RoomLockInfo::OwnerLeft => {
+                    for endpoint in waiting {
+                        self.reject_pending(endpoint, ClusterJoinRejectReason::NotAdmitted);
+                    }
+                }
+                RoomLockInfo::Locked(_) => {}
+            }
+        }
+
+        let locked = matches!(self.lock, RoomLockInfo::Locked(_));
+        let syncing = self.pending.iter().filter(|(_, pending)| !pending.waiting_admit).map(|(endpoint, _)| *endpoint).collect::<Vec<_>>();
+        for endpoint in syncing {
+            if !locked {
+                self.admit_pending(now, endpoint);
+            } else if let Some(pending) = self.pending.get_mut(&endpoint) {
+                tracing::info!(endpoint = ?endpoint, "[ClusterRoom] room locked => peer join pending");
+                pending.waiting_admit = true;
+                pending.started_at = now;
+            }
+        }
+
+        for (peer, admit) in merged.decisions.iter() {
+            let endpoint = self.pending.iter().find(|(_, pending)| pending.waiting_admit && pending.peer == *peer).map(|(endpoint, _)| *endpoint);
+            match endpoint {
+                Some(endpoint) if *admit => self.admit_pending(now, endpoint),
+                Some(endpoint) => self.reject_pending(endpoint, ClusterJoinRejectReason::NotAdmitted),
+                None => {}
+            }
+        }
+
+        // the codec which is pinned first in the cluster is used by all nodes, local sessions only pin it when the room doesn't have one
+        match (&merged.video_codec, self.video_codecs.get(self.room)) {
+            (Some(codec), _) => self.video_codecs.update(self.room, &codec.value),
+            (None, Some(local)) => self.state.input(&mut self.switcher).set_video_codec(now_ms(), local),
+            (None, None) => {}
+        }
+
+        let waiting = self.pending.values().filter(|pending| pending.waiting_admit).map(|pending| pending.peer.clone()).collect::<Vec<_>>();
+        let mixer = self.audio_mixer.room_cfg();
+        let state = self.state.input(&mut self.switcher);
+        state.set_pending(waiting);
+        state.set_mixer(now_ms(), mixer);
+
+        // the owner is notified about peers which are pending in any node
+        let merged = self.state.merged();
+        if let RoomLockInfo::Locked(owner) = &self.lock {
+            if let Some(owner_endpoint) = self.metadata.get_endpoint_from_peer(owner) {
+                let new_pending = merged.pending.iter().filter(|peer| !self.pending_notified.contains(peer)).cloned().collect::<Vec<_>>();
+                for peer in new_pending {
+                    self.metadata.input(&mut self.switcher).on_join_pending(owner_endpoint, peer);
+                }
+            }
+        }
+        self.pending_notified = merged.pending.clone();
+
+        let state = self.state.input(&mut self.switcher);
+        state.retain_decisions(&merged.pending);
+        state.mirror(&merged);
+        state.flush();
+    }
+
     fn on_control_remote_track(&mut self, now: Instant, endpoint: Endpoint, track: RemoteTrackId, control: ClusterRemoteTrackControl) {
         match control {
             ClusterRemoteTrackControl::Started(name, meta) => {
                 let peer = return_if_none!(self.metadata.get_peer_from_endpoint(endpoint));
-                log::info!("[ClusterRoom {}] started track {:?}/{track} => {peer}/{name}", self.room, endpoint);
+                let _span = tracing::info_span!("cluster_room", room_hash = %self.room, peer_id = %peer).entered();
+                tracing::info!(endpoint = ?endpoint, track = %track, name = %name, "[ClusterRoom] started track");
+                self.record_audit(AuditEvent::TrackStarted(peer.clone(), name.clone()));
 
                 if meta.kind.is_audio() {
                     self.audio_mixer.input(&mut self.switcher).on_track_publish(now, endpoint, track, peer.clone(), name.clone());
@@ -228,18 +692,22 @@ impl<Endpoint: Debug + Clone + Copy + Hash + Eq> ClusterRoom<Endpoint> {
                 self.metadata.input(&mut self.switcher).on_track_publish(endpoint, track, name, meta.clone());
             }
             ClusterRemoteTrackControl::Media(media) => {
-                if media.meta.is_audio() {
+                if media.meta.is_audio() && !self.paused {
                     self.audio_mixer.input(&mut self.switcher).on_track_data(now, endpoint, track, &media);
                 }
                 self.media_track.input(&mut self.switcher).on_track_data(endpoint, track, media);
             }
-            ClusterRemoteTrackControl::Ended(_name, meta) => {
-                log::info!("[ClusterRoom {}] stopped track {:?}/{track}", self.room, endpoint);
+            ClusterRemoteTrackControl::Ended(name, meta) => {
+                let _span = tracing::info_span!("cluster_room", room_hash = %self.room).entered();
+                tracing::info!(endpoint = ?endpoint, track = %track, "[ClusterRoom] stopped track");
+                if let Some(peer) = self.metadata.get_peer_from_endpoint(endpoint) {
+                    self.record_audit(AuditEvent::TrackStopped(peer, name));
+                }
 
                 if meta.kind.is_audio() {
                     self.audio_mixer.input(&mut self.switcher).on_track_unpublish(now, endpoint, track);
                 }
-                self.media_track.input(&mut self.switcher).on_track_unpublish(endpoint, track);
+                self.media_track.input(&mut self.switcher).on_track_unpublish(now, endpoint, track);
                 self.metadata.input(&mut self.switcher).on_track_unpublish(endpoint, track);
             }
         }
@@ -275,20 +743,36 @@ impl<Endpoint: Debug + Copy + Clone + Hash + Eq> Drop for ClusterRoom<Endpoint>
         assert!(self.media_track.is_empty(), "Media track not empty, {:?}", self.media_track);
         assert!(self.metadata.is_empty(), "Metadata not empty, {:?}", self.metadata);
         assert!(self.message_channel.is_empty(), "Data channel not empty, {:?}", self.message_channel);
+        assert!(self.state.is_empty(), "Room state not empty, {:?}", self.state);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
 
-    use atm0s_sdn::features::{dht_kv, pubsub, FeaturesControl};
-    use media_server_protocol::endpoint::{AudioMixerConfig, AudioMixerMode, PeerId, PeerMeta, RoomInfoPublish, RoomInfoSubscribe};
+    use atm0s_sdn::features::{dht_kv, pubsub, FeaturesControl, FeaturesEvent};
+    use media_server_protocol::{
+        endpoint::{AudioMixerConfig, AudioMixerMode, BitrateControlMode, PeerId, PeerInfo, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, TrackMeta},
+        media::{MediaKind, MediaMeta, MediaPacket, MediaScaling},
+        multi_tenancy::AppId,
+    };
     use sans_io_runtime::{Task, TaskSwitcherChild};
 
-    use crate::cluster::{id_generator, room::RoomFeature, ClusterEndpointControl, RoomUserData};
+    use crate::{
+        cluster::{
+            id_generator::{self, TrackChannels},
+            room::RoomFeature,
+            AuditEvent, AuditRecord, AuditSink, ClusterAudioMixerControl, ClusterEndpointControl, ClusterEndpointEvent, ClusterJoinRejectReason, ClusterRemoteTrackControl, ClusterRemoteTrackEvent,
+            RoomAudit, RoomConfig, RoomConfigPatch, RoomUserData, DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+        },
+        transport::RemoteTrackId,
+    };
 
-    use super::{ClusterRoom, Input, Output};
+    use super::{ClusterRoom, Input, KvRetryPolicy, Output, RoomTtl, UnknownFeedbackPolicy, DEFAULT_MAX_CHANNEL_SOURCES, DEFAULT_SOURCE_TIMEOUT};
 
     //TODO join room should set key-value and SUB to maps
     //TODO maps event should fire event to endpoint
@@ -307,12 +791,25 @@ mod tests {
         let endpoint = 1;
         let peer: PeerId = "peer1".into();
         let t0 = Instant::now();
-        let mut room = ClusterRoom::<u8>::new(room_id);
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
         room.on_event(
             t0,
             Input::Endpoint(
                 endpoint,
                 ClusterEndpointControl::Join(
+                    AppId::root_app(),
+                    "room".into(),
                     peer.clone(),
                     PeerMeta { metadata: None, extra_data: None },
                     RoomInfoPublish { peer: false, tracks: false },
@@ -329,7 +826,23 @@ mod tests {
         let room_peers_map = id_generator::peers_map(room_id);
         let room_tracks_map = id_generator::tracks_map(room_id);
         let room_mixer_auto_channel = id_generator::gen_mixer_auto_channel_id(room_id);
+        let room_state_map = id_generator::room_state_map(room_id);
+
+        // first join in node subscribes room state and waits for it
+        assert_eq!(
+            room.pop_output(()),
+            Some(Output::Sdn(
+                RoomUserData(room_id, RoomFeature::State),
+                FeaturesControl::DhtKv(dht_kv::Control::MapCmd(room_state_map, dht_kv::MapControl::Sub))
+            ))
+        );
+        assert_eq!(
+            room.pop_output(()),
+            Some(Output::Sdn(RoomUserData(room_id, RoomFeature::State), FeaturesControl::DhtKv(dht_kv::Control::MapGet(room_state_map))))
+        );
+        assert_eq!(room.pop_output(()), None);
 
+        sync(&mut room, t0);
         assert_eq!(
             room.pop_output(()),
             Some(Output::Sdn(
@@ -351,6 +864,14 @@ mod tests {
                 FeaturesControl::PubSub(pubsub::Control(room_mixer_auto_channel, pubsub::ChannelControl::SubAuto))
             ))
         );
+        // mixer config is shared with other nodes
+        assert!(matches!(
+            room.pop_output(()),
+            Some(Output::Sdn(
+                RoomUserData(_, RoomFeature::State),
+                FeaturesControl::DhtKv(dht_kv::Control::MapCmd(_, dht_kv::MapControl::Set(..)))
+            ))
+        ));
         assert_eq!(room.pop_output(()), None);
 
         //after leave we should auto cleanup all resources like kv, pubsub
@@ -376,7 +897,841 @@ mod tests {
                 FeaturesControl::PubSub(pubsub::Control(room_mixer_auto_channel, pubsub::ChannelControl::UnsubAuto))
             ))
         );
+        assert!(matches!(
+            room.pop_output(()),
+            Some(Output::Sdn(
+                RoomUserData(_, RoomFeature::State),
+                FeaturesControl::DhtKv(dht_kv::Control::MapCmd(_, dht_kv::MapControl::Del(_)))
+            ))
+        ));
+        assert_eq!(
+            room.pop_output(()),
+            Some(Output::Sdn(
+                RoomUserData(room_id, RoomFeature::State),
+                FeaturesControl::DhtKv(dht_kv::Control::MapCmd(room_state_map, dht_kv::MapControl::Unsub))
+            ))
+        );
         assert_eq!(room.pop_output(()), None);
         assert!(room.is_empty());
     }
+
+    fn join_with_mixer(room: &mut ClusterRoom<u8>, now: Instant, endpoint: u8, peer: &str, mode: AudioMixerMode, outputs: u16) {
+        room.on_event(
+            now,
+            Input::Endpoint(
+                endpoint,
+                ClusterEndpointControl::Join(
+                    AppId::root_app(),
+                    "room".into(),
+                    peer.into(),
+                    PeerMeta { metadata: None, extra_data: None },
+                    RoomInfoPublish { peer: false, tracks: false },
+                    RoomInfoSubscribe { peers: false, tracks: false },
+                    Some(AudioMixerConfig {
+                        mode,
+                        outputs: (0..outputs).map(|i| i.into()).collect(),
+                        sources: vec![],
+                    }),
+                ),
+            ),
+        );
+    }
+
+    fn mixer_conflicts(outs: &[Output<u8>]) -> Vec<(Vec<u8>, ClusterEndpointEvent)> {
+        outs.iter()
+            .filter_map(|out| match out {
+                Output::Endpoint(endpoints, event @ ClusterEndpointEvent::MixerConfigConflict(..)) => Some((endpoints.clone(), event.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test_log::test]
+    fn conflict_mixer_config_ignored_with_warning() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
+
+        // first mixer endpoint sets room mixer config
+        join_with_mixer(&mut room, t0, 1, "peer1", AudioMixerMode::Auto, 3);
+        sync(&mut room, t0);
+        assert_eq!(mixer_conflicts(&drain(&mut room)), vec![]);
+
+        // different mode or number of outputs are ignored, the endpoint joins without mixer
+        join_with_mixer(&mut room, t0, 2, "peer2", AudioMixerMode::Manual, 1);
+        assert_eq!(mixer_conflicts(&drain(&mut room)), vec![(vec![2], ClusterEndpointEvent::MixerConfigConflict(AudioMixerMode::Auto, 3))]);
+        join_with_mixer(&mut room, t0, 3, "peer3", AudioMixerMode::Auto, 2);
+        assert_eq!(mixer_conflicts(&drain(&mut room)), vec![(vec![3], ClusterEndpointEvent::MixerConfigConflict(AudioMixerMode::Auto, 3))]);
+
+        // manual control from ignored endpoint must not touch the mixer
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::AudioMixer(ClusterAudioMixerControl::Attach(vec![]))));
+        assert_eq!(drain(&mut room), vec![]);
+
+        // same config is accepted
+        join_with_mixer(&mut room, t0, 4, "peer4", AudioMixerMode::Auto, 3);
+        assert_eq!(mixer_conflicts(&drain(&mut room)), vec![]);
+
+        // after all mixer endpoints left, room config is reset
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::Leave));
+        room.on_event(t0, Input::Endpoint(4, ClusterEndpointControl::Leave));
+        drain(&mut room);
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::Leave));
+        join_with_mixer(&mut room, t0, 5, "peer5", AudioMixerMode::Manual, 1);
+        assert_eq!(mixer_conflicts(&drain(&mut room)), vec![]);
+
+        for endpoint in [3, 5] {
+            room.on_event(t0, Input::Endpoint(endpoint, ClusterEndpointControl::Leave));
+        }
+        drain(&mut room);
+        assert!(room.is_empty());
+    }
+
+    /// Answer the room state get as a new room in cluster, held joins are processed after that
+    fn sync(room: &mut ClusterRoom<u8>, now: Instant) {
+        let room_id = room.room;
+        let event = dht_kv::Event::MapGetRes(id_generator::room_state_map(room_id), Ok(vec![]));
+        room.on_event(now, Input::Sdn(RoomUserData(room_id, RoomFeature::State), FeaturesEvent::DhtKv(event)));
+    }
+
+    fn is_state_out(out: &Output<u8>) -> bool {
+        matches!(out, Output::Sdn(RoomUserData(_, RoomFeature::State), _))
+    }
+
+    /// Pop all outputs except room state, which is checked by cross nodes tests
+    fn drain(room: &mut ClusterRoom<u8>) -> Vec<Output<u8>> {
+        let mut outs = vec![];
+        while let Some(out) = room.pop_output(()) {
+            if !is_state_out(&out) {
+                outs.push(out);
+            }
+        }
+        outs
+    }
+
+    fn has_pub_data(outs: &[Output<u8>]) -> bool {
+        outs.iter()
+            .any(|out| matches!(out, Output::Sdn(_, FeaturesControl::PubSub(pubsub::Control(_, pubsub::ChannelControl::PubData(_))))))
+    }
+
+    fn media(meta: MediaMeta) -> MediaPacket {
+        MediaPacket {
+            ts: 0,
+            seq: 0,
+            marker: true,
+            nackable: false,
+            layers: None,
+            meta,
+            data: vec![1, 2, 3],
+        }
+    }
+
+    //Pause room => no media is forwarded until resume, peers and tracks are kept
+    #[test_log::test]
+    fn pause_room_stop_pubsub_data() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
+        let track = RemoteTrackId::from(1);
+        let audio = media(MediaMeta::Opus { audio_level: None });
+        let video = media(MediaMeta::Vp8 {
+            key: false,
+            sim: None,
+            rotation: None,
+        });
+        let video_meta = TrackMeta {
+            kind: MediaKind::Video,
+            scaling: MediaScaling::None,
+            control: BitrateControlMode::MaxBitrate,
+            metadata: None,
+            muted: false,
+            encodings: vec![],
+        };
+        let publishers = [(1, "peer1", TrackMeta::default_audio()), (2, "peer2", video_meta)];
+
+        for (endpoint, peer, meta) in publishers.clone() {
+            room.on_event(
+                t0,
+                Input::Endpoint(
+                    endpoint,
+                    ClusterEndpointControl::Join(
+                        AppId::root_app(),
+                        "room".into(),
+                        peer.into(),
+                        PeerMeta { metadata: None, extra_data: None },
+                        RoomInfoPublish { peer: false, tracks: true },
+                        RoomInfoSubscribe { peers: false, tracks: false },
+                        None,
+                    ),
+                ),
+            );
+            sync(&mut room, t0);
+            room.on_event(
+                t0,
+                Input::Endpoint(endpoint, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Started("main".into(), meta))),
+            );
+        }
+        drain(&mut room);
+
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(audio.clone()))));
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(video.clone()))));
+        assert!(has_pub_data(&drain(&mut room)));
+
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::PauseRoom));
+        assert_eq!(drain(&mut room), vec![Output::Endpoint(vec![1, 2], ClusterEndpointEvent::RoomPaused)]);
+
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(audio.clone()))));
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(video.clone()))));
+        assert_eq!(drain(&mut room), vec![]);
+
+        // resume should request key-frame only from video track which is paused
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::ResumeRoom));
+        let outs = drain(&mut room);
+        assert_eq!(outs.len(), 2);
+        assert!(outs.contains(&Output::Endpoint(vec![2], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame))));
+        assert!(outs.contains(&Output::Endpoint(vec![1, 2], ClusterEndpointEvent::RoomResumed)));
+
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(video))));
+        assert!(has_pub_data(&drain(&mut room)));
+
+        for (endpoint, _peer, meta) in publishers {
+            room.on_event(
+                t0,
+                Input::Endpoint(endpoint, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Ended("main".into(), meta))),
+            );
+            room.on_event(t0, Input::Endpoint(endpoint, ClusterEndpointControl::Leave));
+        }
+        room.on_tick(t0 + Duration::from_secs(3));
+        drain(&mut room);
+        assert!(room.is_empty());
+    }
+
+    #[test_log::test]
+    fn join_subscriber_request_key_frames_debounced() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
+        let track = RemoteTrackId::from(1);
+        let video = media(MediaMeta::Vp8 {
+            key: false,
+            sim: None,
+            rotation: None,
+        });
+        let video_meta = TrackMeta {
+            kind: MediaKind::Video,
+            scaling: MediaScaling::None,
+            control: BitrateControlMode::MaxBitrate,
+            metadata: None,
+            muted: false,
+            encodings: vec![],
+        };
+        let join = |peer: &str, tracks: bool| {
+            ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                peer.into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: false, tracks: true },
+                RoomInfoSubscribe { peers: false, tracks },
+                None,
+            )
+        };
+        let key_frame = |endpoint: u8| Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame));
+
+        for (endpoint, peer) in [(1, "peer1"), (2, "peer2")] {
+            room.on_event(t0, Input::Endpoint(endpoint, join(peer, false)));
+            sync(&mut room, t0);
+            room.on_event(
+                t0,
+                Input::Endpoint(
+                    endpoint,
+                    ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Started("main".into(), video_meta.clone())),
+                ),
+            );
+            room.on_event(
+                t0,
+                Input::Endpoint(endpoint, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(video.clone()))),
+            );
+        }
+        drain(&mut room);
+
+        // a new subscriber requests key-frame from both video publishers
+        room.on_event(t0, Input::Endpoint(3, join("peer3", true)));
+        let outs = drain(&mut room);
+        assert!(outs.contains(&key_frame(1)));
+        assert!(outs.contains(&key_frame(2)));
+
+        // another subscriber in debounce window doesn't request again
+        room.on_event(t0 + Duration::from_millis(100), Input::Endpoint(4, join("peer4", true)));
+        let outs = drain(&mut room);
+        assert!(!outs.contains(&key_frame(1)));
+        assert!(!outs.contains(&key_frame(2)));
+
+        room.on_event(t0 + Duration::from_secs(1), Input::Endpoint(5, join("peer5", true)));
+        let outs = drain(&mut room);
+        assert!(outs.contains(&key_frame(1)));
+        assert!(outs.contains(&key_frame(2)));
+
+        for endpoint in [1, 2] {
+            room.on_event(
+                t0,
+                Input::Endpoint(
+                    endpoint,
+                    ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Ended("main".into(), video_meta.clone())),
+                ),
+            );
+        }
+        for endpoint in 1..=5 {
+            room.on_event(t0, Input::Endpoint(endpoint, ClusterEndpointControl::Leave));
+        }
+        room.on_tick(t0 + Duration::from_secs(3));
+        drain(&mut room);
+        assert!(room.is_empty());
+    }
+
+    #[test_log::test]
+    fn update_room_config_live() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
+        let join = |peer: &str| {
+            ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                peer.into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: false, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+        room.on_event(t0, Input::Endpoint(1, join("peer1")));
+        sync(&mut room, t0);
+        room.on_event(t0, Input::Endpoint(2, join("peer2")));
+        drain(&mut room);
+
+        let patch = RoomConfigPatch {
+            max_bitrate: Some(500_000),
+            ..Default::default()
+        };
+        let config = RoomConfig {
+            max_bitrate: Some(500_000),
+            max_spatial: None,
+        };
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::UpdateRoomConfig(patch.clone())));
+        assert_eq!(drain(&mut room), vec![Output::Endpoint(vec![1, 2], ClusterEndpointEvent::RoomConfigChanged(config))]);
+
+        // same patch dont change anything
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::UpdateRoomConfig(patch)));
+        assert_eq!(drain(&mut room), vec![]);
+
+        // record requires rejoin, the endpoint rejects it so a patch with it is ignored as a whole
+        let patch = RoomConfigPatch {
+            max_bitrate: Some(300_000),
+            record: Some(true),
+            ..Default::default()
+        };
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::UpdateRoomConfig(patch)));
+        assert_eq!(drain(&mut room), vec![]);
+
+        // new peer gets the current config on join
+        room.on_event(t0, Input::Endpoint(3, join("peer3")));
+        assert!(drain(&mut room).contains(&Output::Endpoint(vec![3], ClusterEndpointEvent::RoomConfigChanged(config))));
+    }
+
+    //Join again from same endpoint is metadata update, join with same peer id from other endpoint is rejected
+    #[test_log::test]
+    fn double_join_update_or_reject() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
+        let peer: PeerId = "peer1".into();
+        let peers_map = id_generator::peers_map(room_id);
+        let peer_key = id_generator::peers_key(&peer);
+        let join = |meta: Option<&str>| {
+            ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                peer.clone(),
+                PeerMeta {
+                    metadata: meta.map(|m| m.to_string()),
+                    extra_data: None,
+                },
+                RoomInfoPublish { peer: true, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+        let set_peer = |meta: Option<&str>| {
+            let info = PeerInfo {
+                peer: peer.clone(),
+                meta: PeerMeta {
+                    metadata: meta.map(|m| m.to_string()),
+                    extra_data: None,
+                },
+            };
+            Output::Sdn(
+                RoomUserData(room_id, RoomFeature::MetaData),
+                FeaturesControl::DhtKv(dht_kv::Control::MapCmd(peers_map, dht_kv::MapControl::Set(peer_key, info.serialize()))),
+            )
+        };
+
+        room.on_event(t0, Input::Endpoint(1, join(None)));
+        assert_eq!(drain(&mut room), vec![]);
+        sync(&mut room, t0);
+        assert_eq!(drain(&mut room), vec![set_peer(None)]);
+
+        // retry with same meta => nothing changed
+        room.on_event(t0, Input::Endpoint(1, join(None)));
+        assert_eq!(drain(&mut room), vec![]);
+
+        // same endpoint with new meta => only update peer info
+        room.on_event(t0, Input::Endpoint(1, join(Some("meta2"))));
+        assert_eq!(drain(&mut room), vec![set_peer(Some("meta2"))]);
+
+        // other endpoint with same peer id => rejected without touching kv
+        room.on_event(t0, Input::Endpoint(2, join(None)));
+        assert_eq!(
+            drain(&mut room),
+            vec![Output::Endpoint(vec![2], ClusterEndpointEvent::JoinRejected(peer.clone(), ClusterJoinRejectReason::AlreadyJoined))]
+        );
+
+        // rejected endpoint leave should not affect the joined one
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::Leave));
+        assert_eq!(drain(&mut room), vec![]);
+
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::Leave));
+        assert_eq!(
+            drain(&mut room),
+            vec![Output::Sdn(
+                RoomUserData(room_id, RoomFeature::MetaData),
+                FeaturesControl::DhtKv(dht_kv::Control::MapCmd(peers_map, dht_kv::MapControl::Del(peer_key)))
+            )]
+        );
+        assert!(room.is_empty());
+    }
+
+    //Join to locked room is pending until owner admit, then it proceeds as normal join
+    #[test_log::test]
+    fn locked_room_join_pending_until_admit() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
+        let track = RemoteTrackId::from(1);
+        let audio = media(MediaMeta::Opus { audio_level: None });
+        let guest: PeerId = "guest".into();
+        let peers_map = id_generator::peers_map(room_id);
+        let tracks_map = id_generator::tracks_map(room_id);
+        let join = |peer: &str| {
+            ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                peer.into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: true, tracks: true },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+        let has_kv_set = |outs: &[Output<u8>], target: dht_kv::Map| {
+            outs.iter()
+                .any(|out| matches!(out, Output::Sdn(_, FeaturesControl::DhtKv(dht_kv::Control::MapCmd(map, dht_kv::MapControl::Set(..)))) if *map == target))
+        };
+
+        room.on_event(t0, Input::Endpoint(1, join("owner")));
+        sync(&mut room, t0);
+        drain(&mut room);
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::SetRoomLocked(true)));
+        assert_eq!(drain(&mut room), vec![]);
+
+        room.on_event(t0, Input::Endpoint(2, join("guest")));
+        assert_eq!(drain(&mut room), vec![Output::Endpoint(vec![1], ClusterEndpointEvent::JoinPending(guest.clone()))]);
+
+        // pending peer don't have presence or media in room, and can't admit itself
+        room.on_event(
+            t0,
+            Input::Endpoint(
+                2,
+                ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Started("main".into(), TrackMeta::default_audio())),
+            ),
+        );
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(audio.clone()))));
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::AdmitPeer(guest.clone())));
+        assert_eq!(drain(&mut room), vec![]);
+
+        // after admitted, peer and the track started while pending are published
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::AdmitPeer(guest.clone())));
+        let outs = drain(&mut room);
+        assert!(has_kv_set(&outs, peers_map));
+        assert!(has_kv_set(&outs, tracks_map));
+
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Media(audio))));
+        assert!(has_pub_data(&drain(&mut room)));
+
+        // not admitted in time => rejected
+        room.on_event(t0, Input::Endpoint(3, join("late")));
+        assert_eq!(drain(&mut room), vec![Output::Endpoint(vec![1], ClusterEndpointEvent::JoinPending("late".into()))]);
+        room.on_tick(t0 + Duration::from_secs(60));
+        assert_eq!(
+            drain(&mut room),
+            vec![Output::Endpoint(vec![3], ClusterEndpointEvent::JoinRejected("late".into(), ClusterJoinRejectReason::PendingTimeout))]
+        );
+
+        room.on_event(
+            t0,
+            Input::Endpoint(
+                2,
+                ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Ended("main".into(), TrackMeta::default_audio())),
+            ),
+        );
+        for endpoint in [1, 2, 3] {
+            room.on_event(t0, Input::Endpoint(endpoint, ClusterEndpointControl::Leave));
+        }
+        room.on_tick(t0 + Duration::from_secs(63));
+        drain(&mut room);
+        assert!(room.is_empty());
+    }
+
+    //Room with TTL is warned then closed even when peers are still active, joins are rejected after closed
+    #[test_log::test]
+    fn room_ttl_close_with_active_peers() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let ttl = RoomTtl {
+            ttl: Duration::from_secs(10),
+            warning: Duration::from_secs(3),
+        };
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            Some(ttl),
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            None,
+            Default::default(),
+        );
+        let join = |peer: &str| {
+            ClusterEndpointControl::Join(
+                AppId::from("webinar"),
+                "room".into(),
+                peer.into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: true, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+
+        room.on_event(t0, Input::Endpoint(1, join("peer1")));
+        sync(&mut room, t0);
+        room.on_event(t0 + Duration::from_secs(2), Input::Endpoint(2, join("peer2")));
+        drain(&mut room);
+
+        room.on_tick(t0 + Duration::from_secs(6));
+        assert_eq!(drain(&mut room), vec![]);
+
+        room.on_tick(t0 + Duration::from_secs(8));
+        assert_eq!(drain(&mut room), vec![Output::Endpoint(vec![1, 2], ClusterEndpointEvent::RoomClosingSoon(Duration::from_secs(2)))]);
+        room.on_tick(t0 + Duration::from_secs(9));
+        assert_eq!(drain(&mut room), vec![]);
+
+        room.on_tick(t0 + Duration::from_secs(10));
+        assert_eq!(drain(&mut room), vec![Output::Endpoint(vec![1, 2], ClusterEndpointEvent::RoomClosed)]);
+
+        room.on_event(t0 + Duration::from_secs(11), Input::Endpoint(3, join("peer3")));
+        assert_eq!(
+            drain(&mut room),
+            vec![Output::Endpoint(vec![3], ClusterEndpointEvent::JoinRejected("peer3".into(), ClusterJoinRejectReason::RoomClosed))]
+        );
+
+        // peers leave after closed, then the room is empty for removing
+        room.on_event(t0 + Duration::from_secs(11), Input::Endpoint(1, ClusterEndpointControl::Leave));
+        room.on_event(t0 + Duration::from_secs(11), Input::Endpoint(2, ClusterEndpointControl::Leave));
+        drain(&mut room);
+        assert!(room.is_empty());
+    }
+
+    /// Forward room state sets of a node to other node as kv events, return other outputs
+    #[derive(Default)]
+    struct AuditEvents(Mutex<Vec<AuditEvent>>);
+
+    impl AuditSink for AuditEvents {
+        fn write(&self, record: &AuditRecord) {
+            self.0.lock().expect("Should lock").push(record.event.clone());
+        }
+    }
+
+    #[test_log::test]
+    fn audit_recorded_once_from_room_handlers() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let sink = Arc::new(AuditEvents::default());
+        let mut room = ClusterRoom::<u8>::new(
+            TrackChannels::hashed(room_id),
+            DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+            None,
+            UnknownFeedbackPolicy::default(),
+            DEFAULT_MAX_CHANNEL_SOURCES,
+            DEFAULT_SOURCE_TIMEOUT,
+            Duration::ZERO,
+            KvRetryPolicy::default(),
+            Some(Arc::new(RoomAudit::new(sink.clone()))),
+            Default::default(),
+        );
+        // both peers subscribe to peers and tracks, so each event is delivered to the other endpoint too
+        for (endpoint, peer) in [(1, "peer1"), (2, "peer2")] {
+            room.on_event(
+                t0,
+                Input::Endpoint(
+                    endpoint,
+                    ClusterEndpointControl::Join(
+                        AppId::root_app(),
+                        "room".into(),
+                        peer.into(),
+                        PeerMeta { metadata: None, extra_data: None },
+                        RoomInfoPublish { peer: true, tracks: true },
+                        RoomInfoSubscribe { peers: true, tracks: true },
+                        None,
+                    ),
+                ),
+            );
+        }
+        sync(&mut room, t0);
+        let track = RemoteTrackId::from(1);
+        room.on_event(
+            t0,
+            Input::Endpoint(
+                1,
+                ClusterEndpointControl::RemoteTrack(track, ClusterRemoteTrackControl::Started("audio_main".into(), TrackMeta::default_audio())),
+            ),
+        );
+        drain(&mut room);
+
+        // peer1 leaves without stopping its track
+        room.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::Leave));
+        room.on_event(t0, Input::Endpoint(2, ClusterEndpointControl::Leave));
+        drain(&mut room);
+        assert!(room.is_empty());
+
+        let peer1 = PeerId::from("peer1");
+        let peer2 = PeerId::from("peer2");
+        assert_eq!(
+            *sink.0.lock().expect("Should lock"),
+            vec![
+                AuditEvent::PeerJoined(peer1.clone()),
+                AuditEvent::PeerJoined(peer2.clone()),
+                AuditEvent::TrackStarted(peer1.clone(), "audio_main".into()),
+                AuditEvent::TrackStopped(peer1.clone(), "audio_main".into()),
+                AuditEvent::PeerLeaved(peer1),
+                AuditEvent::PeerLeaved(peer2),
+            ]
+        );
+    }
+
+    fn pipe_state(from: &mut ClusterRoom<u8>, to: &mut ClusterRoom<u8>, now: Instant) -> Vec<Output<u8>> {
+        let mut outs = vec![];
+        while let Some(out) = from.pop_output(()) {
+            match out {
+                Output::Sdn(userdata @ RoomUserData(_, RoomFeature::State), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(map, dht_kv::MapControl::Set(key, data)))) => {
+                    let event = dht_kv::Event::MapEvent(map, dht_kv::MapEvent::OnSet(key, 1, data));
+                    to.on_event(now, Input::Sdn(userdata, FeaturesEvent::DhtKv(event)));
+                }
+                out if is_state_out(&out) => {}
+                out => outs.push(out),
+            }
+        }
+        outs
+    }
+
+    //Lock, pending peers, admit decisions and pause are shared between nodes of the same room
+    #[test_log::test]
+    fn room_state_shared_between_nodes() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let new_room = || {
+            ClusterRoom::<u8>::new(
+                TrackChannels::hashed(room_id),
+                DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+                None,
+                UnknownFeedbackPolicy::default(),
+                DEFAULT_MAX_CHANNEL_SOURCES,
+                DEFAULT_SOURCE_TIMEOUT,
+                Duration::ZERO,
+                KvRetryPolicy::default(),
+                None,
+                Default::default(),
+            )
+        };
+        let mut node1 = new_room();
+        let mut node2 = new_room();
+        let guest: PeerId = "guest".into();
+        let peers_map = id_generator::peers_map(room_id);
+        let join = |peer: &str| {
+            ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                peer.into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: true, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+
+        node1.on_event(t0, Input::Endpoint(1, join("owner")));
+        sync(&mut node1, t0);
+        node2.on_event(t0, Input::Endpoint(1, join("member")));
+        sync(&mut node2, t0);
+        drain(&mut node1);
+        drain(&mut node2);
+
+        // owner in node1 locks the room, guest joins node2 => owner is notified
+        node1.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::SetRoomLocked(true)));
+        assert_eq!(pipe_state(&mut node1, &mut node2, t0), vec![]);
+        node2.on_event(t0, Input::Endpoint(2, join("guest")));
+        assert_eq!(pipe_state(&mut node2, &mut node1, t0), vec![]);
+        assert_eq!(drain(&mut node1), vec![Output::Endpoint(vec![1], ClusterEndpointEvent::JoinPending(guest.clone()))]);
+
+        // member in node2 is not the owner, it can't admit
+        node2.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::AdmitPeer(guest.clone())));
+        assert_eq!(drain(&mut node2), vec![]);
+
+        // owner admits the guest which is pending in node2
+        node1.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::AdmitPeer(guest.clone())));
+        assert_eq!(pipe_state(&mut node1, &mut node2, t0), vec![]);
+        let outs = pipe_state(&mut node2, &mut node1, t0);
+        assert!(outs
+            .iter()
+            .any(|out| matches!(out, Output::Sdn(_, FeaturesControl::DhtKv(dht_kv::Control::MapCmd(map, dht_kv::MapControl::Set(..)))) if *map == peers_map)));
+        pipe_state(&mut node1, &mut node2, t0);
+
+        // pause in node1 is applied to peers in node2
+        node1.on_event(t0, Input::Endpoint(1, ClusterEndpointControl::PauseRoom));
+        assert_eq!(pipe_state(&mut node1, &mut node2, t0), vec![Output::Endpoint(vec![1], ClusterEndpointEvent::RoomPaused)]);
+        assert_eq!(drain(&mut node2), vec![Output::Endpoint(vec![1, 2], ClusterEndpointEvent::RoomPaused)]);
+
+        for node in [&mut node1, &mut node2] {
+            for endpoint in [1, 2] {
+                node.on_event(t0, Input::Endpoint(endpoint, ClusterEndpointControl::Leave));
+            }
+            drain(node);
+            assert!(node.is_empty());
+        }
+    }
+
+    //Video codec which is pinned first in the cluster replaces the local pinned codec of other nodes
+    #[test_log::test]
+    fn room_video_codec_shared_between_nodes() {
+        let room_id = 0.into();
+        let t0 = Instant::now();
+        let codecs1 = Arc::new(RoomVideoCodecs::default());
+        let codecs2 = Arc::new(RoomVideoCodecs::default());
+        let new_room = |codecs: &Arc<RoomVideoCodecs>| {
+            ClusterRoom::<u8>::new(
+                TrackChannels::hashed(room_id),
+                DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
+                None,
+                UnknownFeedbackPolicy::default(),
+                DEFAULT_MAX_CHANNEL_SOURCES,
+                DEFAULT_SOURCE_TIMEOUT,
+                Duration::ZERO,
+                KvRetryPolicy::default(),
+                None,
+                codecs.clone(),
+            )
+        };
+        let mut node1 = new_room(&codecs1);
+        let mut node2 = new_room(&codecs2);
+        let join = |peer: &str| {
+            ClusterEndpointControl::Join(
+                AppId::root_app(),
+                "room".into(),
+                peer.into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: true, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+
+        codecs1.acquire(room_id, "VP9");
+        node1.on_event(t0, Input::Endpoint(1, join("peer1")));
+        sync(&mut node1, t0);
+        node2.on_event(t0, Input::Endpoint(1, join("peer2")));
+        sync(&mut node2, t0);
+        drain(&mut node2);
+
+        codecs2.acquire(room_id, "VP8");
+        pipe_state(&mut node1, &mut node2, t0);
+        assert_eq!(codecs2.get(room_id).as_deref(), Some("VP9"));
+        assert_eq!(codecs1.get(room_id).as_deref(), Some("VP9"));
+    }
 }
@@ -4,10 +4,15 @@
 //! - Subscriber: subscribe to /room_id/audio_mixer to get all of top-3 audios from other servers
 //!                 calculate top-3 audio for each local endpoint
 //!
+//! Mixer config of the room (mode and number of outputs) is set by the first endpoint which joins with a mixer,
+//! in any node of the room, see room state. Later endpoints with a different config join the room without mixer
+//! and receive MixerConfigConflict event. The config is reset after all mixer endpoints leave.
+//!
 
 //TODO refactor multiple subscriber mode to array instead of manual implement with subscriber1, subscriber2, subscriber3
 
 use std::{
+    collections::VecDeque,
     fmt::Debug,
     hash::Hash,
     time::{Duration, Instant},
@@ -17,13 +22,13 @@ use atm0s_sdn::features::pubsub::{self, ChannelId};
 use indexmap::IndexMap;
 use manual::ManualMixer;
 use media_server_protocol::{
-    endpoint::{AudioMixerConfig, PeerId, TrackName},
+    endpoint::{AudioMixerConfig, AudioMixerMode, PeerId, TrackName},
     media::MediaPacket,
 };
-use sans_io_runtime::{TaskGroup, TaskGroupOutput, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
+use sans_io_runtime::{return_if_none, return_if_some, TaskGroup, TaskGroupOutput, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
-    cluster::{ClusterAudioMixerControl, ClusterEndpointEvent, ClusterRoomHash},
+    cluster::{id_generator::TrackChannels, ClusterAudioMixerControl, ClusterEndpointEvent, ClusterRoomHash},
     transport::RemoteTrackId,
 };
 
@@ -58,11 +63,15 @@ type AudioMixerManuals<T> = TaskSwitcherBranch<TaskGroup<manual::Input, Output<T
 #[derive(Debug)]
 pub struct AudioMixer<Endpoint: Debug + Clone> {
     room: ClusterRoomHash,
+    track_channels: TrackChannels,
     mix_channel_id: ChannelId,
     //store number of outputs
     auto_mode: IndexMap<Endpoint, usize>,
     manual_mode: IndexMap<Endpoint, usize>,
     manual_channels: IndexMap<ChannelId, Vec<usize>>,
+    /// Mode and number of outputs which is set by the first mixer endpoint
+    room_cfg: Option<(AudioMixerMode, usize)>,
+    queue: VecDeque<Output<Endpoint>>,
     publisher: TaskSwitcherBranch<AudioMixerPublisher<Endpoint>, Output<Endpoint>>,
     subscriber1: TaskSwitcherBranch<AudioMixerSubscriber<Endpoint, 1>, Output<Endpoint>>,
     subscriber2: TaskSwitcherBranch<AudioMixerSubscriber<Endpoint, 2>, Output<Endpoint>>,
@@ -73,13 +82,16 @@ pub struct AudioMixer<Endpoint: Debug + Clone> {
 }
 
 impl<Endpoint: Debug + Clone + Hash + Eq> AudioMixer<Endpoint> {
-    pub fn new(room: ClusterRoomHash, mix_channel_id: ChannelId) -> Self {
+    pub fn new(track_channels: TrackChannels, mix_channel_id: ChannelId) -> Self {
         Self {
-            room,
+            room: track_channels.room(),
+            track_channels,
             mix_channel_id,
             auto_mode: IndexMap::new(),
             manual_mode: IndexMap::new(),
             manual_channels: IndexMap::new(),
+            room_cfg: None,
+            queue: VecDeque::new(),
             publisher: TaskSwitcherBranch::new(AudioMixerPublisher::new(mix_channel_id), TaskType::Publisher),
             subscriber1: TaskSwitcherBranch::new(AudioMixerSubscriber::new(mix_channel_id), TaskType::Subscriber1),
             subscriber2: TaskSwitcherBranch::new(AudioMixerSubscriber::new(mix_channel_id), TaskType::Subscriber2),
@@ -101,27 +113,52 @@ impl<Endpoint: Debug + Clone + Hash + Eq> AudioMixer<Endpoint> {
         }
     }
 
-    pub fn on_join(&mut self, now: Instant, endpoint: Endpoint, peer: PeerId, cfg: Option<AudioMixerConfig>) {
-        if let Some(cfg) = cfg {
-            match cfg.mode {
-                media_server_protocol::endpoint::AudioMixerMode::Auto => {
-                    self.auto_mode.insert(endpoint.clone(), cfg.outputs.len());
-                    match cfg.outputs.len() {
-                        1 => self.subscriber1.input(&mut self.switcher).on_endpoint_join(now, endpoint, peer, cfg.outputs),
-                        2 => self.subscriber2.input(&mut self.switcher).on_endpoint_join(now, endpoint, peer, cfg.outputs),
-                        3 => self.subscriber3.input(&mut self.switcher).on_endpoint_join(now, endpoint, peer, cfg.outputs),
-                        _ => {
-                            log::warn!("[ClusterRoomAudioMixer] unsupported mixer with {} outputs", cfg.outputs.len());
-                        }
-                    }
+    /// Mixer config of local mixer endpoints, None if no local endpoint has mixer
+    pub fn room_cfg(&self) -> Option<(AudioMixerMode, usize)> {
+        self.room_cfg
+    }
+
+    /// Join with mixer config, `cluster_cfg` is the mixer config of the room which is set first in any node
+    pub fn on_join(&mut self, now: Instant, endpoint: Endpoint, peer: PeerId, cfg: Option<AudioMixerConfig>, cluster_cfg: Option<(AudioMixerMode, usize)>) {
+        let cfg = return_if_none!(cfg);
+        if cfg.mode == AudioMixerMode::Auto && !(1..=3).contains(&cfg.outputs.len()) {
+            log::warn!("[ClusterRoomAudioMixer] unsupported mixer with {} outputs from {peer} => ignore", cfg.outputs.len());
+            return;
+        }
+        match self.room_cfg.or(cluster_cfg) {
+            Some((mode, outputs)) if mode != cfg.mode || outputs != cfg.outputs.len() => {
+                log::warn!(
+                    "[ClusterRoomAudioMixer] {peer} join with mixer {:?}/{} which conflicts with room mixer {mode:?}/{outputs} => ignore",
+                    cfg.mode,
+                    cfg.outputs.len()
+                );
+                self.queue.push_back(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::MixerConfigConflict(mode, outputs)));
+                return;
+            }
+            _ => {
+                if self.room_cfg.is_none() {
+                    log::info!("[ClusterRoomAudioMixer] room mixer config set to {:?}/{} by {peer}", cfg.mode, cfg.outputs.len());
+                    self.room_cfg = Some((cfg.mode, cfg.outputs.len()));
                 }
-                media_server_protocol::endpoint::AudioMixerMode::Manual => {
-                    log::info!("[ClusterRoomAudioMixer] add manual mode for {:?} {peer}", endpoint);
-                    let manual_mixer = ManualMixer::new(self.room, endpoint.clone(), cfg.outputs);
-                    let new_index = self.manuals.input(&mut self.switcher).add_task(manual_mixer);
-                    if let Some(_old_index) = self.manual_mode.insert(endpoint, new_index) {
-                        panic!("Manual mixer for endpoint already exist");
-                    }
+            }
+        }
+
+        match cfg.mode {
+            AudioMixerMode::Auto => {
+                self.auto_mode.insert(endpoint.clone(), cfg.outputs.len());
+                match cfg.outputs.len() {
+                    1 => self.subscriber1.input(&mut self.switcher).on_endpoint_join(now, endpoint, peer, cfg.outputs),
+                    2 => self.subscriber2.input(&mut self.switcher).on_endpoint_join(now, endpoint, peer, cfg.outputs),
+                    3 => self.subscriber3.input(&mut self.switcher).on_endpoint_join(now, endpoint, peer, cfg.outputs),
+                    _ => unreachable!("outputs is validated above"),
+                }
+            }
+            AudioMixerMode::Manual => {
+                log::info!("[ClusterRoomAudioMixer] add manual mode for {:?} {peer}", endpoint);
+                let manual_mixer = ManualMixer::new(self.track_channels.clone(), endpoint.clone(), cfg.outputs);
+                let new_index = self.manuals.input(&mut self.switcher).add_task(manual_mixer);
+                if let Some(_old_index) = self.manual_mode.insert(endpoint, new_index) {
+                    panic!("Manual mixer for endpoint already exist");
                 }
             }
         }
@@ -129,7 +166,14 @@ impl<Endpoint: Debug + Clone + Hash + Eq> AudioMixer<Endpoint> {
 
     pub fn on_control(&mut self, now: Instant, endpoint: Endpoint, control: ClusterAudioMixerControl) {
         log::info!("[ClusterRoomAudioMixer] on endpoint {:?} input {:?}", endpoint, control);
-        let index = *self.manual_mode.get(&endpoint).expect("Manual mixer not found for control");
+        // endpoint which mixer is ignored by conflict dont have manual mixer
+        let index = match self.manual_mode.get(&endpoint) {
+            Some(index) => *index,
+            None => {
+                log::warn!("[ClusterRoomAudioMixer] manual mixer not found for {:?} => ignore control", endpoint);
+                return;
+            }
+        };
         let input = match control {
             ClusterAudioMixerControl::Attach(sources) => manual::Input::Attach(sources),
             ClusterAudioMixerControl::Detach(sources) => manual::Input::Detach(sources),
@@ -152,6 +196,9 @@ impl<Endpoint: Debug + Clone + Hash + Eq> AudioMixer<Endpoint> {
             self.manual_mode.swap_remove(&endpoint);
             self.manuals.input(&mut self.switcher).on_event(now, index, manual::Input::LeaveRoom);
         }
+        if self.auto_mode.is_empty() && self.manual_mode.is_empty() {
+            self.room_cfg = None;
+        }
     }
 
     pub fn on_track_publish(&mut self, now: Instant, endpoint: Endpoint, track: RemoteTrackId, peer: PeerId, name: TrackName) {
@@ -189,7 +236,8 @@ impl<Endpoint: Debug + Clone + Hash + Eq> TaskSwitcherChild<Output<Endpoint>> fo
     type Time = ();
 
     fn is_empty(&self) -> bool {
-        self.manual_channels.is_empty()
+        self.queue.is_empty()
+            && self.manual_channels.is_empty()
             && self.manual_mode.is_empty()
             && self.publisher.is_empty()
             && self.subscriber1.is_empty()
@@ -206,6 +254,7 @@ impl<Endpoint: Debug + Clone + Hash + Eq> TaskSwitcherChild<Output<Endpoint>> fo
     /// We need to wait all publisher, subscriber, and manuals ready to remove
     ///
     fn pop_output(&mut self, _now: Self::Time) -> Option<Output<Endpoint>> {
+        return_if_some!(self.queue.pop_front());
         loop {
             match self.switcher.current()?.try_into().ok()? {
                 TaskType::Publisher => {
@@ -19,7 +19,7 @@ use media_server_protocol::{
 use media_server_utils::Count;
 use sans_io_runtime::{collections::DynamicDeque, Task, TaskSwitcherChild};
 
-use crate::cluster::{id_generator, ClusterAudioMixerEvent, ClusterEndpointEvent, ClusterLocalTrackEvent, ClusterRoomHash};
+use crate::cluster::{id_generator::TrackChannels, ClusterAudioMixerEvent, ClusterEndpointEvent, ClusterLocalTrackEvent, ClusterRoomHash};
 
 use super::Output;
 
@@ -36,6 +36,7 @@ pub struct ManualMixer<Endpoint: Debug> {
     _c: Count<Self>,
     endpoint: Endpoint,
     room: ClusterRoomHash,
+    track_channels: TrackChannels,
     outputs: Vec<LocalTrackId>,
     sources: IndexMap<ChannelId, TrackSource>,
     queue: DynamicDeque<Output<Endpoint>, 4>,
@@ -43,11 +44,12 @@ pub struct ManualMixer<Endpoint: Debug> {
 }
 
 impl<Endpoint: Debug + Clone> ManualMixer<Endpoint> {
-    pub fn new(room: ClusterRoomHash, endpoint: Endpoint, outputs: Vec<LocalTrackId>) -> Self {
+    pub fn new(track_channels: TrackChannels, endpoint: Endpoint, outputs: Vec<LocalTrackId>) -> Self {
         Self {
             _c: Default::default(),
             endpoint,
-            room,
+            room: track_channels.room(),
+            track_channels,
             mixer: audio_mixer::AudioMixer::new(outputs.len()),
             outputs,
             sources: Default::default(),
@@ -56,7 +58,7 @@ impl<Endpoint: Debug + Clone> ManualMixer<Endpoint> {
     }
 
     fn attach(&mut self, _now: Instant, source: TrackSource) {
-        let channel_id = id_generator::gen_track_channel_id(self.room, &source.peer, &source.track);
+        let channel_id = self.track_channels.channel_id(&source.peer, &source.track);
         if let Entry::Vacant(e) = self.sources.entry(channel_id) {
             log::info!("[ClusterManualMixer] add source {:?} => sub {channel_id}", source);
             e.insert(source);
@@ -89,7 +91,7 @@ impl<Endpoint: Debug + Clone> ManualMixer<Endpoint> {
     }
 
     fn detach(&mut self, _now: Instant, source: TrackSource) {
-        let channel_id = id_generator::gen_track_channel_id(self.room, &source.peer, &source.track);
+        let channel_id = self.track_channels.channel_id(&source.peer, &source.track);
         if self.sources.swap_remove(&channel_id).is_some() {
             log::info!("[ClusterManualMixer] remove source {:?} => unsub {channel_id}", source);
             self.queue.push_back(Output::Pubsub(pubsub::Control(channel_id, pubsub::ChannelControl::UnsubAuto)));
@@ -178,7 +180,10 @@ mod test {
     };
     use sans_io_runtime::{Task, TaskSwitcherChild};
 
-    use crate::cluster::{id_generator, ClusterAudioMixerEvent, ClusterEndpointEvent, ClusterLocalTrackEvent};
+    use crate::cluster::{
+        id_generator::{self, TrackChannels},
+        ClusterAudioMixerEvent, ClusterEndpointEvent, ClusterLocalTrackEvent,
+    };
 
     use super::{super::Output, Input, ManualMixer};
 
@@ -192,7 +197,7 @@ mod test {
         let room = 0.into();
         let endpoint = 1;
         let track = 0.into();
-        let mut manual = ManualMixer::<u8>::new(room, endpoint, vec![track]);
+        let mut manual = ManualMixer::<u8>::new(TrackChannels::hashed(room), endpoint, vec![track]);
         let source = TrackSource {
             peer: "peer1".into(),
             track: "audio".into(),
@@ -243,7 +248,7 @@ mod test {
         let room = 0.into();
         let endpoint = 1;
         let track = 0.into();
-        let mut manual = ManualMixer::<u8>::new(room, endpoint, vec![track]);
+        let mut manual = ManualMixer::<u8>::new(TrackChannels::hashed(room), endpoint, vec![track]);
         let source = TrackSource {
             peer: "peer1".into(),
             track: "audio".into(),
@@ -1,15 +1,19 @@
-use std::{fmt::Debug, hash::Hash, time::Instant};
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    time::{Duration, Instant},
+};
 
 use atm0s_sdn::features::pubsub;
 use media_server_protocol::{
     endpoint::{PeerId, TrackName},
     media::MediaPacket,
 };
-use publisher::RoomChannelPublisher;
+use publisher::{RoomChannelPublisher, UnknownFeedbackPolicy};
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
-    cluster::{ClusterEndpointEvent, ClusterRoomHash},
+    cluster::{id_generator::TrackChannels, ClusterEndpointEvent, ClusterRoomHash},
     transport::{LocalTrackId, RemoteTrackId},
 };
 
@@ -41,26 +45,31 @@ pub struct MediaTrack<Endpoint: Debug + Hash + Eq + Copy> {
 }
 
 impl<Endpoint: Debug + Hash + Eq + Copy> MediaTrack<Endpoint> {
-    pub fn new(room: ClusterRoomHash) -> Self {
+    pub fn new(track_channels: TrackChannels, unknown_feedback: UnknownFeedbackPolicy, max_sources: usize, source_timeout: Duration) -> Self {
         Self {
-            room,
-            publisher: TaskSwitcherBranch::new(RoomChannelPublisher::new(room), TaskType::Publisher),
-            subscriber: TaskSwitcherBranch::new(RoomChannelSubscribe::new(room), TaskType::Subscriber),
+            room: track_channels.room(),
+            publisher: TaskSwitcherBranch::new(RoomChannelPublisher::new(track_channels.clone(), unknown_feedback, max_sources), TaskType::Publisher),
+            subscriber: TaskSwitcherBranch::new(RoomChannelSubscribe::new(track_channels, source_timeout), TaskType::Subscriber),
             switcher: TaskSwitcher::new(2),
         }
     }
 
-    pub fn on_pubsub_event(&mut self, event: pubsub::Event) {
+    pub fn on_tick(&mut self, now: Instant) {
+        self.publisher.input(&mut self.switcher).on_tick(now);
+        self.subscriber.input(&mut self.switcher).on_tick(now);
+    }
+
+    pub fn on_pubsub_event(&mut self, now: Instant, event: pubsub::Event) {
         let channel = event.0;
         match event.1 {
             pubsub::ChannelEvent::RouteChanged(next) => {
                 self.subscriber.input(&mut self.switcher).on_track_relay_changed(channel, next);
             }
             pubsub::ChannelEvent::SourceData(_, data) => {
-                self.subscriber.input(&mut self.switcher).on_track_data(channel, data);
+                self.subscriber.input(&mut self.switcher).on_track_data(now, channel, data);
             }
             pubsub::ChannelEvent::FeedbackData(fb) => {
-                self.publisher.input(&mut self.switcher).on_track_feedback(channel, fb);
+                self.publisher.input(&mut self.switcher).on_track_feedback(now, channel, fb);
             }
         }
     }
@@ -69,12 +78,20 @@ impl<Endpoint: Debug + Hash + Eq + Copy> MediaTrack<Endpoint> {
         self.publisher.input(&mut self.switcher).on_track_publish(endpoint, track, peer, name);
     }
 
+    pub fn on_room_paused(&mut self, paused: bool) {
+        self.publisher.input(&mut self.switcher).on_room_paused(paused);
+    }
+
+    pub fn request_video_key_frames(&mut self, now: Instant) {
+        self.publisher.input(&mut self.switcher).request_video_key_frames(now);
+    }
+
     pub fn on_track_data(&mut self, endpoint: Endpoint, track: RemoteTrackId, media: MediaPacket) {
         self.publisher.input(&mut self.switcher).on_track_data(endpoint, track, media);
     }
 
-    pub fn on_track_unpublish(&mut self, endpoint: Endpoint, track: RemoteTrackId) {
-        self.publisher.input(&mut self.switcher).on_track_unpublish(endpoint, track);
+    pub fn on_track_unpublish(&mut self, now: Instant, endpoint: Endpoint, track: RemoteTrackId) {
+        self.publisher.input(&mut self.switcher).on_track_unpublish(now, endpoint, track);
     }
 
     pub fn on_track_subscribe(&mut self, endpoint: Endpoint, track: LocalTrackId, target_peer: PeerId, target_track: TrackName) {
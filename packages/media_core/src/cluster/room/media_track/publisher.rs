@@ -2,7 +2,13 @@
 //! Channel Publisher will takecare of pubsub channel for sending data and handle when received channel feedback
 //!
 
-use std::{collections::VecDeque, fmt::Debug, hash::Hash};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    hash::Hash,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use atm0s_sdn::features::pubsub::{self, ChannelControl, ChannelId, Feedback};
 use indexmap::{IndexMap, IndexSet};
@@ -11,18 +17,80 @@ use media_server_protocol::{
     media::MediaPacket,
 };
 use media_server_utils::Count;
-use sans_io_runtime::{return_if_err, return_if_none, TaskSwitcherChild};
+use sans_io_runtime::{return_if_none, TaskSwitcherChild};
 
 use crate::{
-    cluster::{id_generator, ClusterEndpointEvent, ClusterRemoteTrackEvent, ClusterRoomHash},
+    cluster::{id_generator::TrackChannels, ClusterEndpointEvent, ClusterRemoteTrackEvent, ClusterRoomHash},
     transport::RemoteTrackId,
 };
 
 use super::Output;
 
+/// When the last source of a channel is unpublished, we keep the channel alive in this window for waiting republish.
+/// This allows subscribers to see only a small gap when a publisher restarts its track (e.g. camera toggled off/on).
+const REPUBLISH_WINDOW_MS: u128 = 2000;
+
+/// How feedback kinds which this node doesn't know are handled, e.g. kinds which are added by newer nodes in a rolling upgrade.
+/// Unknown feedbacks are always dropped and counted as [`UnknownFeedback`] in `/api/metrics/counts`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownFeedbackPolicy {
+    /// Only logged at debug level
+    Ignore,
+    /// Logged as warning once per kind in each room
+    #[default]
+    LogOnce,
+}
+
+impl FromStr for UnknownFeedbackPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "log-once" => Ok(Self::LogOnce),
+            _ => Err(format!("invalid unknown feedback policy {s}, must be ignore or log-once")),
+        }
+    }
+}
+
+/// Marker type for counting received feedbacks with unknown kind
+pub struct UnknownFeedback;
+
+/// Min interval of room-wide key-frame requests to a video track, it avoids a key-frame burst when many peers join at once
+const ROOM_KEY_FRAME_DEBOUNCE_MS: u128 = 500;
+
+/// Max number of sources of a channel, which are sessions publishing same peer and track name
+pub const DEFAULT_MAX_CHANNEL_SOURCES: usize = 4;
+
+/// Max number of media packets waiting in queue for sending to pubsub. When SDN is slower than publishers new audio and
+/// delta frames are dropped before they are queued, a video channel which dropped a frame drops until its next key-frame
+/// and requests one, so subscribers never get a frame which references a dropped one.
+const MAX_QUEUED_PUB_DATA: usize = 512;
+/// Key-frames and the rest of frames which are already started are still queued over [`MAX_QUEUED_PUB_DATA`] up to this
+const MAX_QUEUED_PUB_DATA_HARD: usize = MAX_QUEUED_PUB_DATA * 2;
+
+/// Marker type for counting media packets which are dropped because pubsub queue is full
+pub struct PubDataDropped;
+
+/// Channels which didn't send any media in this interval send an empty PubData as heartbeat, e.g. muted or DTX audio,
+/// or room paused. Subscribers only consider the source lost when heartbeats stop too, see [`super::subscriber`]
+const SOURCE_HEARTBEAT_INTERVAL_MS: u128 = 1000;
+
+/// Number of spatial layers which can have target bitrate, same as [`media_server_protocol::media::MediaLayersBitrate`]
+const MAX_SPATIAL_LAYERS: usize = 3;
+
 pub enum FeedbackKind {
-    Bitrate { min: u64, max: u64 },
+    Bitrate {
+        min: u64,
+        max: u64,
+    },
     KeyFrameRequest,
+    /// Target bitrate of a spatial layer of a simulcast stream, which is max desired bitrate of its consumers
+    LayerBitrate {
+        spatial: u8,
+        bitrate: u64,
+        timeout_ms: u16,
+    },
 }
 
 impl TryFrom<Feedback> for FeedbackKind {
@@ -31,6 +99,11 @@ impl TryFrom<Feedback> for FeedbackKind {
         match value.kind {
             0 => Ok(FeedbackKind::Bitrate { min: value.min, max: value.max }),
             1 => Ok(FeedbackKind::KeyFrameRequest),
+            2..=4 => Ok(FeedbackKind::LayerBitrate {
+                spatial: value.kind - 2,
+                bitrate: value.max,
+                timeout_ms: value.timeout_ms,
+            }),
             _ => Err(()),
         }
     }
@@ -40,32 +113,118 @@ impl TryFrom<Feedback> for FeedbackKind {
 pub struct RoomChannelPublisher<Endpoint: Debug> {
     _c: Count<Self>,
     room: ClusterRoomHash,
+    track_channels: TrackChannels,
     tracks: IndexMap<(Endpoint, RemoteTrackId), (PeerId, TrackName, ChannelId)>,
     tracks_source: IndexMap<ChannelId, IndexSet<(Endpoint, RemoteTrackId)>>, // We allow multi sources here for avoiding crash
+    /// Publishes over this number of sources in a channel are rejected, 0 for unlimited
+    max_sources: usize,
+    republish_waits: IndexMap<ChannelId, Instant>,
+    paused: bool,
+    // video tracks which are dropped data while room paused, need key-frame on resume
+    paused_videos: IndexSet<(Endpoint, RemoteTrackId)>,
+    /// Video tracks which published media, with last time of room-wide key-frame request
+    videos: IndexMap<(Endpoint, RemoteTrackId), Option<Instant>>,
+    /// Target bitrate of each spatial layer with its expire time, which is sent with bitrate limit of simulcast streams
+    layer_targets: IndexMap<ChannelId, [Option<(Instant, u64)>; MAX_SPATIAL_LAYERS]>,
+    unknown_feedback: UnknownFeedbackPolicy,
+    unknown_feedback_logged: IndexSet<u8>,
+    /// Channels which sent media since last heartbeat check
+    sent_channels: IndexSet<ChannelId>,
+    /// Time of next heartbeat check, it is set by the first tick
+    heartbeat_at: Option<Instant>,
     queue: VecDeque<Output<Endpoint>>,
+    /// Number of PubData in queue
+    queued_pub_data: usize,
+    /// Video channels whose last queued packet is not the end of a frame
+    mid_frames: IndexSet<ChannelId>,
+    /// Video channels which dropped a frame, they drop until next key-frame
+    dropping: IndexSet<ChannelId>,
 }
 
 impl<Endpoint: Debug + Hash + Eq + Copy> RoomChannelPublisher<Endpoint> {
-    pub fn new(room: ClusterRoomHash) -> Self {
+    pub fn new(track_channels: TrackChannels, unknown_feedback: UnknownFeedbackPolicy, max_sources: usize) -> Self {
         Self {
             _c: Default::default(),
-            room,
+            room: track_channels.room(),
+            track_channels,
             tracks: Default::default(),
             tracks_source: Default::default(),
+            max_sources,
+            republish_waits: Default::default(),
+            paused: false,
+            paused_videos: Default::default(),
+            videos: Default::default(),
+            layer_targets: Default::default(),
+            unknown_feedback,
+            unknown_feedback_logged: Default::default(),
+            sent_channels: Default::default(),
+            heartbeat_at: None,
             queue: VecDeque::new(),
+            queued_pub_data: 0,
+            mid_frames: Default::default(),
+            dropping: Default::default(),
         }
     }
 
-    pub fn on_track_feedback(&mut self, channel: ChannelId, fb: Feedback) {
-        let fb = return_if_err!(FeedbackKind::try_from(fb));
+    pub fn on_tick(&mut self, now: Instant) {
+        let room = self.room;
+        let queue = &mut self.queue;
+        let layer_targets = &mut self.layer_targets;
+        self.republish_waits.retain(|channel_id, started_at| {
+            if now.duration_since(*started_at).as_millis() >= REPUBLISH_WINDOW_MS {
+                log::info!("[ClusterRoom {room}/Publishers] channel {channel_id} republish window timeout => PubStop");
+                layer_targets.swap_remove(channel_id);
+                queue.push_back(Output::Pubsub(pubsub::Control(*channel_id, ChannelControl::PubStop)));
+                false
+            } else {
+                true
+            }
+        });
+        self.send_heartbeats(now);
+    }
+
+    fn send_heartbeats(&mut self, now: Instant) {
+        let heartbeat_at = self.heartbeat_at.get_or_insert(now + Duration::from_millis(SOURCE_HEARTBEAT_INTERVAL_MS as u64));
+        if now < *heartbeat_at {
+            return;
+        }
+        *heartbeat_at = now + Duration::from_millis(SOURCE_HEARTBEAT_INTERVAL_MS as u64);
+        for channel_id in self.tracks_source.keys() {
+            if self.sent_channels.contains(channel_id) {
+                continue;
+            }
+            log::debug!("[ClusterRoom {}/Publishers] channel {channel_id} idle => send heartbeat", self.room);
+            self.queued_pub_data += 1;
+            self.queue.push_back(Output::Pubsub(pubsub::Control(*channel_id, ChannelControl::PubData(vec![]))));
+        }
+        self.sent_channels.clear();
+    }
+
+    pub fn on_track_feedback(&mut self, now: Instant, channel: ChannelId, fb: Feedback) {
+        let kind = fb.kind;
+        let fb = match FeedbackKind::try_from(fb) {
+            Ok(fb) => fb,
+            Err(_) => {
+                self.on_unknown_feedback(channel, kind);
+                return;
+            }
+        };
+        if let FeedbackKind::LayerBitrate { spatial, bitrate, timeout_ms } = fb {
+            // layer targets are sent with the next bitrate feedback, which is always sent by subscribers of simulcast streams
+            log::debug!("[ClusterRoom {}/Publishers] channel {channel} layer {spatial} target bitrate {bitrate}", self.room);
+            let targets = self.layer_targets.entry(channel).or_default();
+            targets[spatial as usize] = Some((now + Duration::from_millis(timeout_ms as u64), bitrate));
+            return;
+        }
+        let layers = self.live_layer_targets(now, channel);
         let sources = return_if_none!(self.tracks_source.get(&channel));
         for (endpoint, track_id) in sources {
             match fb {
                 FeedbackKind::Bitrate { min, max } => {
-                    log::debug!("[ClusterRoom {}/Publishers] channel {channel} limit bitrate [{min},{max}]", self.room);
+                    log::debug!("[ClusterRoom {}/Publishers] channel {channel} limit bitrate [{min},{max}] layers {layers:?}", self.room);
                     self.queue.push_back(Output::Endpoint(
                         vec![*endpoint],
-                        ClusterEndpointEvent::RemoteTrack(*track_id, ClusterRemoteTrackEvent::LimitBitrate { min, max }),
+                        ClusterEndpointEvent::RemoteTrack(*track_id, ClusterRemoteTrackEvent::LimitBitrate { min, max, layers }),
                     ));
                 }
                 FeedbackKind::KeyFrameRequest => {
@@ -75,21 +234,97 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomChannelPublisher<Endpoint> {
                         ClusterEndpointEvent::RemoteTrack(*track_id, ClusterRemoteTrackEvent::RequestKeyFrame),
                     ));
                 }
+                FeedbackKind::LayerBitrate { .. } => {}
+            }
+        }
+    }
+
+    /// Targets of layers which still have consumers, None if no layer has, e.g. single-encoding streams
+    fn live_layer_targets(&mut self, now: Instant, channel: ChannelId) -> Option<[Option<u64>; MAX_SPATIAL_LAYERS]> {
+        let targets = self.layer_targets.get_mut(&channel)?;
+        let mut layers = [None; MAX_SPATIAL_LAYERS];
+        for (target, layer) in targets.iter_mut().zip(layers.iter_mut()) {
+            if target.is_some_and(|(expire_at, _)| expire_at <= now) {
+                *target = None;
+            }
+            *layer = target.map(|(_, bitrate)| bitrate);
+        }
+        if layers.iter().all(|layer| layer.is_none()) {
+            self.layer_targets.swap_remove(&channel);
+            return None;
+        }
+        Some(layers)
+    }
+
+    fn on_unknown_feedback(&mut self, channel: ChannelId, kind: u8) {
+        Count::<UnknownFeedback>::event();
+        match self.unknown_feedback {
+            UnknownFeedbackPolicy::LogOnce if self.unknown_feedback_logged.insert(kind) => {
+                log::warn!("[ClusterRoom {}/Publishers] channel {channel} received unknown feedback kind {kind} => ignored", self.room);
+            }
+            _ => {
+                log::debug!("[ClusterRoom {}/Publishers] channel {channel} received unknown feedback kind {kind} => ignored", self.room);
             }
         }
     }
 
     pub fn on_track_publish(&mut self, endpoint: Endpoint, track: RemoteTrackId, peer: PeerId, name: TrackName) {
-        log::info!("[ClusterRoom {}/Publishers] peer ({peer} started track ({name})", self.room);
-        let channel_id = id_generator::gen_track_channel_id(self.room, &peer, &name);
+        let _span = tracing::info_span!("room_publisher", room_hash = %self.room, peer_id = %peer, track = %name).entered();
+        let channel_id = self.track_channels.channel_id(&peer, &name);
+        if self.max_sources > 0 && self.tracks_source.get(&channel_id).is_some_and(|sources| sources.len() >= self.max_sources) {
+            // a client which relaunches without cleanup can publish same track many times, we don't hide it by accumulating sources
+            tracing::warn!(channel = %channel_id, max_sources = self.max_sources, "[ClusterRoom/Publishers] channel has max sources => reject publish");
+            self.queue
+                .push_back(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::PublishRejected)));
+            return;
+        }
+        tracing::info!(channel = %channel_id, "[ClusterRoom/Publishers] started track");
         self.tracks.insert((endpoint, track), (peer.clone(), name.clone(), channel_id));
         let sources = self.tracks_source.entry(channel_id).or_default();
         if sources.is_empty() {
-            self.queue.push_back(Output::Pubsub(pubsub::Control(channel_id, ChannelControl::PubStart)));
+            if self.republish_waits.swap_remove(&channel_id).is_some() {
+                // channel is still alive, we only need new key-frame for subscribers can decode immediately
+                tracing::info!(channel = %channel_id, "[ClusterRoom/Publishers] republished track => reuse channel");
+                self.queue
+                    .push_back(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)));
+            } else {
+                self.queue.push_back(Output::Pubsub(pubsub::Control(channel_id, ChannelControl::PubStart)));
+            }
         }
         sources.insert((endpoint, track));
     }
 
+    /// When paused, all media data is dropped. On resume we request key-frame from video tracks for subscribers can decode immediately
+    pub fn on_room_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if paused {
+            return;
+        }
+        for (endpoint, track) in self.paused_videos.drain(..) {
+            log::info!("[ClusterRoom {}/Publishers] room resumed => request key_frame from {:?} track {track}", self.room, endpoint);
+            self.queue
+                .push_back(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)));
+        }
+    }
+
+    /// Request key-frame from all video tracks which are published in this room from this node, e.g. a new subscriber joined
+    /// and should decode immediately. Requests to each track are debounced, so peers which join at once cause a single key-frame
+    pub fn request_video_key_frames(&mut self, now: Instant) {
+        if self.paused {
+            // all video tracks are requested key-frame on resume
+            return;
+        }
+        for ((endpoint, track), last) in self.videos.iter_mut() {
+            if last.is_some_and(|last| now.duration_since(last).as_millis() < ROOM_KEY_FRAME_DEBOUNCE_MS) {
+                continue;
+            }
+            *last = Some(now);
+            log::info!("[ClusterRoom {}/Publishers] room-wide request key_frame from {:?} track {track}", self.room, endpoint);
+            self.queue
+                .push_back(Output::Endpoint(vec![*endpoint], ClusterEndpointEvent::RemoteTrack(*track, ClusterRemoteTrackEvent::RequestKeyFrame)));
+        }
+    }
+
     pub fn on_track_data(&mut self, endpoint: Endpoint, track: RemoteTrackId, media: MediaPacket) {
         log::trace!(
             "[ClusterRoom {}/Publishers] peer {:?} track {track} publish media meta {:?} seq {}",
@@ -99,20 +334,74 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomChannelPublisher<Endpoint> {
             media.seq
         );
         let (_peer, _name, channel_id) = return_if_none!(self.tracks.get(&(endpoint, track)));
+        if media.meta.is_video() && !self.videos.contains_key(&(endpoint, track)) {
+            self.videos.insert((endpoint, track), None);
+        }
+        if self.paused {
+            if !media.meta.is_audio() {
+                self.paused_videos.insert((endpoint, track));
+            }
+            return;
+        }
+        let channel_id = *channel_id;
+        self.sent_channels.insert(channel_id);
+        if !self.admit_pub_data(channel_id, &media) {
+            Count::<PubDataDropped>::event();
+            if media.meta.is_video() {
+                self.mid_frames.swap_remove(&channel_id);
+                if self.dropping.insert(channel_id) {
+                    log::debug!("[ClusterRoom {}/Publishers] pubsub queue full => drop {:?} track {track} until key_frame", self.room, endpoint);
+                    self.queue
+                        .push_back(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)));
+                }
+            }
+            return;
+        }
+        if media.meta.is_video() {
+            if media.meta.is_video_key() {
+                self.dropping.swap_remove(&channel_id);
+            }
+            if media.marker {
+                self.mid_frames.swap_remove(&channel_id);
+            } else {
+                self.mid_frames.insert(channel_id);
+            }
+        }
+        self.queued_pub_data += 1;
         let data = media.serialize();
-        self.queue.push_back(Output::Pubsub(pubsub::Control(*channel_id, ChannelControl::PubData(data))))
+        self.queue.push_back(Output::Pubsub(pubsub::Control(channel_id, ChannelControl::PubData(data))))
+    }
+
+    /// Whether a media packet is queued. Over the soft limit only the rest of started frames and key-frames are queued,
+    /// other frames of a dropping channel wait for its key-frame even under the limit
+    fn admit_pub_data(&self, channel_id: ChannelId, media: &MediaPacket) -> bool {
+        if self.queued_pub_data >= MAX_QUEUED_PUB_DATA_HARD {
+            return false;
+        }
+        if media.meta.is_audio() {
+            return self.queued_pub_data < MAX_QUEUED_PUB_DATA;
+        }
+        if self.mid_frames.contains(&channel_id) || media.meta.is_video_key() {
+            return true;
+        }
+        self.queued_pub_data < MAX_QUEUED_PUB_DATA && !self.dropping.contains(&channel_id)
     }
 
-    pub fn on_track_unpublish(&mut self, endpoint: Endpoint, track: RemoteTrackId) {
+    pub fn on_track_unpublish(&mut self, now: Instant, endpoint: Endpoint, track: RemoteTrackId) {
         let (peer, name, channel_id) = return_if_none!(self.tracks.swap_remove(&(endpoint, track)));
+        self.paused_videos.swap_remove(&(endpoint, track));
+        self.videos.swap_remove(&(endpoint, track));
+        let _span = tracing::info_span!("room_publisher", room_hash = %self.room, peer_id = %peer, track = %name).entered();
         let sources = self.tracks_source.get_mut(&channel_id).expect("Should have track_source");
         let removed = sources.swap_remove(&(endpoint, track));
         assert!(removed, "Should remove source child on unpublish");
         if sources.is_empty() {
             self.tracks_source.swap_remove(&channel_id).expect("Should remove source channel on unpublish");
-            self.queue.push_back(Output::Pubsub(pubsub::Control(channel_id, ChannelControl::PubStop)));
+            self.mid_frames.swap_remove(&channel_id);
+            self.dropping.swap_remove(&channel_id);
+            self.republish_waits.insert(channel_id, now);
         }
-        log::info!("[ClusterRoom {}/Publishers] peer ({peer} stopped track {name})", self.room);
+        tracing::info!(channel = %channel_id, "[ClusterRoom/Publishers] stopped track");
     }
 }
 
@@ -120,7 +409,7 @@ impl<Endpoint: Debug + Hash + Eq + Copy> TaskSwitcherChild<Output<Endpoint>> for
     type Time = ();
 
     fn is_empty(&self) -> bool {
-        self.tracks.is_empty() && self.tracks_source.is_empty() && self.queue.is_empty()
+        self.tracks.is_empty() && self.tracks_source.is_empty() && self.republish_waits.is_empty() && self.queue.is_empty()
     }
 
     fn empty_event(&self) -> Output<Endpoint> {
@@ -128,7 +417,11 @@ impl<Endpoint: Debug + Hash + Eq + Copy> TaskSwitcherChild<Output<Endpoint>> for
     }
 
     fn pop_output(&mut self, _now: Self::Time) -> Option<Output<Endpoint>> {
-        self.queue.pop_front()
+        let out = self.queue.pop_front()?;
+        if matches!(out, Output::Pubsub(pubsub::Control(_, ChannelControl::PubData(_)))) {
+            self.queued_pub_data -= 1;
+        }
+        Some(out)
     }
 }
 
@@ -138,25 +431,36 @@ impl<Endpoint: Debug> Drop for RoomChannelPublisher<Endpoint> {
         assert_eq!(self.queue.len(), 0, "Queue not empty on drop {:?}", self.queue);
         assert_eq!(self.tracks.len(), 0, "Tracks not empty on drop {:?}", self.tracks);
         assert_eq!(self.tracks_source.len(), 0, "Tracks source not empty on drop {:?}", self.tracks_source);
+        assert_eq!(self.republish_waits.len(), 0, "Republish waits not empty on drop {:?}", self.republish_waits);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
     use atm0s_sdn::features::pubsub::{ChannelControl, Control, Feedback};
     use media_server_protocol::{
         endpoint::{PeerId, TrackName},
         media::{MediaMeta, MediaPacket},
     };
+    use media_server_utils::get_all_counts;
     use sans_io_runtime::TaskSwitcherChild;
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        Layer,
+    };
 
     use crate::{
         cluster::{ClusterEndpointEvent, ClusterRemoteTrackEvent},
         transport::RemoteTrackId,
     };
 
-    use super::id_generator::gen_track_channel_id;
-    use super::{super::Output, RoomChannelPublisher};
+    use super::{super::Output, PubDataDropped, RoomChannelPublisher, UnknownFeedback, UnknownFeedbackPolicy, DEFAULT_MAX_CHANNEL_SOURCES, MAX_QUEUED_PUB_DATA, REPUBLISH_WINDOW_MS};
+    use crate::cluster::id_generator::{gen_track_channel_id, TrackChannels};
 
     pub fn fake_audio() -> MediaPacket {
         MediaPacket {
@@ -176,7 +480,7 @@ mod tests {
     #[test_log::test]
     fn channel_publish_data() {
         let room = 1.into();
-        let mut publisher = RoomChannelPublisher::<u8>::new(room);
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
 
         let endpoint = 2;
         let track = RemoteTrackId::from(3);
@@ -192,18 +496,131 @@ mod tests {
         assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubData(media.serialize())))));
         assert_eq!(publisher.pop_output(()), None);
 
-        publisher.on_track_unpublish(endpoint, track);
+        let now = Instant::now();
+        publisher.on_track_unpublish(now, endpoint, track);
+        assert_eq!(publisher.pop_output(()), None);
+        publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
         assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop))));
         assert_eq!(publisher.pop_output(()), None);
         assert!(publisher.is_empty());
     }
 
+    fn fake_video(seq: u16, key: bool) -> MediaPacket {
+        MediaPacket {
+            ts: seq as u32 * 3000,
+            seq,
+            marker: true,
+            nackable: true,
+            layers: None,
+            meta: MediaMeta::Vp8 { key, sim: None, rotation: None },
+            data: vec![1, 2, 3, 4],
+        }
+    }
+
+    //Publisher outpacing pubsub => new delta frames are dropped until next key-frame, key-frames are kept and queue is bounded
+    #[test_log::test]
+    fn channel_publish_flood_drop_until_key_frame() {
+        let room = 1.into();
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
+        let dropped = || get_all_counts().get(std::any::type_name::<PubDataDropped>()).copied().unwrap_or(0);
+
+        let endpoint = 2;
+        let track = RemoteTrackId::from(3);
+        let peer = "peer1".to_string().into();
+        let name = "video_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &peer, &name);
+        publisher.on_track_publish(endpoint, track, peer, name);
+
+        let dropped_before = dropped();
+        let total = MAX_QUEUED_PUB_DATA * 2;
+        let packets = (0..total).map(|i| fake_video(i as u16, i % 100 == 0)).collect::<Vec<_>>();
+        for pkt in &packets {
+            publisher.on_track_data(endpoint, track, pkt.clone());
+        }
+
+        // first packets fill the queue, after that only key-frames are kept, each following delta is dropped and asks a key-frame
+        let expected = packets
+            .iter()
+            .filter(|pkt| (pkt.seq as usize) < MAX_QUEUED_PUB_DATA || pkt.meta.is_video_key())
+            .map(|pkt| Output::Pubsub(Control(channel_id, ChannelControl::PubData(pkt.serialize()))))
+            .collect::<Vec<_>>();
+        let outputs = std::iter::from_fn(|| publisher.pop_output(())).collect::<Vec<_>>();
+        let request_key = Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame));
+        let data = outputs.iter().filter(|out| matches!(out, Output::Pubsub(Control(_, ChannelControl::PubData(_))))).collect::<Vec<_>>();
+        assert_eq!(data, expected.iter().collect::<Vec<_>>());
+        assert_eq!(outputs.iter().filter(|out| **out == request_key).count(), 6);
+        assert_eq!(dropped() - dropped_before, total - expected.len());
+
+        // queue is drained but the channel still waits for a key-frame
+        publisher.on_track_data(endpoint, track, fake_video(2000, false));
+        assert_eq!(publisher.pop_output(()), None);
+        publisher.on_track_data(endpoint, track, fake_video(2001, true));
+        publisher.on_track_data(endpoint, track, fake_video(2002, false));
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Pubsub(Control(channel_id, ChannelControl::PubData(fake_video(2001, true).serialize()))))
+        );
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Pubsub(Control(channel_id, ChannelControl::PubData(fake_video(2002, false).serialize()))))
+        );
+
+        publisher.on_track_unpublish(Instant::now(), endpoint, track);
+        publisher.on_tick(Instant::now() + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop))));
+    }
+
+    //A frame which is started under the limit is queued whole, the next frame is dropped
+    #[test_log::test]
+    fn channel_publish_flood_keep_started_frame() {
+        let room = 1.into();
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
+
+        let endpoint = 2;
+        let track = RemoteTrackId::from(3);
+        let peer = "peer1".to_string().into();
+        let name = "video_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &peer, &name);
+        publisher.on_track_publish(endpoint, track, peer, name);
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStart))));
+
+        for i in 0..MAX_QUEUED_PUB_DATA - 1 {
+            publisher.on_track_data(endpoint, track, fake_video(i as u16, i == 0));
+        }
+        let frame = (0..3u16)
+            .map(|i| MediaPacket {
+                marker: i == 2,
+                ..fake_video(1000 + i, false)
+            })
+            .collect::<Vec<_>>();
+        for pkt in &frame {
+            publisher.on_track_data(endpoint, track, pkt.clone());
+        }
+        publisher.on_track_data(endpoint, track, fake_video(1003, false));
+
+        let outputs = std::iter::from_fn(|| publisher.pop_output(())).collect::<Vec<_>>();
+        assert_eq!(outputs.len(), MAX_QUEUED_PUB_DATA + 2 + 1);
+        let tail = frame
+            .iter()
+            .map(|pkt| Output::Pubsub(Control(channel_id, ChannelControl::PubData(pkt.serialize()))))
+            .collect::<Vec<_>>();
+        assert_eq!(outputs[MAX_QUEUED_PUB_DATA - 1..MAX_QUEUED_PUB_DATA + 2], tail[..]);
+        assert_eq!(
+            outputs.last(),
+            Some(&Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)))
+        );
+
+        publisher.on_track_unpublish(Instant::now(), endpoint, track);
+        publisher.on_tick(Instant::now() + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop))));
+    }
+
     //TODO Handle feedback: should handle KeyFrame feedback
     //TODO Handle feedback: should handle Bitrate feedback
     #[test_log::test]
     fn channel_feedback() {
         let room = 1.into();
-        let mut publisher = RoomChannelPublisher::<u8>::new(room);
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
 
         let endpoint = 2;
         let track = RemoteTrackId::from(3);
@@ -214,23 +631,192 @@ mod tests {
         assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStart))));
         assert_eq!(publisher.pop_output(()), None);
 
-        publisher.on_track_feedback(channel_id, Feedback::simple(0, 1000, 100, 200));
+        publisher.on_track_feedback(Instant::now(), channel_id, Feedback::simple(0, 1000, 100, 200));
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(
+                vec![endpoint],
+                ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::LimitBitrate { min: 1000, max: 1000, layers: None })
+            ))
+        );
+
+        publisher.on_track_feedback(Instant::now(), channel_id, Feedback::simple(1, 1, 100, 200));
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)))
+        );
+        assert_eq!(publisher.pop_output(()), None);
+
+        let now = Instant::now();
+        publisher.on_track_unpublish(now, endpoint, track);
+        assert_eq!(publisher.pop_output(()), None);
+        publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop))));
+        assert_eq!(publisher.pop_output(()), None);
+        assert!(publisher.is_empty());
+    }
+
+    #[test_log::test]
+    fn layer_targets_sent_with_bitrate_limit() {
+        let room = 1.into();
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
+
+        let endpoint = 2;
+        let track = RemoteTrackId::from(3);
+        let peer = "peer1".to_string().into();
+        let name = "video_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &peer, &name);
+        publisher.on_track_publish(endpoint, track, peer, name);
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStart))));
+
+        // 3 layers stream with consumers on each layer
+        let now = Instant::now();
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(2, 150_000, 100, 200));
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(3, 500_000, 100, 200));
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(4, 2_000_000, 100, 200));
+        assert_eq!(publisher.pop_output(()), None);
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(0, 2_000_000, 100, 200));
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(
+                vec![endpoint],
+                ClusterEndpointEvent::RemoteTrack(
+                    track,
+                    ClusterRemoteTrackEvent::LimitBitrate {
+                        min: 2_000_000,
+                        max: 2_000_000,
+                        layers: Some([Some(150_000), Some(500_000), Some(2_000_000)])
+                    }
+                )
+            ))
+        );
+
+        // consumers of the highest layer are gone, its target expires
+        let now = now + Duration::from_millis(150);
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(2, 150_000, 100, 200));
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(3, 500_000, 100, 200));
+        let now = now + Duration::from_millis(100);
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(0, 500_000, 100, 200));
         assert_eq!(
             publisher.pop_output(()),
             Some(Output::Endpoint(
                 vec![endpoint],
-                ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::LimitBitrate { min: 1000, max: 1000 })
+                ClusterEndpointEvent::RemoteTrack(
+                    track,
+                    ClusterRemoteTrackEvent::LimitBitrate {
+                        min: 500_000,
+                        max: 500_000,
+                        layers: Some([Some(150_000), Some(500_000), None])
+                    }
+                )
             ))
         );
 
-        publisher.on_track_feedback(channel_id, Feedback::simple(1, 1, 100, 200));
+        // all layer targets expired => simple min max
+        let now = now + Duration::from_millis(200);
+        publisher.on_track_feedback(now, channel_id, Feedback::simple(0, 500_000, 100, 200));
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(
+                vec![endpoint],
+                ClusterEndpointEvent::RemoteTrack(
+                    track,
+                    ClusterRemoteTrackEvent::LimitBitrate {
+                        min: 500_000,
+                        max: 500_000,
+                        layers: None
+                    }
+                )
+            ))
+        );
+        assert!(publisher.layer_targets.is_empty());
+
+        publisher.on_track_unpublish(now, endpoint, track);
+        publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop))));
+        assert_eq!(publisher.pop_output(()), None);
+    }
+
+    #[test_log::test]
+    fn unknown_feedback_counted_and_logged_once() {
+        let unknown_count = || get_all_counts().get(std::any::type_name::<UnknownFeedback>()).copied().unwrap_or(0);
+        let room = 1.into();
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::LogOnce, DEFAULT_MAX_CHANNEL_SOURCES);
+
+        let endpoint = 2;
+        let track = RemoteTrackId::from(3);
+        let peer = "peer1".to_string().into();
+        let name = "video_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &peer, &name);
+        publisher.on_track_publish(endpoint, track, peer, name);
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStart))));
+
+        // feedback kind from a newer node is dropped without affecting known kinds
+        let before = unknown_count();
+        publisher.on_track_feedback(Instant::now(), channel_id, Feedback::simple(7, 1, 100, 200));
+        publisher.on_track_feedback(Instant::now(), channel_id, Feedback::simple(7, 1, 100, 200));
+        assert_eq!(publisher.pop_output(()), None);
+        assert!(unknown_count() >= before + 2);
+        assert_eq!(publisher.unknown_feedback_logged.iter().copied().collect::<Vec<_>>(), vec![7]);
+
+        publisher.on_track_feedback(Instant::now(), channel_id, Feedback::simple(1, 1, 100, 200));
         assert_eq!(
             publisher.pop_output(()),
             Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)))
         );
+
+        let mut ignore = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::Ignore, DEFAULT_MAX_CHANNEL_SOURCES);
+        ignore.on_track_feedback(Instant::now(), channel_id, Feedback::simple(7, 1, 100, 200));
+        assert!(ignore.unknown_feedback_logged.is_empty());
+        assert!("log-once".parse::<UnknownFeedbackPolicy>().is_ok());
+        assert!("other".parse::<UnknownFeedbackPolicy>().is_err());
+    }
+
+    #[test_log::test]
+    fn sources_over_cap_rejected() {
+        let room = 1.into();
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), 2);
+
+        let track = RemoteTrackId::from(3);
+        let peer: PeerId = "peer1".to_string().into();
+        let name: TrackName = "audio_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &peer, &name);
+
+        publisher.on_track_publish(1, track, peer.clone(), name.clone());
+        publisher.on_track_publish(2, track, peer.clone(), name.clone());
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStart))));
+        assert_eq!(publisher.pop_output(()), None);
+
+        // third session of same peer and track is over cap
+        publisher.on_track_publish(3, track, peer, name);
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(vec![3], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::PublishRejected)))
+        );
+        assert_eq!(publisher.pop_output(()), None);
+
+        // rejected source is not published, existing sources keep working
+        let media = fake_audio();
+        publisher.on_track_data(3, track, media.clone());
+        assert_eq!(publisher.pop_output(()), None);
+        publisher.on_track_data(1, track, media.clone());
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubData(media.serialize())))));
+        publisher.on_track_feedback(Instant::now(), channel_id, Feedback::simple(1, 1, 100, 200));
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(vec![1], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)))
+        );
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(vec![2], ClusterEndpointEvent::RemoteTrack(track, ClusterRemoteTrackEvent::RequestKeyFrame)))
+        );
         assert_eq!(publisher.pop_output(()), None);
 
-        publisher.on_track_unpublish(endpoint, track);
+        let now = Instant::now();
+        publisher.on_track_unpublish(now, 3, track);
+        publisher.on_track_unpublish(now, 1, track);
+        publisher.on_track_unpublish(now, 2, track);
+        publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
         assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop))));
         assert_eq!(publisher.pop_output(()), None);
         assert!(publisher.is_empty());
@@ -239,7 +825,7 @@ mod tests {
     #[test_log::test]
     fn two_sessions_same_room_peer_should_not_crash() {
         let room = 1.into();
-        let mut publisher = RoomChannelPublisher::<u8>::new(room);
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
 
         let endpoint1 = 1;
         let endpoint2 = 2;
@@ -254,11 +840,102 @@ mod tests {
         assert!(publisher.pop_output(()).is_some()); // PubStart
         assert!(publisher.pop_output(()).is_none());
 
-        publisher.on_track_unpublish(endpoint1, track);
-        publisher.on_track_unpublish(endpoint2, track);
+        let now = Instant::now();
+        publisher.on_track_unpublish(now, endpoint1, track);
+        publisher.on_track_unpublish(now, endpoint2, track);
+        assert!(publisher.pop_output(()).is_none());
 
+        publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
         assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop)))); // PubStop
         assert_eq!(publisher.pop_output(()), None);
         assert!(publisher.is_empty());
     }
+
+    //Track unpublish then republish in window => should keep channel and request key-frame from new source
+    #[test_log::test]
+    fn republish_should_keep_channel() {
+        let room = 1.into();
+        let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
+
+        let endpoint = 2;
+        let track = RemoteTrackId::from(3);
+        let track2 = RemoteTrackId::from(4);
+        let peer: PeerId = "peer1".to_string().into();
+        let name: TrackName = "video_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &peer, &name);
+        publisher.on_track_publish(endpoint, track, peer.clone(), name.clone());
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStart))));
+        assert_eq!(publisher.pop_output(()), None);
+
+        let now = Instant::now();
+        publisher.on_track_unpublish(now, endpoint, track);
+        assert_eq!(publisher.pop_output(()), None);
+
+        publisher.on_tick(now + Duration::from_millis(500));
+        assert_eq!(publisher.pop_output(()), None);
+
+        // republish with other track id, channel is reused and no PubStart is sent
+        publisher.on_track_publish(endpoint, track2, peer, name);
+        assert_eq!(
+            publisher.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::RemoteTrack(track2, ClusterRemoteTrackEvent::RequestKeyFrame)))
+        );
+        assert_eq!(publisher.pop_output(()), None);
+
+        // after window, channel still alive because of new source, it isn't sending yet so heartbeat is sent instead
+        publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64 + 1000));
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubData(vec![])))));
+        assert_eq!(publisher.pop_output(()), None);
+
+        let media = fake_audio();
+        publisher.on_track_data(endpoint, track2, media.clone());
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubData(media.serialize())))));
+
+        let now = now + Duration::from_millis(5000);
+        publisher.on_track_unpublish(now, endpoint, track2);
+        publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
+        assert_eq!(publisher.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::PubStop))));
+        assert_eq!(publisher.pop_output(()), None);
+        assert!(publisher.is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct SpanCapture(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for SpanCapture {
+        fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, _id: &tracing::span::Id, _ctx: Context<'_, S>) {
+            struct FieldsVisitor(String);
+            impl tracing::field::Visit for FieldsVisitor {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    self.0.push_str(&format!("{}={:?};", field.name(), value));
+                }
+            }
+            let mut visitor = FieldsVisitor(String::new());
+            attrs.record(&mut visitor);
+            self.0.lock().expect("Should lock").push((attrs.metadata().name().to_string(), visitor.0));
+        }
+    }
+
+    //Track publish => should emit span with room, peer and track fields
+    #[test]
+    fn publish_emit_room_span() {
+        let capture = SpanCapture::default();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(capture.clone()), || {
+            let room = 1.into();
+            let mut publisher = RoomChannelPublisher::<u8>::new(TrackChannels::hashed(room), UnknownFeedbackPolicy::default(), DEFAULT_MAX_CHANNEL_SOURCES);
+            let track = RemoteTrackId::from(3);
+            publisher.on_track_publish(2, track, "peer1".to_string().into(), "audio_main".to_string().into());
+            assert!(publisher.pop_output(()).is_some());
+
+            let now = Instant::now();
+            publisher.on_track_unpublish(now, 2, track);
+            publisher.on_tick(now + Duration::from_millis(REPUBLISH_WINDOW_MS as u64));
+            assert!(publisher.pop_output(()).is_some());
+            assert!(publisher.is_empty());
+        });
+
+        let spans = capture.0.lock().expect("Should lock");
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0], ("room_publisher".to_string(), "room_hash=1;peer_id=peer1;track=audio_main;".to_string()));
+    }
 }
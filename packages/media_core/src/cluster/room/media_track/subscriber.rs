@@ -1,8 +1,20 @@
 //!
 //! Channel Subscriber handle logic for viewer. This module takecare sending Sub or Unsub, and also feedback
 //!
+//! When the node which hosts channel source fails, the channel stops receiving data without any event from pubsub.
+//! We detect it with a data timeout, fire SourceLost to subscribers then re-subscribe for failover to a surviving source.
+//! Publishers send heartbeats while their tracks are idle (muted, DTX audio or room paused), so only a source which stopped
+//! sending both media and heartbeats is lost.
+//! Re-subscribe is retried until data arrives again, which fires SourceRecovered. If the source is truly gone
+//! the subscribers only see SourceLost, and they decide what to do with the track.
+//!
 
-use std::{collections::VecDeque, fmt::Debug, hash::Hash, time::Instant};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    hash::Hash,
+    time::{Duration, Instant},
+};
 
 use atm0s_sdn::{
     features::pubsub::{self, ChannelControl, ChannelId, Feedback},
@@ -12,13 +24,13 @@ use derivative::Derivative;
 use indexmap::IndexMap;
 use media_server_protocol::{
     endpoint::{PeerId, TrackName},
-    media::MediaPacket,
+    media::{MediaLayersBitrate, MediaPacket},
 };
 use media_server_utils::Count;
 use sans_io_runtime::{return_if_none, TaskSwitcherChild};
 
 use crate::{
-    cluster::{id_generator, ClusterEndpointEvent, ClusterLocalTrackEvent, ClusterRoomHash},
+    cluster::{id_generator::TrackChannels, ClusterEndpointEvent, ClusterLocalTrackEvent, ClusterRoomHash},
     transport::LocalTrackId,
 };
 
@@ -32,34 +44,94 @@ const KEYFRAME_FEEDBACK_TIMEOUT: u16 = 2000; //2 seconds
 
 const BITRATE_FEEDBACK_KIND: u8 = 0;
 const KEYFRAME_FEEDBACK_KIND: u8 = 1;
+/// Kind of spatial layer 0, layers 1 and 2 are next kinds
+const LAYER_BITRATE_FEEDBACK_KIND: u8 = 2;
+
+/// After this time without media or heartbeat, the source is considered lost
+pub const DEFAULT_SOURCE_TIMEOUT: Duration = Duration::from_secs(5);
+const SOURCE_RESUB_INTERVAL_MS: u128 = 5000; //retry re-subscribe each 5s while source is lost
+
+#[derive(Debug, Default, PartialEq, Eq)]
+enum SourceState {
+    /// Not received any data yet, we can't detect loss because publisher may not started sending
+    #[default]
+    Waiting,
+    Alive {
+        last_data: Instant,
+    },
+    Lost {
+        last_resub: Instant,
+    },
+}
 
 #[derive(Derivative, Debug)]
 #[derivative(Default(bound = ""))]
 struct ChannelContainer<Endpoint: Debug> {
     endpoints: Vec<(Endpoint, LocalTrackId)>,
     bitrate_fbs: IndexMap<Endpoint, (Instant, Feedback)>,
+    /// Last layers info of a simulcast stream, for finding which layer each subscriber receives
+    layers: Option<MediaLayersBitrate>,
+    source: SourceState,
 }
 
 #[derive(Debug)]
 pub struct RoomChannelSubscribe<Endpoint: Debug> {
     _c: Count<Self>,
     room: ClusterRoomHash,
+    track_channels: TrackChannels,
+    /// Zero for disabled source lost detection
+    source_timeout: Duration,
     channels: IndexMap<ChannelId, ChannelContainer<Endpoint>>,
     subscribers: IndexMap<(Endpoint, LocalTrackId), (ChannelId, PeerId, TrackName)>,
     queue: VecDeque<Output<Endpoint>>,
 }
 
 impl<Endpoint: Debug + Hash + Eq + Copy + Debug> RoomChannelSubscribe<Endpoint> {
-    pub fn new(room: ClusterRoomHash) -> Self {
+    pub fn new(track_channels: TrackChannels, source_timeout: Duration) -> Self {
         Self {
             _c: Default::default(),
-            room,
+            room: track_channels.room(),
+            track_channels,
+            source_timeout,
             channels: IndexMap::new(),
             subscribers: IndexMap::new(),
             queue: VecDeque::new(),
         }
     }
 
+    pub fn on_tick(&mut self, now: Instant) {
+        if self.source_timeout.is_zero() {
+            return;
+        }
+        for (channel, container) in self.channels.iter_mut() {
+            match container.source {
+                SourceState::Waiting => {}
+                SourceState::Alive { last_data } => {
+                    if now.duration_since(last_data) >= self.source_timeout {
+                        log::warn!(
+                            "[ClusterRoom {}/Subscribers] channel {channel} source lost => fire event to {:?} and re-subscribe",
+                            self.room,
+                            container.endpoints
+                        );
+                        container.source = SourceState::Lost { last_resub: now };
+                        for (endpoint, track) in &container.endpoints {
+                            self.queue
+                                .push_back(Output::Endpoint(vec![*endpoint], ClusterEndpointEvent::LocalTrack(*track, ClusterLocalTrackEvent::SourceLost)));
+                        }
+                        Self::resubscribe(&mut self.queue, *channel);
+                    }
+                }
+                SourceState::Lost { last_resub } => {
+                    if now.duration_since(last_resub).as_millis() >= SOURCE_RESUB_INTERVAL_MS {
+                        log::info!("[ClusterRoom {}/Subscribers] channel {channel} source still lost => re-subscribe", self.room);
+                        container.source = SourceState::Lost { last_resub: now };
+                        Self::resubscribe(&mut self.queue, *channel);
+                    }
+                }
+            }
+        }
+    }
+
     pub fn on_track_relay_changed(&mut self, channel: ChannelId, _relay: NodeId) {
         let channel_container = return_if_none!(self.channels.get(&channel));
         log::info!(
@@ -73,9 +145,28 @@ impl<Endpoint: Debug + Hash + Eq + Copy + Debug> RoomChannelSubscribe<Endpoint>
         }
     }
 
-    pub fn on_track_data(&mut self, channel: ChannelId, data: Vec<u8>) {
+    pub fn on_track_data(&mut self, now: Instant, channel: ChannelId, data: Vec<u8>) {
+        let channel_container = return_if_none!(self.channels.get_mut(&channel));
+        let pre_source = std::mem::replace(&mut channel_container.source, SourceState::Alive { last_data: now });
+        if let SourceState::Lost { .. } = pre_source {
+            log::info!(
+                "[ClusterRoom {}/Subscribers] channel {channel} source recovered => fire event to {:?}",
+                self.room,
+                channel_container.endpoints
+            );
+            for (endpoint, track) in &channel_container.endpoints {
+                self.queue
+                    .push_back(Output::Endpoint(vec![*endpoint], ClusterEndpointEvent::LocalTrack(*track, ClusterLocalTrackEvent::SourceRecovered)));
+            }
+        }
+        if data.is_empty() {
+            log::trace!("[ClusterRoom {}/Subscribers] channel {channel} heartbeat from idle source", self.room);
+            return;
+        }
         let pkt = return_if_none!(MediaPacket::deserialize(&data));
-        let channel_container = return_if_none!(self.channels.get(&channel));
+        if pkt.layers.as_ref().is_some_and(|layers| layers.number_layers() > 1) {
+            channel_container.layers.clone_from(&pkt.layers);
+        }
         log::trace!(
             "[ClusterRoom {}/Subscribers] on channel media meta {:?} seq {} to {} subscribers",
             self.room,
@@ -92,7 +183,7 @@ impl<Endpoint: Debug + Hash + Eq + Copy + Debug> RoomChannelSubscribe<Endpoint>
     }
 
     pub fn on_track_subscribe(&mut self, endpoint: Endpoint, track: LocalTrackId, target_peer: PeerId, target_track: TrackName) {
-        let channel_id: ChannelId = id_generator::gen_track_channel_id(self.room, &target_peer, &target_track);
+        let channel_id: ChannelId = self.track_channels.channel_id(&target_peer, &target_track);
         log::info!(
             "[ClusterRoom {}/Subscribers] endpoint {:?} track {track} subscribe peer {target_peer} track {target_track}), channel: {channel_id}",
             self.room,
@@ -139,6 +230,23 @@ impl<Endpoint: Debug + Hash + Eq + Copy + Debug> RoomChannelSubscribe<Endpoint>
         log::debug!("[ClusterRoom {}/Subscribers] channel {channel_id} setting desired bitrate {:?}", self.room, sum_fb);
         self.queue
             .push_back(Output::Pubsub(pubsub::Control(*channel_id, ChannelControl::FeedbackAuto(return_if_none!(sum_fb)))));
+
+        // for simulcast streams, each layer target is the max desired bitrate of subscribers which receive that layer
+        let layers = return_if_none!(channel_container.layers.as_ref());
+        let mut targets = [None; 3];
+        for (_, fb) in channel_container.bitrate_fbs.values() {
+            let kbps = (fb.max / 1000).min(u16::MAX as u64) as u16;
+            let selection = return_if_none!(layers.select_layer(kbps, 2, 2));
+            let target = &mut targets[selection.spatial as usize];
+            *target = Some(fb.max.max(target.unwrap_or(0)));
+        }
+        for (spatial, target) in targets.into_iter().enumerate() {
+            if let Some(bitrate) = target {
+                log::debug!("[ClusterRoom {}/Subscribers] channel {channel_id} setting layer {spatial} target bitrate {bitrate}", self.room);
+                let fb = Feedback::simple(LAYER_BITRATE_FEEDBACK_KIND + spatial as u8, bitrate, BITRATE_FEEDBACK_INTERVAL, BITRATE_FEEDBACK_TIMEOUT);
+                self.queue.push_back(Output::Pubsub(pubsub::Control(*channel_id, ChannelControl::FeedbackAuto(fb))));
+            }
+        }
     }
 
     pub fn on_track_unsubscribe(&mut self, endpoint: Endpoint, track: LocalTrackId) {
@@ -158,6 +266,12 @@ impl<Endpoint: Debug + Hash + Eq + Copy + Debug> RoomChannelSubscribe<Endpoint>
             self.queue.push_back(Output::Pubsub(pubsub::Control(channel_id, ChannelControl::UnsubAuto)));
         }
     }
+
+    /// Sub again from scratch, pubsub will look up sources and build a new route
+    fn resubscribe(queue: &mut VecDeque<Output<Endpoint>>, channel: ChannelId) {
+        queue.push_back(Output::Pubsub(pubsub::Control(channel, ChannelControl::UnsubAuto)));
+        queue.push_back(Output::Pubsub(pubsub::Control(channel, ChannelControl::SubAuto)));
+    }
 }
 
 impl<Endpoint: Debug + Hash + Eq + Copy> TaskSwitcherChild<Output<Endpoint>> for RoomChannelSubscribe<Endpoint> {
@@ -192,7 +306,7 @@ mod tests {
     use atm0s_sdn::features::pubsub::{ChannelControl, Control, Feedback};
     use media_server_protocol::{
         endpoint::{PeerId, TrackName},
-        media::{MediaMeta, MediaPacket},
+        media::{MediaLayerBitrate, MediaLayersBitrate, MediaMeta, MediaPacket},
     };
     use sans_io_runtime::TaskSwitcherChild;
 
@@ -201,9 +315,12 @@ mod tests {
         transport::LocalTrackId,
     };
 
-    use super::id_generator::gen_track_channel_id;
     use super::{Output, RoomChannelSubscribe};
-    use super::{BITRATE_FEEDBACK_INTERVAL, BITRATE_FEEDBACK_KIND, BITRATE_FEEDBACK_TIMEOUT, KEYFRAME_FEEDBACK_INTERVAL, KEYFRAME_FEEDBACK_KIND, KEYFRAME_FEEDBACK_TIMEOUT};
+    use super::{
+        BITRATE_FEEDBACK_INTERVAL, BITRATE_FEEDBACK_KIND, BITRATE_FEEDBACK_TIMEOUT, DEFAULT_SOURCE_TIMEOUT, KEYFRAME_FEEDBACK_INTERVAL, KEYFRAME_FEEDBACK_KIND, KEYFRAME_FEEDBACK_TIMEOUT,
+        LAYER_BITRATE_FEEDBACK_KIND, SOURCE_RESUB_INTERVAL_MS,
+    };
+    use crate::cluster::id_generator::{gen_track_channel_id, TrackChannels};
 
     pub fn fake_audio() -> MediaPacket {
         MediaPacket {
@@ -222,7 +339,7 @@ mod tests {
     #[test_log::test]
     fn normal_sub_ubsub() {
         let room = 1.into();
-        let mut subscriber = RoomChannelSubscribe::<u8>::new(room);
+        let mut subscriber = RoomChannelSubscribe::<u8>::new(TrackChannels::hashed(room), DEFAULT_SOURCE_TIMEOUT);
 
         let endpoint = 2;
         let track = LocalTrackId::from(3);
@@ -234,7 +351,7 @@ mod tests {
         assert_eq!(subscriber.pop_output(()), None);
 
         let pkt = fake_audio();
-        subscriber.on_track_data(channel_id, pkt.serialize());
+        subscriber.on_track_data(Instant::now(), channel_id, pkt.serialize());
         assert_eq!(
             subscriber.pop_output(()),
             Some(Output::Endpoint(
@@ -254,7 +371,7 @@ mod tests {
     #[test_log::test]
     fn send_key_frame() {
         let room = 1.into();
-        let mut subscriber = RoomChannelSubscribe::<u8>::new(room);
+        let mut subscriber = RoomChannelSubscribe::<u8>::new(TrackChannels::hashed(room), DEFAULT_SOURCE_TIMEOUT);
 
         let endpoint = 2;
         let track = LocalTrackId::from(3);
@@ -285,7 +402,7 @@ mod tests {
     #[test_log::test]
     fn send_bitrate_limit_speed() {
         let room = 1.into();
-        let mut subscriber = RoomChannelSubscribe::<u8>::new(room);
+        let mut subscriber = RoomChannelSubscribe::<u8>::new(TrackChannels::hashed(room), DEFAULT_SOURCE_TIMEOUT);
 
         let endpoint1 = 2;
         let track1 = LocalTrackId::from(3);
@@ -360,4 +477,183 @@ mod tests {
         assert_eq!(subscriber.pop_output(()), None);
         assert!(subscriber.is_empty());
     }
+
+    /// Source node fails => SourceLost and re-subscribe until data arrives again from other source => SourceRecovered
+    #[test_log::test]
+    fn source_lost_failover() {
+        let room = 1.into();
+        let mut subscriber = RoomChannelSubscribe::<u8>::new(TrackChannels::hashed(room), DEFAULT_SOURCE_TIMEOUT);
+
+        let endpoint = 2;
+        let track = LocalTrackId::from(3);
+        let target_peer: PeerId = "peer2".to_string().into();
+        let target_track: TrackName = "audio_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &target_peer, &target_track);
+        subscriber.on_track_subscribe(endpoint, track, target_peer.clone(), target_track.clone());
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::SubAuto))));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        // without any data, we don't know if source exists yet
+        let t0 = Instant::now();
+        subscriber.on_tick(t0 + Duration::from_secs(10));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        let pkt = fake_audio();
+        subscriber.on_track_data(t0, channel_id, pkt.serialize());
+        assert!(matches!(
+            subscriber.pop_output(()),
+            Some(Output::Endpoint(_, ClusterEndpointEvent::LocalTrack(_, ClusterLocalTrackEvent::Media(..))))
+        ));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        subscriber.on_tick(t0 + DEFAULT_SOURCE_TIMEOUT - Duration::from_millis(1));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        // source node failed => lost and failover
+        let lost_at = t0 + DEFAULT_SOURCE_TIMEOUT;
+        subscriber.on_tick(lost_at);
+        assert_eq!(
+            subscriber.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::LocalTrack(track, ClusterLocalTrackEvent::SourceLost)))
+        );
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::UnsubAuto))));
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::SubAuto))));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        // still lost => only retry re-subscribe, no duplicated SourceLost
+        subscriber.on_tick(lost_at + Duration::from_millis(SOURCE_RESUB_INTERVAL_MS as u64));
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::UnsubAuto))));
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::SubAuto))));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        // data from surviving source => recovered
+        subscriber.on_track_data(lost_at + Duration::from_secs(6), channel_id, pkt.serialize());
+        assert_eq!(
+            subscriber.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::LocalTrack(track, ClusterLocalTrackEvent::SourceRecovered)))
+        );
+        assert_eq!(
+            subscriber.pop_output(()),
+            Some(Output::Endpoint(
+                vec![endpoint],
+                ClusterEndpointEvent::LocalTrack(track, ClusterLocalTrackEvent::Media(*channel_id, pkt))
+            ))
+        );
+        assert_eq!(subscriber.pop_output(()), None);
+
+        subscriber.on_track_unsubscribe(endpoint, track);
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::UnsubAuto))));
+        assert_eq!(subscriber.pop_output(()), None);
+        assert!(subscriber.is_empty());
+    }
+
+    /// Muted or DTX source only sends heartbeats => not lost, heartbeats are not forwarded. Zero timeout disables detection
+    #[test_log::test]
+    fn source_idle_with_heartbeat_not_lost() {
+        let room = 1.into();
+        let target_peer: PeerId = "peer2".to_string().into();
+        let target_track: TrackName = "audio_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &target_peer, &target_track);
+        let t0 = Instant::now();
+
+        for source_timeout in [DEFAULT_SOURCE_TIMEOUT, Duration::ZERO] {
+            let mut subscriber = RoomChannelSubscribe::<u8>::new(TrackChannels::hashed(room), source_timeout);
+            subscriber.on_track_subscribe(2, LocalTrackId::from(3), target_peer.clone(), target_track.clone());
+            assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::SubAuto))));
+
+            subscriber.on_track_data(t0, channel_id, fake_audio().serialize());
+            assert!(subscriber.pop_output(()).is_some());
+            assert_eq!(subscriber.pop_output(()), None);
+
+            for sec in 1..20 {
+                let now = t0 + Duration::from_secs(sec);
+                subscriber.on_track_data(now, channel_id, vec![]);
+                subscriber.on_tick(now);
+                assert_eq!(subscriber.pop_output(()), None);
+            }
+
+            // heartbeats stopped => lost only if detection is enabled
+            subscriber.on_tick(t0 + Duration::from_secs(19) + DEFAULT_SOURCE_TIMEOUT);
+            assert_eq!(
+                subscriber.pop_output(()),
+                (!source_timeout.is_zero()).then_some(Output::Endpoint(vec![2], ClusterEndpointEvent::LocalTrack(LocalTrackId::from(3), ClusterLocalTrackEvent::SourceLost)))
+            );
+            while subscriber.pop_output(()).is_some() {}
+            subscriber.on_track_unsubscribe(2, LocalTrackId::from(3));
+            while subscriber.pop_output(()).is_some() {}
+        }
+    }
+
+    #[test_log::test]
+    fn send_layer_bitrate_for_simulcast() {
+        let room = 1.into();
+        let mut subscriber = RoomChannelSubscribe::<u8>::new(TrackChannels::hashed(room), DEFAULT_SOURCE_TIMEOUT);
+
+        let track = LocalTrackId::from(3);
+        let target_peer: PeerId = "peer2".to_string().into();
+        let target_track: TrackName = "video_main".to_string().into();
+        let channel_id = gen_track_channel_id(room, &target_peer, &target_track);
+        for endpoint in [2, 3, 4] {
+            subscriber.on_track_subscribe(endpoint, track, target_peer.clone(), target_track.clone());
+        }
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::SubAuto))));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        // 3 layers stream with 100, 400 and 1500 kbps
+        let mut layers = MediaLayersBitrate::default();
+        layers.set_layer(0, MediaLayerBitrate::new(&[50, 80, 100]));
+        layers.set_layer(1, MediaLayerBitrate::new(&[200, 300, 400]));
+        layers.set_layer(2, MediaLayerBitrate::new(&[800, 1200, 1500]));
+        let pkt = MediaPacket {
+            ts: 0,
+            seq: 0,
+            marker: true,
+            nackable: true,
+            layers: Some(layers),
+            meta: MediaMeta::Vp8 { key: true, sim: None, rotation: None },
+            data: vec![1, 2, 3, 4],
+        };
+        let now = Instant::now();
+        subscriber.on_track_data(now, channel_id, pkt.serialize());
+        while subscriber.pop_output(()).is_some() {}
+
+        subscriber.on_track_desired_bitrate(now, 2, track, 150_000);
+        subscriber.on_track_desired_bitrate(now, 3, track, 600_000);
+        while subscriber.pop_output(()).is_some() {}
+
+        // each layer target is the max desired bitrate of its subscribers
+        subscriber.on_track_desired_bitrate(now, 4, track, 2_000_000);
+        let layer_fb = |spatial: u8, bitrate: u64| {
+            Some(Output::Pubsub(Control(
+                channel_id,
+                ChannelControl::FeedbackAuto(Feedback::simple(LAYER_BITRATE_FEEDBACK_KIND + spatial, bitrate, BITRATE_FEEDBACK_INTERVAL, BITRATE_FEEDBACK_TIMEOUT)),
+            )))
+        };
+        assert_eq!(
+            subscriber.pop_output(()),
+            Some(Output::Pubsub(Control(
+                channel_id,
+                ChannelControl::FeedbackAuto(Feedback {
+                    kind: BITRATE_FEEDBACK_KIND,
+                    count: 3,
+                    max: 2_000_000,
+                    min: 150_000,
+                    sum: 2_750_000,
+                    interval_ms: BITRATE_FEEDBACK_INTERVAL,
+                    timeout_ms: BITRATE_FEEDBACK_TIMEOUT
+                })
+            )))
+        );
+        assert_eq!(subscriber.pop_output(()), layer_fb(0, 150_000));
+        assert_eq!(subscriber.pop_output(()), layer_fb(1, 600_000));
+        assert_eq!(subscriber.pop_output(()), layer_fb(2, 2_000_000));
+        assert_eq!(subscriber.pop_output(()), None);
+
+        for endpoint in [2, 3, 4] {
+            subscriber.on_track_unsubscribe(endpoint, track);
+        }
+        assert_eq!(subscriber.pop_output(()), Some(Output::Pubsub(Control(channel_id, ChannelControl::UnsubAuto))));
+        assert_eq!(subscriber.pop_output(()), None);
+        assert!(subscriber.is_empty());
+    }
 }
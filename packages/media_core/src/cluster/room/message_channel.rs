@@ -36,11 +36,11 @@ pub struct RoomMessageChannel<Endpoint: Debug + Hash + Eq + Copy> {
 }
 
 impl<Endpoint: Hash + Eq + Copy + Debug> RoomMessageChannel<Endpoint> {
-    pub fn new(room: ClusterRoomHash) -> Self {
+    pub fn new(room: ClusterRoomHash, max_payload: usize) -> Self {
         log::info!("[ClusterRoomDataChannel {}] Create virtual datachannel", room);
         Self {
             room,
-            publisher: TaskSwitcherBranch::new(MessageChannelPublisher::new(room), TaskType::Publisher),
+            publisher: TaskSwitcherBranch::new(MessageChannelPublisher::new(room, max_payload), TaskType::Publisher),
             subscriber: TaskSwitcherBranch::new(MessageChannelSubscriber::new(room), TaskType::Subscriber),
             switcher: TaskSwitcher::new(2),
         }
@@ -136,7 +136,7 @@ mod tests {
         cluster::{
             id_generator,
             room::message_channel::{Output, RoomMessageChannel},
-            ClusterEndpointEvent,
+            ClusterEndpointEvent, ClusterMessageChannelError, DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD,
         },
         endpoint::MessageChannelLabel,
     };
@@ -145,7 +145,7 @@ mod tests {
     fn start_stop_publish() {
         let now = ();
         let room_id = 1.into();
-        let mut room = RoomMessageChannel::new(room_id);
+        let mut room = RoomMessageChannel::new(room_id, DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD);
         let user1 = 1;
         let user2 = 2;
         let label1 = &MessageChannelLabel("test".to_string());
@@ -172,7 +172,7 @@ mod tests {
     fn sub_unsub() {
         let now = ();
         let room_id = 1.into();
-        let mut room = RoomMessageChannel::new(room_id);
+        let mut room = RoomMessageChannel::new(room_id, DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD);
         let user1 = 1;
         let user2 = 2;
         let user3 = 3;
@@ -215,7 +215,7 @@ mod tests {
     fn receive_data() {
         let now = ();
         let room_id = 1.into();
-        let mut room = RoomMessageChannel::new(room_id);
+        let mut room = RoomMessageChannel::new(room_id, DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD);
         let user1 = 1;
         let user2 = 2;
         let label1 = MessageChannelLabel("test".to_string());
@@ -266,7 +266,7 @@ mod tests {
     fn publish_data() {
         let now = ();
         let room_id = 1.into();
-        let mut room = RoomMessageChannel::new(room_id);
+        let mut room = RoomMessageChannel::new(room_id, DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD);
         let user1 = 1;
         let user2 = 2;
         let label1 = &MessageChannelLabel("test".to_string());
@@ -301,11 +301,50 @@ mod tests {
         assert_eq!(room.pop_output(now), None);
     }
 
+    #[test_log::test]
+    fn reject_too_large_data() {
+        let now = ();
+        let room_id = 1.into();
+        let mut room = RoomMessageChannel::new(room_id, 4);
+        let user1 = 1;
+        let label1 = &MessageChannelLabel("test".to_string());
+
+        let channel_id1 = id_generator::gen_msg_channel_id(room_id, label1);
+
+        room.on_channel_publish_start(user1, label1);
+        assert_eq!(room.pop_output(now), Some(Output::Pubsub(pubsub::Control(channel_id1, ChannelControl::PubStart))));
+
+        let small = MessageChannelPacket {
+            from: PeerId::from("testid"),
+            data: vec![1, 2, 3, 4],
+        };
+        room.on_channel_data(user1, label1, small.clone());
+        assert_eq!(room.pop_output(now), Some(Output::Pubsub(pubsub::Control(channel_id1, ChannelControl::PubData(small.serialize())))));
+
+        let large = MessageChannelPacket {
+            from: PeerId::from("testid"),
+            data: vec![1, 2, 3, 4, 5],
+        };
+        room.on_channel_data(user1, label1, large);
+        assert_eq!(
+            room.pop_output(now),
+            Some(Output::Endpoint(
+                vec![user1],
+                ClusterEndpointEvent::MessageChannelError(label1.clone(), ClusterMessageChannelError::TooLarge(5))
+            ))
+        );
+        assert_eq!(room.pop_output(now), None);
+
+        room.on_channel_publish_stop(user1, label1);
+        assert_eq!(room.pop_output(now), Some(Output::Pubsub(pubsub::Control(channel_id1, ChannelControl::PubStop))));
+        assert_eq!(room.pop_output(now), None);
+    }
+
     #[test_log::test]
     fn leave_room() {
         let now = ();
         let room_id = 1.into();
-        let mut room = RoomMessageChannel::new(room_id);
+        let mut room = RoomMessageChannel::new(room_id, DEFAULT_MESSAGE_CHANNEL_MAX_PAYLOAD);
         let user1 = 1;
         let user2 = 2;
         let label1 = &MessageChannelLabel("test".to_string());
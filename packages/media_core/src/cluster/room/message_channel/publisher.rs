@@ -7,7 +7,7 @@ use media_server_utils::Count;
 use sans_io_runtime::{return_if_none, TaskSwitcherChild};
 
 use crate::{
-    cluster::{id_generator, ClusterRoomHash},
+    cluster::{id_generator, ClusterEndpointEvent, ClusterMessageChannelError, ClusterRoomHash},
     endpoint::MessageChannelLabel,
 };
 
@@ -24,17 +24,19 @@ pub struct MessageChannelPublisher<Endpoint: Debug> {
     room: ClusterRoomHash,
     channels: IndexMap<ChannelId, ChannelContainer<Endpoint>>,
     publishers: IndexMap<Endpoint, IndexSet<ChannelId>>,
+    max_payload: usize,
     queue: VecDeque<Output<Endpoint>>,
 }
 
 impl<Endpoint: Debug + Hash + Eq + Copy> MessageChannelPublisher<Endpoint> {
-    pub fn new(room: ClusterRoomHash) -> Self {
+    pub fn new(room: ClusterRoomHash, max_payload: usize) -> Self {
         Self {
             _c: Default::default(),
             room,
             queue: VecDeque::new(),
             channels: IndexMap::new(),
             publishers: IndexMap::new(),
+            max_payload,
         }
     }
 
@@ -99,11 +101,17 @@ impl<Endpoint: Debug + Hash + Eq + Copy> MessageChannelPublisher<Endpoint> {
 
         let channel_id: ChannelId = id_generator::gen_msg_channel_id(self.room, label);
         let channel = return_if_none!(self.channels.get(&channel_id));
-        if channel.publishers.contains(&endpoint) {
+        if !channel.publishers.contains(&endpoint) {
+            log::warn!("[ClusterRoomMessageChannel {}/Publisher] publish without start", self.room);
+        } else if data.data.len() > self.max_payload {
+            log::warn!("[ClusterRoomMessageChannel {}/Publisher] reject message {} bytes, max {}", self.room, data.data.len(), self.max_payload);
+            self.queue.push_back(Output::Endpoint(
+                vec![endpoint],
+                ClusterEndpointEvent::MessageChannelError(label.clone(), ClusterMessageChannelError::TooLarge(data.data.len())),
+            ));
+        } else {
             let data = data.serialize();
             self.queue.push_back(Output::Pubsub(pubsub::Control(channel_id, ChannelControl::PubData(data))))
-        } else {
-            log::warn!("[ClusterRoomMessageChannel {}/Publisher] publish without start", self.room);
         }
     }
 }
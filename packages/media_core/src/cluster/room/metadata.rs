@@ -6,8 +6,20 @@
 //! - Track only: subscribe on track info, this method is useful with large users application like broadcast or webinar
 //! - Manual: client manual call subscribe on which peer it interested in, this method is useful with some spartial audio application
 //!
+//! A remote peer which is deleted from peers map is kept present for leave grace, if it is set again inside the grace
+//! (transient disconnect then reconnect) subscribers don't see any PeerLeaved and PeerJoined churn.
+//!
+//! DhtKv doesn't report a failed Set, so with [`KvRetryPolicy`] the peer Set of a join is confirmed by OnSet of the peers map,
+//! or by a get of the peers map after the timeout. An unconfirmed Set is sent again with doubled timeout, and after all retries
+//! the join fails with [`ClusterJoinRejectReason::StorageError`] instead of leaving the peer half-joined.
+//!
 
-use std::{collections::VecDeque, fmt::Debug, hash::Hash};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    hash::Hash,
+    time::{Duration, Instant},
+};
 
 use atm0s_sdn::features::dht_kv::{self, Map, MapControl, MapEvent};
 use indexmap::{IndexMap, IndexSet};
@@ -15,22 +27,58 @@ use media_server_protocol::endpoint::{PeerId, PeerInfo, PeerMeta, RoomInfoPublis
 use sans_io_runtime::{return_if_none, TaskSwitcherChild};
 
 use crate::{
-    cluster::{id_generator, ClusterEndpointEvent, ClusterRoomHash},
+    cluster::{id_generator, ClusterEndpointEvent, ClusterJoinRejectReason, ClusterRoomHash},
     transport::RemoteTrackId,
 };
 
+/// How a Join control is handled, it is decided by local endpoints of the room
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinKind {
+    /// Endpoint is not in room and no other endpoint is using the peer id
+    Fresh,
+    /// Same endpoint joins again with same peer id (client retry), it is treated as metadata update
+    Update,
+    /// Peer id is already used by other endpoint, or this endpoint is already joined with other peer id
+    Rejected,
+}
+
+/// Confirmation policy of peer Set in peers map
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KvRetryPolicy {
+    /// Time to wait for confirmation of the first attempt, doubled after each retry. Zero disables confirmation
+    pub timeout: Duration,
+    /// Number of times an unconfirmed Set is sent again before the join fails
+    pub retries: u8,
+}
+
+/// Peer Set which is not confirmed yet
+#[derive(Debug)]
+struct PendingSet<Endpoint> {
+    endpoint: Endpoint,
+    attempt: u8,
+    /// Armed at the next tick after the Set is sent
+    deadline: Option<Instant>,
+    /// A get of peers map is sent for confirming
+    verifying: bool,
+}
+
 #[derive(Debug)]
 struct PeerContainer {
     peer: PeerId,
+    meta: PeerMeta,
     publish: RoomInfoPublish,
     sub_peers: IndexSet<PeerId>,
-    pub_tracks: IndexMap<RemoteTrackId, TrackName>,
+    /// Single tracks which are subscribed manually, see [`RoomMetadata::on_batch_track_subscriptions`]
+    sub_tracks: IndexSet<(PeerId, TrackName)>,
+    pub_tracks: IndexMap<RemoteTrackId, (TrackName, TrackMeta)>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Output<Endpoint> {
     Kv(dht_kv::Control),
     Endpoint(Vec<Endpoint>, ClusterEndpointEvent),
+    /// Answer of a tracks query, with query id
+    Tracks(u64, Vec<TrackInfo>),
     OnResourceEmpty,
 }
 
@@ -44,13 +92,22 @@ pub struct RoomMetadata<Endpoint: Debug + Hash + Eq> {
     tracks_map_subscribers: IndexSet<Endpoint>,
     //This is for storing list of endpoints subscribe manual a target track
     peers_tracks_subs: IndexMap<dht_kv::Map, IndexSet<Endpoint>>,
+    //This is for storing list of endpoints subscribe manual single tracks of a target peer, by private peer map
+    single_tracks_subs: IndexMap<dht_kv::Map, IndexMap<Endpoint, IndexSet<TrackName>>>,
     cluster_peers: IndexMap<dht_kv::Key, PeerInfo>,
+    /// Remote peers which are deleted from peers map but still present until the deadline
+    leaving_peers: IndexMap<dht_kv::Key, Instant>,
+    leave_grace: Duration,
     cluster_tracks: IndexMap<dht_kv::Key, TrackInfo>,
+    /// Tracks queries which are waiting for tracks map get result
+    tracks_queries: Vec<u64>,
+    kv_retry: KvRetryPolicy,
+    pending_sets: IndexMap<dht_kv::Key, PendingSet<Endpoint>>,
     queue: VecDeque<Output<Endpoint>>,
 }
 
 impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
-    pub fn new(room: ClusterRoomHash) -> Self {
+    pub fn new(room: ClusterRoomHash, leave_grace: Duration, kv_retry: KvRetryPolicy) -> Self {
         Self {
             room,
             peers_map: id_generator::peers_map(room),
@@ -59,8 +116,14 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
             peers_map_subscribers: Default::default(),
             tracks_map_subscribers: Default::default(),
             peers_tracks_subs: Default::default(),
+            single_tracks_subs: Default::default(),
             cluster_peers: Default::default(),
+            leaving_peers: Default::default(),
+            leave_grace,
             cluster_tracks: Default::default(),
+            tracks_queries: Default::default(),
+            kv_retry,
+            pending_sets: Default::default(),
             queue: Default::default(),
         }
     }
@@ -69,6 +132,210 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
         Some(self.peers.get(&endpoint)?.peer.clone())
     }
 
+    pub fn get_endpoint_from_peer(&self, peer: &PeerId) -> Option<Endpoint> {
+        self.peers.iter().find(|(_, container)| container.peer == *peer).map(|(endpoint, _)| *endpoint)
+    }
+
+    /// Room has joined endpoints in this node
+    pub fn has_peers(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    /// Notify room paused state to a joined endpoint, or to all local endpoints if it is None
+    pub fn on_room_paused(&mut self, endpoint: Option<Endpoint>, paused: bool) {
+        let endpoints = match endpoint {
+            Some(endpoint) => vec![endpoint],
+            None => self.peers.keys().copied().collect::<Vec<_>>(),
+        };
+        if endpoints.is_empty() {
+            return;
+        }
+        let event = if paused {
+            ClusterEndpointEvent::RoomPaused
+        } else {
+            ClusterEndpointEvent::RoomResumed
+        };
+        self.queue.push_back(Output::Endpoint(endpoints, event));
+    }
+
+    /// Event which is sent to a single endpoint
+    pub fn on_endpoint_event(&mut self, endpoint: Endpoint, event: ClusterEndpointEvent) {
+        self.queue.push_back(Output::Endpoint(vec![endpoint], event));
+    }
+
+    /// Room-wide event which is sent to all joined endpoints
+    pub fn on_room_event(&mut self, event: ClusterEndpointEvent) {
+        let endpoints = self.peers.keys().copied().collect::<Vec<_>>();
+        if !endpoints.is_empty() {
+            self.queue.push_back(Output::Endpoint(endpoints, event));
+        }
+    }
+
+    /// Fire PeerLeaved for remote peers which didn't come back inside leave grace
+    pub fn on_tick(&mut self, now: Instant) {
+        while let Some((peer_key, deadline)) = self.leaving_peers.first() {
+            if now < *deadline {
+                break;
+            }
+            let peer_key = *peer_key;
+            self.leaving_peers.shift_remove(&peer_key);
+            if let Some(info) = self.cluster_peers.swap_remove(&peer_key) {
+                self.fire_peer_leaved(info);
+            }
+        }
+        self.check_pending_sets(now);
+    }
+
+    /// Get of peers map after the timeout, so peer Set is confirmed without subscribing the map
+    fn check_pending_sets(&mut self, now: Instant) {
+        let mut need_get = false;
+        let mut unconfirmed = vec![];
+        for (peer_key, pending) in self.pending_sets.iter_mut() {
+            let deadline = *pending.deadline.get_or_insert(now + self.kv_retry.timeout * (1 << pending.attempt.min(16)));
+            if now < deadline {
+                continue;
+            }
+            if pending.verifying {
+                unconfirmed.push(*peer_key);
+            } else {
+                pending.verifying = true;
+                pending.deadline = Some(now + self.kv_retry.timeout);
+                need_get = true;
+            }
+        }
+        if need_get {
+            log::info!("[ClusterRoom {}] peer set not confirmed in time => get peers map", self.room);
+            self.queue.push_back(Output::Kv(dht_kv::Control::MapGet(self.peers_map)));
+        }
+        for peer_key in unconfirmed {
+            self.on_set_unconfirmed(peer_key);
+        }
+    }
+
+    /// Retry the peer Set, or fail the join after all retries
+    fn on_set_unconfirmed(&mut self, peer_key: dht_kv::Key) {
+        let pending = return_if_none!(self.pending_sets.get_mut(&peer_key));
+        let peer = return_if_none!(self.peers.get(&pending.endpoint));
+        if pending.attempt < self.kv_retry.retries {
+            pending.attempt += 1;
+            pending.deadline = None;
+            pending.verifying = false;
+            log::warn!(
+                "[ClusterRoom {}] peer ({}) set not confirmed => retry {}/{}",
+                self.room,
+                peer.peer,
+                pending.attempt,
+                self.kv_retry.retries
+            );
+            let info = PeerInfo {
+                peer: peer.peer.clone(),
+                meta: peer.meta.clone(),
+            };
+            self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.peers_map, MapControl::Set(peer_key, info.serialize()))));
+        } else {
+            let endpoint = pending.endpoint;
+            let peer = peer.peer.clone();
+            self.pending_sets.swap_remove(&peer_key);
+            log::warn!("[ClusterRoom {}] peer ({peer}) set not confirmed after {} retries => join failed", self.room, self.kv_retry.retries);
+            self.queue
+                .push_back(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::JoinRejected(peer, ClusterJoinRejectReason::StorageError)));
+        }
+    }
+
+    /// Query all tracks of room from the tracks map, it is read-only and does not subscribe the map.
+    /// Concurrent queries share a single map get.
+    pub fn on_query_tracks(&mut self, query: u64) {
+        self.tracks_queries.push(query);
+        if self.tracks_queries.len() == 1 {
+            log::info!("[ClusterRoom {}] query tracks => get tracks map", self.room);
+            self.queue.push_back(Output::Kv(dht_kv::Control::MapGet(self.tracks_map)));
+        }
+    }
+
+    /// Get result of a map, a failed get is answered as empty list because the map is not found when room don't have any track
+    pub fn on_kv_get_res<E: Debug>(&mut self, map: Map, res: Result<Vec<Vec<u8>>, E>) {
+        if self.peers_map == map {
+            self.on_peers_get_res(res);
+            return;
+        }
+        if self.tracks_map != map || self.tracks_queries.is_empty() {
+            return;
+        }
+        let tracks = match res {
+            Ok(values) => values.iter().filter_map(|data| TrackInfo::deserialize(data)).collect::<Vec<_>>(),
+            Err(e) => {
+                log::warn!("[ClusterRoom {}] get tracks map error {:?} => answer empty", self.room, e);
+                vec![]
+            }
+        };
+        log::info!("[ClusterRoom {}] got {} tracks => answer {} queries", self.room, tracks.len(), self.tracks_queries.len());
+        for query in std::mem::take(&mut self.tracks_queries) {
+            self.queue.push_back(Output::Tracks(query, tracks.clone()));
+        }
+    }
+
+    /// Peer Sets which are waiting for the get are confirmed if they are in the map, a failed get counts as not confirmed
+    fn on_peers_get_res<E: Debug>(&mut self, res: Result<Vec<Vec<u8>>, E>) {
+        let stored = match res {
+            Ok(values) => values
+                .iter()
+                .filter_map(|data| PeerInfo::deserialize(data))
+                .map(|info| id_generator::peers_key(&info.peer))
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                log::warn!("[ClusterRoom {}] get peers map error {:?}", self.room, e);
+                vec![]
+            }
+        };
+        let verifying = self.pending_sets.iter().filter(|(_, pending)| pending.verifying).map(|(peer_key, _)| *peer_key).collect::<Vec<_>>();
+        for peer_key in verifying {
+            if stored.contains(&peer_key) {
+                log::info!("[ClusterRoom {}] peer set confirmed by get", self.room);
+                self.pending_sets.swap_remove(&peer_key);
+            } else {
+                self.on_set_unconfirmed(peer_key);
+            }
+        }
+    }
+
+    /// Decide how a Join from the endpoint is handled. Only local endpoints are checked,
+    /// same peer id on other nodes can't be detected here because peers map is only available after subscribing.
+    pub fn join_kind(&self, endpoint: Endpoint, peer: &PeerId) -> JoinKind {
+        match self.peers.get(&endpoint) {
+            Some(container) if container.peer == *peer => JoinKind::Update,
+            Some(_) => JoinKind::Rejected,
+            None if self.peers.values().any(|container| container.peer == *peer) => JoinKind::Rejected,
+            None => JoinKind::Fresh,
+        }
+    }
+
+    /// Same endpoint joins again, only peer meta is updated, publish and subscribe scopes are kept from the first join.
+    /// Peers map is only Set again when meta changed, so subscribers don't receive a duplicated PeerJoined.
+    pub fn on_rejoin(&mut self, endpoint: Endpoint, meta: PeerMeta) {
+        let peer = return_if_none!(self.peers.get_mut(&endpoint));
+        if peer.meta == meta {
+            log::info!("[ClusterRoom {}] peer ({}) join again with same meta => ignore", self.room, peer.peer);
+            return;
+        }
+        log::info!("[ClusterRoom {}] peer ({}) join again => update meta", self.room, peer.peer);
+        peer.meta = meta.clone();
+        if peer.publish.peer {
+            let peer_key = id_generator::peers_key(&peer.peer);
+            let info = PeerInfo { peer: peer.peer.clone(), meta };
+            self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.peers_map, MapControl::Set(peer_key, info.serialize()))));
+        }
+    }
+
+    pub fn on_join_rejected(&mut self, endpoint: Endpoint, peer: PeerId, reason: ClusterJoinRejectReason) {
+        log::warn!("[ClusterRoom {}] reject join peer ({peer}) with reason {reason:?}", self.room);
+        self.queue.push_back(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::JoinRejected(peer, reason)));
+    }
+
+    pub fn on_join_pending(&mut self, owner: Endpoint, peer: PeerId) {
+        log::info!("[ClusterRoom {}] peer ({peer}) waiting for admit", self.room);
+        self.queue.push_back(Output::Endpoint(vec![owner], ClusterEndpointEvent::JoinPending(peer)));
+    }
+
     /// We put peer to list and register endpoint to peers and tracks list subscriber based on level
     pub fn on_join(&mut self, endpoint: Endpoint, peer: PeerId, meta: PeerMeta, publish: RoomInfoPublish, subscribe: RoomInfoSubscribe) {
         log::info!("[ClusterRoom {}] join peer ({peer})", self.room);
@@ -77,8 +344,10 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
             endpoint,
             PeerContainer {
                 peer: peer.clone(),
+                meta: meta.clone(),
                 publish: publish.clone(),
                 sub_peers: Default::default(),
+                sub_tracks: Default::default(),
                 pub_tracks: Default::default(),
             },
         );
@@ -87,7 +356,18 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
         // Let Set to peers_map if need need publisj.peer
         if publish.peer {
             self.queue
-                .push_back(Output::Kv(dht_kv::Control::MapCmd(self.peers_map, MapControl::Set(peer_key, PeerInfo { peer, meta }.serialize()))))
+                .push_back(Output::Kv(dht_kv::Control::MapCmd(self.peers_map, MapControl::Set(peer_key, PeerInfo { peer, meta }.serialize()))));
+            if !self.kv_retry.timeout.is_zero() {
+                self.pending_sets.insert(
+                    peer_key,
+                    PendingSet {
+                        endpoint,
+                        attempt: 0,
+                        deadline: None,
+                        verifying: false,
+                    },
+                );
+            }
         }
         // Let Sub to peers_map if need need subscribe.peers
         if subscribe.peers {
@@ -133,6 +413,7 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
         let peer = return_if_none!(self.peers.swap_remove(&endpoint));
         log::info!("[ClusterRoom {}] leave peer {}", self.room, peer.peer);
         let peer_key = id_generator::peers_key(&peer.peer);
+        self.pending_sets.swap_remove(&peer_key);
         // If remain remote tracks, must to delete from list.
         if peer.publish.peer {
             self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.peers_map, MapControl::Del(peer_key))))
@@ -140,7 +421,7 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
 
         // If remain remote tracks, must to delete from list.
         let peer_map = id_generator::peer_map(self.room, &peer.peer);
-        for (_, track) in peer.pub_tracks.into_iter() {
+        for (_, (track, _)) in peer.pub_tracks.into_iter() {
             let track_key = id_generator::tracks_key(&peer.peer, &track);
             self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.tracks_map, MapControl::Del(track_key))));
             self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(peer_map, MapControl::Del(track_key))));
@@ -163,16 +444,26 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
             subs.swap_remove(&endpoint);
             if subs.is_empty() {
                 self.peers_tracks_subs.swap_remove(&target_peer_map);
+                if !self.single_tracks_subs.contains_key(&target_peer_map) {
+                    self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(target_peer_map, MapControl::Unsub)));
+                }
+            }
+        }
+
+        for (target, track) in peer.sub_tracks.into_iter() {
+            let target_peer_map = id_generator::peer_map(self.room, &target);
+            self.remove_single_track_sub(endpoint, target_peer_map, &track);
+            if !self.is_peer_map_used(&target_peer_map) {
                 self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(target_peer_map, MapControl::Unsub)));
             }
         }
     }
 
     pub fn on_subscribe_peer(&mut self, endpoint: Endpoint, target: PeerId) {
-        let peer = self.peers.get_mut(&endpoint).expect("Should have peer");
         let target_peer_map = id_generator::peer_map(self.room, &target);
+        let need_sub = !self.is_peer_map_used(&target_peer_map);
+        let peer = self.peers.get_mut(&endpoint).expect("Should have peer");
         let subs = self.peers_tracks_subs.entry(target_peer_map).or_default();
-        let need_sub = subs.is_empty();
         subs.insert(endpoint);
         peer.sub_peers.insert(target);
 
@@ -189,8 +480,98 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
         peer.sub_peers.swap_remove(&target);
         if subs.is_empty() {
             self.peers_tracks_subs.swap_remove(&target_peer_map);
-            self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(target_peer_map, MapControl::Unsub)));
+            if !self.single_tracks_subs.contains_key(&target_peer_map) {
+                self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(target_peer_map, MapControl::Unsub)));
+            }
+        }
+    }
+
+    /// Reconcile single track subscriptions of the endpoint in one step, e.g. when the client changes its layout.
+    /// Unsubscribes are applied before subscribes, and private peer maps are only Sub or Unsub by the net change of the
+    /// whole batch, so moving tracks of a peer between layouts doesn't cause map churn.
+    /// A track which is already known from an active peer map is restored to the endpoint immediately.
+    pub fn on_batch_track_subscriptions(&mut self, endpoint: Endpoint, subscribe: Vec<(PeerId, TrackName)>, unsubscribe: Vec<(PeerId, TrackName)>) {
+        if !self.peers.contains_key(&endpoint) {
+            log::warn!("[ClusterRoom {}] batch track subscriptions from unknown endpoint {:?}", self.room, endpoint);
+            return;
+        }
+        let mut used_before = IndexMap::new();
+        for (target, _) in subscribe.iter().chain(unsubscribe.iter()) {
+            let target_peer_map = id_generator::peer_map(self.room, target);
+            let used = self.is_peer_map_used(&target_peer_map);
+            used_before.entry(target_peer_map).or_insert(used);
         }
+        log::info!(
+            "[ClusterRoom {}] endpoint {:?} batch subscribe {} tracks, unsubscribe {} tracks",
+            self.room,
+            endpoint,
+            subscribe.len(),
+            unsubscribe.len()
+        );
+
+        for (target, track) in unsubscribe {
+            let target_peer_map = id_generator::peer_map(self.room, &target);
+            if let Some(peer) = self.peers.get_mut(&endpoint) {
+                peer.sub_tracks.swap_remove(&(target, track.clone()));
+            }
+            self.remove_single_track_sub(endpoint, target_peer_map, &track);
+        }
+
+        for (target, track) in subscribe {
+            let target_peer_map = id_generator::peer_map(self.room, &target);
+            let added = self.single_tracks_subs.entry(target_peer_map).or_default().entry(endpoint).or_default().insert(track.clone());
+            if let Some(peer) = self.peers.get_mut(&endpoint) {
+                peer.sub_tracks.insert((target.clone(), track.clone()));
+            }
+            // an active map doesn't replay its entries, so the track is restored from cache
+            if added && used_before.get(&target_peer_map).copied().unwrap_or(false) {
+                if let Some(info) = self.cluster_tracks.get(&id_generator::tracks_key(&target, &track)) {
+                    self.queue.push_back(Output::Endpoint(vec![endpoint], Self::track_set_event(None, info.clone())));
+                }
+            }
+        }
+
+        for (target_peer_map, used) in used_before {
+            match (used, self.is_peer_map_used(&target_peer_map)) {
+                (false, true) => self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(target_peer_map, MapControl::Sub))),
+                (true, false) => self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(target_peer_map, MapControl::Unsub))),
+                _ => {}
+            }
+        }
+    }
+
+    /// Single tracks which are subscribed by the endpoint
+    pub fn track_subscriptions(&self, endpoint: Endpoint) -> Vec<(PeerId, TrackName)> {
+        self.peers.get(&endpoint).map(|peer| peer.sub_tracks.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    fn remove_single_track_sub(&mut self, endpoint: Endpoint, target_peer_map: Map, track: &TrackName) {
+        let subs = return_if_none!(self.single_tracks_subs.get_mut(&target_peer_map));
+        if let Some(tracks) = subs.get_mut(&endpoint) {
+            tracks.swap_remove(track);
+            if tracks.is_empty() {
+                subs.swap_remove(&endpoint);
+            }
+        }
+        if subs.is_empty() {
+            self.single_tracks_subs.swap_remove(&target_peer_map);
+        }
+    }
+
+    /// Private peer map is subscribed when an endpoint subscribes the peer or any single track of it
+    fn is_peer_map_used(&self, target_peer_map: &Map) -> bool {
+        self.peers_tracks_subs.contains_key(target_peer_map) || self.single_tracks_subs.contains_key(target_peer_map)
+    }
+
+    /// Endpoints which subscribe the peer of the private map, or only the track
+    fn peer_map_subscribers(&self, peer_map: &Map, track: &TrackName) -> Vec<Endpoint> {
+        let mut subscribers = self.peers_tracks_subs.get(peer_map).map(|subs| subs.iter().copied().collect::<Vec<_>>()).unwrap_or_default();
+        for (endpoint, tracks) in self.single_tracks_subs.get(peer_map).into_iter().flatten() {
+            if tracks.contains(track) && !subscribers.contains(endpoint) {
+                subscribers.push(*endpoint);
+            }
+        }
+        subscribers
     }
 
     pub fn on_track_publish(&mut self, endpoint: Endpoint, track_id: RemoteTrackId, track: TrackName, meta: TrackMeta) {
@@ -199,10 +580,10 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
             let info = TrackInfo {
                 peer: peer.peer.clone(),
                 track: track.clone(),
-                meta,
+                meta: meta.clone(),
             };
             let track_key = id_generator::tracks_key(&peer.peer, &track);
-            peer.pub_tracks.insert(track_id, track);
+            peer.pub_tracks.insert(track_id, (track, meta));
 
             let peer_map = id_generator::peer_map(self.room, &peer.peer);
             self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.tracks_map, MapControl::Set(track_key, info.serialize()))));
@@ -212,7 +593,7 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
 
     pub fn on_track_unpublish(&mut self, endpoint: Endpoint, track_id: RemoteTrackId) {
         let peer = return_if_none!(self.peers.get_mut(&endpoint));
-        let track = return_if_none!(peer.pub_tracks.swap_remove(&track_id));
+        let (track, _) = return_if_none!(peer.pub_tracks.swap_remove(&track_id));
         let track_key = id_generator::tracks_key(&peer.peer, &track);
 
         let peer_map = id_generator::peer_map(self.room, &peer.peer);
@@ -221,11 +602,31 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
         self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(peer_map, MapControl::Del(track_key))));
     }
 
-    pub fn on_kv_event(&mut self, map: Map, event: MapEvent) {
+    /// Update muted flag in published track info, subscribers will receive TrackMuted instead of TrackStarted
+    pub fn on_track_muted(&mut self, endpoint: Endpoint, track: TrackName, muted: bool) {
+        let peer = return_if_none!(self.peers.get_mut(&endpoint));
+        let (_, (_, meta)) = return_if_none!(peer.pub_tracks.iter_mut().find(|(_, (name, _))| *name == track));
+        if meta.muted == muted {
+            return;
+        }
+        log::info!("[ClusterRoom {}] peer {} set track {track} muted {muted}", self.room, peer.peer);
+        meta.muted = muted;
+        let info = TrackInfo {
+            peer: peer.peer.clone(),
+            track: track.clone(),
+            meta: meta.clone(),
+        };
+        let track_key = id_generator::tracks_key(&peer.peer, &track);
+        let peer_map = id_generator::peer_map(self.room, &peer.peer);
+        self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.tracks_map, MapControl::Set(track_key, info.serialize()))));
+        self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(peer_map, MapControl::Set(track_key, info.serialize()))));
+    }
+
+    pub fn on_kv_event(&mut self, now: Instant, map: Map, event: MapEvent) {
         if self.peers_map == map {
             match event {
-                dht_kv::MapEvent::OnSet(peer_key, _source, data) => self.on_peers_kv_event(peer_key, Some(data)),
-                dht_kv::MapEvent::OnDel(peer_key, _source) => self.on_peers_kv_event(peer_key, None),
+                dht_kv::MapEvent::OnSet(peer_key, _source, data) => self.on_peers_kv_event(now, peer_key, Some(data)),
+                dht_kv::MapEvent::OnDel(peer_key, _source) => self.on_peers_kv_event(now, peer_key, None),
                 dht_kv::MapEvent::OnRelaySelected(_) => {}
             }
         } else if self.tracks_map == map {
@@ -234,7 +635,7 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
                 dht_kv::MapEvent::OnDel(track_key, _source) => self.on_tracks_kv_event(track_key, None),
                 dht_kv::MapEvent::OnRelaySelected(_) => {}
             }
-        } else if self.peers_tracks_subs.contains_key(&map) {
+        } else if self.is_peer_map_used(&map) {
             match event {
                 dht_kv::MapEvent::OnSet(track_key, _source, data) => self.on_peers_tracks_kv_event(map, track_key, Some(data)),
                 dht_kv::MapEvent::OnDel(track_key, _source) => self.on_peers_tracks_kv_event(map, track_key, None),
@@ -243,7 +644,7 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
         }
     }
 
-    fn on_peers_kv_event(&mut self, peer_key: dht_kv::Key, data: Option<Vec<u8>>) {
+    fn on_peers_kv_event(&mut self, now: Instant, peer_key: dht_kv::Key, data: Option<Vec<u8>>) {
         let info = if let Some(data) = data {
             Some(return_if_none!(PeerInfo::deserialize(&data)))
         } else {
@@ -252,17 +653,32 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
 
         let subscribers = self.peers_map_subscribers.iter().copied().collect::<Vec<_>>();
         if let Some(info) = info {
+            if self.pending_sets.swap_remove(&peer_key).is_some() {
+                log::info!("[ClusterRoom {}] peer ({}) set confirmed by map event", self.room, info.peer);
+            }
+            if self.leaving_peers.shift_remove(&peer_key).is_some() && self.cluster_peers.get(&peer_key).is_some_and(|pre| pre.peer == info.peer && pre.meta == info.meta) {
+                log::info!("[ClusterRoom {}] cluster: peer ({}) came back inside leave grace => no event", self.room, info.peer);
+                return;
+            }
             log::info!("[ClusterRoom {}] cluster: peer {} joined => fire event to {:?}", self.room, info.peer, subscribers);
             self.cluster_peers.insert(peer_key, info.clone());
             if !subscribers.is_empty() {
                 self.queue.push_back(Output::Endpoint(subscribers, ClusterEndpointEvent::PeerJoined(info.peer, info.meta)));
             }
-        } else {
+        } else if self.leave_grace.is_zero() {
             let info = return_if_none!(self.cluster_peers.swap_remove(&peer_key));
-            log::info!("[ClusterRoom {}] cluster: peer ({}) leaved => fire event to {:?}", self.room, info.peer, subscribers);
-            if !subscribers.is_empty() {
-                self.queue.push_back(Output::Endpoint(subscribers, ClusterEndpointEvent::PeerLeaved(info.peer, info.meta)));
-            }
+            self.fire_peer_leaved(info);
+        } else if let Some(info) = self.cluster_peers.get(&peer_key) {
+            log::info!("[ClusterRoom {}] cluster: peer ({}) deleted => keep present for {:?}", self.room, info.peer, self.leave_grace);
+            self.leaving_peers.entry(peer_key).or_insert(now + self.leave_grace);
+        }
+    }
+
+    fn fire_peer_leaved(&mut self, info: PeerInfo) {
+        let subscribers = self.peers_map_subscribers.iter().copied().collect::<Vec<_>>();
+        log::info!("[ClusterRoom {}] cluster: peer ({}) leaved => fire event to {:?}", self.room, info.peer, subscribers);
+        if !subscribers.is_empty() {
+            self.queue.push_back(Output::Endpoint(subscribers, ClusterEndpointEvent::PeerLeaved(info.peer, info.meta)));
         }
     }
 
@@ -282,10 +698,9 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
                 info.track,
                 subscribers
             );
-            self.cluster_tracks.insert(track, info.clone());
+            let pre = self.cluster_tracks.insert(track, info.clone());
             if !subscribers.is_empty() {
-                self.queue
-                    .push_back(Output::Endpoint(subscribers, ClusterEndpointEvent::TrackStarted(info.peer, info.track, info.meta)));
+                self.queue.push_back(Output::Endpoint(subscribers, Self::track_set_event(pre, info)));
             }
         } else {
             let info = return_if_none!(self.cluster_tracks.swap_remove(&track));
@@ -310,8 +725,8 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
             None
         };
 
-        let subscribers = return_if_none!(self.peers_tracks_subs.get(&peer_map)).iter().copied().collect::<Vec<_>>();
         if let Some(info) = info {
+            let subscribers = self.peer_map_subscribers(&peer_map, &info.track);
             log::info!(
                 "[ClusterRoom {}] cluster: peer ({}) started track {}) => fire event to {:?}",
                 self.room,
@@ -319,11 +734,13 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
                 info.track,
                 subscribers
             );
-            self.cluster_tracks.insert(track, info.clone());
-            self.queue
-                .push_back(Output::Endpoint(subscribers, ClusterEndpointEvent::TrackStarted(info.peer, info.track, info.meta)));
+            let pre = self.cluster_tracks.insert(track, info.clone());
+            if !subscribers.is_empty() {
+                self.queue.push_back(Output::Endpoint(subscribers, Self::track_set_event(pre, info)));
+            }
         } else {
             let info = return_if_none!(self.cluster_tracks.swap_remove(&track));
+            let subscribers = self.peer_map_subscribers(&peer_map, &info.track);
             log::info!(
                 "[ClusterRoom {}] cluster: peer ({}) stopped track {}) => fire event to {:?}",
                 self.room,
@@ -331,8 +748,18 @@ impl<Endpoint: Debug + Hash + Eq + Copy> RoomMetadata<Endpoint> {
                 info.track,
                 subscribers
             );
-            self.queue
-                .push_back(Output::Endpoint(subscribers, ClusterEndpointEvent::TrackStopped(info.peer, info.track, info.meta)));
+            if !subscribers.is_empty() {
+                self.queue
+                    .push_back(Output::Endpoint(subscribers, ClusterEndpointEvent::TrackStopped(info.peer, info.track, info.meta)));
+            }
+        }
+    }
+
+    /// A track set for already known track with only muted flag changed is a mute toggle, not a new track
+    fn track_set_event(pre: Option<TrackInfo>, info: TrackInfo) -> ClusterEndpointEvent {
+        match pre {
+            Some(pre) if pre.meta.muted != info.meta.muted => ClusterEndpointEvent::TrackMuted(info.peer, info.track, info.meta.muted),
+            _ => ClusterEndpointEvent::TrackStarted(info.peer, info.track, info.meta),
         }
     }
 }
@@ -341,7 +768,13 @@ impl<Endpoint: Debug + Hash + Eq> TaskSwitcherChild<Output<Endpoint>> for RoomMe
     type Time = ();
 
     fn is_empty(&self) -> bool {
-        self.queue.is_empty() && self.peers.is_empty() && self.peers_map_subscribers.is_empty() && self.tracks_map_subscribers.is_empty() && self.peers_tracks_subs.is_empty()
+        self.queue.is_empty()
+            && self.peers.is_empty()
+            && self.peers_map_subscribers.is_empty()
+            && self.tracks_map_subscribers.is_empty()
+            && self.peers_tracks_subs.is_empty()
+            && self.single_tracks_subs.is_empty()
+            && self.tracks_queries.is_empty()
     }
 
     fn empty_event(&self) -> Output<Endpoint> {
@@ -361,27 +794,31 @@ impl<Endpoint: Debug + Hash + Eq> Drop for RoomMetadata<Endpoint> {
         assert_eq!(self.peers_map_subscribers.len(), 0, "Metadata Peers subscriber not empty {:?}", self.peers_map_subscribers);
         assert_eq!(self.tracks_map_subscribers.len(), 0, "Metadata Tracks subscriber not empty {:?}", self.tracks_map_subscribers);
         assert_eq!(self.peers_tracks_subs.len(), 0, "Metadata Peers tracks subs not empty {:?}", self.peers_tracks_subs);
+        assert_eq!(self.single_tracks_subs.len(), 0, "Metadata single tracks subs not empty {:?}", self.single_tracks_subs);
+        assert_eq!(self.tracks_queries.len(), 0, "Metadata Tracks queries not empty {:?}", self.tracks_queries);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, Instant};
+
     use atm0s_sdn::features::dht_kv::{Control, MapControl, MapEvent};
-    use media_server_protocol::endpoint::{PeerId, PeerInfo, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, TrackInfo, TrackName};
+    use media_server_protocol::endpoint::{PeerId, PeerInfo, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, TrackInfo, TrackMeta, TrackName};
     use sans_io_runtime::TaskSwitcherChild;
 
     use crate::{
-        cluster::{id_generator, ClusterEndpointEvent, ClusterRoomHash},
+        cluster::{id_generator, ClusterEndpointEvent, ClusterJoinRejectReason, ClusterRoomHash},
         transport::RemoteTrackId,
     };
 
-    use super::{Output, RoomMetadata};
+    use super::{KvRetryPolicy, Output, RoomMetadata};
 
     /// Test correct get peer info
     #[test_log::test]
     fn correct_get_peer() {
         let room: ClusterRoomHash = 1.into();
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
         let peer_id: PeerId = "peer1".to_string().into();
         let peer_meta = PeerMeta { metadata: None, extra_data: None };
         let endpoint = 1;
@@ -408,7 +845,7 @@ mod tests {
         let room: ClusterRoomHash = 1.into();
         let peers_map = id_generator::peers_map(room);
         let tracks_map = id_generator::tracks_map(room);
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
         let peer_id: PeerId = "peer1".to_string().into();
         let peer_meta = PeerMeta { metadata: None, extra_data: None };
         let peer_info = PeerInfo::new(peer_id.clone(), peer_meta.clone());
@@ -426,7 +863,7 @@ mod tests {
         assert_eq!(room_meta.pop_output(()), None);
 
         // should handle incoming event with only peer and reject track
-        room_meta.on_kv_event(peers_map, MapEvent::OnSet(peer_key, 0, peer_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), peers_map, MapEvent::OnSet(peer_key, 0, peer_info.serialize()));
         assert_eq!(
             room_meta.pop_output(()),
             Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::PeerJoined(peer_id.clone(), peer_meta.clone())))
@@ -436,14 +873,14 @@ mod tests {
         let track_name: TrackName = "audio_main".to_string().into();
         let track_info = TrackInfo::simple_audio(peer_id.clone());
         let track_key = id_generator::tracks_key(&peer_id, &track_name);
-        room_meta.on_kv_event(tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
         assert_eq!(room_meta.pop_output(()), None);
 
         // should only handle remove peer event, reject track
-        room_meta.on_kv_event(tracks_map, MapEvent::OnDel(track_key, 0));
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnDel(track_key, 0));
         assert_eq!(room_meta.pop_output(()), None);
 
-        room_meta.on_kv_event(peers_map, MapEvent::OnDel(peer_key, 0));
+        room_meta.on_kv_event(Instant::now(), peers_map, MapEvent::OnDel(peer_key, 0));
         assert_eq!(
             room_meta.pop_output(()),
             Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::PeerLeaved(peer_id.clone(), peer_info.meta)))
@@ -462,13 +899,13 @@ mod tests {
     fn join_sub_peer_only_should_restore_old_peers() {
         let room: ClusterRoomHash = 1.into();
         let peers_map = id_generator::peers_map(room);
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
 
         let peer2: PeerId = "peer2".to_string().into();
         let peer2_key = id_generator::peers_key(&peer2);
         let peer2_info = PeerInfo::new(peer2, PeerMeta { metadata: None, extra_data: None });
 
-        room_meta.on_kv_event(peers_map, MapEvent::OnSet(peer2_key, 0, peer2_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), peers_map, MapEvent::OnSet(peer2_key, 0, peer2_info.serialize()));
         assert_eq!(room_meta.pop_output(()), None);
 
         let endpoint = 1;
@@ -495,12 +932,65 @@ mod tests {
     }
 
     //TODO Test join as track only => should subscribe only tracks, fire only track events
+    /// Remote peer which reconnects inside leave grace should not cause PeerLeaved and PeerJoined churn,
+    /// longer outage should fire PeerLeaved after the grace
+    #[test_log::test]
+    fn peer_leave_grace_coalesce_reconnect() {
+        let room: ClusterRoomHash = 1.into();
+        let peers_map = id_generator::peers_map(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::from_secs(5), KvRetryPolicy::default());
+        let endpoint = 1;
+        room_meta.on_join(
+            endpoint,
+            "peer1".to_string().into(),
+            PeerMeta { metadata: None, extra_data: None },
+            RoomInfoPublish { peer: false, tracks: false },
+            RoomInfoSubscribe { peers: true, tracks: false },
+        );
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peers_map, MapControl::Sub))));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        let peer2: PeerId = "peer2".to_string().into();
+        let peer2_key = id_generator::peers_key(&peer2);
+        let peer2_info = PeerInfo::new(peer2.clone(), PeerMeta { metadata: None, extra_data: None });
+        let t0 = Instant::now();
+        room_meta.on_kv_event(t0, peers_map, MapEvent::OnSet(peer2_key, 0, peer2_info.serialize()));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::PeerJoined(peer2.clone(), peer2_info.meta.clone())))
+        );
+
+        // disconnect and reconnect inside grace => no event
+        room_meta.on_kv_event(t0, peers_map, MapEvent::OnDel(peer2_key, 0));
+        room_meta.on_tick(t0 + Duration::from_secs(2));
+        room_meta.on_kv_event(t0 + Duration::from_secs(2), peers_map, MapEvent::OnSet(peer2_key, 0, peer2_info.serialize()));
+        room_meta.on_tick(t0 + Duration::from_secs(10));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // longer outage => PeerLeaved after grace
+        let t1 = t0 + Duration::from_secs(20);
+        room_meta.on_kv_event(t1, peers_map, MapEvent::OnDel(peer2_key, 0));
+        room_meta.on_tick(t1 + Duration::from_secs(4));
+        assert_eq!(room_meta.pop_output(()), None);
+        room_meta.on_tick(t1 + Duration::from_secs(5));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::PeerLeaved(peer2, peer2_info.meta)))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        room_meta.on_leave(endpoint);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peers_map, MapControl::Unsub))));
+        assert_eq!(room_meta.pop_output(()), None);
+        assert!(room_meta.is_empty());
+    }
+
     #[test_log::test]
     fn join_track_only() {
         let room: ClusterRoomHash = 1.into();
         let peers_map = id_generator::peers_map(room);
         let tracks_map = id_generator::tracks_map(room);
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
         let peer_id: PeerId = "peer1".to_string().into();
         let peer_meta = PeerMeta { metadata: None, extra_data: None };
         let peer_info = PeerInfo::new(peer_id.clone(), peer_meta.clone());
@@ -517,13 +1007,13 @@ mod tests {
         assert_eq!(room_meta.pop_output(()), None);
 
         // should handle incoming event with only track and reject peer
-        room_meta.on_kv_event(peers_map, MapEvent::OnSet(peer_key, 0, peer_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), peers_map, MapEvent::OnSet(peer_key, 0, peer_info.serialize()));
         assert_eq!(room_meta.pop_output(()), None);
 
         let track_name: TrackName = "audio_main".to_string().into();
         let track_info = TrackInfo::simple_audio(peer_id.clone());
         let track_key = id_generator::tracks_key(&peer_id, &track_name);
-        room_meta.on_kv_event(tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
         assert_eq!(
             room_meta.pop_output(()),
             Some(Output::Endpoint(
@@ -534,7 +1024,7 @@ mod tests {
         assert_eq!(room_meta.pop_output(()), None);
 
         // should only handle remove track event, reject peer
-        room_meta.on_kv_event(tracks_map, MapEvent::OnDel(track_key, 0));
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnDel(track_key, 0));
         assert_eq!(
             room_meta.pop_output(()),
             Some(Output::Endpoint(
@@ -544,7 +1034,7 @@ mod tests {
         );
         assert_eq!(room_meta.pop_output(()), None);
 
-        room_meta.on_kv_event(peers_map, MapEvent::OnDel(peer_key, 0));
+        room_meta.on_kv_event(Instant::now(), peers_map, MapEvent::OnDel(peer_key, 0));
         assert_eq!(room_meta.pop_output(()), None);
 
         // peer leave should send unsub
@@ -559,14 +1049,14 @@ mod tests {
     fn join_sub_track_only_should_restore_old_tracks() {
         let room: ClusterRoomHash = 1.into();
         let tracks_map = id_generator::tracks_map(room);
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
 
         let peer2: PeerId = "peer2".to_string().into();
         let track_name: TrackName = "audio_main".to_string().into();
         let track_key = id_generator::tracks_key(&peer2, &track_name);
         let track_info = TrackInfo::simple_audio(peer2);
 
-        room_meta.on_kv_event(tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
         assert_eq!(room_meta.pop_output(()), None);
 
         let endpoint = 1;
@@ -601,7 +1091,7 @@ mod tests {
         let room: ClusterRoomHash = 1.into();
         let peers_map = id_generator::peers_map(room);
         let tracks_map = id_generator::tracks_map(room);
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
         let peer_id: PeerId = "peer1".to_string().into();
         let peer_meta = PeerMeta { metadata: None, extra_data: None };
         let peer_info = PeerInfo::new(peer_id.clone(), peer_meta.clone());
@@ -617,20 +1107,20 @@ mod tests {
         assert_eq!(room_meta.pop_output(()), None);
 
         // should handle incoming event with only track and reject peer
-        room_meta.on_kv_event(peers_map, MapEvent::OnSet(peer_key, 0, peer_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), peers_map, MapEvent::OnSet(peer_key, 0, peer_info.serialize()));
         assert_eq!(room_meta.pop_output(()), None);
 
         let track_name: TrackName = "audio_main".to_string().into();
         let track_info = TrackInfo::simple_audio(peer_id.clone());
         let track_key = id_generator::tracks_key(&peer_id, &track_name);
-        room_meta.on_kv_event(tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
         assert_eq!(room_meta.pop_output(()), None);
 
         // should only handle remove track event, reject peer
-        room_meta.on_kv_event(tracks_map, MapEvent::OnDel(track_key, 0));
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnDel(track_key, 0));
         assert_eq!(room_meta.pop_output(()), None);
 
-        room_meta.on_kv_event(peers_map, MapEvent::OnDel(peer_key, 0));
+        room_meta.on_kv_event(Instant::now(), peers_map, MapEvent::OnDel(peer_key, 0));
         assert_eq!(room_meta.pop_output(()), None);
 
         // peer leave should send unsub
@@ -643,7 +1133,7 @@ mod tests {
     #[test_log::test]
     fn join_manual_with_subscribe() {
         let room: ClusterRoomHash = 1.into();
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
         let peer_id: PeerId = "peer1".to_string().into();
         let peer_meta = PeerMeta { metadata: None, extra_data: None };
         let endpoint = 1;
@@ -666,7 +1156,7 @@ mod tests {
         let track_name: TrackName = "audio_main".to_string().into();
         let track_info = TrackInfo::simple_audio(peer_id.clone());
         let track_key = id_generator::tracks_key(&peer2, &track_name);
-        room_meta.on_kv_event(peer2_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
+        room_meta.on_kv_event(Instant::now(), peer2_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
         assert_eq!(
             room_meta.pop_output(()),
             Some(Output::Endpoint(
@@ -677,7 +1167,7 @@ mod tests {
         assert_eq!(room_meta.pop_output(()), None);
 
         // should only handle remove track event, reject peer
-        room_meta.on_kv_event(peer2_map, MapEvent::OnDel(track_key, 0));
+        room_meta.on_kv_event(Instant::now(), peer2_map, MapEvent::OnDel(track_key, 0));
         assert_eq!(
             room_meta.pop_output(()),
             Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::TrackStopped(peer2.clone(), track_name.clone(), track_info.meta)))
@@ -695,12 +1185,80 @@ mod tests {
         assert!(room_meta.is_empty());
     }
 
+    /// Batch subscriptions only Sub or Unsub private peer maps by the net change, and events are only fired for
+    /// subscribed tracks
+    #[test]
+    fn batch_track_subscriptions() {
+        let room: ClusterRoomHash = 1.into();
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
+        let peer_meta = PeerMeta { metadata: None, extra_data: None };
+        let endpoint = 1;
+        room_meta.on_join(
+            endpoint,
+            "peer1".to_string().into(),
+            peer_meta,
+            RoomInfoPublish { peer: false, tracks: false },
+            RoomInfoSubscribe { peers: false, tracks: false },
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        let peer2: PeerId = "peer2".to_string().into();
+        let peer3: PeerId = "peer3".to_string().into();
+        let peer2_map = id_generator::peer_map(room, &peer2);
+        let peer3_map = id_generator::peer_map(room, &peer3);
+        let audio: TrackName = "audio_main".to_string().into();
+        let video: TrackName = "video_main".to_string().into();
+
+        room_meta.on_batch_track_subscriptions(endpoint, vec![(peer2.clone(), audio.clone())], vec![]);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peer2_map, MapControl::Sub))));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        let audio_info = TrackInfo::simple_audio(peer2.clone());
+        room_meta.on_kv_event(Instant::now(), peer2_map, MapEvent::OnSet(id_generator::tracks_key(&peer2, &audio), 0, audio_info.serialize()));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(
+                vec![endpoint],
+                ClusterEndpointEvent::TrackStarted(peer2.clone(), audio.clone(), audio_info.meta.clone())
+            ))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // subscribe two and unsubscribe one, peer2 map is kept
+        room_meta.on_batch_track_subscriptions(endpoint, vec![(peer2.clone(), video.clone()), (peer3.clone(), audio.clone())], vec![(peer2.clone(), audio.clone())]);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peer3_map, MapControl::Sub))));
+        assert_eq!(room_meta.pop_output(()), None);
+        assert_eq!(room_meta.track_subscriptions(endpoint), vec![(peer2.clone(), video.clone()), (peer3.clone(), audio.clone())]);
+
+        // unsubscribed track doesn't fire event, subscribed track does
+        room_meta.on_kv_event(Instant::now(), peer2_map, MapEvent::OnDel(id_generator::tracks_key(&peer2, &audio), 0));
+        assert_eq!(room_meta.pop_output(()), None);
+        let video_info = TrackInfo {
+            peer: peer2.clone(),
+            track: video.clone(),
+            meta: TrackMeta::default_video(),
+        };
+        room_meta.on_kv_event(Instant::now(), peer2_map, MapEvent::OnSet(id_generator::tracks_key(&peer2, &video), 0, video_info.serialize()));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::TrackStarted(peer2.clone(), video.clone(), video_info.meta)))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // leave should unsub all private maps
+        room_meta.on_leave(endpoint);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peer2_map, MapControl::Unsub))));
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peer3_map, MapControl::Unsub))));
+        assert_eq!(room_meta.pop_output(()), None);
+        assert!(room_meta.is_empty());
+    }
+
     //TODO Test track publish => should set key to both single peer map and tracks map
     #[test_log::test]
     fn track_publish_enable() {
         let room: ClusterRoomHash = 1.into();
         let tracks_map = id_generator::tracks_map(room);
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
 
         let endpoint = 1;
         let peer_id: PeerId = "peer1".to_string().into();
@@ -742,11 +1300,120 @@ mod tests {
         assert!(room_meta.is_empty());
     }
 
+    //Test mute toggle => subscribers receive TrackMuted and track is still alive
+    #[test_log::test]
+    fn track_muted_should_fire_mute_event() {
+        let room: ClusterRoomHash = 1.into();
+        let tracks_map = id_generator::tracks_map(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
+
+        let publisher = 1;
+        let subscriber = 2;
+        let peer_id: PeerId = "peer1".to_string().into();
+        let peer_meta = PeerMeta { metadata: None, extra_data: None };
+        room_meta.on_join(
+            publisher,
+            peer_id.clone(),
+            peer_meta.clone(),
+            RoomInfoPublish { peer: false, tracks: true },
+            RoomInfoSubscribe { peers: false, tracks: false },
+        );
+        room_meta.on_join(
+            subscriber,
+            "peer2".to_string().into(),
+            peer_meta.clone(),
+            RoomInfoPublish { peer: false, tracks: false },
+            RoomInfoSubscribe { peers: false, tracks: true },
+        );
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(tracks_map, MapControl::Sub))));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        let track_id: RemoteTrackId = RemoteTrackId::from(1);
+        let track_name: TrackName = "audio_main".to_string().into();
+        let track_info = TrackInfo::simple_audio(peer_id.clone());
+        let mut muted_info = track_info.clone();
+        muted_info.meta.muted = true;
+        let peer_map = id_generator::peer_map(room, &peer_id);
+        let track_key = id_generator::tracks_key(&peer_id, &track_name);
+        room_meta.on_track_publish(publisher, track_id, track_name.clone(), track_info.meta.clone());
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Kv(Control::MapCmd(tracks_map, MapControl::Set(track_key, track_info.serialize()))))
+        );
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Kv(Control::MapCmd(peer_map, MapControl::Set(track_key, track_info.serialize()))))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(
+                vec![subscriber],
+                ClusterEndpointEvent::TrackStarted(peer_id.clone(), track_name.clone(), track_info.meta.clone())
+            ))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // mute should update track info in kv
+        room_meta.on_track_muted(publisher, track_name.clone(), true);
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Kv(Control::MapCmd(tracks_map, MapControl::Set(track_key, muted_info.serialize()))))
+        );
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Kv(Control::MapCmd(peer_map, MapControl::Set(track_key, muted_info.serialize()))))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // same state should not generate anything
+        room_meta.on_track_muted(publisher, track_name.clone(), true);
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // subscriber get mute event instead of track started or stopped
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnSet(track_key, 0, muted_info.serialize()));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(vec![subscriber], ClusterEndpointEvent::TrackMuted(peer_id.clone(), track_name.clone(), true)))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnSet(track_key, 0, track_info.serialize()));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(vec![subscriber], ClusterEndpointEvent::TrackMuted(peer_id.clone(), track_name.clone(), false)))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // track is still alive, so it can be stopped normally
+        room_meta.on_kv_event(Instant::now(), tracks_map, MapEvent::OnDel(track_key, 0));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(
+                vec![subscriber],
+                ClusterEndpointEvent::TrackStopped(peer_id.clone(), track_name.clone(), track_info.meta)
+            ))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        room_meta.on_leave(publisher);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(tracks_map, MapControl::Del(track_key)))));
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peer_map, MapControl::Del(track_key)))));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        room_meta.on_leave(subscriber);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(tracks_map, MapControl::Unsub))));
+        assert_eq!(room_meta.pop_output(()), None);
+        assert!(room_meta.is_empty());
+    }
+
     //TODO Test track publish in disable mode => should not set key to both single peer map and tracks map
     #[test_log::test]
     fn track_publish_disable() {
         let room: ClusterRoomHash = 1.into();
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
 
         let endpoint = 1;
         let peer_id: PeerId = "peer1".to_string().into();
@@ -781,7 +1448,7 @@ mod tests {
     fn leave_room_auto_del_remote_tracks() {
         let room: ClusterRoomHash = 1.into();
         let tracks_map = id_generator::tracks_map(room);
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
 
         let endpoint = 1;
         let peer_id: PeerId = "peer1".to_string().into();
@@ -823,7 +1490,7 @@ mod tests {
     #[test_log::test]
     fn leave_room_auto_unsub_private_peer_maps() {
         let room: ClusterRoomHash = 1.into();
-        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
         let peer_id: PeerId = "peer1".to_string().into();
         let peer_meta = PeerMeta { metadata: None, extra_data: None };
         let endpoint = 1;
@@ -848,4 +1515,143 @@ mod tests {
         assert_eq!(room_meta.pop_output(()), None);
         assert!(room_meta.is_empty());
     }
+
+    /// Test query tracks after two peers published, it should get tracks map once and answer all queries
+    #[test_log::test]
+    fn query_tracks_of_two_publishers() {
+        let room: ClusterRoomHash = 1.into();
+        let tracks_map = id_generator::tracks_map(room);
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, KvRetryPolicy::default());
+
+        let mut published = vec![];
+        for (endpoint, peer) in [(1, "peer1"), (2, "peer2")] {
+            let peer_id: PeerId = peer.to_string().into();
+            room_meta.on_join(
+                endpoint,
+                peer_id.clone(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: false, tracks: true },
+                RoomInfoSubscribe { peers: false, tracks: false },
+            );
+            let track_info = TrackInfo::simple_audio(peer_id.clone());
+            room_meta.on_track_publish(endpoint, RemoteTrackId::from(1), track_info.track.clone(), track_info.meta.clone());
+            while let Some(out) = room_meta.pop_output(()) {
+                if let Output::Kv(Control::MapCmd(map, MapControl::Set(_, data))) = out {
+                    if map == tracks_map {
+                        published.push(data);
+                    }
+                }
+            }
+        }
+        assert_eq!(published.len(), 2);
+
+        room_meta.on_query_tracks(100);
+        room_meta.on_query_tracks(101);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapGet(tracks_map))));
+        assert_eq!(room_meta.pop_output(()), None);
+        assert!(!room_meta.is_empty());
+
+        room_meta.on_kv_get_res(tracks_map, Ok::<_, ()>(published));
+        let expected = vec![TrackInfo::simple_audio("peer1".to_string().into()), TrackInfo::simple_audio("peer2".to_string().into())];
+        assert_eq!(room_meta.pop_output(()), Some(Output::Tracks(100, expected.clone())));
+        assert_eq!(room_meta.pop_output(()), Some(Output::Tracks(101, expected)));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        room_meta.on_leave(1);
+        room_meta.on_leave(2);
+        while room_meta.pop_output(()).is_some() {}
+        assert!(room_meta.is_empty());
+    }
+
+    /// Peer Set which is never stored is retried then the join fails with storage error, instead of keeping the peer half-joined
+    #[test_log::test]
+    fn peer_set_failed_reject_join() {
+        let room: ClusterRoomHash = 1.into();
+        let peers_map = id_generator::peers_map(room);
+        let policy = KvRetryPolicy {
+            timeout: Duration::from_secs(1),
+            retries: 1,
+        };
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, policy);
+        let peer_id: PeerId = "peer1".to_string().into();
+        let peer_meta = PeerMeta { metadata: None, extra_data: None };
+        let peer_info = PeerInfo::new(peer_id.clone(), peer_meta.clone());
+        let peer_key = id_generator::peers_key(&peer_id);
+        let endpoint = 1;
+        room_meta.on_join(
+            endpoint,
+            peer_id.clone(),
+            peer_meta,
+            RoomInfoPublish { peer: true, tracks: false },
+            RoomInfoSubscribe { peers: false, tracks: false },
+        );
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peers_map, MapControl::Set(peer_key, peer_info.serialize())))));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        let t0 = Instant::now();
+        room_meta.on_tick(t0);
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // not confirmed in time => get peers map, failed get => retry Set
+        room_meta.on_tick(t0 + Duration::from_secs(1));
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapGet(peers_map))));
+        room_meta.on_kv_get_res(peers_map, Err::<Vec<Vec<u8>>, _>("timeout"));
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peers_map, MapControl::Set(peer_key, peer_info.serialize())))));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // retry timeout is doubled
+        room_meta.on_tick(t0 + Duration::from_secs(2));
+        room_meta.on_tick(t0 + Duration::from_secs(3));
+        assert_eq!(room_meta.pop_output(()), None);
+        room_meta.on_tick(t0 + Duration::from_secs(4));
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapGet(peers_map))));
+        room_meta.on_kv_get_res(peers_map, Ok::<_, ()>(vec![]));
+        assert_eq!(
+            room_meta.pop_output(()),
+            Some(Output::Endpoint(vec![endpoint], ClusterEndpointEvent::JoinRejected(peer_id, ClusterJoinRejectReason::StorageError)))
+        );
+        assert_eq!(room_meta.pop_output(()), None);
+
+        // endpoint leaves after rejected, the Set is cleaned as normal
+        room_meta.on_leave(endpoint);
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapCmd(peers_map, MapControl::Del(peer_key)))));
+        assert_eq!(room_meta.pop_output(()), None);
+        assert!(room_meta.is_empty());
+    }
+
+    /// Peer Set is confirmed by get of peers map when the room doesn't subscribe it, or by map event
+    #[test_log::test]
+    fn peer_set_confirmed() {
+        let room: ClusterRoomHash = 1.into();
+        let peers_map = id_generator::peers_map(room);
+        let policy = KvRetryPolicy {
+            timeout: Duration::from_secs(1),
+            retries: 0,
+        };
+        let mut room_meta: RoomMetadata<u8> = RoomMetadata::<u8>::new(room, Duration::ZERO, policy);
+        let peer_meta = PeerMeta { metadata: None, extra_data: None };
+        let t0 = Instant::now();
+        for (endpoint, peer) in [(1, "peer1"), (2, "peer2")] {
+            room_meta.on_join(
+                endpoint,
+                peer.to_string().into(),
+                peer_meta.clone(),
+                RoomInfoPublish { peer: true, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+            );
+        }
+        while room_meta.pop_output(()).is_some() {}
+
+        room_meta.on_tick(t0);
+        let peer2 = PeerInfo::new("peer2".to_string().into(), peer_meta.clone());
+        room_meta.on_kv_event(t0, peers_map, MapEvent::OnSet(id_generator::peers_key(&peer2.peer), 0, peer2.serialize()));
+        room_meta.on_tick(t0 + Duration::from_secs(1));
+        assert_eq!(room_meta.pop_output(()), Some(Output::Kv(Control::MapGet(peers_map))));
+        let peer1 = PeerInfo::new("peer1".to_string().into(), peer_meta);
+        room_meta.on_kv_get_res(peers_map, Ok::<_, ()>(vec![peer1.serialize(), peer2.serialize()]));
+        assert_eq!(room_meta.pop_output(()), None);
+
+        room_meta.on_tick(t0 + Duration::from_secs(10));
+        assert_eq!(room_meta.pop_output(()), None);
+    }
 }
@@ -0,0 +1,465 @@
+//!
+//! Room state part keeps room-wide settings in the cluster, so a room which has peers in multiple nodes behaves as one room:
+//! paused, lock, live config, TTL deadline and mixer config.
+//!
+//! Each node writes only its own entry [`RoomStateInfo`] in the room state map, and the state of room is the merge of all entries:
+//!
+//! - Paused, lock and config are versioned by change time, the newest value wins. Each node copies the newest values to its own entry,
+//!   so they are kept after the node which changed them leaves the room.
+//! - Deadline is the earliest one, mixer config and video codec of the room are the ones which are set first.
+//! - Pending peers and admit decisions are per node, the lock owner node sees pending peers of all nodes and writes its decisions.
+//!
+//! The map is subscribed by the first join of this node and a get of the map is sent together, joins are held until the get result
+//! or [`STATE_SYNC_TIMEOUT`] so a locked room can't be bypassed by joining in a new node. The entry is deleted when the room
+//! doesn't have any local endpoint.
+//!
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn::features::dht_kv::{self, Key, Map, MapControl, MapEvent};
+use indexmap::IndexMap;
+use media_server_protocol::endpoint::{AudioMixerMode, PeerId, RoomLockInfo, RoomMixerInfo, RoomStateInfo, Versioned};
+use sans_io_runtime::TaskSwitcherChild;
+
+use crate::cluster::{id_generator, ClusterRoomHash, RoomConfig, RoomConfigPatch};
+
+/// Joins are admitted without remote state if the state map get is not answered in time
+pub const STATE_SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Output {
+    Kv(dht_kv::Control),
+    OnResourceEmpty,
+}
+
+/// Merged state of all nodes
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MergedState {
+    pub paused: Versioned<bool>,
+    pub lock: Versioned<RoomLockInfo>,
+    pub max_bitrate: Versioned<Option<u64>>,
+    pub max_spatial: Versioned<Option<u8>>,
+    pub deadline: Option<u64>,
+    pub video_codec: Option<Versioned<String>>,
+    pub pending: Vec<PeerId>,
+    pub decisions: Vec<(PeerId, bool)>,
+}
+
+impl MergedState {
+    pub fn config(&self) -> RoomConfig {
+        RoomConfig {
+            max_bitrate: self.max_bitrate.value,
+            max_spatial: self.max_spatial.value,
+        }
+    }
+
+    pub fn decision(&self, peer: &PeerId) -> Option<bool> {
+        self.decisions.iter().find(|(p, _)| p == peer).map(|(_, admit)| *admit)
+    }
+}
+
+/// Newest value, same version is ordered by node key so all nodes pick the same one
+fn newest<T: Clone>(cur: &mut (Key, Versioned<T>), key: Key, other: &Versioned<T>) {
+    if (other.version, key) > (cur.1.version, cur.0) {
+        *cur = (key, other.clone());
+    }
+}
+
+#[derive(Debug)]
+pub struct RoomState {
+    room: ClusterRoomHash,
+    map: Map,
+    key: Key,
+    /// The map is subscribed, it is started by the first join of this node
+    active: bool,
+    /// Remote entries are loaded by the map get, or the sync timed out
+    synced: bool,
+    /// Armed at the next tick after activated
+    sync_deadline: Option<Instant>,
+    local: RoomStateInfo,
+    /// Last entry which is Set to the map
+    written: Option<RoomStateInfo>,
+    remotes: IndexMap<Key, RoomStateInfo>,
+    queue: VecDeque<Output>,
+}
+
+impl RoomState {
+    pub fn new(room: ClusterRoomHash) -> Self {
+        Self {
+            room,
+            map: id_generator::room_state_map(room),
+            key: id_generator::room_state_key(),
+            active: false,
+            synced: false,
+            sync_deadline: None,
+            local: Default::default(),
+            written: None,
+            remotes: Default::default(),
+            queue: Default::default(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Subscribe the state map and get current entries of other nodes
+    pub fn activate(&mut self) {
+        if self.active {
+            return;
+        }
+        log::info!("[ClusterRoomState {}] first local join => subscribe and get room state", self.room);
+        self.active = true;
+        self.synced = false;
+        self.sync_deadline = None;
+        self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.map, MapControl::Sub)));
+        self.queue.push_back(Output::Kv(dht_kv::Control::MapGet(self.map)));
+    }
+
+    /// Delete local entry and unsubscribe, the room doesn't have any local endpoint
+    pub fn deactivate(&mut self) {
+        if !self.active {
+            return;
+        }
+        log::info!("[ClusterRoomState {}] no local endpoint => delete local entry and unsubscribe", self.room);
+        if self.written.take().is_some() {
+            self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.map, MapControl::Del(self.key))));
+        }
+        self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.map, MapControl::Unsub)));
+        self.active = false;
+        self.synced = false;
+        self.local = Default::default();
+        self.remotes.clear();
+    }
+
+    /// Return true if the sync is timed out at this tick
+    pub fn on_tick(&mut self, now: Instant) -> bool {
+        if !self.active || self.synced {
+            return false;
+        }
+        let deadline = *self.sync_deadline.get_or_insert(now + STATE_SYNC_TIMEOUT);
+        if now >= deadline {
+            log::warn!("[ClusterRoomState {}] room state get not answered in time => use local state", self.room);
+            self.synced = true;
+            return true;
+        }
+        false
+    }
+
+    pub fn on_kv_event(&mut self, map: Map, event: MapEvent) {
+        if map != self.map || !self.active {
+            return;
+        }
+        match event {
+            MapEvent::OnSet(key, _source, data) => {
+                if key == self.key {
+                    return;
+                }
+                if let Some(info) = RoomStateInfo::deserialize(&data) {
+                    self.remotes.insert(key, info);
+                }
+            }
+            MapEvent::OnDel(key, _source) => {
+                self.remotes.swap_remove(&key);
+            }
+            MapEvent::OnRelaySelected(_) => {}
+        }
+    }
+
+    /// A failed get is same as empty map, because the map is not found when room is new in cluster
+    pub fn on_kv_get_res<E: Debug>(&mut self, map: Map, res: Result<Vec<(Key, Vec<u8>)>, E>) {
+        if map != self.map || !self.active || self.synced {
+            return;
+        }
+        match res {
+            Ok(entries) => {
+                for (key, data) in entries {
+                    if key == self.key {
+                        continue;
+                    }
+                    if let Some(info) = RoomStateInfo::deserialize(&data) {
+                        self.remotes.insert(key, info);
+                    }
+                }
+            }
+            Err(e) => log::warn!("[ClusterRoomState {}] get room state error {:?} => use local state", self.room, e),
+        }
+        log::info!("[ClusterRoomState {}] room state synced with {} remote entries", self.room, self.remotes.len());
+        self.synced = true;
+    }
+
+    pub fn merged(&self) -> MergedState {
+        let mut paused = (self.key, self.local.paused.clone());
+        let mut lock = (self.key, self.local.lock.clone());
+        let mut max_bitrate = (self.key, self.local.max_bitrate.clone());
+        let mut max_spatial = (self.key, self.local.max_spatial.clone());
+        let mut deadline = self.local.deadline;
+        let mut video_codec = self.local.video_codec.clone().map(|codec| (self.key, codec));
+        let mut pending = self.local.pending.clone();
+        let mut decisions = self.local.decisions.clone();
+        for (key, info) in self.remotes.iter() {
+            newest(&mut paused, *key, &info.paused);
+            newest(&mut lock, *key, &info.lock);
+            newest(&mut max_bitrate, *key, &info.max_bitrate);
+            newest(&mut max_spatial, *key, &info.max_spatial);
+            deadline = match (deadline, info.deadline) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            if let Some(codec) = &info.video_codec {
+                if video_codec.as_ref().map_or(true, |(cur_key, cur)| (codec.version, *key) < (cur.version, *cur_key)) {
+                    video_codec = Some((*key, codec.clone()));
+                }
+            }
+            pending.extend(info.pending.iter().cloned());
+            decisions.extend(info.decisions.iter().cloned());
+        }
+        MergedState {
+            paused: paused.1,
+            lock: lock.1,
+            max_bitrate: max_bitrate.1,
+            max_spatial: max_spatial.1,
+            deadline,
+            video_codec: video_codec.map(|(_, codec)| codec),
+            pending,
+            decisions,
+        }
+    }
+
+    /// Mixer config which is set first in other nodes, local mixer endpoints are checked by the local mixer
+    pub fn remote_mixer(&self) -> Option<RoomMixerInfo> {
+        self.remotes
+            .iter()
+            .filter_map(|(key, info)| info.mixer.map(|mixer| (mixer.since, *key, mixer)))
+            .min_by_key(|(since, key, _)| (*since, *key))
+            .map(|(_, _, mixer)| mixer)
+    }
+
+    /// Copy room-wide values of merged state to local entry
+    pub fn mirror(&mut self, merged: &MergedState) {
+        self.local.paused = merged.paused.clone();
+        self.local.lock = merged.lock.clone();
+        self.local.max_bitrate = merged.max_bitrate.clone();
+        self.local.max_spatial = merged.max_spatial.clone();
+        self.local.deadline = merged.deadline;
+        self.local.video_codec = merged.video_codec.clone();
+    }
+
+    /// Version of a new change, it is always newer than the current value even with clock skew between nodes
+    fn next_version<T>(current: &Versioned<T>, now_ms: u64) -> u64 {
+        now_ms.max(current.version + 1)
+    }
+
+    pub fn set_paused(&mut self, now_ms: u64, paused: bool) {
+        let current = self.merged().paused;
+        if current.value != paused {
+            self.local.paused = Versioned::new(Self::next_version(&current, now_ms), paused);
+        }
+    }
+
+    pub fn set_lock(&mut self, now_ms: u64, lock: RoomLockInfo) {
+        let current = self.merged().lock;
+        if current.value != lock {
+            self.local.lock = Versioned::new(Self::next_version(&current, now_ms), lock);
+        }
+    }
+
+    /// Apply live fields of the patch, fields which require rejoin must be rejected before
+    pub fn set_config(&mut self, now_ms: u64, patch: &RoomConfigPatch) {
+        let merged = self.merged();
+        if let Some(max_bitrate) = patch.max_bitrate.filter(|v| merged.max_bitrate.value != Some(*v)) {
+            self.local.max_bitrate = Versioned::new(Self::next_version(&merged.max_bitrate, now_ms), Some(max_bitrate));
+        }
+        if let Some(max_spatial) = patch.max_spatial.filter(|v| merged.max_spatial.value != Some(*v)) {
+            self.local.max_spatial = Versioned::new(Self::next_version(&merged.max_spatial, now_ms), Some(max_spatial));
+        }
+    }
+
+    pub fn set_deadline(&mut self, deadline_ms: u64) {
+        self.local.deadline = Some(self.local.deadline.map_or(deadline_ms, |d| d.min(deadline_ms)));
+    }
+
+    /// Set mixer config of local endpoints, the time of the remote mixer is kept if it is same config, so it stays the first one
+    pub fn set_mixer(&mut self, now_ms: u64, mixer: Option<(AudioMixerMode, usize)>) {
+        let remote_mixer = self.remote_mixer();
+        self.local.mixer = match (mixer, self.local.mixer) {
+            (None, _) => None,
+            (Some((mode, outputs)), Some(local)) if local.mode == mode && local.outputs == outputs => Some(local),
+            (Some((mode, outputs)), _) => {
+                let since = remote_mixer.filter(|m| m.mode == mode && m.outputs == outputs).map_or(now_ms, |m| m.since);
+                Some(RoomMixerInfo { mode, outputs, since })
+            }
+        };
+    }
+
+    /// Pin video codec of the room, it is ignored if the room already has one
+    pub fn set_video_codec(&mut self, now_ms: u64, codec: String) {
+        if self.merged().video_codec.is_none() {
+            self.local.video_codec = Some(Versioned::new(now_ms, codec));
+        }
+    }
+
+    pub fn set_pending(&mut self, pending: Vec<PeerId>) {
+        self.local.pending = pending;
+    }
+
+    pub fn set_decision(&mut self, peer: PeerId, admit: bool) {
+        self.local.decisions.retain(|(p, _)| *p != peer);
+        self.local.decisions.push((peer, admit));
+    }
+
+    /// Decisions are removed after the peer is not pending in any node
+    pub fn retain_decisions(&mut self, pending: &[PeerId]) {
+        self.local.decisions.retain(|(peer, _)| pending.contains(peer));
+    }
+
+    /// Set local entry to the map if it is changed
+    pub fn flush(&mut self) {
+        if !self.active || self.written.as_ref() == Some(&self.local) {
+            return;
+        }
+        if self.written.is_none() && self.local == RoomStateInfo::default() {
+            return;
+        }
+        self.written = Some(self.local.clone());
+        self.queue.push_back(Output::Kv(dht_kv::Control::MapCmd(self.map, MapControl::Set(self.key, self.local.serialize()))));
+    }
+}
+
+impl TaskSwitcherChild<Output> for RoomState {
+    type Time = ();
+
+    fn is_empty(&self) -> bool {
+        !self.active && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> Output {
+        Output::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: ()) -> Option<Output> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn::features::dht_kv::{self, MapControl, MapEvent};
+    use media_server_protocol::endpoint::{AudioMixerMode, RoomLockInfo, RoomStateInfo, Versioned};
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::cluster::{id_generator, RoomConfigPatch};
+
+    use super::{Output, RoomState};
+
+    fn set_of(state: &mut RoomState) -> Option<Vec<u8>> {
+        let mut data = None;
+        while let Some(out) = state.pop_output(()) {
+            if let Output::Kv(dht_kv::Control::MapCmd(_, MapControl::Set(_, d))) = out {
+                data = Some(d);
+            }
+        }
+        data
+    }
+
+    //Newest value wins for each field, and same values are kept by other nodes after the writer leaves
+    #[test]
+    fn merge_newest_and_mirror() {
+        let room = 0.into();
+        let map = id_generator::room_state_map(room);
+        let mut node1 = RoomState::new(room);
+        let mut node2 = RoomState::new(room);
+        for node in [&mut node1, &mut node2] {
+            node.activate();
+            node.on_kv_get_res::<()>(map, Ok(vec![]));
+            assert!(node.is_synced());
+        }
+
+        node1.set_paused(1000, true);
+        node1.set_config(
+            1000,
+            &RoomConfigPatch {
+                max_bitrate: Some(500_000),
+                ..Default::default()
+            },
+        );
+        node1.flush();
+        let data = set_of(&mut node1).expect("Should set local entry");
+        node2.on_kv_event(map, MapEvent::OnSet(1.into(), 1, data));
+        let merged = node2.merged();
+        assert!(merged.paused.value);
+        assert_eq!(merged.max_bitrate.value, Some(500_000));
+
+        // a change with skewed clock is still newer
+        node2.set_paused(10, false);
+        assert_eq!(node2.merged().paused, Versioned::new(1001, false));
+
+        node2.set_lock(2000, RoomLockInfo::Locked("owner".into()));
+        node2.mirror(&node2.merged());
+        node2.flush();
+        let data = set_of(&mut node2).expect("Should set local entry");
+        let info = RoomStateInfo::deserialize(&data).expect("Should decode");
+        assert_eq!(info.max_bitrate.value, Some(500_000));
+        assert_eq!(info.lock.value, RoomLockInfo::Locked("owner".into()));
+
+        // node1 leaves, config is still in node2 entry
+        node2.on_kv_event(map, MapEvent::OnDel(1.into(), 1));
+        assert_eq!(node2.merged().max_bitrate.value, Some(500_000));
+    }
+
+    #[test]
+    fn mixer_first_set_wins_and_deadline_earliest() {
+        let room = 0.into();
+        let map = id_generator::room_state_map(room);
+        let mut state = RoomState::new(room);
+        state.activate();
+        state.on_kv_get_res::<()>(map, Err(()));
+
+        state.set_mixer(2000, Some((AudioMixerMode::Manual, 1)));
+        state.set_deadline(9000);
+        let remote = RoomStateInfo {
+            mixer: Some(media_server_protocol::endpoint::RoomMixerInfo {
+                mode: AudioMixerMode::Auto,
+                outputs: 3,
+                since: 1000,
+            }),
+            deadline: Some(8000),
+            ..Default::default()
+        };
+        state.on_kv_event(map, MapEvent::OnSet(1.into(), 1, remote.serialize()));
+        assert_eq!(state.remote_mixer().map(|m| (m.mode, m.outputs)), Some((AudioMixerMode::Auto, 3)));
+        assert_eq!(state.merged().deadline, Some(8000));
+
+        // local mixer with same config as the remote one keeps the remote time
+        state.set_mixer(3000, None);
+        state.set_mixer(3000, Some((AudioMixerMode::Auto, 3)));
+        state.flush();
+        let data = set_of(&mut state).expect("Should set local entry");
+        assert_eq!(RoomStateInfo::deserialize(&data).and_then(|info| info.mixer).map(|m| m.since), Some(1000));
+
+        // video codec which is pinned first in the cluster wins
+        state.set_video_codec(5000, "VP8".to_string());
+        let remote = RoomStateInfo {
+            video_codec: Some(Versioned::new(4000, "VP9".to_string())),
+            ..Default::default()
+        };
+        state.on_kv_event(map, MapEvent::OnSet(2.into(), 2, remote.serialize()));
+        assert_eq!(state.merged().video_codec.map(|codec| codec.value), Some("VP9".to_string()));
+        state.set_video_codec(6000, "H264".to_string());
+        assert_eq!(state.merged().video_codec.map(|codec| codec.value), Some("VP9".to_string()));
+
+        // after deactivated, local entry is deleted
+        state.deactivate();
+        assert!(state.pop_output(()).is_some());
+        assert!(state.pop_output(()).is_some());
+        assert!(state.is_empty());
+    }
+}
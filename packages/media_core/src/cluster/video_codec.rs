@@ -0,0 +1,75 @@
+//! Video codec which is pinned for each room, shared by all workers of the node.
+//!
+//! When the server answers only one video codec, peers of a room must use the same codec so media can be relayed
+//! without transcoding. Workers pin the codec of the first session with video in a room, and the codec is shared
+//! with other nodes through the room state, the codec which is pinned first in the cluster wins. A room is kept
+//! here while it has sessions in this node, so the entry is removed after the last session of the room is gone.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use super::ClusterRoomHash;
+
+#[derive(Debug, Default)]
+pub struct RoomVideoCodecs {
+    /// Codec name and number of sessions in this node which pinned it
+    rooms: Mutex<HashMap<ClusterRoomHash, (String, usize)>>,
+}
+
+impl RoomVideoCodecs {
+    /// Codec name of the room, None if no session with video is in the room
+    pub fn get(&self, room: ClusterRoomHash) -> Option<String> {
+        self.rooms.lock().expect("Should lock").get(&room).map(|(codec, _)| codec.clone())
+    }
+
+    /// Register a session of the room which negotiated the codec, the codec is pinned if the room doesn't have one
+    pub fn acquire(&self, room: ClusterRoomHash, codec: &str) {
+        let mut rooms = self.rooms.lock().expect("Should lock");
+        let entry = rooms.entry(room).or_insert_with(|| (codec.to_string(), 0));
+        entry.1 += 1;
+    }
+
+    /// Unregister a session which is acquired before, the room is removed after its last session
+    pub fn release(&self, room: ClusterRoomHash) {
+        let mut rooms = self.rooms.lock().expect("Should lock");
+        if let Some(entry) = rooms.get_mut(&room) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                rooms.remove(&room);
+            }
+        }
+    }
+
+    /// Replace the codec with the one which is pinned first in the cluster, only for rooms which have sessions in this node
+    pub fn update(&self, room: ClusterRoomHash, codec: &str) {
+        let mut rooms = self.rooms.lock().expect("Should lock");
+        if let Some(entry) = rooms.get_mut(&room) {
+            if entry.0 != codec {
+                log::info!("[RoomVideoCodecs] room {room} video codec {} => {codec} by cluster", entry.0);
+                entry.0 = codec.to_string();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RoomVideoCodecs;
+
+    #[test]
+    fn pin_until_last_session_released() {
+        let codecs = RoomVideoCodecs::default();
+        let room = 1.into();
+        codecs.update(room, "VP8");
+        assert_eq!(codecs.get(room), None);
+
+        codecs.acquire(room, "VP9");
+        codecs.acquire(room, "VP8");
+        assert_eq!(codecs.get(room).as_deref(), Some("VP9"));
+
+        codecs.update(room, "H264");
+        codecs.release(room);
+        assert_eq!(codecs.get(room).as_deref(), Some("H264"));
+        codecs.release(room);
+        assert_eq!(codecs.get(room), None);
+    }
+}
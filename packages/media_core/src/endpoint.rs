@@ -1,29 +1,40 @@
 //! Endpoint take care integrate between transport and endpoint internal logic. It don't have logic, just forward events
 
-use std::{marker::PhantomData, time::Instant};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    marker::PhantomData,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use media_server_protocol::{
-    endpoint::{AudioMixerConfig, BitrateControlMode, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackMeta, TrackName, TrackPriority, TrackSource},
+    endpoint::{
+        AudioMixerConfig, AudioMixerMode, BitrateControlMode, BitratePriority, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackKindFilter, TrackMeta, TrackName, TrackPriority,
+        TrackSource,
+    },
     media::MediaPacket,
     multi_tenancy::{AppContext, AppId},
     protobuf::{self, cluster_connector::peer_event},
     record::SessionRecordEvent,
     transport::RpcResult,
 };
-use media_server_utils::Count;
+use media_server_utils::{Count, EgressBudget, LoopMetrics, LoopMetricsRecorder};
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
     return_if_some, Task, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild,
 };
 
 use crate::{
-    cluster::{ClusterEndpointControl, ClusterEndpointEvent, ClusterRoomHash},
+    cluster::{ClusterEndpointControl, ClusterEndpointEvent, ClusterJoinRejectReason, ClusterRoomHash, RoomConfig, RoomConfigPatch},
     transport::{LocalTrackId, RemoteTrackId, Transport, TransportInput, TransportOutput},
 };
 
 use internal::EndpointInternal;
 
 use self::internal::InternalOutput;
+pub use self::internal::{PlayoutConfig, RelayGraceConfig};
 
 mod internal;
 
@@ -47,11 +58,14 @@ impl From<protobuf::shared::sender::Config> for EndpointRemoteTrackConfig {
 #[derive(Debug, PartialEq, Eq)]
 pub enum EndpointRemoteTrackReq {
     Config(EndpointRemoteTrackConfig),
+    /// App-level mute state of the track, it is synced to subscribers over room metadata
+    Mute(bool),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum EndpointRemoteTrackRes {
     Config(RpcResult<()>),
+    Mute(RpcResult<()>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -61,6 +75,9 @@ pub struct EndpointLocalTrackConfig {
     pub max_temporal: u8,
     pub min_spatial: Option<u8>,
     pub min_temporal: Option<u8>,
+    pub bitrate_priority: BitratePriority,
+    /// Buffer media to the target playout delay before sending, for smooth playback instead of lowest latency
+    pub smooth_playout: bool,
 }
 
 impl From<protobuf::shared::receiver::Config> for EndpointLocalTrackConfig {
@@ -71,6 +88,8 @@ impl From<protobuf::shared::receiver::Config> for EndpointLocalTrackConfig {
             max_temporal: value.max_temporal as u8,
             min_spatial: value.min_spatial.map(|m| m as u8),
             min_temporal: value.min_temporal.map(|m| m as u8),
+            bitrate_priority: value.bitrate_priority().into(),
+            smooth_playout: value.smooth_playout,
         }
     }
 }
@@ -132,6 +151,20 @@ pub enum EndpointReq {
     LeaveRoom,
     SubscribePeer(PeerId),
     UnsubscribePeer(PeerId),
+    /// Reconcile single track subscriptions in one request, e.g. when the client changes its layout
+    BatchTrackSubscriptions {
+        subscribe: Vec<(PeerId, TrackName)>,
+        unsubscribe: Vec<(PeerId, TrackName)>,
+    },
+    /// Filter which kinds of track from a peer are announced, can be changed at any time while in room
+    SetPeerKindFilter(PeerId, TrackKindFilter),
+    /// Pause (true) or resume (false) media of the whole room
+    PauseRoom(bool),
+    SetRoomLocked(bool),
+    /// Admit (true) or reject (false) a pending peer, only the lock owner can do it
+    AdmitPeer(PeerId, bool),
+    /// Update live room config, a patch with fields which require rejoin is rejected
+    UpdateRoomConfig(RoomConfigPatch),
     AudioMixer(EndpointAudioMixerReq),
     RemoteTrack(RemoteTrackId, EndpointRemoteTrackReq),
     LocalTrack(LocalTrackId, EndpointLocalTrackReq),
@@ -145,6 +178,12 @@ pub enum EndpointRes {
     LeaveRoom(RpcResult<()>),
     SubscribePeer(RpcResult<()>),
     UnsubscribePeer(RpcResult<()>),
+    BatchTrackSubscriptions(RpcResult<()>),
+    SetPeerKindFilter(RpcResult<()>),
+    PauseRoom(RpcResult<()>),
+    SetRoomLocked(RpcResult<()>),
+    AdmitPeer(RpcResult<()>),
+    UpdateRoomConfig(RpcResult<()>),
     AudioMixer(EndpointAudioMixerRes),
     RemoteTrack(RemoteTrackId, EndpointRemoteTrackRes),
     LocalTrack(LocalTrackId, EndpointLocalTrackRes),
@@ -163,7 +202,12 @@ pub enum EndpointLocalTrackEvent {
 #[derive(Debug, PartialEq, Eq)]
 pub enum EndpointRemoteTrackEvent {
     RequestKeyFrame,
-    LimitBitrateBps { min: u64, max: u64 },
+    /// Layers are target bitrate of each spatial layer of a simulcast stream, see [`crate::cluster::ClusterRemoteTrackEvent::LimitBitrate`]
+    LimitBitrateBps {
+        min: u64,
+        max: u64,
+        layers: Option<[Option<u64>; 3]>,
+    },
 }
 
 /// This is used for controlling audio mixer feature
@@ -171,17 +215,35 @@ pub enum EndpointRemoteTrackEvent {
 pub enum EndpointAudioMixerEvent {
     SlotSet(u8, PeerId, TrackName),
     SlotUnset(u8),
+    /// Mixer config is ignored because room mixer is already set with other (mode, number of outputs)
+    ConfigConflict(AudioMixerMode, usize),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum EndpointEvent {
+    /// Join is rejected by room, e.g. the peer id is already joined, endpoint is switched back to not-in-room state.
+    /// With [`ClusterJoinRejectReason::AlreadyInRoom`] the endpoint stays in its current room
+    JoinRejected(PeerId, ClusterJoinRejectReason),
+    /// A peer is waiting to join the room which is locked by this endpoint
+    JoinPending(PeerId),
     PeerJoined(PeerId, PeerMeta),
     PeerLeaved(PeerId, PeerMeta),
     PeerTrackStarted(PeerId, TrackName, TrackMeta),
     PeerTrackStopped(PeerId, TrackName, TrackMeta),
+    PeerTrackMuted(PeerId, TrackName, bool),
+    /// Room media is paused or resumed by host
+    RoomPaused(bool),
+    /// Live room config is changed, also sent after join when the room config is not default
+    RoomConfigChanged(RoomConfig),
     AudioMixer(EndpointAudioMixerEvent),
     RemoteMediaTrack(RemoteTrackId, EndpointRemoteTrackEvent),
     LocalMediaTrack(LocalTrackId, EndpointLocalTrackEvent),
+    /// Published track is not created because the endpoint reached [`TrackLimits::max_publish`], its media is dropped and its
+    /// requests are answered with [`crate::errors::EndpointErrors::RemoteTrackRejected`] until it is started again
+    RemoteMediaTrackRejected(RemoteTrackId),
+    /// Receiving track is not created because the endpoint reached [`TrackLimits::max_subscribe`], its requests are answered with
+    /// [`crate::errors::EndpointErrors::LocalTrackRejected`] until it is started again
+    LocalMediaTrackRejected(LocalTrackId),
     /// Egress est params
     BweConfig {
         current: u64,
@@ -192,6 +254,8 @@ pub enum EndpointEvent {
 
     /// DataChannel events
     ChannelMessage(MessageChannelLabel, PeerId, Vec<u8>),
+    /// A message which is published to channel is rejected by room, e.g. too large
+    ChannelError(MessageChannelLabel, RpcError),
 }
 
 pub enum EndpointInput<Ext> {
@@ -217,12 +281,139 @@ enum TaskType {
     Internal = 1,
 }
 
+/// Max number of tracks of an endpoint, it protects the node from clients which open too many m-lines. 0 for unlimited
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrackLimits {
+    /// Tracks which are published by the endpoint
+    pub max_publish: usize,
+    /// Tracks which receive media from room
+    pub max_subscribe: usize,
+}
+
+/// Opus quality of an app. Bitrate is signaled to clients and used by server-side encoders, complexity is only used
+/// by server-side encoders because it has no SDP parameter. None fields keep client or encoder defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpusParams {
+    /// Target bitrate in bps, 6000-510000
+    pub max_average_bitrate: Option<u32>,
+    /// Encoder complexity, 0-10
+    pub complexity: Option<u8>,
+    /// Clock rate of opus rtpmap in answers, RFC 7587 always uses 48000 but some telephony systems don't
+    pub clock_rate: u32,
+    /// Channels of opus rtpmap in answers
+    pub channels: u8,
+}
+
+pub const DEFAULT_OPUS_CLOCK_RATE: u32 = 48000;
+pub const DEFAULT_OPUS_CHANNELS: u8 = 2;
+
+impl Default for OpusParams {
+    fn default() -> Self {
+        Self {
+            max_average_bitrate: None,
+            complexity: None,
+            clock_rate: DEFAULT_OPUS_CLOCK_RATE,
+            channels: DEFAULT_OPUS_CHANNELS,
+        }
+    }
+}
+
+/// Opus quality per app, e.g. high bitrate for podcasts and low for walkie-talkie
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OpusConfig {
+    /// Params of apps which are not in `apps`
+    pub default: OpusParams,
+    pub apps: HashMap<AppId, OpusParams>,
+}
+
+impl OpusConfig {
+    pub fn params(&self, app: &AppId) -> OpusParams {
+        self.apps.get(app).copied().unwrap_or(self.default)
+    }
+}
+
+/// Join of an endpoint which is already in other room. An endpoint is in one room at a time, because its tracks
+/// are routed in cluster by the room hash
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MultiRoomPolicy {
+    /// Leave the current room then join the new one
+    #[default]
+    Switch,
+    /// Reject the join with [`ClusterJoinRejectReason::AlreadyInRoom`], client must leave the current room first
+    Reject,
+}
+
+impl FromStr for MultiRoomPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "switch" => Ok(Self::Switch),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("unsupported multi room policy {s}")),
+        }
+    }
+}
+
+impl Display for MultiRoomPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Switch => f.write_str("switch"),
+            Self::Reject => f.write_str("reject"),
+        }
+    }
+}
+
+pub const DEFAULT_SESSION_MAX_DURATION_WARNING: Duration = Duration::from_secs(60);
+
+/// Max duration of sessions, e.g. trial plans with time-limited calls. It is counted from the first connect of each
+/// session, so unlike room TTL a peer which rejoins gets a new duration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionMaxDurationConfig {
+    /// Max duration of sessions of apps which are not in `apps`, None for unlimited
+    pub default: Option<Duration>,
+    /// Max duration per app, zero disables it for the app
+    pub apps: HashMap<AppId, Duration>,
+    /// Clients are warned this time before the session is closed
+    pub warning: Duration,
+}
+
+impl Default for SessionMaxDurationConfig {
+    fn default() -> Self {
+        Self {
+            default: None,
+            apps: HashMap::new(),
+            warning: DEFAULT_SESSION_MAX_DURATION_WARNING,
+        }
+    }
+}
+
+impl SessionMaxDurationConfig {
+    pub fn max_duration(&self, app: &AppId) -> Option<Duration> {
+        self.apps.get(app).copied().or(self.default).filter(|max| !max.is_zero())
+    }
+}
+
 #[derive(Debug)]
 pub struct EndpointCfg {
     pub app: AppContext,
     pub max_egress_bitrate: u64,
     pub max_ingress_bitrate: u64,
     pub record: bool,
+    /// Measure on_tick/on_event processing time and output queue depth
+    pub metrics: bool,
+    /// Buffer of subscribed media while relay path is changing
+    pub relay_grace: RelayGraceConfig,
+    /// Target delay of subscribed media in smooth playout mode
+    pub playout: PlayoutConfig,
+    pub track_limits: TrackLimits,
+    pub multi_room: MultiRoomPolicy,
+    /// Node-wide egress budget, None if node egress is not capped
+    pub egress_budget: Option<Arc<EgressBudget>>,
+    /// Session is closed after this duration since connected, None for unlimited
+    pub max_duration: Option<Duration>,
+    /// Client is warned this time before the session is closed by max duration
+    pub max_duration_warning: Duration,
 }
 
 pub struct Endpoint<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> {
@@ -233,11 +424,13 @@ pub struct Endpoint<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> {
     internal: TaskSwitcherBranch<EndpointInternal, InternalOutput>,
     switcher: TaskSwitcher,
     shutdown: bool,
+    metrics: Option<LoopMetricsRecorder>,
     _tmp: PhantomData<(ExtIn, ExtOut)>,
 }
 
 impl<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> Endpoint<T, ExtIn, ExtOut> {
     pub fn new(session_id: u64, cfg: EndpointCfg, transport: T) -> Self {
+        let metrics = cfg.metrics.then(|| LoopMetricsRecorder::new("endpoint"));
         Self {
             _c: Default::default(),
             app: cfg.app.app.clone(),
@@ -246,9 +439,15 @@ impl<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> Endpoint<T, ExtIn, ExtOut> {
             internal: TaskSwitcherBranch::new(EndpointInternal::new(cfg), TaskType::Internal),
             switcher: TaskSwitcher::new(2),
             shutdown: false,
+            metrics,
             _tmp: PhantomData,
         }
     }
+
+    /// Metrics which are not reported yet, only available when metrics is enabled
+    pub fn metrics(&self) -> Option<&LoopMetrics> {
+        self.metrics.as_ref().map(|m| m.metrics())
+    }
 }
 
 impl<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> Task<EndpointInput<ExtIn>, EndpointOutput<ExtOut>> for Endpoint<T, ExtIn, ExtOut>
@@ -256,21 +455,20 @@ where
     T::Time: From<Instant>,
 {
     fn on_tick(&mut self, now: Instant) {
+        let started = self.metrics.is_some().then(Instant::now);
         self.internal.input(&mut self.switcher).on_tick(now);
         self.transport.input(&mut self.switcher).on_tick(now);
+        if let (Some(metrics), Some(started)) = (self.metrics.as_mut(), started) {
+            metrics.on_tick(started.elapsed());
+            metrics.report(now);
+        }
     }
 
     fn on_event(&mut self, now: Instant, input: EndpointInput<ExtIn>) {
-        match input {
-            EndpointInput::Net(net) => {
-                self.transport.input(&mut self.switcher).on_input(now, TransportInput::Net(net));
-            }
-            EndpointInput::Ext(ext) => {
-                self.transport.input(&mut self.switcher).on_input(now, TransportInput::Ext(ext));
-            }
-            EndpointInput::Cluster(event) => {
-                self.internal.input(&mut self.switcher).on_cluster_event(now, event);
-            }
+        let started = self.metrics.is_some().then(Instant::now);
+        self.process_input(now, input);
+        if let (Some(metrics), Some(started)) = (self.metrics.as_mut(), started) {
+            metrics.on_event(started.elapsed());
         }
     }
 
@@ -300,6 +498,33 @@ where
     }
 
     fn pop_output(&mut self, now: Instant) -> Option<EndpointOutput<ExtOut>> {
+        let out = self.pop_inner_output(now);
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.on_pop(out.is_some());
+        }
+        out
+    }
+}
+
+impl<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> Endpoint<T, ExtIn, ExtOut>
+where
+    T::Time: From<Instant>,
+{
+    fn process_input(&mut self, now: Instant, input: EndpointInput<ExtIn>) {
+        match input {
+            EndpointInput::Net(net) => {
+                self.transport.input(&mut self.switcher).on_input(now, TransportInput::Net(net));
+            }
+            EndpointInput::Ext(ext) => {
+                self.transport.input(&mut self.switcher).on_input(now, TransportInput::Ext(ext));
+            }
+            EndpointInput::Cluster(event) => {
+                self.internal.input(&mut self.switcher).on_cluster_event(now, event);
+            }
+        }
+    }
+
+    fn pop_inner_output(&mut self, now: Instant) -> Option<EndpointOutput<ExtOut>> {
         loop {
             match self.switcher.current()?.try_into().ok()? {
                 TaskType::Internal => {
@@ -315,9 +540,7 @@ where
             }
         }
     }
-}
 
-impl<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> Endpoint<T, ExtIn, ExtOut> {
     fn process_transport_output(&mut self, now: Instant, out: TransportOutput<ExtOut>) -> Option<EndpointOutput<ExtOut>> {
         match out {
             TransportOutput::Event(event) => {
@@ -348,6 +571,11 @@ impl<T: Transport<ExtIn, ExtOut>, ExtIn, ExtOut> Endpoint<T, ExtIn, ExtOut> {
                 None
             }
             InternalOutput::Cluster(room, control) => Some(EndpointOutput::Cluster(room, control)),
+            InternalOutput::Close => {
+                log::info!("[Endpoint] internal request close => shutdown");
+                self.on_shutdown(now);
+                None
+            }
             InternalOutput::OnResourceEmpty => {
                 // we don't need to forward this event to parent, itself will fire OnResourceEmpty
                 Some(EndpointOutput::Continue)
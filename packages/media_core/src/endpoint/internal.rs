@@ -1,34 +1,46 @@
 //! EndpointInternal compose small parts: local track, remote track. It act as integration hub
 
-use std::{collections::VecDeque, time::Instant};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Instant,
+};
 
 use media_server_protocol::{
     endpoint::{AudioMixerConfig, AudioMixerMode, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe},
-    protobuf::{cluster_connector::peer_event, shared::Kind},
+    media::MediaKind,
+    protobuf::{
+        cluster_connector::peer_event::{self, disconnected::Reason},
+        shared::Kind,
+    },
     record::SessionRecordEvent,
     transport::RpcError,
 };
-use media_server_utils::IndexMap2d;
+use media_server_utils::{EgressBudgetSlot, IndexMap2d};
 use sans_io_runtime::{return_if_none, return_if_some, TaskGroup, TaskGroupOutput, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
     cluster::{
-        ClusterAudioMixerControl, ClusterAudioMixerEvent, ClusterEndpointControl, ClusterEndpointEvent, ClusterLocalTrackEvent, ClusterMessageChannelControl, ClusterRemoteTrackEvent, ClusterRoomHash,
+        ClusterAudioMixerControl, ClusterAudioMixerEvent, ClusterEndpointControl, ClusterEndpointEvent, ClusterJoinRejectReason, ClusterLocalTrackEvent, ClusterMessageChannelControl,
+        ClusterMessageChannelError, ClusterRemoteTrackEvent, ClusterRoomHash, RoomConfig,
     },
     errors::EndpointErrors,
-    transport::{LocalTrackEvent, LocalTrackId, RemoteTrackEvent, RemoteTrackId, TransportEvent, TransportState, TransportStats},
+    transport::{LocalTrackEvent, LocalTrackId, RemoteTrackEvent, RemoteTrackId, TransportEvent, TransportNegotiated, TransportState, TransportStats},
 };
 
-use self::{bitrate_allocator::BitrateAllocator, local_track::EndpointLocalTrack, remote_track::EndpointRemoteTrack};
+use self::{bitrate_allocator::BitrateAllocator, kind_filter::PeerKindFilter, local_track::EndpointLocalTrack, remote_track::EndpointRemoteTrack};
 
 use super::{
-    EndpointAudioMixerEvent, EndpointAudioMixerReq, EndpointAudioMixerRes, EndpointCfg, EndpointEvent, EndpointMessageChannelReq, EndpointMessageChannelRes, EndpointReq, EndpointReqId, EndpointRes,
+    EndpointAudioMixerEvent, EndpointAudioMixerReq, EndpointAudioMixerRes, EndpointCfg, EndpointEvent, EndpointLocalTrackReq, EndpointLocalTrackRes, EndpointMessageChannelReq,
+    EndpointMessageChannelRes, EndpointRemoteTrackReq, EndpointRemoteTrackRes, EndpointReq, EndpointReqId, EndpointRes, MultiRoomPolicy,
 };
 
 mod bitrate_allocator;
+mod kind_filter;
 mod local_track;
 mod remote_track;
 
+pub use local_track::{PlayoutConfig, RelayGraceConfig};
+
 #[derive(num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
 #[repr(usize)]
 enum TaskType {
@@ -44,6 +56,8 @@ pub enum InternalOutput {
     RecordEvent(Instant, SessionRecordEvent),
     RpcRes(EndpointReqId, EndpointRes),
     Cluster(ClusterRoomHash, ClusterEndpointControl),
+    /// Endpoint must be closed, e.g. the room is closed by TTL
+    Close,
     OnResourceEmpty,
 }
 
@@ -55,10 +69,24 @@ pub struct EndpointInternal {
     wait_join: EndpointInternalWaitJoin,
     joined: Option<(ClusterRoomHash, RoomId, PeerId, Option<AudioMixerMode>)>,
     local_tracks_id: IndexMap2d<LocalTrackId, usize>,
+    /// Kind of local tracks, which is used for checking peer kind filter
+    local_tracks_kind: HashMap<LocalTrackId, MediaKind>,
     remote_tracks_id: IndexMap2d<RemoteTrackId, usize>,
+    /// Tracks which are rejected by track limits, their requests are answered with error until they are started again
+    rejected_local_tracks: HashSet<LocalTrackId>,
+    rejected_remote_tracks: HashSet<RemoteTrackId>,
     local_tracks: TaskSwitcherBranch<TaskGroup<local_track::Input, local_track::Output, EndpointLocalTrack, 4>, TaskGroupOutput<local_track::Output>>,
     remote_tracks: TaskSwitcherBranch<TaskGroup<remote_track::Input, remote_track::Output, EndpointRemoteTrack, 16>, TaskGroupOutput<remote_track::Output>>,
     bitrate_allocator: TaskSwitcherBranch<BitrateAllocator, bitrate_allocator::Output>,
+    kind_filter: PeerKindFilter,
+    /// Live config of the joined room
+    room_config: RoomConfig,
+    egress_budget: Option<EgressBudgetSlot>,
+    /// First connected time, max duration of the session is counted from it
+    connected_at: Option<Instant>,
+    max_duration_warned: bool,
+    /// Reason which is reported when the transport is disconnected
+    end_reason: Reason,
     queue: VecDeque<InternalOutput>,
     shutdown: bool,
     switcher: TaskSwitcher,
@@ -71,10 +99,19 @@ impl EndpointInternal {
             wait_join: None,
             joined: None,
             local_tracks_id: Default::default(),
+            local_tracks_kind: Default::default(),
             remote_tracks_id: Default::default(),
+            rejected_local_tracks: Default::default(),
+            rejected_remote_tracks: Default::default(),
             local_tracks: TaskSwitcherBranch::default(TaskType::LocalTracks),
             remote_tracks: TaskSwitcherBranch::default(TaskType::RemoteTracks),
             bitrate_allocator: TaskSwitcherBranch::new(BitrateAllocator::new(cfg.max_ingress_bitrate, cfg.max_ingress_bitrate), TaskType::BitrateAllocator),
+            kind_filter: Default::default(),
+            room_config: Default::default(),
+            egress_budget: cfg.egress_budget.as_ref().map(|budget| budget.slot()),
+            connected_at: None,
+            max_duration_warned: false,
+            end_reason: Reason::UserAction,
             queue: Default::default(),
             shutdown: false,
             switcher: TaskSwitcher::new(3),
@@ -86,6 +123,27 @@ impl EndpointInternal {
         self.bitrate_allocator.input(&mut self.switcher).on_tick();
         self.local_tracks.input(&mut self.switcher).on_tick(now);
         self.remote_tracks.input(&mut self.switcher).on_tick(now);
+        self.check_max_duration(now);
+    }
+
+    /// Warn the client before the session reaches max duration, then leave the room and close the session
+    fn check_max_duration(&mut self, now: Instant) {
+        let max_duration = return_if_none!(self.cfg.max_duration);
+        let connected_at = return_if_none!(self.connected_at);
+        if self.shutdown || self.end_reason == Reason::MaxDurationReached {
+            return;
+        }
+        let elapsed = now.saturating_duration_since(connected_at);
+        if elapsed >= max_duration {
+            log::info!("[EndpointInternal] session reached max duration {max_duration:?} => leave and close");
+            self.end_reason = Reason::MaxDurationReached;
+            self.leave_room(now);
+            self.queue.push_back(InternalOutput::Close);
+        } else if !self.max_duration_warned && elapsed + self.cfg.max_duration_warning >= max_duration {
+            self.max_duration_warned = true;
+            let remain = (max_duration - elapsed).as_secs().min(u8::MAX as u64) as u8;
+            self.queue.push_back(InternalOutput::Event(EndpointEvent::GoAway(remain, Some("max_duration".to_string()))));
+        }
     }
 
     pub fn on_shutdown(&mut self, now: Instant) {
@@ -150,10 +208,18 @@ impl EndpointInternal {
             TransportEvent::LocalTrack(track, event) => self.on_transport_local_track(now, track, event),
             TransportEvent::Stats(stats) => self.on_transport_stats(now, stats),
             TransportEvent::EgressBitrateEstimate(bitrate) => {
-                let bitrate2 = bitrate.min(self.cfg.max_egress_bitrate);
+                let mut bitrate2 = bitrate.min(self.max_egress_bitrate());
+                if let Some(budget) = self.egress_budget.as_mut() {
+                    bitrate2 = budget.allocate(bitrate2);
+                }
                 log::debug!("[EndpointInternal] limit egress bitrate {bitrate2}, rewrite from {bitrate}");
                 self.bitrate_allocator.input(&mut self.switcher).set_egress_estimate(bitrate2);
             }
+            TransportEvent::Rtt(rtt_ms) => {
+                log::debug!("[EndpointInternal] transport rtt {rtt_ms} ms");
+                self.bitrate_allocator.input(&mut self.switcher).set_egress_rtt(rtt_ms);
+            }
+            TransportEvent::Negotiated(negotiated) => self.on_transport_negotiated(now, negotiated),
         }
     }
 
@@ -164,6 +230,12 @@ impl EndpointInternal {
                     log::info!("[EndpointInternal] join_room({room}, {peer}) but in Connecting state => wait");
                     self.wait_join = Some((req_id, room, peer, meta, publish, subscribe, mixer));
                 }
+                Some(_) if self.cfg.multi_room == MultiRoomPolicy::Reject && self.is_in_other_room(&room) => {
+                    log::warn!("[EndpointInternal] join_room({room}, {peer}) but already in other room => reject");
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::JoinRoom(Err(RpcError::new2(EndpointErrors::EndpointAlreadyInRoom)))));
+                    self.queue.push_back(InternalOutput::Event(EndpointEvent::JoinRejected(peer, ClusterJoinRejectReason::AlreadyInRoom)));
+                }
                 _ => {
                     self.join_room(now, req_id, room, peer, meta, publish, subscribe, mixer);
                 }
@@ -195,13 +267,134 @@ impl EndpointInternal {
                         .push_back(InternalOutput::RpcRes(req_id, EndpointRes::UnsubscribePeer(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))));
                 }
             }
+            EndpointReq::BatchTrackSubscriptions { subscribe, unsubscribe } => {
+                if let Some((room, _, _, _)) = &self.joined {
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::BatchTrackSubscriptions(Ok(()))));
+                    self.queue
+                        .push_back(InternalOutput::Cluster(*room, ClusterEndpointControl::BatchTrackSubscriptions { subscribe, unsubscribe }));
+                } else {
+                    self.queue.push_back(InternalOutput::RpcRes(
+                        req_id,
+                        EndpointRes::BatchTrackSubscriptions(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom))),
+                    ));
+                }
+            }
+            EndpointReq::SetPeerKindFilter(peer, filter) => {
+                if self.joined.is_some() {
+                    log::info!("[EndpointInternal] set kind filter of peer {peer} to {filter:?}");
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::SetPeerKindFilter(Ok(()))));
+                    // local tracks which are viewing the peer with a filtered kind must stop forwarding
+                    for (track_id, kind) in self.local_tracks_kind.iter() {
+                        if filter.allow(*kind) {
+                            continue;
+                        }
+                        if let Some(index) = self.local_tracks_id.get1(track_id) {
+                            self.local_tracks.input(&mut self.switcher).on_event(now, *index, local_track::Input::PeerKindFiltered(peer.clone()));
+                        }
+                    }
+                    for event in self.kind_filter.set_filter(peer, filter) {
+                        self.queue.push_back(InternalOutput::Event(event));
+                    }
+                } else {
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::SetPeerKindFilter(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))));
+                }
+            }
+            EndpointReq::PauseRoom(paused) => {
+                if let Some((room, _, _, _)) = &self.joined {
+                    let control = if paused {
+                        ClusterEndpointControl::PauseRoom
+                    } else {
+                        ClusterEndpointControl::ResumeRoom
+                    };
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::PauseRoom(Ok(()))));
+                    self.queue.push_back(InternalOutput::Cluster(*room, control));
+                } else {
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::PauseRoom(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))));
+                }
+            }
+            EndpointReq::SetRoomLocked(locked) => {
+                if let Some((room, _, _, _)) = &self.joined {
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::SetRoomLocked(Ok(()))));
+                    self.queue.push_back(InternalOutput::Cluster(*room, ClusterEndpointControl::SetRoomLocked(locked)));
+                } else {
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::SetRoomLocked(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))));
+                }
+            }
+            EndpointReq::AdmitPeer(peer, admit) => {
+                if let Some((room, _, _, _)) = &self.joined {
+                    let control = if admit {
+                        ClusterEndpointControl::AdmitPeer(peer)
+                    } else {
+                        ClusterEndpointControl::RejectPeer(peer)
+                    };
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::AdmitPeer(Ok(()))));
+                    self.queue.push_back(InternalOutput::Cluster(*room, control));
+                } else {
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::AdmitPeer(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))));
+                }
+            }
+            EndpointReq::UpdateRoomConfig(patch) => {
+                let rejoin_fields = patch.rejoin_fields();
+                if !rejoin_fields.is_empty() {
+                    log::warn!("[EndpointInternal] room config fields {rejoin_fields:?} require rejoin => reject");
+                    let err = RpcError::new(EndpointErrors::RoomConfigRequireRejoin, &rejoin_fields.join(","));
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::UpdateRoomConfig(Err(err))));
+                } else if let Some((room, _, _, _)) = &self.joined {
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::UpdateRoomConfig(Ok(()))));
+                    self.queue.push_back(InternalOutput::Cluster(*room, ClusterEndpointControl::UpdateRoomConfig(patch)));
+                } else {
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::UpdateRoomConfig(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))));
+                }
+            }
             EndpointReq::RemoteTrack(track_id, req) => {
+                if self.rejected_remote_tracks.contains(&track_id) {
+                    log::warn!("[EndpointInternal] request to rejected remote track {track_id} => reject");
+                    let err = RpcError::new2(EndpointErrors::RemoteTrackRejected);
+                    let res = match req {
+                        EndpointRemoteTrackReq::Config(_) => EndpointRemoteTrackRes::Config(Err(err)),
+                        EndpointRemoteTrackReq::Mute(_) => EndpointRemoteTrackRes::Mute(Err(err)),
+                    };
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::RemoteTrack(track_id, res)));
+                    return;
+                }
                 let index = return_if_none!(self.remote_tracks_id.get1(&track_id));
                 self.remote_tracks.input(&mut self.switcher).on_event(now, *index, remote_track::Input::RpcReq(req_id, req));
             }
             EndpointReq::LocalTrack(track_id, req) => {
+                if self.rejected_local_tracks.contains(&track_id) {
+                    log::warn!("[EndpointInternal] request to rejected local track {track_id} => reject");
+                    let err = RpcError::new2(EndpointErrors::LocalTrackRejected);
+                    let res = match req {
+                        EndpointLocalTrackReq::Attach(..) => EndpointLocalTrackRes::Attach(Err(err)),
+                        EndpointLocalTrackReq::Detach() => EndpointLocalTrackRes::Detach(Err(err)),
+                        EndpointLocalTrackReq::Config(_) => EndpointLocalTrackRes::Config(Err(err)),
+                    };
+                    self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::LocalTrack(track_id, res)));
+                    return;
+                }
                 let index = return_if_none!(self.local_tracks_id.get1(&track_id));
-                self.local_tracks.input(&mut self.switcher).on_event(now, *index, local_track::Input::RpcReq(req_id, req));
+                let kind_filtered = match (&req, self.local_tracks_kind.get(&track_id)) {
+                    (EndpointLocalTrackReq::Attach(source, _), Some(kind)) => !self.kind_filter.allow(&source.peer, *kind),
+                    _ => false,
+                };
+                if matches!(req, EndpointLocalTrackReq::Attach(..)) && self.egress_budget.as_ref().is_some_and(|budget| budget.is_full()) {
+                    log::warn!("[EndpointInternal] node egress budget is full => reject attach local track {track_id}");
+                    let err = RpcError::new2(EndpointErrors::LocalTrackEgressBudgetFull);
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::LocalTrack(track_id, EndpointLocalTrackRes::Attach(Err(err)))));
+                } else if kind_filtered {
+                    log::warn!("[EndpointInternal] source kind is filtered => reject attach local track {track_id}");
+                    let err = RpcError::new2(EndpointErrors::LocalTrackKindFiltered);
+                    self.queue
+                        .push_back(InternalOutput::RpcRes(req_id, EndpointRes::LocalTrack(track_id, EndpointLocalTrackRes::Attach(Err(err)))));
+                } else {
+                    self.local_tracks.input(&mut self.switcher).on_event(now, *index, local_track::Input::RpcReq(req_id, req));
+                }
             }
             EndpointReq::AudioMixer(req) => match req {
                 EndpointAudioMixerReq::Attach(sources) => {
@@ -343,6 +536,7 @@ impl EndpointInternal {
             }
             TransportState::Connected(ip) => {
                 log::info!("[EndpointInternal] connected");
+                self.connected_at.get_or_insert(now);
                 let (pre_ts, pre_event) = pre_state.expect("Should have previous state");
                 if matches!(pre_event, TransportState::Reconnecting(_)) {
                     self.queue.push_back(InternalOutput::PeerEvent(
@@ -374,7 +568,10 @@ impl EndpointInternal {
                 log::info!("[EndpointInternal] disconnected {:?}", err);
                 self.queue.push_back(InternalOutput::PeerEvent(
                     now,
-                    peer_event::Event::Disconnected(peer_event::Disconnected { duration_ms: 0, reason: 0 }), //TODO provide correct reason
+                    peer_event::Event::Disconnected(peer_event::Disconnected {
+                        duration_ms: 0,
+                        reason: self.end_reason as i32,
+                    }),
                 ));
                 if self.cfg.record {
                     self.queue.push_back(InternalOutput::RecordEvent(now, SessionRecordEvent::Disconnected));
@@ -386,6 +583,15 @@ impl EndpointInternal {
 
     fn on_transport_remote_track(&mut self, now: Instant, track: RemoteTrackId, event: RemoteTrackEvent) {
         if let Some((name, _priority, meta)) = event.need_create() {
+            let max_publish = self.cfg.track_limits.max_publish;
+            if max_publish > 0 && self.remote_tracks.tasks() >= max_publish {
+                log::warn!("[EndpointInternal] remote track {:?} over publish limit {max_publish} => reject", track);
+                self.remote_tracks_id.remove1(&track);
+                self.rejected_remote_tracks.insert(track);
+                self.queue.push_back(InternalOutput::Event(EndpointEvent::RemoteMediaTrackRejected(track)));
+                return;
+            }
+            self.rejected_remote_tracks.remove(&track);
             log::info!("[EndpointInternal] create remote track {:?}", track);
             let room = self.joined.as_ref().map(|j| j.0);
             let index = self
@@ -400,10 +606,27 @@ impl EndpointInternal {
 
     fn on_transport_local_track(&mut self, now: Instant, track: LocalTrackId, event: LocalTrackEvent) {
         if let Some(kind) = event.need_create() {
+            let max_subscribe = self.cfg.track_limits.max_subscribe;
+            if max_subscribe > 0 && self.local_tracks_id.len() >= max_subscribe {
+                log::warn!("[EndpointInternal] local track {:?} over subscribe limit {max_subscribe} => reject", track);
+                self.rejected_local_tracks.insert(track);
+                self.queue.push_back(InternalOutput::Event(EndpointEvent::LocalMediaTrackRejected(track)));
+                return;
+            }
+            self.rejected_local_tracks.remove(&track);
             log::info!("[EndpointInternal] create local track {:?}", track);
             let room = self.joined.as_ref().map(|j| j.0);
-            let index = self.local_tracks.input(&mut self.switcher).add_task(EndpointLocalTrack::new(track, kind, room));
+            let index = self
+                .local_tracks
+                .input(&mut self.switcher)
+                .add_task(EndpointLocalTrack::new(track, kind, room, self.cfg.relay_grace, self.cfg.playout));
             self.local_tracks_id.insert(track, index);
+            self.local_tracks_kind.insert(track, kind);
+            if self.room_config.max_spatial.is_some() {
+                self.local_tracks
+                    .input(&mut self.switcher)
+                    .on_event(now, index, local_track::Input::RoomMaxSpatial(self.room_config.max_spatial));
+            }
 
             // We need to fire event here because local track never removed.
             // Inside local track we only fire attach or detach event
@@ -421,6 +644,19 @@ impl EndpointInternal {
 
     fn on_transport_stats(&mut self, _now: Instant, _stats: TransportStats) {}
 
+    fn on_transport_negotiated(&mut self, now: Instant, negotiated: TransportNegotiated) {
+        log::info!("[EndpointInternal] negotiated {:?}", negotiated);
+        self.queue.push_back(InternalOutput::PeerEvent(
+            now,
+            peer_event::Event::Negotiated(peer_event::Negotiated {
+                audio_codec: negotiated.audio_codec,
+                video_codec: negotiated.video_codec,
+                max_resolution: negotiated.max_resolution.map(|(width, height)| format!("{width}x{height}")),
+                layers: negotiated.layers,
+            }),
+        ));
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn join_room(&mut self, now: Instant, req_id: EndpointReqId, room: RoomId, peer: PeerId, meta: PeerMeta, publish: RoomInfoPublish, subscribe: RoomInfoSubscribe, mixer: Option<AudioMixerConfig>) {
         let room_hash = ClusterRoomHash::generate(&self.cfg.app, &room);
@@ -430,8 +666,10 @@ impl EndpointInternal {
         self.leave_room(now);
 
         self.joined = Some((room_hash, room.clone(), peer.clone(), mixer.as_ref().map(|m| m.mode)));
-        self.queue
-            .push_back(InternalOutput::Cluster(room_hash, ClusterEndpointControl::Join(peer.clone(), meta, publish, subscribe, mixer)));
+        self.queue.push_back(InternalOutput::Cluster(
+            room_hash,
+            ClusterEndpointControl::Join(self.cfg.app.app.clone(), room.clone(), peer.clone(), meta, publish, subscribe, mixer),
+        ));
         if self.cfg.record {
             self.queue
                 .push_back(InternalOutput::RecordEvent(now, SessionRecordEvent::JoinRoom(self.cfg.app.app.clone(), room.clone(), peer.clone())));
@@ -448,9 +686,16 @@ impl EndpointInternal {
         }
     }
 
+    /// Rejoin the current room is not counted as joining other room
+    fn is_in_other_room(&self, room: &RoomId) -> bool {
+        self.joined.as_ref().is_some_and(|(hash, ..)| *hash != ClusterRoomHash::generate(&self.cfg.app, room))
+    }
+
     fn leave_room(&mut self, now: Instant) {
         let (hash, room, peer, _) = return_if_none!(self.joined.take());
         log::info!("[EndpointInternal] leave_room({room}, {peer})");
+        self.kind_filter.clear();
+        self.set_room_config(now, RoomConfig::default());
 
         for (_track_id, index) in self.local_tracks_id.pairs() {
             self.local_tracks.input(&mut self.switcher).on_event(now, index, local_track::Input::LeaveRoom);
@@ -475,25 +720,99 @@ impl EndpointInternal {
         self.queue
             .push_back(InternalOutput::PeerEvent(now, peer_event::Event::Leave(peer_event::Leave { room: room.into(), peer: peer.into() })));
     }
+
+    /// Room config can only lower the endpoint config
+    fn max_egress_bitrate(&self) -> u64 {
+        self.room_config.max_bitrate.map_or(self.cfg.max_egress_bitrate, |max| max.min(self.cfg.max_egress_bitrate))
+    }
+
+    fn set_room_config(&mut self, now: Instant, config: RoomConfig) {
+        if self.room_config == config {
+            return;
+        }
+        let spatial_changed = self.room_config.max_spatial != config.max_spatial;
+        self.room_config = config;
+        let max_egress_bitrate = self.max_egress_bitrate();
+        self.bitrate_allocator.input(&mut self.switcher).set_egress_max(max_egress_bitrate);
+        if spatial_changed {
+            for (_track_id, index) in self.local_tracks_id.pairs() {
+                self.local_tracks.input(&mut self.switcher).on_event(now, index, local_track::Input::RoomMaxSpatial(config.max_spatial));
+            }
+        }
+    }
 }
 
 /// This block is for cluster related events
 impl EndpointInternal {
     pub fn on_cluster_event(&mut self, now: Instant, event: ClusterEndpointEvent) {
         match event {
+            ClusterEndpointEvent::JoinRejected(peer, reason) => {
+                if !self.joined.as_ref().is_some_and(|(_, _, joined_peer, _)| *joined_peer == peer) {
+                    return;
+                }
+                log::warn!("[EndpointInternal] join as {peer} rejected by room with reason {reason:?} => leave");
+                self.leave_room(now);
+                self.queue.push_back(InternalOutput::Event(EndpointEvent::JoinRejected(peer, reason)));
+            }
+            ClusterEndpointEvent::JoinPending(peer) => self.queue.push_back(InternalOutput::Event(EndpointEvent::JoinPending(peer))),
             ClusterEndpointEvent::PeerJoined(peer, meta) => self.queue.push_back(InternalOutput::Event(EndpointEvent::PeerJoined(peer, meta))),
             ClusterEndpointEvent::PeerLeaved(peer, meta) => self.queue.push_back(InternalOutput::Event(EndpointEvent::PeerLeaved(peer, meta))),
-            ClusterEndpointEvent::TrackStarted(peer, track, meta) => self.queue.push_back(InternalOutput::Event(EndpointEvent::PeerTrackStarted(peer, track, meta))),
-            ClusterEndpointEvent::TrackStopped(peer, track, meta) => self.queue.push_back(InternalOutput::Event(EndpointEvent::PeerTrackStopped(peer, track, meta))),
+            ClusterEndpointEvent::TrackStarted(peer, track, meta) => {
+                if self.kind_filter.on_track_started(&peer, &track, &meta) {
+                    self.queue.push_back(InternalOutput::Event(EndpointEvent::PeerTrackStarted(peer, track, meta)));
+                }
+            }
+            ClusterEndpointEvent::TrackStopped(peer, track, meta) => {
+                if self.kind_filter.on_track_stopped(&peer, &track, &meta) {
+                    self.queue.push_back(InternalOutput::Event(EndpointEvent::PeerTrackStopped(peer, track, meta)));
+                }
+            }
+            ClusterEndpointEvent::TrackMuted(peer, track, muted) => {
+                if self.kind_filter.on_track_muted(&peer, &track, muted) {
+                    self.queue.push_back(InternalOutput::Event(EndpointEvent::PeerTrackMuted(peer, track, muted)));
+                }
+            }
+            ClusterEndpointEvent::RoomPaused => self.queue.push_back(InternalOutput::Event(EndpointEvent::RoomPaused(true))),
+            ClusterEndpointEvent::RoomResumed => self.queue.push_back(InternalOutput::Event(EndpointEvent::RoomPaused(false))),
+            ClusterEndpointEvent::RoomClosingSoon(remain) => {
+                let remain = remain.as_secs().min(u8::MAX as u64) as u8;
+                self.queue.push_back(InternalOutput::Event(EndpointEvent::GoAway(remain, Some("room_ttl".to_string()))));
+            }
+            ClusterEndpointEvent::RoomClosed => {
+                log::info!("[EndpointInternal] room closed by ttl => leave and close");
+                self.leave_room(now);
+                self.queue.push_back(InternalOutput::Close);
+            }
+            ClusterEndpointEvent::RoomConfigChanged(config) => {
+                log::info!("[EndpointInternal] room config changed {config:?}");
+                self.set_room_config(now, config);
+                self.queue.push_back(InternalOutput::Event(EndpointEvent::RoomConfigChanged(config)));
+            }
             ClusterEndpointEvent::AudioMixer(event) => match event {
                 ClusterAudioMixerEvent::SlotSet(slot, peer, track) => self
                     .queue
                     .push_back(InternalOutput::Event(EndpointEvent::AudioMixer(EndpointAudioMixerEvent::SlotSet(slot, peer, track)))),
                 ClusterAudioMixerEvent::SlotUnset(slot) => self.queue.push_back(InternalOutput::Event(EndpointEvent::AudioMixer(EndpointAudioMixerEvent::SlotUnset(slot)))),
             },
+            ClusterEndpointEvent::MixerConfigConflict(mode, outputs) => {
+                log::warn!("[EndpointInternal] mixer config conflicts with room mixer {mode:?}/{outputs} => joined without mixer");
+                // room dont create mixer for this endpoint, so manual mixer controls must be rejected
+                if let Some((_, _, _, mixer_mode)) = self.joined.as_mut() {
+                    *mixer_mode = None;
+                }
+                self.queue
+                    .push_back(InternalOutput::Event(EndpointEvent::AudioMixer(EndpointAudioMixerEvent::ConfigConflict(mode, outputs))));
+            }
             ClusterEndpointEvent::RemoteTrack(track, event) => self.on_cluster_remote_track(now, track, event),
             ClusterEndpointEvent::LocalTrack(track, event) => self.on_cluster_local_track(now, track, event),
             ClusterEndpointEvent::MessageChannelData(key, from, message) => self.queue.push_back(InternalOutput::Event(EndpointEvent::ChannelMessage(key, from, message))),
+            ClusterEndpointEvent::MessageChannelError(key, err) => {
+                log::warn!("[EndpointInternal] message channel {} error {:?} => message dropped", key.0, err);
+                let err = match err {
+                    ClusterMessageChannelError::TooLarge(size) => RpcError::new(EndpointErrors::MessageChannelTooLarge, &format!("message {size} bytes is too large")),
+                };
+                self.queue.push_back(InternalOutput::Event(EndpointEvent::ChannelError(key, err)));
+            }
         }
     }
 
@@ -549,6 +868,9 @@ impl EndpointInternal {
             remote_track::Output::RecordEvent(ts, event) => {
                 self.queue.push_back(InternalOutput::RecordEvent(ts, event));
             }
+            remote_track::Output::Muted(room, track, muted) => {
+                self.queue.push_back(InternalOutput::Cluster(room, ClusterEndpointControl::SetTrackMuted(track, muted)));
+            }
         }
     }
 
@@ -568,21 +890,25 @@ impl EndpointInternal {
             local_track::Output::RpcRes(req_id, res) => {
                 self.queue.push_back(InternalOutput::RpcRes(req_id, EndpointRes::LocalTrack(id, res)));
             }
-            local_track::Output::Bind(kind, priority) => {
-                log::info!("[EndpointInternal] local track bind {kind} priority {priority}");
+            local_track::Output::Bind(kind, priority, bitrate_priority) => {
+                log::info!("[EndpointInternal] local track bind {kind} priority {priority} bitrate priority {bitrate_priority:?}");
                 if kind.is_video() {
-                    self.bitrate_allocator.input(&mut self.switcher).set_egress_video_track(id, priority);
+                    self.bitrate_allocator.input(&mut self.switcher).set_egress_video_track(id, priority, bitrate_priority);
+                } else {
+                    self.bitrate_allocator.input(&mut self.switcher).set_egress_audio_track(id);
                 }
             }
-            local_track::Output::Updated(kind, priority) => {
+            local_track::Output::Updated(kind, priority, bitrate_priority) => {
                 if kind.is_video() {
-                    self.bitrate_allocator.input(&mut self.switcher).set_egress_video_track(id, priority);
+                    self.bitrate_allocator.input(&mut self.switcher).set_egress_video_track(id, priority, bitrate_priority);
                 }
             }
             local_track::Output::Unbind(kind) => {
                 log::info!("[EndpointInternal] local track unbind {kind}");
                 if kind.is_video() {
                     self.bitrate_allocator.input(&mut self.switcher).del_egress_video_track(id);
+                } else {
+                    self.bitrate_allocator.input(&mut self.switcher).del_egress_audio_track(id);
                 }
             }
             local_track::Output::PeerEvent(ts, event) => {
@@ -591,6 +917,7 @@ impl EndpointInternal {
             local_track::Output::OnResourceEmpty => {
                 self.local_tracks.input(&mut self.switcher).remove_task(index);
                 self.local_tracks_id.remove1(&id);
+                self.local_tracks_kind.remove(&id);
             }
         }
     }
@@ -626,20 +953,34 @@ impl Drop for EndpointInternal {
 mod tests {
     use std::{
         net::{IpAddr, Ipv4Addr},
-        time::Instant,
+        sync::Arc,
+        time::{Duration, Instant},
     };
 
     use media_server_protocol::{
-        endpoint::{PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackMeta},
+        endpoint::{BitrateControlMode, BitratePriority, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackKindFilter, TrackMeta, TrackSource},
+        media::MediaKind,
         protobuf::shared::Kind,
     };
-    use media_server_protocol::{multi_tenancy::AppContext, protobuf::cluster_connector::peer_event};
+    use media_server_protocol::{
+        multi_tenancy::AppContext,
+        protobuf::cluster_connector::peer_event::{self, disconnected::Reason},
+        transport::RpcError,
+    };
+    use media_server_utils::EgressBudget;
     use sans_io_runtime::TaskSwitcherChild;
 
     use crate::{
-        cluster::{ClusterEndpointControl, ClusterRemoteTrackControl, ClusterRoomHash},
-        endpoint::{internal::InternalOutput, EndpointCfg, EndpointReq, EndpointRes},
-        transport::{RemoteTrackEvent, TransportEvent, TransportState},
+        cluster::{
+            ClusterEndpointControl, ClusterEndpointEvent, ClusterJoinRejectReason, ClusterLocalTrackControl, ClusterMessageChannelError, ClusterRemoteTrackControl, ClusterRoomHash, RoomConfig,
+            RoomConfigPatch,
+        },
+        endpoint::{
+            internal::InternalOutput, EndpointCfg, EndpointEvent, EndpointLocalTrackConfig, EndpointLocalTrackReq, EndpointLocalTrackRes, EndpointRemoteTrackConfig, EndpointRemoteTrackReq,
+            EndpointRemoteTrackRes, EndpointReq, EndpointRes, MessageChannelLabel, MultiRoomPolicy, TrackLimits,
+        },
+        errors::EndpointErrors,
+        transport::{LocalTrackEvent, RemoteTrackEvent, TransportEvent, TransportState},
     };
 
     use super::EndpointInternal;
@@ -652,6 +993,14 @@ mod tests {
             max_egress_bitrate: 2_000_000,
             max_ingress_bitrate: 2_000_000,
             record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits: Default::default(),
+            multi_room: Default::default(),
+            egress_budget: None,
+            max_duration: None,
+            max_duration_warning: Default::default(),
         });
 
         let remote = IpAddr::V4(Ipv4Addr::LOCALHOST);
@@ -685,7 +1034,10 @@ mod tests {
         let room_hash = ClusterRoomHash::generate(&app, &room);
         assert_eq!(
             internal.pop_output(now),
-            Some(InternalOutput::Cluster(room_hash, ClusterEndpointControl::Join(peer.clone(), meta, publish, subscribe, None)))
+            Some(InternalOutput::Cluster(
+                room_hash,
+                ClusterEndpointControl::Join(app.app.clone(), room.clone(), peer.clone(), meta, publish, subscribe, None)
+            ))
         );
         assert_eq!(
             internal.pop_output(now),
@@ -764,6 +1116,73 @@ mod tests {
         assert_eq!(internal.pop_output(now), None);
     }
 
+    #[test_log::test]
+    fn room_config_lower_max_bitrate() {
+        let mut internal = EndpointInternal::new(EndpointCfg {
+            app: AppContext::root_app(),
+            max_egress_bitrate: 2_000_000,
+            max_ingress_bitrate: 2_000_000,
+            record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits: Default::default(),
+            multi_room: Default::default(),
+            egress_budget: None,
+            max_duration: None,
+            max_duration_warning: Default::default(),
+        });
+        let now = Instant::now();
+        assert_eq!(internal.max_egress_bitrate(), 2_000_000);
+
+        let config = |max_bitrate| RoomConfig { max_bitrate, max_spatial: None };
+        internal.on_cluster_event(now, ClusterEndpointEvent::RoomConfigChanged(config(Some(500_000))));
+        assert_eq!(internal.max_egress_bitrate(), 500_000);
+
+        // room config can't raise the endpoint ceiling
+        internal.on_cluster_event(now, ClusterEndpointEvent::RoomConfigChanged(config(Some(5_000_000))));
+        assert_eq!(internal.max_egress_bitrate(), 2_000_000);
+
+        internal.on_cluster_event(now, ClusterEndpointEvent::RoomConfigChanged(config(Some(500_000))));
+        internal.on_transport_event(now, TransportEvent::EgressBitrateEstimate(1_500_000));
+        internal.on_tick(now);
+        while let Some(out) = internal.pop_output(now) {
+            if let InternalOutput::Event(EndpointEvent::BweConfig { current, desired }) = out {
+                assert!(current <= 500_000 && desired <= 500_000, "{current} {desired}");
+            }
+        }
+    }
+
+    //Room rejects a published message => client is notified with error event
+    #[test_log::test]
+    fn message_channel_error_to_client() {
+        let mut internal = EndpointInternal::new(EndpointCfg {
+            app: AppContext::root_app(),
+            max_egress_bitrate: 2_000_000,
+            max_ingress_bitrate: 2_000_000,
+            record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits: Default::default(),
+            multi_room: Default::default(),
+            egress_budget: None,
+            max_duration: None,
+            max_duration_warning: Default::default(),
+        });
+        let now = Instant::now();
+        let label = MessageChannelLabel("chat".to_string());
+        internal.on_cluster_event(now, ClusterEndpointEvent::MessageChannelError(label.clone(), ClusterMessageChannelError::TooLarge(70000)));
+        assert_eq!(
+            internal.pop_output(now),
+            Some(InternalOutput::Event(EndpointEvent::ChannelError(
+                label,
+                RpcError::new(EndpointErrors::MessageChannelTooLarge, "message 70000 bytes is too large")
+            )))
+        );
+        assert_eq!(internal.pop_output(now), None);
+    }
+
     #[test_log::test]
     fn test_join_overwrite_auto_leave() {
         let app = AppContext::root_app();
@@ -772,6 +1191,14 @@ mod tests {
             max_egress_bitrate: 2_000_000,
             max_ingress_bitrate: 2_000_000,
             record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits: Default::default(),
+            multi_room: Default::default(),
+            egress_budget: None,
+            max_duration: None,
+            max_duration_warning: Default::default(),
         });
 
         let remote = IpAddr::V4(Ipv4Addr::LOCALHOST);
@@ -812,7 +1239,7 @@ mod tests {
             internal.pop_output(now),
             Some(InternalOutput::Cluster(
                 room1_hash,
-                ClusterEndpointControl::Join(peer.clone(), meta.clone(), publish.clone(), subscribe.clone(), None),
+                ClusterEndpointControl::Join(app.app.clone(), room1.clone(), peer.clone(), meta.clone(), publish.clone(), subscribe.clone(), None),
             ))
         );
         assert_eq!(
@@ -855,7 +1282,7 @@ mod tests {
             internal.pop_output(now),
             Some(InternalOutput::Cluster(
                 room2_hash,
-                ClusterEndpointControl::Join(peer.clone(), meta.clone(), publish.clone(), subscribe.clone(), None),
+                ClusterEndpointControl::Join(app.app.clone(), room2.clone(), peer.clone(), meta.clone(), publish.clone(), subscribe.clone(), None),
             ))
         );
         assert_eq!(
@@ -871,12 +1298,528 @@ mod tests {
         assert_eq!(internal.pop_output(now), None);
     }
 
+    #[test_log::test]
+    fn test_peer_kind_filter_audio_only_then_video() {
+        let app = AppContext::root_app();
+        let mut internal = EndpointInternal::new(EndpointCfg {
+            app: app.clone(),
+            max_egress_bitrate: 2_000_000,
+            max_ingress_bitrate: 2_000_000,
+            record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits: Default::default(),
+            multi_room: Default::default(),
+            egress_budget: None,
+            max_duration: None,
+            max_duration_warning: Default::default(),
+        });
+
+        let now = Instant::now();
+        let remote_peer: PeerId = "remote".into();
+
+        //set filter before join room should fail
+        internal.on_transport_rpc(now, 0.into(), EndpointReq::SetPeerKindFilter(remote_peer.clone(), TrackKindFilter::audio_only()));
+        assert!(matches!(internal.pop_output(now), Some(InternalOutput::RpcRes(_, EndpointRes::SetPeerKindFilter(Err(_))))));
+
+        internal.on_transport_event(now, TransportEvent::State(TransportState::Connected(IpAddr::V4(Ipv4Addr::LOCALHOST))));
+        let subscribe = RoomInfoSubscribe { peers: true, tracks: true };
+        let publish = RoomInfoPublish { peer: true, tracks: true };
+        let meta = PeerMeta { metadata: None, extra_data: None };
+        internal.on_transport_rpc(now, 1.into(), EndpointReq::JoinRoom("room".into(), "peer".into(), meta, publish, subscribe, None));
+        while internal.pop_output(now).is_some() {}
+
+        internal.on_transport_rpc(now, 2.into(), EndpointReq::SetPeerKindFilter(remote_peer.clone(), TrackKindFilter::audio_only()));
+        assert_eq!(internal.pop_output(now), Some(InternalOutput::RpcRes(2.into(), EndpointRes::SetPeerKindFilter(Ok(())))));
+        assert_eq!(internal.pop_output(now), None);
+
+        //only audio is announced, video is hidden
+        let audio_meta = TrackMeta::default_audio();
+        let video_meta = TrackMeta::default_video();
+        internal.on_cluster_event(now, ClusterEndpointEvent::TrackStarted(remote_peer.clone(), "audio_main".into(), audio_meta.clone()));
+        internal.on_cluster_event(now, ClusterEndpointEvent::TrackStarted(remote_peer.clone(), "video_main".into(), video_meta.clone()));
+        internal.on_cluster_event(now, ClusterEndpointEvent::TrackMuted(remote_peer.clone(), "video_main".into(), true));
+        assert_eq!(
+            internal.pop_output(now),
+            Some(InternalOutput::Event(EndpointEvent::PeerTrackStarted(remote_peer.clone(), "audio_main".into(), audio_meta.clone())))
+        );
+        assert_eq!(internal.pop_output(now), None);
+
+        //enable video again, the hidden video track is announced with latest muted state
+        let mut video_muted_meta = video_meta.clone();
+        video_muted_meta.muted = true;
+        internal.on_transport_rpc(now, 3.into(), EndpointReq::SetPeerKindFilter(remote_peer.clone(), TrackKindFilter::default()));
+        assert_eq!(internal.pop_output(now), Some(InternalOutput::RpcRes(3.into(), EndpointRes::SetPeerKindFilter(Ok(())))));
+        assert_eq!(
+            internal.pop_output(now),
+            Some(InternalOutput::Event(EndpointEvent::PeerTrackStarted(
+                remote_peer.clone(),
+                "video_main".into(),
+                video_muted_meta.clone()
+            )))
+        );
+        assert_eq!(internal.pop_output(now), None);
+
+        //switch to video only will stop audio
+        internal.on_transport_rpc(now, 4.into(), EndpointReq::SetPeerKindFilter(remote_peer.clone(), TrackKindFilter::video_only()));
+        assert_eq!(internal.pop_output(now), Some(InternalOutput::RpcRes(4.into(), EndpointRes::SetPeerKindFilter(Ok(())))));
+        assert_eq!(
+            internal.pop_output(now),
+            Some(InternalOutput::Event(EndpointEvent::PeerTrackStopped(remote_peer.clone(), "audio_main".into(), audio_meta)))
+        );
+        assert_eq!(internal.pop_output(now), None);
+    }
+
+    fn budget_endpoint(budget: &Arc<EgressBudget>, now: Instant) -> EndpointInternal {
+        let mut internal = EndpointInternal::new(EndpointCfg {
+            app: AppContext::root_app(),
+            max_egress_bitrate: 2_000_000,
+            max_ingress_bitrate: 2_000_000,
+            record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits: Default::default(),
+            multi_room: Default::default(),
+            egress_budget: Some(budget.clone()),
+            max_duration: None,
+            max_duration_warning: Default::default(),
+        });
+        internal.on_transport_event(now, TransportEvent::State(TransportState::Connected(IpAddr::V4(Ipv4Addr::LOCALHOST))));
+        let meta = PeerMeta { metadata: None, extra_data: None };
+        internal.on_transport_rpc(
+            now,
+            0.into(),
+            EndpointReq::JoinRoom(
+                "room".into(),
+                "peer".into(),
+                meta,
+                RoomInfoPublish { peer: false, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            ),
+        );
+        internal.on_transport_event(now, TransportEvent::LocalTrack(0.into(), LocalTrackEvent::Started(MediaKind::Video)));
+        while internal.pop_output(now).is_some() {}
+        internal
+    }
+
+    fn attach_req() -> EndpointReq {
+        let source = TrackSource {
+            peer: "remote".into(),
+            track: "video_main".into(),
+        };
+        let config = EndpointLocalTrackConfig {
+            priority: 1.into(),
+            max_spatial: 2,
+            max_temporal: 2,
+            min_spatial: None,
+            min_temporal: None,
+            bitrate_priority: BitratePriority::Camera,
+            smooth_playout: false,
+        };
+        EndpointReq::LocalTrack(0.into(), EndpointLocalTrackReq::Attach(source, config))
+    }
+
+    /// Return the bwe current bitrate after the estimate
+    fn drive_egress(internal: &mut EndpointInternal, now: Instant, estimate: u64) -> Option<u64> {
+        internal.on_transport_event(now, TransportEvent::EgressBitrateEstimate(estimate));
+        internal.on_tick(now);
+        let mut current = None;
+        while let Some(out) = internal.pop_output(now) {
+            if let InternalOutput::Event(EndpointEvent::BweConfig { current: bitrate, .. }) = out {
+                current = Some(bitrate);
+            }
+        }
+        current
+    }
+
+    #[test_log::test]
+    fn node_egress_budget_lower_all_sessions() {
+        let now = Instant::now();
+        let budget = EgressBudget::new(3_000_000);
+        let mut session1 = budget_endpoint(&budget, now);
+        let mut session2 = budget_endpoint(&budget, now);
+        let mut session3 = budget_endpoint(&budget, now);
+
+        session1.on_transport_rpc(now, 1.into(), attach_req());
+        assert!(matches!(
+            session1.pop_output(now),
+            Some(InternalOutput::RpcRes(_, EndpointRes::LocalTrack(_, EndpointLocalTrackRes::Attach(Ok(())))))
+        ));
+        assert_eq!(drive_egress(&mut session1, now, 1_500_000), Some(1_500_000));
+
+        session2.on_transport_rpc(now, 1.into(), attach_req());
+        assert!(matches!(
+            session2.pop_output(now),
+            Some(InternalOutput::RpcRes(_, EndpointRes::LocalTrack(_, EndpointLocalTrackRes::Attach(Ok(())))))
+        ));
+        // demand 3Mbps is over 90% of cap => both sessions get lower bitrate, so lower layers are forwarded
+        assert_eq!(drive_egress(&mut session2, now, 1_500_000), Some(1_350_000));
+        assert_eq!(drive_egress(&mut session1, now, 1_500_000), Some(1_350_000));
+
+        // node is full, new subscription is refused
+        session3.on_transport_rpc(now, 1.into(), attach_req());
+        assert_eq!(
+            session3.pop_output(now),
+            Some(InternalOutput::RpcRes(
+                1.into(),
+                EndpointRes::LocalTrack(0.into(), EndpointLocalTrackRes::Attach(Err(RpcError::new2(EndpointErrors::LocalTrackEgressBudgetFull))))
+            ))
+        );
+
+        // after a session is gone the others recover
+        drop(session2);
+        assert_eq!(drive_egress(&mut session1, now, 1_500_000), Some(1_500_000));
+    }
+
+    #[test_log::test]
+    fn peer_kind_filter_stop_forwarding() {
+        let now = Instant::now();
+        let budget = EgressBudget::new(100_000_000);
+        let mut internal = budget_endpoint(&budget, now);
+
+        internal.on_transport_rpc(now, 1.into(), attach_req());
+        assert!(matches!(
+            internal.pop_output(now),
+            Some(InternalOutput::RpcRes(_, EndpointRes::LocalTrack(_, EndpointLocalTrackRes::Attach(Ok(())))))
+        ));
+        while internal.pop_output(now).is_some() {}
+
+        // video of remote peer is filtered out => the local video track which is viewing it is detached
+        internal.on_transport_rpc(now, 2.into(), EndpointReq::SetPeerKindFilter("remote".into(), TrackKindFilter::audio_only()));
+        assert_eq!(internal.pop_output(now), Some(InternalOutput::RpcRes(2.into(), EndpointRes::SetPeerKindFilter(Ok(())))));
+        let mut unsubscribed = false;
+        while let Some(out) = internal.pop_output(now) {
+            if let InternalOutput::Cluster(_, ClusterEndpointControl::LocalTrack(track, ClusterLocalTrackControl::Unsubscribe)) = out {
+                assert_eq!(track, 0.into());
+                unsubscribed = true;
+            }
+        }
+        assert!(unsubscribed);
+
+        // view it again is rejected until video is enabled
+        internal.on_transport_rpc(now, 3.into(), attach_req());
+        assert_eq!(
+            internal.pop_output(now),
+            Some(InternalOutput::RpcRes(
+                3.into(),
+                EndpointRes::LocalTrack(0.into(), EndpointLocalTrackRes::Attach(Err(RpcError::new2(EndpointErrors::LocalTrackKindFiltered))))
+            ))
+        );
+
+        internal.on_transport_rpc(now, 4.into(), EndpointReq::SetPeerKindFilter("remote".into(), TrackKindFilter::default()));
+        assert_eq!(internal.pop_output(now), Some(InternalOutput::RpcRes(4.into(), EndpointRes::SetPeerKindFilter(Ok(())))));
+        internal.on_transport_rpc(now, 5.into(), attach_req());
+        assert!(matches!(
+            internal.pop_output(now),
+            Some(InternalOutput::RpcRes(_, EndpointRes::LocalTrack(_, EndpointLocalTrackRes::Attach(Ok(())))))
+        ));
+    }
+
+    #[test_log::test]
+    fn high_rtt_lowers_desired_bitrate() {
+        let now = Instant::now();
+        let budget = EgressBudget::new(100_000_000);
+        let mut internal = budget_endpoint(&budget, now);
+        internal.on_transport_rpc(now, 1.into(), attach_req());
+        assert_eq!(drive_egress(&mut internal, now, 1_000_000), Some(1_000_000));
+
+        let desired = |internal: &mut EndpointInternal, rtt_ms| {
+            internal.on_transport_event(now, TransportEvent::Rtt(rtt_ms));
+            internal.on_tick(now);
+            let mut bitrate = None;
+            while let Some(out) = internal.pop_output(now) {
+                if let InternalOutput::Cluster(_, ClusterEndpointControl::LocalTrack(_, ClusterLocalTrackControl::DesiredBitrate(desired))) = out {
+                    bitrate = Some(desired);
+                }
+            }
+            bitrate
+        };
+
+        // rtt reaches the egress allocator, track target is lowered and sent to room
+        assert_eq!(desired(&mut internal, 50), None);
+        assert_eq!(desired(&mut internal, 400), Some(800_000));
+        assert_eq!(desired(&mut internal, 50), Some(1_000_000));
+    }
+
+    fn limited_endpoint(track_limits: TrackLimits, now: Instant) -> EndpointInternal {
+        joined_endpoint(track_limits, MultiRoomPolicy::default(), now)
+    }
+
+    /// Endpoint which is connected and joined `room` as `peer`, outputs are drained
+    fn joined_endpoint(track_limits: TrackLimits, multi_room: MultiRoomPolicy, now: Instant) -> EndpointInternal {
+        let mut internal = EndpointInternal::new(EndpointCfg {
+            app: AppContext::root_app(),
+            max_egress_bitrate: 2_000_000,
+            max_ingress_bitrate: 2_000_000,
+            record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits,
+            multi_room,
+            egress_budget: None,
+            max_duration: None,
+            max_duration_warning: Default::default(),
+        });
+        internal.on_transport_event(now, TransportEvent::State(TransportState::Connected(IpAddr::V4(Ipv4Addr::LOCALHOST))));
+        let meta = PeerMeta { metadata: None, extra_data: None };
+        internal.on_transport_rpc(
+            now,
+            0.into(),
+            EndpointReq::JoinRoom(
+                "room".into(),
+                "peer".into(),
+                meta,
+                RoomInfoPublish { peer: false, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            ),
+        );
+        while internal.pop_output(now).is_some() {}
+        internal
+    }
+
+    fn drain(internal: &mut EndpointInternal, now: Instant) -> Vec<InternalOutput> {
+        let mut outputs = vec![];
+        while let Some(out) = internal.pop_output(now) {
+            outputs.push(out);
+        }
+        outputs
+    }
+
+    //Room controls are forwarded to room when joined, config fields which require rejoin are rejected with the field names
+    #[test_log::test]
+    fn room_controls_forward_or_reject() {
+        let now = Instant::now();
+        let room = ClusterRoomHash::generate(&AppContext::root_app(), &"room".into());
+        let mut internal = joined_endpoint(TrackLimits::default(), MultiRoomPolicy::default(), now);
+
+        internal.on_transport_rpc(now, 1.into(), EndpointReq::PauseRoom(true));
+        internal.on_transport_rpc(now, 2.into(), EndpointReq::AdmitPeer("guest".into(), false));
+        assert_eq!(
+            drain(&mut internal, now),
+            vec![
+                InternalOutput::RpcRes(1.into(), EndpointRes::PauseRoom(Ok(()))),
+                InternalOutput::Cluster(room, ClusterEndpointControl::PauseRoom),
+                InternalOutput::RpcRes(2.into(), EndpointRes::AdmitPeer(Ok(()))),
+                InternalOutput::Cluster(room, ClusterEndpointControl::RejectPeer("guest".into())),
+            ]
+        );
+
+        let patch = RoomConfigPatch {
+            max_bitrate: Some(500_000),
+            record: Some(true),
+            ..Default::default()
+        };
+        internal.on_transport_rpc(now, 3.into(), EndpointReq::UpdateRoomConfig(patch));
+        assert_eq!(
+            drain(&mut internal, now),
+            vec![InternalOutput::RpcRes(
+                3.into(),
+                EndpointRes::UpdateRoomConfig(Err(RpcError::new(EndpointErrors::RoomConfigRequireRejoin, "record")))
+            )]
+        );
+
+        let config = RoomConfig {
+            max_bitrate: Some(500_000),
+            max_spatial: None,
+        };
+        internal.on_cluster_event(now, ClusterEndpointEvent::RoomConfigChanged(config));
+        assert!(drain(&mut internal, now).contains(&InternalOutput::Event(EndpointEvent::RoomConfigChanged(config))));
+
+        internal.on_transport_rpc(now, 4.into(), EndpointReq::LeaveRoom);
+        drain(&mut internal, now);
+        internal.on_transport_rpc(now, 5.into(), EndpointReq::SetRoomLocked(true));
+        assert_eq!(
+            drain(&mut internal, now),
+            vec![InternalOutput::RpcRes(5.into(), EndpointRes::SetRoomLocked(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom))))]
+        );
+    }
+
+    #[test_log::test]
+    fn join_other_room_with_policy() {
+        let now = Instant::now();
+        let app = AppContext::root_app();
+        let join = |room: &str| {
+            EndpointReq::JoinRoom(
+                room.into(),
+                "peer".into(),
+                PeerMeta { metadata: None, extra_data: None },
+                RoomInfoPublish { peer: false, tracks: false },
+                RoomInfoSubscribe { peers: false, tracks: false },
+                None,
+            )
+        };
+        let room_hash = ClusterRoomHash::generate(&app, &"room".into());
+        let room2_hash = ClusterRoomHash::generate(&app, &"room2".into());
+
+        // reject keeps the endpoint in the current room
+        let mut internal = joined_endpoint(TrackLimits::default(), MultiRoomPolicy::Reject, now);
+        internal.on_transport_rpc(now, 1.into(), join("room2"));
+        assert_eq!(
+            drain(&mut internal, now),
+            vec![
+                InternalOutput::RpcRes(1.into(), EndpointRes::JoinRoom(Err(RpcError::new2(EndpointErrors::EndpointAlreadyInRoom)))),
+                InternalOutput::Event(EndpointEvent::JoinRejected("peer".into(), ClusterJoinRejectReason::AlreadyInRoom)),
+            ]
+        );
+        internal.on_transport_rpc(now, 2.into(), EndpointReq::SubscribePeer("peer2".into()));
+        assert_eq!(drain(&mut internal, now)[1], InternalOutput::Cluster(room_hash, ClusterEndpointControl::SubscribePeer("peer2".into())));
+
+        // rejoin the same room is allowed
+        internal.on_transport_rpc(now, 3.into(), join("room"));
+        assert_eq!(drain(&mut internal, now)[0], InternalOutput::RpcRes(3.into(), EndpointRes::JoinRoom(Ok(()))));
+
+        // switch leaves the current room then joins the new one
+        let mut internal = joined_endpoint(TrackLimits::default(), MultiRoomPolicy::Switch, now);
+        internal.on_transport_rpc(now, 1.into(), join("room2"));
+        let outputs = drain(&mut internal, now);
+        assert_eq!(outputs[0], InternalOutput::RpcRes(1.into(), EndpointRes::JoinRoom(Ok(()))));
+        let leave = outputs.iter().position(|out| *out == InternalOutput::Cluster(room_hash, ClusterEndpointControl::Leave));
+        let join = outputs
+            .iter()
+            .position(|out| matches!(out, InternalOutput::Cluster(hash, ClusterEndpointControl::Join(..)) if *hash == room2_hash));
+        assert!(leave.is_some() && leave < join, "{outputs:?}");
+    }
+
+    #[test_log::test]
+    fn publish_over_limit_rejected() {
+        let now = Instant::now();
+        let mut internal = limited_endpoint(TrackLimits { max_publish: 1, max_subscribe: 0 }, now);
+        let started = |name: &str| RemoteTrackEvent::Started {
+            name: name.to_string(),
+            priority: 1.into(),
+            meta: TrackMeta::default_audio(),
+        };
+
+        internal.on_transport_event(now, TransportEvent::RemoteTrack(0.into(), started("audio_main")));
+        assert!(!drain(&mut internal, now).contains(&InternalOutput::Event(EndpointEvent::RemoteMediaTrackRejected(0.into()))));
+
+        internal.on_transport_event(now, TransportEvent::RemoteTrack(1.into(), started("audio_second")));
+        assert_eq!(drain(&mut internal, now), vec![InternalOutput::Event(EndpointEvent::RemoteMediaTrackRejected(1.into()))]);
+
+        // existing track still works, requests to rejected track are answered with error
+        let config = || EndpointRemoteTrackConfig {
+            priority: 2.into(),
+            control: BitrateControlMode::MaxBitrate,
+        };
+        internal.on_transport_rpc(now, 1.into(), EndpointReq::RemoteTrack(0.into(), EndpointRemoteTrackReq::Config(config())));
+        assert!(drain(&mut internal, now).contains(&InternalOutput::RpcRes(1.into(), EndpointRes::RemoteTrack(0.into(), EndpointRemoteTrackRes::Config(Ok(()))))));
+        internal.on_transport_rpc(now, 2.into(), EndpointReq::RemoteTrack(1.into(), EndpointRemoteTrackReq::Config(config())));
+        let err = RpcError::new2(EndpointErrors::RemoteTrackRejected);
+        assert_eq!(
+            drain(&mut internal, now),
+            vec![InternalOutput::RpcRes(2.into(), EndpointRes::RemoteTrack(1.into(), EndpointRemoteTrackRes::Config(Err(err))))]
+        );
+    }
+
+    #[test_log::test]
+    fn subscribe_over_limit_rejected() {
+        let now = Instant::now();
+        let mut internal = limited_endpoint(TrackLimits { max_publish: 0, max_subscribe: 1 }, now);
+
+        internal.on_transport_event(now, TransportEvent::LocalTrack(0.into(), LocalTrackEvent::Started(MediaKind::Video)));
+        assert!(!drain(&mut internal, now).contains(&InternalOutput::Event(EndpointEvent::LocalMediaTrackRejected(0.into()))));
+
+        internal.on_transport_event(now, TransportEvent::LocalTrack(1.into(), LocalTrackEvent::Started(MediaKind::Video)));
+        assert_eq!(drain(&mut internal, now), vec![InternalOutput::Event(EndpointEvent::LocalMediaTrackRejected(1.into()))]);
+
+        // existing track still works, requests to rejected track are answered with error
+        internal.on_transport_rpc(now, 1.into(), attach_req());
+        assert!(drain(&mut internal, now).contains(&InternalOutput::RpcRes(1.into(), EndpointRes::LocalTrack(0.into(), EndpointLocalTrackRes::Attach(Ok(()))))));
+        let EndpointReq::LocalTrack(_, attach) = attach_req() else { panic!("Should be local track req") };
+        internal.on_transport_rpc(now, 2.into(), EndpointReq::LocalTrack(1.into(), attach));
+        let err = RpcError::new2(EndpointErrors::LocalTrackRejected);
+        assert_eq!(
+            drain(&mut internal, now),
+            vec![InternalOutput::RpcRes(2.into(), EndpointRes::LocalTrack(1.into(), EndpointLocalTrackRes::Attach(Err(err))))]
+        );
+    }
+
     //TODO single local track, join leave room
     //TODO multi local tracks, join leave room
     //TODO single remote track, join leave room
 
     //TODO multi remote tracks, join leave room
     //TODO both local and remote tracks, join leave room
+    #[test_log::test]
+    fn max_duration_warn_then_close() {
+        let app = AppContext::root_app();
+        let mut internal = EndpointInternal::new(EndpointCfg {
+            app: app.clone(),
+            max_egress_bitrate: 2_000_000,
+            max_ingress_bitrate: 2_000_000,
+            record: false,
+            metrics: false,
+            relay_grace: Default::default(),
+            playout: Default::default(),
+            track_limits: Default::default(),
+            multi_room: Default::default(),
+            egress_budget: None,
+            max_duration: Some(Duration::from_secs(10)),
+            max_duration_warning: Duration::from_secs(3),
+        });
+        let drain = |internal: &mut EndpointInternal, now: Instant| {
+            let mut outputs = vec![];
+            while let Some(out) = internal.pop_output(now) {
+                if !matches!(out, InternalOutput::Event(EndpointEvent::BweConfig { .. })) {
+                    outputs.push(out);
+                }
+            }
+            outputs
+        };
+
+        let remote = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let now = Instant::now();
+        internal.on_transport_event(now, TransportEvent::State(TransportState::Connecting(remote)));
+        internal.on_transport_event(now, TransportEvent::State(TransportState::Connected(remote)));
+        let room: RoomId = "room".into();
+        let peer: PeerId = "peer".into();
+        let meta = PeerMeta { metadata: None, extra_data: None };
+        let publish = RoomInfoPublish { peer: true, tracks: true };
+        let subscribe = RoomInfoSubscribe { peers: true, tracks: true };
+        internal.on_transport_rpc(now, 0.into(), EndpointReq::JoinRoom(room.clone(), peer.clone(), meta, publish, subscribe, None));
+        drain(&mut internal, now);
+        let room_hash = ClusterRoomHash::generate(&app, &room);
+
+        internal.on_tick(now + Duration::from_secs(6));
+        assert_eq!(drain(&mut internal, now), vec![]);
+
+        // warned once before max duration
+        internal.on_tick(now + Duration::from_secs(7));
+        assert_eq!(drain(&mut internal, now), vec![InternalOutput::Event(EndpointEvent::GoAway(3, Some("max_duration".to_string())))]);
+        internal.on_tick(now + Duration::from_secs(8));
+        assert_eq!(drain(&mut internal, now), vec![]);
+
+        let end = now + Duration::from_secs(10);
+        internal.on_tick(end);
+        assert_eq!(
+            drain(&mut internal, end),
+            vec![
+                InternalOutput::Cluster(room_hash, ClusterEndpointControl::Leave),
+                InternalOutput::PeerEvent(end, peer_event::Event::Leave(peer_event::Leave { room: room.into(), peer: peer.into() })),
+                InternalOutput::Close,
+            ]
+        );
+
+        // transport is closed by endpoint, disconnected event has the end reason
+        internal.on_shutdown(end);
+        internal.on_transport_event(end, TransportEvent::State(TransportState::Disconnected(None)));
+        assert_eq!(
+            drain(&mut internal, end),
+            vec![InternalOutput::PeerEvent(
+                end,
+                peer_event::Event::Disconnected(peer_event::Disconnected {
+                    duration_ms: 0,
+                    reason: Reason::MaxDurationReached as i32,
+                })
+            )]
+        );
+    }
+
     //TODO test local and remote stopped must clear resource
     //TODO handle close request
     //TODO handle transport connected
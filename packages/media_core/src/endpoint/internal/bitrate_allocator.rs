@@ -9,7 +9,7 @@ mod ingress;
 
 pub use egress::Action as EgressAction;
 pub use ingress::Action as IngressAction;
-use media_server_protocol::endpoint::TrackPriority;
+use media_server_protocol::endpoint::{BitratePriority, TrackPriority};
 use sans_io_runtime::TaskSwitcherChild;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -41,14 +41,30 @@ impl BitrateAllocator {
         self.egress.set_egress_estimate(bitrate);
     }
 
-    pub fn set_egress_video_track(&mut self, track: LocalTrackId, priority: TrackPriority) {
-        self.egress.set_video_track(track, priority);
+    pub fn set_egress_rtt(&mut self, rtt_ms: u32) {
+        self.egress.set_rtt(rtt_ms);
+    }
+
+    pub fn set_egress_max(&mut self, bitrate: u64) {
+        self.egress.set_max_bitrate(bitrate);
+    }
+
+    pub fn set_egress_video_track(&mut self, track: LocalTrackId, priority: TrackPriority, bitrate_priority: BitratePriority) {
+        self.egress.set_video_track(track, priority, bitrate_priority);
     }
 
     pub fn del_egress_video_track(&mut self, track: LocalTrackId) {
         self.egress.del_video_track(track);
     }
 
+    pub fn set_egress_audio_track(&mut self, track: LocalTrackId) {
+        self.egress.set_audio_track(track);
+    }
+
+    pub fn del_egress_audio_track(&mut self, track: LocalTrackId) {
+        self.egress.del_audio_track(track);
+    }
+
     pub fn set_ingress_video_track(&mut self, track: RemoteTrackId, priority: TrackPriority) {
         self.ingress.set_video_track(track, priority);
     }
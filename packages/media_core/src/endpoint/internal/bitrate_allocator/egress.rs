@@ -1,13 +1,31 @@
-use std::collections::VecDeque;
+//! Egress allocator split the subscriber bandwidth over subscribed tracks.
+//!
+//! Audio is never limited, so its bitrate is reserved first. The rest is split over video tracks by priority weight.
+//! When it is not enough for all video tracks, tracks are dropped in order of BitratePriority (camera first, then screen),
+//! and inside the same BitratePriority the one with lower priority weight is dropped first.
+//! On high rtt paths the estimate reacts late to congestion, so a part of video bitrate is kept as headroom, which makes
+//! layer switching more conservative.
 
-use indexmap::IndexMap;
-use media_server_protocol::endpoint::TrackPriority;
+use std::{cmp::Reverse, collections::VecDeque};
+
+use indexmap::{IndexMap, IndexSet};
+use media_server_protocol::endpoint::{BitratePriority, TrackPriority};
 
 use crate::transport::LocalTrackId;
 
 const DEFAULT_BITRATE_BPS: u64 = 800_000;
 const NO_TRACK_BWE_CURRENT: u64 = 100_000;
 const NO_TRACK_BWE_DESIRED: u64 = 300_000;
+/// Reserved bitrate for each audio track, enough for opus with fec
+const AUDIO_BITRATE_BPS: u64 = 50_000;
+/// Under this bitrate a video track is useless, dropping it is better than degrading all tracks
+const MIN_VIDEO_BITRATE_BPS: u64 = 150_000;
+/// Rtt from which video tracks are allocated with headroom
+const HIGH_RTT_ENTER_MS: u32 = 300;
+/// Rtt under which headroom is released, lower than enter one so rtt around the threshold doesn't flip the allocation
+const HIGH_RTT_EXIT_MS: u32 = 200;
+/// Percent of video bitrate which is allocated on high rtt
+const HIGH_RTT_VIDEO_PERCENT: u64 = 80;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Action {
@@ -24,7 +42,9 @@ pub struct EgressBitrateAllocator {
     max_egress_bitrate: u64,
     changed: bool,
     egress_bitrate: u64,
-    tracks: IndexMap<LocalTrackId, TrackPriority>,
+    high_rtt: bool,
+    tracks: IndexMap<LocalTrackId, (TrackPriority, BitratePriority)>,
+    audio_tracks: IndexSet<LocalTrackId>,
     queue: VecDeque<Output>,
 }
 
@@ -34,7 +54,9 @@ impl EgressBitrateAllocator {
             max_egress_bitrate,
             changed: false,
             egress_bitrate: DEFAULT_BITRATE_BPS,
+            high_rtt: false,
             tracks: Default::default(),
+            audio_tracks: Default::default(),
             queue: Default::default(),
         }
     }
@@ -48,9 +70,30 @@ impl EgressBitrateAllocator {
         self.changed = true;
     }
 
-    pub fn set_video_track(&mut self, track: LocalTrackId, priority: TrackPriority) {
-        log::info!("[EgressBitrateAllocator] set video track {track} priority {priority}");
-        self.tracks.insert(track, priority);
+    /// Only entering or exiting high rtt changes the allocation
+    pub fn set_rtt(&mut self, rtt_ms: u32) {
+        let high_rtt = if self.high_rtt {
+            rtt_ms >= HIGH_RTT_EXIT_MS
+        } else {
+            rtt_ms >= HIGH_RTT_ENTER_MS
+        };
+        if high_rtt != self.high_rtt {
+            log::info!("[EgressBitrateAllocator] rtt {rtt_ms} ms, high rtt {high_rtt}");
+            self.high_rtt = high_rtt;
+            self.changed = true;
+        }
+    }
+
+    /// Change the bitrate ceiling at runtime, e.g. by room config
+    pub fn set_max_bitrate(&mut self, max_egress_bitrate: u64) {
+        log::info!("[EgressBitrateAllocator] set max egress bitrate {max_egress_bitrate}");
+        self.max_egress_bitrate = max_egress_bitrate;
+        self.changed = true;
+    }
+
+    pub fn set_video_track(&mut self, track: LocalTrackId, priority: TrackPriority, bitrate_priority: BitratePriority) {
+        log::info!("[EgressBitrateAllocator] set video track {track} priority {priority} bitrate priority {bitrate_priority:?}");
+        self.tracks.insert(track, (priority, bitrate_priority));
         self.changed = true;
     }
 
@@ -60,6 +103,18 @@ impl EgressBitrateAllocator {
         self.changed = true;
     }
 
+    pub fn set_audio_track(&mut self, track: LocalTrackId) {
+        log::info!("[EgressBitrateAllocator] set audio track {track}");
+        self.audio_tracks.insert(track);
+        self.changed = true;
+    }
+
+    pub fn del_audio_track(&mut self, track: LocalTrackId) {
+        log::info!("[EgressBitrateAllocator] del audio track {track}");
+        self.audio_tracks.swap_remove(&track);
+        self.changed = true;
+    }
+
     pub fn pop_output(&mut self) -> Option<Output> {
         self.queue.pop_front()
     }
@@ -70,14 +125,39 @@ impl EgressBitrateAllocator {
         }
         self.changed = false;
         let use_bitrate = self.egress_bitrate.min(self.max_egress_bitrate);
+        let mut video_bitrate = use_bitrate.saturating_sub(self.audio_tracks.len() as u64 * AUDIO_BITRATE_BPS);
+        if self.high_rtt {
+            video_bitrate = video_bitrate * HIGH_RTT_VIDEO_PERCENT / 100;
+        }
+
+        // most important first, we always keep at least one video track
+        let mut ordered: Vec<_> = self
+            .tracks
+            .iter()
+            .map(|(track, (priority, bitrate_priority))| (*track, *bitrate_priority, Reverse(**priority)))
+            .collect();
+        ordered.sort_by_key(|(_, bitrate_priority, priority)| (*bitrate_priority, *priority));
+        let mut kept = ordered.len();
+        while kept > 1 && kept as u64 * MIN_VIDEO_BITRATE_BPS > video_bitrate {
+            kept -= 1;
+        }
+        let dropped: IndexSet<_> = ordered[kept..].iter().map(|(track, _, _)| *track).collect();
+
         let mut sum = TrackPriority::from(0);
-        for (_track, priority) in self.tracks.iter() {
-            sum += *priority;
+        for (track, (priority, _)) in self.tracks.iter() {
+            if !dropped.contains(track) {
+                sum += *priority;
+            }
         }
 
         if *(sum.as_ref()) != 0 {
-            for (track, priority) in self.tracks.iter() {
-                let bitrate = (use_bitrate * (**priority) as u64) / *sum as u64;
+            for (track, (priority, bitrate_priority)) in self.tracks.iter() {
+                let bitrate = if dropped.contains(track) {
+                    log::debug!("[EgressBitrateAllocator] not enough bitrate {video_bitrate} => drop track {track} {bitrate_priority:?}");
+                    0
+                } else {
+                    (video_bitrate * (**priority) as u64) / *sum as u64
+                };
                 log::debug!("[EgressBitrateAllocator] set track {track} with bitrate {bitrate}");
                 self.queue.push_back(Output::Track(*track, Action::SetBitrate(bitrate)));
             }
@@ -105,7 +185,9 @@ impl EgressBitrateAllocator {
 mod test {
     use crate::endpoint::internal::bitrate_allocator::egress::{EgressBitrateAllocator, NO_TRACK_BWE_CURRENT, NO_TRACK_BWE_DESIRED};
 
-    use super::{Action, Output, DEFAULT_BITRATE_BPS};
+    use media_server_protocol::endpoint::BitratePriority;
+
+    use super::{Action, Output, AUDIO_BITRATE_BPS, DEFAULT_BITRATE_BPS, HIGH_RTT_ENTER_MS, HIGH_RTT_EXIT_MS, HIGH_RTT_VIDEO_PERCENT};
 
     const MAX_BW: u64 = 2_500_000;
 
@@ -122,7 +204,7 @@ mod test {
     #[test_log::test]
     fn single_source() {
         let mut allocator = EgressBitrateAllocator::new(MAX_BW);
-        allocator.set_video_track(0.into(), 1.into());
+        allocator.set_video_track(0.into(), 1.into(), BitratePriority::Camera);
 
         allocator.on_tick();
         assert_eq!(allocator.pop_output(), Some(Output::Track(0.into(), Action::SetBitrate(DEFAULT_BITRATE_BPS))));
@@ -137,11 +219,28 @@ mod test {
         assert_eq!(allocator.pop_output(), None);
     }
 
+    #[test_log::test]
+    fn lower_max_bitrate_at_runtime() {
+        let mut allocator = EgressBitrateAllocator::new(MAX_BW);
+        allocator.set_video_track(0.into(), 1.into(), BitratePriority::Camera);
+        allocator.set_egress_estimate(MAX_BW);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(0.into(), Action::SetBitrate(MAX_BW))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(MAX_BW, MAX_BW)));
+        assert_eq!(allocator.pop_output(), None);
+
+        allocator.set_max_bitrate(500_000);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(0.into(), Action::SetBitrate(500_000))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(500_000, 500_000)));
+        assert_eq!(allocator.pop_output(), None);
+    }
+
     #[test_log::test]
     fn multi_source() {
         let mut allocator = EgressBitrateAllocator::new(MAX_BW);
-        allocator.set_video_track(0.into(), 1.into());
-        allocator.set_video_track(1.into(), 3.into());
+        allocator.set_video_track(0.into(), 1.into(), BitratePriority::Camera);
+        allocator.set_video_track(1.into(), 3.into(), BitratePriority::Camera);
 
         allocator.on_tick();
         assert_eq!(allocator.pop_output(), Some(Output::Track(0.into(), Action::SetBitrate(DEFAULT_BITRATE_BPS / 4))));
@@ -149,4 +248,71 @@ mod test {
         assert_eq!(allocator.pop_output(), Some(Output::BweConfig(DEFAULT_BITRATE_BPS, DEFAULT_BITRATE_BPS * 6 / 5)));
         assert_eq!(allocator.pop_output(), None);
     }
+
+    #[test_log::test]
+    fn congestion_drop_camera_first() {
+        let mut allocator = EgressBitrateAllocator::new(MAX_BW);
+        allocator.set_audio_track(0.into());
+        allocator.set_video_track(1.into(), 1.into(), BitratePriority::Camera);
+        allocator.set_video_track(2.into(), 1.into(), BitratePriority::Screen);
+
+        // enough for both video tracks
+        allocator.set_egress_estimate(1_050_000);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(1.into(), Action::SetBitrate(500_000))));
+        assert_eq!(allocator.pop_output(), Some(Output::Track(2.into(), Action::SetBitrate(500_000))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(1_050_000, 1_260_000)));
+        assert_eq!(allocator.pop_output(), None);
+
+        // tight budget => audio still reserved, camera dropped and screen keep all the rest
+        allocator.set_egress_estimate(300_000);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(1.into(), Action::SetBitrate(0))));
+        assert_eq!(allocator.pop_output(), Some(Output::Track(2.into(), Action::SetBitrate(300_000 - AUDIO_BITRATE_BPS))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(300_000, 360_000)));
+        assert_eq!(allocator.pop_output(), None);
+
+        // screen left => camera is the only video track so it is kept
+        allocator.del_video_track(2.into());
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(1.into(), Action::SetBitrate(300_000 - AUDIO_BITRATE_BPS))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(300_000, 360_000)));
+        assert_eq!(allocator.pop_output(), None);
+    }
+
+    #[test_log::test]
+    fn high_rtt_keeps_headroom() {
+        let mut allocator = EgressBitrateAllocator::new(MAX_BW);
+        allocator.set_video_track(0.into(), 1.into(), BitratePriority::Camera);
+        allocator.set_egress_estimate(1_000_000);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(0.into(), Action::SetBitrate(1_000_000))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(1_000_000, 1_200_000)));
+        assert_eq!(allocator.pop_output(), None);
+
+        // low rtt changes nothing
+        allocator.set_rtt(HIGH_RTT_EXIT_MS / 2);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), None);
+
+        // bwe config still follows the estimate, only video tracks are lowered
+        allocator.set_rtt(HIGH_RTT_ENTER_MS);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(0.into(), Action::SetBitrate(1_000_000 * HIGH_RTT_VIDEO_PERCENT / 100))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(1_000_000, 1_200_000)));
+        assert_eq!(allocator.pop_output(), None);
+
+        // rtt hovering between exit and enter thresholds keeps the headroom
+        for rtt in [HIGH_RTT_ENTER_MS - 1, HIGH_RTT_EXIT_MS, HIGH_RTT_ENTER_MS + 1, HIGH_RTT_EXIT_MS] {
+            allocator.set_rtt(rtt);
+            allocator.on_tick();
+            assert_eq!(allocator.pop_output(), None);
+        }
+
+        allocator.set_rtt(HIGH_RTT_EXIT_MS - 1);
+        allocator.on_tick();
+        assert_eq!(allocator.pop_output(), Some(Output::Track(0.into(), Action::SetBitrate(1_000_000))));
+        assert_eq!(allocator.pop_output(), Some(Output::BweConfig(1_000_000, 1_200_000)));
+        assert_eq!(allocator.pop_output(), None);
+    }
 }
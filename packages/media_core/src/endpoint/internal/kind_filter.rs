@@ -0,0 +1,76 @@
+//! Per peer track kind filter. We keep all announced tracks of remote peers here,
+//! so when the filter changed we can announce or stop the affected tracks without asking cluster again.
+
+use indexmap::IndexMap;
+use media_server_protocol::{
+    endpoint::{PeerId, TrackKindFilter, TrackMeta, TrackName},
+    media::MediaKind,
+};
+
+use crate::endpoint::EndpointEvent;
+
+#[derive(Debug, Default)]
+pub struct PeerKindFilter {
+    filters: IndexMap<PeerId, TrackKindFilter>,
+    tracks: IndexMap<(PeerId, TrackName), TrackMeta>,
+}
+
+impl PeerKindFilter {
+    fn filter(&self, peer: &PeerId) -> TrackKindFilter {
+        self.filters.get(peer).copied().unwrap_or_default()
+    }
+
+    /// Return true if tracks of the kind from the peer can be announced and viewed
+    pub fn allow(&self, peer: &PeerId, kind: MediaKind) -> bool {
+        self.filter(peer).allow(kind)
+    }
+
+    /// Return true if the event should be forwarded to transport
+    pub fn on_track_started(&mut self, peer: &PeerId, track: &TrackName, meta: &TrackMeta) -> bool {
+        self.tracks.insert((peer.clone(), track.clone()), meta.clone());
+        self.filter(peer).allow(meta.kind)
+    }
+
+    /// Return true if the event should be forwarded to transport
+    pub fn on_track_stopped(&mut self, peer: &PeerId, track: &TrackName, meta: &TrackMeta) -> bool {
+        self.tracks.shift_remove(&(peer.clone(), track.clone()));
+        self.filter(peer).allow(meta.kind)
+    }
+
+    /// Return true if the event should be forwarded to transport
+    pub fn on_track_muted(&mut self, peer: &PeerId, track: &TrackName, muted: bool) -> bool {
+        let filter = self.filter(peer);
+        match self.tracks.get_mut(&(peer.clone(), track.clone())) {
+            Some(meta) => {
+                meta.muted = muted;
+                filter.allow(meta.kind)
+            }
+            None => true,
+        }
+    }
+
+    /// Update filter of a peer, return events for tracks which become visible or hidden
+    pub fn set_filter(&mut self, peer: PeerId, filter: TrackKindFilter) -> Vec<EndpointEvent> {
+        let pre = self.filter(&peer);
+        if filter == TrackKindFilter::default() {
+            self.filters.shift_remove(&peer);
+        } else {
+            self.filters.insert(peer.clone(), filter);
+        }
+
+        self.tracks
+            .iter()
+            .filter(|((track_peer, _), _)| *track_peer == peer)
+            .filter_map(|((track_peer, track), meta)| match (pre.allow(meta.kind), filter.allow(meta.kind)) {
+                (false, true) => Some(EndpointEvent::PeerTrackStarted(track_peer.clone(), track.clone(), meta.clone())),
+                (true, false) => Some(EndpointEvent::PeerTrackStopped(track_peer.clone(), track.clone(), meta.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.filters.clear();
+        self.tracks.clear();
+    }
+}
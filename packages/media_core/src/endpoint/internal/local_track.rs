@@ -6,8 +6,8 @@ use std::{collections::VecDeque, time::Instant};
 
 use atm0s_sdn::TimePivot;
 use media_server_protocol::{
-    endpoint::{PeerId, TrackName, TrackPriority},
-    media::{MediaKind, MediaMeta},
+    endpoint::{BitratePriority, PeerId, TrackName, TrackPriority},
+    media::{MediaKind, MediaMeta, MediaPacket},
     protobuf::{cluster_connector::peer_event, shared::receiver::Status as ProtoStatus},
     transport::{LocalTrackId, RpcError},
 };
@@ -20,14 +20,23 @@ use crate::{
     transport::LocalTrackEvent,
 };
 
+use loss_detector::LossDetector;
 use packet_selector::PacketSelector;
+use playout::Playout;
+use relay_grace::RelayGrace;
 use voice_activity::VoiceActivityDetector;
 
 use super::bitrate_allocator::EgressAction;
 
+mod loss_detector;
 mod packet_selector;
+mod playout;
+mod relay_grace;
 mod voice_activity;
 
+pub use playout::PlayoutConfig;
+pub use relay_grace::RelayGraceConfig;
+
 const MEDIA_TIMEOUT_MS: u64 = 2_000; //after 2s not receive media, the track will become inactive
 
 pub enum Input {
@@ -37,6 +46,10 @@ pub enum Input {
     Event(LocalTrackEvent),
     RpcReq(EndpointReqId, EndpointLocalTrackReq),
     BitrateAllocation(EgressAction),
+    /// Max spatial layer which is set by room config
+    RoomMaxSpatial(Option<u8>),
+    /// Kind of this track is filtered out for the peer, we need to stop forwarding if it is the current source
+    PeerKindFiltered(PeerId),
 }
 
 pub enum Output {
@@ -44,8 +57,8 @@ pub enum Output {
     Cluster(ClusterRoomHash, ClusterLocalTrackControl),
     PeerEvent(Instant, peer_event::Event),
     RpcRes(EndpointReqId, EndpointLocalTrackRes),
-    Bind(MediaKind, TrackPriority),
-    Updated(MediaKind, TrackPriority),
+    Bind(MediaKind, TrackPriority, BitratePriority),
+    Updated(MediaKind, TrackPriority, BitratePriority),
     Unbind(MediaKind),
     OnResourceEmpty,
 }
@@ -66,11 +79,14 @@ pub struct EndpointLocalTrack {
     selector: PacketSelector,
     timer: TimePivot,
     voice_activity: VoiceActivityDetector,
+    loss_detector: LossDetector,
+    relay_grace: RelayGrace,
+    playout: Playout,
     shutdown: bool,
 }
 
 impl EndpointLocalTrack {
-    pub fn new(track: LocalTrackId, kind: MediaKind, room: Option<ClusterRoomHash>) -> Self {
+    pub fn new(track: LocalTrackId, kind: MediaKind, room: Option<ClusterRoomHash>, relay_grace: RelayGraceConfig, playout: PlayoutConfig) -> Self {
         log::info!("[EndpointLocalTrack] track {kind}, room {:?}", room);
         Self {
             track,
@@ -81,6 +97,9 @@ impl EndpointLocalTrack {
             selector: PacketSelector::new(kind, 2, 2),
             timer: TimePivot::build(),
             voice_activity: VoiceActivityDetector::default(),
+            loss_detector: LossDetector::default(),
+            relay_grace: RelayGrace::new(relay_grace),
+            playout: Playout::new(kind, playout),
             shutdown: false,
         }
     }
@@ -96,6 +115,7 @@ impl EndpointLocalTrack {
         assert_ne!(self.room, None);
         let room = return_if_none!(self.room.take());
         log::info!("[EndpointLocalTrack] leave room {room}");
+        self.playout.reset();
         let (peer, track, _) = return_if_none!(self.bind.take());
         log::info!("[EndpointLocalTrack] leave room {room} => auto Unsubscribe {peer} {track}");
         self.queue.push_back(Output::Cluster(room, ClusterLocalTrackControl::Unsubscribe));
@@ -112,44 +132,102 @@ impl EndpointLocalTrack {
     fn on_cluster_event(&mut self, now: Instant, event: ClusterLocalTrackEvent) {
         match event {
             ClusterLocalTrackEvent::RelayChanged => {
-                if self.kind.is_video() {
-                    let room = return_if_none!(self.room.as_ref());
-                    log::info!("[EndpointLocalTrack] relay changed => request key-frame");
-                    self.queue.push_back(Output::Cluster(*room, ClusterLocalTrackControl::RequestKeyFrame));
+                let now_ms = self.timer.timestamp_ms(now);
+                if self.relay_grace.on_relay_changed(now_ms) {
+                    if self.kind.is_video() {
+                        let room = return_if_none!(self.room.as_ref());
+                        log::info!("[EndpointLocalTrack] relay changed => request key-frame");
+                        self.queue.push_back(Output::Cluster(*room, ClusterLocalTrackControl::RequestKeyFrame));
+                    }
+                } else {
+                    log::info!("[EndpointLocalTrack] relay changed => buffer media in grace window");
                 }
             }
             ClusterLocalTrackEvent::SourceChanged => {
                 //currently for audio_mixer
                 log::info!("[EndpointLocalTrack] source changed => reset seq, ts rewrite");
                 self.selector.reset();
+                self.loss_detector.reset();
+                self.relay_grace.reset();
+            }
+            ClusterLocalTrackEvent::SourceLost => {
+                log::warn!("[EndpointLocalTrack] source lost => inactive");
+                if let Some((_, _, status)) = &mut self.bind {
+                    if *status != Status::Inactive {
+                        *status = Status::Inactive;
+                        self.queue.push_back(Output::Event(EndpointLocalTrackEvent::Status(ProtoStatus::Inactive)));
+                    }
+                }
             }
-            ClusterLocalTrackEvent::Media(channel, mut pkt) => {
+            ClusterLocalTrackEvent::SourceRecovered => {
+                //source can be other node, so seq and ts is not continuous
+                log::info!("[EndpointLocalTrack] source recovered => reset seq, ts rewrite");
+                self.selector.reset();
+                self.loss_detector.reset();
+                self.relay_grace.reset();
+                if self.kind.is_video() {
+                    let room = return_if_none!(self.room.as_ref());
+                    self.queue.push_back(Output::Cluster(*room, ClusterLocalTrackControl::RequestKeyFrame));
+                }
+            }
+            ClusterLocalTrackEvent::Media(channel, pkt) => {
                 log::trace!("[EndpointLocalTrack] on media payload {:?} seq {}", pkt.meta, pkt.seq);
                 let now_ms = self.timer.timestamp_ms(now);
-                if self.selector.select(self.timer.timestamp_ms(now), channel, &mut pkt).is_some() {
-                    self.pop_selector(now_ms);
-
-                    if let Some((_, _, status)) = &mut self.bind {
-                        match status {
-                            Status::Waiting | Status::Inactive => {
-                                *status = Status::Active { last_media_ts: now_ms };
-                                self.queue.push_back(Output::Event(EndpointLocalTrackEvent::Status(ProtoStatus::Active)));
-                            }
-                            Status::Active { last_media_ts } => {
-                                *last_media_ts = now_ms;
-                            }
-                        }
-                    }
+                self.relay_grace.push(now_ms, channel, pkt);
+                self.pop_relay_grace(now);
+            }
+        }
+    }
+
+    fn pop_relay_grace(&mut self, now: Instant) {
+        if self.relay_grace.take_key_frame() && self.kind.is_video() {
+            if let Some(room) = self.room {
+                log::info!("[EndpointLocalTrack] media not continuous after relay changed => request key-frame");
+                self.queue.push_back(Output::Cluster(room, ClusterLocalTrackControl::RequestKeyFrame));
+            }
+        }
+        while let Some((channel, pkt)) = self.relay_grace.pop() {
+            self.on_media(now, channel, pkt);
+        }
+    }
 
-                    if let MediaMeta::Opus { audio_level } = &pkt.meta {
-                        if let Some(level) = self.voice_activity.on_audio(now_ms, *audio_level) {
-                            self.queue.push_back(Output::Event(EndpointLocalTrackEvent::VoiceActivity(level)));
-                        }
+    fn on_media(&mut self, now: Instant, channel: u64, mut pkt: MediaPacket) {
+        let now_ms = self.timer.timestamp_ms(now);
+        if self.kind.is_video() && self.loss_detector.on_video(now_ms, channel, &pkt) {
+            if let Some(room) = self.room {
+                log::info!("[EndpointLocalTrack] packet loss detected => request key-frame");
+                self.queue.push_back(Output::Cluster(room, ClusterLocalTrackControl::RequestKeyFrame));
+            }
+        }
+        if self.selector.select(self.timer.timestamp_ms(now), channel, &mut pkt).is_some() {
+            self.pop_selector(now_ms);
+
+            if let Some((_, _, status)) = &mut self.bind {
+                match status {
+                    Status::Waiting | Status::Inactive => {
+                        *status = Status::Active { last_media_ts: now_ms };
+                        self.queue.push_back(Output::Event(EndpointLocalTrackEvent::Status(ProtoStatus::Active)));
+                    }
+                    Status::Active { last_media_ts } => {
+                        *last_media_ts = now_ms;
                     }
+                }
+            }
 
-                    self.queue.push_back(Output::Event(EndpointLocalTrackEvent::Media(pkt)));
+            if let MediaMeta::Opus { audio_level } = &pkt.meta {
+                if let Some(level) = self.voice_activity.on_audio(now_ms, *audio_level) {
+                    self.queue.push_back(Output::Event(EndpointLocalTrackEvent::VoiceActivity(level)));
                 }
             }
+
+            self.playout.push(now_ms, pkt);
+            self.pop_playout(now_ms);
+        }
+    }
+
+    fn pop_playout(&mut self, now_ms: u64) {
+        while let Some(pkt) = self.playout.pop(now_ms) {
+            self.queue.push_back(Output::Event(EndpointLocalTrackEvent::Media(pkt)));
         }
     }
 
@@ -188,7 +266,8 @@ impl EndpointLocalTrack {
                     }
                     self.bind = Some((peer.clone(), track.clone(), Status::Waiting));
                     self.selector.set_limit_layer(now_ms, config.max_spatial, config.max_temporal);
-                    self.queue.push_back(Output::Bind(self.kind, config.priority));
+                    self.playout.set_smooth(config.smooth_playout);
+                    self.queue.push_back(Output::Bind(self.kind, config.priority, config.bitrate_priority));
                     self.queue.push_back(Output::Cluster(*room, ClusterLocalTrackControl::Subscribe(peer.clone(), track.clone())));
                     self.queue.push_back(Output::PeerEvent(
                         now,
@@ -199,6 +278,9 @@ impl EndpointLocalTrack {
                         }),
                     ));
                     self.selector.reset();
+                    self.loss_detector.reset();
+                    self.relay_grace.reset();
+                    self.playout.reset();
                 } else {
                     log::warn!("[EndpointLocalTrack] track {} view but not in room", self.kind);
                     self.queue
@@ -208,19 +290,10 @@ impl EndpointLocalTrack {
             EndpointLocalTrackReq::Detach() => {
                 //TODO process config here
                 if let Some(room) = self.room.as_ref() {
-                    if let Some((peer, track, _)) = self.bind.take() {
-                        log::info!("[EndpointLocalTrack] unview room {room} peer {peer} track {track}");
+                    if self.bind.is_some() {
+                        log::info!("[EndpointLocalTrack] unview room {room}");
                         self.queue.push_back(Output::RpcRes(req_id, EndpointLocalTrackRes::Detach(Ok(()))));
-                        self.queue.push_back(Output::Unbind(self.kind));
-                        self.queue.push_back(Output::Cluster(*room, ClusterLocalTrackControl::Unsubscribe));
-                        self.queue.push_back(Output::PeerEvent(
-                            now,
-                            peer_event::Event::LocalTrackDetach(peer_event::LocalTrackDetach {
-                                track: *self.track as i32,
-                                remote_peer: peer.into(),
-                                remote_track: track.into(),
-                            }),
-                        ));
+                        self.detach(now);
                     } else {
                         log::warn!("[EndpointLocalTrack] unview but not bind to any source");
                         self.queue
@@ -235,12 +308,38 @@ impl EndpointLocalTrack {
             EndpointLocalTrackReq::Config(config) => {
                 let now_ms = self.timer.timestamp_ms(now);
                 self.selector.set_limit_layer(now_ms, config.max_spatial, config.max_temporal);
+                self.playout.set_smooth(config.smooth_playout);
+                self.pop_playout(now_ms);
                 self.queue.push_back(Output::RpcRes(req_id, EndpointLocalTrackRes::Config(Ok(()))));
-                self.queue.push_back(Output::Updated(self.kind, config.priority));
+                self.queue.push_back(Output::Updated(self.kind, config.priority, config.bitrate_priority));
             }
         }
     }
 
+    fn on_peer_kind_filtered(&mut self, now: Instant, peer: PeerId) {
+        if self.bind.as_ref().is_some_and(|(bind_peer, _, _)| *bind_peer == peer) {
+            log::info!("[EndpointLocalTrack] track {} source peer {peer} is filtered => detach", self.kind);
+            self.detach(now);
+        }
+    }
+
+    fn detach(&mut self, now: Instant) {
+        let room = return_if_none!(self.room.as_ref());
+        let (peer, track, _) = return_if_none!(self.bind.take());
+        log::info!("[EndpointLocalTrack] detach room {room} peer {peer} track {track}");
+        self.playout.reset();
+        self.queue.push_back(Output::Unbind(self.kind));
+        self.queue.push_back(Output::Cluster(*room, ClusterLocalTrackControl::Unsubscribe));
+        self.queue.push_back(Output::PeerEvent(
+            now,
+            peer_event::Event::LocalTrackDetach(peer_event::LocalTrackDetach {
+                track: *self.track as i32,
+                remote_peer: peer.into(),
+                remote_track: track.into(),
+            }),
+        ));
+    }
+
     fn on_bitrate_allocation_action(&mut self, now: Instant, action: EgressAction) {
         match action {
             EgressAction::SetBitrate(bitrate) => {
@@ -276,6 +375,9 @@ impl Task<Input, Output> for EndpointLocalTrack {
         let now_ms = self.timer.timestamp_ms(now);
         self.selector.on_tick(now_ms);
         self.pop_selector(now_ms);
+        self.relay_grace.on_tick(now_ms);
+        self.pop_relay_grace(now);
+        self.pop_playout(now_ms);
 
         if let Some((_, _, status)) = &mut self.bind {
             if let Status::Active { last_media_ts } = status {
@@ -295,6 +397,8 @@ impl Task<Input, Output> for EndpointLocalTrack {
             Input::Event(event) => self.on_transport_event(now, event),
             Input::RpcReq(req_id, req) => self.on_rpc_req(now, req_id, req),
             Input::BitrateAllocation(action) => self.on_bitrate_allocation_action(now, action),
+            Input::RoomMaxSpatial(max_spatial) => self.selector.set_room_max_spatial(self.timer.timestamp_ms(now), max_spatial),
+            Input::PeerKindFiltered(peer) => self.on_peer_kind_filtered(now, peer),
         }
     }
 
@@ -333,6 +437,132 @@ impl Drop for EndpointLocalTrack {
 
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, Instant};
+
+    use media_server_protocol::{
+        media::{H264Profile, MediaKind, MediaMeta, MediaPacket},
+        transport::LocalTrackId,
+    };
+    use sans_io_runtime::{Task, TaskSwitcherChild};
+
+    use crate::{
+        cluster::{ClusterLocalTrackControl, ClusterLocalTrackEvent, ClusterRoomHash},
+        endpoint::EndpointLocalTrackEvent,
+    };
+
+    use super::{EndpointLocalTrack, Input, Output, PlayoutConfig, RelayGraceConfig};
+
+    fn video_pkt(seq: u16, key: bool) -> MediaPacket {
+        MediaPacket {
+            ts: 0,
+            seq,
+            marker: true,
+            nackable: false,
+            layers: None,
+            meta: MediaMeta::H264 {
+                key,
+                profile: H264Profile::P42001fNonInterleaved,
+                sim: None,
+                rotation: None,
+            },
+            data: vec![1, 2, 3],
+        }
+    }
+
+    /// Return forwarded media seqs and number of key-frame requests
+    fn pop_media(track: &mut EndpointLocalTrack, now: Instant) -> (Vec<u16>, usize) {
+        let mut seqs = vec![];
+        let mut key_frames = 0;
+        while let Some(out) = track.pop_output(now) {
+            match out {
+                Output::Event(EndpointLocalTrackEvent::Media(pkt)) => seqs.push(pkt.seq),
+                Output::Cluster(_, ClusterLocalTrackControl::RequestKeyFrame) => key_frames += 1,
+                _ => {}
+            }
+        }
+        (seqs, key_frames)
+    }
+
+    fn count_key_frame_requests(track: &mut EndpointLocalTrack, now: Instant) -> usize {
+        let mut count = 0;
+        while let Some(out) = track.pop_output(now) {
+            if matches!(out, Output::Cluster(_, ClusterLocalTrackControl::RequestKeyFrame)) {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn packet_loss_request_key_frame() {
+        let now = Instant::now();
+        let room = ClusterRoomHash::from(1);
+        let mut track = EndpointLocalTrack::new(LocalTrackId::from(0), MediaKind::Video, Some(room), RelayGraceConfig::default(), PlayoutConfig::default());
+
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(0, true))));
+        for seq in 1..20 {
+            track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(seq, false))));
+        }
+        assert_eq!(count_key_frame_requests(&mut track, now), 0);
+
+        // sequence gap => request key-frame
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(30, false))));
+        assert_eq!(count_key_frame_requests(&mut track, now), 1);
+
+        // other gap right after is rate-limited
+        for seq in 31..51 {
+            track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(seq, false))));
+        }
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(60, false))));
+        assert_eq!(count_key_frame_requests(&mut track, now), 0);
+    }
+
+    #[test]
+    fn relay_changed_in_grace_keep_media_continuous() {
+        let now = Instant::now();
+        let room = ClusterRoomHash::from(1);
+        let mut track = EndpointLocalTrack::new(LocalTrackId::from(0), MediaKind::Video, Some(room), RelayGraceConfig::default(), PlayoutConfig::default());
+
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(0, true))));
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(1, false))));
+        assert_eq!(pop_media(&mut track, now).1, 0);
+
+        // new relay delivers packets reordered and old relay still delivers a duplicated packet
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::RelayChanged));
+        let now2 = now + Duration::from_millis(50);
+        track.on_event(now2, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(3, false))));
+        track.on_event(now2, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(1, false))));
+        track.on_event(now2, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(2, false))));
+        track.on_event(now2, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(4, false))));
+        track.on_tick(now + Duration::from_millis(300));
+
+        let (seqs, key_frames) = pop_media(&mut track, now2);
+        assert_eq!(key_frames, 0);
+        let rewritten: Vec<u16> = seqs.windows(2).map(|w| w[1].wrapping_sub(w[0])).collect();
+        assert_eq!(seqs.len(), 3);
+        assert_eq!(rewritten, vec![1, 1]);
+    }
+
+    #[test]
+    fn relay_changed_long_gap_request_key_frame() {
+        let now = Instant::now();
+        let room = ClusterRoomHash::from(1);
+        let mut track = EndpointLocalTrack::new(LocalTrackId::from(0), MediaKind::Video, Some(room), RelayGraceConfig::default(), PlayoutConfig::default());
+
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(0, true))));
+        assert_eq!(pop_media(&mut track, now).1, 0);
+
+        track.on_event(now, Input::Cluster(ClusterLocalTrackEvent::RelayChanged));
+        assert_eq!(pop_media(&mut track, now).1, 0);
+
+        // media resumes after the key-frame gap
+        let now2 = now + Duration::from_millis(600);
+        track.on_event(now2, Input::Cluster(ClusterLocalTrackEvent::Media(1, video_pkt(1, false))));
+        let (seqs, key_frames) = pop_media(&mut track, now2);
+        assert_eq!(seqs.len(), 1);
+        assert_eq!(key_frames, 1);
+    }
+
     //TODO view not in room
     //TODO view in room
     //TODO unview ok
@@ -0,0 +1,195 @@
+//! LossDetector detect packet loss from sequence gaps of incoming video packets.
+//! When loss inside a window exceeds threshold, we request a key-frame for fast recovering instead of waiting for next natural key-frame.
+//! Simulcast layers have separated sequence spaces, so each spatial layer is tracked independently.
+
+use media_server_protocol::media::{MediaMeta, MediaPacket};
+
+const LOSS_MIN_SAMPLES: u64 = 20; //only check loss after having enough packets
+const LOSS_WINDOW_SAMPLES: u64 = 100; //reset counters after each window
+const LOSS_THRESHOLD_PERCENT: u64 = 10;
+const LOSS_MAX_GAP: u16 = 1000; //bigger gap is considered as stream reset
+const LOSS_KEY_FRAME_INTERVAL_MS: u64 = 1000; //only request key-frame each 1s because of loss
+
+#[derive(Default)]
+struct LayerState {
+    last_seq: Option<u16>,
+    received: u64,
+    lost: u64,
+}
+
+impl LayerState {
+    /// Return true if loss inside current window exceeds threshold
+    fn on_seq(&mut self, seq: u16) -> bool {
+        let last_seq = if let Some(last_seq) = self.last_seq {
+            last_seq
+        } else {
+            self.last_seq = Some(seq);
+            self.received += 1;
+            return false;
+        };
+
+        let diff = seq.wrapping_sub(last_seq);
+        if diff == 0 || diff >= u16::MAX / 2 {
+            // duplicated or reordered packet, it is already counted as lost before, so we correct it here
+            self.lost = self.lost.saturating_sub(1);
+            self.received += 1;
+            return false;
+        }
+
+        if diff > LOSS_MAX_GAP {
+            log::info!("[LocalTrack/LossDetector] seq jumped from {last_seq} to {seq} => reset");
+            *self = Self::default();
+            self.last_seq = Some(seq);
+            self.received = 1;
+            return false;
+        }
+
+        self.last_seq = Some(seq);
+        self.received += 1;
+        self.lost += (diff - 1) as u64;
+
+        let total = self.received + self.lost;
+        if total >= LOSS_MIN_SAMPLES && self.lost * 100 >= total * LOSS_THRESHOLD_PERCENT {
+            log::info!("[LocalTrack/LossDetector] loss {}/{} exceeds threshold {LOSS_THRESHOLD_PERCENT}%", self.lost, total);
+            self.received = 0;
+            self.lost = 0;
+            true
+        } else {
+            if total >= LOSS_WINDOW_SAMPLES {
+                self.received = 0;
+                self.lost = 0;
+            }
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LossDetector {
+    channel: Option<u64>,
+    layers: Vec<LayerState>,
+    last_request: Option<u64>,
+}
+
+impl LossDetector {
+    /// Reset, call reset if local_track changed source
+    pub fn reset(&mut self) {
+        self.channel = None;
+        self.layers.clear();
+    }
+
+    /// Return true if we should request a key-frame because of packet loss
+    pub fn on_video(&mut self, now_ms: u64, channel: u64, pkt: &MediaPacket) -> bool {
+        if self.channel != Some(channel) {
+            self.reset();
+            self.channel = Some(channel);
+        }
+
+        let spatial = match &pkt.meta {
+            MediaMeta::H264 { sim: Some(sim), .. } => sim.spatial,
+            MediaMeta::Vp8 { sim: Some(sim), .. } => sim.spatial,
+            _ => 0,
+        } as usize;
+        if self.layers.len() <= spatial {
+            self.layers.resize_with(spatial + 1, Default::default);
+        }
+
+        if !self.layers[spatial].on_seq(pkt.seq) {
+            return false;
+        }
+
+        if self.last_request.is_some_and(|last| last + LOSS_KEY_FRAME_INTERVAL_MS > now_ms) {
+            log::debug!("[LocalTrack/LossDetector] loss detected but key-frame requested recently => skip");
+            return false;
+        }
+        self.last_request = Some(now_ms);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::media::{H264Profile, H264Sim, MediaMeta, MediaPacket};
+
+    use super::{LossDetector, LOSS_KEY_FRAME_INTERVAL_MS};
+
+    fn video_pkt(seq: u16, spatial: Option<u8>) -> MediaPacket {
+        MediaPacket {
+            ts: 0,
+            seq,
+            marker: true,
+            nackable: false,
+            layers: None,
+            meta: MediaMeta::H264 {
+                key: false,
+                profile: H264Profile::P42001fNonInterleaved,
+                sim: spatial.map(|spatial| H264Sim { spatial }),
+                rotation: None,
+            },
+            data: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn no_loss() {
+        let mut detector = LossDetector::default();
+        for seq in 0..1000 {
+            assert!(!detector.on_video(0, 0, &video_pkt(seq, None)));
+        }
+    }
+
+    #[test]
+    fn seq_gap_request_key_frame_rate_limited() {
+        let mut detector = LossDetector::default();
+        for seq in 0..20 {
+            assert!(!detector.on_video(0, 0, &video_pkt(seq, None)));
+        }
+        // lost 5 packets
+        assert!(detector.on_video(10, 0, &video_pkt(25, None)));
+
+        // lost again but inside rate limit interval
+        for seq in 26..46 {
+            assert!(!detector.on_video(20, 0, &video_pkt(seq, None)));
+        }
+        assert!(!detector.on_video(20, 0, &video_pkt(51, None)));
+
+        // lost again after interval
+        for seq in 52..72 {
+            assert!(!detector.on_video(10 + LOSS_KEY_FRAME_INTERVAL_MS, 0, &video_pkt(seq, None)));
+        }
+        assert!(detector.on_video(10 + LOSS_KEY_FRAME_INTERVAL_MS, 0, &video_pkt(77, None)));
+    }
+
+    #[test]
+    fn reordered_not_counted_as_loss() {
+        let mut detector = LossDetector::default();
+        for seq in 0..20 {
+            assert!(!detector.on_video(0, 0, &video_pkt(seq, None)));
+        }
+        for seq in (20..200).step_by(2) {
+            assert!(!detector.on_video(0, 0, &video_pkt(seq + 1, None)));
+            assert!(!detector.on_video(0, 0, &video_pkt(seq, None)));
+        }
+    }
+
+    #[test]
+    fn simulcast_layers_tracked_separately() {
+        let mut detector = LossDetector::default();
+        for seq in 0..200 {
+            assert!(!detector.on_video(0, 0, &video_pkt(seq, Some(0))));
+            assert!(!detector.on_video(0, 0, &video_pkt(seq + 10000, Some(1))));
+        }
+    }
+
+    #[test]
+    fn source_changed_reset() {
+        let mut detector = LossDetector::default();
+        for seq in 0..20 {
+            assert!(!detector.on_video(0, 0, &video_pkt(seq, None)));
+        }
+        assert!(!detector.on_video(0, 1, &video_pkt(25, None)));
+        for seq in 26..46 {
+            assert!(!detector.on_video(0, 1, &video_pkt(seq, None)));
+        }
+    }
+}
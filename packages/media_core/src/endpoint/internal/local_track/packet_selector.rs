@@ -70,6 +70,8 @@ pub struct PacketSelector {
     queue: VecDeque<Action>,
     bitrate: Option<u64>,
     limit: (u8, u8),
+    /// Max spatial layer of the room, it caps the limit which is requested by client
+    room_max_spatial: Option<u8>,
 }
 
 impl PacketSelector {
@@ -84,6 +86,7 @@ impl PacketSelector {
             queue: VecDeque::new(),
             bitrate: None,
             limit: (max_spatial, max_temporal),
+            room_max_spatial: None,
         }
     }
 
@@ -119,8 +122,24 @@ impl PacketSelector {
     /// Set limit layer, which is used for select best layer
     pub fn set_limit_layer(&mut self, now_ms: u64, max_spatial: u8, min_spatial: u8) {
         self.limit = (max_spatial, min_spatial);
+        self.apply_limit(now_ms);
+    }
+
+    /// Set max spatial layer of the room, None for only using the limit of client
+    pub fn set_room_max_spatial(&mut self, now_ms: u64, max_spatial: Option<u8>) {
+        self.room_max_spatial = max_spatial;
+        self.apply_limit(now_ms);
+    }
+
+    fn effective_limit(&self) -> (u8, u8) {
+        let (spatial, temporal) = self.limit;
+        (self.room_max_spatial.map_or(spatial, |room| spatial.min(room)), temporal)
+    }
+
+    fn apply_limit(&mut self, now_ms: u64) {
+        let (spatial, temporal) = self.effective_limit();
         if let Some(s) = self.selector.as_mut() {
-            s.set_limit_layer(&mut self.ctx, now_ms, max_spatial, min_spatial);
+            s.set_limit_layer(&mut self.ctx, now_ms, spatial, temporal);
         }
     }
 
@@ -192,7 +211,7 @@ impl PacketSelector {
             self.need_key_frame = false;
         }
         if self.selector.is_none() && pkt.meta.is_video_key() {
-            self.selector = create_selector(pkt, bitrate, self.limit);
+            self.selector = create_selector(pkt, bitrate, self.effective_limit());
             self.selector.as_mut().expect("Should have video selector").on_init(&mut self.ctx, now_ms);
         }
 
@@ -0,0 +1,175 @@
+//! Playout buffer of a subscribed track, it is applied to media which is sent to the client and is distinct from the
+//! receive jitter buffer of the client.
+//! In low-latency mode media is sent as soon as it arrives, so jitter between the source and this node is passed to the client.
+//! In smooth mode each packet is released at its RTP timestamp plus a target delay, counted from an anchor packet, so the
+//! client receives media at the pace it was produced at the cost of the target delay. Packets which arrive later than
+//! their release time are sent immediately, the anchor is reset when the timestamp jumps (e.g. source changed) or drifts
+//! further than the target.
+
+use std::collections::VecDeque;
+
+use media_server_protocol::media::{MediaKind, MediaPacket};
+
+/// Max number of buffered packets, the oldest packet is released when the buffer is full
+const MAX_PACKETS: usize = 512;
+
+/// Target delay of smooth mode per media kind, 0 disables the buffer for the kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayoutConfig {
+    pub audio_ms: u64,
+    pub video_ms: u64,
+}
+
+impl Default for PlayoutConfig {
+    fn default() -> Self {
+        Self { audio_ms: 60, video_ms: 100 }
+    }
+}
+
+impl PlayoutConfig {
+    pub fn target_ms(&self, kind: MediaKind) -> u64 {
+        if kind.is_video() {
+            self.video_ms
+        } else {
+            self.audio_ms
+        }
+    }
+}
+
+pub struct Playout {
+    target_ms: u64,
+    /// RTP clock rate in ticks per ms
+    clock_khz: i64,
+    smooth: bool,
+    /// (arrival ms, rtp ts) which release time of packets is counted from
+    anchor: Option<(u64, u32)>,
+    /// (release ms, packet)
+    buffer: VecDeque<(u64, MediaPacket)>,
+}
+
+impl Playout {
+    pub fn new(kind: MediaKind, cfg: PlayoutConfig) -> Self {
+        Self {
+            target_ms: cfg.target_ms(kind),
+            clock_khz: if kind.is_video() {
+                90
+            } else {
+                48
+            },
+            smooth: false,
+            anchor: None,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Switch between smooth and low-latency mode, packets which are buffered are released immediately in low-latency mode
+    pub fn set_smooth(&mut self, smooth: bool) {
+        if self.smooth != smooth {
+            log::info!("[LocalTrack/Playout] smooth {smooth}, target {} ms", self.target_ms);
+            self.smooth = smooth;
+            self.anchor = None;
+        }
+    }
+
+    /// Drop buffered packets, call it when the track is detached
+    pub fn reset(&mut self) {
+        self.anchor = None;
+        self.buffer.clear();
+    }
+
+    pub fn push(&mut self, now_ms: u64, pkt: MediaPacket) {
+        let release_ms = if self.smooth && self.target_ms > 0 {
+            // keep release time in order, so packets are not reordered
+            self.release_ms(now_ms, pkt.ts).max(self.buffer.back().map_or(0, |(release_ms, _)| *release_ms))
+        } else {
+            now_ms
+        };
+        self.buffer.push_back((release_ms, pkt));
+    }
+
+    /// Pop a packet which should be sent now
+    pub fn pop(&mut self, now_ms: u64) -> Option<MediaPacket> {
+        let (release_ms, _) = self.buffer.front()?;
+        if *release_ms <= now_ms || !self.smooth || self.buffer.len() > MAX_PACKETS {
+            self.buffer.pop_front().map(|(_, pkt)| pkt)
+        } else {
+            None
+        }
+    }
+
+    fn release_ms(&mut self, now_ms: u64, ts: u32) -> u64 {
+        let target_ms = self.target_ms as i64;
+        let now = now_ms as i64;
+        if let Some((anchor_ms, anchor_ts)) = self.anchor {
+            let release = anchor_ms as i64 + ts.wrapping_sub(anchor_ts) as i32 as i64 / self.clock_khz + target_ms;
+            if release + target_ms >= now && release <= now + 2 * target_ms {
+                return release.max(now) as u64;
+            }
+            log::debug!("[LocalTrack/Playout] release time {} ms is out of window => reset anchor", release - now);
+        }
+        self.anchor = Some((now_ms, ts));
+        now_ms + self.target_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::media::{MediaKind, MediaMeta, MediaPacket};
+
+    use super::{Playout, PlayoutConfig};
+
+    fn video_pkt(seq: u16, ts: u32) -> MediaPacket {
+        MediaPacket {
+            ts,
+            seq,
+            marker: true,
+            nackable: false,
+            layers: None,
+            meta: MediaMeta::Vp8 {
+                key: false,
+                sim: None,
+                rotation: None,
+            },
+            data: vec![1, 2, 3],
+        }
+    }
+
+    /// Push packets of (arrival ms, seq, rtp ts) and return (seq, sent ms) by popping each ms
+    fn playout(playout: &mut Playout, packets: &[(u64, u16, u32)], until_ms: u64) -> Vec<(u16, u64)> {
+        let mut sent = vec![];
+        for now_ms in 0..until_ms {
+            for (_, seq, ts) in packets.iter().filter(|(arrival, _, _)| *arrival == now_ms) {
+                playout.push(now_ms, video_pkt(*seq, *ts));
+            }
+            while let Some(pkt) = playout.pop(now_ms) {
+                sent.push((pkt.seq, now_ms));
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn smooth_mode_delay_and_remove_jitter() {
+        // frames every 33 ms (3000 ticks of 90kHz), arrive with jitter
+        let packets = [(0, 0, 0), (50, 1, 3000), (60, 2, 6000), (100, 3, 9000)];
+        let cfg = PlayoutConfig { audio_ms: 0, video_ms: 100 };
+
+        let mut low_latency = Playout::new(MediaKind::Video, cfg);
+        assert_eq!(playout(&mut low_latency, &packets, 300), vec![(0, 0), (1, 50), (2, 60), (3, 100)]);
+
+        let mut smooth = Playout::new(MediaKind::Video, cfg);
+        smooth.set_smooth(true);
+        assert_eq!(playout(&mut smooth, &packets, 300), vec![(0, 100), (1, 133), (2, 166), (3, 200)]);
+
+        // switch to low-latency releases buffered packets
+        smooth.push(300, video_pkt(4, 36000));
+        assert_eq!(smooth.pop(300).map(|pkt| pkt.seq), None);
+        smooth.set_smooth(false);
+        assert_eq!(smooth.pop(300).map(|pkt| pkt.seq), Some(4));
+
+        // zero target of audio is same as low-latency
+        let mut audio = Playout::new(MediaKind::Audio, cfg);
+        audio.set_smooth(true);
+        assert_eq!(playout(&mut audio, &[(10, 0, 0), (35, 1, 960)], 50), vec![(0, 10), (1, 35)]);
+    }
+}
@@ -0,0 +1,287 @@
+//! RelayGrace smooths the transition when the relay path of a subscribed channel changes.
+//! While the relay is changing, packets from the old and new path can be duplicated or reordered and media may stall briefly.
+//! Inside the grace window packets are reordered by seq in a bounded buffer and duplicates are dropped, so the viewer gets continuous media.
+//! A key-frame is only needed when media stalled longer than the key-frame gap, or packets are still missing at the end of the window.
+//! Simulcast layers have separated sequence spaces, so each spatial layer is tracked independently.
+
+use std::collections::VecDeque;
+
+use media_server_protocol::media::{MediaMeta, MediaPacket};
+use sans_io_runtime::return_if_none;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayGraceConfig {
+    /// Window after relay changed which packets are reordered in, 0 disables the buffer and video always requests key-frame
+    pub grace_ms: u64,
+    /// Media gap caused by relay change which is longer than this requests a key-frame
+    pub key_frame_gap_ms: u64,
+    /// Max number of buffered packets, the oldest packet is released when the buffer is full
+    pub max_packets: usize,
+}
+
+impl Default for RelayGraceConfig {
+    fn default() -> Self {
+        Self {
+            grace_ms: 200,
+            key_frame_gap_ms: 500,
+            max_packets: 64,
+        }
+    }
+}
+
+struct Grace {
+    started_ms: u64,
+    got_media: bool,
+    buffer: VecDeque<(u64, MediaPacket)>,
+}
+
+pub struct RelayGrace {
+    cfg: RelayGraceConfig,
+    /// Last released seq of each spatial layer
+    layers: Vec<Option<u16>>,
+    last_media_ms: Option<u64>,
+    grace: Option<Grace>,
+    ready: VecDeque<(u64, MediaPacket)>,
+    key_frame: bool,
+}
+
+fn spatial(pkt: &MediaPacket) -> usize {
+    match &pkt.meta {
+        MediaMeta::H264 { sim: Some(sim), .. } => sim.spatial as usize,
+        MediaMeta::Vp8 { sim: Some(sim), .. } => sim.spatial as usize,
+        _ => 0,
+    }
+}
+
+impl RelayGrace {
+    pub fn new(cfg: RelayGraceConfig) -> Self {
+        Self {
+            cfg,
+            layers: vec![],
+            last_media_ms: None,
+            grace: None,
+            ready: VecDeque::new(),
+            key_frame: false,
+        }
+    }
+
+    /// Reset, call reset if local_track changed source. Buffered packets of old source are dropped
+    pub fn reset(&mut self) {
+        self.layers.clear();
+        self.last_media_ms = None;
+        self.grace = None;
+    }
+
+    /// Start grace window, return true if key-frame should be requested now because there is nothing to keep continuous
+    pub fn on_relay_changed(&mut self, now_ms: u64) -> bool {
+        if self.cfg.grace_ms == 0 || self.last_media_ms.is_none() {
+            return true;
+        }
+        self.end_grace();
+        self.grace = Some(Grace {
+            started_ms: now_ms,
+            got_media: false,
+            buffer: VecDeque::new(),
+        });
+        false
+    }
+
+    pub fn on_tick(&mut self, now_ms: u64) {
+        if self.grace.as_ref().is_some_and(|grace| now_ms >= grace.started_ms + self.cfg.grace_ms) {
+            self.end_grace();
+        }
+    }
+
+    pub fn push(&mut self, now_ms: u64, channel: u64, pkt: MediaPacket) {
+        if self.grace.is_none() {
+            self.release(now_ms, channel, pkt);
+            return;
+        }
+        let grace = self.grace.as_mut().expect("Should have grace");
+
+        if !grace.got_media {
+            grace.got_media = true;
+            let last_media_ms = self.last_media_ms.unwrap_or(grace.started_ms);
+            if now_ms >= last_media_ms + self.cfg.key_frame_gap_ms {
+                log::info!("[LocalTrack/RelayGrace] media gap {} ms after relay changed => need key-frame", now_ms - last_media_ms);
+                self.key_frame = true;
+            }
+        }
+
+        let layer = spatial(&pkt);
+        match self.layers.get(layer) {
+            Some(Some(last)) => {
+                let diff = pkt.seq.wrapping_sub(*last);
+                if diff == 0 || diff >= u16::MAX / 2 {
+                    log::debug!("[LocalTrack/RelayGrace] drop duplicated or old packet {} from relay changing", pkt.seq);
+                    return;
+                }
+            }
+            _ => {
+                // first packet of this layer, nothing to keep continuous with
+                self.release(now_ms, channel, pkt);
+                return;
+            }
+        }
+        if grace.buffer.iter().any(|(_, buffered)| buffered.seq == pkt.seq && spatial(buffered) == layer) {
+            return;
+        }
+        // keep packets of each layer in seq order
+        let pos = match grace
+            .buffer
+            .iter()
+            .rposition(|(_, buffered)| spatial(buffered) == layer && pkt.seq.wrapping_sub(buffered.seq) < u16::MAX / 2)
+        {
+            Some(pos) => pos + 1,
+            None => grace.buffer.iter().position(|(_, buffered)| spatial(buffered) == layer).unwrap_or(grace.buffer.len()),
+        };
+        grace.buffer.insert(pos, (channel, pkt));
+        if grace.buffer.len() > self.cfg.max_packets {
+            let (channel, pkt) = grace.buffer.pop_front().expect("Should have packet");
+            self.key_frame |= !self.is_next(&pkt);
+            self.release(now_ms, channel, pkt);
+        }
+        self.release_continuous(now_ms);
+    }
+
+    pub fn pop(&mut self) -> Option<(u64, MediaPacket)> {
+        self.ready.pop_front()
+    }
+
+    /// Return true once if a key-frame should be requested
+    pub fn take_key_frame(&mut self) -> bool {
+        std::mem::replace(&mut self.key_frame, false)
+    }
+
+    fn is_next(&self, pkt: &MediaPacket) -> bool {
+        match self.layers.get(spatial(pkt)) {
+            Some(Some(last)) => pkt.seq == last.wrapping_add(1),
+            _ => true,
+        }
+    }
+
+    fn release_continuous(&mut self, now_ms: u64) {
+        loop {
+            let grace = return_if_none!(self.grace.as_mut());
+            let pos = return_if_none!(grace.buffer.iter().position(|(_, pkt)| match self.layers.get(spatial(pkt)) {
+                Some(Some(last)) => pkt.seq == last.wrapping_add(1),
+                _ => false,
+            }));
+            let (channel, pkt) = grace.buffer.remove(pos).expect("Should have packet");
+            self.release(now_ms, channel, pkt);
+        }
+    }
+
+    fn end_grace(&mut self) {
+        let grace = return_if_none!(self.grace.take());
+        if !grace.buffer.is_empty() {
+            log::info!("[LocalTrack/RelayGrace] grace ended with {} packets after missing seq => need key-frame", grace.buffer.len());
+            self.key_frame = true;
+        }
+        for (channel, pkt) in grace.buffer {
+            let now_ms = self.last_media_ms.unwrap_or_default();
+            self.release(now_ms, channel, pkt);
+        }
+    }
+
+    fn release(&mut self, now_ms: u64, channel: u64, pkt: MediaPacket) {
+        let layer = spatial(&pkt);
+        if self.layers.len() <= layer {
+            self.layers.resize(layer + 1, None);
+        }
+        self.layers[layer] = Some(pkt.seq);
+        self.last_media_ms = Some(now_ms);
+        self.ready.push_back((channel, pkt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::media::{MediaMeta, MediaPacket};
+
+    use super::{RelayGrace, RelayGraceConfig};
+
+    fn pkt(seq: u16) -> MediaPacket {
+        MediaPacket {
+            ts: seq as u32 * 3000,
+            seq,
+            marker: true,
+            nackable: false,
+            layers: None,
+            meta: MediaMeta::Vp8 {
+                key: false,
+                sim: None,
+                rotation: None,
+            },
+            data: vec![1, 2, 3],
+        }
+    }
+
+    fn pop_seqs(grace: &mut RelayGrace) -> Vec<u16> {
+        let mut seqs = vec![];
+        while let Some((_, pkt)) = grace.pop() {
+            seqs.push(pkt.seq);
+        }
+        seqs
+    }
+
+    #[test]
+    fn reorder_and_dedup_in_grace() {
+        let mut grace = RelayGrace::new(RelayGraceConfig::default());
+        grace.push(0, 1, pkt(1));
+        grace.push(10, 1, pkt(2));
+        assert_eq!(pop_seqs(&mut grace), vec![1, 2]);
+        assert!(!grace.on_relay_changed(20));
+
+        // new relay delivers 4 before 3, old relay delivers duplicated 2
+        grace.push(30, 1, pkt(4));
+        grace.push(31, 1, pkt(2));
+        assert_eq!(pop_seqs(&mut grace), Vec::<u16>::new());
+        grace.push(32, 1, pkt(3));
+        grace.push(40, 1, pkt(5));
+        assert_eq!(pop_seqs(&mut grace), vec![3, 4, 5]);
+        grace.on_tick(220);
+        assert!(!grace.take_key_frame());
+    }
+
+    #[test]
+    fn missing_seq_at_grace_end_need_key_frame() {
+        let mut grace = RelayGrace::new(RelayGraceConfig::default());
+        grace.push(0, 1, pkt(1));
+        assert_eq!(pop_seqs(&mut grace), vec![1]);
+        assert!(!grace.on_relay_changed(10));
+        grace.push(20, 1, pkt(3));
+        grace.push(30, 1, pkt(4));
+        grace.on_tick(100);
+        assert_eq!(pop_seqs(&mut grace), Vec::<u16>::new());
+
+        grace.on_tick(210);
+        assert_eq!(pop_seqs(&mut grace), vec![3, 4]);
+        assert!(grace.take_key_frame());
+        assert!(!grace.take_key_frame());
+    }
+
+    #[test]
+    fn buffer_is_bounded() {
+        let mut grace = RelayGrace::new(RelayGraceConfig { max_packets: 4, ..Default::default() });
+        grace.push(0, 1, pkt(1));
+        assert_eq!(pop_seqs(&mut grace), vec![1]);
+        assert!(!grace.on_relay_changed(10));
+        for seq in 3..8 {
+            grace.push(20, 1, pkt(seq));
+        }
+        // buffer overflow releases oldest packet then all continuous packets after it
+        assert_eq!(pop_seqs(&mut grace), vec![3, 4, 5, 6, 7]);
+        assert!(grace.take_key_frame());
+    }
+
+    #[test]
+    fn disabled_or_no_media_request_key_frame() {
+        let mut grace = RelayGrace::new(RelayGraceConfig { grace_ms: 0, ..Default::default() });
+        grace.push(0, 1, pkt(1));
+        assert!(grace.on_relay_changed(10));
+
+        let mut grace = RelayGrace::new(RelayGraceConfig::default());
+        assert!(grace.on_relay_changed(10));
+    }
+}
@@ -39,6 +39,8 @@ pub enum Output {
     Started(MediaKind, TrackPriority),
     Update(MediaKind, TrackPriority),
     Stopped(MediaKind),
+    /// Mute state is changed by client, it is stored in track metadata of the room
+    Muted(ClusterRoomHash, TrackName, bool),
 }
 
 pub struct EndpointRemoteTrack {
@@ -51,6 +53,8 @@ pub struct EndpointRemoteTrack {
     /// This is for storing current stream layers, everytime key-frame arrived we will set this if it not set
     last_layers: Option<MediaLayersBitrate>,
     cluster_bitrate_limit: Option<(u64, u64)>,
+    /// Target bitrate of each spatial layer which is requested by cluster consumers, only for simulcast streams
+    cluster_layer_targets: Option<[Option<u64>; 3]>,
     record: bool,
     shutdown: bool,
 }
@@ -67,6 +71,7 @@ impl EndpointRemoteTrack {
             allocate_bitrate: None,
             last_layers: None,
             cluster_bitrate_limit: None,
+            cluster_layer_targets: None,
             record,
             shutdown: false,
         }
@@ -113,11 +118,16 @@ impl EndpointRemoteTrack {
     fn on_cluster_event(&mut self, _now: Instant, event: ClusterRemoteTrackEvent) {
         match event {
             ClusterRemoteTrackEvent::RequestKeyFrame => self.queue.push_back(Output::Event(EndpointRemoteTrackEvent::RequestKeyFrame)),
-            ClusterRemoteTrackEvent::LimitBitrate { min, max } => {
+            ClusterRemoteTrackEvent::PublishRejected => {
+                log::warn!("[EndpointRemoteTrack] publish {} rejected by room, other sessions already publish same track", self.name);
+            }
+            ClusterRemoteTrackEvent::LimitBitrate { min, max, layers } => {
                 self.cluster_bitrate_limit = Some((min, max));
+                self.cluster_layer_targets = layers;
                 if self.meta.control.eq(&BitrateControlMode::DynamicConsumers) {
                     if let Some((min, max)) = self.calc_limit_bitrate() {
-                        self.queue.push_back(Output::Event(EndpointRemoteTrackEvent::LimitBitrateBps { min, max }));
+                        let layers = self.calc_limit_layers();
+                        self.queue.push_back(Output::Event(EndpointRemoteTrackEvent::LimitBitrateBps { min, max, layers }));
                     }
                 }
             }
@@ -198,6 +208,18 @@ impl EndpointRemoteTrack {
                     self.queue.push_back(Output::Update(self.meta.kind, config.priority));
                 }
             }
+            EndpointRemoteTrackReq::Mute(muted) => {
+                if let Some(room) = self.room {
+                    log::info!("[EndpointRemoteTrack] set muted {muted}");
+                    self.meta.muted = muted;
+                    self.queue.push_back(Output::RpcRes(req_id, EndpointRemoteTrackRes::Mute(Ok(()))));
+                    self.queue.push_back(Output::Muted(room, self.name.clone(), muted));
+                } else {
+                    log::warn!("[EndpointRemoteTrack] set muted {muted} but not in room");
+                    self.queue
+                        .push_back(Output::RpcRes(req_id, EndpointRemoteTrackRes::Mute(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))));
+                }
+            }
         }
     }
 
@@ -207,7 +229,8 @@ impl EndpointRemoteTrack {
                 log::info!("[EndpointRemoteTrack] on allocation bitrate {bitrate}");
                 self.allocate_bitrate = Some(bitrate);
                 if let Some((min, max)) = self.calc_limit_bitrate() {
-                    self.queue.push_back(Output::Event(EndpointRemoteTrackEvent::LimitBitrateBps { min, max }))
+                    let layers = self.calc_limit_layers();
+                    self.queue.push_back(Output::Event(EndpointRemoteTrackEvent::LimitBitrateBps { min, max, layers }))
                 }
             }
         }
@@ -222,6 +245,20 @@ impl EndpointRemoteTrack {
             (None, None) => None,
         }
     }
+
+    /// Layer targets from cluster, each of them is also limited by allocated bitrate
+    fn calc_limit_layers(&self) -> Option<[Option<u64>; 3]> {
+        if !self.meta.control.eq(&BitrateControlMode::DynamicConsumers) {
+            return None;
+        }
+        let mut layers = self.cluster_layer_targets?;
+        if let Some(allocated) = self.allocate_bitrate {
+            for target in layers.iter_mut().flatten() {
+                *target = (*target).min(allocated);
+            }
+        }
+        Some(layers)
+    }
 }
 
 impl Task<Input, Output> for EndpointRemoteTrack {
@@ -276,12 +313,18 @@ mod tests {
     use std::time::{Duration, Instant};
 
     use media_server_protocol::{
-        endpoint::{TrackMeta, TrackName},
+        endpoint::{BitrateControlMode, TrackMeta, TrackName},
         protobuf::{cluster_connector::peer_event, shared::Kind},
+        transport::RpcError,
     };
     use sans_io_runtime::{Task, TaskSwitcherChild};
 
-    use crate::{cluster::ClusterRemoteTrackControl, transport::RemoteTrackEvent};
+    use crate::{
+        cluster::{ClusterRemoteTrackControl, ClusterRemoteTrackEvent},
+        endpoint::{internal::bitrate_allocator::IngressAction, EndpointRemoteTrackEvent, EndpointRemoteTrackReq, EndpointRemoteTrackRes},
+        errors::EndpointErrors,
+        transport::RemoteTrackEvent,
+    };
 
     use super::{EndpointRemoteTrack, Input, Output};
 
@@ -339,6 +382,86 @@ mod tests {
         assert!(track.is_empty());
     }
 
+    #[test_log::test]
+    fn layer_targets_to_publisher() {
+        let room = 0.into();
+        let mut meta = TrackMeta::default_video();
+        meta.control = BitrateControlMode::DynamicConsumers;
+        let now = Instant::now();
+        let mut track = EndpointRemoteTrack::new(Some(room), 1.into(), TrackName::from("video_main"), meta, false);
+
+        // 3 layers simulcast stream
+        let layers = Some([Some(150_000), Some(500_000), Some(2_000_000)]);
+        track.on_event(now, Input::Cluster(ClusterRemoteTrackEvent::LimitBitrate { min: 150_000, max: 2_000_000, layers }));
+        assert_eq!(
+            track.pop_output(now),
+            Some(Output::Event(EndpointRemoteTrackEvent::LimitBitrateBps { min: 150_000, max: 2_000_000, layers }))
+        );
+        assert_eq!(track.pop_output(now), None);
+
+        // layer targets are limited by allocated ingress bitrate
+        track.on_event(now, Input::BitrateAllocation(IngressAction::SetBitrate(1_000_000)));
+        assert_eq!(
+            track.pop_output(now),
+            Some(Output::Event(EndpointRemoteTrackEvent::LimitBitrateBps {
+                min: 150_000,
+                max: 1_000_000,
+                layers: Some([Some(150_000), Some(500_000), Some(1_000_000)]),
+            }))
+        );
+        assert_eq!(track.pop_output(now), None);
+
+        // single-encoding streams keep simple min max
+        track.on_event(
+            now,
+            Input::Cluster(ClusterRemoteTrackEvent::LimitBitrate {
+                min: 300_000,
+                max: 800_000,
+                layers: None,
+            }),
+        );
+        assert_eq!(
+            track.pop_output(now),
+            Some(Output::Event(EndpointRemoteTrackEvent::LimitBitrateBps {
+                min: 300_000,
+                max: 800_000,
+                layers: None
+            }))
+        );
+        assert_eq!(track.pop_output(now), None);
+    }
+
+    #[test_log::test]
+    fn mute_in_room() {
+        let room = 0.into();
+        let track_name = TrackName::from("audio_main");
+        let now = Instant::now();
+        let mut track = EndpointRemoteTrack::new(Some(room), 1.into(), track_name.clone(), TrackMeta::default_audio(), false);
+
+        track.on_event(now, Input::RpcReq(1.into(), EndpointRemoteTrackReq::Mute(true)));
+        assert_eq!(track.pop_output(now), Some(Output::RpcRes(1.into(), EndpointRemoteTrackRes::Mute(Ok(())))));
+        assert_eq!(track.pop_output(now), Some(Output::Muted(room, track_name.clone(), true)));
+        assert_eq!(track.pop_output(now), None);
+
+        track.on_event(now, Input::RpcReq(2.into(), EndpointRemoteTrackReq::Mute(false)));
+        assert_eq!(track.pop_output(now), Some(Output::RpcRes(2.into(), EndpointRemoteTrackRes::Mute(Ok(())))));
+        assert_eq!(track.pop_output(now), Some(Output::Muted(room, track_name, false)));
+        assert_eq!(track.pop_output(now), None);
+    }
+
+    #[test_log::test]
+    fn mute_not_in_room() {
+        let now = Instant::now();
+        let mut track = EndpointRemoteTrack::new(None, 1.into(), TrackName::from("audio_main"), TrackMeta::default_audio(), false);
+
+        track.on_event(now, Input::RpcReq(1.into(), EndpointRemoteTrackReq::Mute(true)));
+        assert_eq!(
+            track.pop_output(now),
+            Some(Output::RpcRes(1.into(), EndpointRemoteTrackRes::Mute(Err(RpcError::new2(EndpointErrors::EndpointNotInRoom)))))
+        );
+        assert_eq!(track.pop_output(now), None);
+    }
+
     //TODO start not in room
     //TODO stop in room
     //TODO stop not in room
@@ -2,10 +2,17 @@
 #[repr(u32)]
 pub enum EndpointErrors {
     EndpointNotInRoom = 0x0001,
+    EndpointAlreadyInRoom = 0x0002,
     LocalTrackNotPinSource = 0x1001,
     LocalTrackInvalidPriority = 0x1002,
+    LocalTrackEgressBudgetFull = 0x1003,
+    LocalTrackKindFiltered = 0x1004,
+    LocalTrackRejected = 0x1005,
     RemoteTrackInvalidPriority = 0x2001,
     RemoteTrackStopped = 0x2002,
+    RemoteTrackRejected = 0x2003,
     AudioMixerWrongMode = 0x3001,
     Destroying = 0x4001,
+    RoomConfigRequireRejoin = 0x5001,
+    MessageChannelTooLarge = 0x6001,
 }
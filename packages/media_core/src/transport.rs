@@ -79,6 +79,17 @@ impl RemoteTrackEvent {
     }
 }
 
+/// Result of offer/answer negotiation, it is only used for diagnostics
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TransportNegotiated {
+    pub audio_codec: Option<String>,
+    pub video_codec: Option<String>,
+    /// Largest simulcast layer (width, height), only known when client signals layer restrictions
+    pub max_resolution: Option<(u32, u32)>,
+    /// Simulcast layers which are published by client
+    pub layers: Vec<String>,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum TransportEvent {
     State(TransportState),
@@ -86,6 +97,9 @@ pub enum TransportEvent {
     LocalTrack(LocalTrackId, LocalTrackEvent),
     Stats(TransportStats),
     EgressBitrateEstimate(u64),
+    /// Rtt in milliseconds of the active path to client, it is only sent when changed
+    Rtt(u32),
+    Negotiated(TransportNegotiated),
 }
 
 /// This is control message from endpoint
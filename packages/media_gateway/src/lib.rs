@@ -1,6 +1,14 @@
 pub mod agent_service;
+mod residency;
+pub mod route_trace;
+mod sdn_health;
 mod store;
 pub mod store_service;
+mod zone_fallback;
+
+pub use residency::{AppResidency, ZoneConstraint};
+pub use sdn_health::{sdn_health, SdnHealth, SdnProbe};
+pub use zone_fallback::ZoneFallback;
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum ServiceKind {
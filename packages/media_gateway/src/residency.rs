@@ -0,0 +1,73 @@
+//! Data residency of apps. Sessions of a restricted app are only placed on nodes of its allowed zones, and a client whose
+//! region (nearest zone) is not allowed is rejected instead of routed to other region. This covers every route which
+//! gateway selects for the app: client connects, restart-ice, migrate and rtpengine.
+//!
+//! Only session placement is restricted, media nodes don't know residency of apps. Pubsub relay between media nodes
+//! follows the sdn overlay routing, which can transit nodes of other zones even when both ends are in allowed zones, so
+//! deployments which must keep media inside a region should run a separate cluster there.
+
+use std::str::FromStr;
+
+use media_server_protocol::{cluster::ZoneId, multi_tenancy::AppId};
+
+/// Zones which a route of a restricted app must stay in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZoneConstraint {
+    /// Client connect, it is rejected when the region of client is not one of the zones
+    Client(Vec<ZoneId>),
+    /// Route without a known client location, like migrate or rtpengine, only nodes of the zones are selected
+    Nodes(Vec<ZoneId>),
+}
+
+impl ZoneConstraint {
+    pub fn zones(&self) -> &[ZoneId] {
+        match self {
+            Self::Client(zones) | Self::Nodes(zones) => zones,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppResidency {
+    pub app: AppId,
+    pub zones: Vec<ZoneId>,
+}
+
+/// Parse from format `<app>=<zone1>,<zone2>`, example `app1=1,2`
+impl FromStr for AppResidency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (app, zones) = s.split_once('=').ok_or_else(|| format!("invalid app residency {s}, expected <app>=<zone1>,<zone2>"))?;
+        let zones = zones
+            .split(',')
+            .filter(|zone| !zone.trim().is_empty())
+            .map(|zone| zone.trim().parse::<u32>().map(ZoneId).map_err(|_| format!("invalid zone {zone} in app residency {s}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        if zones.is_empty() {
+            return Err(format!("app residency {s} has no zone"));
+        }
+        Ok(Self { app: app.trim().into(), zones })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::cluster::ZoneId;
+
+    use super::AppResidency;
+
+    #[test]
+    fn parse_app_residency() {
+        assert_eq!(
+            "app1=1, 2".parse::<AppResidency>(),
+            Ok(AppResidency {
+                app: "app1".into(),
+                zones: vec![ZoneId(1), ZoneId(2)]
+            })
+        );
+        assert!("app1".parse::<AppResidency>().is_err());
+        assert!("app1=".parse::<AppResidency>().is_err());
+        assert!("app1=a".parse::<AppResidency>().is_err());
+    }
+}
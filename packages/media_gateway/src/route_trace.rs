@@ -0,0 +1,123 @@
+//! Sampled traces of routing decisions, for capacity planning: which zones and nodes are chosen for which client
+//! locations, and how often the pool is empty. Unlike route feedback, which is per session and sent to connector,
+//! a trace is about the selector behavior and it is exported to a [`RouteTraceSink`], e.g. log or an OTLP exporter.
+
+use std::{fmt::Debug, sync::Arc};
+
+use atm0s_sdn::NodeId;
+use media_server_protocol::{cluster::ZoneId, protobuf::cluster_gateway::ping_event::gateway_origin::Location};
+
+use crate::ServiceKind;
+
+/// How the node of a route is selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteOutcome {
+    /// Media node in current zone
+    Local,
+    /// Gateway of the nearest other zone
+    Zone(ZoneId),
+    /// Gateway or media node of a fallback zone because the region of client is empty
+    Fallback(ZoneId),
+    /// Region of client is not in allowed zones of the app, the route is rejected instead of routed to other region
+    RegionNotAllowed(ZoneId),
+    PoolEmpty,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteCandidate {
+    pub node: NodeId,
+    pub zone: ZoneId,
+    pub usage: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteTrace {
+    pub kind: ServiceKind,
+    /// Location of client, None when it is unknown and the gateway location is used
+    pub location: Option<Location>,
+    pub candidates: Vec<RouteCandidate>,
+    /// Nodes which are skipped by circuit breaker
+    pub excluded: Vec<NodeId>,
+    pub chosen: Option<NodeId>,
+    pub outcome: RouteOutcome,
+}
+
+pub trait RouteTraceSink: Send + Sync {
+    fn export(&self, trace: RouteTrace);
+}
+
+/// Write traces to log with target `route_trace`, so they can be filtered or shipped separately
+pub struct LogRouteTraceSink;
+
+impl RouteTraceSink for LogRouteTraceSink {
+    fn export(&self, trace: RouteTrace) {
+        log::info!(target: "route_trace", "{:?}", trace);
+    }
+}
+
+#[derive(Clone)]
+pub struct RouteTraceConfig {
+    /// Fraction of routes which are traced, 0.0-1.0
+    pub rate: f32,
+    pub sink: Arc<dyn RouteTraceSink>,
+}
+
+impl Debug for RouteTraceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RouteTraceConfig").field("rate", &self.rate).finish()
+    }
+}
+
+pub struct RouteTracer {
+    rate: f32,
+    credit: f32,
+    sink: Arc<dyn RouteTraceSink>,
+}
+
+impl RouteTracer {
+    pub fn new(cfg: RouteTraceConfig) -> Self {
+        Self {
+            rate: cfg.rate.clamp(0.0, 1.0),
+            credit: 0.0,
+            sink: cfg.sink,
+        }
+    }
+
+    /// Return true when the next route should be traced. Samples are spread evenly instead of random,
+    /// so a rate is exact over a short window
+    pub fn sample(&mut self) -> bool {
+        self.credit += self.rate;
+        if self.credit >= 1.0 {
+            self.credit -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn export(&self, trace: RouteTrace) {
+        self.sink.export(trace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{LogRouteTraceSink, RouteTraceConfig, RouteTracer};
+
+    #[test]
+    fn sample_rate() {
+        let sampled = |rate: f32| {
+            let mut tracer = RouteTracer::new(RouteTraceConfig {
+                rate,
+                sink: Arc::new(LogRouteTraceSink),
+            });
+            (0..100).filter(|_| tracer.sample()).count()
+        };
+        assert_eq!(sampled(0.0), 0);
+        assert_eq!(sampled(0.25), 25);
+        assert_eq!(sampled(1.0), 100);
+        assert_eq!(sampled(2.0), 100);
+    }
+}
@@ -0,0 +1,119 @@
+//! SDN health of nodes which send pings to this gateway. A node can be up but poorly reachable over SDN, e.g. a lossy or
+//! congested path, then its pings arrive late or are lost. Pings are sent every tick, so the delay of a ping is how much
+//! later than the interval it arrives after the previous one. The delay is smoothed per node, and the silence since the
+//! last ping is counted too, so a node whose pings stop is unhealthy before it is removed by ping timeout.
+//!
+//! Routing skips unhealthy nodes and prefers healthy ones over degraded ones, usage only orders nodes of same health.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use atm0s_sdn::NodeId;
+
+/// Interval of pings, it is the SDN tick
+pub const PING_INTERVAL_MS: u64 = 1000;
+const DEGRADED_DELAY_MS: u64 = 300;
+const UNHEALTHY_DELAY_MS: u64 = 1000;
+
+/// Ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SdnHealth {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+impl SdnHealth {
+    fn from_delay(delay_ms: u64) -> Self {
+        if delay_ms >= UNHEALTHY_DELAY_MS {
+            Self::Unhealthy
+        } else if delay_ms >= DEGRADED_DELAY_MS {
+            Self::Degraded
+        } else {
+            Self::Healthy
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdnProbe {
+    last_ping: u64,
+    /// Smoothed delay of pings
+    delay_ms: u64,
+    health: SdnHealth,
+}
+
+impl SdnProbe {
+    pub fn new(now: u64) -> Self {
+        Self {
+            last_ping: now,
+            delay_ms: 0,
+            health: SdnHealth::Healthy,
+        }
+    }
+
+    pub fn on_ping(&mut self, now: u64) {
+        let delay = now.saturating_sub(self.last_ping).saturating_sub(PING_INTERVAL_MS);
+        self.delay_ms = (self.delay_ms * 3 + delay) / 4;
+        self.last_ping = now;
+        self.health = SdnHealth::from_delay(self.delay_ms);
+    }
+
+    pub fn on_tick(&mut self, now: u64) {
+        let silence = now.saturating_sub(self.last_ping).saturating_sub(PING_INTERVAL_MS);
+        self.health = SdnHealth::from_delay(self.delay_ms.max(silence));
+    }
+
+    pub fn health(&self) -> SdnHealth {
+        self.health
+    }
+
+    pub fn delay_ms(&self) -> u64 {
+        self.delay_ms
+    }
+}
+
+/// SDN health of nodes which are seen by the gateway of this process, for metrics
+static SDN_HEALTH: Mutex<BTreeMap<NodeId, SdnProbe>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn set_sdn_health(nodes: BTreeMap<NodeId, SdnProbe>) {
+    *SDN_HEALTH.lock().expect("Should lock sdn health") = nodes;
+}
+
+/// SDN health of nodes which send pings to this gateway, empty when this process isn't a gateway
+pub fn sdn_health() -> BTreeMap<NodeId, SdnProbe> {
+    SDN_HEALTH.lock().expect("Should lock sdn health").clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SdnHealth, SdnProbe, PING_INTERVAL_MS};
+
+    #[test]
+    fn lost_pings_degrade_health() {
+        let mut probe = SdnProbe::new(0);
+        for i in 1..=5 {
+            probe.on_ping(i * PING_INTERVAL_MS);
+        }
+        assert_eq!(probe.health(), SdnHealth::Healthy);
+
+        // every other ping is lost
+        for i in 1..=5 {
+            probe.on_ping(5 * PING_INTERVAL_MS + i * 2 * PING_INTERVAL_MS);
+        }
+        assert_eq!(probe.health(), SdnHealth::Degraded);
+        for i in 1..=5 {
+            probe.on_ping(15 * PING_INTERVAL_MS + i * 3 * PING_INTERVAL_MS);
+        }
+        assert_eq!(probe.health(), SdnHealth::Unhealthy);
+
+        // recovered after pings are regular again
+        for i in 1..=10 {
+            probe.on_ping(30 * PING_INTERVAL_MS + i * PING_INTERVAL_MS);
+        }
+        assert_eq!(probe.health(), SdnHealth::Healthy);
+
+        // silence is unhealthy before the ping timeout
+        probe.on_tick(40 * PING_INTERVAL_MS + 2500);
+        assert_eq!(probe.health(), SdnHealth::Unhealthy);
+    }
+}
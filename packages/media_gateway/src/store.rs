@@ -1,10 +1,16 @@
+use std::collections::BTreeMap;
+
 use atm0s_sdn::NodeId;
 use media_server_protocol::{
     cluster::ZoneId,
     protobuf::cluster_gateway::ping_event::{gateway_origin::Location, GatewayOrigin, Origin, ServiceStats},
 };
 
-use crate::{NodeMetrics, ServiceKind};
+use crate::{
+    route_trace::{RouteTrace, RouteTraceConfig, RouteTracer},
+    sdn_health::set_sdn_health,
+    NodeMetrics, ServiceKind, ZoneConstraint, ZoneFallback,
+};
 
 use self::service::ServiceStore;
 
@@ -30,23 +36,31 @@ pub struct GatewayStore {
     max_cpu: u8,
     max_memory: u8,
     max_disk: u8,
+    tracer: Option<RouteTracer>,
 }
 
 impl GatewayStore {
-    pub fn new(zone: ZoneId, location: Location, max_cpu: u8, max_memory: u8, max_disk: u8) -> Self {
+    pub fn new(zone: ZoneId, location: Location, max_cpu: u8, max_memory: u8, max_disk: u8, fallbacks: Vec<ZoneFallback>) -> Self {
         Self {
             node: NodeMetrics::default(),
-            webrtc: ServiceStore::new(zone, ServiceKind::Webrtc, location),
-            rtpengine: ServiceStore::new(zone, ServiceKind::RtpEngine, location),
+            webrtc: ServiceStore::new(zone, ServiceKind::Webrtc, location, fallbacks.clone()),
+            rtpengine: ServiceStore::new(zone, ServiceKind::RtpEngine, location, fallbacks),
             zone,
             location,
             output: None,
             max_cpu,
             max_disk,
             max_memory,
+            tracer: None,
         }
     }
 
+    /// Export a sample of routing decisions, see [`crate::route_trace`]
+    pub fn set_route_trace(&mut self, cfg: RouteTraceConfig) {
+        log::info!("[GatewayStore] route trace enabled with {:?}", cfg);
+        self.tracer = Some(RouteTracer::new(cfg));
+    }
+
     pub fn on_node_metrics(&mut self, _now: u64, metrics: NodeMetrics) {
         self.node = metrics;
     }
@@ -54,6 +68,7 @@ impl GatewayStore {
     pub fn on_tick(&mut self, now: u64) {
         self.webrtc.on_tick(now);
         self.rtpengine.on_tick(now);
+        set_sdn_health(self.webrtc.sdn_probes().chain(self.rtpengine.sdn_probes()).collect::<BTreeMap<_, _>>());
 
         let ping = PingEvent {
             cpu: self.node.cpu,
@@ -109,12 +124,25 @@ impl GatewayStore {
         }
     }
 
-    pub fn best_for(&self, kind: ServiceKind, location: Option<Location>) -> Option<NodeId> {
-        let node = match kind {
-            ServiceKind::Webrtc => self.webrtc.best_for(location),
-            ServiceKind::RtpEngine => self.rtpengine.best_for(location),
+    pub fn best_for(&mut self, kind: ServiceKind, location: Option<Location>, excluded: &[NodeId], constraint: Option<&ZoneConstraint>) -> Option<NodeId> {
+        let store = match kind {
+            ServiceKind::Webrtc => &self.webrtc,
+            ServiceKind::RtpEngine => &self.rtpengine,
         };
+        let (node, outcome) = store.route(location, excluded, constraint);
         log::debug!("[GatewayStore] query best {:?} for {:?} got {:?}", kind, location, node);
+        if let Some(tracer) = self.tracer.as_mut() {
+            if tracer.sample() {
+                tracer.export(RouteTrace {
+                    candidates: store.candidates(),
+                    kind,
+                    location,
+                    excluded: excluded.to_vec(),
+                    chosen: node,
+                    outcome,
+                });
+            }
+        }
         node
     }
 
@@ -127,6 +155,24 @@ impl GatewayStore {
         node
     }
 
+    /// Return all media nodes in current zone and one gateway for each other zone, without duplicates.
+    /// This is used for broadcasting admin requests to whole cluster
+    pub fn nodes(&self) -> (Vec<NodeId>, Vec<NodeId>) {
+        let mut nodes = vec![];
+        for node in self.webrtc.local_nodes().chain(self.rtpengine.local_nodes()) {
+            if !nodes.contains(&node) {
+                nodes.push(node);
+            }
+        }
+        let mut gateways = vec![];
+        for gateway in self.webrtc.zone_gateways().chain(self.rtpengine.zone_gateways()) {
+            if !gateways.contains(&gateway) {
+                gateways.push(gateway);
+            }
+        }
+        (nodes, gateways)
+    }
+
     pub fn local_stats(&self) -> Option<ServiceStats> {
         self.webrtc.local_stats()
     }
@@ -188,18 +234,32 @@ fn rtpengine_usage(ping: &PingEvent, max_cpu: u8, max_memory: u8, max_disk: u8)
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+
     use media_server_protocol::{
         cluster::ZoneId,
         protobuf::cluster_gateway::ping_event::{gateway_origin::Location, GatewayOrigin, MediaOrigin, Origin, ServiceStats},
     };
 
-    use crate::ServiceKind;
+    use crate::{
+        route_trace::{RouteCandidate, RouteOutcome, RouteTrace, RouteTraceConfig, RouteTraceSink},
+        ServiceKind, ZoneConstraint,
+    };
 
     use super::{GatewayStore, PingEvent};
 
+    #[derive(Default)]
+    struct VecSink(Mutex<Vec<RouteTrace>>);
+
+    impl RouteTraceSink for VecSink {
+        fn export(&self, trace: RouteTrace) {
+            self.0.lock().expect("Should lock").push(trace);
+        }
+    }
+
     #[test]
     fn local_ping() {
-        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90);
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
         store.on_ping(
             0,
             1,
@@ -213,7 +273,7 @@ mod tests {
             },
         );
 
-        assert_eq!(store.best_for(ServiceKind::Webrtc, None), Some(1));
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[], None), Some(1));
 
         assert_eq!(store.pop_output(), None);
         store.on_tick(100);
@@ -235,7 +295,7 @@ mod tests {
 
     #[test]
     fn local_reject_max_usage() {
-        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90);
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
         store.on_ping(
             0,
             1,
@@ -275,12 +335,109 @@ mod tests {
             },
         );
 
-        assert_eq!(store.best_for(ServiceKind::Webrtc, None), None);
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[], None), None);
+    }
+
+    #[test]
+    fn route_trace_full_sampling() {
+        let sink = Arc::new(VecSink::default());
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
+        store.set_route_trace(RouteTraceConfig { rate: 1.0, sink: sink.clone() });
+        store.on_ping(
+            0,
+            1,
+            PingEvent {
+                cpu: 10,
+                memory: 0,
+                disk: 0,
+                origin: Origin::Media(MediaOrigin {}),
+                webrtc: Some(ServiceStats { live: 100, max: 1000, active: true }),
+                rtpengine: None,
+            },
+        );
+
+        let location = Location { lat: 1.5, lon: 1.5 };
+        assert_eq!(store.best_for(ServiceKind::Webrtc, Some(location), &[], None), Some(1));
+        assert_eq!(store.best_for(ServiceKind::Webrtc, Some(location), &[1], None), None);
+
+        let traces = sink.0.lock().expect("Should lock");
+        assert_eq!(
+            *traces,
+            vec![
+                RouteTrace {
+                    kind: ServiceKind::Webrtc,
+                    location: Some(location),
+                    candidates: vec![RouteCandidate { node: 1, zone: ZoneId(0), usage: 10 }],
+                    excluded: vec![],
+                    chosen: Some(1),
+                    outcome: RouteOutcome::Local,
+                },
+                RouteTrace {
+                    kind: ServiceKind::Webrtc,
+                    location: Some(location),
+                    candidates: vec![RouteCandidate { node: 1, zone: ZoneId(0), usage: 10 }],
+                    excluded: vec![1],
+                    chosen: None,
+                    outcome: RouteOutcome::PoolEmpty,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn residency_reject_disallowed_region() {
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
+        store.on_ping(
+            0,
+            1,
+            PingEvent {
+                cpu: 0,
+                memory: 0,
+                disk: 0,
+                origin: Origin::Media(MediaOrigin {}),
+                webrtc: Some(ServiceStats { live: 100, max: 1000, active: true }),
+                rtpengine: None,
+            },
+        );
+        store.on_ping(
+            0,
+            257,
+            PingEvent {
+                cpu: 0,
+                memory: 0,
+                disk: 0,
+                origin: Origin::Gateway(GatewayOrigin {
+                    location: Some(Location { lat: 10.0, lon: 10.0 }),
+                    zone: 256,
+                }),
+                webrtc: Some(ServiceStats { live: 100, max: 1000, active: true }),
+                rtpengine: None,
+            },
+        );
+
+        let local = Some(Location { lat: 1.0, lon: 1.0 });
+        let remote = Some(Location { lat: 10.0, lon: 10.0 });
+        let client = ZoneConstraint::Client(vec![ZoneId(256)]);
+        let nodes = ZoneConstraint::Nodes(vec![ZoneId(256)]);
+
+        // client in zone 0 is rejected even though zone 256 has nodes, instead of routed cross-region
+        assert_eq!(store.best_for(ServiceKind::Webrtc, local, &[], Some(&client)), None);
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[], Some(&client)), None);
+        assert_eq!(store.best_for(ServiceKind::Webrtc, local, &[], None), Some(1));
+
+        // client in allowed zone is routed inside it
+        assert_eq!(store.best_for(ServiceKind::Webrtc, remote, &[], Some(&client)), Some(257));
+        assert_eq!(store.best_for(ServiceKind::Webrtc, remote, &[257], Some(&client)), None);
+        assert_eq!(store.best_for(ServiceKind::Webrtc, remote, &[257], None), Some(1));
+
+        // route without client location, like migrate or rtpengine, still stays inside allowed zones
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[], Some(&nodes)), Some(257));
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[257], Some(&nodes)), None);
     }
 
     #[test]
     fn remote_ping() {
-        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90);
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
         store.on_ping(
             0,
             257,
@@ -297,7 +454,7 @@ mod tests {
             },
         );
 
-        assert_eq!(store.best_for(ServiceKind::Webrtc, None), Some(257));
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[], None), Some(257));
 
         assert_eq!(store.pop_output(), None);
         store.on_tick(100);
@@ -317,9 +474,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn list_nodes() {
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
+        store.on_ping(
+            0,
+            1,
+            PingEvent {
+                cpu: 0,
+                memory: 0,
+                disk: 0,
+                origin: Origin::Media(MediaOrigin {}),
+                webrtc: Some(ServiceStats { live: 100, max: 1000, active: true }),
+                rtpengine: Some(ServiceStats { live: 100, max: 1000, active: true }),
+            },
+        );
+        store.on_ping(
+            0,
+            2,
+            PingEvent {
+                cpu: 0,
+                memory: 0,
+                disk: 0,
+                origin: Origin::Media(MediaOrigin {}),
+                webrtc: None,
+                rtpengine: Some(ServiceStats { live: 100, max: 1000, active: true }),
+            },
+        );
+        store.on_ping(
+            0,
+            257,
+            PingEvent {
+                cpu: 0,
+                memory: 0,
+                disk: 0,
+                origin: Origin::Gateway(GatewayOrigin {
+                    location: Some(Location { lat: 2.0, lon: 2.0 }),
+                    zone: 256,
+                }),
+                webrtc: Some(ServiceStats { live: 100, max: 1000, active: true }),
+                rtpengine: None,
+            },
+        );
+
+        assert_eq!(store.nodes(), (vec![1, 2], vec![257]));
+    }
+
     #[test]
     fn clear_timeout() {
-        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90);
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
         store.on_ping(
             0,
             1,
@@ -350,18 +553,18 @@ mod tests {
         );
 
         // Verify nodes are registered
-        assert_eq!(store.best_for(ServiceKind::Webrtc, None), Some(1));
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[], None), Some(1));
 
         // Trigger timeout
         store.on_tick(5000); // PING_TIMEOUT is 5000
 
         // Verify nodes are cleared
-        assert_eq!(store.best_for(ServiceKind::Webrtc, None), None);
+        assert_eq!(store.best_for(ServiceKind::Webrtc, None, &[], None), None);
     }
 
     #[test]
     fn clear_timeout_rtpengine() {
-        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90);
+        let mut store = GatewayStore::new(ZoneId(0), Location { lat: 1.0, lon: 1.0 }, 60, 80, 90, vec![]);
         store.on_ping(
             0,
             1,
@@ -392,12 +595,12 @@ mod tests {
         );
 
         // Verify nodes are registered
-        assert_eq!(store.best_for(ServiceKind::RtpEngine, None), Some(1));
+        assert_eq!(store.best_for(ServiceKind::RtpEngine, None, &[], None), Some(1));
 
         // Trigger timeout
         store.on_tick(5000); // PING_TIMEOUT is 5000
 
         // Verify nodes are cleared
-        assert_eq!(store.best_for(ServiceKind::RtpEngine, None), None);
+        assert_eq!(store.best_for(ServiceKind::RtpEngine, None, &[], None), None);
     }
 }
@@ -1,10 +1,16 @@
+use std::collections::HashMap;
+
 use atm0s_sdn::NodeId;
 use media_server_protocol::{
     cluster::ZoneId,
     protobuf::cluster_gateway::ping_event::{gateway_origin::Location, ServiceStats},
 };
 
-use crate::ServiceKind;
+use crate::{
+    route_trace::{RouteCandidate, RouteOutcome},
+    sdn_health::{SdnHealth, SdnProbe},
+    ServiceKind, ZoneConstraint, ZoneFallback,
+};
 
 const PING_TIMEOUT: u64 = 5000; //timeout after 5s not ping
 
@@ -14,6 +20,7 @@ struct NodeSource {
     usage: u8,
     stats: ServiceStats,
     last_updated: u64,
+    sdn: SdnProbe,
 }
 
 /// This is for other cluster
@@ -31,17 +38,22 @@ pub struct ServiceStore {
     location: Location,
     local_sources: Vec<NodeSource>,
     zone_sources: Vec<ZoneSource>,
+    /// Locations of all zones which we have seen, kept after a zone is empty for detecting region of client
+    zone_locations: HashMap<ZoneId, Location>,
+    fallbacks: Vec<ZoneFallback>,
 }
 
 impl ServiceStore {
-    pub fn new(zone: ZoneId, kind: ServiceKind, location: Location) -> Self {
-        log::info!("[ServiceStore {:?}] create new in {:?}", kind, location);
+    pub fn new(zone: ZoneId, kind: ServiceKind, location: Location, fallbacks: Vec<ZoneFallback>) -> Self {
+        log::info!("[ServiceStore {:?}] create new in {:?} with fallbacks {:?}", kind, location, fallbacks);
         Self {
             zone,
             kind,
             location,
             local_sources: vec![],
             zone_sources: vec![],
+            zone_locations: HashMap::from([(zone, location)]),
+            fallbacks,
         }
     }
 
@@ -50,6 +62,20 @@ impl ServiceStore {
         for z in self.zone_sources.iter_mut() {
             z.gateways.retain(|s| s.last_updated + PING_TIMEOUT > now);
         }
+        for s in self.local_sources.iter_mut().chain(self.zone_sources.iter_mut().flat_map(|z| z.gateways.iter_mut())) {
+            let pre = s.sdn.health();
+            s.sdn.on_tick(now);
+            if pre != s.sdn.health() {
+                log::warn!(
+                    "[ServiceStore {:?}] node {} sdn health {:?} => {:?}, delay {} ms",
+                    self.kind,
+                    s.node,
+                    pre,
+                    s.sdn.health(),
+                    s.sdn.delay_ms()
+                );
+            }
+        }
         self.zone_sources.retain(|s| !s.gateways.is_empty());
     }
 
@@ -57,6 +83,7 @@ impl ServiceStore {
         if let Some(s) = self.local_sources.iter_mut().find(|s| s.node == node) {
             s.usage = usage;
             s.last_updated = now;
+            s.sdn.on_ping(now);
         } else {
             log::info!("[ServiceStore {:?}] new node {} usage {}, stats {:?}", self.kind, node, usage, stats);
             self.local_sources.push(NodeSource {
@@ -64,6 +91,7 @@ impl ServiceStore {
                 usage,
                 last_updated: now,
                 stats,
+                sdn: SdnProbe::new(now),
             });
         }
         self.local_sources.sort();
@@ -78,12 +106,14 @@ impl ServiceStore {
 
     #[allow(clippy::too_many_arguments)]
     pub fn on_gateway_ping(&mut self, now: u64, zone: ZoneId, gateway: u32, gateway_usage: u8, location: Location, usage: u8, stats: ServiceStats) {
+        self.zone_locations.insert(zone, location);
         if let Some(z) = self.zone_sources.iter_mut().find(|s| s.zone == zone) {
             z.usage = usage;
             z.last_updated = now;
             if let Some(g) = z.gateways.iter_mut().find(|g| g.node == gateway) {
                 g.usage = gateway_usage;
                 g.last_updated = now;
+                g.sdn.on_ping(now);
             } else {
                 log::info!(
                     "[ServiceStore {:?}] zone {zone:?} at {:?} add new gateway {gateway} gateway usage {gateway_usage}, stats {:?}",
@@ -96,6 +126,7 @@ impl ServiceStore {
                     usage: gateway_usage,
                     last_updated: now,
                     stats,
+                    sdn: SdnProbe::new(now),
                 });
             }
             z.gateways.sort();
@@ -116,6 +147,7 @@ impl ServiceStore {
                     usage: gateway_usage,
                     last_updated: now,
                     stats,
+                    sdn: SdnProbe::new(now),
                 }],
             });
         }
@@ -142,21 +174,107 @@ impl ServiceStore {
         }
     }
 
-    pub fn best_for(&self, location: Option<Location>) -> Option<u32> {
+    /// Best node for the location, excluded nodes are skipped like they are not in the store
+    pub fn best_for(&self, location: Option<Location>, excluded: &[NodeId]) -> Option<u32> {
+        self.route(location, excluded, None).0
+    }
+
+    /// Same as [`Self::best_for`], together with how the node is selected.
+    /// With zone constraint (data residency of an app), only nodes of its zones are selected, including fallback zones,
+    /// and a client connect is rejected when the region of client is not allowed
+    pub fn route(&self, location: Option<Location>, excluded: &[NodeId], constraint: Option<&ZoneConstraint>) -> (Option<u32>, RouteOutcome) {
         let location = location.unwrap_or(self.location);
-        let mut min_dis = distance(&self.location, &location);
-        let mut min_node = self.local_sources.first().map(|s| s.node);
+        let allowed = constraint.map(|c| c.zones());
+        let is_allowed = |zone: ZoneId| allowed.map_or(true, |zones| zones.contains(&zone));
+        let region = self.region_of(&location);
+        if matches!(constraint, Some(ZoneConstraint::Client(_))) && !is_allowed(region) {
+            log::warn!("[ServiceStore {:?}] region {region:?} of {:?} is not in allowed zones {:?} => reject", self.kind, location, allowed);
+            return (None, RouteOutcome::RegionNotAllowed(region));
+        }
+        if let Some((zone, node)) = self.region_fallback(region, &location, excluded, is_allowed) {
+            return (Some(node), RouteOutcome::Fallback(zone));
+        }
 
-        for z in self.zone_sources.iter() {
+        let mut min_dis = distance(&self.location, &location);
+        let mut min_node = if is_allowed(self.zone) {
+            first_allowed(&self.local_sources, excluded)
+        } else {
+            None
+        };
+        let mut min_zone = self.zone;
+
+        for z in self.zone_sources.iter().filter(|z| is_allowed(z.zone)) {
+            let gateway = match first_allowed(&z.gateways, excluded) {
+                Some(gateway) => gateway,
+                None => continue,
+            };
             let dis = distance(&location, &z.location);
             if min_node.is_none() || min_dis > dis {
                 min_dis = dis;
-                min_node = z.gateways.first().map(|s| s.node);
+                min_node = Some(gateway);
+                min_zone = z.zone;
             }
         }
 
         log::info!("[ServiceStore {:?}] query best node for {:?} got min_dis {min_dis} min_node {:?}", self.kind, location, min_node);
-        min_node
+        let outcome = match min_node {
+            None => RouteOutcome::PoolEmpty,
+            Some(_) if min_zone == self.zone => RouteOutcome::Local,
+            Some(_) => RouteOutcome::Zone(min_zone),
+        };
+        (min_node, outcome)
+    }
+
+    /// All nodes which a route can select: media nodes of current zone and gateways of other zones.
+    /// Usage of a gateway is the service usage of its zone
+    pub fn candidates(&self) -> Vec<RouteCandidate> {
+        let local = self.local_sources.iter().map(|s| RouteCandidate {
+            node: s.node,
+            zone: self.zone,
+            usage: s.usage,
+        });
+        let remote = self.zone_sources.iter().flat_map(|z| {
+            z.gateways.iter().map(|g| RouteCandidate {
+                node: g.node,
+                zone: z.zone,
+                usage: z.usage,
+            })
+        });
+        local.chain(remote).collect()
+    }
+
+    /// Region of client is the nearest known zone, even if it is empty
+    fn region_of(&self, location: &Location) -> ZoneId {
+        self.zone_locations
+            .iter()
+            .map(|(zone, zone_location)| (*zone, distance(location, zone_location)))
+            .min_by(|(_, dis1), (_, dis2)| dis1.total_cmp(dis2))
+            .map(|(zone, _)| zone)
+            .unwrap_or(self.zone)
+    }
+
+    /// When the region is empty, the configured fallback zones are tried in order. None means the node should be selected by distance
+    fn region_fallback(&self, region: ZoneId, location: &Location, excluded: &[NodeId], is_allowed: impl Fn(ZoneId) -> bool) -> Option<(ZoneId, u32)> {
+        if self.best_in_zone(region, excluded).is_some() {
+            return None;
+        }
+
+        let fallback = self.fallbacks.iter().find(|f| f.zone == region)?;
+        let (zone, node) = fallback
+            .fallbacks
+            .iter()
+            .filter(|zone| is_allowed(**zone))
+            .find_map(|zone| Some((*zone, self.best_in_zone(*zone, excluded)?)))?;
+        log::info!("[ServiceStore {:?}] region {region:?} of {:?} is empty, fallback to zone {zone:?} node {node}", self.kind, location);
+        Some((zone, node))
+    }
+
+    fn best_in_zone(&self, zone: ZoneId, excluded: &[NodeId]) -> Option<u32> {
+        if zone == self.zone {
+            first_allowed(&self.local_sources, excluded)
+        } else {
+            self.zone_sources.iter().find(|z| z.zone == zone).and_then(|z| first_allowed(&z.gateways, excluded))
+        }
     }
 
     /// If we in same zone then only check local registry
@@ -181,6 +299,21 @@ impl ServiceStore {
         }
     }
 
+    /// All media nodes inside current zone
+    pub fn local_nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.local_sources.iter().map(|s| s.node)
+    }
+
+    /// SDN probe of all media nodes inside current zone and gateways of other zones
+    pub fn sdn_probes(&self) -> impl Iterator<Item = (NodeId, SdnProbe)> + '_ {
+        self.local_sources.iter().chain(self.zone_sources.iter().flat_map(|z| z.gateways.iter())).map(|s| (s.node, s.sdn))
+    }
+
+    /// Best gateway of each other zone
+    pub fn zone_gateways(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.zone_sources.iter().filter_map(|z| z.gateways.first().map(|s| s.node))
+    }
+
     pub fn local_stats(&self) -> Option<ServiceStats> {
         if self.local_sources.is_empty() {
             return None;
@@ -238,6 +371,16 @@ impl PartialOrd for ZoneSource {
 }
 
 /// Calculate distance between two nodes.
+/// Sources are sorted by usage, so the first not excluded one with the best SDN health is the best.
+/// Sources with unhealthy SDN are skipped like excluded ones
+fn first_allowed(sources: &[NodeSource], excluded: &[NodeId]) -> Option<u32> {
+    sources
+        .iter()
+        .filter(|s| !excluded.contains(&s.node) && s.sdn.health() != SdnHealth::Unhealthy)
+        .min_by_key(|s| s.sdn.health())
+        .map(|s| s.node)
+}
+
 fn distance(node1: &Location, node2: &Location) -> f32 {
     //TODO make it more accuracy
     ((node1.lat - node2.lat).powi(2) + (node1.lon - node2.lon).powi(2)).sqrt()
@@ -250,97 +393,140 @@ mod tests {
         protobuf::cluster_gateway::ping_event::{gateway_origin::Location, ServiceStats},
     };
 
-    use crate::{store::service::PING_TIMEOUT, ServiceKind};
+    use crate::{sdn_health::PING_INTERVAL_MS, store::service::PING_TIMEOUT, ServiceKind, ZoneFallback};
 
     use super::ServiceStore;
 
     #[test]
     fn empty_store() {
-        let store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 });
-        assert_eq!(store.best_for(None), None);
-        assert_eq!(store.best_for(Some(Location { lat: 1.0, lon: 1.0 })), None);
+        let store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
+        assert_eq!(store.best_for(None, &[]), None);
+        assert_eq!(store.best_for(Some(Location { lat: 1.0, lon: 1.0 }), &[]), None);
 
         assert_eq!(store.local_stats(), None);
     }
 
     #[test]
     fn local_store() {
-        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 });
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
 
         store.on_node_ping(0, 1, 60, ServiceStats { live: 100, max: 1000, active: true });
         store.on_node_ping(0, 2, 50, ServiceStats { live: 60, max: 1000, active: true });
 
         //should got lowest usage
-        assert_eq!(store.best_for(None), Some(2));
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(2));
+        assert_eq!(store.best_for(None, &[]), Some(2));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(2));
         assert_eq!(store.local_stats(), Some(ServiceStats { live: 160, max: 2000, active: true }));
 
         //after node2 increase usage should fallback to node1
         store.on_node_ping(0, 2, 61, ServiceStats { live: 120, max: 1000, active: true });
 
-        assert_eq!(store.best_for(None), Some(1));
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(1));
+        assert_eq!(store.best_for(None, &[]), Some(1));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(1));
 
         //after remove should fallback to remain
         store.remove_node(1);
 
-        assert_eq!(store.best_for(None), Some(2));
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(2));
+        assert_eq!(store.best_for(None, &[]), Some(2));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(2));
     }
 
     #[test]
     fn remote_zones_store() {
-        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 });
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
 
         store.on_gateway_ping(0, ZoneId(1), 256, 60, Location { lat: 2.0, lon: 2.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
         store.on_gateway_ping(0, ZoneId(1), 257, 50, Location { lat: 2.0, lon: 2.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
 
         //should got lowest usage gateway node
-        assert_eq!(store.best_for(None), Some(257));
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(257));
+        assert_eq!(store.best_for(None, &[]), Some(257));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(257));
 
         //after gateway 257 increase usage should switch to 256
         store.on_gateway_ping(0, ZoneId(1), 257, 65, Location { lat: 2.0, lon: 2.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
 
-        assert_eq!(store.best_for(None), Some(256));
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(256));
+        assert_eq!(store.best_for(None, &[]), Some(256));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(256));
 
         //should fallback to remain gateway
         store.remove_gateway(ZoneId(1), 256);
 
-        assert_eq!(store.best_for(None), Some(257));
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(257));
+        assert_eq!(store.best_for(None, &[]), Some(257));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(257));
     }
 
     #[test]
     fn local_and_remote_zones() {
-        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 });
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
 
         store.on_node_ping(0, 1, 60, ServiceStats { live: 100, max: 1000, active: true });
         store.on_gateway_ping(0, ZoneId(1), 257, 60, Location { lat: 2.0, lon: 2.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
 
         //should got local zone if don't provide location
-        assert_eq!(store.best_for(None), Some(1));
+        assert_eq!(store.best_for(None, &[]), Some(1));
 
         //should got closest zone gaetway
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(257));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(257));
 
         //after remove local should fallback to other zone
         store.remove_node(1);
 
-        assert_eq!(store.best_for(None), Some(257));
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), Some(257));
+        assert_eq!(store.best_for(None, &[]), Some(257));
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), Some(257));
 
         //after remove other zone should return None
         store.remove_gateway(ZoneId(1), 257);
 
-        assert_eq!(store.best_for(None), None);
-        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 })), None);
+        assert_eq!(store.best_for(None, &[]), None);
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[]), None);
+    }
+
+    #[test]
+    fn skip_excluded_nodes() {
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
+
+        store.on_node_ping(0, 1, 50, ServiceStats { live: 100, max: 1000, active: true });
+        store.on_node_ping(0, 2, 60, ServiceStats { live: 100, max: 1000, active: true });
+        store.on_gateway_ping(0, ZoneId(1), 257, 60, Location { lat: 2.0, lon: 2.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
+
+        //should got next local node when best node is excluded
+        assert_eq!(store.best_for(None, &[1]), Some(2));
+
+        //should got other zone when all local nodes are excluded
+        assert_eq!(store.best_for(None, &[1, 2]), Some(257));
+
+        //zone with all gateways excluded is skipped
+        assert_eq!(store.best_for(Some(Location { lat: 2.0, lon: 2.0 }), &[257]), Some(1));
+        assert_eq!(store.best_for(None, &[1, 2, 257]), None);
+    }
+
+    #[test]
+    fn fallback_to_neighbor_region() {
+        let fallbacks = vec![ZoneFallback {
+            zone: ZoneId(2),
+            fallbacks: vec![ZoneId(4), ZoneId(3)],
+        }];
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, fallbacks);
+
+        store.on_gateway_ping(0, ZoneId(1), 257, 60, Location { lat: 5.0, lon: 5.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
+        store.on_gateway_ping(0, ZoneId(2), 513, 60, Location { lat: 6.0, lon: 6.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
+        store.on_gateway_ping(0, ZoneId(3), 769, 60, Location { lat: 20.0, lon: 20.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
+
+        //client region has node
+        assert_eq!(store.best_for(Some(Location { lat: 6.0, lon: 6.0 }), &[]), Some(513));
+
+        //client region is empty, zone 4 is unknown so neighbor zone 3 serves even if zone 1 is closer
+        store.remove_gateway(ZoneId(2), 513);
+        assert_eq!(store.best_for(Some(Location { lat: 6.0, lon: 6.0 }), &[]), Some(769));
+
+        //without fallback of the region, closest zone is selected
+        store.remove_gateway(ZoneId(3), 769);
+        assert_eq!(store.best_for(Some(Location { lat: 6.0, lon: 6.0 }), &[]), Some(257));
     }
 
     #[test]
     fn clear_timeout() {
-        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 });
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
 
         store.on_node_ping(0, 1, 60, ServiceStats { live: 100, max: 1000, active: true });
         store.on_gateway_ping(0, ZoneId(1), 257, 60, Location { lat: 2.0, lon: 2.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
@@ -354,9 +540,33 @@ mod tests {
         assert_eq!(store.zone_sources.len(), 0);
     }
 
+    #[test]
+    fn skip_poor_sdn_health_nodes() {
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
+        let stats = ServiceStats { live: 100, max: 1000, active: true };
+
+        // node 1 has lowest usage but most of its pings are lost, node 2 pings regularly and node 3 loses every other ping
+        for i in 1..=12 {
+            let now = i * PING_INTERVAL_MS;
+            if i % 3 == 0 {
+                store.on_node_ping(now, 1, 40, stats);
+            }
+            store.on_node_ping(now, 2, 60, stats);
+            if i % 2 == 0 {
+                store.on_node_ping(now, 3, 50, stats);
+            }
+            store.on_tick(now);
+        }
+
+        // unhealthy node is excluded, degraded node is used only when no healthy one
+        assert_eq!(store.best_for(None, &[]), Some(2));
+        assert_eq!(store.best_for(None, &[2]), Some(3));
+        assert_eq!(store.best_for(None, &[2, 3]), None);
+    }
+
     #[test]
     fn dest_for_same_zone() {
-        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 });
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
         store.on_node_ping(0, 1, 60, ServiceStats { live: 100, max: 1000, active: true });
 
         assert_eq!(store.dest_for(1), Some(1));
@@ -365,7 +575,7 @@ mod tests {
 
     #[test]
     fn dest_for_other_zone() {
-        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 });
+        let mut store = ServiceStore::new(ZoneId(0), ServiceKind::Webrtc, Location { lat: 1.0, lon: 1.0 }, vec![]);
 
         store.on_node_ping(0, 1, 60, ServiceStats { live: 100, max: 1000, active: true });
         store.on_gateway_ping(0, ZoneId(1), 257, 60, Location { lat: 2.0, lon: 2.0 }, 50, ServiceStats { live: 100, max: 1000, active: true });
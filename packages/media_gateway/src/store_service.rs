@@ -18,15 +18,19 @@ use media_server_protocol::{
 use prost::Message as _;
 
 use crate::{
+    route_trace::RouteTraceConfig,
     store::{GatewayStore, PingEvent},
-    NodeMetrics, ServiceKind, DATA_PORT, STORE_SERVICE_ID, STORE_SERVICE_NAME,
+    NodeMetrics, ServiceKind, ZoneConstraint, ZoneFallback, DATA_PORT, STORE_SERVICE_ID, STORE_SERVICE_NAME,
 };
 
 #[derive(Debug, Clone)]
 pub enum Control {
     NodeStats(NodeMetrics),
-    FindNodeReq(u64, ServiceKind, Option<Location>),
+    /// Find best node for the location, excluded nodes are skipped. With zone constraint, only nodes in its zones are selected
+    /// and a client connect is rejected when the client is not in one of them
+    FindNodeReq(u64, ServiceKind, Option<Location>, Vec<NodeId>, Option<ZoneConstraint>),
     FindDestReq(u64, ServiceKind, NodeId),
+    ListNodesReq(u64),
     GetMediaStats,
 }
 
@@ -35,6 +39,8 @@ pub enum Event {
     MediaStats(u32, u32),
     FindNodeRes(u64, Option<u32>),
     FindDestRes(u64, Option<u32>),
+    /// Media nodes in current zone and gateways of other zones
+    ListNodesRes(u64, Vec<u32>, Vec<u32>),
 }
 
 pub struct GatewayStoreService<UserData, SC, SE, TC, TW> {
@@ -50,9 +56,14 @@ where
     SC: From<Control> + TryInto<Control>,
     SE: From<Event> + TryInto<Event>,
 {
-    pub fn new(zone: ZoneId, lat: f32, lon: f32, max_cpu: u8, max_memory: u8, max_disk: u8) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(zone: ZoneId, lat: f32, lon: f32, max_cpu: u8, max_memory: u8, max_disk: u8, fallbacks: Vec<ZoneFallback>, route_trace: Option<RouteTraceConfig>) -> Self {
+        let mut store = GatewayStore::new(zone, Location { lat, lon }, max_cpu, max_memory, max_disk, fallbacks);
+        if let Some(cfg) = route_trace {
+            store.set_route_trace(cfg);
+        }
         Self {
-            store: GatewayStore::new(zone, Location { lat, lon }, max_cpu, max_memory, max_disk),
+            store,
             queue: VecDeque::from([ServiceOutput::FeatureControl(data::Control::DataListen(DATA_PORT).into())]),
             seq: 0,
             shutdown: false,
@@ -139,14 +150,18 @@ where
             ServiceInput::Control(actor, control) => {
                 if let Ok(control) = control.try_into() {
                     match control {
-                        Control::FindNodeReq(req_id, kind, location) => {
-                            let out = self.store.best_for(kind, location);
+                        Control::FindNodeReq(req_id, kind, location, excluded, constraint) => {
+                            let out = self.store.best_for(kind, location, &excluded, constraint.as_ref());
                             self.queue.push_back(ServiceOutput::Event(actor, Event::FindNodeRes(req_id, out).into()));
                         }
                         Control::FindDestReq(req_id, kind, dest) => {
                             let out = self.store.dest_for(kind, dest);
                             self.queue.push_back(ServiceOutput::Event(actor, Event::FindDestRes(req_id, out).into()));
                         }
+                        Control::ListNodesReq(req_id) => {
+                            let (nodes, gateways) = self.store.nodes();
+                            self.queue.push_back(ServiceOutput::Event(actor, Event::ListNodesRes(req_id, nodes, gateways).into()));
+                        }
                         Control::NodeStats(metrics) => {
                             log::debug!("[GatewayStoreService] node metrics {:?}", metrics);
                             self.store.on_node_metrics(now, metrics);
@@ -220,10 +235,14 @@ pub struct GatewayStoreServiceBuilder<UserData, SC, SE, TC, TW> {
     max_memory: u8,
     max_disk: u8,
     max_cpu: u8,
+    fallbacks: Vec<ZoneFallback>,
+    route_trace: Option<RouteTraceConfig>,
 }
 
 impl<UserData, SC, SE, TC, TW> GatewayStoreServiceBuilder<UserData, SC, SE, TC, TW> {
-    pub fn new(zone: ZoneId, lat: f32, lon: f32, max_cpu: u8, max_memory: u8, max_disk: u8) -> Self {
+    /// `route_trace` exports a sample of routing decisions, None for disabled
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(zone: ZoneId, lat: f32, lon: f32, max_cpu: u8, max_memory: u8, max_disk: u8, fallbacks: Vec<ZoneFallback>, route_trace: Option<RouteTraceConfig>) -> Self {
         Self {
             zone,
             lat,
@@ -232,6 +251,8 @@ impl<UserData, SC, SE, TC, TW> GatewayStoreServiceBuilder<UserData, SC, SE, TC,
             max_cpu,
             max_memory,
             max_disk,
+            fallbacks,
+            route_trace,
         }
     }
 }
@@ -257,7 +278,16 @@ where
     }
 
     fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
-        Box::new(GatewayStoreService::new(self.zone, self.lat, self.lon, self.max_cpu, self.max_memory, self.max_disk))
+        Box::new(GatewayStoreService::new(
+            self.zone,
+            self.lat,
+            self.lon,
+            self.max_cpu,
+            self.max_memory,
+            self.max_disk,
+            self.fallbacks.clone(),
+            self.route_trace.clone(),
+        ))
     }
 
     fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
@@ -0,0 +1,47 @@
+//! Region fallback ladder. When the zone which is nearest to the client has no available node, the zones in
+//! the configured proximity order are tried before falling back to the nearest available zone by distance.
+
+use std::str::FromStr;
+
+use media_server_protocol::cluster::ZoneId;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneFallback {
+    pub zone: ZoneId,
+    /// Fallback zones in proximity order, nearest first
+    pub fallbacks: Vec<ZoneId>,
+}
+
+/// Parse from format `<zone>:<zone1>,<zone2>`, example `1:2,3`
+impl FromStr for ZoneFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (zone, fallbacks) = s.split_once(':').ok_or_else(|| format!("invalid zone fallback {s}, expected <zone>:<zone1>,<zone2>"))?;
+        let parse_zone = |zone: &str| zone.trim().parse::<u32>().map(ZoneId).map_err(|_| format!("invalid zone {zone} in zone fallback {s}"));
+        Ok(Self {
+            zone: parse_zone(zone)?,
+            fallbacks: fallbacks.split(',').filter(|zone| !zone.trim().is_empty()).map(parse_zone).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::cluster::ZoneId;
+
+    use super::ZoneFallback;
+
+    #[test]
+    fn parse_zone_fallback() {
+        assert_eq!(
+            "1:2, 3".parse::<ZoneFallback>(),
+            Ok(ZoneFallback {
+                zone: ZoneId(1),
+                fallbacks: vec![ZoneId(2), ZoneId(3)]
+            })
+        );
+        assert!("1".parse::<ZoneFallback>().is_err());
+        assert!("1:a".parse::<ZoneFallback>().is_err());
+    }
+}
@@ -23,6 +23,10 @@ struct Args {
     #[arg(env, long)]
     transmux_out_path: Option<String>,
 
+    /// Transmux each simulcast layer to separate file
+    #[arg(env, long)]
+    transmux_layers: bool,
+
     /// Compose audio
     #[arg(env, long)]
     compose_audio: bool,
@@ -57,6 +61,7 @@ async fn main() -> Result<(), String> {
         } else {
             args.transmux_out_path.map(RecordConvertOutputLocation::Local)
         },
+        transmux_layers: args.transmux_layers,
         compose: if args.compose_audio || args.compose_video {
             Some(RecordComposerConfig {
                 audio: args.compose_audio,
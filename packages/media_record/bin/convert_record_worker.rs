@@ -160,6 +160,8 @@ async fn main() -> Result<(), std::io::Error> {
 #[derive(Debug, Object)]
 struct TransmuxConfig {
     custom_s3: Option<String>,
+    /// Write each simulcast layer to separate file, default false
+    layers: Option<bool>,
 }
 
 #[derive(Debug, Object)]
@@ -211,6 +213,7 @@ impl HttpApis {
 
         // get yyyy/mm/dd with chrono
         let current_date_path = chrono::Utc::now().format("%Y/%m/%d").to_string();
+        let transmux_layers = body.transmux.as_ref().and_then(|t| t.layers).unwrap_or(false);
         let transmux = if let Some(t) = body.transmux {
             if let Some(custom_s3) = t.custom_s3 {
                 Some(RecordConvertOutputLocation::S3(custom_s3))
@@ -243,6 +246,7 @@ impl HttpApis {
             let converter = RecordConvert::new(RecordConvertConfig {
                 in_s3: input_s3,
                 transmux,
+                transmux_layers,
                 compose: body.compose.map(|c| {
                     let (uri, relative) = c
                         .custom_s3
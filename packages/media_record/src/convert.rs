@@ -15,6 +15,8 @@ pub enum RecordConvertOutputLocation {
 pub struct RecordConvertConfig {
     pub in_s3: String,
     pub transmux: Option<RecordConvertOutputLocation>,
+    /// Write each simulcast layer of video tracks to a separate file
+    pub transmux_layers: bool,
     pub compose: Option<RecordComposerConfig>,
 }
 
@@ -36,7 +38,7 @@ impl RecordConvert {
     pub async fn convert(self) -> Result<RecordConvertOutput, String> {
         let mut transmux = None;
         if let Some(out) = self.cfg.transmux {
-            let transmuxer = RecordTransmuxer::new(self.cfg.in_s3.clone(), out);
+            let transmuxer = RecordTransmuxer::new(self.cfg.in_s3.clone(), out, self.cfg.transmux_layers);
             transmux = Some(transmuxer.convert().await?);
         }
         let mut compose = None;
@@ -3,6 +3,8 @@ use media_server_protocol::media::MediaPacket;
 use rtp::packetizer::Depacketizer;
 
 pub struct VpxDemuxer {
+    /// Only this spatial layer of vp8 simulcast is demuxed, other layers are dropped
+    spatial: u8,
     seen_key_frame: bool,
     current_frame: Option<(bool, BytesMut)>,
 }
@@ -15,7 +17,12 @@ impl Default for VpxDemuxer {
 
 impl VpxDemuxer {
     pub fn new() -> Self {
+        Self::new_with_spatial(0)
+    }
+
+    pub fn new_with_spatial(spatial: u8) -> Self {
         Self {
+            spatial,
             seen_key_frame: false,
             current_frame: None,
         }
@@ -28,7 +35,7 @@ impl VpxDemuxer {
             media_server_protocol::media::MediaMeta::H264 { .. } => panic!("wrong codec"),
             media_server_protocol::media::MediaMeta::Vp8 { key, sim, rotation } => {
                 if let Some(sim) = sim {
-                    if sim.spatial != 0 {
+                    if sim.spatial != self.spatial {
                         //TODO: how to get maximum quality
                         return None;
                     }
@@ -10,17 +10,24 @@ pub struct VpxWriter<W: Write + Seek> {
     webm: Option<Segment<Writer<W>>>,
     audio: Option<AudioTrack>,
     video: Option<(VideoTrack, VpxDemuxer)>,
+    spatial: u8,
     start_ts: u64,
     last_ts: u64,
 }
 
 impl<W: Write + Seek> VpxWriter<W> {
     pub fn new(writer: W, start_ts: u64) -> Self {
+        Self::new_with_spatial(writer, start_ts, 0)
+    }
+
+    /// Writer which only takes the spatial layer of vp8 simulcast
+    pub fn new_with_spatial(writer: W, start_ts: u64, spatial: u8) -> Self {
         let webm = Segment::new(Writer::new(writer)).expect("Should create webm");
         Self {
             webm: Some(webm),
             audio: None,
             video: None,
+            spatial,
             start_ts,
             last_ts: start_ts,
         }
@@ -53,7 +60,7 @@ impl<W: Write + Seek> CodecWriter for VpxWriter<W> {
                     media_server_protocol::media::MediaMeta::Vp9 { .. } => VideoCodecId::VP9,
                     _ => panic!("Wrong codec, should be vp8 or vp9"),
                 };
-                let demuxer = VpxDemuxer::new();
+                let demuxer = VpxDemuxer::new_with_spatial(self.spatial);
                 if let Some(webm) = &mut self.webm {
                     self.video = Some((webm.add_video_track(100, 100, None, codec), demuxer));
                 } else {
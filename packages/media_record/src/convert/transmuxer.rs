@@ -3,7 +3,7 @@
 //! The file is created at local at first then upload to s3, after upload to s3 successfully, it will be removed in local.
 //! TODO: avoid using local file, may be we have way to do-it in-memory buffer then upload in-air to s3.
 //!
-use std::{collections::HashMap, path::PathBuf, str::FromStr, time::Duration};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 use rusty_s3::S3Action;
 use surf::Body;
@@ -23,20 +23,23 @@ pub struct RecordTransmuxer {
     in_s3: String,
     local_folder: String,
     out_s3: Option<String>,
+    split_layers: bool,
 }
 
 impl RecordTransmuxer {
-    pub fn new(in_s3: String, out: RecordConvertOutputLocation) -> Self {
+    pub fn new(in_s3: String, out: RecordConvertOutputLocation, split_layers: bool) -> Self {
         match out {
             RecordConvertOutputLocation::S3(s3) => Self {
                 in_s3,
                 out_s3: Some(s3),
                 local_folder: format!("/tmp/media-record-transmuxer-{}", rand::random::<u64>()),
+                split_layers,
             },
             RecordConvertOutputLocation::Local(local) => Self {
                 in_s3,
                 out_s3: None,
                 local_folder: local,
+                split_layers,
             },
         }
     }
@@ -45,10 +48,7 @@ impl RecordTransmuxer {
         let (s3, credentials, s3_sub_folder) = convert_s3_uri(&self.in_s3).map_err(|e| e.to_string())?;
         let temp_folder = std::path::Path::new(&self.local_folder);
         std::fs::create_dir_all(temp_folder).map_err(|e| e.to_string())?;
-        let mut record_summary = TransmuxSummary {
-            peers: HashMap::new(),
-            metadata_json: "".to_string(),
-        };
+        let mut record_summary = TransmuxSummary::default();
         let room_reader = RoomReader::new(s3, credentials, &s3_sub_folder);
         let peers = room_reader.peers().await.map_err(|e| e.to_string())?;
         //we use channel to wait all sessions
@@ -64,9 +64,10 @@ impl RecordTransmuxer {
                 let session_prefix = format!("{}-{}-", peer_id, session_id);
                 log::info!("got session {session_id}");
                 let tx = tx.clone();
+                let split_layers = self.split_layers;
                 tokio::spawn(async move {
                     log::info!("start session {session_id} loop");
-                    let mut media = TrackWriter::new(tmp_folder, &session_prefix);
+                    let mut media = TrackWriter::new(tmp_folder, &session_prefix, split_layers);
                     if let Err(e) = session.connect().await {
                         log::error!("connect session {session_id} failed: {e}");
                         //TODO send error to main
@@ -74,10 +75,16 @@ impl RecordTransmuxer {
                     }
                     while let Some(row) = session.recv().await {
                         log::debug!("push session {session_id} pkt {}", row.ts);
-                        if let Some(event) = media.push(row) {
+                        media.push(row);
+                        while let Some(event) = media.pop_event() {
                             tx.send((peer_id.clone(), session_id, event)).await.expect("Should send to main");
                         }
                     }
+                    // close tracks which are not stopped when the session record ended
+                    media.close(None);
+                    while let Some(event) = media.pop_event() {
+                        tx.send((peer_id.clone(), session_id, event)).await.expect("Should send to main");
+                    }
                     log::info!("end session {session_id} loop");
                 });
             }
@@ -85,32 +92,9 @@ impl RecordTransmuxer {
         drop(tx);
 
         while let Some((peer_id, session_id, event)) = rx.recv().await {
-            let peer = record_summary.peers.entry(peer_id).or_default();
-            let session = peer.sessions.entry(session_id).or_default();
-            match event {
-                track_writer::Event::TrackStart(name, kind, ts, path) => {
-                    log::info!("track start {name} {kind} {ts} {path}");
-                    let track: &mut TrackSummary = session.track.entry(name.into()).or_insert_with(|| TrackSummary { kind, timeline: vec![] });
-                    track.timeline.push(TrackTimeline { path, start: ts, end: None });
-                }
-                track_writer::Event::TrackStop(name, _kind, ts) => {
-                    log::info!("track stop {name} {ts}");
-                    if let Some(track) = session.track.get_mut(name.as_str()) {
-                        if let Some(timeline) = track.timeline.last_mut() {
-                            if timeline.end.is_none() {
-                                timeline.end = Some(ts);
-                            } else {
-                                log::warn!("timeline end not empty");
-                            }
-                        } else {
-                            log::warn!("track stop but timeline not found");
-                        }
-                    } else {
-                        log::warn!("track stop but track not found");
-                    }
-                }
-            }
+            record_summary.on_track_event(peer_id, session_id, event);
         }
+        record_summary.update_offsets();
 
         let summary_json = serde_json::to_string(&record_summary).expect("Should convert to json");
 
@@ -6,16 +6,22 @@ use media_server_protocol::{
 };
 use serde::Serialize;
 
+use super::track_writer::Event;
+
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackTimeline {
     pub path: String,
     pub start: u64,
     pub end: Option<u64>,
+    /// Offset from the start of the whole record, used for aligning tracks when muxing
+    pub offset: u64,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackSummary {
     pub kind: MediaKind,
+    /// Spatial layer when each simulcast layer is written to a separate file
+    pub layer: Option<u8>,
     pub timeline: Vec<TrackTimeline>,
 }
 
@@ -32,9 +38,71 @@ pub struct PeerSummary {
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct TransmuxSummary {
     pub metadata_json: String,
+    /// Timestamp of the earliest track, which all track offsets are relative to
+    pub start: u64,
     pub peers: HashMap<String, PeerSummary>,
 }
 
+impl TransmuxSummary {
+    pub(crate) fn on_track_event(&mut self, peer: String, session: u64, event: Event) {
+        let peer = self.peers.entry(peer).or_default();
+        let session = peer.sessions.entry(session).or_default();
+        match event {
+            Event::TrackStart(name, kind, layer, ts, path) => {
+                log::info!("track start {name} {kind} {layer:?} {ts} {path}");
+                let key = match layer {
+                    Some(layer) => format!("{name}/{layer}"),
+                    None => name.into(),
+                };
+                let track: &mut TrackSummary = session.track.entry(key).or_insert_with(|| TrackSummary { kind, layer, timeline: vec![] });
+                track.timeline.push(TrackTimeline {
+                    path,
+                    start: ts,
+                    end: None,
+                    offset: 0,
+                });
+            }
+            Event::TrackStop(name, _kind, layer, ts) => {
+                log::info!("track stop {name} {layer:?} {ts}");
+                let key = match layer {
+                    Some(layer) => format!("{name}/{layer}"),
+                    None => name.into(),
+                };
+                if let Some(track) = session.track.get_mut(&key) {
+                    if let Some(timeline) = track.timeline.last_mut() {
+                        if timeline.end.is_none() {
+                            timeline.end = Some(ts);
+                        } else {
+                            log::warn!("timeline end not empty");
+                        }
+                    } else {
+                        log::warn!("track stop but timeline not found");
+                    }
+                } else {
+                    log::warn!("track stop but track not found");
+                }
+            }
+        }
+    }
+
+    /// Set the record start to the earliest timeline and each timeline offset relative to it.
+    /// This is the manifest for muxing separated track files together.
+    pub(crate) fn update_offsets(&mut self) {
+        let timelines = || self.peers.values().flat_map(|p| p.sessions.values()).flat_map(|s| s.track.values()).flat_map(|t| t.timeline.iter());
+        self.start = timelines().map(|t| t.start).min().unwrap_or_default();
+        let start = self.start;
+        for timeline in self
+            .peers
+            .values_mut()
+            .flat_map(|p| p.sessions.values_mut())
+            .flat_map(|s| s.track.values_mut())
+            .flat_map(|t| t.timeline.iter_mut())
+        {
+            timeline.offset = timeline.start - start;
+        }
+    }
+}
+
 impl From<TrackTimeline> for record_job_completed::TrackTimeline {
     fn from(value: TrackTimeline) -> Self {
         record_job_completed::TrackTimeline {
@@ -1,97 +1,213 @@
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    path::PathBuf,
+};
 
 use media_server_protocol::{
     endpoint::{TrackMeta, TrackName},
-    media::MediaKind,
+    media::{MediaKind, MediaMeta},
     record::{SessionRecordEvent, SessionRecordRow},
     transport::RemoteTrackId,
 };
 
 use crate::convert::codec::{CodecWriter, VpxWriter};
 
+/// Track events with the spatial layer when layers are written to separate files
 pub enum Event {
-    TrackStart(TrackName, MediaKind, u64, String),
-    TrackStop(TrackName, MediaKind, u64),
+    TrackStart(TrackName, MediaKind, Option<u8>, u64, String),
+    TrackStop(TrackName, MediaKind, Option<u8>, u64),
+}
+
+struct LayerWriter {
+    writer: Box<dyn CodecWriter + Send>,
+    last_ts: u64,
 }
 
 pub struct TrackWriter {
     folder: PathBuf,
     prefix: String,
+    /// Each spatial layer of simulcast video is written to a separate file instead of only the lowest layer
+    split_layers: bool,
     tracks_meta: HashMap<RemoteTrackId, (TrackName, TrackMeta)>,
-    tracks_writer: HashMap<RemoteTrackId, Box<dyn CodecWriter + Send>>,
+    tracks_writer: HashMap<(RemoteTrackId, Option<u8>), LayerWriter>,
+    events: VecDeque<Event>,
 }
 
 impl TrackWriter {
-    pub fn new(folder: PathBuf, prefix: &str) -> Self {
-        log::info!("new session media writer {folder:?}/{prefix}");
+    pub fn new(folder: PathBuf, prefix: &str, split_layers: bool) -> Self {
+        log::info!("new session media writer {folder:?}/{prefix}, split layers {split_layers}");
         Self {
             folder,
             prefix: prefix.to_string(),
+            split_layers,
             tracks_meta: HashMap::new(),
             tracks_writer: HashMap::new(),
+            events: VecDeque::new(),
         }
     }
 
-    pub fn push(&mut self, event: SessionRecordRow) -> Option<Event> {
+    // We allow clippy::map_entry because the suggestion provided by clippy has a bug:
+    // cannot borrow `*self` as mutable more than once at a time
+    // There is a open Issue on the Rust Clippy GitHub Repo:
+    // https://github.com/rust-lang/rust-clippy/issues/11976
+    #[allow(clippy::map_entry)]
+    pub fn push(&mut self, event: SessionRecordRow) {
         match event.event {
             SessionRecordEvent::TrackStarted(id, name, meta) => {
                 log::info!("track {:?} started, name {name} meta {:?}", id, meta);
                 self.tracks_meta.insert(id, (name, meta));
-                None
             }
             SessionRecordEvent::TrackStopped(id) => {
                 log::info!("track {:?} stopped", id);
-                let (name, meta) = self.tracks_meta.remove(&id)?;
-                self.tracks_writer.remove(&id)?;
-                Some(Event::TrackStop(name, meta.kind, event.ts))
+                self.stop_track(id, Some(event.ts));
             }
             SessionRecordEvent::TrackMedia(id, media) => {
-                // We allow clippy::map_entry because the suggestion provided by clippy has a bug:
-                // cannot borrow `*self` as mutable more than once at a time
-                // There is a open Issue on the Rust Clippy GitHub Repo:
-                // https://github.com/rust-lang/rust-clippy/issues/11976
-                #[allow(clippy::map_entry)]
-                let out = if !self.tracks_writer.contains_key(&id) {
+                let layer = match &media.meta {
+                    MediaMeta::Vp8 { sim: Some(sim), .. } if self.split_layers => Some(sim.spatial),
+                    _ => None,
+                };
+                if !self.tracks_writer.contains_key(&(id, layer)) {
                     if let Some((name, meta)) = self.tracks_meta.get(&id) {
-                        let (file_name, writer): (String, Box<dyn CodecWriter + Send>) = match &media.meta {
-                            media_server_protocol::media::MediaMeta::Opus { .. } => {
-                                let file_name = format!("{}-opus-{}-{}.webm", self.prefix, name, event.ts);
-                                let file_path = self.folder.join(&file_name);
-                                log::info!("create writer for track {name} => file {file_path:?}");
-                                let writer = Box::new(VpxWriter::new(File::create(&file_path).unwrap(), event.ts));
-                                (file_name, writer)
-                            }
-                            media_server_protocol::media::MediaMeta::H264 { .. } => todo!(),
-                            media_server_protocol::media::MediaMeta::Vp8 { .. } => {
-                                let file_name = format!("{}-vp8-{}-{}.webm", self.prefix, name, event.ts);
-                                let file_path = self.folder.join(&file_name);
-                                log::info!("create writer for track {name} => file {file_path:?}");
-                                let writer = Box::new(VpxWriter::new(File::create(&file_path).unwrap(), event.ts));
-                                (file_name, writer)
-                            }
-                            media_server_protocol::media::MediaMeta::Vp9 { .. } => {
-                                let file_name = format!("{}-vp9-{}-{}.webm", self.prefix, name, event.ts);
-                                let file_path = self.folder.join(&file_name);
-                                log::info!("create writer for track {name} => file {file_path:?}");
-                                let writer = Box::new(VpxWriter::new(File::create(&file_path).unwrap(), event.ts));
-                                (file_name, writer)
-                            }
+                        let codec = match &media.meta {
+                            MediaMeta::Opus { .. } => "opus",
+                            MediaMeta::H264 { .. } => todo!(),
+                            MediaMeta::Vp8 { .. } => "vp8",
+                            MediaMeta::Vp9 { .. } => "vp9",
                         };
-                        log::info!("create writer for track {name} => file {file_name}");
-                        self.tracks_writer.insert(id, writer);
-                        Some(Event::TrackStart(name.clone(), meta.kind, event.ts, file_name))
+                        let file_name = match layer {
+                            Some(spatial) => format!("{}-{codec}-{}-l{spatial}-{}.webm", self.prefix, name, event.ts),
+                            None => format!("{}-{codec}-{}-{}.webm", self.prefix, name, event.ts),
+                        };
+                        let file_path = self.folder.join(&file_name);
+                        log::info!("create writer for track {name} layer {layer:?} => file {file_path:?}");
+                        let writer = Box::new(VpxWriter::new_with_spatial(File::create(&file_path).unwrap(), event.ts, layer.unwrap_or(0)));
+                        self.tracks_writer.insert((id, layer), LayerWriter { writer, last_ts: event.ts });
+                        self.events.push_back(Event::TrackStart(name.clone(), meta.kind, layer, event.ts, file_name));
                     } else {
                         log::warn!("missing track info for pkt  form track {:?}", id);
-                        return None;
+                        return;
                     }
-                } else {
-                    None
-                };
-                let writer = self.tracks_writer.get_mut(&id).expect("Should have track here");
-                writer.push_media(event.ts, media);
-                out
+                }
+                let writer = self.tracks_writer.get_mut(&(id, layer)).expect("Should have track here");
+                writer.last_ts = event.ts;
+                writer.writer.push_media(event.ts, media);
             }
-            _ => None,
+            SessionRecordEvent::LeaveRoom | SessionRecordEvent::Disconnected => self.close(Some(event.ts)),
+            SessionRecordEvent::JoinRoom(..) => {}
         }
     }
+
+    /// Stop all tracks, which is used when session ended without track stopped events.
+    /// Without end ts, each file is ended at its last media
+    pub fn close(&mut self, end_ts: Option<u64>) {
+        let mut tracks = self.tracks_meta.keys().copied().collect::<Vec<_>>();
+        tracks.sort_by_key(|id| **id);
+        for id in tracks {
+            self.stop_track(id, end_ts);
+        }
+    }
+
+    pub fn pop_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    fn stop_track(&mut self, id: RemoteTrackId, end_ts: Option<u64>) {
+        let (name, meta) = match self.tracks_meta.remove(&id) {
+            Some(track) => track,
+            None => return,
+        };
+        let mut layers = self.tracks_writer.keys().filter(|(track, _)| *track == id).map(|(_, layer)| *layer).collect::<Vec<_>>();
+        layers.sort();
+        for layer in layers {
+            let writer = self.tracks_writer.remove(&(id, layer)).expect("Should have track writer");
+            self.events.push_back(Event::TrackStop(name.clone(), meta.kind, layer, end_ts.unwrap_or(writer.last_ts)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::{
+        endpoint::{TrackMeta, TrackName},
+        media::{MediaMeta, MediaPacket, Vp8Sim},
+        record::{SessionRecordEvent, SessionRecordRow},
+        transport::RemoteTrackId,
+    };
+
+    use super::TrackWriter;
+    use crate::convert::TransmuxSummary;
+
+    fn vp8(seq: u16, spatial: u8) -> MediaPacket {
+        MediaPacket {
+            ts: seq as u32 * 3000,
+            seq,
+            marker: true,
+            nackable: true,
+            layers: None,
+            meta: MediaMeta::Vp8 {
+                key: true,
+                sim: Some(Vp8Sim {
+                    picture_id: None,
+                    tl0_pic_idx: None,
+                    spatial,
+                    temporal: 0,
+                    layer_sync: false,
+                }),
+                rotation: None,
+            },
+            data: vec![0x10, 0x9d, 0x01, 0x2a],
+        }
+    }
+
+    fn row(ts: u64, event: SessionRecordEvent) -> SessionRecordRow {
+        SessionRecordRow { ts, event }
+    }
+
+    #[test]
+    fn separate_outputs_with_offsets() {
+        let folder = std::env::temp_dir().join(format!("media-record-track-writer-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&folder).expect("Should create folder");
+        let mut writer = TrackWriter::new(folder.clone(), "peer-1-", true);
+        let audio = RemoteTrackId::from(0);
+        let video = RemoteTrackId::from(1);
+
+        let rows = vec![
+            row(1000, SessionRecordEvent::TrackStarted(audio, TrackName::from("audio_main"), TrackMeta::default_audio())),
+            row(1000, SessionRecordEvent::TrackMedia(audio, MediaPacket::build_audio(0, 0, None, vec![1, 2, 3]))),
+            row(1020, SessionRecordEvent::TrackMedia(audio, MediaPacket::build_audio(960, 1, None, vec![1, 2, 3]))),
+            // video starts later with two simulcast layers
+            row(3000, SessionRecordEvent::TrackStarted(video, TrackName::from("video_main"), TrackMeta::default_video())),
+            row(3000, SessionRecordEvent::TrackMedia(video, vp8(0, 0))),
+            row(3010, SessionRecordEvent::TrackMedia(video, vp8(0, 1))),
+            row(3100, SessionRecordEvent::TrackMedia(video, vp8(1, 0))),
+            row(4000, SessionRecordEvent::TrackStopped(video)),
+            row(5000, SessionRecordEvent::TrackMedia(audio, MediaPacket::build_audio(1920, 2, None, vec![1, 2, 3]))),
+            row(6000, SessionRecordEvent::Disconnected),
+        ];
+        let mut summary = TransmuxSummary::default();
+        for r in rows {
+            writer.push(r);
+            while let Some(event) = writer.pop_event() {
+                summary.on_track_event("peer".to_string(), 1, event);
+            }
+        }
+        summary.update_offsets();
+
+        assert_eq!(summary.start, 1000);
+        let session = &summary.peers["peer"].sessions[&1];
+        let track = |key: &str| {
+            let track = &session.track[key];
+            assert_eq!(track.timeline.len(), 1);
+            let timeline = &track.timeline[0];
+            assert!(folder.join(&timeline.path).exists(), "{} should be written", timeline.path);
+            (track.layer, timeline.start, timeline.end, timeline.offset)
+        };
+        assert_eq!(session.track.len(), 3);
+        assert_eq!(track("audio_main"), (None, 1000, Some(6000), 0));
+        assert_eq!(track("video_main/0"), (Some(0), 3000, Some(4000), 2000));
+        assert_eq!(track("video_main/1"), (Some(1), 3010, Some(4000), 2010));
+
+        std::fs::remove_dir_all(folder).expect("Should remove folder");
+    }
 }
@@ -1,22 +1,32 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 
 use media_server_protocol::{
     protobuf::cluster_connector::{RecordReq, RecordRes},
     record::SessionRecordEvent,
 };
+use media_server_utils::Count;
 use session::SessionRecord;
+use sink::RecordingSink;
 use storage::{memory::MemoryFile, FileId, RecordFile};
-use tokio::sync::mpsc::Sender;
+use tokio::{
+    io::AsyncReadExt,
+    sync::mpsc::{channel, error::TrySendError, Sender},
+};
 use worker::UploadWorker;
 
 #[cfg(feature = "convert_record")]
 pub mod convert;
 mod raw_record;
 mod session;
+pub mod sink;
 mod storage;
 mod worker;
 
 pub use raw_record::*;
+pub use sink::{sink_from_uri, DiskSink, S3Sink};
 pub use storage::convert_s3_uri;
 
 //TODO: generate MediaRecordStats
@@ -39,12 +49,75 @@ pub enum Output {
     UploadRequest(u64, RecordReq),
 }
 
+/// Max number of segments which are waiting to be written to the sink
+const SINK_QUEUE: usize = 100;
+
+/// Count of sink jobs which are deferred because the sink queue is full, they are retried in order on tick
+pub struct RecordSinkDeferred;
+
+enum SinkJob {
+    Segment(RecordReq, MemoryFile),
+    Finalize(u64),
+}
+
+/// Jobs are sent to the sink task in order. When its queue is full they are kept and retried on tick, so a slow sink
+/// delays segments and finalize but never drops them
+struct SinkQueue {
+    tx: Sender<SinkJob>,
+    deferred: VecDeque<SinkJob>,
+}
+
+impl SinkQueue {
+    fn push(&mut self, job: SinkJob) {
+        if !self.deferred.is_empty() {
+            Count::<RecordSinkDeferred>::event();
+            self.deferred.push_back(job);
+            return;
+        }
+        match self.tx.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(job)) => {
+                log::warn!("[MediaWorkerService] sink queue is full => defer job");
+                Count::<RecordSinkDeferred>::event();
+                self.deferred.push_back(job);
+            }
+            Err(TrySendError::Closed(_)) => log::error!("[MediaWorkerService] sink task is closed => drop job"),
+        }
+    }
+
+    fn flush(&mut self) {
+        while let Some(job) = self.deferred.pop_front() {
+            match self.tx.try_send(job) {
+                Ok(()) => {}
+                Err(TrySendError::Full(job)) => {
+                    self.deferred.push_front(job);
+                    log::warn!("[MediaWorkerService] sink queue is still full, {} jobs deferred", self.deferred.len());
+                    return;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    log::error!("[MediaWorkerService] sink task is closed => drop {} deferred jobs", self.deferred.len() + 1);
+                    self.deferred.clear();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+enum Destination {
+    /// Upload links are requested from connector, then chunks are uploaded by upload workers
+    Connector {
+        chunk_map: HashMap<u64, FileId>,
+        worker_tx: Sender<worker::Input>,
+    },
+    Sink(SinkQueue),
+}
+
 pub struct MediaRecordService {
     req_id_seed: u64,
     queue: VecDeque<Output>,
-    chunk_map: HashMap<u64, FileId>,
     sessions: HashMap<u64, SessionRecord>,
-    worker_tx: Sender<worker::Input>,
+    destination: Destination,
 }
 
 impl MediaRecordService {
@@ -66,15 +139,55 @@ impl MediaRecordService {
             req_id_seed: 0,
             queue: VecDeque::new(),
             sessions: HashMap::new(),
-            chunk_map: HashMap::new(),
-            worker_tx,
+            destination: Destination::Connector { chunk_map: HashMap::new(), worker_tx },
+        }
+    }
+
+    /// Write recordings to the sink instead of uploading with links from connector
+    pub fn new_with_sink(sink: Arc<dyn RecordingSink>) -> Self {
+        Self::with_sink_queue(sink, SINK_QUEUE)
+    }
+
+    fn with_sink_queue(sink: Arc<dyn RecordingSink>, queue_size: usize) -> Self {
+        let (tx, mut rx) = channel(queue_size);
+        tokio::spawn(async move {
+            // jobs are handled one by one, so segments and finalize of a session are in order
+            while let Some(job) = rx.recv().await {
+                match job {
+                    SinkJob::Segment(req, mut file) => {
+                        let mut data = Vec::with_capacity(file.len());
+                        if let Err(e) = file.read_to_end(&mut data).await {
+                            log::error!("[MediaRecordService] read segment {} of session {} error {e}", req.index, req.session);
+                            continue;
+                        }
+                        if let Err(e) = sink.write_segment(&req, data).await {
+                            log::error!("[MediaRecordService] write segment {} of session {} error {e}", req.index, req.session);
+                        }
+                    }
+                    SinkJob::Finalize(session) => {
+                        if let Err(e) = sink.finalize(session).await {
+                            log::error!("[MediaRecordService] finalize session {session} error {e}");
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            req_id_seed: 0,
+            queue: VecDeque::new(),
+            sessions: HashMap::new(),
+            destination: Destination::Sink(SinkQueue { tx, deferred: VecDeque::new() }),
         }
     }
 
     pub fn on_tick(&mut self, now: u64) {
+        if let Destination::Sink(sink) = &mut self.destination {
+            sink.flush();
+        }
         for (_id, session) in self.sessions.iter_mut() {
             if let Some((req, file)) = session.tick(now) {
-                Self::process_chunk(&mut self.req_id_seed, req, file, &mut self.queue, &mut self.chunk_map, &self.worker_tx);
+                Self::process_chunk(&mut self.req_id_seed, req, file, &mut self.queue, &mut self.destination);
             }
         }
         self.sessions.retain(|_, session| !session.is_closed());
@@ -85,9 +198,11 @@ impl MediaRecordService {
             Input::Event(session, ts, event) => self.on_record_event(now, session, ts, event),
             Input::UploadResponse(req_id, res) => {
                 log::info!("[MediaWorkerService] received res for req {req_id}");
-                if let Some(file_id) = self.chunk_map.remove(&req_id) {
-                    if let Err(e) = self.worker_tx.try_send(worker::Input::UploadLink(file_id, res.s3_uri)) {
-                        log::error!("[MediaWorkerService] send record link to record controller worker error {e}");
+                if let Destination::Connector { chunk_map, worker_tx } = &mut self.destination {
+                    if let Some(file_id) = chunk_map.remove(&req_id) {
+                        if let Err(e) = worker_tx.try_send(worker::Input::UploadLink(file_id, res.s3_uri)) {
+                            log::error!("[MediaWorkerService] send record link to record controller worker error {e}");
+                        }
                     }
                 }
             }
@@ -101,20 +216,141 @@ impl MediaRecordService {
 
 impl MediaRecordService {
     fn on_record_event(&mut self, now_ms: u64, session: u64, event_ts: u64, event: SessionRecordEvent) {
+        let session_id = session;
         let session = self.sessions.entry(session).or_insert_with(|| SessionRecord::new(session));
+        let was_closed = session.is_closed();
         if let Some((req, file)) = session.push(now_ms, event_ts, event) {
-            Self::process_chunk(&mut self.req_id_seed, req, file, &mut self.queue, &mut self.chunk_map, &self.worker_tx);
+            Self::process_chunk(&mut self.req_id_seed, req, file, &mut self.queue, &mut self.destination);
+        }
+        if !was_closed && session.is_closed() {
+            if let Destination::Sink(sink) = &mut self.destination {
+                sink.push(SinkJob::Finalize(session_id));
+            }
+        }
+    }
+
+    fn process_chunk(req_seed: &mut u64, req: RecordReq, file: MemoryFile, queue: &mut VecDeque<Output>, destination: &mut Destination) {
+        match destination {
+            Destination::Connector { chunk_map, worker_tx } => {
+                let req_id = *req_seed;
+                *req_seed += 1;
+                log::info!("[MediaWorkerService] request upload uri with req_id {req_id}");
+                queue.push_back(Output::UploadRequest(req_id, req));
+                chunk_map.insert(req_id, file.id());
+                if let Err(e) = worker_tx.try_send(worker::Input::RecordChunk(file)) {
+                    log::error!("[MediaWorkerService] send record chunk to record controller worker error {e}");
+                }
+            }
+            Destination::Sink(sink) => sink.push(SinkJob::Segment(req, file)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use media_server_protocol::{protobuf::cluster_connector::RecordReq, record::SessionRecordEvent};
+
+    use media_server_utils::get_all_counts;
+
+    use crate::{sink::RecordingSink, Input, MediaRecordService, RecordSinkDeferred};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Call {
+        Segment(u64, u32),
+        Finalize(u64),
+    }
+
+    #[derive(Default)]
+    struct MemorySink {
+        calls: Arc<Mutex<Vec<Call>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl RecordingSink for MemorySink {
+        async fn write_segment(&self, segment: &RecordReq, data: Vec<u8>) -> Result<(), String> {
+            assert!(!data.is_empty());
+            self.calls.lock().expect("Should lock").push(Call::Segment(segment.session, segment.index));
+            Ok(())
+        }
+
+        async fn finalize(&self, session: u64) -> Result<(), String> {
+            self.calls.lock().expect("Should lock").push(Call::Finalize(session));
+            Ok(())
+        }
+    }
+
+    /// Sink which blocks writes until it is released
+    #[derive(Default)]
+    struct SlowSink {
+        inner: MemorySink,
+        release: Arc<tokio::sync::Notify>,
+    }
+
+    #[async_trait::async_trait]
+    impl RecordingSink for SlowSink {
+        async fn write_segment(&self, segment: &RecordReq, data: Vec<u8>) -> Result<(), String> {
+            self.release.notified().await;
+            self.inner.write_segment(segment, data).await
+        }
+
+        async fn finalize(&self, session: u64) -> Result<(), String> {
+            self.inner.finalize(session).await
         }
     }
 
-    fn process_chunk(req_seed: &mut u64, req: RecordReq, file: MemoryFile, queue: &mut VecDeque<Output>, chunk_map: &mut HashMap<u64, FileId>, worker_tx: &Sender<worker::Input>) {
-        let req_id = *req_seed;
-        *req_seed += 1;
-        log::info!("[MediaWorkerService] request upload uri with req_id {req_id}");
-        queue.push_back(Output::UploadRequest(req_id, req));
-        chunk_map.insert(req_id, file.id());
-        if let Err(e) = worker_tx.try_send(worker::Input::RecordChunk(file)) {
-            log::error!("[MediaWorkerService] send record chunk to record controller worker error {e}");
+    #[tokio::test]
+    async fn slow_sink_defers_jobs_without_drop() {
+        let sink = SlowSink::default();
+        let calls = sink.inner.calls.clone();
+        let release = sink.release.clone();
+        let mut service = MediaRecordService::with_sink_queue(Arc::new(sink), 1);
+        let deferred = || get_all_counts().get(std::any::type_name::<RecordSinkDeferred>()).copied().unwrap_or(0);
+        let before = deferred();
+
+        // sessions are closed right away, so each of them has one segment then finalize
+        for session in 1..=3 {
+            service.on_input(0, Input::Event(session, 0, SessionRecordEvent::JoinRoom("app".into(), "room".into(), "peer".into())));
+            service.on_input(1000, Input::Event(session, 1000, SessionRecordEvent::Disconnected));
+        }
+        assert!(deferred() > before);
+
+        for _ in 0..200 {
+            release.notify_one();
+            service.on_tick(2000);
+            if calls.lock().expect("Should lock").len() >= 6 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let expected = (1..=3).flat_map(|session| [Call::Segment(session, 0), Call::Finalize(session)]).collect::<Vec<_>>();
+        assert_eq!(*calls.lock().expect("Should lock"), expected);
+    }
+
+    #[tokio::test]
+    async fn sink_segments_then_finalize() {
+        let sink = MemorySink::default();
+        let calls = sink.calls.clone();
+        let mut service = MediaRecordService::new_with_sink(Arc::new(sink));
+
+        let session = 1;
+        service.on_input(0, Input::Event(session, 0, SessionRecordEvent::JoinRoom("app".into(), "room".into(), "peer".into())));
+        // first segment is cut after 60 seconds
+        service.on_input(60_000, Input::Event(session, 60_000, SessionRecordEvent::TrackStopped(0.into())));
+        service.on_input(60_500, Input::Event(session, 60_500, SessionRecordEvent::TrackStopped(1.into())));
+        service.on_input(61_000, Input::Event(session, 61_000, SessionRecordEvent::Disconnected));
+        assert!(service.pop_output().is_none());
+
+        for _ in 0..100 {
+            if calls.lock().expect("Should lock").len() >= 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        assert_eq!(*calls.lock().expect("Should lock"), vec![Call::Segment(session, 0), Call::Segment(session, 1), Call::Finalize(session)]);
     }
 }
@@ -1,3 +1,12 @@
 mod worker;
 
+pub use media_server_core::{
+    cluster::{ChannelNaming, FileAuditSink, KvRetryPolicy, RoomAudit, RoomTtlConfig, RoomVideoCodecs, TrackChannelName, UnknownFeedbackPolicy},
+    endpoint::{MultiRoomPolicy, OpusConfig, OpusParams, PlayoutConfig, RelayGraceConfig, SessionMaxDurationConfig, TrackLimits},
+};
+
+pub use transport_webrtc::{
+    ice_tcp_frame, BundlePolicy, ConsentConfig, DtlsCertPolicy, DtlsCipher, DtlsPolicy, DtlsSetup, DtlsSuites, DtlsVersion, IceCredsConfig, IceTcpDecoder, IceTcpPacket, ReaperConfig, RtcpFbPolicy,
+    RtpExtension, SdpSession, SrtpProfile, VideoCodec,
+};
 pub use worker::{Input, MediaConfig, MediaServerWorker, Output, Owner, SdnConfig, UserData, SC, SE, TC, TW};
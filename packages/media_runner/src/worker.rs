@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use atm0s_sdn::{
@@ -16,24 +16,32 @@ use atm0s_sdn::{
 use atm0s_sdn_network::data_plane::NetPair;
 use indexmap::IndexMap;
 use media_server_connector::agent_service::ConnectorAgentServiceBuilder;
-use media_server_core::cluster::{self, MediaCluster};
+use media_server_core::{
+    cluster::{self, AuditEvent, ChannelNaming, KvRetryPolicy, MediaCluster, MediaClusterConfig, RoomAudit, RoomTtlConfig, RoomVideoCodecs, UnknownFeedbackPolicy},
+    endpoint::{MultiRoomPolicy, OpusConfig, PlayoutConfig, RelayGraceConfig, SessionMaxDurationConfig, TrackLimits},
+};
 use media_server_gateway::{agent_service::GatewayAgentServiceBuilder, NodeMetrics, ServiceKind, AGENT_SERVICE_ID};
 use media_server_protocol::{
     cluster::{ClusterMediaInfo, ClusterNodeGenericInfo, ClusterNodeInfo, ZoneId},
+    endpoint::PeerId,
     gateway::generate_gateway_zone_tag,
+    multi_tenancy::AppContext,
     protobuf::{
-        cluster_connector::{connector_request, PeerEvent},
+        cluster_connector::{connector_request, peer_event, PeerEvent},
         gateway::{ConnectResponse, RemoteIceResponse},
     },
     record::SessionRecordEvent,
+    session_tags::SessionTags,
     transport::{
+        admin::{self, CloseSessionsRes, NodeCloseResult},
         rtpengine, webrtc,
-        whep::{self, WhepConnectRes, WhepDeleteRes, WhepRemoteIceRes},
+        whep::{self, WhepConnectRes, WhepDeleteRes, WhepEventsRes, WhepRemoteIceRes},
         whip::{self, WhipConnectRes, WhipDeleteRes, WhipRemoteIceRes},
         RpcReq, RpcRes,
     },
 };
 use media_server_secure::MediaEdgeSecure;
+use media_server_utils::{now_ms, RtpEgressAllowlist, RtpIngestPolicy};
 use rand::{random, rngs::OsRng};
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
@@ -41,20 +49,94 @@ use sans_io_runtime::{
     TaskSwitcher, TaskSwitcherBranch,
 };
 use transport_rtpengine::{MediaWorkerRtpEngine, RtpEngineSession};
-use transport_webrtc::{MediaWorkerWebrtc, VariantParams, WebrtcSession};
+use transport_webrtc::{
+    BundlePolicy, ConsentConfig, DtlsPolicy, IceCredsConfig, IceTcpPacket, MediaWorkerWebrtc, ReaperConfig, RtcpFbPolicy, RtpExtension, SdpSession, VariantParams, VideoCodec, WebrtcSession,
+    WebrtcWorkerConfig,
+};
 
 const FEEDBACK_GATEWAY_AGENT_INTERVAL: u64 = 1000; //only feedback every second
 
 pub struct MediaConfig<ES> {
     pub ice_lite: bool,
+    /// Length of generated ICE ufrag and pwd
+    pub webrtc_ice_creds: IceCredsConfig,
+    pub webrtc_consent: ConsentConfig,
+    /// Preferred ips for webrtc candidates, in priority order
+    pub webrtc_candidate_order: Vec<IpAddr>,
+    /// Allowed H264 profile-level-ids in preference order, empty for all forwardable profiles
+    pub webrtc_h264_profiles: Vec<u32>,
+    /// Video codecs in preference order, only one is answered and peers of a room are kept on same codec. Empty for all
+    pub webrtc_video_codecs: Vec<VideoCodec>,
+    /// Pinned video codec of rooms, shared by all workers of the node
+    pub room_video_codecs: Arc<RoomVideoCodecs>,
+    /// Rtp header extensions which are never answered, bwe is disabled when transport-cc is disabled
+    pub webrtc_disable_extensions: Vec<RtpExtension>,
+    /// Min DTLS version and fingerprint policy for webrtc sessions
+    pub webrtc_dtls_policy: DtlsPolicy,
+    /// Whether NACK and key-frame requests are sent to clients which don't offer them
+    pub webrtc_rtcp_fb: RtcpFbPolicy,
+    /// Session-level origin, name and tool of answers
+    pub webrtc_sdp_session: SdpSession,
+    /// How offers with m-lines outside the BUNDLE group are answered
+    pub webrtc_bundle_policy: BundlePolicy,
+    /// Buffer of subscribed media while relay path is changing
+    pub relay_grace: RelayGraceConfig,
+    /// Target delay of subscribed media in smooth playout mode
+    pub playout: PlayoutConfig,
+    /// Max number of published and subscribed tracks of each session
+    pub track_limits: TrackLimits,
+    /// How a session joining another room while it is in a room is handled
+    pub multi_room: MultiRoomPolicy,
+    /// Opus bitrate and encoder complexity, per app
+    pub opus: OpusConfig,
+    /// Maximum number of candidates in answer, None is unlimited
+    pub webrtc_max_candidates: Option<usize>,
+    /// Maximum number of m-lines in an offer
+    pub webrtc_max_media_sections: usize,
+    /// Maximum number of remote ICE candidates of a webrtc session
+    pub webrtc_max_remote_candidates: usize,
+    /// Maximum number of handshaking webrtc sessions in this worker, None is unlimited
+    pub webrtc_max_connecting: Option<usize>,
+    /// Thresholds of stuck webrtc sessions which are force removed
+    pub webrtc_reaper: ReaperConfig,
     pub webrtc_addrs: Vec<SocketAddr>,
     pub webrtc_addrs_alt: Vec<SocketAddr>,
+    /// Addresses of ICE-TCP listeners of this worker, empty if ICE-TCP is disabled
+    pub webrtc_ice_tcp_addrs: Vec<SocketAddr>,
     pub rtpengine_listen_ip: IpAddr,
     pub rtpengine_public_ip: IpAddr,
     pub secure: Arc<ES>,
     pub max_live: HashMap<ServiceKind, u32>,
     pub enable_gateway_agent: bool,
     pub enable_connector_agent: bool,
+    /// Collect event-loop timing metrics of webrtc worker and endpoints
+    pub enable_loop_metrics: bool,
+    /// How long sessions are given to close gracefully on shutdown before they are force removed, zero for immediate
+    pub shutdown_grace: Duration,
+    /// Max payload in bytes of a message channel publish, bigger messages are rejected
+    pub message_channel_max_payload: usize,
+    /// Force close policy of rooms, per app
+    pub room_ttl: RoomTtlConfig,
+    /// Max duration of sessions, per app
+    pub session_max_duration: SessionMaxDurationConfig,
+    /// How malformed or oversized RTP from publishers is handled, shared by webrtc and rtpengine sessions
+    pub rtp_ingest: RtpIngestPolicy,
+    /// Destinations which plain rtp egress sessions can send to, empty for disabled
+    pub rtp_egress_allow: RtpEgressAllowlist,
+    /// How pubsub feedback kinds which this node doesn't know are handled
+    pub unknown_feedback: UnknownFeedbackPolicy,
+    /// Max number of sessions publishing same peer and track in a room, 0 for unlimited
+    pub max_channel_sources: usize,
+    /// How long a subscribed channel can be without media or heartbeat before its source is considered lost, zero for disabled
+    pub source_timeout: Duration,
+    /// How long a remote peer which disconnected is kept present in rooms before PeerLeaved, zero for immediate
+    pub peer_leave_grace: Duration,
+    /// How a join whose peer info is not confirmed stored in the cluster is retried before it fails
+    pub peer_kv_retry: KvRetryPolicy,
+    /// Hash-chained audit log of room membership and track events, shared by all workers. None for disabled
+    pub room_audit: Option<Arc<RoomAudit>>,
+    /// Naming scheme of track channels, it must be same in all media nodes of the cluster
+    pub channel_naming: ChannelNaming,
 }
 
 pub type SdnConfig = SdnWorkerCfg<UserData, SC, SE, TC, TW>;
@@ -97,6 +179,8 @@ pub enum Input {
     /// ext, is_controller
     ExtSdn(SdnExtIn<UserData, SC>, bool),
     Net(Owner, BackendIncoming),
+    /// Unframed packet from an ICE-TCP connection of this worker
+    IceTcp(IceTcpPacket),
     Bus(SdnWorkerBusEvent<UserData, SC, SE, TC, TW>),
 }
 
@@ -104,6 +188,8 @@ pub enum Output {
     ExtRpc(u64, RpcRes<usize>),
     ExtSdn(SdnExtOut<UserData, SE>),
     Net(Owner, BackendOutgoing),
+    /// Packet to an ICE-TCP connection of this worker
+    IceTcp(IceTcpPacket),
     Bus(SdnWorkerBusEvent<UserData, SC, SE, TC, TW>),
     Record(u64, Instant, SessionRecordEvent),
     Continue,
@@ -127,6 +213,7 @@ enum MediaClusterEndpoint {
 #[allow(clippy::type_complexity)]
 pub struct MediaServerWorker<ES: 'static + MediaEdgeSecure> {
     worker: u16,
+    node_id: u32,
     sdn_addr: NodeAddr,
     sdn_worker: TaskSwitcherBranch<SdnWorker<UserData, SC, SE, TC, TW>, SdnWorkerOutput<UserData, SC, SE, TC, TW>>,
     sdn_backend_addrs: IndexMap<SocketAddr, usize>,
@@ -135,11 +222,18 @@ pub struct MediaServerWorker<ES: 'static + MediaEdgeSecure> {
     media_webrtc: TaskSwitcherBranch<MediaWorkerWebrtc<ES>, transport_webrtc::GroupOutput>,
     media_rtpengine: TaskSwitcherBranch<MediaWorkerRtpEngine, transport_rtpengine::GroupOutput>,
     media_max_live: u32,
+    shutdown_grace: Duration,
+    draining_until: Option<Instant>,
     switcher: TaskSwitcher,
     queue: DynamicDeque<Output, 16>,
     timer: TimePivot,
     last_feedback_gateway_agent: u64,
     secure: Arc<ES>,
+    /// Custom tags of webrtc sessions in this worker, attached to the session end peer event
+    session_tags: HashMap<u64, SessionTags>,
+    room_audit: Option<Arc<RoomAudit>>,
+    /// Room and peer of recording sessions in this worker, for auditing when their recording stops
+    recording_sessions: HashMap<u64, (cluster::ClusterRoomHash, PeerId)>,
     shutdown: bool,
 }
 
@@ -214,15 +308,78 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
 
         Self {
             worker,
+            node_id,
             sdn_addr: node_addr,
             sdn_worker: TaskSwitcherBranch::new(SdnWorker::new(sdn_config), TaskType::Sdn),
-            media_cluster: TaskSwitcherBranch::default(TaskType::MediaCluster),
+            media_cluster: TaskSwitcherBranch::new(
+                MediaCluster::new(MediaClusterConfig {
+                    message_max_payload: media.message_channel_max_payload,
+                    room_ttl: media.room_ttl.clone(),
+                    unknown_feedback: media.unknown_feedback,
+                    max_channel_sources: media.max_channel_sources,
+                    source_timeout: media.source_timeout,
+                    peer_leave_grace: media.peer_leave_grace,
+                    peer_kv_retry: media.peer_kv_retry,
+                    audit: media.room_audit.clone(),
+                    video_codecs: media.room_video_codecs.clone(),
+                    channel_naming: media.channel_naming,
+                }),
+                TaskType::MediaCluster,
+            ),
             media_webrtc: TaskSwitcherBranch::new(
-                MediaWorkerWebrtc::new(media.webrtc_addrs, media.webrtc_addrs_alt, media.ice_lite, media.secure.clone()),
+                MediaWorkerWebrtc::new(
+                    WebrtcWorkerConfig {
+                        node_id,
+                        addrs: media.webrtc_addrs,
+                        addrs_alt: media.webrtc_addrs_alt,
+                        ice_tcp_addrs: media.webrtc_ice_tcp_addrs,
+                        ice_lite: media.ice_lite,
+                        ice_creds: media.webrtc_ice_creds,
+                        consent: media.webrtc_consent,
+                        candidate_order: media.webrtc_candidate_order,
+                        h264_profiles: media.webrtc_h264_profiles,
+                        video_codecs: media.webrtc_video_codecs,
+                        room_video_codecs: media.room_video_codecs,
+                        disabled_extensions: media.webrtc_disable_extensions,
+                        dtls_policy: media.webrtc_dtls_policy,
+                        rtp_ingest: media.rtp_ingest,
+                        rtcp_fb: media.webrtc_rtcp_fb,
+                        sdp_session: media.webrtc_sdp_session,
+                        bundle_policy: media.webrtc_bundle_policy,
+                        relay_grace: media.relay_grace,
+                        playout: media.playout,
+                        track_limits: media.track_limits,
+                        multi_room: media.multi_room,
+                        opus: media.opus.clone(),
+                        max_duration: media.session_max_duration.clone(),
+                        max_candidates: media.webrtc_max_candidates,
+                        max_media_sections: media.webrtc_max_media_sections,
+                        max_remote_candidates: media.webrtc_max_remote_candidates,
+                        max_connecting: media.webrtc_max_connecting,
+                        reaper: media.webrtc_reaper,
+                        loop_metrics: media.enable_loop_metrics,
+                    },
+                    media.secure.clone(),
+                ),
                 TaskType::MediaWebrtc,
             ),
-            media_rtpengine: TaskSwitcherBranch::new(MediaWorkerRtpEngine::new(media.rtpengine_listen_ip, media.rtpengine_public_ip), TaskType::MediaRtpEngine),
+            media_rtpengine: TaskSwitcherBranch::new(
+                MediaWorkerRtpEngine::new(
+                    media.rtpengine_listen_ip,
+                    media.rtpengine_public_ip,
+                    media.relay_grace,
+                    media.playout,
+                    media.track_limits,
+                    media.opus.clone(),
+                    media.session_max_duration.clone(),
+                    media.rtp_ingest,
+                    media.rtp_egress_allow.clone(),
+                ),
+                TaskType::MediaRtpEngine,
+            ),
             media_max_live,
+            shutdown_grace: media.shutdown_grace,
+            draining_until: None,
             switcher: TaskSwitcher::new(4),
             queue,
             timer: TimePivot::build(),
@@ -230,6 +387,9 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
             secure,
             sdn_backend_addrs: Default::default(),
             sdn_backend_slots: Default::default(),
+            session_tags: Default::default(),
+            room_audit: media.room_audit.clone(),
+            recording_sessions: Default::default(),
             shutdown: false,
         }
     }
@@ -243,6 +403,14 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
     }
 
     pub fn on_tick(&mut self, now: Instant) {
+        if let Some(deadline) = self.draining_until {
+            if now >= deadline || self.media_webrtc.tasks() == 0 {
+                log::info!("[MediaServerWorker] drain finished with {} webrtc sessions remain => force shutdown", self.media_webrtc.tasks());
+                self.draining_until = None;
+                self.force_shutdown(now);
+            }
+        }
+
         let s = &mut self.switcher;
         let now_ms = self.timer.timestamp_ms(now);
         self.sdn_worker.input(s).on_tick(now_ms);
@@ -346,6 +514,9 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                     self.media_rtpengine.input(&mut self.switcher).on_event(now, transport_rtpengine::GroupInput::Net(child, event));
                 }
             },
+            Input::IceTcp(pkt) => {
+                self.media_webrtc.input(&mut self.switcher).on_event(now, transport_webrtc::GroupInput::IceTcp(pkt));
+            }
             Input::Bus(event) => {
                 let now_ms = self.timer.timestamp_ms(now);
                 self.sdn_worker.input(&mut self.switcher).on_event(now_ms, SdnWorkerInput::Bus(event));
@@ -385,7 +556,24 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
         None
     }
 
+    /// Shutdown is done in two phases. First webrtc sessions are closed gracefully while sdn and cluster are still running,
+    /// so the final peer events can reach connector and rooms are left cleanly. After all sessions are closed or the grace
+    /// period is over, everything is force shutdown.
     pub fn on_shutdown(&mut self, now: Instant) {
+        if self.shutdown || self.draining_until.is_some() {
+            return;
+        }
+        if self.shutdown_grace.is_zero() {
+            self.force_shutdown(now);
+        } else {
+            log::info!("[MediaServerWorker] shutdown => drain sessions in {:?}", self.shutdown_grace);
+            self.draining_until = Some(now + self.shutdown_grace);
+            self.media_webrtc.input(&mut self.switcher).graceful_shutdown(now, self.shutdown_grace);
+        }
+    }
+
+    fn force_shutdown(&mut self, now: Instant) {
+        self.shutdown = true;
         let now_ms = self.timer.timestamp_ms(now);
         self.sdn_worker.input(&mut self.switcher).on_shutdown(now_ms);
         self.media_cluster.input(&mut self.switcher).shutdown(now);
@@ -451,6 +639,10 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                 }
                 Output::Continue
             }
+            cluster::Output::RoomTracks(req_id, tracks) => {
+                log::info!("[MediaServerWorker] rpc request {req_id}, admin::RpcReq::RoomTracks => {} tracks", tracks.len());
+                Output::ExtRpc(req_id, RpcRes::Admin(admin::RpcRes::RoomTracks(Ok(tracks))))
+            }
             cluster::Output::OnResourceEmpty => Output::Continue,
             cluster::Output::Continue => Output::Continue,
         }
@@ -459,6 +651,7 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
     fn output_webrtc(&mut self, now: Instant, out: transport_webrtc::GroupOutput) -> Output {
         match out {
             transport_webrtc::GroupOutput::Net(out) => Output::Net(Owner::MediaWebrtc, out),
+            transport_webrtc::GroupOutput::IceTcp(pkt) => Output::IceTcp(pkt),
             transport_webrtc::GroupOutput::Cluster(session, room, control) => {
                 self.media_cluster.input(&mut self.switcher).on_endpoint_control(now, session.into(), room, control);
                 Output::Continue
@@ -475,6 +668,7 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                             connector_request::Request::Peer(PeerEvent {
                                 app: app.into(),
                                 session_id,
+                                tags: session_end_tags(&mut self.session_tags, session_id, &event),
                                 event: Some(event),
                             }),
                         )
@@ -483,7 +677,10 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                 );
                 Output::Continue
             }
-            transport_webrtc::GroupOutput::RecordEvent(_, session_id, ts, event) => Output::Record(session_id, ts, event),
+            transport_webrtc::GroupOutput::RecordEvent(_, session_id, ts, event) => {
+                self.audit_recording(session_id, &event);
+                Output::Record(session_id, ts, event)
+            }
             transport_webrtc::GroupOutput::Ext(session, ext) => match ext {
                 transport_webrtc::ExtOut::RemoteIce(req_id, variant, res) => match variant {
                     transport_webrtc::Variant::Whip => Output::ExtRpc(req_id, RpcRes::Whip(whip::RpcRes::RemoteIce(res.map(|_| WhipRemoteIceRes {})))),
@@ -508,6 +705,9 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                     transport_webrtc::Variant::Whep => Output::ExtRpc(req_id, RpcRes::Whep(whep::RpcRes::Delete(res.map(|_| WhepDeleteRes {})))),
                     transport_webrtc::Variant::Webrtc => Output::ExtRpc(req_id, RpcRes::Webrtc(webrtc::RpcRes::Delete(res))),
                 },
+                transport_webrtc::ExtOut::Migrate(req_id, res) => Output::ExtRpc(req_id, RpcRes::Webrtc(webrtc::RpcRes::Migrate(res))),
+                transport_webrtc::ExtOut::Events(req_id, res) => Output::ExtRpc(req_id, RpcRes::Whep(whep::RpcRes::Events(res.map(|events| WhepEventsRes { events })))),
+                transport_webrtc::ExtOut::Dump(req_id, res) => Output::ExtRpc(req_id, RpcRes::Webrtc(webrtc::RpcRes::Dump(res))),
             },
             transport_webrtc::GroupOutput::OnResourceEmpty => Output::Continue,
             transport_webrtc::GroupOutput::Continue => Output::Continue,
@@ -537,6 +737,7 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                             connector_request::Request::Peer(PeerEvent {
                                 app: app.into(),
                                 session_id,
+                                tags: Default::default(),
                                 event: Some(event),
                             }),
                         )
@@ -545,7 +746,10 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                 );
                 Output::Continue
             }
-            transport_rtpengine::GroupOutput::RecordEvent(_, session_id, ts, event) => Output::Record(session_id, ts, event),
+            transport_rtpengine::GroupOutput::RecordEvent(_, session_id, ts, event) => {
+                self.audit_recording(session_id, &event);
+                Output::Record(session_id, ts, event)
+            }
             transport_rtpengine::GroupOutput::OnResourceEmpty => Output::Continue,
             transport_rtpengine::GroupOutput::Continue => Output::Continue,
         }
@@ -553,12 +757,25 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
 }
 
 impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
+    fn store_session_tags(&mut self, session_id: u64, tags: SessionTags) {
+        if !tags.is_empty() {
+            self.session_tags.insert(session_id, tags);
+        }
+    }
+
+    fn audit_recording(&mut self, session_id: u64, event: &SessionRecordEvent) {
+        if let Some(audit) = &self.room_audit {
+            audit_session_recording(audit, &mut self.recording_sessions, now_ms(), session_id, event);
+        }
+    }
+
     fn process_rpc(&mut self, now: Instant, req_id: u64, req: RpcReq<usize>) {
         log::info!("[MediaServerWorker] incoming rpc req {req_id}");
         match req {
             RpcReq::Whip(req) => match req {
                 whip::RpcReq::Connect(req) => {
                     log::info!("[MediaServerWorker] on rpc request {req_id}, whip::RpcReq::Connect");
+                    let (session_id, tags) = (req.session_id, req.tags);
                     match self.media_webrtc.input(&mut self.switcher).spawn(
                         req.app,
                         req.ip,
@@ -568,6 +785,7 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                     ) {
                         Ok((_ice_lite, sdp, conn_id)) => {
                             log::info!("[MediaServerWorker] rpc request {req_id}, whip::RpcReq::Connect => created conn {conn_id}");
+                            self.store_session_tags(session_id, tags);
                             self.queue.push_back(Output::ExtRpc(req_id, RpcRes::Whip(whip::RpcRes::Connect(Ok(WhipConnectRes { conn_id, sdp })))))
                         }
                         Err(e) => {
@@ -594,6 +812,7 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
             RpcReq::Whep(req) => match req {
                 whep::RpcReq::Connect(req) => {
                     log::info!("[MediaServerWorker] on rpc request {req_id}, whep::RpcReq::Connect");
+                    let (session_id, tags) = (req.session_id, req.tags);
                     let peer_id = format!("whep-{}", random::<u64>());
                     match self.media_webrtc.input(&mut self.switcher).spawn(
                         req.app,
@@ -604,6 +823,7 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                     ) {
                         Ok((_ice_lite, sdp, conn_id)) => {
                             log::info!("[MediaServerWorker] rpc request {req_id}, whep::RpcReq::Connect => created conn {conn_id}");
+                            self.store_session_tags(session_id, tags);
                             self.queue.push_back(Output::ExtRpc(req_id, RpcRes::Whep(whep::RpcRes::Connect(Ok(WhepConnectRes { conn_id, sdp })))))
                         }
                         Err(e) => {
@@ -626,6 +846,13 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                         transport_webrtc::GroupInput::Ext(req.conn_id.into(), transport_webrtc::ExtIn::Disconnect(req_id, transport_webrtc::Variant::Whep)),
                     );
                 }
+                whep::RpcReq::Events(req) => {
+                    // event streams poll frequently, so this is not logged at info level
+                    log::debug!("[MediaServerWorker] on rpc request {req_id}, whep::RpcReq::Events");
+                    self.media_webrtc
+                        .input(&mut self.switcher)
+                        .on_event(now, transport_webrtc::GroupInput::Ext(req.conn_id.into(), transport_webrtc::ExtIn::Events(req_id, req.initial)));
+                }
             },
             RpcReq::Webrtc(req) => match req {
                 webrtc::RpcReq::Connect(app, session_id, ip, user_agent, req, extra_data, record) => {
@@ -637,6 +864,7 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                     {
                         Ok((ice_lite, sdp, conn_id)) => {
                             log::info!("[MediaServerWorker] rpc request {req_id}, webrtc::RpcReq::Connect => created conn {conn_id}");
+                            self.store_session_tags(session_id, req.tags);
                             self.queue.push_back(Output::ExtRpc(
                                 req_id,
                                 RpcRes::Webrtc(webrtc::RpcRes::Connect(Ok((
@@ -679,6 +907,21 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                         transport_webrtc::GroupInput::Ext(conn.into(), transport_webrtc::ExtIn::Disconnect(req_id, transport_webrtc::Variant::Webrtc)),
                     );
                 }
+                webrtc::RpcReq::Migrate(conn, _app, dest) => {
+                    log::info!("[MediaServerWorker] on rpc request {req_id}, webrtc::RpcReq::Migrate to {dest:?}");
+                    self.media_webrtc.input(&mut self.switcher).migrate_session(now, conn, req_id, dest);
+                }
+                webrtc::RpcReq::Dump(conn) => {
+                    log::info!("[MediaServerWorker] on rpc request {req_id}, webrtc::RpcReq::Dump");
+                    self.media_webrtc
+                        .input(&mut self.switcher)
+                        .on_event(now, transport_webrtc::GroupInput::Ext(conn.into(), transport_webrtc::ExtIn::Dump(req_id)));
+                }
+                webrtc::RpcReq::Validate(_app, _ip, req) => {
+                    log::info!("[MediaServerWorker] on rpc request {req_id}, webrtc::RpcReq::Validate");
+                    let res = self.media_webrtc.input(&mut self.switcher).validate_offer(&req.sdp).map(Into::into);
+                    self.queue.push_back(Output::ExtRpc(req_id, RpcRes::Webrtc(webrtc::RpcRes::Validate(res))));
+                }
             },
             RpcReq::RtpEngine(req) => match req {
                 rtpengine::RpcReq::CreateOffer(conn_req) => {
@@ -721,6 +964,23 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                         }
                     }
                 }
+                rtpengine::RpcReq::CreateEgress(conn_req) => {
+                    log::info!("[MediaServerWorker] on rpc request {req_id}, rtpengine::RpcReq::CreateEgress");
+                    match self
+                        .media_rtpengine
+                        .input(&mut self.switcher)
+                        .spawn_egress(conn_req.app, conn_req.room, conn_req.peer, conn_req.source, conn_req.kind, conn_req.dest, conn_req.session_id)
+                    {
+                        Ok((conn_id, sdp)) => {
+                            log::info!("[MediaServerWorker] rpc request {req_id}, rtpengine::RpcReq::CreateEgress => created conn {conn_id}");
+                            self.queue.push_back(Output::ExtRpc(req_id, RpcRes::RtpEngine(rtpengine::RpcRes::CreateEgress(Ok((conn_id, sdp))))))
+                        }
+                        Err(e) => {
+                            log::error!("[MediaServerWorker] rpc request {req_id}, rtpengine::RpcReq::CreateEgress => error {e:?}");
+                            self.queue.push_back(Output::ExtRpc(req_id, RpcRes::RtpEngine(rtpengine::RpcRes::CreateEgress(Err(e)))))
+                        }
+                    }
+                }
                 rtpengine::RpcReq::Delete(conn) => {
                     log::info!("[MediaServerWorker] on rpc request {req_id}, rtpengine::RpcReq::Delete");
                     self.media_rtpengine
@@ -728,16 +988,128 @@ impl<ES: 'static + MediaEdgeSecure> MediaServerWorker<ES> {
                         .on_event(now, transport_rtpengine::GroupInput::Ext(conn.into(), transport_rtpengine::ExtIn::Disconnect(req_id)));
                 }
             },
+            RpcReq::Admin(req) => match req {
+                admin::RpcReq::CloseSessions(req) => {
+                    log::info!("[MediaServerWorker] on rpc request {req_id}, admin::RpcReq::CloseSessions");
+                    let room = req.room.map(|room| cluster::ClusterRoomHash::generate(&req.app, &room));
+                    let webrtc = self.media_webrtc.input(&mut self.switcher).close_sessions(now, &req.app.app, room, req.migrated_session);
+                    // rtpengine sessions are never migrated
+                    let rtpengine = match req.migrated_session {
+                        Some(_) => 0,
+                        None => self.media_rtpengine.input(&mut self.switcher).close_sessions(now, &req.app.app, room),
+                    };
+                    log::info!("[MediaServerWorker] rpc request {req_id}, admin::RpcReq::CloseSessions => closed {webrtc} webrtc, {rtpengine} rtpengine sessions");
+                    let res = CloseSessionsRes {
+                        nodes: vec![NodeCloseResult {
+                            node: self.node_id,
+                            closed: Ok((webrtc + rtpengine) as u32),
+                        }],
+                    };
+                    self.queue.push_back(Output::ExtRpc(req_id, RpcRes::Admin(admin::RpcRes::CloseSessions(Ok(res)))))
+                }
+                admin::RpcReq::RoomTracks(req) => {
+                    log::info!("[MediaServerWorker] on rpc request {req_id}, admin::RpcReq::RoomTracks");
+                    self.media_cluster.input(&mut self.switcher).query_room_tracks(now, req_id, &req.app.app, &req.room);
+                }
+                // routes only exist in gateways
+                admin::RpcReq::PendingRoutes => self.queue.push_back(Output::ExtRpc(req_id, RpcRes::Admin(admin::RpcRes::PendingRoutes(Ok(vec![]))))),
+                admin::RpcReq::CancelRoute(_) => self.queue.push_back(Output::ExtRpc(req_id, RpcRes::Admin(admin::RpcRes::CancelRoute(Ok(false))))),
+            },
+        }
+    }
+}
+
+/// Session tags are attached to session end events, after that the session is gone so tags are released
+fn session_end_tags(tags: &mut HashMap<u64, SessionTags>, session_id: u64, event: &peer_event::Event) -> SessionTags {
+    match event {
+        peer_event::Event::Disconnected(_) | peer_event::Event::ConnectError(_) => tags.remove(&session_id).unwrap_or_default(),
+        _ => SessionTags::new(),
+    }
+}
+
+/// Recording of a session starts when it joins a room with record enabled, and stops when it leaves or disconnects
+fn audit_session_recording(audit: &RoomAudit, sessions: &mut HashMap<u64, (cluster::ClusterRoomHash, PeerId)>, now_ms: u64, session_id: u64, event: &SessionRecordEvent) {
+    let stopped = match event {
+        SessionRecordEvent::JoinRoom(app, room, peer) => {
+            let room = cluster::ClusterRoomHash::generate(&AppContext { app: app.clone() }, room);
+            let stopped = sessions.insert(session_id, (room, peer.clone()));
+            audit.on_event(now_ms, room, AuditEvent::RecordingStateChanged(peer.clone(), true));
+            stopped
         }
+        SessionRecordEvent::LeaveRoom | SessionRecordEvent::Disconnected => sessions.remove(&session_id),
+        _ => None,
+    };
+    if let Some((room, peer)) = stopped {
+        audit.on_event(now_ms, room, AuditEvent::RecordingStateChanged(peer, false));
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use media_server_core::cluster::{AuditEvent, AuditRecord, AuditSink, RoomAudit};
+    use media_server_protocol::{endpoint::PeerId, protobuf::cluster_connector::peer_event, record::SessionRecordEvent, session_tags::SessionTags};
     use transport_rtpengine::RtpEngineSession;
     use transport_webrtc::WebrtcSession;
 
-    use super::MediaClusterEndpoint;
+    use super::{audit_session_recording, session_end_tags, MediaClusterEndpoint};
+
+    #[test]
+    fn session_tags_on_session_end_event() {
+        let tags = SessionTags::from([("user".to_string(), "u1".to_string()), ("bucket".to_string(), "b".to_string())]);
+        let mut sessions = HashMap::from([(1000, tags.clone())]);
+
+        let connected = peer_event::Event::Connected(peer_event::Connected {
+            after_ms: 10,
+            remote_ip: "127.0.0.1".to_string(),
+        });
+        assert_eq!(session_end_tags(&mut sessions, 1000, &connected), SessionTags::new());
+
+        let disconnected = peer_event::Event::Disconnected(peer_event::Disconnected { duration_ms: 1000, reason: 0 });
+        assert_eq!(session_end_tags(&mut sessions, 1001, &disconnected), SessionTags::new());
+        assert_eq!(session_end_tags(&mut sessions, 1000, &disconnected), tags);
+        assert!(sessions.is_empty(), "tags should be released after session end");
+    }
+
+    #[derive(Default)]
+    struct AuditEvents(Mutex<Vec<AuditEvent>>);
+
+    impl AuditSink for AuditEvents {
+        fn write(&self, record: &AuditRecord) {
+            self.0.lock().expect("Should lock").push(record.event.clone());
+        }
+    }
+
+    #[test]
+    fn recording_audited_from_record_events() {
+        let sink = Arc::new(AuditEvents::default());
+        let audit = RoomAudit::new(sink.clone());
+        let mut sessions = HashMap::new();
+        let peer = PeerId::from("peer1");
+        let join = SessionRecordEvent::JoinRoom("app".into(), "room1".into(), peer.clone());
+
+        audit_session_recording(&audit, &mut sessions, 1000, 1, &join);
+        audit_session_recording(&audit, &mut sessions, 1001, 1, &SessionRecordEvent::LeaveRoom);
+        // session which is not recording has nothing to stop
+        audit_session_recording(&audit, &mut sessions, 1002, 1, &SessionRecordEvent::Disconnected);
+        audit_session_recording(&audit, &mut sessions, 1003, 2, &join);
+        audit_session_recording(&audit, &mut sessions, 1004, 2, &SessionRecordEvent::Disconnected);
+        assert!(sessions.is_empty());
+
+        assert_eq!(
+            *sink.0.lock().expect("Should lock"),
+            vec![
+                AuditEvent::RecordingStateChanged(peer.clone(), true),
+                AuditEvent::RecordingStateChanged(peer.clone(), false),
+                AuditEvent::RecordingStateChanged(peer.clone(), true),
+                AuditEvent::RecordingStateChanged(peer, false),
+            ]
+        );
+    }
 
     #[test]
     fn smallmap_collision() {
@@ -2,10 +2,14 @@ use std::sync::Arc;
 
 use crate::{AppStorage, MediaConsoleSecure, MediaEdgeSecure, MediaGatewaySecure, TokenObject};
 use jwt_simple::prelude::*;
-use media_server_protocol::multi_tenancy::{AppContext, AppId};
+use media_server_protocol::{
+    multi_tenancy::{AppContext, AppId},
+    transport::webrtc::WebrtcMigrateTicket,
+};
 use serde::{de::DeserializeOwned, Serialize};
 
 const CONN_ID_TYPE: &str = "conn";
+const MIGRATE_TICKET_TYPE: &str = "webrtc_migrate";
 const CONSOLE_SESSION_TYPE: &str = "console_session";
 
 pub struct MediaEdgeSecureJwt {
@@ -58,6 +62,26 @@ impl MediaEdgeSecure for MediaEdgeSecureJwt {
         }
         Some(claims.custom)
     }
+
+    fn encode_migrate_ticket(&self, ticket: WebrtcMigrateTicket) -> String {
+        let claims = Claims::with_custom_claims(ticket, Duration::from_secs(WebrtcMigrateTicket::TTL_SECONDS)).with_issuer(MIGRATE_TICKET_TYPE);
+        self.key.authenticate(claims).expect("Should create jwt")
+    }
+
+    fn decode_migrate_ticket(&self, token: &str) -> Option<WebrtcMigrateTicket> {
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[MIGRATE_TICKET_TYPE])),
+            ..Default::default()
+        };
+        let claims = self.key.verify_token::<WebrtcMigrateTicket>(token, Some(options)).ok()?;
+        if let Some(expires_at) = claims.expires_at {
+            let now = Clock::now_since_epoch();
+            if now >= expires_at {
+                return None;
+            }
+        }
+        Some(claims.custom)
+    }
 }
 
 pub struct MediaGatewaySecureJwt {
@@ -141,7 +165,7 @@ impl MediaConsoleSecure for MediaConsoleSecureJwt {
 mod tests {
     use std::{sync::Arc, thread::sleep, time::Duration};
 
-    use media_server_protocol::multi_tenancy::AppId;
+    use media_server_protocol::{multi_tenancy::AppId, transport::webrtc::WebrtcMigrateTicket};
     use serde::{Deserialize, Serialize};
 
     use crate::{
@@ -234,4 +258,19 @@ mod tests {
         assert_eq!(edge_jwt.decode_conn_id::<Test>(&token), None, "Should error after timeout");
         assert_eq!(gateway_jwt.decode_conn_id::<Test>(&token), None, "Should error after timeout");
     }
+
+    #[test]
+    fn migrate_ticket_test() {
+        let edge_jwt = MediaEdgeSecureJwt::from(b"12345678".as_slice());
+        let ticket = WebrtcMigrateTicket::new(1000, 1, 2);
+        let token = edge_jwt.encode_migrate_ticket(ticket.clone());
+        assert_eq!(edge_jwt.decode_migrate_ticket(&token), Some(ticket.clone()), "Should decode ok");
+        assert_ne!(WebrtcMigrateTicket::new(1000, 1, 2).nonce, ticket.nonce, "Should have random nonce");
+
+        // tickets and conn ids are not interchangeable
+        assert_eq!(edge_jwt.decode_conn_id::<WebrtcMigrateTicket>(&token), None, "Should error if ticket is used as conn id");
+        let conn = edge_jwt.encode_conn_id(ticket.clone(), 10);
+        assert_eq!(edge_jwt.decode_migrate_ticket(&conn), None, "Should error if conn id is used as ticket");
+        assert_eq!(MediaEdgeSecureJwt::from(b"other".as_slice()).decode_migrate_ticket(&token), None, "Should error with other key");
+    }
 }
@@ -1,6 +1,7 @@
 use media_server_protocol::{
     multi_tenancy::AppContext,
     tokens::{RtpEngineToken, WebrtcToken, WhepToken, WhipToken},
+    transport::webrtc::WebrtcMigrateTicket,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
@@ -16,6 +17,9 @@ pub trait MediaEdgeSecure {
     fn decode_token<O: TokenObject>(&self, data: &str) -> Option<(AppContext, O)>;
     fn encode_conn_id<C: Serialize + DeserializeOwned>(&self, conn: C, ttl_seconds: u64) -> String;
     fn decode_conn_id<C: Serialize + DeserializeOwned>(&self, data: &str) -> Option<C>;
+    /// Migrate tickets are signed with their own issuer, so a ticket can't be used as a conn id or the other way around
+    fn encode_migrate_ticket(&self, ticket: WebrtcMigrateTicket) -> String;
+    fn decode_migrate_ticket(&self, data: &str) -> Option<WebrtcMigrateTicket>;
 }
 
 pub trait AppStorage: Send + Sync + 'static {
@@ -23,6 +23,11 @@ impl<T> Count<T> {
         counter.fetch_add(1, Ordering::SeqCst);
     }
 
+    /// Count an event of T without a live instance, it is never decreased so the value is total number of events
+    pub fn event() {
+        Self::increase();
+    }
+
     fn decrease() {
         let mut registry = REGISTRY.lock();
         let counter = registry.entry(Self::type_name()).or_insert_with(|| AtomicUsize::new(0));
@@ -0,0 +1,151 @@
+//! Node-wide egress bandwidth budget, shared by endpoints of all workers.
+//!
+//! Each endpoint holds an [`EgressBudgetSlot`] and reports its demand, which is the egress estimate after its own caps.
+//! When the total demand is over [`PRESSURE_PERCENT`] of the cap, every endpoint is allocated the same share of its demand,
+//! so subscribers are biased toward lower layers together. New subscriptions are refused when demand is over the cap.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+/// Allocation starts scaling down when demand is over this percent of the cap, keeping headroom for audio and probes
+const PRESSURE_PERCENT: u64 = 90;
+
+static NODE_BUDGET: OnceCell<Arc<EgressBudget>> = OnceCell::new();
+
+#[derive(Debug)]
+pub struct EgressBudget {
+    cap_bps: u64,
+    demand_bps: AtomicU64,
+    allocated_bps: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EgressBudgetSummary {
+    pub cap_bps: u64,
+    pub demand_bps: u64,
+    pub allocated_bps: u64,
+}
+
+impl EgressBudget {
+    pub fn new(cap_bps: u64) -> Arc<Self> {
+        Arc::new(Self {
+            cap_bps,
+            demand_bps: AtomicU64::new(0),
+            allocated_bps: AtomicU64::new(0),
+        })
+    }
+
+    pub fn slot(self: &Arc<Self>) -> EgressBudgetSlot {
+        EgressBudgetSlot {
+            budget: self.clone(),
+            demand: 0,
+            allocated: 0,
+        }
+    }
+
+    /// True when demand of current sessions already reaches the cap
+    pub fn is_full(&self) -> bool {
+        self.demand_bps.load(Ordering::Relaxed) >= self.cap_bps
+    }
+
+    pub fn summary(&self) -> EgressBudgetSummary {
+        EgressBudgetSummary {
+            cap_bps: self.cap_bps,
+            demand_bps: self.demand_bps.load(Ordering::Relaxed),
+            allocated_bps: self.allocated_bps.load(Ordering::Relaxed),
+        }
+    }
+
+    fn allocate(&self, demand: u64) -> u64 {
+        let target = self.cap_bps * PRESSURE_PERCENT / 100;
+        let total = self.demand_bps.load(Ordering::Relaxed);
+        if total <= target {
+            demand
+        } else {
+            (demand as u128 * target as u128 / total as u128) as u64
+        }
+    }
+}
+
+/// Share of an endpoint in the budget, its demand and allocation are removed on drop
+#[derive(Debug)]
+pub struct EgressBudgetSlot {
+    budget: Arc<EgressBudget>,
+    demand: u64,
+    allocated: u64,
+}
+
+impl EgressBudgetSlot {
+    /// Update demand of the endpoint and return the bitrate which it is allowed to send
+    pub fn allocate(&mut self, demand: u64) -> u64 {
+        self.budget.demand_bps.fetch_add(demand, Ordering::Relaxed);
+        self.budget.demand_bps.fetch_sub(self.demand, Ordering::Relaxed);
+        self.demand = demand;
+
+        let allocated = self.budget.allocate(demand);
+        self.budget.allocated_bps.fetch_add(allocated, Ordering::Relaxed);
+        self.budget.allocated_bps.fetch_sub(self.allocated, Ordering::Relaxed);
+        self.allocated = allocated;
+        allocated
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.budget.is_full()
+    }
+}
+
+impl Drop for EgressBudgetSlot {
+    fn drop(&mut self) {
+        self.budget.demand_bps.fetch_sub(self.demand, Ordering::Relaxed);
+        self.budget.allocated_bps.fetch_sub(self.allocated, Ordering::Relaxed);
+    }
+}
+
+/// Set the budget of this node, only the first call takes effect
+pub fn init_node_egress_budget(cap_bps: u64) -> Arc<EgressBudget> {
+    NODE_BUDGET.get_or_init(|| EgressBudget::new(cap_bps)).clone()
+}
+
+/// Budget of this node, None if egress is not capped
+pub fn node_egress_budget() -> Option<Arc<EgressBudget>> {
+    NODE_BUDGET.get().cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EgressBudget;
+
+    #[test]
+    fn allocate_under_cap() {
+        let budget = EgressBudget::new(10_000_000);
+        let mut slot1 = budget.slot();
+        let mut slot2 = budget.slot();
+        assert_eq!(slot1.allocate(2_000_000), 2_000_000);
+        assert_eq!(slot2.allocate(3_000_000), 3_000_000);
+        assert_eq!(budget.summary().demand_bps, 5_000_000);
+        assert!(!budget.is_full());
+    }
+
+    #[test]
+    fn allocate_near_cap_scale_all_sessions() {
+        let budget = EgressBudget::new(4_000_000);
+        let mut slot1 = budget.slot();
+        let mut slot2 = budget.slot();
+        slot1.allocate(2_000_000);
+        slot2.allocate(2_000_000);
+        // both sessions get the same share of 90% cap
+        assert_eq!(slot1.allocate(2_000_000), 1_800_000);
+        assert_eq!(slot2.allocate(2_000_000), 1_800_000);
+        assert!(budget.is_full());
+
+        drop(slot2);
+        assert_eq!(budget.summary().demand_bps, 2_000_000);
+        assert_eq!(slot1.allocate(2_000_000), 2_000_000);
+        assert!(!budget.is_full());
+    }
+}
@@ -1,21 +1,33 @@
 mod count;
+mod egress_budget;
 mod f16;
 mod indexmap_2d;
+mod loop_metrics;
+mod readiness;
+mod rtp_egress;
+mod rtp_ingest;
 mod select;
 mod seq_extend;
 mod seq_rewrite;
 mod state;
 mod time;
 mod ts_rewrite;
+mod udp_buffer;
 mod uri;
 
 pub use count::{get_all_counts, Count};
+pub use egress_budget::{init_node_egress_budget, node_egress_budget, EgressBudget, EgressBudgetSlot, EgressBudgetSummary};
 pub use f16::{F16i, F16u};
 pub use indexmap_2d::IndexMap2d;
+pub use loop_metrics::{get_all_loop_metrics, DurationHistogram, LoopMetrics, LoopMetricsRecorder, LoopMetricsSummary};
+pub use readiness::{node_degraded, node_ready, DegradedGuard, StartingGuard};
+pub use rtp_egress::{AllowedNet, RtpEgressAllowlist};
+pub use rtp_ingest::{check_rtp, check_rtp_header, is_rtp, BadRtp, BadRtpPacket, RtpIngest, RtpIngestGuard, RtpIngestPolicy, UnverifiedRtpDropped, DEFAULT_RTP_MAX_SIZE};
 pub use select::*;
 pub use seq_extend::RtpSeqExtend;
 pub use seq_rewrite::SeqRewrite;
 pub use state::*;
-pub use time::now_ms;
+pub use time::{elapsed_ms, now_ms};
 pub use ts_rewrite::TsRewrite;
+pub use udp_buffer::{apply_udp_buffer, bind_udp_with_buffer, UdpBufferConfig, UdpBufferGranted};
 pub use uri::CustomUri;
@@ -0,0 +1,236 @@
+//! Lightweight timing metrics for sans-io loops.
+//!
+//! Each loop owner keeps a local [`LoopMetricsRecorder`] and only flushes it into the global registry
+//! once per [`REPORT_INTERVAL`], so the hot path never touches the shared lock.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use spin::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+const BUCKETS: usize = 24; //power of two microsecond buckets, last bucket is about 8 seconds
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+static REGISTRY: Lazy<Mutex<HashMap<&'static str, LoopMetrics>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Histogram with power of two microsecond buckets, percentiles are bucket upper bounds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DurationHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max_us: u64,
+}
+
+impl DurationHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = ((u64::BITS - us.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max_us(&self) -> u64 {
+        self.max_us
+    }
+
+    /// Return the upper bound in microseconds of the bucket which contains the given percentile
+    pub fn percentile_us(&self, percent: u64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let target = (self.count * percent).div_ceil(100).max(1);
+        let mut acc = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            acc += count;
+            if acc >= target {
+                let upper = if bucket == 0 {
+                    0
+                } else {
+                    (1u64 << bucket) - 1
+                };
+                return upper.min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (slot, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *slot += count;
+        }
+        self.count += other.count;
+        self.max_us = self.max_us.max(other.max_us);
+    }
+}
+
+/// Timing of on_tick and on_event calls, and max number of outputs produced by one call
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopMetrics {
+    pub tick: DurationHistogram,
+    pub event: DurationHistogram,
+    pub max_queue: usize,
+}
+
+impl LoopMetrics {
+    pub fn merge(&mut self, other: &Self) {
+        self.tick.merge(&other.tick);
+        self.event.merge(&other.event);
+        self.max_queue = self.max_queue.max(other.max_queue);
+    }
+
+    pub fn summary(&self) -> LoopMetricsSummary {
+        LoopMetricsSummary {
+            tick_count: self.tick.count(),
+            tick_p50_us: self.tick.percentile_us(50),
+            tick_p99_us: self.tick.percentile_us(99),
+            tick_max_us: self.tick.max_us(),
+            event_count: self.event.count(),
+            event_p50_us: self.event.percentile_us(50),
+            event_p99_us: self.event.percentile_us(99),
+            event_max_us: self.event.max_us(),
+            max_queue: self.max_queue,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoopMetricsSummary {
+    pub tick_count: u64,
+    pub tick_p50_us: u64,
+    pub tick_p99_us: u64,
+    pub tick_max_us: u64,
+    pub event_count: u64,
+    pub event_p50_us: u64,
+    pub event_p99_us: u64,
+    pub event_max_us: u64,
+    pub max_queue: usize,
+}
+
+/// Local recorder for a loop owner, flushed to the global registry with the given name
+#[derive(Debug)]
+pub struct LoopMetricsRecorder {
+    name: &'static str,
+    metrics: LoopMetrics,
+    queue: usize,
+    last_report: Option<Instant>,
+}
+
+impl LoopMetricsRecorder {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            metrics: LoopMetrics::default(),
+            queue: 0,
+            last_report: None,
+        }
+    }
+
+    pub fn on_tick(&mut self, elapsed: Duration) {
+        self.metrics.tick.record(elapsed);
+    }
+
+    pub fn on_event(&mut self, elapsed: Duration) {
+        self.metrics.event.record(elapsed);
+    }
+
+    /// Call on each pop_output, has_output is false when the queue is drained
+    pub fn on_pop(&mut self, has_output: bool) {
+        if has_output {
+            self.queue += 1;
+        } else {
+            self.metrics.max_queue = self.metrics.max_queue.max(self.queue);
+            self.queue = 0;
+        }
+    }
+
+    /// Metrics which are not flushed yet
+    pub fn metrics(&self) -> &LoopMetrics {
+        &self.metrics
+    }
+
+    /// Flush to global registry if report interval passed
+    pub fn report(&mut self, now: Instant) {
+        match self.last_report {
+            Some(last) if last + REPORT_INTERVAL > now => {}
+            Some(_) => {
+                self.flush();
+                self.last_report = Some(now);
+            }
+            None => self.last_report = Some(now),
+        }
+    }
+
+    fn flush(&mut self) {
+        let metrics = std::mem::take(&mut self.metrics);
+        let mut registry = REGISTRY.lock();
+        registry.entry(self.name).or_default().merge(&metrics);
+    }
+}
+
+impl Drop for LoopMetricsRecorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Returns a map of all loop names to their metrics since start
+pub fn get_all_loop_metrics() -> BTreeMap<&'static str, LoopMetricsSummary> {
+    let registry = REGISTRY.lock();
+    registry.iter().map(|(name, metrics)| (*name, metrics.summary())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn histogram_percentile() {
+        let mut hist = DurationHistogram::default();
+        assert_eq!(hist.percentile_us(50), 0);
+        for _ in 0..98 {
+            hist.record(Duration::from_micros(10));
+        }
+        hist.record(Duration::from_micros(1000));
+        hist.record(Duration::from_micros(5000));
+
+        assert_eq!(hist.count(), 100);
+        assert_eq!(hist.max_us(), 5000);
+        assert_eq!(hist.percentile_us(50), 15);
+        assert_eq!(hist.percentile_us(99), 1023);
+        assert_eq!(hist.percentile_us(100), 5000);
+    }
+
+    #[test]
+    fn recorder_queue_and_report() {
+        let now = Instant::now();
+        let mut recorder = LoopMetricsRecorder::new("test_loop");
+        recorder.on_tick(Duration::from_micros(100));
+        recorder.on_event(Duration::from_micros(10));
+        recorder.on_pop(true);
+        recorder.on_pop(true);
+        recorder.on_pop(false);
+        recorder.on_pop(true);
+        recorder.on_pop(false);
+        assert_eq!(recorder.metrics().tick.count(), 1);
+        assert_eq!(recorder.metrics().event.count(), 1);
+        assert_eq!(recorder.metrics().max_queue, 2);
+
+        recorder.report(now);
+        assert_eq!(get_all_loop_metrics().get("test_loop"), None);
+
+        recorder.report(now + REPORT_INTERVAL);
+        assert_eq!(recorder.metrics().tick.count(), 0);
+        let summary = get_all_loop_metrics().get("test_loop").cloned().expect("Should have metrics");
+        assert_eq!(summary.tick_count, 1);
+        assert_eq!(summary.event_count, 1);
+        assert_eq!(summary.max_queue, 2);
+    }
+}
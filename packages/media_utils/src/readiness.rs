@@ -0,0 +1,76 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+/// Number of components which are still starting, e.g. workers which are binding sockets
+static STARTING: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks a component as starting until it is ready or dropped, the node is only ready when no component is starting
+#[derive(Debug)]
+pub struct StartingGuard {
+    ready: bool,
+}
+
+impl StartingGuard {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        STARTING.fetch_add(1, Ordering::SeqCst);
+        Self { ready: false }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    pub fn set_ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            STARTING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for StartingGuard {
+    fn drop(&mut self) {
+        self.set_ready();
+    }
+}
+
+/// True when all components of this node are ready for traffic
+pub fn node_ready() -> bool {
+    STARTING.load(Ordering::SeqCst) == 0
+}
+
+/// Components which are degraded, the node still serves traffic without them
+static DEGRADED: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+/// Marks a component as degraded until dropped, e.g. an unreachable optional service
+#[derive(Debug)]
+pub struct DegradedGuard {
+    component: &'static str,
+}
+
+impl DegradedGuard {
+    pub fn new(component: &'static str) -> Self {
+        DEGRADED.lock().expect("Should lock degraded").push(component);
+        Self { component }
+    }
+}
+
+impl Drop for DegradedGuard {
+    fn drop(&mut self) {
+        let mut degraded = DEGRADED.lock().expect("Should lock degraded");
+        if let Some(index) = degraded.iter().position(|c| *c == self.component) {
+            degraded.swap_remove(index);
+        }
+    }
+}
+
+/// Names of degraded components, sorted and without duplicates
+pub fn node_degraded() -> Vec<&'static str> {
+    let mut degraded = DEGRADED.lock().expect("Should lock degraded").clone();
+    degraded.sort();
+    degraded.dedup();
+    degraded
+}
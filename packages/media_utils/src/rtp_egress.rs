@@ -0,0 +1,110 @@
+//!
+//! Destinations which plain RTP egress sessions are allowed to send to.
+//!
+//! Egress sends media to an address which is given by the caller, so without a check the server could be used to
+//! send traffic at arbitrary hosts. Destinations must be inside one of the configured networks, an empty list
+//! denies all destinations, which disables plain RTP egress.
+//!
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// Network in CIDR format, e.g. `10.0.0.0/8`, a single ip is parsed as a network of one address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl AllowedNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => Self::masked(u32::from(net) as u128, 32, self.prefix) == Self::masked(u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => Self::masked(u128::from(net), 128, self.prefix) == Self::masked(u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+
+    fn masked(value: u128, bits: u8, prefix: u8) -> u128 {
+        if prefix == 0 {
+            0
+        } else {
+            value >> (bits - prefix)
+        }
+    }
+}
+
+impl FromStr for AllowedNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("invalid ip {addr}: {e}"))?;
+        let max = if addr.is_ipv4() {
+            32
+        } else {
+            128
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|e| format!("invalid prefix {prefix}: {e}"))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("prefix {prefix} is over {max}"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RtpEgressAllowlist {
+    nets: Vec<AllowedNet>,
+}
+
+impl RtpEgressAllowlist {
+    pub fn new(nets: Vec<AllowedNet>) -> Self {
+        Self { nets }
+    }
+
+    /// True if the destination is inside one of the allowed networks
+    pub fn allows(&self, dest: &SocketAddr) -> bool {
+        dest.port() != 0 && self.nets.iter().any(|net| net.contains(dest.ip()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllowedNet, RtpEgressAllowlist};
+
+    #[test]
+    fn parse_allowed_net() {
+        assert!("10.0.0.0/8".parse::<AllowedNet>().is_ok());
+        assert!("10.0.0.1".parse::<AllowedNet>().is_ok());
+        assert!("fd00::/8".parse::<AllowedNet>().is_ok());
+        assert!("10.0.0.0/33".parse::<AllowedNet>().is_err());
+        assert!("10.0.0/8".parse::<AllowedNet>().is_err());
+    }
+
+    #[test]
+    fn allow_only_inside_networks() {
+        let empty = RtpEgressAllowlist::default();
+        assert!(!empty.allows(&"10.0.0.1:5000".parse().unwrap()));
+
+        let allowlist = RtpEgressAllowlist::new(vec!["10.0.0.0/8".parse().unwrap(), "192.168.1.5".parse().unwrap(), "fd00::/8".parse().unwrap()]);
+        assert!(allowlist.allows(&"10.1.2.3:5000".parse().unwrap()));
+        assert!(allowlist.allows(&"192.168.1.5:5000".parse().unwrap()));
+        assert!(allowlist.allows(&"[fd12::1]:5000".parse().unwrap()));
+        assert!(!allowlist.allows(&"192.168.1.6:5000".parse().unwrap()));
+        assert!(!allowlist.allows(&"8.8.8.8:53".parse().unwrap()));
+        assert!(!allowlist.allows(&"[2001:db8::1]:5000".parse().unwrap()));
+        assert!(!allowlist.allows(&"10.1.2.3:0".parse().unwrap()));
+
+        let all = RtpEgressAllowlist::new(vec!["0.0.0.0/0".parse().unwrap()]);
+        assert!(all.allows(&"8.8.8.8:53".parse().unwrap()));
+    }
+}
@@ -0,0 +1,287 @@
+//!
+//! Defensive checks of RTP which is received from publishers, before it is given to parsers deeper in the stack.
+//!
+//! Malformed packets (truncated, wrong version, length fields over the packet) and oversized packets are dropped
+//! and counted per session, the warning is rate-limited so a misbehaving publisher cannot flood logs.
+//!
+//! Anyone who knows the address of a session can send packets to it, so packets are checked in two steps:
+//! - unverified: before SRTP authentication or from an unknown source, only the header is checked (SRTP keeps it
+//!   in clear). Bad packets are dropped but never close the session, otherwise spoofed packets could close it.
+//! - verified: SRTP authenticated, or plain RTP from the negotiated remote address. When the number of bad verified
+//!   packets in a second exceeds the abusive threshold, the session should be closed.
+//!
+//! Totals of all sessions are exported as [`BadRtpPacket`] and [`UnverifiedRtpDropped`] counts.
+//!
+
+use std::time::{Duration, Instant};
+
+use crate::Count;
+
+/// Max size of received RTP, it is the usual ethernet MTU
+pub const DEFAULT_RTP_MAX_SIZE: usize = 1500;
+
+const RTP_HEADER_LEN: usize = 12;
+
+/// Count of dropped RTP packets which are verified to come from the peer of the session
+pub struct BadRtpPacket;
+/// Count of dropped RTP packets which are not verified, they can be spoofed
+pub struct UnverifiedRtpDropped;
+
+const WARN_INTERVAL: Duration = Duration::from_secs(1);
+const ABUSIVE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpIngestPolicy {
+    /// Packets bigger than it are dropped as oversized
+    pub max_size: usize,
+    /// Bad packets per second which the session is closed as abusive over, None only drops them
+    pub abusive_per_sec: Option<u32>,
+}
+
+impl Default for RtpIngestPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_RTP_MAX_SIZE,
+            abusive_per_sec: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadRtp {
+    Oversized(usize),
+    Truncated(usize),
+    Version(u8),
+    /// CSRC list or header extension is longer than the packet
+    HeaderOverflow,
+}
+
+/// True if the packet is RTP in a socket which is multiplexed with STUN, DTLS and RTCP (RFC 7983, RFC 5761)
+pub fn is_rtp(buf: &[u8]) -> bool {
+    match buf {
+        [byte0, byte1, ..] => (128..192).contains(byte0) && !(64..96).contains(&(byte1 & 0x7F)),
+        _ => false,
+    }
+}
+
+/// Check the size and RTP header of a packet, the payload is not checked
+pub fn check_rtp(buf: &[u8], max_size: usize) -> Result<(), BadRtp> {
+    if buf.len() > max_size {
+        return Err(BadRtp::Oversized(buf.len()));
+    }
+    check_rtp_header(buf)
+}
+
+/// Check the RTP header of a packet, which is in clear for both plain RTP and SRTP
+pub fn check_rtp_header(buf: &[u8]) -> Result<(), BadRtp> {
+    if buf.len() < RTP_HEADER_LEN {
+        return Err(BadRtp::Truncated(buf.len()));
+    }
+    let version = buf[0] >> 6;
+    if version != 2 {
+        return Err(BadRtp::Version(version));
+    }
+    let csrc_count = (buf[0] & 0x0F) as usize;
+    let mut header_len = RTP_HEADER_LEN + 4 * csrc_count;
+    if buf[0] & 0x10 != 0 {
+        let ext = buf.get(header_len..header_len + 4).ok_or(BadRtp::HeaderOverflow)?;
+        header_len += 4 + 4 * u16::from_be_bytes([ext[2], ext[3]]) as usize;
+    }
+    if header_len > buf.len() {
+        return Err(BadRtp::HeaderOverflow);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtpIngest {
+    Accept,
+    Drop,
+    /// Dropped and the bad packet rate is over the abusive threshold
+    Abusive,
+}
+
+/// Per-session checker of received RTP
+#[derive(Debug)]
+pub struct RtpIngestGuard {
+    policy: RtpIngestPolicy,
+    bad_packets: u64,
+    unverified_drops: u64,
+    window: Option<(Instant, u32)>,
+    last_warn: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RtpIngestGuard {
+    pub fn new(policy: RtpIngestPolicy) -> Self {
+        Self {
+            policy,
+            bad_packets: 0,
+            unverified_drops: 0,
+            window: None,
+            last_warn: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Check the header of a packet which is not verified yet, bad packets are dropped without closing the session
+    pub fn on_unverified_rtp(&mut self, now: Instant, buf: &[u8]) -> RtpIngest {
+        match check_rtp_header(buf) {
+            Ok(()) => RtpIngest::Accept,
+            Err(err) => {
+                self.unverified_drops += 1;
+                Count::<UnverifiedRtpDropped>::event();
+                self.warn(now, "unverified", err);
+                RtpIngest::Drop
+            }
+        }
+    }
+
+    /// Check a plain RTP packet from the negotiated remote address
+    pub fn on_verified_rtp(&mut self, now: Instant, buf: &[u8]) -> RtpIngest {
+        match check_rtp(buf, self.policy.max_size) {
+            Ok(()) => RtpIngest::Accept,
+            Err(err) => self.on_verified_bad(now, err),
+        }
+    }
+
+    /// Check size of a SRTP packet after authentication, its header is already checked as unverified
+    pub fn on_verified_size(&mut self, now: Instant, size: usize) -> RtpIngest {
+        if size > self.policy.max_size {
+            self.on_verified_bad(now, BadRtp::Oversized(size))
+        } else {
+            RtpIngest::Accept
+        }
+    }
+
+    fn on_verified_bad(&mut self, now: Instant, err: BadRtp) -> RtpIngest {
+        self.bad_packets += 1;
+        Count::<BadRtpPacket>::event();
+        self.warn(now, "verified", err);
+
+        let window = match self.window {
+            Some((started, count)) if now < started + ABUSIVE_WINDOW => (started, count + 1),
+            _ => (now, 1),
+        };
+        self.window = Some(window);
+        match self.policy.abusive_per_sec {
+            Some(limit) if window.1 > limit => RtpIngest::Abusive,
+            _ => RtpIngest::Drop,
+        }
+    }
+
+    fn warn(&mut self, now: Instant, kind: &str, err: BadRtp) {
+        if self.last_warn.is_some_and(|last| now < last + WARN_INTERVAL) {
+            self.suppressed += 1;
+        } else {
+            log::warn!(
+                "[RtpIngest] drop bad {kind} rtp {err:?}, {} suppressed since last warning, total {} verified {} unverified",
+                self.suppressed,
+                self.bad_packets,
+                self.unverified_drops
+            );
+            self.last_warn = Some(now);
+            self.suppressed = 0;
+        }
+    }
+
+    /// Number of dropped packets of the session which are verified to come from its peer
+    pub fn bad_packets(&self) -> u64 {
+        self.bad_packets
+    }
+
+    /// Number of dropped packets of the session which are not verified
+    pub fn unverified_drops(&self) -> u64 {
+        self.unverified_drops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::get_all_counts;
+
+    use super::{check_rtp, is_rtp, BadRtp, RtpIngest, RtpIngestGuard, RtpIngestPolicy, UnverifiedRtpDropped};
+
+    fn rtp(payload: usize) -> Vec<u8> {
+        let mut buf = vec![0x80, 111, 0, 1, 0, 0, 0, 100, 0, 0, 0, 1];
+        buf.resize(buf.len() + payload, 0);
+        buf
+    }
+
+    #[test]
+    fn check_header() {
+        assert!(is_rtp(&rtp(100)));
+        // rtcp sender report
+        assert!(!is_rtp(&[0x80, 200, 0, 6]));
+        assert_eq!(check_rtp(&rtp(100), 1500), Ok(()));
+        assert_eq!(check_rtp(&rtp(1500), 1500), Err(BadRtp::Oversized(1512)));
+        assert_eq!(check_rtp(&rtp(0)[..8], 1500), Err(BadRtp::Truncated(8)));
+
+        let mut version = rtp(10);
+        version[0] = 0x40;
+        assert_eq!(check_rtp(&version, 1500), Err(BadRtp::Version(1)));
+
+        // 15 csrc need 60 bytes
+        let mut csrc = rtp(10);
+        csrc[0] = 0x8F;
+        assert_eq!(check_rtp(&csrc, 1500), Err(BadRtp::HeaderOverflow));
+
+        // extension with 2 words
+        let mut ext = rtp(0);
+        ext[0] = 0x90;
+        ext.extend_from_slice(&[0xBE, 0xDE, 0, 2, 1, 2, 3, 4]);
+        assert_eq!(check_rtp(&ext, 1500), Err(BadRtp::HeaderOverflow));
+        ext.extend_from_slice(&[5, 6, 7, 8]);
+        assert_eq!(check_rtp(&ext, 1500), Ok(()));
+    }
+
+    #[test]
+    fn drop_and_count_bad_packets() {
+        let t0 = Instant::now();
+        let mut guard = RtpIngestGuard::new(RtpIngestPolicy {
+            max_size: 1500,
+            abusive_per_sec: Some(2),
+        });
+        assert_eq!(guard.on_verified_rtp(t0, &rtp(100)), RtpIngest::Accept);
+        assert_eq!(guard.on_verified_rtp(t0, &[0x80, 111, 0]), RtpIngest::Drop);
+        assert_eq!(guard.on_verified_rtp(t0, &rtp(100)), RtpIngest::Accept);
+        assert_eq!(guard.on_verified_size(t0, 2000), RtpIngest::Drop);
+        assert_eq!(guard.on_verified_size(t0, 1000), RtpIngest::Accept);
+        assert_eq!(guard.bad_packets(), 2);
+
+        // over 2 bad packets in a second is abusive
+        assert_eq!(guard.on_verified_rtp(t0, &[]), RtpIngest::Abusive);
+        // next window starts again
+        assert_eq!(guard.on_verified_rtp(t0 + Duration::from_secs(1), &[]), RtpIngest::Drop);
+        assert_eq!(guard.on_verified_rtp(t0 + Duration::from_secs(1), &rtp(10)), RtpIngest::Accept);
+        assert_eq!(guard.bad_packets(), 4);
+
+        // without threshold bad packets are only dropped
+        let mut guard = RtpIngestGuard::new(RtpIngestPolicy::default());
+        for _ in 0..100 {
+            assert_eq!(guard.on_verified_rtp(t0, &[]), RtpIngest::Drop);
+        }
+        assert_eq!(guard.bad_packets(), 100);
+    }
+
+    #[test]
+    fn unverified_bad_packets_never_abusive() {
+        let t0 = Instant::now();
+        let mut guard = RtpIngestGuard::new(RtpIngestPolicy {
+            max_size: 1500,
+            abusive_per_sec: Some(2),
+        });
+        let before = get_all_counts().get(std::any::type_name::<UnverifiedRtpDropped>()).copied().unwrap_or(0);
+        for _ in 0..10 {
+            assert_eq!(guard.on_unverified_rtp(t0, &[0x80, 111, 0]), RtpIngest::Drop);
+        }
+        // size is checked after authentication, the header is fine
+        assert_eq!(guard.on_unverified_rtp(t0, &rtp(2000)), RtpIngest::Accept);
+        assert_eq!(guard.unverified_drops(), 10);
+        assert_eq!(guard.bad_packets(), 0);
+        let after = get_all_counts().get(std::any::type_name::<UnverifiedRtpDropped>()).copied().unwrap_or(0);
+        assert!(after >= before + 10);
+    }
+}
@@ -1,5 +1,27 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
 pub fn now_ms() -> u64 {
     let start = SystemTime::now();
     start.duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis() as u64
 }
+
+/// Milliseconds between two monotonic instants, for measuring durations such as route latency.
+/// Wall clock can step backward with NTP correction so it is only used for absolute timestamps; 0 if `now` is before `started`
+pub fn elapsed_ms(started: Instant, now: Instant) -> u64 {
+    now.saturating_duration_since(started).as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::elapsed_ms;
+
+    #[test]
+    fn elapsed_ms_never_underflow() {
+        let started = Instant::now();
+        assert_eq!(elapsed_ms(started, started + Duration::from_millis(120)), 120);
+        // instants which are taken in reverse order, as wall clock after a backward step
+        assert_eq!(elapsed_ms(started + Duration::from_millis(500), started), 0);
+    }
+}
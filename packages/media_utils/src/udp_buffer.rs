@@ -0,0 +1,115 @@
+//!
+//! Helper for tuning UDP socket buffer sizes (SO_RCVBUF / SO_SNDBUF) on high-density nodes.
+//!
+//! The OS may clamp the requested size, on Linux the max value is limited by sysctl:
+//!
+//! ```text
+//! sysctl -w net.core.rmem_max=26214400
+//! sysctl -w net.core.wmem_max=26214400
+//! ```
+//!
+//! Linux also doubles the value for bookkeeping overhead, so the granted size can be bigger than requested.
+//!
+//! Sizes are set with [`bind_udp_with_buffer`] before the socket is bound, so they apply from the first packet.
+//!
+
+use std::net::{SocketAddr, UdpSocket};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpBufferConfig {
+    pub recv: Option<usize>,
+    pub send: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpBufferGranted {
+    pub recv: usize,
+    pub send: usize,
+}
+
+/// Apply buffer sizes to socket then return actually-granted sizes, a warning is logged if the OS clamped the value.
+pub fn apply_udp_buffer(socket: &UdpSocket, cfg: UdpBufferConfig) -> std::io::Result<UdpBufferGranted> {
+    apply_sock_buffer(SockRef::from(socket), socket.local_addr()?, cfg)
+}
+
+/// Create a non-blocking UDP socket with buffer sizes applied then bind it to `addr`.
+pub fn bind_udp_with_buffer(addr: SocketAddr, cfg: UdpBufferConfig) -> std::io::Result<(UdpSocket, UdpBufferGranted)> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    let granted = apply_sock_buffer(SockRef::from(&socket), addr, cfg)?;
+    socket.bind(&addr.into())?;
+    Ok((socket.into(), granted))
+}
+
+fn apply_sock_buffer(sock: SockRef<'_>, local: SocketAddr, cfg: UdpBufferConfig) -> std::io::Result<UdpBufferGranted> {
+    if let Some(size) = cfg.recv {
+        sock.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = cfg.send {
+        sock.set_send_buffer_size(size)?;
+    }
+    let granted = UdpBufferGranted {
+        recv: sock.recv_buffer_size()?,
+        send: sock.send_buffer_size()?,
+    };
+    for (name, requested, granted) in [("recv", cfg.recv, granted.recv), ("send", cfg.send, granted.send)] {
+        match requested {
+            Some(requested) if granted < requested => {
+                log::warn!("[UdpBuffer] socket {local} {name} buffer requested {requested} but OS clamped to {granted}, please check sysctl net.core.rmem_max/wmem_max");
+            }
+            Some(requested) => log::info!("[UdpBuffer] socket {local} {name} buffer requested {requested}, granted {granted}"),
+            None => log::debug!("[UdpBuffer] socket {local} {name} buffer default {granted}"),
+        }
+    }
+    Ok(granted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use socket2::SockRef;
+
+    use super::{apply_udp_buffer, bind_udp_with_buffer, UdpBufferConfig};
+
+    #[test]
+    fn apply_or_clamp_buffer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("Should bind");
+        let requested = 64 * 1024;
+        let granted = apply_udp_buffer(
+            &socket,
+            UdpBufferConfig {
+                recv: Some(requested),
+                send: Some(requested),
+            },
+        )
+        .expect("Should apply");
+        // Some OS clamp the value, but it should never be zero
+        assert!(granted.recv > 0 && granted.send > 0);
+        #[cfg(target_os = "linux")]
+        {
+            // 64KB is under default rmem_max, Linux doubles it
+            assert!(granted.recv >= requested, "granted {}", granted.recv);
+            assert!(granted.send >= requested, "granted {}", granted.send);
+        }
+    }
+
+    #[test]
+    fn bind_with_buffer() {
+        let requested = 96 * 1024;
+        let cfg = UdpBufferConfig {
+            recv: Some(requested),
+            send: Some(requested),
+        };
+        let (socket, granted) = bind_udp_with_buffer("127.0.0.1:0".parse().expect("Should parse"), cfg).expect("Should bind");
+        assert_ne!(socket.local_addr().expect("Should have addr").port(), 0);
+        // the size is set on the bound socket itself
+        let sock = SockRef::from(&socket);
+        assert_eq!(sock.recv_buffer_size().expect("Should get"), granted.recv);
+        assert_eq!(sock.send_buffer_size().expect("Should get"), granted.send);
+        #[cfg(target_os = "linux")]
+        assert!(granted.recv >= requested && granted.send >= requested, "granted {granted:?}");
+    }
+}
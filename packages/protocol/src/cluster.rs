@@ -1,3 +1,13 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -43,7 +53,161 @@ pub enum ClusterNodeInfo {
     Connector(ClusterNodeGenericInfo),
 }
 
-/// Generate global cluster session_id
+/// Session ids are kept in i64 range, some database will error with bigger values
+const SESSION_ID_MASK: u64 = 0x7FFF_FFFF_FFFF_FFFF;
+
+static SESSION_ID_GENERATOR: OnceLock<Box<dyn SessionIdGenerator>> = OnceLock::new();
+
+/// Scheme of cluster session ids.
+///
+/// Ids only need to be unique inside the cluster: requests are routed by the node and server conn of `ClusterConnId`,
+/// the session id is carried as an opaque value, so any scheme works with routing. Generated ids are masked to i64 range.
+pub trait SessionIdGenerator: Send + Sync {
+    fn generate(&self) -> u64;
+}
+
+/// Default scheme, 63 random bits. Collisions are only probabilistically avoided
+pub struct RandomSessionId;
+
+impl SessionIdGenerator for RandomSessionId {
+    fn generate(&self) -> u64 {
+        rand::random::<u64>()
+    }
+}
+
+/// Sortable scheme: 41 bits milliseconds since 2024-01-01, 12 bits node and 10 bits sequence.
+/// Ids are unique while nodes which generate ids have different low 12 bits of node_id, when more than 1024 ids are
+/// generated in a millisecond the sequence borrows the next millisecond so ids are still increasing.
+pub struct NodeTimeSessionId {
+    node: u64,
+    last: AtomicU64,
+}
+
+impl NodeTimeSessionId {
+    const EPOCH_MS: u64 = 1_704_067_200_000;
+    const NODE_BITS: u32 = 12;
+    const SEQ_BITS: u32 = 10;
+
+    pub fn new(node_id: u32) -> Self {
+        Self {
+            node: node_id as u64 & ((1 << Self::NODE_BITS) - 1),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Node part of an id which is generated by this scheme
+    pub fn node_of(session_id: u64) -> u32 {
+        ((session_id >> Self::SEQ_BITS) & ((1 << Self::NODE_BITS) - 1)) as u32
+    }
+}
+
+impl SessionIdGenerator for NodeTimeSessionId {
+    fn generate(&self) -> u64 {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let now = now_ms.saturating_sub(Self::EPOCH_MS) << Self::SEQ_BITS;
+        // (timestamp << SEQ_BITS | seq) is increased by one if the clock didn't move forward
+        let mut last = self.last.load(Ordering::Relaxed);
+        let next = loop {
+            let next = now.max(last + 1);
+            match self.last.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break next,
+                Err(current) => last = current,
+            }
+        };
+        let ts = next >> Self::SEQ_BITS;
+        let seq = next & ((1 << Self::SEQ_BITS) - 1);
+        (ts << (Self::NODE_BITS + Self::SEQ_BITS)) | (self.node << Self::SEQ_BITS) | seq
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SessionIdScheme {
+    #[default]
+    Random,
+    NodeTime,
+}
+
+impl SessionIdScheme {
+    pub fn generator(&self, node_id: u32) -> Box<dyn SessionIdGenerator> {
+        match self {
+            Self::Random => Box::new(RandomSessionId),
+            Self::NodeTime => Box::new(NodeTimeSessionId::new(node_id)),
+        }
+    }
+}
+
+impl FromStr for SessionIdScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "random" => Ok(Self::Random),
+            "node-time" => Ok(Self::NodeTime),
+            _ => Err(format!("unsupported session id scheme {s}")),
+        }
+    }
+}
+
+impl Display for SessionIdScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Random => f.write_str("random"),
+            Self::NodeTime => f.write_str("node-time"),
+        }
+    }
+}
+
+/// Set the session id generator of this process, only the first call takes effect. Return false if it is already set
+pub fn set_session_id_generator(generator: Box<dyn SessionIdGenerator>) -> bool {
+    SESSION_ID_GENERATOR.set(generator).is_ok()
+}
+
+/// Generate global cluster session_id with the configured generator, random if not set
 pub fn gen_cluster_session_id() -> u64 {
-    rand::random::<u64>() & 0x7FFF_FFFF_FFFF_FFFF //avoid over i64, which some database will error
+    match SESSION_ID_GENERATOR.get() {
+        Some(generator) => generator.generate() & SESSION_ID_MASK,
+        None => RandomSessionId.generate() & SESSION_ID_MASK,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{
+        endpoint::{ClusterConnId, ServerConnId},
+        transport::ConnLayer,
+    };
+
+    use super::{NodeTimeSessionId, SessionIdGenerator, SESSION_ID_MASK};
+
+    #[test]
+    fn node_time_session_id_unique() {
+        let generator = NodeTimeSessionId::new(0x1234_0105);
+        let mut ids = HashSet::new();
+        let mut last = 0;
+        // more than one millisecond of sequence, so the timestamp is borrowed
+        for _ in 0..10_000 {
+            let id = generator.generate();
+            assert!(id > last, "ids should be increasing");
+            assert_eq!(id & SESSION_ID_MASK, id);
+            assert_eq!(NodeTimeSessionId::node_of(id), 0x105);
+            assert!(ids.insert(id));
+            last = id;
+        }
+
+        // other node never generates the same ids
+        let other = NodeTimeSessionId::new(0x1234_0106);
+        for _ in 0..10_000 {
+            let id = other.generate();
+            assert_eq!(NodeTimeSessionId::node_of(id), 0x106);
+            assert!(ids.insert(id));
+        }
+
+        // conn id with custom session id is still routed to its node
+        let id = generator.generate();
+        let conn = ServerConnId { worker: 1, index: 2 }.up((0x1234_0105, id));
+        let conn: ClusterConnId = conn.to_string().parse().expect("Should parse conn id");
+        assert_eq!(conn.get_down_part(), (0x1234_0105, id));
+    }
 }
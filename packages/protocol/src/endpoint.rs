@@ -9,23 +9,41 @@ use std::{
     str::FromStr,
 };
 
-use crate::{protobuf, transport::ConnLayer};
+use crate::{media::MediaKind, protobuf, transport::ConnLayer};
 
 mod audio_mixer;
+mod room_state;
 mod track;
 
 pub use audio_mixer::*;
+pub use room_state::*;
 pub use track::*;
 
 ///
 /// ClusterConnId is used for re-router request from gateway to correct node
 /// This is a pair of node info and node inner worker and index
 ///
+/// A migrated session is given a conn id which points to the destination node with `migrate_session` set,
+/// restart-ice with it creates new endpoint in the destination node with same session id.
+///
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct ClusterConnId {
     pub node: u32,
     pub node_session: u64,
     pub server_conn: ServerConnId,
+    pub migrate_session: Option<u64>,
+}
+
+impl ClusterConnId {
+    /// Conn id which is given to client for moving session `session_id` to node `dest`
+    pub fn migrate(dest: u32, session_id: u64) -> Self {
+        Self {
+            node: dest,
+            node_session: 0,
+            server_conn: ServerConnId { worker: 0, index: 0 },
+            migrate_session: Some(session_id),
+        }
+    }
 }
 
 impl FromStr for ClusterConnId {
@@ -35,13 +53,26 @@ impl FromStr for ClusterConnId {
         let node = parts.first().ok_or("MISSING NODE_ID")?.parse::<u32>().map_err(|_| "PARSE ERROR NODE_ID")?;
         let node_session = parts.get(1).ok_or("MISSING NODE_SESSION")?.parse::<u64>().map_err(|_| "PARSE ERROR NODE_SESSION")?;
         let server_conn = parts.get(2).ok_or("MISSING SERVER_CONN")?.parse::<ServerConnId>().map_err(|_| "PARSE ERROR SERVER_CONN")?;
-        Ok(Self { node, node_session, server_conn })
+        let migrate_session = match parts.get(3) {
+            Some(session) => Some(session.parse::<u64>().map_err(|_| "PARSE ERROR MIGRATE_SESSION")?),
+            None => None,
+        };
+        Ok(Self {
+            node,
+            node_session,
+            server_conn,
+            migrate_session,
+        })
     }
 }
 
 impl Display for ClusterConnId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}-{}-{}", self.node, self.node_session, self.server_conn)
+        write!(f, "{}-{}-{}", self.node, self.node_session, self.server_conn)?;
+        if let Some(session) = self.migrate_session {
+            write!(f, "-{session}")?;
+        }
+        Ok(())
     }
 }
 
@@ -105,6 +136,7 @@ impl ConnLayer for ServerConnId {
             node: param.0,
             node_session: param.1,
             server_conn: self,
+            migrate_session: None,
         }
     }
 
@@ -176,6 +208,39 @@ impl From<protobuf::shared::RoomInfoSubscribe> for RoomInfoSubscribe {
     }
 }
 
+///
+/// Which kinds of track from a remote peer are announced to the endpoint, default is both.
+/// This is useful for a subscriber on a poor connection which only want audio of a peer
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackKindFilter {
+    pub audio: bool,
+    pub video: bool,
+}
+
+impl Default for TrackKindFilter {
+    fn default() -> Self {
+        Self { audio: true, video: true }
+    }
+}
+
+impl TrackKindFilter {
+    pub fn audio_only() -> Self {
+        Self { audio: true, video: false }
+    }
+
+    pub fn video_only() -> Self {
+        Self { audio: false, video: true }
+    }
+
+    pub fn allow(&self, kind: MediaKind) -> bool {
+        match kind {
+            MediaKind::Audio => self.audio,
+            MediaKind::Video => self.video,
+        }
+    }
+}
+
 ///
 /// RoomId type, we should use this type instead of direct String
 /// This is useful when we can validate
@@ -273,6 +338,28 @@ impl From<protobuf::shared::BitrateControlMode> for BitrateControlMode {
     }
 }
 
+///
+/// BitratePriority is used for allocating bandwidth when subscriber network is not enough for all subscribed tracks.
+/// Tracks are served in order Audio > Screen > Camera, so camera is the first one to be dropped.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub enum BitratePriority {
+    Audio,
+    Screen,
+    #[default]
+    Camera,
+}
+
+impl From<protobuf::shared::receiver::BitratePriority> for BitratePriority {
+    fn from(value: protobuf::shared::receiver::BitratePriority) -> Self {
+        match value {
+            protobuf::shared::receiver::BitratePriority::Audio => Self::Audio,
+            protobuf::shared::receiver::BitratePriority::Screen => Self::Screen,
+            protobuf::shared::receiver::BitratePriority::Camera => Self::Camera,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
@@ -292,6 +379,7 @@ mod test {
             node: 1,
             node_session: 2,
             server_conn: ServerConnId { worker: 3, index: 4 },
+            migrate_session: None,
         };
         assert_eq!(conn.to_string(), "1-2-3,4");
         assert_eq!(
@@ -300,7 +388,16 @@ mod test {
                 node: 1,
                 node_session: 2,
                 server_conn: ServerConnId { worker: 3, index: 4 },
+                migrate_session: None,
             })
         );
     }
+
+    #[test]
+    fn migrate_conn_id_parse() {
+        let conn = ClusterConnId::migrate(5, 1000);
+        assert_eq!(conn.to_string(), "5-0-0,0-1000");
+        assert_eq!(ClusterConnId::from_str("5-0-0,0-1000"), Ok(conn));
+        assert_eq!(ClusterConnId::from_str("5-0-0,0-abc"), Err("PARSE ERROR MIGRATE_SESSION"));
+    }
 }
@@ -7,7 +7,7 @@ use crate::{
 
 use super::{PeerHashCode, PeerId, TrackName, TrackSource};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum AudioMixerMode {
     Auto,
     Manual,
@@ -22,6 +22,15 @@ impl From<Mode> for AudioMixerMode {
     }
 }
 
+impl From<AudioMixerMode> for Mode {
+    fn from(value: AudioMixerMode) -> Self {
+        match value {
+            AudioMixerMode::Auto => Mode::Auto,
+            AudioMixerMode::Manual => Mode::Manual,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct AudioMixerConfig {
     pub mode: AudioMixerMode,
@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+
+use super::{AudioMixerMode, PeerId};
+
+///
+/// Room-wide value with the time it is changed in unix ms, when entries of nodes are merged the newest value wins
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub version: u64,
+    pub value: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(version: u64, value: T) -> Self {
+        Self { version, value }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomLockInfo {
+    #[default]
+    Unlocked,
+    /// Locked by the peer, only it can admit or reject pending peers
+    Locked(PeerId),
+    /// Owner leaved while the room is locked, the room is unlocked and peers which are pending are rejected
+    OwnerLeft,
+}
+
+///
+/// Mixer config of endpoints in a node, with the time it is set in unix ms
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomMixerInfo {
+    pub mode: AudioMixerMode,
+    pub outputs: usize,
+    pub since: u64,
+}
+
+///
+/// RoomStateInfo is the entry of a node in the room state map, each node only writes its own entry.
+/// Room-wide fields are copied from the newest entry, so they are kept after the node which changed them leaves the room.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomStateInfo {
+    pub paused: Versioned<bool>,
+    pub lock: Versioned<RoomLockInfo>,
+    pub max_bitrate: Versioned<Option<u64>>,
+    pub max_spatial: Versioned<Option<u8>>,
+    /// Close time of the room in unix ms, the earliest deadline of all nodes is used
+    pub deadline: Option<u64>,
+    /// Mixer config of local mixer endpoints, None when the node doesn't have any
+    pub mixer: Option<RoomMixerInfo>,
+    /// Video codec which all peers of the room are answered with, the one which is pinned first is used
+    pub video_codec: Option<Versioned<String>>,
+    /// Local peers which are waiting for admit
+    pub pending: Vec<PeerId>,
+    /// Admit (true) or reject (false) decisions of the lock owner for peers which are pending in other nodes
+    pub decisions: Vec<(PeerId, bool)>,
+}
+
+impl RoomStateInfo {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("should ok")
+    }
+
+    pub fn deserialize(data: &[u8]) -> Option<RoomStateInfo> {
+        bincode::deserialize::<Self>(data).ok()
+    }
+}
@@ -35,12 +35,25 @@ impl TrackPriority {
     }
 }
 
+/// A simulcast encoding which is published by the track, restrictions are None if the publisher don't signal them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackEncoding {
+    pub rid: String,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_fps: Option<u32>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TrackMeta {
     pub kind: MediaKind,
     pub scaling: MediaScaling,
     pub control: BitrateControlMode,
     pub metadata: Option<String>,
+    /// App-level mute state advertised by publisher, media can still flow while muted
+    pub muted: bool,
+    /// Simulcast encodings in publisher order, empty if track is not simulcast or encodings are unknown
+    pub encodings: Vec<TrackEncoding>,
 }
 
 impl TrackMeta {
@@ -50,6 +63,19 @@ impl TrackMeta {
             scaling: MediaScaling::None,
             control: BitrateControlMode::MaxBitrate,
             metadata: None,
+            muted: false,
+            encodings: vec![],
+        }
+    }
+
+    pub fn default_video() -> Self {
+        Self {
+            kind: MediaKind::Video,
+            scaling: MediaScaling::None,
+            control: BitrateControlMode::MaxBitrate,
+            metadata: None,
+            muted: false,
+            encodings: vec![],
         }
     }
 }
@@ -57,7 +83,7 @@ impl TrackMeta {
 ///
 /// TrackInfo will be used for broadcast to cluster
 ///
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TrackInfo {
     pub peer: PeerId,
     pub track: TrackName,
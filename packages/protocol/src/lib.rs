@@ -8,5 +8,6 @@ pub mod multi_tenancy;
 pub mod protobuf;
 pub mod record;
 pub mod rpc;
+pub mod session_tags;
 pub mod tokens;
 pub mod transport;
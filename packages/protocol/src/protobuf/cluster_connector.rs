@@ -81,9 +81,15 @@ pub struct PeerEvent {
     pub app: ::prost::alloc::string::String,
     #[prost(uint64, tag = "1")]
     pub session_id: u64,
+    /// custom tags from connect request, only set on route_begin and session end events
+    #[prost(map = "string, string", tag = "20")]
+    pub tags: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
     #[prost(
         oneof = "peer_event::Event",
-        tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18"
+        tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 21"
     )]
     pub event: ::core::option::Option<peer_event::Event>,
 }
@@ -133,6 +139,7 @@ pub mod peer_event {
             Timeout = 1,
             GatewayError = 2,
             MediaError = 3,
+            Cancelled = 4,
         }
         impl ErrorType {
             /// String value of the enum field names used in the ProtoBuf definition.
@@ -145,6 +152,7 @@ pub mod peer_event {
                     Self::Timeout => "Timeout",
                     Self::GatewayError => "GatewayError",
                     Self::MediaError => "MediaError",
+                    Self::Cancelled => "Cancelled",
                 }
             }
             /// Creates an enum from field names used in the ProtoBuf definition.
@@ -154,6 +162,7 @@ pub mod peer_event {
                     "Timeout" => Some(Self::Timeout),
                     "GatewayError" => Some(Self::GatewayError),
                     "MediaError" => Some(Self::MediaError),
+                    "Cancelled" => Some(Self::Cancelled),
                     _ => None,
                 }
             }
@@ -287,6 +296,8 @@ pub mod peer_event {
             Timeout = 1,
             NodeShutdown = 2,
             KickByApi = 3,
+            MaxDurationReached = 4,
+            Reaped = 5,
         }
         impl Reason {
             /// String value of the enum field names used in the ProtoBuf definition.
@@ -299,6 +310,8 @@ pub mod peer_event {
                     Self::Timeout => "Timeout",
                     Self::NodeShutdown => "NodeShutdown",
                     Self::KickByApi => "KickByAPI",
+                    Self::MaxDurationReached => "MaxDurationReached",
+                    Self::Reaped => "Reaped",
                 }
             }
             /// Creates an enum from field names used in the ProtoBuf definition.
@@ -308,6 +321,8 @@ pub mod peer_event {
                     "Timeout" => Some(Self::Timeout),
                     "NodeShutdown" => Some(Self::NodeShutdown),
                     "KickByAPI" => Some(Self::KickByApi),
+                    "MaxDurationReached" => Some(Self::MaxDurationReached),
+                    "Reaped" => Some(Self::Reaped),
                     _ => None,
                 }
             }
@@ -358,6 +373,19 @@ pub mod peer_event {
         pub remote_track: ::prost::alloc::string::String,
     }
     #[derive(serde::Serialize)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Negotiated {
+        #[prost(string, optional, tag = "1")]
+        pub audio_codec: ::core::option::Option<::prost::alloc::string::String>,
+        #[prost(string, optional, tag = "2")]
+        pub video_codec: ::core::option::Option<::prost::alloc::string::String>,
+        /// in format WIDTHxHEIGHT, only known when client signals simulcast restrictions
+        #[prost(string, optional, tag = "3")]
+        pub max_resolution: ::core::option::Option<::prost::alloc::string::String>,
+        #[prost(string, repeated, tag = "4")]
+        pub layers: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    }
+    #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Event {
         #[prost(message, tag = "2")]
@@ -394,6 +422,8 @@ pub mod peer_event {
         LocalTrackAttach(LocalTrackAttach),
         #[prost(message, tag = "18")]
         LocalTrackDetach(LocalTrackDetach),
+        #[prost(message, tag = "21")]
+        Negotiated(Negotiated),
     }
 }
 #[derive(serde::Serialize)]
@@ -98,6 +98,13 @@ pub struct WhipConnectRequest {
     pub extra_data: ::core::option::Option<::prost::alloc::string::String>,
     #[prost(message, optional, tag = "9")]
     pub app: ::core::option::Option<super::shared::AppContext>,
+    #[prost(uint64, optional, tag = "10")]
+    pub deadline_ms: ::core::option::Option<u64>,
+    #[prost(map = "string, string", tag = "11")]
+    pub tags: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
 }
 #[derive(serde::Serialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -153,6 +160,11 @@ pub struct WhepConnectRequest {
     pub extra_data: ::core::option::Option<::prost::alloc::string::String>,
     #[prost(message, optional, tag = "9")]
     pub app: ::core::option::Option<super::shared::AppContext>,
+    #[prost(map = "string, string", tag = "10")]
+    pub tags: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
 }
 #[derive(serde::Serialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -188,6 +200,55 @@ pub struct WhepCloseResponse {
     #[prost(string, tag = "1")]
     pub conn: ::prost::alloc::string::String,
 }
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WhepEvent {
+    #[prost(oneof = "whep_event::Event", tags = "1, 2, 3, 4")]
+    pub event: ::core::option::Option<whep_event::Event>,
+}
+/// Nested message and enum types in `WhepEvent`.
+pub mod whep_event {
+    #[derive(serde::Serialize)]
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct LayerChanged {
+        #[prost(uint32, tag = "1")]
+        pub spatial: u32,
+        #[prost(uint32, tag = "2")]
+        pub temporal: u32,
+    }
+    #[derive(serde::Serialize)]
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct Stats {
+        #[prost(uint64, tag = "1")]
+        pub egress_bitrate: u64,
+    }
+    #[derive(serde::Serialize)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Event {
+        #[prost(string, tag = "1")]
+        Candidate(::prost::alloc::string::String),
+        #[prost(bool, tag = "2")]
+        EndOfCandidates(bool),
+        #[prost(message, tag = "3")]
+        LayerChanged(LayerChanged),
+        #[prost(message, tag = "4")]
+        Stats(Stats),
+    }
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WhepEventsRequest {
+    #[prost(string, tag = "1")]
+    pub conn: ::prost::alloc::string::String,
+    #[prost(bool, tag = "2")]
+    pub initial: bool,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WhepEventsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub events: ::prost::alloc::vec::Vec<WhepEvent>,
+}
 /// For SDK
 #[derive(serde::Serialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -250,6 +311,69 @@ pub struct WebrtcRestartIceRequest {
 pub struct WebrtcRestartIceResponse {
     #[prost(message, optional, tag = "1")]
     pub res: ::core::option::Option<super::gateway::ConnectResponse>,
+    /// Node which the session is migrated from, gateway closes the old session there
+    #[prost(uint32, optional, tag = "2")]
+    pub migrated_from: ::core::option::Option<u32>,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebrtcMigrateRequest {
+    #[prost(string, tag = "1")]
+    pub conn: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub dest_node: u32,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebrtcMigrateResponse {
+    #[prost(string, tag = "1")]
+    pub conn: ::prost::alloc::string::String,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebrtcDumpRequest {
+    #[prost(string, tag = "1")]
+    pub conn: ::prost::alloc::string::String,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebrtcDumpResponse {
+    #[prost(string, tag = "1")]
+    pub offer: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub answer: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub ice_state: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "4")]
+    pub local_candidates: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag = "5")]
+    pub remote_candidates: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, optional, tag = "6")]
+    pub selected_pair: ::core::option::Option<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag = "7")]
+    pub codecs: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, tag = "8")]
+    pub transport_state: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "9")]
+    pub bad_rtp_packets: u64,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebrtcValidateRequest {
+    #[prost(message, optional, tag = "1")]
+    pub app: ::core::option::Option<super::shared::AppContext>,
+    #[prost(string, tag = "2")]
+    pub ip: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub req: ::core::option::Option<super::gateway::ConnectRequest>,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebrtcValidateResponse {
+    #[prost(message, optional, tag = "1")]
+    pub res: ::core::option::Option<super::gateway::ValidateOfferResponse>,
+    #[prost(message, optional, tag = "2")]
+    pub error: ::core::option::Option<super::shared::Error>,
 }
 /// For RtpEngine
 #[derive(serde::Serialize)]
@@ -318,6 +442,34 @@ pub struct RtpEngineCreateAnswerResponse {
 }
 #[derive(serde::Serialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RtpEngineCreateEgressRequest {
+    #[prost(uint64, tag = "1")]
+    pub session_id: u64,
+    #[prost(string, tag = "2")]
+    pub room: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub peer: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub source_peer: ::prost::alloc::string::String,
+    #[prost(string, tag = "5")]
+    pub source_track: ::prost::alloc::string::String,
+    #[prost(enumeration = "super::shared::Kind", tag = "6")]
+    pub kind: i32,
+    #[prost(string, tag = "7")]
+    pub dest: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "8")]
+    pub app: ::core::option::Option<super::shared::AppContext>,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RtpEngineCreateEgressResponse {
+    #[prost(string, tag = "1")]
+    pub conn: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub sdp: ::prost::alloc::string::String,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RtpEngineDeleteRequest {
     #[prost(string, tag = "1")]
     pub conn: ::prost::alloc::string::String,
@@ -328,6 +480,38 @@ pub struct RtpEngineDeleteResponse {
     #[prost(string, tag = "1")]
     pub conn: ::prost::alloc::string::String,
 }
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CloseSessionsRequest {
+    #[prost(message, optional, tag = "1")]
+    pub app: ::core::option::Option<super::shared::AppContext>,
+    #[prost(string, optional, tag = "2")]
+    pub room: ::core::option::Option<::prost::alloc::string::String>,
+    /// Only close the webrtc session with this id, if it is migrated to other node
+    #[prost(uint64, optional, tag = "3")]
+    pub migrated_session: ::core::option::Option<u64>,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CloseSessionsResponse {
+    #[prost(uint32, tag = "1")]
+    pub closed: u32,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RoomTracksRequest {
+    #[prost(message, optional, tag = "1")]
+    pub app: ::core::option::Option<super::shared::AppContext>,
+    #[prost(string, tag = "2")]
+    pub room: ::prost::alloc::string::String,
+}
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RoomTracksResponse {
+    /// each one is a serialized TrackInfo, same as value in the room tracks map
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub tracks: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+}
 #[allow(async_fn_in_trait)]
 pub trait MediaEdgeServiceHandler<CTX> {
     async fn whip_connect(
@@ -360,6 +544,11 @@ pub trait MediaEdgeServiceHandler<CTX> {
         ctx: &CTX,
         req: WhepCloseRequest,
     ) -> Option<WhepCloseResponse>;
+    async fn whep_events(
+        &self,
+        ctx: &CTX,
+        req: WhepEventsRequest,
+    ) -> Option<WhepEventsResponse>;
     async fn webrtc_connect(
         &self,
         ctx: &CTX,
@@ -375,6 +564,21 @@ pub trait MediaEdgeServiceHandler<CTX> {
         ctx: &CTX,
         req: WebrtcRestartIceRequest,
     ) -> Option<WebrtcRestartIceResponse>;
+    async fn webrtc_migrate(
+        &self,
+        ctx: &CTX,
+        req: WebrtcMigrateRequest,
+    ) -> Option<WebrtcMigrateResponse>;
+    async fn webrtc_dump(
+        &self,
+        ctx: &CTX,
+        req: WebrtcDumpRequest,
+    ) -> Option<WebrtcDumpResponse>;
+    async fn webrtc_validate(
+        &self,
+        ctx: &CTX,
+        req: WebrtcValidateRequest,
+    ) -> Option<WebrtcValidateResponse>;
     async fn rtp_engine_create_offer(
         &self,
         ctx: &CTX,
@@ -390,11 +594,26 @@ pub trait MediaEdgeServiceHandler<CTX> {
         ctx: &CTX,
         req: RtpEngineCreateAnswerRequest,
     ) -> Option<RtpEngineCreateAnswerResponse>;
+    async fn rtp_engine_create_egress(
+        &self,
+        ctx: &CTX,
+        req: RtpEngineCreateEgressRequest,
+    ) -> Option<RtpEngineCreateEgressResponse>;
     async fn rtp_engine_delete(
         &self,
         ctx: &CTX,
         req: RtpEngineDeleteRequest,
     ) -> Option<RtpEngineDeleteResponse>;
+    async fn close_sessions(
+        &self,
+        ctx: &CTX,
+        req: CloseSessionsRequest,
+    ) -> Option<CloseSessionsResponse>;
+    async fn room_tracks(
+        &self,
+        ctx: &CTX,
+        req: RoomTracksRequest,
+    ) -> Option<RoomTracksResponse>;
 }
 pub struct MediaEdgeServiceClient<
     D,
@@ -496,6 +715,18 @@ impl<
         let in_buf = stream.read().await?;
         WhepCloseResponse::decode(in_buf.as_slice()).ok()
     }
+    pub async fn whep_events(
+        &self,
+        dest: D,
+        req: WhepEventsRequest,
+    ) -> Option<WhepEventsResponse> {
+        use prost::Message;
+        let mut stream = self.client.connect(dest, "whep_events.service").await?;
+        let out_buf = req.encode_to_vec();
+        stream.write(&out_buf).await?;
+        let in_buf = stream.read().await?;
+        WhepEventsResponse::decode(in_buf.as_slice()).ok()
+    }
     pub async fn webrtc_connect(
         &self,
         dest: D,
@@ -532,6 +763,42 @@ impl<
         let in_buf = stream.read().await?;
         WebrtcRestartIceResponse::decode(in_buf.as_slice()).ok()
     }
+    pub async fn webrtc_migrate(
+        &self,
+        dest: D,
+        req: WebrtcMigrateRequest,
+    ) -> Option<WebrtcMigrateResponse> {
+        use prost::Message;
+        let mut stream = self.client.connect(dest, "webrtc_migrate.service").await?;
+        let out_buf = req.encode_to_vec();
+        stream.write(&out_buf).await?;
+        let in_buf = stream.read().await?;
+        WebrtcMigrateResponse::decode(in_buf.as_slice()).ok()
+    }
+    pub async fn webrtc_dump(
+        &self,
+        dest: D,
+        req: WebrtcDumpRequest,
+    ) -> Option<WebrtcDumpResponse> {
+        use prost::Message;
+        let mut stream = self.client.connect(dest, "webrtc_dump.service").await?;
+        let out_buf = req.encode_to_vec();
+        stream.write(&out_buf).await?;
+        let in_buf = stream.read().await?;
+        WebrtcDumpResponse::decode(in_buf.as_slice()).ok()
+    }
+    pub async fn webrtc_validate(
+        &self,
+        dest: D,
+        req: WebrtcValidateRequest,
+    ) -> Option<WebrtcValidateResponse> {
+        use prost::Message;
+        let mut stream = self.client.connect(dest, "webrtc_validate.service").await?;
+        let out_buf = req.encode_to_vec();
+        stream.write(&out_buf).await?;
+        let in_buf = stream.read().await?;
+        WebrtcValidateResponse::decode(in_buf.as_slice()).ok()
+    }
     pub async fn rtp_engine_create_offer(
         &self,
         dest: D,
@@ -577,6 +844,21 @@ impl<
         let in_buf = stream.read().await?;
         RtpEngineCreateAnswerResponse::decode(in_buf.as_slice()).ok()
     }
+    pub async fn rtp_engine_create_egress(
+        &self,
+        dest: D,
+        req: RtpEngineCreateEgressRequest,
+    ) -> Option<RtpEngineCreateEgressResponse> {
+        use prost::Message;
+        let mut stream = self
+            .client
+            .connect(dest, "rtp_engine_create_egress.service")
+            .await?;
+        let out_buf = req.encode_to_vec();
+        stream.write(&out_buf).await?;
+        let in_buf = stream.read().await?;
+        RtpEngineCreateEgressResponse::decode(in_buf.as_slice()).ok()
+    }
     pub async fn rtp_engine_delete(
         &self,
         dest: D,
@@ -589,6 +871,30 @@ impl<
         let in_buf = stream.read().await?;
         RtpEngineDeleteResponse::decode(in_buf.as_slice()).ok()
     }
+    pub async fn close_sessions(
+        &self,
+        dest: D,
+        req: CloseSessionsRequest,
+    ) -> Option<CloseSessionsResponse> {
+        use prost::Message;
+        let mut stream = self.client.connect(dest, "close_sessions.service").await?;
+        let out_buf = req.encode_to_vec();
+        stream.write(&out_buf).await?;
+        let in_buf = stream.read().await?;
+        CloseSessionsResponse::decode(in_buf.as_slice()).ok()
+    }
+    pub async fn room_tracks(
+        &self,
+        dest: D,
+        req: RoomTracksRequest,
+    ) -> Option<RoomTracksResponse> {
+        use prost::Message;
+        let mut stream = self.client.connect(dest, "room_tracks.service").await?;
+        let out_buf = req.encode_to_vec();
+        stream.write(&out_buf).await?;
+        let in_buf = stream.read().await?;
+        RoomTracksResponse::decode(in_buf.as_slice()).ok()
+    }
 }
 pub struct MediaEdgeServiceServer<
     CTX,
@@ -721,6 +1027,21 @@ impl<
                         }
                     });
                 }
+                "whep_events.service" => {
+                    tokio::task::spawn_local(async move {
+                        if let Some(in_buf) = stream.read().await {
+                            if let Ok(req) = WhepEventsRequest::decode(
+                                in_buf.as_slice(),
+                            ) {
+                                if let Some(res) = handler.whep_events(&ctx, req).await {
+                                    let out_buf = res.encode_to_vec();
+                                    stream.write(&out_buf).await;
+                                    stream.close().await;
+                                }
+                            }
+                        }
+                    });
+                }
                 "webrtc_connect.service" => {
                     tokio::task::spawn_local(async move {
                         if let Some(in_buf) = stream.read().await {
@@ -772,6 +1093,54 @@ impl<
                         }
                     });
                 }
+                "webrtc_migrate.service" => {
+                    tokio::task::spawn_local(async move {
+                        if let Some(in_buf) = stream.read().await {
+                            if let Ok(req) = WebrtcMigrateRequest::decode(
+                                in_buf.as_slice(),
+                            ) {
+                                if let Some(res) = handler
+                                    .webrtc_migrate(&ctx, req)
+                                    .await
+                                {
+                                    let out_buf = res.encode_to_vec();
+                                    stream.write(&out_buf).await;
+                                    stream.close().await;
+                                }
+                            }
+                        }
+                    });
+                }
+                "webrtc_dump.service" => {
+                    tokio::task::spawn_local(async move {
+                        if let Some(in_buf) = stream.read().await {
+                            if let Ok(req) = WebrtcDumpRequest::decode(
+                                in_buf.as_slice(),
+                            ) {
+                                if let Some(res) = handler.webrtc_dump(&ctx, req).await {
+                                    let out_buf = res.encode_to_vec();
+                                    stream.write(&out_buf).await;
+                                    stream.close().await;
+                                }
+                            }
+                        }
+                    });
+                }
+                "webrtc_validate.service" => {
+                    tokio::task::spawn_local(async move {
+                        if let Some(in_buf) = stream.read().await {
+                            if let Ok(req) = WebrtcValidateRequest::decode(
+                                in_buf.as_slice(),
+                            ) {
+                                if let Some(res) = handler.webrtc_validate(&ctx, req).await {
+                                    let out_buf = res.encode_to_vec();
+                                    stream.write(&out_buf).await;
+                                    stream.close().await;
+                                }
+                            }
+                        }
+                    });
+                }
                 "rtp_engine_create_offer.service" => {
                     tokio::task::spawn_local(async move {
                         if let Some(in_buf) = stream.read().await {
@@ -826,6 +1195,24 @@ impl<
                         }
                     });
                 }
+                "rtp_engine_create_egress.service" => {
+                    tokio::task::spawn_local(async move {
+                        if let Some(in_buf) = stream.read().await {
+                            if let Ok(req) = RtpEngineCreateEgressRequest::decode(
+                                in_buf.as_slice(),
+                            ) {
+                                if let Some(res) = handler
+                                    .rtp_engine_create_egress(&ctx, req)
+                                    .await
+                                {
+                                    let out_buf = res.encode_to_vec();
+                                    stream.write(&out_buf).await;
+                                    stream.close().await;
+                                }
+                            }
+                        }
+                    });
+                }
                 "rtp_engine_delete.service" => {
                     tokio::task::spawn_local(async move {
                         if let Some(in_buf) = stream.read().await {
@@ -844,6 +1231,42 @@ impl<
                         }
                     });
                 }
+                "close_sessions.service" => {
+                    tokio::task::spawn_local(async move {
+                        if let Some(in_buf) = stream.read().await {
+                            if let Ok(req) = CloseSessionsRequest::decode(
+                                in_buf.as_slice(),
+                            ) {
+                                if let Some(res) = handler
+                                    .close_sessions(&ctx, req)
+                                    .await
+                                {
+                                    let out_buf = res.encode_to_vec();
+                                    stream.write(&out_buf).await;
+                                    stream.close().await;
+                                }
+                            }
+                        }
+                    });
+                }
+                "room_tracks.service" => {
+                    tokio::task::spawn_local(async move {
+                        if let Some(in_buf) = stream.read().await {
+                            if let Ok(req) = RoomTracksRequest::decode(
+                                in_buf.as_slice(),
+                            ) {
+                                if let Some(res) = handler
+                                    .room_tracks(&ctx, req)
+                                    .await
+                                {
+                                    let out_buf = res.encode_to_vec();
+                                    stream.write(&out_buf).await;
+                                    stream.close().await;
+                                }
+                            }
+                        }
+                    });
+                }
                 _ => {}
             }
         }
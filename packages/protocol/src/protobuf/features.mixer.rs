@@ -68,7 +68,7 @@ pub mod response {
 #[derive(serde::Serialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ServerEvent {
-    #[prost(oneof = "server_event::Event", tags = "1, 2")]
+    #[prost(oneof = "server_event::Event", tags = "1, 2, 3")]
     pub event: ::core::option::Option<server_event::Event>,
 }
 /// Nested message and enum types in `ServerEvent`.
@@ -89,6 +89,15 @@ pub mod server_event {
         #[prost(uint32, tag = "1")]
         pub slot: u32,
     }
+    /// Mixer of the join conflicts with the room mixer, the session is joined without mixer
+    #[derive(serde::Serialize)]
+    #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+    pub struct ConfigConflict {
+        #[prost(enumeration = "super::Mode", tag = "1")]
+        pub mode: i32,
+        #[prost(uint32, tag = "2")]
+        pub outputs: u32,
+    }
     #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Event {
@@ -96,6 +105,8 @@ pub mod server_event {
         SlotSet(SlotSet),
         #[prost(message, tag = "2")]
         SlotUnset(SlotUnset),
+        #[prost(message, tag = "3")]
+        ConfigConflict(ConfigConflict),
     }
 }
 #[derive(serde::Serialize)]
@@ -10,6 +10,20 @@ pub struct ConnectRequest {
     pub tracks: ::core::option::Option<super::shared::Tracks>,
     #[prost(string, tag = "5")]
     pub sdp: ::prost::alloc::string::String,
+    #[prost(map = "string, string", tag = "6")]
+    pub tags: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
+    /// Debug hint for ICE pair selection: `relay` or `interface=<local ip>`
+    #[prost(string, optional, tag = "7")]
+    pub ice_hint: ::core::option::Option<::prost::alloc::string::String>,
+    /// Client can answer renegotiation offers from server, which add receivers for new room tracks
+    #[prost(bool, tag = "8")]
+    pub renegotiation: bool,
+    /// Ticket from GoAway of a migrated session, restart-ice to the new node must carry it
+    #[prost(string, optional, tag = "9")]
+    pub migrate_token: ::core::option::Option<::prost::alloc::string::String>,
 }
 #[derive(serde::Serialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -21,6 +35,19 @@ pub struct ConnectResponse {
     #[prost(bool, tag = "3")]
     pub ice_lite: bool,
 }
+/// Report of a dry-run connect, the answer which would be sent without creating a session
+#[derive(serde::Serialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValidateOfferResponse {
+    #[prost(string, tag = "1")]
+    pub sdp: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "2")]
+    pub codecs: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag = "3")]
+    pub rejected_mids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag = "4")]
+    pub warnings: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
 #[derive(serde::Serialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RemoteIceRequest {
@@ -28,7 +28,7 @@ pub mod request {
     #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
     pub struct Session {
-        #[prost(oneof = "session::Request", tags = "1, 2, 3, 4")]
+        #[prost(oneof = "session::Request", tags = "1, 2, 3, 4, 5")]
         pub request: ::core::option::Option<session::Request>,
     }
     /// Nested message and enum types in `Session`.
@@ -55,6 +55,15 @@ pub mod request {
         #[derive(serde::Serialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct Disconnect {}
+        /// Answer of a server renegotiation offer
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct RenegotiateAnswer {
+            #[prost(uint32, tag = "1")]
+            pub id: u32,
+            #[prost(string, tag = "2")]
+            pub sdp: ::prost::alloc::string::String,
+        }
         #[derive(serde::Serialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Request {
@@ -66,12 +75,17 @@ pub mod request {
             Sdp(UpdateSdp),
             #[prost(message, tag = "4")]
             Disconnect(Disconnect),
+            #[prost(message, tag = "5")]
+            Renegotiate(RenegotiateAnswer),
         }
     }
     #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
     pub struct Room {
-        #[prost(oneof = "room::Request", tags = "1, 2")]
+        /// Token with room admin permission of the joined room, required by pause, lock, admit and config requests
+        #[prost(string, optional, tag = "10")]
+        pub admin_token: ::core::option::Option<::prost::alloc::string::String>,
+        #[prost(oneof = "room::Request", tags = "1, 2, 3, 4, 5, 6, 7, 8")]
         pub request: ::core::option::Option<room::Request>,
     }
     /// Nested message and enum types in `Room`.
@@ -89,12 +103,82 @@ pub mod request {
             pub peer: ::prost::alloc::string::String,
         }
         #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Pause {
+            #[prost(bool, tag = "1")]
+            pub paused: bool,
+        }
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Lock {
+            #[prost(bool, tag = "1")]
+            pub locked: bool,
+        }
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct Admit {
+            #[prost(string, tag = "1")]
+            pub peer: ::prost::alloc::string::String,
+            #[prost(bool, tag = "2")]
+            pub admit: bool,
+        }
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct UpdateConfig {
+            #[prost(uint64, optional, tag = "1")]
+            pub max_bitrate: ::core::option::Option<u64>,
+            #[prost(uint32, optional, tag = "2")]
+            pub max_spatial: ::core::option::Option<u32>,
+            #[prost(bool, optional, tag = "3")]
+            pub record: ::core::option::Option<bool>,
+        }
+        /// Which kinds of track from the peer are announced and can be viewed
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct KindFilter {
+            #[prost(string, tag = "1")]
+            pub peer: ::prost::alloc::string::String,
+            #[prost(bool, tag = "2")]
+            pub audio: bool,
+            #[prost(bool, tag = "3")]
+            pub video: bool,
+        }
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct TrackRef {
+            #[prost(string, tag = "1")]
+            pub peer: ::prost::alloc::string::String,
+            #[prost(string, tag = "2")]
+            pub track: ::prost::alloc::string::String,
+        }
+        /// Subscribe and unsubscribe single tracks of other peers in one request, it is applied atomically
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct BatchTracks {
+            #[prost(message, repeated, tag = "1")]
+            pub subscribe: ::prost::alloc::vec::Vec<TrackRef>,
+            #[prost(message, repeated, tag = "2")]
+            pub unsubscribe: ::prost::alloc::vec::Vec<TrackRef>,
+        }
+        #[derive(serde::Serialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Request {
             #[prost(message, tag = "1")]
             Subscribe(SubscribePeer),
             #[prost(message, tag = "2")]
             Unsubscribe(UnsubscribePeer),
+            #[prost(message, tag = "3")]
+            Pause(Pause),
+            #[prost(message, tag = "4")]
+            Lock(Lock),
+            #[prost(message, tag = "5")]
+            Admit(Admit),
+            #[prost(message, tag = "6")]
+            Config(UpdateConfig),
+            #[prost(message, tag = "7")]
+            KindFilter(KindFilter),
+            #[prost(message, tag = "8")]
+            BatchTracks(BatchTracks),
         }
     }
     #[derive(serde::Serialize)]
@@ -146,7 +230,7 @@ pub mod request {
     pub struct Sender {
         #[prost(string, tag = "1")]
         pub name: ::prost::alloc::string::String,
-        #[prost(oneof = "sender::Request", tags = "2, 3, 4")]
+        #[prost(oneof = "sender::Request", tags = "2, 3, 4, 5")]
         pub request: ::core::option::Option<sender::Request>,
     }
     /// Nested message and enum types in `Sender`.
@@ -167,6 +251,12 @@ pub mod request {
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct Detach {}
         #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Mute {
+            #[prost(bool, tag = "1")]
+            pub muted: bool,
+        }
+        #[derive(serde::Serialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Request {
             #[prost(message, tag = "2")]
@@ -175,6 +265,8 @@ pub mod request {
             Detach(Detach),
             #[prost(message, tag = "4")]
             Config(super::super::super::shared::sender::Config),
+            #[prost(message, tag = "5")]
+            Mute(Mute),
         }
     }
     #[derive(serde::Serialize)]
@@ -243,7 +335,7 @@ pub mod response {
     #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
     pub struct Session {
-        #[prost(oneof = "session::Response", tags = "1, 2, 3, 4")]
+        #[prost(oneof = "session::Response", tags = "1, 2, 3, 4, 5")]
         pub response: ::core::option::Option<session::Response>,
     }
     /// Nested message and enum types in `Session`.
@@ -264,6 +356,9 @@ pub mod response {
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct Disconnect {}
         #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct RenegotiateAnswer {}
+        #[derive(serde::Serialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Response {
             #[prost(message, tag = "1")]
@@ -274,12 +369,14 @@ pub mod response {
             Sdp(UpdateSdp),
             #[prost(message, tag = "4")]
             Disconnect(Disconnect),
+            #[prost(message, tag = "5")]
+            Renegotiate(RenegotiateAnswer),
         }
     }
     #[derive(serde::Serialize)]
     #[derive(Clone, Copy, PartialEq, ::prost::Message)]
     pub struct Room {
-        #[prost(oneof = "room::Response", tags = "1, 2")]
+        #[prost(oneof = "room::Response", tags = "1, 2, 3, 4, 5, 6, 7, 8")]
         pub response: ::core::option::Option<room::Response>,
     }
     /// Nested message and enum types in `Room`.
@@ -291,12 +388,42 @@ pub mod response {
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct UnsubscribePeer {}
         #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Pause {}
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Lock {}
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Admit {}
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct UpdateConfig {}
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct KindFilter {}
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct BatchTracks {}
+        #[derive(serde::Serialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
         pub enum Response {
             #[prost(message, tag = "1")]
             Subscribe(SubscribePeer),
             #[prost(message, tag = "2")]
             Unsubscribe(UnsubscribePeer),
+            #[prost(message, tag = "3")]
+            Pause(Pause),
+            #[prost(message, tag = "4")]
+            Lock(Lock),
+            #[prost(message, tag = "5")]
+            Admit(Admit),
+            #[prost(message, tag = "6")]
+            Config(UpdateConfig),
+            #[prost(message, tag = "7")]
+            KindFilter(KindFilter),
+            #[prost(message, tag = "8")]
+            BatchTracks(BatchTracks),
         }
     }
     #[derive(serde::Serialize)]
@@ -342,7 +469,7 @@ pub mod response {
     #[derive(serde::Serialize)]
     #[derive(Clone, Copy, PartialEq, ::prost::Message)]
     pub struct Sender {
-        #[prost(oneof = "sender::Response", tags = "1, 2, 3")]
+        #[prost(oneof = "sender::Response", tags = "1, 2, 3, 4")]
         pub response: ::core::option::Option<sender::Response>,
     }
     /// Nested message and enum types in `Sender`.
@@ -357,6 +484,9 @@ pub mod response {
         #[derive(Clone, Copy, PartialEq, ::prost::Message)]
         pub struct Config {}
         #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Mute {}
+        #[derive(serde::Serialize)]
         #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
         pub enum Response {
             #[prost(message, tag = "1")]
@@ -365,6 +495,8 @@ pub mod response {
             Detach(Detach),
             #[prost(message, tag = "3")]
             Config(Config),
+            #[prost(message, tag = "4")]
+            Mute(Mute),
         }
     }
     #[derive(serde::Serialize)]
@@ -427,7 +559,7 @@ pub mod server_event {
     #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
     pub struct Session {
-        #[prost(oneof = "session::Event", tags = "1, 2, 3, 4, 5")]
+        #[prost(oneof = "session::Event", tags = "1, 2, 3, 4, 5, 6, 7")]
         pub event: ::core::option::Option<session::Event>,
     }
     /// Nested message and enum types in `Session`.
@@ -464,6 +596,32 @@ pub mod server_event {
             pub reason: ::prost::alloc::string::String,
             #[prost(uint32, tag = "2")]
             pub remain_seconds: u32,
+            /// Set when session is migrated, client should restart-ice with this conn id
+            #[prost(string, optional, tag = "3")]
+            pub conn_id: ::core::option::Option<::prost::alloc::string::String>,
+            /// Signed ticket which client must send in the restart-ice request with the conn id
+            #[prost(string, optional, tag = "4")]
+            pub migrate_token: ::core::option::Option<::prost::alloc::string::String>,
+        }
+        /// Offer from server which adds m-lines for the receivers, client must answer with the same id
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct Renegotiate {
+            #[prost(uint32, tag = "1")]
+            pub id: u32,
+            #[prost(string, tag = "2")]
+            pub sdp: ::prost::alloc::string::String,
+            #[prost(message, repeated, tag = "3")]
+            pub receivers: ::prost::alloc::vec::Vec<super::super::super::shared::Receiver>,
+        }
+        /// Receiver whose source stopped is reused for a new room track, its m-line is kept so no offer is needed
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct ReceiverReused {
+            #[prost(string, tag = "1")]
+            pub previous: ::prost::alloc::string::String,
+            #[prost(message, optional, tag = "2")]
+            pub receiver: ::core::option::Option<super::super::super::shared::Receiver>,
         }
         #[derive(serde::Serialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
@@ -478,12 +636,16 @@ pub mod server_event {
             Disconnected(Disconnected),
             #[prost(message, tag = "5")]
             Goway(GoAway),
+            #[prost(message, tag = "6")]
+            Renegotiate(Renegotiate),
+            #[prost(message, tag = "7")]
+            Reused(ReceiverReused),
         }
     }
     #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
     pub struct Room {
-        #[prost(oneof = "room::Event", tags = "1, 2, 3, 4, 5, 6")]
+        #[prost(oneof = "room::Event", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10")]
         pub event: ::core::option::Option<room::Event>,
     }
     /// Nested message and enum types in `Room`.
@@ -547,6 +709,37 @@ pub mod server_event {
             pub kind: i32,
         }
         #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct TrackMuted {
+            #[prost(string, tag = "1")]
+            pub peer: ::prost::alloc::string::String,
+            #[prost(string, tag = "2")]
+            pub track: ::prost::alloc::string::String,
+            #[prost(bool, tag = "3")]
+            pub muted: bool,
+        }
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct Paused {
+            #[prost(bool, tag = "1")]
+            pub paused: bool,
+        }
+        /// Sent to the room lock owner when a peer is waiting for admit
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct JoinPending {
+            #[prost(string, tag = "1")]
+            pub peer: ::prost::alloc::string::String,
+        }
+        #[derive(serde::Serialize)]
+        #[derive(Clone, Copy, PartialEq, ::prost::Message)]
+        pub struct ConfigChanged {
+            #[prost(uint64, optional, tag = "1")]
+            pub max_bitrate: ::core::option::Option<u64>,
+            #[prost(uint32, optional, tag = "2")]
+            pub max_spatial: ::core::option::Option<u32>,
+        }
+        #[derive(serde::Serialize)]
         #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Event {
             #[prost(message, tag = "1")]
@@ -561,6 +754,14 @@ pub mod server_event {
             TrackUpdated(TrackUpdated),
             #[prost(message, tag = "6")]
             TrackStopped(TrackStopped),
+            #[prost(message, tag = "7")]
+            TrackMuted(TrackMuted),
+            #[prost(message, tag = "8")]
+            Paused(Paused),
+            #[prost(message, tag = "9")]
+            JoinPending(JoinPending),
+            #[prost(message, tag = "10")]
+            ConfigChanged(ConfigChanged),
         }
     }
     #[derive(serde::Serialize)]
@@ -568,7 +769,7 @@ pub mod server_event {
     pub struct MessageChannel {
         #[prost(string, tag = "1")]
         pub label: ::prost::alloc::string::String,
-        #[prost(oneof = "message_channel::Event", tags = "2")]
+        #[prost(oneof = "message_channel::Event", tags = "2, 3")]
         pub event: ::core::option::Option<message_channel::Event>,
     }
     /// Nested message and enum types in `MessageChannel`.
@@ -586,6 +787,8 @@ pub mod server_event {
         pub enum Event {
             #[prost(message, tag = "2")]
             Message(Message),
+            #[prost(message, tag = "3")]
+            Error(super::super::super::shared::Error),
         }
     }
     #[derive(serde::Serialize)]
@@ -593,7 +796,7 @@ pub mod server_event {
     pub struct Sender {
         #[prost(string, tag = "1")]
         pub name: ::prost::alloc::string::String,
-        #[prost(oneof = "sender::Event", tags = "2")]
+        #[prost(oneof = "sender::Event", tags = "2, 3")]
         pub event: ::core::option::Option<sender::Event>,
     }
     /// Nested message and enum types in `Sender`.
@@ -607,11 +810,20 @@ pub mod server_event {
             )]
             pub status: i32,
         }
+        /// Target bitrate of each simulcast layer in bps, 0 when the layer has no consumers
         #[derive(serde::Serialize)]
-        #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct LayerBitrates {
+            #[prost(uint64, repeated, tag = "1")]
+            pub layers: ::prost::alloc::vec::Vec<u64>,
+        }
+        #[derive(serde::Serialize)]
+        #[derive(Clone, PartialEq, ::prost::Oneof)]
         pub enum Event {
             #[prost(message, tag = "2")]
             State(State),
+            #[prost(message, tag = "3")]
+            LayerBitrates(LayerBitrates),
         }
     }
     #[derive(serde::Serialize)]
@@ -48,6 +48,10 @@ pub mod receiver {
         pub min_spatial: ::core::option::Option<u32>,
         #[prost(uint32, optional, tag = "5")]
         pub min_temporal: ::core::option::Option<u32>,
+        #[prost(enumeration = "BitratePriority", tag = "6")]
+        pub bitrate_priority: i32,
+        #[prost(bool, tag = "8")]
+        pub smooth_playout: bool,
     }
     #[derive(serde::Serialize)]
     #[derive(Clone, PartialEq, ::prost::Message)]
@@ -70,6 +74,46 @@ pub mod receiver {
         ::prost::Enumeration
     )]
     #[repr(i32)]
+    pub enum BitratePriority {
+        Camera = 0,
+        Screen = 1,
+        Audio = 2,
+    }
+    impl BitratePriority {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Camera => "CAMERA",
+                Self::Screen => "SCREEN",
+                Self::Audio => "AUDIO",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "CAMERA" => Some(Self::Camera),
+                "SCREEN" => Some(Self::Screen),
+                "AUDIO" => Some(Self::Audio),
+                _ => None,
+            }
+        }
+    }
+    #[derive(serde::Serialize)]
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
     pub enum Status {
         Waiting = 0,
         Active = 1,
@@ -0,0 +1,93 @@
+//! Custom key/value tags which integrators attach to a session at connect time. Tags are not used by the media server,
+//! they are only carried unchanged into connector peer events for analytics segmentation.
+//! Because tags come from clients, count and size are limited.
+
+use std::collections::HashMap;
+
+pub type SessionTags = HashMap<String, String>;
+
+pub const MAX_SESSION_TAGS: usize = 16;
+pub const MAX_SESSION_TAG_KEY_LEN: usize = 64;
+pub const MAX_SESSION_TAG_VALUE_LEN: usize = 256;
+
+/// Http header for whip and whep, value is in format `key1=value1,key2=value2`
+pub const SESSION_TAGS_HEADER: &str = "x-session-tags";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionTagsError {
+    TooManyTags(usize),
+    InvalidKey(String),
+    ValueTooLong(String),
+    InvalidFormat(String),
+}
+
+impl std::fmt::Display for SessionTagsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooManyTags(count) => write!(f, "too many session tags {count}, max {MAX_SESSION_TAGS}"),
+            Self::InvalidKey(key) => write!(f, "invalid session tag key {key}"),
+            Self::ValueTooLong(key) => write!(f, "session tag {key} value is longer than {MAX_SESSION_TAG_VALUE_LEN}"),
+            Self::InvalidFormat(part) => write!(f, "invalid session tag {part}"),
+        }
+    }
+}
+
+/// Key must be non-empty and not longer than limit, value only has a length limit
+pub fn validate_session_tags(tags: &SessionTags) -> Result<(), SessionTagsError> {
+    if tags.len() > MAX_SESSION_TAGS {
+        return Err(SessionTagsError::TooManyTags(tags.len()));
+    }
+    for (key, value) in tags {
+        if key.is_empty() || key.len() > MAX_SESSION_TAG_KEY_LEN {
+            return Err(SessionTagsError::InvalidKey(key.clone()));
+        }
+        if value.len() > MAX_SESSION_TAG_VALUE_LEN {
+            return Err(SessionTagsError::ValueTooLong(key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Parse and validate tags from header value in format `key1=value1,key2=value2`
+pub fn parse_session_tags_header(value: &str) -> Result<SessionTags, SessionTagsError> {
+    let mut tags = SessionTags::new();
+    for part in value.split(',').map(|part| part.trim()).filter(|part| !part.is_empty()) {
+        let (key, value) = part.split_once('=').ok_or_else(|| SessionTagsError::InvalidFormat(part.to_string()))?;
+        tags.insert(key.trim().to_string(), value.trim().to_string());
+    }
+    validate_session_tags(&tags)?;
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_session_tags_header, validate_session_tags, SessionTags, SessionTagsError, MAX_SESSION_TAGS, MAX_SESSION_TAG_VALUE_LEN};
+
+    #[test]
+    fn parse_header() {
+        let tags = parse_session_tags_header("user=u1, bucket = b , region=eu=west").expect("Should parse");
+        assert_eq!(
+            tags,
+            SessionTags::from([
+                ("user".to_string(), "u1".to_string()),
+                ("bucket".to_string(), "b".to_string()),
+                ("region".to_string(), "eu=west".to_string()),
+            ])
+        );
+        assert_eq!(parse_session_tags_header(""), Ok(SessionTags::new()));
+        assert_eq!(parse_session_tags_header("user"), Err(SessionTagsError::InvalidFormat("user".to_string())));
+        assert_eq!(parse_session_tags_header("=u1"), Err(SessionTagsError::InvalidKey("".to_string())));
+    }
+
+    #[test]
+    fn enforce_limits() {
+        let tags: SessionTags = (0..MAX_SESSION_TAGS + 1).map(|i| (format!("k{i}"), "v".to_string())).collect();
+        assert_eq!(validate_session_tags(&tags), Err(SessionTagsError::TooManyTags(MAX_SESSION_TAGS + 1)));
+
+        let tags = SessionTags::from([("k".to_string(), "v".repeat(MAX_SESSION_TAG_VALUE_LEN + 1))]);
+        assert_eq!(validate_session_tags(&tags), Err(SessionTagsError::ValueTooLong("k".to_string())));
+
+        let tags = SessionTags::from([("k".repeat(65), "v".to_string())]);
+        assert_eq!(validate_session_tags(&tags), Err(SessionTagsError::InvalidKey("k".repeat(65))));
+    }
+}
@@ -21,6 +21,9 @@ pub struct WebrtcToken {
     pub peer: Option<String>,
     pub record: bool,
     pub extra_data: Option<String>,
+    /// Allow pause, lock, admit and config requests of the room
+    #[serde(default)]
+    pub room_admin: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::protobuf;
 
+pub mod admin;
 pub mod rtpengine;
 pub mod webrtc;
 pub mod whep;
@@ -59,6 +60,7 @@ pub enum RpcReq<Conn> {
     Whip(whip::RpcReq<Conn>),
     Webrtc(webrtc::RpcReq<Conn>),
     RtpEngine(rtpengine::RpcReq<Conn>),
+    Admin(admin::RpcReq),
 }
 
 impl<Conn: ConnLayer> RpcReq<Conn> {
@@ -80,6 +82,7 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
                 let (req, layer) = req.down();
                 (RpcReq::RtpEngine(req), layer)
             }
+            Self::Admin(req) => (RpcReq::Admin(req), None),
         }
     }
 
@@ -89,6 +92,7 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
             Self::Whep(req) => req.get_down_part(),
             Self::Webrtc(req) => req.get_down_part(),
             Self::RtpEngine(req) => req.get_down_part(),
+            Self::Admin(..) => None,
         }
     }
 }
@@ -99,6 +103,7 @@ pub enum RpcRes<Conn> {
     Whip(whip::RpcRes<Conn>),
     Webrtc(webrtc::RpcRes<Conn>),
     RtpEngine(rtpengine::RpcRes<Conn>),
+    Admin(admin::RpcRes),
 }
 
 impl<Conn: ConnLayer> RpcRes<Conn> {
@@ -108,6 +113,7 @@ impl<Conn: ConnLayer> RpcRes<Conn> {
             Self::Whep(req) => RpcRes::Whep(req.up(param)),
             Self::Webrtc(req) => RpcRes::Webrtc(req.up(param)),
             Self::RtpEngine(req) => RpcRes::RtpEngine(req.up(param)),
+            Self::Admin(res) => RpcRes::Admin(res),
         }
     }
 }
@@ -146,4 +152,10 @@ impl From<RpcError> for protobuf::shared::Error {
     }
 }
 
+impl From<protobuf::shared::Error> for RpcError {
+    fn from(val: protobuf::shared::Error) -> Self {
+        RpcError { code: val.code, message: val.message }
+    }
+}
+
 pub type RpcResult<Type> = Result<Type, RpcError>;
@@ -0,0 +1,152 @@
+use crate::{
+    endpoint::{RoomId, TrackInfo},
+    multi_tenancy::{AppContext, AppId},
+    protobuf,
+};
+
+use super::RpcResult;
+
+/// Close all sessions of an app, or only sessions inside a room if it is provided
+#[derive(Debug, Clone)]
+pub struct CloseSessionsReq {
+    pub app: AppContext,
+    pub room: Option<RoomId>,
+    /// Only close the webrtc session with this id if it is migrated to other node, the new session has the same id
+    /// so it is kept. Used for closing the old session as soon as the migrated one is created
+    pub migrated_session: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeCloseResult {
+    pub node: u32,
+    pub closed: RpcResult<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CloseSessionsRes {
+    pub nodes: Vec<NodeCloseResult>,
+}
+
+impl CloseSessionsRes {
+    /// Total closed sessions over all success nodes
+    pub fn closed(&self) -> u32 {
+        self.nodes.iter().filter_map(|n| n.closed.as_ref().ok()).sum()
+    }
+
+    /// Merge other result into this, counts of same node are summed, first error of a node is kept
+    pub fn merge(&mut self, other: CloseSessionsRes) {
+        for node in other.nodes {
+            if let Some(slot) = self.nodes.iter_mut().find(|n| n.node == node.node) {
+                slot.closed = match (&slot.closed, node.closed) {
+                    (Ok(count), Ok(added)) => Ok(count + added),
+                    (Ok(_), Err(e)) => Err(e),
+                    (Err(e), _) => Err(e.clone()),
+                };
+            } else {
+                self.nodes.push(node);
+            }
+        }
+    }
+}
+
+/// List tracks which are published in a room, over all nodes
+#[derive(Debug, Clone)]
+pub struct RoomTracksReq {
+    pub app: AppContext,
+    pub room: RoomId,
+}
+
+/// Routing attempt of a gateway which is waiting for the destination node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRoute {
+    pub session_id: u64,
+    pub app: AppId,
+    /// whip, whep, webrtc or rtpengine
+    pub kind: String,
+    /// Selected node, None if the gateway is still selecting
+    pub dest_node: Option<u32>,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum RpcReq {
+    CloseSessions(CloseSessionsReq),
+    RoomTracks(RoomTracksReq),
+    /// In-flight routes of the gateway which is processing the request, media nodes don't have any
+    PendingRoutes,
+    /// Cancel in-flight route of a session, the result is false if it is not found
+    CancelRoute(u64),
+}
+
+#[derive(Debug, Clone)]
+pub enum RpcRes {
+    CloseSessions(RpcResult<CloseSessionsRes>),
+    RoomTracks(RpcResult<Vec<TrackInfo>>),
+    PendingRoutes(RpcResult<Vec<PendingRoute>>),
+    CancelRoute(RpcResult<bool>),
+}
+
+impl From<protobuf::cluster_gateway::CloseSessionsRequest> for CloseSessionsReq {
+    fn from(value: protobuf::cluster_gateway::CloseSessionsRequest) -> Self {
+        Self {
+            app: value.app.into(),
+            room: value.room.map(|r| r.into()),
+            migrated_session: value.migrated_session,
+        }
+    }
+}
+
+impl From<CloseSessionsReq> for protobuf::cluster_gateway::CloseSessionsRequest {
+    fn from(val: CloseSessionsReq) -> Self {
+        protobuf::cluster_gateway::CloseSessionsRequest {
+            app: Some(val.app.into()),
+            room: val.room.map(|r| r.into()),
+            migrated_session: val.migrated_session,
+        }
+    }
+}
+
+impl From<protobuf::cluster_gateway::RoomTracksRequest> for RoomTracksReq {
+    fn from(value: protobuf::cluster_gateway::RoomTracksRequest) -> Self {
+        Self {
+            app: value.app.into(),
+            room: value.room.into(),
+        }
+    }
+}
+
+impl From<RoomTracksReq> for protobuf::cluster_gateway::RoomTracksRequest {
+    fn from(val: RoomTracksReq) -> Self {
+        protobuf::cluster_gateway::RoomTracksRequest {
+            app: Some(val.app.into()),
+            room: val.room.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::RpcError;
+
+    use super::{CloseSessionsRes, NodeCloseResult};
+
+    #[test]
+    fn merge_per_node_results() {
+        let mut res = CloseSessionsRes {
+            nodes: vec![NodeCloseResult { node: 1, closed: Ok(2) }],
+        };
+        res.merge(CloseSessionsRes {
+            nodes: vec![NodeCloseResult { node: 1, closed: Ok(3) }, NodeCloseResult { node: 2, closed: Ok(1) }],
+        });
+        res.merge(CloseSessionsRes {
+            nodes: vec![NodeCloseResult {
+                node: 3,
+                closed: Err(RpcError::new(1_u32, "timeout")),
+            }],
+        });
+        assert_eq!(res.closed(), 6);
+        assert_eq!(res.nodes.len(), 3);
+        assert_eq!(res.nodes[0], NodeCloseResult { node: 1, closed: Ok(5) });
+        assert!(res.nodes[2].closed.is_err());
+    }
+}
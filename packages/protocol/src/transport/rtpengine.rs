@@ -1,5 +1,8 @@
+use std::net::SocketAddr;
+
 use crate::{
-    endpoint::{PeerId, RoomId},
+    endpoint::{PeerId, RoomId, TrackSource},
+    media::MediaKind,
     multi_tenancy::AppContext,
     protobuf,
 };
@@ -32,10 +35,23 @@ pub struct RtpCreateAnswerRequest {
     pub extra_data: Option<String>,
 }
 
+/// Plain rtp egress, the session subscribes `source` and sends rtp packets to `dest`
+#[derive(Debug, Clone)]
+pub struct RtpCreateEgressRequest {
+    pub app: AppContext,
+    pub session_id: u64,
+    pub room: RoomId,
+    pub peer: PeerId,
+    pub source: TrackSource,
+    pub kind: MediaKind,
+    pub dest: SocketAddr,
+}
+
 #[derive(Debug, Clone)]
 pub enum RpcReq<Conn> {
     CreateOffer(RtpCreateOfferRequest),
     CreateAnswer(RtpCreateAnswerRequest),
+    CreateEgress(RtpCreateEgressRequest),
     SetAnswer(Conn, RtpSetAnswerRequest),
     Delete(Conn),
 }
@@ -49,6 +65,7 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
                 (RpcReq::SetAnswer(down, req), Some(layer))
             }
             RpcReq::CreateAnswer(conn_req) => (RpcReq::CreateAnswer(conn_req.clone()), None),
+            RpcReq::CreateEgress(conn_req) => (RpcReq::CreateEgress(conn_req), None),
             RpcReq::Delete(conn) => {
                 let (down, layer) = conn.down();
                 (RpcReq::Delete(down), Some(layer))
@@ -61,6 +78,7 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
             RpcReq::CreateOffer(..) => None,
             RpcReq::SetAnswer(conn, ..) => Some(conn.get_down_part()),
             RpcReq::CreateAnswer(..) => None,
+            RpcReq::CreateEgress(..) => None,
             RpcReq::Delete(conn, ..) => Some(conn.get_down_part()),
         }
     }
@@ -71,6 +89,7 @@ pub enum RpcRes<Conn> {
     CreateOffer(RpcResult<(Conn, String)>),
     SetAnswer(RpcResult<Conn>),
     CreateAnswer(RpcResult<(Conn, String)>),
+    CreateEgress(RpcResult<(Conn, String)>),
     Delete(RpcResult<Conn>),
 }
 
@@ -80,6 +99,7 @@ impl<Conn: ConnLayer> RpcRes<Conn> {
             RpcRes::CreateOffer(res) => RpcRes::CreateOffer(res.map(|(conn, sdp)| (conn.up(param), sdp))),
             RpcRes::SetAnswer(res) => RpcRes::SetAnswer(res.map(|conn| conn.up(param))),
             RpcRes::CreateAnswer(res) => RpcRes::CreateAnswer(res.map(|(conn, sdp)| (conn.up(param), sdp))),
+            RpcRes::CreateEgress(res) => RpcRes::CreateEgress(res.map(|(conn, sdp)| (conn.up(param), sdp))),
             RpcRes::Delete(res) => RpcRes::Delete(res.map(|conn| conn.up(param))),
         }
     }
@@ -140,3 +160,72 @@ impl From<RtpCreateAnswerRequest> for protobuf::cluster_gateway::RtpEngineCreate
         }
     }
 }
+
+impl TryFrom<protobuf::cluster_gateway::RtpEngineCreateEgressRequest> for RtpCreateEgressRequest {
+    type Error = ();
+    fn try_from(value: protobuf::cluster_gateway::RtpEngineCreateEgressRequest) -> Result<Self, Self::Error> {
+        Ok(Self {
+            kind: value.kind().into(),
+            dest: value.dest.parse().map_err(|_| ())?,
+            app: value.app.into(),
+            session_id: value.session_id,
+            room: value.room.into(),
+            peer: value.peer.into(),
+            source: TrackSource {
+                peer: value.source_peer.into(),
+                track: value.source_track.into(),
+            },
+        })
+    }
+}
+
+impl From<RtpCreateEgressRequest> for protobuf::cluster_gateway::RtpEngineCreateEgressRequest {
+    fn from(val: RtpCreateEgressRequest) -> Self {
+        let kind: protobuf::shared::Kind = val.kind.into();
+        protobuf::cluster_gateway::RtpEngineCreateEgressRequest {
+            app: Some(val.app.into()),
+            session_id: val.session_id,
+            room: val.room.into(),
+            peer: val.peer.into(),
+            source_peer: val.source.peer.into(),
+            source_track: val.source.track.into(),
+            kind: kind as i32,
+            dest: val.dest.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{endpoint::TrackSource, media::MediaKind, multi_tenancy::AppContext, protobuf};
+
+    use super::RtpCreateEgressRequest;
+
+    #[test]
+    fn egress_request_protobuf_round_trip() {
+        let req = RtpCreateEgressRequest {
+            app: AppContext::root_app(),
+            session_id: 1,
+            room: "room".into(),
+            peer: "recorder".into(),
+            source: TrackSource {
+                peer: "peer1".into(),
+                track: "video_main".into(),
+            },
+            kind: MediaKind::Video,
+            dest: "10.0.0.1:5004".parse().unwrap(),
+        };
+        let encoded: protobuf::cluster_gateway::RtpEngineCreateEgressRequest = req.clone().into();
+        let decoded: RtpCreateEgressRequest = encoded.clone().try_into().unwrap();
+        assert_eq!(decoded.kind, MediaKind::Video);
+        assert_eq!(decoded.dest, req.dest);
+        assert_eq!(decoded.source, req.source);
+        assert_eq!(decoded.room, req.room);
+
+        let invalid = protobuf::cluster_gateway::RtpEngineCreateEgressRequest {
+            dest: "invalid".to_string(),
+            ..encoded
+        };
+        assert!(RtpCreateEgressRequest::try_from(invalid).is_err());
+    }
+}
@@ -1,11 +1,69 @@
 use std::net::IpAddr;
 
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
 use super::{ConnLayer, RpcResult};
 use crate::{
+    endpoint::ClusterConnId,
     multi_tenancy::AppContext,
-    protobuf::gateway::{ConnectRequest, ConnectResponse, RemoteIceRequest, RemoteIceResponse},
+    protobuf::{
+        self,
+        gateway::{ConnectRequest, ConnectResponse, RemoteIceRequest, RemoteIceResponse, ValidateOfferResponse},
+        session::RoomJoin,
+        shared::receiver::State as ReceiverState,
+    },
 };
 
+/// Error codes of webrtc transport, shared with http apis which map them to status codes
+#[derive(num_enum::TryFromPrimitive, num_enum::IntoPrimitive, derive_more::Display)]
+#[repr(u32)]
+pub enum WebrtcError {
+    InvalidSdp = 0x2000,
+    InternalServerError = 0x2001,
+    RpcInvalidRequest = 0x2002,
+    RpcTrackNameNotFound = 0x2003,
+    RpcTrackNotAttached = 0x2004,
+    RpcTrackAlreadyAttached = 0x2005,
+    RpcEndpointNotFound = 0x2006,
+    RpcTokenInvalid = 0x2007,
+    RpcTokenRoomPeerNotMatch = 0x2008,
+    RpcTokenAppNotMatch = 0x2009,
+    RpcAlreadyDisconnected = 0x2010,
+    ConnectOverloaded = 0x2011,
+    WorkerShuttingDown = 0x2012,
+    RpcMigrateNotSupported = 0x2013,
+    DtlsPolicyRejected = 0x2014,
+    NoCompatibleCodec = 0x2015,
+    InvalidIceCandidate = 0x2016,
+    TooManyMediaSections = 0x2017,
+    RpcEventsNotSupported = 0x2018,
+    WorkerNotReady = 0x2019,
+    TooManyIceCandidates = 0x2020,
+    RpcTokenNotRoomAdmin = 0x2021,
+    RpcMigrateTicketInvalid = 0x2022,
+}
+
+/// Diagnostic state of a session in webrtc worker, whip and whep sessions are included. ICE passwords are redacted from SDPs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDump {
+    pub offer: String,
+    pub answer: String,
+    /// Last ICE connection state, e.g. new, checking, connected
+    pub ice_state: String,
+    pub local_candidates: Vec<String>,
+    /// Candidates which are signaled by client, in offer or trickled
+    pub remote_candidates: Vec<String>,
+    /// Nominated pair as `local -> remote (type)`, None before ICE connected
+    pub selected_pair: Option<String>,
+    /// Media codecs which are selected in answer
+    pub codecs: Vec<String>,
+    /// connecting, connected, consent_failed, dtls_rejected, rtp_abusive or closed
+    pub transport_state: String,
+    /// Authenticated RTP packets which are dropped as malformed or oversized
+    pub bad_rtp_packets: u64,
+}
+
 #[derive(Debug, Clone)]
 pub enum RpcReq<Conn> {
     /// Ip, Agent, Req, Userdata, Record
@@ -14,6 +72,12 @@ pub enum RpcReq<Conn> {
     /// ConnId, Ip, Agent, Req, Userdata, Record
     RestartIce(Conn, AppContext, IpAddr, String, ConnectRequest, Option<String>, bool),
     Delete(Conn),
+    /// ConnId, App, Dest node. Dest is selected by gateway if not provided, inside data residency zones of the app
+    Migrate(Conn, AppContext, Option<u32>),
+    /// ConnId, for support diagnostics
+    Dump(Conn),
+    /// Ip, Req. Dry-run of connect for checking client compatibility, no session is created
+    Validate(AppContext, IpAddr, ConnectRequest),
 }
 
 impl<Conn: ConnLayer> RpcReq<Conn> {
@@ -32,6 +96,15 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
                 let (down, layer) = conn.down();
                 (RpcReq::Delete(down), Some(layer))
             }
+            RpcReq::Migrate(conn, app, dest) => {
+                let (down, layer) = conn.down();
+                (RpcReq::Migrate(down, app, dest), Some(layer))
+            }
+            RpcReq::Dump(conn) => {
+                let (down, layer) = conn.down();
+                (RpcReq::Dump(down), Some(layer))
+            }
+            RpcReq::Validate(app, ip_addr, req) => (RpcReq::Validate(app, ip_addr, req), None),
         }
     }
 
@@ -41,6 +114,9 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
             RpcReq::RemoteIce(conn, ..) => Some(conn.get_down_part()),
             RpcReq::RestartIce(conn, ..) => Some(conn.get_down_part()),
             RpcReq::Delete(conn, ..) => Some(conn.get_down_part()),
+            RpcReq::Migrate(conn, ..) => Some(conn.get_down_part()),
+            RpcReq::Dump(conn) => Some(conn.get_down_part()),
+            RpcReq::Validate(..) => None,
         }
     }
 }
@@ -51,6 +127,10 @@ pub enum RpcRes<Conn> {
     RemoteIce(RpcResult<RemoteIceResponse>),
     RestartIce(RpcResult<(Conn, ConnectResponse)>),
     Delete(RpcResult<()>),
+    /// Conn id which client will use for restart-ice to the destination node
+    Migrate(RpcResult<ClusterConnId>),
+    Dump(RpcResult<SessionDump>),
+    Validate(RpcResult<ValidateOfferResponse>),
 }
 
 impl<Conn: ConnLayer> RpcRes<Conn> {
@@ -62,6 +142,186 @@ impl<Conn: ConnLayer> RpcRes<Conn> {
             RpcRes::RestartIce(Ok((conn, res))) => RpcRes::RestartIce(Ok((conn.up(param), res))),
             RpcRes::RestartIce(Err(e)) => RpcRes::RestartIce(Err(e)),
             RpcRes::Delete(res) => RpcRes::Delete(res),
+            RpcRes::Migrate(res) => RpcRes::Migrate(res),
+            RpcRes::Dump(res) => RpcRes::Dump(res),
+            RpcRes::Validate(res) => RpcRes::Validate(res),
+        }
+    }
+}
+
+impl From<SessionDump> for protobuf::cluster_gateway::WebrtcDumpResponse {
+    fn from(value: SessionDump) -> Self {
+        Self {
+            offer: value.offer,
+            answer: value.answer,
+            ice_state: value.ice_state,
+            local_candidates: value.local_candidates,
+            remote_candidates: value.remote_candidates,
+            selected_pair: value.selected_pair,
+            codecs: value.codecs,
+            transport_state: value.transport_state,
+            bad_rtp_packets: value.bad_rtp_packets,
+        }
+    }
+}
+
+impl From<protobuf::cluster_gateway::WebrtcDumpResponse> for SessionDump {
+    fn from(value: protobuf::cluster_gateway::WebrtcDumpResponse) -> Self {
+        Self {
+            offer: value.offer,
+            answer: value.answer,
+            ice_state: value.ice_state,
+            local_candidates: value.local_candidates,
+            remote_candidates: value.remote_candidates,
+            selected_pair: value.selected_pair,
+            codecs: value.codecs,
+            transport_state: value.transport_state,
+            bad_rtp_packets: value.bad_rtp_packets,
+        }
+    }
+}
+
+impl From<RpcResult<ValidateOfferResponse>> for protobuf::cluster_gateway::WebrtcValidateResponse {
+    fn from(value: RpcResult<ValidateOfferResponse>) -> Self {
+        match value {
+            Ok(res) => Self { res: Some(res), error: None },
+            Err(e) => Self { res: None, error: Some(e.into()) },
         }
     }
 }
+
+///
+/// Ticket of a migrated session, it is signed by the node which holds the session and given to client in GoAway.
+/// Restart-ice to the dest node must carry it, the room join and attached receivers are taken from the ticket
+/// instead of the request, so a client can't move other sessions or gain subscriptions by migrating.
+/// Join and receiver states are kept protobuf encoded because protobuf types are not deserializable.
+/// The nonce is random per ticket, the dest node consumes it on redeem so a ticket can only be used once.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebrtcMigrateTicket {
+    pub session_id: u64,
+    pub from_node: u32,
+    pub dest_node: u32,
+    pub nonce: u64,
+    join: Option<Vec<u8>>,
+    receivers: Vec<(String, Vec<u8>)>,
+}
+
+impl WebrtcMigrateTicket {
+    /// Tickets are valid for the go-away period of the migrated session
+    pub const TTL_SECONDS: u64 = 10;
+
+    pub fn new(session_id: u64, from_node: u32, dest_node: u32) -> Self {
+        Self {
+            session_id,
+            from_node,
+            dest_node,
+            nonce: rand::random(),
+            join: None,
+            receivers: vec![],
+        }
+    }
+
+    pub fn set_join(&mut self, join: &RoomJoin) {
+        self.join = Some(join.encode_to_vec());
+    }
+
+    pub fn join(&self) -> Option<RoomJoin> {
+        self.join.as_ref().and_then(|join| RoomJoin::decode(join.as_slice()).ok())
+    }
+
+    pub fn add_receiver(&mut self, name: &str, state: &ReceiverState) {
+        self.receivers.push((name.to_string(), state.encode_to_vec()));
+    }
+
+    /// Replace join and receiver states of the restart-ice request with handed over ones.
+    /// Receivers which are not in the request are dropped because they don't have m-line in the new connection.
+    /// Return names of receivers which must be attached after join
+    pub fn apply(&self, req: &mut ConnectRequest) -> Vec<String> {
+        req.join = self.join();
+        let mut attached = vec![];
+        for receiver in req.tracks.iter_mut().flat_map(|tracks| tracks.receivers.iter_mut()) {
+            let state = self
+                .receivers
+                .iter()
+                .find(|(name, _)| *name == receiver.name)
+                .and_then(|(_, state)| ReceiverState::decode(state.as_slice()).ok());
+            if state.as_ref().is_some_and(|s| s.source.is_some()) {
+                attached.push(receiver.name.clone());
+            }
+            receiver.state = state;
+        }
+        attached
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protobuf::{
+        gateway::ConnectRequest,
+        session::RoomJoin,
+        shared::{
+            receiver::{Config, Source, State},
+            Kind, Receiver, Tracks,
+        },
+    };
+
+    use super::WebrtcMigrateTicket;
+
+    fn receiver(name: &str, state: Option<State>) -> Receiver {
+        Receiver {
+            kind: Kind::Video as i32,
+            name: name.to_string(),
+            state,
+        }
+    }
+
+    #[test]
+    fn migrate_ticket_overrides_request() {
+        let state = State {
+            config: Some(Config {
+                priority: 10,
+                max_spatial: 2,
+                max_temporal: 2,
+                ..Default::default()
+            }),
+            source: Some(Source {
+                peer: "peer2".to_string(),
+                track: "video_main".to_string(),
+            }),
+        };
+        let mut ticket = WebrtcMigrateTicket::new(1000, 1, 2);
+        ticket.set_join(&RoomJoin {
+            room: "room".to_string(),
+            peer: "peer1".to_string(),
+            ..Default::default()
+        });
+        ticket.add_receiver("video_0", &state);
+        ticket.add_receiver("video_gone", &state);
+
+        let forged = State {
+            source: Some(Source {
+                peer: "other".to_string(),
+                track: "other".to_string(),
+            }),
+            ..Default::default()
+        };
+        let mut req = ConnectRequest {
+            join: Some(RoomJoin {
+                room: "other_room".to_string(),
+                peer: "peer1".to_string(),
+                ..Default::default()
+            }),
+            tracks: Some(Tracks {
+                receivers: vec![receiver("video_0", None), receiver("video_1", Some(forged))],
+                senders: vec![],
+            }),
+            ..Default::default()
+        };
+        assert_eq!(ticket.apply(&mut req), vec!["video_0".to_string()]);
+        assert_eq!(req.join.map(|j| j.room), Some("room".to_string()));
+        let receivers = req.tracks.expect("should have tracks").receivers;
+        assert_eq!(receivers[0].state, Some(state));
+        assert_eq!(receivers[1].state, None);
+    }
+}
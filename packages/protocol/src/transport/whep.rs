@@ -4,6 +4,7 @@ use crate::{
     endpoint::{PeerId, RoomId},
     multi_tenancy::AppContext,
     protobuf,
+    session_tags::SessionTags,
 };
 
 use super::{ConnLayer, RpcResult};
@@ -18,6 +19,8 @@ pub struct WhepConnectReq {
     pub ip: IpAddr,
     pub user_agent: String,
     pub extra_data: Option<String>,
+    /// Custom tags which are carried into connector peer events
+    pub tags: SessionTags,
 }
 
 #[derive(Debug, Clone)]
@@ -43,11 +46,39 @@ pub struct WhepDeleteReq<Conn> {
 #[derive(Debug, Clone)]
 pub struct WhepDeleteRes {}
 
+/// Server to client event of a whep session, which is streamed over SSE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WhepEvent {
+    /// Server ICE candidate line, without `a=` prefix
+    Candidate(String),
+    EndOfCandidates,
+    LayerChanged {
+        spatial: u8,
+        temporal: u8,
+    },
+    Stats {
+        egress_bitrate: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct WhepEventsReq<Conn> {
+    pub conn_id: Conn,
+    /// First poll of a stream, server candidates are included again
+    pub initial: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct WhepEventsRes {
+    pub events: Vec<WhepEvent>,
+}
+
 #[derive(Debug, Clone, convert_enum::From, convert_enum::TryInto)]
 pub enum RpcReq<Conn> {
     Connect(WhepConnectReq),
     RemoteIce(WhepRemoteIceReq<Conn>),
     Delete(WhepDeleteReq<Conn>),
+    Events(WhepEventsReq<Conn>),
 }
 
 impl<Conn: ConnLayer> RpcReq<Conn> {
@@ -62,6 +93,10 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
                 let (down, layer) = req.conn_id.down();
                 (RpcReq::Delete(WhepDeleteReq { conn_id: down }), Some(layer))
             }
+            RpcReq::Events(req) => {
+                let (down, layer) = req.conn_id.down();
+                (RpcReq::Events(WhepEventsReq { conn_id: down, initial: req.initial }), Some(layer))
+            }
         }
     }
 
@@ -70,6 +105,7 @@ impl<Conn: ConnLayer> RpcReq<Conn> {
             RpcReq::Connect(_req) => None,
             RpcReq::RemoteIce(req) => Some(req.conn_id.get_down_part()),
             RpcReq::Delete(req) => Some(req.conn_id.get_down_part()),
+            RpcReq::Events(req) => Some(req.conn_id.get_down_part()),
         }
     }
 }
@@ -79,6 +115,7 @@ pub enum RpcRes<Conn> {
     Connect(RpcResult<WhepConnectRes<Conn>>),
     RemoteIce(RpcResult<WhepRemoteIceRes>),
     Delete(RpcResult<WhepDeleteRes>),
+    Events(RpcResult<WhepEventsRes>),
 }
 
 impl<Conn: ConnLayer> RpcRes<Conn> {
@@ -91,6 +128,7 @@ impl<Conn: ConnLayer> RpcRes<Conn> {
             RpcRes::Connect(Err(e)) => RpcRes::Connect(Err(e)),
             RpcRes::RemoteIce(res) => RpcRes::RemoteIce(res),
             RpcRes::Delete(res) => RpcRes::Delete(res),
+            RpcRes::Events(res) => RpcRes::Events(res),
         }
     }
 }
@@ -107,6 +145,7 @@ impl TryFrom<protobuf::cluster_gateway::WhepConnectRequest> for WhepConnectReq {
             ip: value.ip.parse().map_err(|_| ())?,
             user_agent: value.user_agent,
             extra_data: value.extra_data,
+            tags: value.tags,
         })
     }
 }
@@ -122,6 +161,39 @@ impl From<WhepConnectReq> for protobuf::cluster_gateway::WhepConnectRequest {
             room: val.room.into(),
             peer: val.peer.into(),
             extra_data: val.extra_data,
+            tags: val.tags,
+        }
+    }
+}
+
+impl From<WhepEvent> for protobuf::cluster_gateway::WhepEvent {
+    fn from(value: WhepEvent) -> Self {
+        use protobuf::cluster_gateway::whep_event::{Event, LayerChanged, Stats};
+        let event = match value {
+            WhepEvent::Candidate(candidate) => Event::Candidate(candidate),
+            WhepEvent::EndOfCandidates => Event::EndOfCandidates(true),
+            WhepEvent::LayerChanged { spatial, temporal } => Event::LayerChanged(LayerChanged {
+                spatial: spatial as u32,
+                temporal: temporal as u32,
+            }),
+            WhepEvent::Stats { egress_bitrate } => Event::Stats(Stats { egress_bitrate }),
+        };
+        Self { event: Some(event) }
+    }
+}
+
+impl TryFrom<protobuf::cluster_gateway::WhepEvent> for WhepEvent {
+    type Error = ();
+    fn try_from(value: protobuf::cluster_gateway::WhepEvent) -> Result<Self, Self::Error> {
+        use protobuf::cluster_gateway::whep_event::Event;
+        match value.event.ok_or(())? {
+            Event::Candidate(candidate) => Ok(Self::Candidate(candidate)),
+            Event::EndOfCandidates(_) => Ok(Self::EndOfCandidates),
+            Event::LayerChanged(layer) => Ok(Self::LayerChanged {
+                spatial: layer.spatial as u8,
+                temporal: layer.temporal as u8,
+            }),
+            Event::Stats(stats) => Ok(Self::Stats { egress_bitrate: stats.egress_bitrate }),
         }
     }
 }
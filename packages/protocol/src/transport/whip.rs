@@ -1,9 +1,10 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, time::Duration};
 
 use crate::{
     endpoint::{PeerId, RoomId},
     multi_tenancy::AppContext,
     protobuf,
+    session_tags::SessionTags,
 };
 
 use super::{ConnLayer, RpcResult};
@@ -19,6 +20,21 @@ pub struct WhipConnectReq {
     pub ip: IpAddr,
     pub user_agent: String,
     pub extra_data: Option<String>,
+    /// Absolute unix timestamp in milliseconds, the request should be aborted after it
+    pub deadline_ms: Option<u64>,
+    /// Custom tags which are carried into connector peer events
+    pub tags: SessionTags,
+}
+
+impl WhipConnectReq {
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.deadline_ms.map(|deadline| now_ms >= deadline).unwrap_or(false)
+    }
+
+    /// Time left until the deadline, None if the request has no deadline
+    pub fn remaining(&self, now_ms: u64) -> Option<Duration> {
+        self.deadline_ms.map(|deadline| Duration::from_millis(deadline.saturating_sub(now_ms)))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -109,6 +125,8 @@ impl TryFrom<protobuf::cluster_gateway::WhipConnectRequest> for WhipConnectReq {
             ip: value.ip.parse().map_err(|_| ())?,
             user_agent: value.user_agent,
             extra_data: value.extra_data,
+            deadline_ms: value.deadline_ms,
+            tags: value.tags,
         })
     }
 }
@@ -125,6 +143,8 @@ impl From<WhipConnectReq> for protobuf::cluster_gateway::WhipConnectRequest {
             peer: val.peer.into(),
             record: val.record,
             extra_data: val.extra_data,
+            deadline_ms: val.deadline_ms,
+            tags: val.tags,
         }
     }
 }
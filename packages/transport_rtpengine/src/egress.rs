@@ -0,0 +1,417 @@
+//! Plain RTP egress. The session subscribes a single room track through a normal local track
+//! and forwards the RTP payload as-is to a fixed UDP destination, which can be an ffmpeg transcoder or a SIP gateway.
+//! Video is only forwarded after the first key frame, so the receiver can start decoding immediately.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use media_server_core::{
+    endpoint::{EndpointEvent, EndpointLocalTrackConfig, EndpointLocalTrackEvent, EndpointLocalTrackReq, EndpointReq},
+    transport::{LocalTrackEvent, LocalTrackId, Transport, TransportEvent, TransportInput, TransportOutput, TransportState},
+};
+use media_server_protocol::{
+    endpoint::{PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackPriority, TrackSource},
+    media::{MediaCodec, MediaKind, MediaPacket},
+    transport::RpcError,
+};
+use media_server_utils::Count;
+use sans_io_runtime::{
+    backend::{BackendIncoming, BackendOutgoing},
+    collections::DynamicDeque,
+    return_if_none, TaskSwitcherChild,
+};
+
+use crate::{
+    transport::{ExtIn, ExtOut},
+    RtpEngineError,
+};
+
+const LOCAL_TRACK: LocalTrackId = LocalTrackId::build(0);
+const DEFAULT_PRIORITY: TrackPriority = TrackPriority::build(1);
+const KEY_FRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+const OPUS_PT: u8 = 111;
+const VP8_PT: u8 = 96;
+const VP9_PT: u8 = 98;
+const H264_PT: u8 = 102;
+
+pub struct TransportRtpEgress {
+    _c: Count<Self>,
+    room: RoomId,
+    peer: PeerId,
+    source: TrackSource,
+    kind: MediaKind,
+    dest: SocketAddr,
+    ssrc: u32,
+    udp_slot: Option<usize>,
+    attached: bool,
+    /// Some when we are waiting for a key frame, with the last time we requested it
+    waiting_key: Option<Instant>,
+    queue: DynamicDeque<TransportOutput<ExtOut>, 4>,
+    shutdown: bool,
+}
+
+impl TransportRtpEgress {
+    /// Create egress session, return the session and the sdp which describes the stream for the receiver
+    pub fn new(room: RoomId, peer: PeerId, source: TrackSource, kind: MediaKind, dest: SocketAddr, listen_ip: IpAddr, ssrc: u32) -> (Self, String) {
+        log::info!("[TransportRtpEgress] create {kind} egress from {}/{} to {dest}", source.peer, source.track);
+        let sdp = sdp_builder(kind, dest);
+        (
+            Self {
+                _c: Default::default(),
+                room,
+                peer,
+                source,
+                kind,
+                dest,
+                ssrc,
+                udp_slot: None,
+                attached: false,
+                waiting_key: None,
+                queue: DynamicDeque::from([
+                    TransportOutput::Net(BackendOutgoing::UdpListen {
+                        addr: SocketAddr::new(listen_ip, 0),
+                        reuse: false,
+                    }),
+                    TransportOutput::Event(TransportEvent::State(TransportState::Connecting(dest.ip()))),
+                ]),
+                shutdown: false,
+            },
+            sdp,
+        )
+    }
+
+    fn on_backend(&mut self, event: BackendIncoming) {
+        match event {
+            BackendIncoming::UdpListenResult { bind, result } => match result {
+                Ok((addr, slot)) => {
+                    log::info!("[TransportRtpEgress] bind {bind} => {addr} with slot {slot} => join room {}", self.room);
+                    self.udp_slot = Some(slot);
+                    self.queue.push_back(TransportOutput::Event(TransportEvent::State(TransportState::Connected(self.dest.ip()))));
+                    self.queue
+                        .push_back(TransportOutput::Event(TransportEvent::LocalTrack(LOCAL_TRACK, LocalTrackEvent::Started(self.kind))));
+                    self.queue.push_back(TransportOutput::RpcReq(
+                        0.into(),
+                        EndpointReq::JoinRoom(
+                            self.room.clone(),
+                            self.peer.clone(),
+                            PeerMeta { metadata: None, extra_data: None },
+                            RoomInfoPublish { peer: false, tracks: false },
+                            RoomInfoSubscribe { peers: false, tracks: true },
+                            None,
+                        ),
+                    ));
+                }
+                Err(err) => {
+                    log::error!("[TransportRtpEgress] bind {bind} failed {err:?}");
+                    self.queue.push_back(TransportOutput::Event(TransportEvent::State(TransportState::Disconnected(None))));
+                }
+            },
+            BackendIncoming::UdpPacket { from, data, .. } => {
+                // receiver can send RTCP or keepalive back, we don't process it for now
+                log::debug!("[TransportRtpEgress] ignore packet from {from} len {}", data.len());
+            }
+        }
+    }
+
+    fn on_event(&mut self, now: Instant, event: EndpointEvent) {
+        match event {
+            EndpointEvent::PeerTrackStarted(peer, track, meta) => {
+                if peer != self.source.peer || track != self.source.track {
+                    return;
+                }
+                if meta.kind != self.kind {
+                    log::warn!("[TransportRtpEgress] source {peer}/{track} is {} but egress is {} => skip", meta.kind, self.kind);
+                    return;
+                }
+                log::info!("[TransportRtpEgress] source {peer}/{track} started => attach");
+                self.attached = true;
+                self.queue.push_back(TransportOutput::RpcReq(
+                    1.into(),
+                    EndpointReq::LocalTrack(
+                        LOCAL_TRACK,
+                        EndpointLocalTrackReq::Attach(
+                            self.source.clone(),
+                            EndpointLocalTrackConfig {
+                                priority: DEFAULT_PRIORITY,
+                                max_spatial: 2,
+                                max_temporal: 2,
+                                min_spatial: None,
+                                min_temporal: None,
+                                bitrate_priority: Default::default(),
+                                smooth_playout: false,
+                            },
+                        ),
+                    ),
+                ));
+                if self.kind.is_video() {
+                    self.request_key_frame(now);
+                }
+            }
+            EndpointEvent::PeerTrackStopped(peer, track, _) => {
+                if !self.attached || peer != self.source.peer || track != self.source.track {
+                    return;
+                }
+                log::info!("[TransportRtpEgress] source {peer}/{track} stopped => detach");
+                self.attached = false;
+                self.waiting_key = None;
+                self.queue
+                    .push_back(TransportOutput::RpcReq(1.into(), EndpointReq::LocalTrack(LOCAL_TRACK, EndpointLocalTrackReq::Detach())));
+            }
+            EndpointEvent::LocalMediaTrack(_track, EndpointLocalTrackEvent::Media(media)) => self.on_media(media),
+            _ => {}
+        }
+    }
+
+    fn on_media(&mut self, media: MediaPacket) {
+        let slot = return_if_none!(self.udp_slot);
+        if self.waiting_key.is_some() {
+            if !media.meta.is_video_key() {
+                return;
+            }
+            log::info!("[TransportRtpEgress] got first key frame => start forwarding to {}", self.dest);
+            self.waiting_key = None;
+        }
+
+        let pt = payload_type(media.meta.codec());
+        match rtp_rs::RtpPacketBuilder::new()
+            .marked(media.marker)
+            .payload_type(pt)
+            .ssrc(self.ssrc)
+            .timestamp(media.ts)
+            .sequence(media.seq.into())
+            .payload(&media.data)
+            .build()
+        {
+            Ok(data) => self.queue.push_back(TransportOutput::Net(BackendOutgoing::UdpPacket {
+                slot,
+                to: self.dest,
+                data: data.into(),
+            })),
+            Err(e) => log::warn!("[TransportRtpEgress] build rtp packet error {e:?}"),
+        }
+    }
+
+    fn request_key_frame(&mut self, now: Instant) {
+        self.waiting_key = Some(now);
+        self.queue.push_back(TransportOutput::Event(TransportEvent::LocalTrack(LOCAL_TRACK, LocalTrackEvent::RequestKeyFrame)));
+    }
+}
+
+impl Transport<ExtIn, ExtOut> for TransportRtpEgress {
+    fn on_tick(&mut self, now: Instant) {
+        if let Some(requested_at) = self.waiting_key {
+            if self.attached && now >= requested_at + KEY_FRAME_REQUEST_INTERVAL {
+                log::debug!("[TransportRtpEgress] still waiting key frame => request again");
+                self.request_key_frame(now);
+            }
+        }
+    }
+
+    fn on_input(&mut self, now: Instant, input: TransportInput<ExtIn>) {
+        match input {
+            TransportInput::Net(event) => self.on_backend(event),
+            TransportInput::Endpoint(event) => self.on_event(now, event),
+            TransportInput::RpcRes(_, res) => {
+                log::info!("[TransportRtpEgress] on rpc_res {res:?}");
+            }
+            TransportInput::Ext(ext) => match ext {
+                ExtIn::SetAnswer(req_id, _) => {
+                    self.queue
+                        .push_back(TransportOutput::Ext(ExtOut::SetAnswer(req_id, Err(RpcError::new2(RtpEngineError::EgressNotSupported)))));
+                }
+                ExtIn::Disconnect(req_id) => {
+                    log::info!("[TransportRtpEgress] switched to disconnected with close action from client");
+                    self.queue.push_back(TransportOutput::Ext(ExtOut::Disconnect(req_id)));
+                    self.queue.push_back(TransportOutput::Event(TransportEvent::State(TransportState::Disconnected(None))));
+                }
+                ExtIn::Close => {
+                    log::info!("[TransportRtpEgress] switched to disconnected with close action from server");
+                    self.queue.push_back(TransportOutput::Event(TransportEvent::State(TransportState::Disconnected(None))));
+                }
+            },
+        }
+    }
+
+    fn on_shutdown(&mut self, _now: Instant) {
+        if !self.shutdown {
+            log::info!("[TransportRtpEgress] shutdown request");
+            self.shutdown = true;
+        }
+    }
+}
+
+impl TaskSwitcherChild<TransportOutput<ExtOut>> for TransportRtpEgress {
+    type Time = Instant;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> TransportOutput<ExtOut> {
+        TransportOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: Instant) -> Option<TransportOutput<ExtOut>> {
+        self.queue.pop_front()
+    }
+}
+
+fn payload_type(codec: MediaCodec) -> u8 {
+    match codec {
+        MediaCodec::Opus => OPUS_PT,
+        MediaCodec::Vp8 => VP8_PT,
+        MediaCodec::Vp9(_) => VP9_PT,
+        MediaCodec::H264(_) => H264_PT,
+    }
+}
+
+/// Sdp for the receiver side, it can be passed directly to ffmpeg with `-protocol_whitelist file,udp,rtp -i egress.sdp`
+fn sdp_builder(kind: MediaKind, dest: SocketAddr) -> String {
+    let ip_ver = if dest.is_ipv4() {
+        "IP4"
+    } else {
+        "IP6"
+    };
+    let ip = dest.ip();
+    let port = dest.port();
+    let media = match kind {
+        MediaKind::Audio => format!("m=audio {port} RTP/AVP {OPUS_PT}\na=rtpmap:{OPUS_PT} opus/48000/2\n"),
+        MediaKind::Video => format!(
+            "m=video {port} RTP/AVP {VP8_PT} {VP9_PT} {H264_PT}\na=rtpmap:{VP8_PT} VP8/90000\na=rtpmap:{VP9_PT} VP9/90000\na=rtpmap:{H264_PT} H264/90000\na=fmtp:{H264_PT} packetization-mode=1\n"
+        ),
+    };
+    format!(
+        "v=0
+o=- 0 0 IN {ip_ver} {ip}
+s=egress
+c=IN {ip_ver} {ip}
+t=0 0
+{media}a=recvonly
+"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    };
+
+    use media_server_core::{
+        endpoint::{EndpointEvent, EndpointLocalTrackEvent, EndpointLocalTrackReq, EndpointReq},
+        transport::{LocalTrackEvent, Transport, TransportEvent, TransportInput, TransportOutput},
+    };
+    use media_server_protocol::{
+        endpoint::{TrackMeta, TrackSource},
+        media::{MediaKind, MediaMeta, MediaPacket},
+    };
+    use sans_io_runtime::{
+        backend::{BackendIncoming, BackendOutgoing},
+        TaskSwitcherChild,
+    };
+
+    use super::{TransportRtpEgress, LOCAL_TRACK, VP8_PT};
+
+    fn vp8_pkt(seq: u16, key: bool) -> MediaPacket {
+        MediaPacket {
+            ts: seq as u32 * 3000,
+            seq,
+            marker: true,
+            nackable: true,
+            layers: None,
+            meta: MediaMeta::Vp8 { key, sim: None, rotation: None },
+            data: vec![1, 2, 3, 4],
+        }
+    }
+
+    fn pop_all(egress: &mut TransportRtpEgress, now: Instant) -> Vec<TransportOutput<super::ExtOut>> {
+        let mut outs = vec![];
+        while let Some(out) = egress.pop_output(now) {
+            outs.push(out);
+        }
+        outs
+    }
+
+    #[test]
+    fn egress_forward_rtp_after_key_frame() {
+        let receiver = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5004);
+        let source = TrackSource {
+            peer: "peer1".to_string().into(),
+            track: "video_main".to_string().into(),
+        };
+        let now = Instant::now();
+        let (mut egress, sdp) = TransportRtpEgress::new(
+            "room1".to_string().into(),
+            "egress1".to_string().into(),
+            source.clone(),
+            MediaKind::Video,
+            receiver,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            1234,
+        );
+        assert!(sdp.contains("m=video 5004 RTP/AVP"));
+        pop_all(&mut egress, now);
+
+        egress.on_input(
+            now,
+            TransportInput::Net(BackendIncoming::UdpListenResult {
+                bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                result: Ok((SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000), 1)),
+            }),
+        );
+        let outs = pop_all(&mut egress, now);
+        assert!(outs.contains(&TransportOutput::Event(TransportEvent::LocalTrack(LOCAL_TRACK, LocalTrackEvent::Started(MediaKind::Video)))));
+        assert!(outs.iter().any(|o| matches!(o, TransportOutput::RpcReq(_, EndpointReq::JoinRoom(..)))));
+
+        // source track started => attach and request key frame
+        egress.on_input(
+            now,
+            TransportInput::Endpoint(EndpointEvent::PeerTrackStarted(source.peer.clone(), source.track.clone(), TrackMeta::default_video())),
+        );
+        let outs = pop_all(&mut egress, now);
+        assert!(outs
+            .iter()
+            .any(|o| matches!(o, TransportOutput::RpcReq(_, EndpointReq::LocalTrack(_, EndpointLocalTrackReq::Attach(s, _))) if *s == source)));
+        assert!(outs.contains(&TransportOutput::Event(TransportEvent::LocalTrack(LOCAL_TRACK, LocalTrackEvent::RequestKeyFrame))));
+
+        // non key frame is dropped before first key frame
+        egress.on_input(
+            now,
+            TransportInput::Endpoint(EndpointEvent::LocalMediaTrack(LOCAL_TRACK, EndpointLocalTrackEvent::Media(vp8_pkt(1, false)))),
+        );
+        assert_eq!(pop_all(&mut egress, now), vec![]);
+
+        // key frame is not received in time => request again
+        egress.on_tick(now + Duration::from_secs(1));
+        assert_eq!(
+            pop_all(&mut egress, now),
+            vec![TransportOutput::Event(TransportEvent::LocalTrack(LOCAL_TRACK, LocalTrackEvent::RequestKeyFrame))]
+        );
+
+        // key frame and following packets are sent to receiver
+        for (seq, key) in [(2, true), (3, false)] {
+            egress.on_input(
+                now,
+                TransportInput::Endpoint(EndpointEvent::LocalMediaTrack(LOCAL_TRACK, EndpointLocalTrackEvent::Media(vp8_pkt(seq, key)))),
+            );
+            let outs = pop_all(&mut egress, now);
+            assert_eq!(outs.len(), 1);
+            match &outs[0] {
+                TransportOutput::Net(BackendOutgoing::UdpPacket { slot, to, data }) => {
+                    assert_eq!(*slot, 1);
+                    assert_eq!(*to, receiver);
+                    let rtp = rtp_rs::RtpReader::new(data).expect("Should be rtp");
+                    assert_eq!(rtp.payload_type(), VP8_PT);
+                    assert_eq!(rtp.ssrc(), 1234);
+                    assert_eq!(u16::from(rtp.sequence_number()), seq);
+                    assert_eq!(rtp.payload(), &[1, 2, 3, 4]);
+                }
+                _ => panic!("Should be udp packet"),
+            }
+        }
+    }
+}
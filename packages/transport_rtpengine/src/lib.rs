@@ -1,3 +1,4 @@
+mod egress;
 mod transport;
 mod worker;
 
@@ -11,4 +12,6 @@ pub enum RtpEngineError {
     InternalServerError = 0x2001,
     SdpConnectionNotFound = 0x2002,
     SdpMediaNotFound = 0x2003,
+    EgressNotSupported = 0x2004,
+    EgressDestNotAllowed = 0x2005,
 }
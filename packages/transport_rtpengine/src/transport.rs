@@ -10,7 +10,7 @@ use media_server_codecs::{
     AudioTranscoder,
 };
 use media_server_core::{
-    endpoint::{EndpointEvent, EndpointLocalTrackConfig, EndpointLocalTrackEvent, EndpointLocalTrackReq, EndpointReq},
+    endpoint::{EndpointEvent, EndpointLocalTrackConfig, EndpointLocalTrackEvent, EndpointLocalTrackReq, EndpointReq, OpusParams},
     transport::{LocalTrackEvent, LocalTrackId, RemoteTrackEvent, RemoteTrackId, Transport, TransportError, TransportEvent, TransportInput, TransportOutput, TransportState},
 };
 use media_server_protocol::{
@@ -18,7 +18,7 @@ use media_server_protocol::{
     media::{MediaKind, MediaMeta, MediaPacket},
     transport::{RpcError, RpcResult},
 };
-use media_server_utils::Count;
+use media_server_utils::{Count, RtpIngest, RtpIngestGuard, RtpIngestPolicy};
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
     collections::DynamicDeque,
@@ -39,6 +39,8 @@ const DEFAULT_PRIORITY: TrackPriority = TrackPriority::build(1);
 pub enum ExtIn {
     SetAnswer(u64, String),
     Disconnect(u64),
+    /// Close from server side, used by bulk close, no response is sent back
+    Close,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -62,11 +64,14 @@ pub struct TransportRtpEngine {
     pcma_to_opus: AudioTranscoder<PcmaDecoder, OpusEncoder>,
     opus_to_pcma: AudioTranscoder<OpusDecoder, PcmaEncoder>,
     tmp_buf: [u8; 1500],
+    rtp_ingest: RtpIngestGuard,
     shutdown: bool,
 }
 
 impl TransportRtpEngine {
-    pub fn new_offer(room: RoomId, peer: PeerId, public_ip: IpAddr, listen_ip: IpAddr) -> Result<(Self, String), String> {
+    /// `opus` is used for the opus encoder of audio which is transcoded from the SIP side
+    /// `rtp_ingest` decides how malformed or oversized RTP from the SIP side is handled
+    pub fn new_offer(room: RoomId, peer: PeerId, public_ip: IpAddr, listen_ip: IpAddr, opus: OpusParams, rtp_ingest: RtpIngestPolicy) -> Result<(Self, String), String> {
         let socket = std::net::UdpSocket::bind(SocketAddr::new(listen_ip, 0)).map_err(|e| e.to_string())?;
         let port = socket.local_addr().map_err(|e| e.to_string())?.port();
         let answer = sdp_builder(public_ip, port);
@@ -90,16 +95,17 @@ impl TransportRtpEngine {
                     }),
                     TransportOutput::Event(TransportEvent::State(TransportState::New)),
                 ]),
-                pcma_to_opus: AudioTranscoder::new(PcmaDecoder::default(), OpusEncoder::default()),
+                pcma_to_opus: AudioTranscoder::new(PcmaDecoder::default(), OpusEncoder::new(opus.max_average_bitrate, opus.complexity)),
                 opus_to_pcma: AudioTranscoder::new(OpusDecoder::default(), PcmaEncoder::default()),
                 tmp_buf: [0; 1500],
+                rtp_ingest: RtpIngestGuard::new(rtp_ingest),
                 shutdown: false,
             },
             answer,
         ))
     }
 
-    pub fn new_answer(room: RoomId, peer: PeerId, public_ip: IpAddr, listen_ip: IpAddr, offer: &str) -> Result<(Self, String), String> {
+    pub fn new_answer(room: RoomId, peer: PeerId, public_ip: IpAddr, listen_ip: IpAddr, offer: &str, opus: OpusParams, rtp_ingest: RtpIngestPolicy) -> Result<(Self, String), String> {
         let mut offer = SessionDescription::try_from(offer.to_string()).map_err(|e| e.to_string())?;
         let dest_ip: IpAddr = if let Some(conn) = offer.connection {
             conn.connection_address.base
@@ -134,9 +140,10 @@ impl TransportRtpEngine {
                     }),
                     TransportOutput::Event(TransportEvent::State(TransportState::Connecting(dest_ip))),
                 ]),
-                pcma_to_opus: AudioTranscoder::new(PcmaDecoder::default(), OpusEncoder::default()),
+                pcma_to_opus: AudioTranscoder::new(PcmaDecoder::default(), OpusEncoder::new(opus.max_average_bitrate, opus.complexity)),
                 opus_to_pcma: AudioTranscoder::new(OpusDecoder::default(), PcmaEncoder::default()),
                 tmp_buf: [0; 1500],
+                rtp_ingest: RtpIngestGuard::new(rtp_ingest),
                 shutdown: false,
             },
             answer,
@@ -201,6 +208,10 @@ impl Transport<ExtIn, ExtOut> for TransportRtpEngine {
                     self.queue.push_back(TransportOutput::Ext(ExtOut::Disconnect(req_id)));
                     self.queue.push_back(TransportOutput::Event(TransportEvent::State(TransportState::Disconnected(None))));
                 }
+                ExtIn::Close => {
+                    log::info!("[TransportRtpEngine] switched to disconnected with close action from server");
+                    self.queue.push_back(TransportOutput::Event(TransportEvent::State(TransportState::Disconnected(None))));
+                }
             },
         }
     }
@@ -231,6 +242,24 @@ impl TransportRtpEngine {
                 let buf = data.deref();
                 let pkt_type = pkt_type(buf);
                 if let Some(MultiplexKind::Rtp) = pkt_type {
+                    // plain rtp is not authenticated, only packets from the negotiated remote count toward abusive close
+                    let ingest = if self.remote == Some(from) {
+                        self.rtp_ingest.on_verified_rtp(now, buf)
+                    } else {
+                        self.rtp_ingest.on_unverified_rtp(now, buf)
+                    };
+                    match ingest {
+                        RtpIngest::Accept => {}
+                        RtpIngest::Drop => return,
+                        RtpIngest::Abusive => {
+                            if !self.shutdown {
+                                log::warn!("[TransportRtpEngine] {} bad rtp packets from {from} => close as abusive", self.rtp_ingest.bad_packets());
+                                self.queue.push_back(TransportOutput::Event(TransportEvent::State(TransportState::Disconnected(None))));
+                                self.shutdown = true;
+                            }
+                            return;
+                        }
+                    }
                     if let Ok(rtp) = rtp_rs::RtpReader::new(buf) {
                         self.last_recv_rtp = Some(now);
                         log::debug!(
@@ -309,6 +338,8 @@ impl TransportRtpEngine {
                                     max_temporal: 2,
                                     min_spatial: None,
                                     min_temporal: None,
+                                    bitrate_priority: Default::default(),
+                                    smooth_playout: false,
                                 },
                             ),
                         ),
@@ -382,7 +413,7 @@ pub enum MultiplexKind {
 }
 
 fn pkt_type(value: &[u8]) -> Option<MultiplexKind> {
-    let byte0 = value[0];
+    let byte0 = *value.first()?;
     let len = value.len();
 
     if (128..192).contains(&byte0) && len > 2 {
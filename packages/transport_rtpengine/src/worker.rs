@@ -1,25 +1,94 @@
-use std::{collections::VecDeque, net::IpAddr, time::Instant};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
 
 use media_server_core::{
     cluster::{ClusterEndpointControl, ClusterEndpointEvent, ClusterRoomHash},
-    endpoint::{Endpoint, EndpointCfg, EndpointInput, EndpointOutput},
+    endpoint::{Endpoint, EndpointCfg, EndpointInput, EndpointOutput, MultiRoomPolicy, OpusConfig, PlayoutConfig, RelayGraceConfig, SessionMaxDurationConfig, TrackLimits},
+    transport::{Transport, TransportInput, TransportOutput},
 };
 use media_server_protocol::{
-    endpoint::{PeerId, RoomId},
+    endpoint::{PeerId, RoomId, TrackSource},
+    media::MediaKind,
     multi_tenancy::{AppContext, AppId},
     protobuf::cluster_connector::peer_event,
     record::SessionRecordEvent,
     transport::{RpcError, RpcResult},
 };
+use media_server_utils::{node_egress_budget, RtpEgressAllowlist, RtpIngestPolicy};
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
     group_owner_type, return_if_some, TaskGroup, TaskGroupOutput, TaskSwitcherChild,
 };
 
-use crate::transport::{ExtIn, ExtOut, TransportRtpEngine};
+use crate::{
+    egress::TransportRtpEgress,
+    transport::{ExtIn, ExtOut, TransportRtpEngine},
+    RtpEngineError,
+};
 
 group_owner_type!(RtpEngineSession);
 
+/// Sessions of rtpengine worker, sip sessions or plain rtp egress sessions
+enum SessionTransport {
+    Engine(TransportRtpEngine),
+    Egress(TransportRtpEgress),
+}
+
+impl Transport<ExtIn, ExtOut> for SessionTransport {
+    fn on_tick(&mut self, now: Instant) {
+        match self {
+            Self::Engine(tran) => tran.on_tick(now),
+            Self::Egress(tran) => tran.on_tick(now),
+        }
+    }
+
+    fn on_input(&mut self, now: Instant, input: TransportInput<ExtIn>) {
+        match self {
+            Self::Engine(tran) => tran.on_input(now, input),
+            Self::Egress(tran) => tran.on_input(now, input),
+        }
+    }
+
+    fn on_shutdown(&mut self, now: Instant) {
+        match self {
+            Self::Engine(tran) => tran.on_shutdown(now),
+            Self::Egress(tran) => tran.on_shutdown(now),
+        }
+    }
+}
+
+impl TaskSwitcherChild<TransportOutput<ExtOut>> for SessionTransport {
+    type Time = Instant;
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Engine(tran) => tran.is_empty(),
+            Self::Egress(tran) => tran.is_empty(),
+        }
+    }
+
+    fn empty_event(&self) -> TransportOutput<ExtOut> {
+        TransportOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, now: Instant) -> Option<TransportOutput<ExtOut>> {
+        match self {
+            Self::Engine(tran) => tran.pop_output(now),
+            Self::Egress(tran) => tran.pop_output(now),
+        }
+    }
+}
+
+/// Which app and room a session belongs to, used for bulk closing
+struct SessionSlot {
+    app: AppId,
+    room: ClusterRoomHash,
+    closing: bool,
+}
+
 #[allow(clippy::large_enum_variant)]
 pub enum GroupInput {
     Net(usize, BackendIncoming),
@@ -42,39 +111,133 @@ pub enum GroupOutput {
 pub struct MediaWorkerRtpEngine {
     listen_ip: IpAddr,
     public_ip: IpAddr,
-    endpoints: TaskGroup<EndpointInput<ExtIn>, EndpointOutput<ExtOut>, Endpoint<TransportRtpEngine, ExtIn, ExtOut>, 16>,
+    relay_grace: RelayGraceConfig,
+    playout: PlayoutConfig,
+    track_limits: TrackLimits,
+    opus: OpusConfig,
+    max_duration: SessionMaxDurationConfig,
+    rtp_ingest: RtpIngestPolicy,
+    egress_allow: RtpEgressAllowlist,
+    endpoints: TaskGroup<EndpointInput<ExtIn>, EndpointOutput<ExtOut>, Endpoint<SessionTransport, ExtIn, ExtOut>, 16>,
+    sessions: HashMap<usize, SessionSlot>,
     queue: VecDeque<GroupOutput>,
     shutdown: bool,
 }
 
 impl MediaWorkerRtpEngine {
-    pub fn new(listen_ip: IpAddr, public_ip: IpAddr) -> Self {
+    pub fn new(
+        listen_ip: IpAddr,
+        public_ip: IpAddr,
+        relay_grace: RelayGraceConfig,
+        playout: PlayoutConfig,
+        track_limits: TrackLimits,
+        opus: OpusConfig,
+        max_duration: SessionMaxDurationConfig,
+        rtp_ingest: RtpIngestPolicy,
+        egress_allow: RtpEgressAllowlist,
+    ) -> Self {
         Self {
             listen_ip,
             public_ip,
+            relay_grace,
+            playout,
+            track_limits,
+            opus,
+            max_duration,
+            rtp_ingest,
+            egress_allow,
             endpoints: TaskGroup::default(),
+            sessions: HashMap::new(),
             queue: VecDeque::new(),
             shutdown: false,
         }
     }
 
     pub fn spawn(&mut self, app: AppContext, room: RoomId, peer: PeerId, record: bool, session_id: u64, offer: Option<&str>) -> RpcResult<(usize, String)> {
+        let slot = SessionSlot {
+            app: app.app.clone(),
+            room: ClusterRoomHash::generate(&app, &room),
+            closing: false,
+        };
+        let opus = self.opus.params(&app.app);
         let (tran, answer) = if let Some(offer) = offer {
-            TransportRtpEngine::new_answer(room, peer, self.public_ip, self.listen_ip, offer).map_err(|e| RpcError::new(1000_u32, &e))?
+            TransportRtpEngine::new_answer(room, peer, self.public_ip, self.listen_ip, offer, opus, self.rtp_ingest).map_err(|e| RpcError::new(1000_u32, &e))?
         } else {
-            TransportRtpEngine::new_offer(room, peer, self.public_ip, self.listen_ip).map_err(|e| RpcError::new(1000_u32, &e))?
+            TransportRtpEngine::new_offer(room, peer, self.public_ip, self.listen_ip, opus, self.rtp_ingest).map_err(|e| RpcError::new(1000_u32, &e))?
         };
+        let max_duration = self.max_duration.max_duration(&app.app);
         let cfg = EndpointCfg {
             app,
             max_ingress_bitrate: 2_500_000,
             max_egress_bitrate: 2_500_000,
             record,
+            metrics: false,
+            relay_grace: self.relay_grace,
+            playout: self.playout,
+            track_limits: self.track_limits,
+            multi_room: MultiRoomPolicy::default(),
+            egress_budget: node_egress_budget(),
+            max_duration,
+            max_duration_warning: self.max_duration.warning,
         };
-        let endpoint = Endpoint::new(session_id, cfg, tran);
+        let endpoint = Endpoint::new(session_id, cfg, SessionTransport::Engine(tran));
         let index = self.endpoints.add_task(endpoint);
+        self.sessions.insert(index, slot);
         Ok((index, answer))
     }
 
+    /// Spawn a plain rtp egress session which forwards the source track to `dest`, return the sdp for the receiver
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn_egress(&mut self, app: AppContext, room: RoomId, peer: PeerId, source: TrackSource, kind: MediaKind, dest: SocketAddr, session_id: u64) -> RpcResult<(usize, String)> {
+        if !self.egress_allow.allows(&dest) {
+            log::warn!("[MediaWorkerRtpEngine] reject egress to {dest} which is not allowed");
+            return Err(RpcError::new2(RtpEngineError::EgressDestNotAllowed));
+        }
+        let slot = SessionSlot {
+            app: app.app.clone(),
+            room: ClusterRoomHash::generate(&app, &room),
+            closing: false,
+        };
+        let (tran, sdp) = TransportRtpEgress::new(room, peer, source, kind, dest, self.listen_ip, session_id as u32);
+        let cfg = EndpointCfg {
+            app,
+            max_ingress_bitrate: 2_500_000,
+            max_egress_bitrate: 2_500_000,
+            record: false,
+            metrics: false,
+            relay_grace: self.relay_grace,
+            playout: self.playout,
+            track_limits: self.track_limits,
+            multi_room: MultiRoomPolicy::default(),
+            egress_budget: node_egress_budget(),
+            // egress lives as long as its source, it is not a client session
+            max_duration: None,
+            max_duration_warning: self.max_duration.warning,
+        };
+        let endpoint = Endpoint::new(session_id, cfg, SessionTransport::Egress(tran));
+        let index = self.endpoints.add_task(endpoint);
+        self.sessions.insert(index, slot);
+        Ok((index, sdp))
+    }
+
+    /// Close all sessions of the app, or only sessions inside a room if it is provided.
+    /// Sessions which are already closing are skipped. Return number of sessions which are closed by this call
+    pub fn close_sessions(&mut self, now: Instant, app: &AppId, room: Option<ClusterRoomHash>) -> usize {
+        let mut indexes = vec![];
+        for (index, slot) in self.sessions.iter_mut() {
+            if slot.closing || slot.app != *app || room.is_some_and(|room| slot.room != room) {
+                continue;
+            }
+            slot.closing = true;
+            indexes.push(*index);
+        }
+        for index in indexes.iter() {
+            log::info!("[MediaWorkerRtpEngine] bulk close session {index}");
+            self.endpoints.on_event(now, *index, EndpointInput::Ext(ExtIn::Close));
+        }
+        indexes.len()
+    }
+
     fn process_output(&mut self, index: usize, out: EndpointOutput<ExtOut>) -> GroupOutput {
         match out {
             EndpointOutput::Net(net) => GroupOutput::Net(index, net),
@@ -84,6 +247,7 @@ impl MediaWorkerRtpEngine {
             EndpointOutput::OnResourceEmpty => {
                 log::info!("[TransportRtpEngine] destroy endpoint {index}");
                 self.endpoints.remove_task(index);
+                self.sessions.remove(&index);
                 GroupOutput::Continue
             }
             EndpointOutput::Ext(ext) => GroupOutput::Ext(RtpEngineSession(index), ext),
@@ -118,6 +282,9 @@ impl MediaWorkerRtpEngine {
                     ExtIn::Disconnect(req_id) => {
                         self.endpoints.on_event(now, owner.index(), EndpointInput::Ext(ExtIn::Disconnect(req_id)));
                     }
+                    ExtIn::Close => {
+                        self.endpoints.on_event(now, owner.index(), EndpointInput::Ext(ExtIn::Close));
+                    }
                 }
             }
         }
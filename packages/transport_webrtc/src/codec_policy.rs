@@ -0,0 +1,134 @@
+//! Server side video codec preference. When an offer contains multiple video codecs we only answer one of them,
+//! and peers in the same room are pinned to the same codec so media can be relayed without transcoding.
+
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VideoCodec {
+    Vp8,
+    Vp9,
+    H264,
+}
+
+impl FromStr for VideoCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "vp8" => Ok(Self::Vp8),
+            "vp9" => Ok(Self::Vp9),
+            "h264" => Ok(Self::H264),
+            _ => Err(format!("unsupported video codec {s}")),
+        }
+    }
+}
+
+impl Display for VideoCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vp8 => f.write_str("VP8"),
+            Self::Vp9 => f.write_str("VP9"),
+            Self::H264 => f.write_str("H264"),
+        }
+    }
+}
+
+/// Collect video codecs from rtpmap lines of video m-sections, in order of appearance
+pub fn offer_video_codecs(offer: &str) -> Vec<VideoCodec> {
+    let mut codecs = vec![];
+    let mut in_video = false;
+    for line in offer.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            in_video = media.starts_with("video");
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            if !in_video {
+                continue;
+            }
+            let name = rtpmap.split(' ').nth(1).and_then(|c| c.split('/').next()).unwrap_or_default();
+            if let Ok(codec) = VideoCodec::from_str(name) {
+                if !codecs.contains(&codec) {
+                    codecs.push(codec);
+                }
+            }
+        }
+    }
+    codecs
+}
+
+/// Rtpmap names which are not media codecs, they only work together with a media codec
+const NON_MEDIA_CODECS: [&str; 5] = ["rtx", "red", "ulpfec", "flexfec-03", "telephone-event"];
+
+/// Collect media codec names from rtpmap lines of audio and video m-sections, in order of appearance
+pub fn sdp_media_codecs(sdp: &str) -> Vec<String> {
+    let mut codecs: Vec<String> = vec![];
+    let mut in_media = false;
+    for line in sdp.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            in_media = media.starts_with("audio") || media.starts_with("video");
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            if !in_media {
+                continue;
+            }
+            let name = rtpmap.split(' ').nth(1).and_then(|c| c.split('/').next()).unwrap_or_default();
+            if !name.is_empty() && !NON_MEDIA_CODECS.iter().any(|c| c.eq_ignore_ascii_case(name)) && !codecs.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+                codecs.push(name.to_string());
+            }
+        }
+    }
+    codecs
+}
+
+/// Media codecs which are enabled for a session, same as the codec config of rtc builder
+pub fn supported_media_codecs(h264_enabled: bool, video_codec: Option<VideoCodec>) -> Vec<String> {
+    let mut codecs = vec!["opus".to_string()];
+    for codec in [VideoCodec::Vp8, VideoCodec::Vp9, VideoCodec::H264] {
+        if video_codec.map_or(true, |c| c == codec) && (codec != VideoCodec::H264 || h264_enabled) {
+            codecs.push(codec.to_string());
+        }
+    }
+    codecs
+}
+
+/// Select the only video codec which will be answered, None for answering all supported codecs.
+///
+/// - `preferred` empty: policy is disabled
+/// - room already has a codec and the offer supports it: keep the room codec
+/// - otherwise: first preferred codec which the offer supports, or the first preferred codec which will reject video
+pub fn select_video_codec(offered: &[VideoCodec], preferred: &[VideoCodec], room_codec: Option<VideoCodec>) -> Option<VideoCodec> {
+    if preferred.is_empty() {
+        return None;
+    }
+    if let Some(room_codec) = room_codec.filter(|c| offered.contains(c)) {
+        return Some(room_codec);
+    }
+    preferred.iter().find(|c| offered.contains(c)).or(preferred.first()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{offer_video_codecs, sdp_media_codecs, select_video_codec, supported_media_codecs, VideoCodec};
+
+    #[test]
+    fn parse_offer_video_codecs() {
+        let offer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 98 102 103\r\na=rtpmap:96 VP8/90000\r\na=rtpmap:98 VP9/90000\r\na=rtpmap:102 H264/90000\r\na=rtpmap:103 rtx/90000\r\n";
+        assert_eq!(offer_video_codecs(offer), vec![VideoCodec::Vp8, VideoCodec::Vp9, VideoCodec::H264]);
+    }
+
+    #[test]
+    fn parse_sdp_media_codecs() {
+        let offer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 9 0 126\r\na=rtpmap:9 G722/8000\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:126 telephone-event/8000\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 97\r\na=rtpmap:96 AV1/90000\r\na=rtpmap:97 rtx/90000\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n";
+        assert_eq!(sdp_media_codecs(offer), vec!["G722".to_string(), "PCMU".to_string(), "AV1".to_string()]);
+        assert_eq!(supported_media_codecs(true, None), vec!["opus", "VP8", "VP9", "H264"]);
+        assert_eq!(supported_media_codecs(false, Some(VideoCodec::H264)), vec!["opus"]);
+    }
+
+    #[test]
+    fn select_codec_policy() {
+        let preferred = [VideoCodec::Vp9, VideoCodec::Vp8];
+        assert_eq!(select_video_codec(&[VideoCodec::Vp8, VideoCodec::Vp9], &[], None), None);
+        assert_eq!(select_video_codec(&[VideoCodec::Vp8, VideoCodec::Vp9], &preferred, None), Some(VideoCodec::Vp9));
+        assert_eq!(select_video_codec(&[VideoCodec::Vp8, VideoCodec::Vp9], &preferred, Some(VideoCodec::Vp8)), Some(VideoCodec::Vp8));
+        assert_eq!(select_video_codec(&[VideoCodec::Vp9], &preferred, Some(VideoCodec::Vp8)), Some(VideoCodec::Vp9));
+        assert_eq!(select_video_codec(&[VideoCodec::H264], &preferred, None), Some(VideoCodec::Vp9));
+    }
+}
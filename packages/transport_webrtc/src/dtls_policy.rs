@@ -0,0 +1,790 @@
+//! DTLS hardening policy. Str0m doesn't expose DTLS version settings, so the minimum version is enforced by
+//! inspecting the handshake hello before it is passed to str0m. Certificate policy is applied on the offer fingerprints,
+//! the remote certificate itself is always verified against the fingerprint by str0m.
+//!
+//! DTLS role follows `a=setup` of the offer: `active` is answered `passive` and `passive` is answered `active`. For `actpass`
+//! offers the server role can be configured, it is applied by rewriting the offer setup before str0m negotiates, so the
+//! answer and the handshake direction of str0m always agree.
+//!
+//! DTLS-SRTP protection profiles and DTLS cipher suites can be restricted to an allowlist. Str0m doesn't expose them either,
+//! so handshakes are checked on wire: a ClientHello which offers no allowed suite is rejected, and a ServerHello in either
+//! direction which selects a suite outside the allowlist closes the session, so the negotiated suites are always allowed.
+//! Fragmented hellos are reassembled before they are checked. Only profiles and suites which str0m can negotiate are
+//! accepted in the allowlist, other names fail when the config is parsed.
+//!
+//! Default policy is same as str0m behavior: any DTLS version, any suite, any fingerprint hash and str0m choice of role for `actpass`.
+
+use std::{fmt::Display, str::FromStr};
+
+const DTLS_1_0: u16 = 0xfeff;
+const DTLS_1_2: u16 = 0xfefd;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const RECORD_HEADER_LEN: usize = 13;
+const HANDSHAKE_HEADER_LEN: usize = 12;
+/// record header 13 bytes + handshake header 12 bytes
+const HELLO_VERSION_OFFSET: usize = RECORD_HEADER_LEN + HANDSHAKE_HEADER_LEN;
+const HELLO_RANDOM_LEN: usize = 32;
+const EXTENSION_USE_SRTP: u16 = 14;
+/// Hellos are a few hundred bytes, a bigger one is not buffered for reassembly
+const MAX_HELLO_LEN: usize = 16 * 1024;
+
+/// Fingerprint hashes which are considered too weak with the strong-fingerprint policy
+const WEAK_FINGERPRINT_HASHES: [&str; 3] = ["md2", "md5", "sha-1"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DtlsVersion {
+    #[default]
+    Dtls1_0,
+    Dtls1_2,
+}
+
+impl DtlsVersion {
+    /// DTLS versions on wire are inverted, a lower value is a newer version
+    fn from_wire(version: u16) -> Option<Self> {
+        match version {
+            DTLS_1_0 => Some(Self::Dtls1_0),
+            DTLS_1_2 => Some(Self::Dtls1_2),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for DtlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "1.0" => Ok(Self::Dtls1_0),
+            "1.2" => Ok(Self::Dtls1_2),
+            _ => Err(format!("unsupported dtls version {s}")),
+        }
+    }
+}
+
+impl Display for DtlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dtls1_0 => f.write_str("1.0"),
+            Self::Dtls1_2 => f.write_str("1.2"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DtlsCertPolicy {
+    /// Remote certificate only need to match the offer fingerprint
+    #[default]
+    Fingerprint,
+    /// Same as Fingerprint, but offers with fingerprints of weak hash (md5, sha-1) are rejected
+    StrongFingerprint,
+}
+
+impl FromStr for DtlsCertPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fingerprint" => Ok(Self::Fingerprint),
+            "strong-fingerprint" => Ok(Self::StrongFingerprint),
+            _ => Err(format!("unsupported dtls cert policy {s}")),
+        }
+    }
+}
+
+impl Display for DtlsCertPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fingerprint => f.write_str("fingerprint"),
+            Self::StrongFingerprint => f.write_str("strong-fingerprint"),
+        }
+    }
+}
+
+/// Server DTLS role which is answered to `actpass` offers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DtlsSetup {
+    /// Keep the role which is chosen by str0m
+    #[default]
+    Auto,
+    /// Server starts the handshake
+    Active,
+    /// Client starts the handshake
+    Passive,
+}
+
+impl FromStr for DtlsSetup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "active" => Ok(Self::Active),
+            "passive" => Ok(Self::Passive),
+            _ => Err(format!("unsupported dtls setup {s}")),
+        }
+    }
+}
+
+impl Display for DtlsSetup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Active => f.write_str("active"),
+            Self::Passive => f.write_str("passive"),
+        }
+    }
+}
+
+/// DTLS-SRTP protection profile (RFC 5764, RFC 7714), only the profiles which str0m implements are listed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SrtpProfile {
+    Aes128CmSha1_80,
+    AeadAes128Gcm,
+}
+
+impl SrtpProfile {
+    const ALL: [Self; 2] = [Self::Aes128CmSha1_80, Self::AeadAes128Gcm];
+
+    fn id(&self) -> u16 {
+        match self {
+            Self::Aes128CmSha1_80 => 0x0001,
+            Self::AeadAes128Gcm => 0x0007,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Aes128CmSha1_80 => "SRTP_AES128_CM_SHA1_80",
+            Self::AeadAes128Gcm => "SRTP_AEAD_AES_128_GCM",
+        }
+    }
+}
+
+impl FromStr for SrtpProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|profile| profile.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("unsupported srtp profile {s}"))
+    }
+}
+
+impl Display for SrtpProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// DTLS cipher suite, only the AES-GCM suites with ECDHE which str0m offers are listed, so CBC suites can't be allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DtlsCipher {
+    EcdheEcdsaAes128GcmSha256,
+    EcdheEcdsaAes256GcmSha384,
+    EcdheRsaAes128GcmSha256,
+    EcdheRsaAes256GcmSha384,
+}
+
+impl DtlsCipher {
+    const ALL: [Self; 4] = [
+        Self::EcdheEcdsaAes128GcmSha256,
+        Self::EcdheEcdsaAes256GcmSha384,
+        Self::EcdheRsaAes128GcmSha256,
+        Self::EcdheRsaAes256GcmSha384,
+    ];
+
+    fn id(&self) -> u16 {
+        match self {
+            Self::EcdheEcdsaAes128GcmSha256 => 0xc02b,
+            Self::EcdheEcdsaAes256GcmSha384 => 0xc02c,
+            Self::EcdheRsaAes128GcmSha256 => 0xc02f,
+            Self::EcdheRsaAes256GcmSha384 => 0xc030,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::EcdheEcdsaAes128GcmSha256 => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+            Self::EcdheEcdsaAes256GcmSha384 => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+            Self::EcdheRsaAes128GcmSha256 => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+            Self::EcdheRsaAes256GcmSha384 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        }
+    }
+}
+
+impl FromStr for DtlsCipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|cipher| cipher.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("unsupported dtls cipher suite {s}"))
+    }
+}
+
+impl Display for DtlsCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Allowed protection profiles and cipher suites as bitsets of their position in `ALL`, an empty set allows everything
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DtlsSuites {
+    profiles: u8,
+    ciphers: u8,
+}
+
+impl DtlsSuites {
+    pub fn new(profiles: &[SrtpProfile], ciphers: &[DtlsCipher]) -> Self {
+        Self {
+            profiles: SrtpProfile::ALL.iter().enumerate().filter(|(_, p)| profiles.contains(p)).fold(0, |mask, (i, _)| mask | 1 << i),
+            ciphers: DtlsCipher::ALL.iter().enumerate().filter(|(_, c)| ciphers.contains(c)).fold(0, |mask, (i, _)| mask | 1 << i),
+        }
+    }
+
+    fn is_unrestricted(&self) -> bool {
+        self.profiles == 0 && self.ciphers == 0
+    }
+
+    fn profile_allowed(&self, id: u16) -> bool {
+        self.profiles == 0 || SrtpProfile::ALL.iter().position(|p| p.id() == id).is_some_and(|i| self.profiles & (1 << i) != 0)
+    }
+
+    fn cipher_allowed(&self, id: u16) -> bool {
+        self.ciphers == 0 || DtlsCipher::ALL.iter().position(|c| c.id() == id).is_some_and(|i| self.ciphers & (1 << i) != 0)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DtlsPolicy {
+    pub min_version: DtlsVersion,
+    pub cert: DtlsCertPolicy,
+    pub setup: DtlsSetup,
+    pub suites: DtlsSuites,
+}
+
+impl DtlsPolicy {
+    /// Check offer fingerprints with cert policy, Err is the rejected hash function
+    pub fn check_offer(&self, offer: &str) -> Result<(), String> {
+        if self.cert == DtlsCertPolicy::Fingerprint {
+            return Ok(());
+        }
+        let weak = offer
+            .lines()
+            .filter_map(|line| line.strip_prefix("a=fingerprint:"))
+            .filter_map(|fingerprint| fingerprint.split_whitespace().next())
+            .find(|hash| WEAK_FINGERPRINT_HASHES.contains(&hash.to_ascii_lowercase().as_str()));
+        match weak {
+            Some(hash) => Err(hash.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Check offer setup and rewrite `actpass` to the role which makes str0m answer with the configured role.
+    /// Err if setup is unknown or differs between m-sections, because all of them share one DTLS transport
+    pub fn offer_setup(&self, offer: &str) -> Result<String, String> {
+        let setup = match sdp_setup(offer)? {
+            Some(setup) => setup,
+            None => return Ok(offer.to_string()),
+        };
+        let rewrite = match (setup, self.setup) {
+            ("actpass", DtlsSetup::Active) => "a=setup:passive",
+            ("actpass", DtlsSetup::Passive) => "a=setup:active",
+            _ => return Ok(offer.to_string()),
+        };
+        let mut out = String::with_capacity(offer.len());
+        for line in offer.split_inclusive('\n') {
+            if line.trim_end().starts_with("a=setup:") {
+                out.push_str(rewrite);
+                out.push_str("\r\n");
+            } else {
+                out.push_str(line);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Check an incoming udp packet, only DTLS hello is checked. Err is the remote version which is lower than min version,
+    /// None if the version is unknown to us.
+    pub fn check_packet(&self, data: &[u8]) -> Result<(), Option<DtlsVersion>> {
+        if self.min_version == DtlsVersion::Dtls1_0 {
+            return Ok(());
+        }
+        let version = match hello_version(data) {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        match DtlsVersion::from_wire(version) {
+            Some(version) if version >= self.min_version => Ok(()),
+            Some(version) => Err(Some(version)),
+            // unknown newer version is lower on wire, it is still accepted
+            None if version < DTLS_1_2 => Ok(()),
+            None => Err(None),
+        }
+    }
+
+    /// Check cipher suites and SRTP profiles of DTLS hellos in a packet, it is used for both incoming and outgoing packets
+    /// with a reassembly per direction. ClientHello must offer at least one allowed suite of each kind, ServerHello must
+    /// select allowed ones. A fragmented hello is checked when its last fragment arrives. Returns the suites selected
+    /// by a ServerHello, Err is the reason of rejection
+    pub fn check_suites(&self, hellos: &mut HelloReassembly, data: &[u8]) -> Result<Option<NegotiatedSuites>, String> {
+        if self.suites.is_unrestricted() || data.first() != Some(&CONTENT_TYPE_HANDSHAKE) {
+            return Ok(None);
+        }
+        let mut negotiated = None;
+        let mut records = data;
+        while records.len() >= RECORD_HEADER_LEN {
+            let len = u16::from_be_bytes([records[11], records[12]]) as usize;
+            let Some(record) = records.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) else {
+                return Err("malformed dtls record".to_string());
+            };
+            // hellos are never encrypted, records of later epochs are not inspected
+            let plain_handshake = records[0] == CONTENT_TYPE_HANDSHAKE && records[3..5] == [0, 0];
+            records = &records[RECORD_HEADER_LEN + len..];
+            if !plain_handshake {
+                continue;
+            }
+            let mut messages = record;
+            while messages.len() >= HANDSHAKE_HEADER_LEN {
+                let msg_type = messages[0];
+                let len = u24(&messages[1..4]);
+                let message_seq = u16::from_be_bytes([messages[4], messages[5]]);
+                let offset = u24(&messages[6..9]);
+                let fragment_len = u24(&messages[9..12]);
+                let Some(fragment) = messages.get(HANDSHAKE_HEADER_LEN..HANDSHAKE_HEADER_LEN + fragment_len) else {
+                    return Err("malformed dtls handshake".to_string());
+                };
+                messages = &messages[HANDSHAKE_HEADER_LEN + fragment_len..];
+                if msg_type != HANDSHAKE_CLIENT_HELLO && msg_type != HANDSHAKE_SERVER_HELLO {
+                    continue;
+                }
+                if let Some(body) = hellos.push(msg_type, message_seq, len, offset, fragment)? {
+                    negotiated = self.check_hello(msg_type, &body)?.or(negotiated);
+                }
+            }
+        }
+        Ok(negotiated)
+    }
+
+    fn check_hello(&self, msg_type: u8, body: &[u8]) -> Result<Option<NegotiatedSuites>, String> {
+        let hello = match msg_type {
+            HANDSHAKE_CLIENT_HELLO => parse_client_hello(body),
+            _ => parse_server_hello(body),
+        }
+        .ok_or_else(|| "malformed dtls hello".to_string())?;
+        if self.suites.ciphers != 0 && !hello.ciphers.iter().any(|id| self.suites.cipher_allowed(*id)) {
+            return Err(format!("no allowed cipher suite in {:04x?}", hello.ciphers));
+        }
+        if self.suites.profiles != 0 && !hello.profiles.iter().any(|id| self.suites.profile_allowed(*id)) {
+            return Err(format!("no allowed srtp profile in {:04x?}", hello.profiles));
+        }
+        Ok((msg_type == HANDSHAKE_SERVER_HELLO).then(|| NegotiatedSuites {
+            cipher: hello.ciphers[0],
+            profile: hello.profiles.first().copied(),
+        }))
+    }
+}
+
+/// Cipher suite and SRTP profile ids which are selected by a ServerHello
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedSuites {
+    pub cipher: u16,
+    pub profile: Option<u16>,
+}
+
+impl Display for NegotiatedSuites {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.profile {
+            Some(profile) => write!(f, "cipher {:04x} srtp profile {profile:04x}", self.cipher),
+            None => write!(f, "cipher {:04x} without srtp profile", self.cipher),
+        }
+    }
+}
+
+/// Reassembly of a fragmented DTLS hello, one per direction of a connection
+#[derive(Debug, Default)]
+pub struct HelloReassembly {
+    msg_type: u8,
+    message_seq: u16,
+    body: Vec<u8>,
+    /// Received ranges of body, sorted and merged
+    ranges: Vec<(usize, usize)>,
+}
+
+impl HelloReassembly {
+    /// Add a hello fragment, returns the whole body when it is complete. Err if the fragment doesn't fit the hello
+    /// or overlaps a received fragment with different content
+    fn push(&mut self, msg_type: u8, message_seq: u16, len: usize, offset: usize, fragment: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if len > MAX_HELLO_LEN {
+            return Err(format!("dtls hello too long {len}"));
+        }
+        let end = offset + fragment.len();
+        if end > len {
+            return Err(format!("dtls hello fragment {offset}..{end} outside length {len}"));
+        }
+        if self.ranges.is_empty() || self.msg_type != msg_type || self.message_seq != message_seq || self.body.len() != len {
+            self.msg_type = msg_type;
+            self.message_seq = message_seq;
+            self.body = vec![0; len];
+            self.ranges.clear();
+        }
+        for &(start, stop) in &self.ranges {
+            let (from, to) = (start.max(offset), stop.min(end));
+            if from < to && self.body[from..to] != fragment[from - offset..to - offset] {
+                return Err("inconsistent dtls hello fragments".to_string());
+            }
+        }
+        self.body[offset..end].copy_from_slice(fragment);
+        self.ranges.push((offset, end));
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(start, stop) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(stop),
+                _ => merged.push((start, stop)),
+            }
+        }
+        self.ranges = merged;
+        if self.ranges == [(0, len)] {
+            self.ranges.clear();
+            return Ok(Some(std::mem::take(&mut self.body)));
+        }
+        Ok(None)
+    }
+}
+
+/// Suites of a hello, offered ones for ClientHello and the selected one for ServerHello
+struct HelloSuites {
+    ciphers: Vec<u16>,
+    profiles: Vec<u16>,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+fn u16_list(buf: &[u8]) -> Vec<u16> {
+    buf.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect()
+}
+
+fn u24(buf: &[u8]) -> usize {
+    u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<HelloSuites> {
+    let mut reader = Reader { buf: body };
+    reader.take(2 + HELLO_RANDOM_LEN)?;
+    reader.vec8()?; // session id
+    reader.vec8()?; // cookie
+    let ciphers = u16_list(reader.vec16()?);
+    reader.vec8()?; // compression methods
+    let profiles = use_srtp_profiles(&mut reader)?;
+    Some(HelloSuites { ciphers, profiles })
+}
+
+fn parse_server_hello(body: &[u8]) -> Option<HelloSuites> {
+    let mut reader = Reader { buf: body };
+    reader.take(2 + HELLO_RANDOM_LEN)?;
+    reader.vec8()?; // session id
+    let cipher = reader.u16()?;
+    reader.u8()?; // compression method
+    let profiles = use_srtp_profiles(&mut reader)?;
+    Some(HelloSuites { ciphers: vec![cipher], profiles })
+}
+
+/// Profiles of use_srtp extension, empty if the hello doesn't have it
+fn use_srtp_profiles(reader: &mut Reader) -> Option<Vec<u16>> {
+    if reader.buf.is_empty() {
+        return Some(vec![]);
+    }
+    let mut extensions = Reader { buf: reader.vec16()? };
+    while !extensions.buf.is_empty() {
+        let ext_type = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if ext_type == EXTENSION_USE_SRTP {
+            let mut data = Reader { buf: data };
+            return Some(u16_list(data.vec16()?));
+        }
+    }
+    Some(vec![])
+}
+
+/// Answer setup must take the opposite role of the offer, otherwise both sides wait for or start the handshake together
+pub fn check_answer_setup(offer: &str, answer: &str) -> Result<(), String> {
+    let (offer, answer) = match (sdp_setup(offer)?, sdp_setup(answer)?) {
+        (Some(offer), Some(answer)) => (offer, answer),
+        _ => return Ok(()),
+    };
+    match (offer, answer) {
+        ("actpass", "active" | "passive") | ("active", "passive") | ("passive", "active") => Ok(()),
+        _ => Err(format!("answer setup {answer} doesn't match offer setup {offer}")),
+    }
+}
+
+/// Setup of all m-sections, None if not present
+fn sdp_setup(sdp: &str) -> Result<Option<&str>, String> {
+    let mut found = None;
+    for setup in sdp.lines().filter_map(|line| line.trim_end().strip_prefix("a=setup:")) {
+        if !matches!(setup, "actpass" | "active" | "passive") {
+            return Err(format!("unsupported setup {setup}"));
+        }
+        match found {
+            Some(found) if found != setup => return Err(format!("mixed setup {found} and {setup}")),
+            _ => found = Some(setup),
+        }
+    }
+    Ok(found)
+}
+
+/// Version field of ClientHello or ServerHello if the packet is the first fragment of one
+fn hello_version(data: &[u8]) -> Option<u16> {
+    if data.len() < HELLO_VERSION_OFFSET + 2 || data[0] != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    let msg_type = data[13];
+    if msg_type != HANDSHAKE_CLIENT_HELLO && msg_type != HANDSHAKE_SERVER_HELLO {
+        return None;
+    }
+    // only first fragment contains version
+    if data[19..22] != [0, 0, 0] {
+        return None;
+    }
+    Some(u16::from_be_bytes([data[HELLO_VERSION_OFFSET], data[HELLO_VERSION_OFFSET + 1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_answer_setup, DtlsCertPolicy, DtlsCipher, DtlsPolicy, DtlsSetup, DtlsSuites, DtlsVersion, HelloReassembly, NegotiatedSuites, SrtpProfile, DTLS_1_0, DTLS_1_2};
+
+    fn client_hello(version: u16) -> Vec<u8> {
+        let mut pkt = vec![22];
+        pkt.extend_from_slice(&DTLS_1_0.to_be_bytes()); // record version
+        pkt.extend_from_slice(&[0; 8]); // epoch + seq
+        pkt.extend_from_slice(&[0, 40]); // record length
+        pkt.push(1); // ClientHello
+        pkt.extend_from_slice(&[0, 0, 28]); // length
+        pkt.extend_from_slice(&[0, 0]); // message seq
+        pkt.extend_from_slice(&[0, 0, 0]); // fragment offset
+        pkt.extend_from_slice(&[0, 0, 28]); // fragment length
+        pkt.extend_from_slice(&version.to_be_bytes());
+        pkt.extend_from_slice(&[0; 26]);
+        pkt
+    }
+
+    #[test]
+    fn reject_dtls_below_min_version() {
+        let policy = DtlsPolicy {
+            min_version: DtlsVersion::Dtls1_2,
+            cert: DtlsCertPolicy::Fingerprint,
+            setup: DtlsSetup::Auto,
+            suites: DtlsSuites::default(),
+        };
+        assert_eq!(policy.check_packet(&client_hello(DTLS_1_0)), Err(Some(DtlsVersion::Dtls1_0)));
+        assert_eq!(policy.check_packet(&client_hello(DTLS_1_2)), Ok(()));
+        // not a hello, or not dtls at all
+        assert_eq!(policy.check_packet(&[0, 1, 0, 0]), Ok(()));
+        assert_eq!(policy.check_packet(&client_hello(DTLS_1_0)[..20]), Ok(()));
+
+        assert_eq!(DtlsPolicy::default().check_packet(&client_hello(DTLS_1_0)), Ok(()));
+    }
+
+    #[test]
+    fn strong_fingerprint_policy() {
+        let offer = "v=0\r\na=fingerprint:sha-1 8C:64:ED:03\r\n";
+        assert_eq!(DtlsPolicy::default().check_offer(offer), Ok(()));
+        let policy = DtlsPolicy {
+            min_version: DtlsVersion::Dtls1_0,
+            cert: DtlsCertPolicy::StrongFingerprint,
+            setup: DtlsSetup::Auto,
+            suites: DtlsSuites::default(),
+        };
+        assert_eq!(policy.check_offer(offer), Err("sha-1".to_string()));
+        assert_eq!(policy.check_offer("v=0\r\na=fingerprint:sha-256 8C:64:ED:03\r\n"), Ok(()));
+        assert_eq!("1.2".parse::<DtlsVersion>(), Ok(DtlsVersion::Dtls1_2));
+        assert_eq!("Strong-Fingerprint".parse::<DtlsCertPolicy>(), Ok(DtlsCertPolicy::StrongFingerprint));
+    }
+
+    #[test]
+    fn offer_setup_follow_policy() {
+        let offer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=setup:actpass\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=setup:actpass\r\n";
+        let policy = |setup| DtlsPolicy { setup, ..Default::default() };
+        assert_eq!(policy(DtlsSetup::Auto).offer_setup(offer).as_deref(), Ok(offer));
+        assert_eq!(policy(DtlsSetup::Passive).offer_setup(offer), Ok(offer.replace("actpass", "active")));
+        assert_eq!(policy(DtlsSetup::Active).offer_setup(offer), Ok(offer.replace("actpass", "passive")));
+
+        // active client is never rewritten
+        let active = offer.replace("actpass", "active");
+        assert_eq!(policy(DtlsSetup::Active).offer_setup(&active).as_deref(), Ok(active.as_str()));
+        assert!(DtlsPolicy::default().offer_setup(&offer.replacen("actpass", "active", 1)).is_err());
+        assert!(DtlsPolicy::default().offer_setup("v=0\r\na=setup:holdconn\r\n").is_err());
+        assert_eq!("Passive".parse::<DtlsSetup>(), Ok(DtlsSetup::Passive));
+    }
+
+    #[test]
+    fn answer_setup_opposite_role() {
+        assert_eq!(check_answer_setup("a=setup:actpass\r\n", "a=setup:passive\r\n"), Ok(()));
+        assert_eq!(check_answer_setup("a=setup:active\r\n", "a=setup:passive\r\n"), Ok(()));
+        assert_eq!(check_answer_setup("a=setup:passive\r\n", "a=setup:active\r\n"), Ok(()));
+        assert!(check_answer_setup("a=setup:active\r\n", "a=setup:active\r\n").is_err());
+        assert!(check_answer_setup("a=setup:actpass\r\n", "a=setup:actpass\r\n").is_err());
+    }
+
+    fn hello(msg_type: u8, suites: &[u16], profiles: &[u16]) -> Vec<u8> {
+        let mut body = DTLS_1_2.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0; 32]); // random
+        body.push(0); // session id
+        if msg_type == 1 {
+            body.push(0); // cookie
+            body.extend_from_slice(&((suites.len() * 2) as u16).to_be_bytes());
+            suites.iter().for_each(|id| body.extend_from_slice(&id.to_be_bytes()));
+            body.extend_from_slice(&[1, 0]); // compression methods
+        } else {
+            body.extend_from_slice(&suites[0].to_be_bytes());
+            body.push(0); // compression method
+        }
+        let mut use_srtp = ((profiles.len() * 2) as u16).to_be_bytes().to_vec();
+        profiles.iter().for_each(|id| use_srtp.extend_from_slice(&id.to_be_bytes()));
+        use_srtp.push(0); // mki
+        let mut extensions = 14_u16.to_be_bytes().to_vec();
+        extensions.extend_from_slice(&(use_srtp.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&use_srtp);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let len = (body.len() as u32).to_be_bytes();
+        let mut pkt = vec![22];
+        pkt.extend_from_slice(&DTLS_1_0.to_be_bytes()); // record version
+        pkt.extend_from_slice(&[0; 8]); // epoch + seq
+        pkt.extend_from_slice(&((body.len() + 12) as u16).to_be_bytes()); // record length
+        pkt.push(msg_type);
+        pkt.extend_from_slice(&len[1..]); // length
+        pkt.extend_from_slice(&[0, 0]); // message seq
+        pkt.extend_from_slice(&[0, 0, 0]); // fragment offset
+        pkt.extend_from_slice(&len[1..]); // fragment length
+        pkt.extend_from_slice(&body);
+        pkt
+    }
+
+    /// Record of one fragment of a hello which is built by [`hello`]
+    fn fragment(pkt: &[u8], offset: usize, len: usize) -> Vec<u8> {
+        let mut record = pkt[..11].to_vec();
+        record.extend_from_slice(&((len + 12) as u16).to_be_bytes()); // record length
+        record.extend_from_slice(&pkt[13..19]); // type, length, message seq
+        record.extend_from_slice(&(offset as u32).to_be_bytes()[1..]); // fragment offset
+        record.extend_from_slice(&(len as u32).to_be_bytes()[1..]); // fragment length
+        record.extend_from_slice(&pkt[25 + offset..25 + offset + len]);
+        record
+    }
+
+    #[test]
+    fn suites_restricted_to_allowlist() {
+        let policy = DtlsPolicy {
+            suites: DtlsSuites::new(&[SrtpProfile::AeadAes128Gcm], &[DtlsCipher::EcdheEcdsaAes128GcmSha256]),
+            ..Default::default()
+        };
+        let mut hellos = HelloReassembly::default();
+        // client which offers an allowed suite of each kind is accepted
+        assert_eq!(policy.check_suites(&mut hellos, &hello(1, &[0xc02b, 0xc02f], &[0x0001, 0x0007])), Ok(None));
+        // client which offers only disallowed suites is rejected
+        assert!(policy.check_suites(&mut hellos, &hello(1, &[0xc02f, 0xc013], &[0x0007])).is_err());
+        assert!(policy.check_suites(&mut hellos, &hello(1, &[0xc02b], &[0x0001, 0x0002])).is_err());
+
+        // negotiated suites must be inside the allowlist
+        let negotiated = NegotiatedSuites {
+            cipher: 0xc02b,
+            profile: Some(0x0007),
+        };
+        assert_eq!(policy.check_suites(&mut hellos, &hello(2, &[0xc02b], &[0x0007])), Ok(Some(negotiated)));
+        assert!(policy.check_suites(&mut hellos, &hello(2, &[0xc02b], &[0x0001])).is_err());
+        assert!(policy.check_suites(&mut hellos, &hello(2, &[0xc02f], &[0x0007])).is_err());
+
+        // not a hello, and default policy allows everything
+        assert_eq!(policy.check_suites(&mut hellos, &[0, 1, 0, 0]), Ok(None));
+        assert_eq!(DtlsPolicy::default().check_suites(&mut hellos, &hello(2, &[0xc009], &[0x0001])), Ok(None));
+
+        // only ciphers are restricted, any profile is allowed
+        let policy = DtlsPolicy {
+            suites: DtlsSuites::new(&[], &[DtlsCipher::EcdheEcdsaAes128GcmSha256]),
+            ..Default::default()
+        };
+        assert!(policy.check_suites(&mut hellos, &hello(2, &[0xc02b], &[0x0001])).is_ok());
+        assert_eq!("srtp_aead_aes_128_gcm".parse::<SrtpProfile>(), Ok(SrtpProfile::AeadAes128Gcm));
+        assert_eq!("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".parse::<DtlsCipher>(), Ok(DtlsCipher::EcdheRsaAes128GcmSha256));
+        // profiles and suites which str0m can't negotiate are not accepted in config
+        assert!("SRTP_AEAD_AES_256_GCM".parse::<SrtpProfile>().is_err());
+        assert!("TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA".parse::<DtlsCipher>().is_err());
+    }
+
+    #[test]
+    fn fragmented_hello_checked_when_complete() {
+        let policy = DtlsPolicy {
+            suites: DtlsSuites::new(&[SrtpProfile::AeadAes128Gcm], &[]),
+            ..Default::default()
+        };
+        let mut hellos = HelloReassembly::default();
+        let pkt = hello(1, &[0xc02b], &[0x0001]);
+        let len = pkt.len() - 25;
+        // fragments out of order, the disallowed offer is only seen with the last one
+        assert_eq!(policy.check_suites(&mut hellos, &fragment(&pkt, 40, len - 40)), Ok(None));
+        assert_eq!(policy.check_suites(&mut hellos, &fragment(&pkt, 0, 20)), Ok(None));
+        assert!(policy.check_suites(&mut hellos, &fragment(&pkt, 10, 30)).is_err());
+
+        // two fragments in one datagram
+        let pkt = hello(2, &[0xc02b], &[0x0007]);
+        let len = pkt.len() - 25;
+        let datagram = [fragment(&pkt, 0, 16), fragment(&pkt, 16, len - 16)].concat();
+        assert_eq!(
+            policy.check_suites(&mut hellos, &datagram),
+            Ok(Some(NegotiatedSuites {
+                cipher: 0xc02b,
+                profile: Some(0x0007)
+            }))
+        );
+
+        // overlapping fragment with different content is rejected
+        let mut other = fragment(&pkt, 0, 16);
+        assert_eq!(policy.check_suites(&mut hellos, &fragment(&pkt, 0, 20)), Ok(None));
+        other[30] ^= 0xff;
+        assert!(policy.check_suites(&mut hellos, &other).is_err());
+
+        // encrypted handshake records are not inspected
+        let mut encrypted = fragment(&pkt, 0, 16);
+        encrypted[4] = 1;
+        assert_eq!(policy.check_suites(&mut HelloReassembly::default(), &encrypted), Ok(None));
+    }
+}
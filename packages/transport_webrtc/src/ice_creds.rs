@@ -0,0 +1,69 @@
+//! Length of generated ICE credentials, for interop testing and security policies which check their entropy.
+//! RFC 8839 5.4 requires at least 24 bits of randomness for ufrag and 128 bits for pwd, with 6 bits per ice-char they
+//! are 4 and 22 chars, and both are at most 256 chars. Lengths out of these bounds are clamped.
+
+use rand::Rng;
+use str0m::ice::IceCreds;
+
+pub const MIN_UFRAG_LEN: usize = 4;
+pub const MIN_PWD_LEN: usize = 22;
+pub const MAX_CREDS_LEN: usize = 256;
+
+/// ice-char = ALPHA / DIGIT / "+" / "/"
+const ICE_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// None keeps the credential which is generated by str0m
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IceCredsConfig {
+    pub ufrag_len: Option<usize>,
+    pub pwd_len: Option<usize>,
+}
+
+impl IceCredsConfig {
+    pub fn generate(&self) -> IceCreds {
+        let mut creds = IceCreds::new();
+        if let Some(len) = self.ufrag_len {
+            creds.ufrag = random_ice_chars(len.clamp(MIN_UFRAG_LEN, MAX_CREDS_LEN));
+        }
+        if let Some(len) = self.pwd_len {
+            creds.pass = random_ice_chars(len.clamp(MIN_PWD_LEN, MAX_CREDS_LEN));
+        }
+        creds
+    }
+}
+
+fn random_ice_chars(len: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..len).map(|_| ICE_CHARS[rng.gen_range(0..ICE_CHARS.len())] as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IceCredsConfig, ICE_CHARS, MAX_CREDS_LEN, MIN_PWD_LEN, MIN_UFRAG_LEN};
+
+    #[test]
+    fn generate_with_configured_length() {
+        let creds = IceCredsConfig {
+            ufrag_len: Some(16),
+            pwd_len: Some(64),
+        }
+        .generate();
+        assert_eq!(creds.ufrag.len(), 16);
+        assert_eq!(creds.pass.len(), 64);
+        assert!(creds.ufrag.bytes().chain(creds.pass.bytes()).all(|c| ICE_CHARS.contains(&c)));
+
+        // out of rfc bounds are clamped
+        let creds = IceCredsConfig {
+            ufrag_len: Some(1),
+            pwd_len: Some(1000),
+        }
+        .generate();
+        assert_eq!(creds.ufrag.len(), MIN_UFRAG_LEN);
+        assert_eq!(creds.pass.len(), MAX_CREDS_LEN);
+
+        // default keeps str0m generated values, which also meet rfc minimums
+        let creds = IceCredsConfig::default().generate();
+        assert!(creds.ufrag.len() >= MIN_UFRAG_LEN);
+        assert!(creds.pass.len() >= MIN_PWD_LEN);
+    }
+}
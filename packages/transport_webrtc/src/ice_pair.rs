@@ -0,0 +1,264 @@
+//! ICE candidate pair diagnostics and per-session pair hint. Str0m doesn't expose the nominated pair, so the selected
+//! pair is taken from the addresses of transmits after ICE connected, remote candidate types are looked up from the
+//! candidates which are given to str0m. The hint only changes which candidates str0m sees and their order,
+//! nomination itself is still done by ICE agents. Rtt from media stats is reported to endpoint as rtt of the selected
+//! pair, subscriber adaptation uses it for being more conservative on high rtt paths.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// With relay hint, remote non-relay candidates are released after this time if no relay candidate is received
+const RELAY_HINT_WAIT: Duration = Duration::from_secs(2);
+/// Rtt of the selected pair is only reported again when it changes more than this, stats of each mid are near the same
+const RTT_REPORT_DELTA_MS: u32 = 10;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IceHint {
+    #[default]
+    None,
+    /// Remote non-relay candidates are held back, they are dropped when a relay candidate is received
+    PreferRelay,
+    /// Local candidate with this ip is advertised with highest priority
+    PreferInterface(IpAddr),
+}
+
+impl FromStr for IceHint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "" | "none" => Ok(Self::None),
+            "relay" => Ok(Self::PreferRelay),
+            _ => match s.strip_prefix("interface=") {
+                Some(ip) => ip.parse().map(Self::PreferInterface).map_err(|e| format!("invalid ice hint interface {ip}: {e}")),
+                None => Err(format!("unsupported ice hint {s}")),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectedPair {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    /// Candidate type of remote (host, srflx, prflx, relay), None if remote is not a signaled candidate
+    pub remote_kind: Option<String>,
+}
+
+impl Display for SelectedPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} ({})", self.local, self.remote, self.remote_kind.as_deref().unwrap_or("prflx"))
+    }
+}
+
+#[derive(Default)]
+pub struct IcePairs {
+    hint: IceHint,
+    remote_kinds: HashMap<SocketAddr, String>,
+    /// All signaled remote candidates, including ones which are held by hint
+    signaled: Vec<String>,
+    relay_seen: bool,
+    held: Vec<String>,
+    hold_until: Option<Instant>,
+    selected: Option<SelectedPair>,
+    rtt_reported: bool,
+    /// Last reported rtt of the selected pair
+    rtt_ms: Option<u32>,
+}
+
+impl IcePairs {
+    pub fn new(hint: IceHint) -> Self {
+        Self { hint, ..Default::default() }
+    }
+
+    /// Remove candidates of offer which are held by hint, other candidates are kept as is
+    pub fn filter_offer(&mut self, offer: &str) -> String {
+        let candidates = offer
+            .lines()
+            .filter_map(|line| line.strip_prefix("a="))
+            .filter(|line| line.starts_with("candidate:"))
+            .map(|line| line.to_string())
+            .collect();
+        let allowed = self.on_remote_candidates(candidates);
+        offer
+            .split_inclusive('\n')
+            .filter(|line| match line.trim_end().strip_prefix("a=") {
+                Some(candidate) if candidate.starts_with("candidate:") => allowed.iter().any(|allowed| allowed == candidate),
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Return candidates which can be added to str0m now
+    pub fn on_remote_candidates(&mut self, candidates: Vec<String>) -> Vec<String> {
+        let mut allowed = vec![];
+        for candidate in candidates {
+            self.signaled.push(candidate.clone());
+            let kind = candidate_info(&candidate).map(|(addr, kind)| {
+                self.remote_kinds.insert(addr, kind.to_string());
+                kind == "relay"
+            });
+            match (self.hint, kind) {
+                (IceHint::PreferRelay, Some(true)) => {
+                    if !self.relay_seen {
+                        log::info!("[IcePairs] relay candidate received with relay hint => drop {} held candidates", self.held.len());
+                        self.relay_seen = true;
+                        self.held.clear();
+                    }
+                    allowed.push(candidate);
+                }
+                (IceHint::PreferRelay, _) if self.relay_seen => {}
+                (IceHint::PreferRelay, _) => self.held.push(candidate),
+                _ => allowed.push(candidate),
+            }
+        }
+        allowed
+    }
+
+    /// Release held candidates when no relay candidate is received in time
+    pub fn on_tick(&mut self, now: Instant) -> Vec<String> {
+        if self.held.is_empty() {
+            return vec![];
+        }
+        let until = *self.hold_until.get_or_insert(now + RELAY_HINT_WAIT);
+        if now < until {
+            return vec![];
+        }
+        log::info!("[IcePairs] no relay candidate with relay hint => release {} held candidates", self.held.len());
+        self.hint = IceHint::None;
+        std::mem::take(&mut self.held)
+    }
+
+    /// Called with addresses of transmits after ICE connected, return the pair when it is newly selected or changed
+    pub fn on_transmit(&mut self, local: SocketAddr, remote: SocketAddr) -> Option<&SelectedPair> {
+        if self.selected.as_ref().is_some_and(|pair| pair.local == local && pair.remote == remote) {
+            return None;
+        }
+        self.rtt_reported = false;
+        self.rtt_ms = None;
+        self.selected = Some(SelectedPair {
+            local,
+            remote,
+            remote_kind: self.remote_kinds.get(&remote).cloned(),
+        });
+        self.selected.as_ref()
+    }
+
+    /// Selected pair for logging with the first rtt measurement, only once per selected pair
+    pub fn take_rtt_report(&mut self) -> Option<&SelectedPair> {
+        if self.rtt_reported {
+            return None;
+        }
+        self.rtt_reported = self.selected.is_some();
+        self.selected.as_ref()
+    }
+
+    /// Called with rtt of media stats, return the rtt of the selected pair when it should be reported to endpoint
+    pub fn on_rtt(&mut self, rtt_ms: f32) -> Option<u32> {
+        self.selected.as_ref()?;
+        let rtt_ms = rtt_ms.max(0.0).round() as u32;
+        if self.rtt_ms.is_some_and(|last| last.abs_diff(rtt_ms) < RTT_REPORT_DELTA_MS) {
+            return None;
+        }
+        self.rtt_ms = Some(rtt_ms);
+        Some(rtt_ms)
+    }
+
+    pub fn selected(&self) -> Option<&SelectedPair> {
+        self.selected.as_ref()
+    }
+
+    pub fn remote_candidates(&self) -> &[String] {
+        &self.signaled
+    }
+}
+
+/// Address and type of a candidate line `candidate:foundation component proto prio ip port typ kind ...`
+fn candidate_info(candidate: &str) -> Option<(SocketAddr, &str)> {
+    let mut parts = candidate.strip_prefix("a=").unwrap_or(candidate).split_whitespace().skip(4);
+    let ip: IpAddr = parts.next()?.parse().ok()?;
+    let port: u16 = parts.next()?.parse().ok()?;
+    if parts.next()? != "typ" {
+        return None;
+    }
+    Some((SocketAddr::new(ip, port), parts.next()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{IceHint, IcePairs, SelectedPair};
+
+    const HOST: &str = "candidate:1 1 udp 2122260223 192.168.1.10 50000 typ host generation 0";
+    const SRFLX: &str = "candidate:2 1 udp 1686052607 1.2.3.4 50001 typ srflx raddr 192.168.1.10 rport 50000 generation 0";
+    const RELAY: &str = "candidate:3 1 udp 41885439 5.6.7.8 3478 typ relay raddr 1.2.3.4 rport 50001 generation 0";
+
+    #[test]
+    fn report_selected_pair() {
+        let mut pairs = IcePairs::new(IceHint::None);
+        let offer = format!("v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na={HOST}\r\na={SRFLX}\r\n");
+        assert_eq!(pairs.filter_offer(&offer), offer);
+        assert_eq!(pairs.on_remote_candidates(vec![RELAY.to_string()]), vec![RELAY.to_string()]);
+
+        let local = "10.0.0.1:10000".parse().expect("Should parse addr");
+        let pair = SelectedPair {
+            local,
+            remote: "1.2.3.4:50001".parse().expect("Should parse addr"),
+            remote_kind: Some("srflx".to_string()),
+        };
+        assert_eq!(pairs.on_transmit(local, pair.remote), Some(&pair));
+        assert_eq!(pairs.on_transmit(local, pair.remote), None);
+        assert_eq!(pairs.take_rtt_report(), Some(&pair));
+        assert_eq!(pairs.take_rtt_report(), None);
+        assert_eq!(pair.to_string(), "10.0.0.1:10000 -> 1.2.3.4:50001 (srflx)");
+
+        // small rtt changes are not reported again
+        assert_eq!(pairs.on_rtt(40.4), Some(40));
+        assert_eq!(pairs.on_rtt(45.0), None);
+        assert_eq!(pairs.on_rtt(120.0), Some(120));
+
+        // pair switched to relay, rtt is reported again for the new pair
+        let relay = "5.6.7.8:3478".parse().expect("Should parse addr");
+        assert_eq!(pairs.on_transmit(local, relay).and_then(|pair| pair.remote_kind.as_deref()), Some("relay"));
+        assert_eq!(pairs.on_rtt(120.0), Some(120));
+    }
+
+    #[test]
+    fn prefer_relay_hint_uses_relay_pair() {
+        let mut pairs = IcePairs::new(IceHint::PreferRelay);
+        let offer = format!("v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na={HOST}\r\na={SRFLX}\r\na=mid:0\r\n");
+        assert_eq!(pairs.filter_offer(&offer), "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n");
+
+        let now = Instant::now();
+        assert_eq!(pairs.on_tick(now), Vec::<String>::new());
+        // relay is trickled later, non-relay candidates are never given to str0m
+        assert_eq!(pairs.on_remote_candidates(vec![RELAY.to_string(), HOST.to_string()]), vec![RELAY.to_string()]);
+        assert_eq!(pairs.on_tick(now + Duration::from_secs(5)), Vec::<String>::new());
+
+        let local = "10.0.0.1:10000".parse().expect("Should parse addr");
+        let pair = pairs.on_transmit(local, "5.6.7.8:3478".parse().expect("Should parse addr")).expect("Should report pair");
+        assert_eq!(pair.remote_kind.as_deref(), Some("relay"));
+    }
+
+    #[test]
+    fn prefer_relay_hint_fallback_without_relay() {
+        let mut pairs = IcePairs::new(IceHint::PreferRelay);
+        let now = Instant::now();
+        assert_eq!(pairs.on_remote_candidates(vec![HOST.to_string(), SRFLX.to_string()]), Vec::<String>::new());
+        assert_eq!(pairs.on_tick(now), Vec::<String>::new());
+        assert_eq!(pairs.on_tick(now + Duration::from_secs(2)), vec![HOST.to_string(), SRFLX.to_string()]);
+        // after released, candidates are not held anymore
+        assert_eq!(pairs.on_remote_candidates(vec![HOST.to_string()]), vec![HOST.to_string()]);
+
+        assert_eq!("relay".parse::<IceHint>(), Ok(IceHint::PreferRelay));
+        assert_eq!("interface=10.0.0.1".parse::<IceHint>(), Ok(IceHint::PreferInterface("10.0.0.1".parse().expect("Should parse ip"))));
+        assert!("tcp".parse::<IceHint>().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! ICE role conflict (RFC 8445 section 7.3.1.1). Both agents can believe they are controlling (or controlled), e.g. after
+//! an ICE restart with a buggy client, then no pair is nominated and the connection fails. The conflict is detected from
+//! the role attribute of binding requests: the local role and tie-breaker are learned from requests which str0m sends,
+//! the remote ones from received requests. The agent with the larger tie-breaker is controlling, so:
+//! - the loser switches its role
+//! - the winner keeps its role, the remote agent resolves the same conflict when it receives our checks
+//!
+//! Str0m doesn't send the 487 (Role Conflict) error response, the winner relies on the symmetric resolution above.
+//!
+//! Only a full ICE agent sends checks, so this applies when the media server runs without `--ice-lite` (the default).
+//! An ice-lite server is always controlled and a conflict can't happen on its side.
+
+const STUN_HEADER_LEN: usize = 20;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const ATTR_ICE_CONTROLLED: u16 = 0x8029;
+const ATTR_ICE_CONTROLLING: u16 = 0x802A;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceRole {
+    Controlling,
+    Controlled,
+}
+
+/// Role attribute of a binding request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StunRole {
+    pub role: IceRole,
+    pub tie_breaker: u64,
+}
+
+/// Role attribute of a STUN binding request, None for other packets or requests without role
+pub fn binding_request_role(buf: &[u8]) -> Option<StunRole> {
+    // first two bits of STUN are zero, it separates STUN from DTLS and RTP on the same port
+    if buf.len() < STUN_HEADER_LEN || buf[0] & 0xC0 != 0 {
+        return None;
+    }
+    let msg_type = u16::from_be_bytes([buf[0], buf[1]]);
+    let msg_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let cookie = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    if msg_type != STUN_BINDING_REQUEST || cookie != STUN_MAGIC_COOKIE || buf.len() < STUN_HEADER_LEN + msg_len {
+        return None;
+    }
+    let mut attrs = &buf[STUN_HEADER_LEN..STUN_HEADER_LEN + msg_len];
+    while attrs.len() >= 4 {
+        let attr_type = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let attr_len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + attr_len)?;
+        let role = match attr_type {
+            ATTR_ICE_CONTROLLING => Some(IceRole::Controlling),
+            ATTR_ICE_CONTROLLED => Some(IceRole::Controlled),
+            _ => None,
+        };
+        if let Some(role) = role {
+            let tie_breaker = u64::from_be_bytes(value.try_into().ok()?);
+            return Some(StunRole { role, tie_breaker });
+        }
+        // attributes are padded to 4 bytes
+        let padded = (4 + attr_len + 3) & !3;
+        attrs = attrs.get(padded..).unwrap_or_default();
+    }
+    None
+}
+
+/// Role of the local agent, it is unknown until the first sent binding request. An ice-lite agent never sends checks,
+/// it is always controlled and doesn't take part in role conflicts
+#[derive(Debug, Default)]
+pub struct IceRoleResolver {
+    local: Option<StunRole>,
+    conflicts: u64,
+}
+
+impl IceRoleResolver {
+    /// Learn the local role and tie-breaker from a packet which is sent by str0m
+    pub fn on_local_packet(&mut self, buf: &[u8]) {
+        if let Some(local) = binding_request_role(buf) {
+            self.local = Some(local);
+        }
+    }
+
+    /// Check a received packet, return the new local role if the local agent lost a role conflict
+    pub fn on_remote_packet(&mut self, buf: &[u8]) -> Option<IceRole> {
+        let local = self.local.as_mut()?;
+        let remote = binding_request_role(buf)?;
+        if local.role != remote.role {
+            return None;
+        }
+        self.conflicts += 1;
+        // the agent with larger tie-breaker is controlling
+        let new_role = match (local.role, local.tie_breaker >= remote.tie_breaker) {
+            (IceRole::Controlling, false) => IceRole::Controlled,
+            (IceRole::Controlled, true) => IceRole::Controlling,
+            _ => {
+                log::info!("[IceRole] role conflict, both {:?} => keep role, remote must switch", local.role);
+                return None;
+            }
+        };
+        log::warn!("[IceRole] role conflict, both {:?} => switch to {new_role:?}", local.role);
+        local.role = new_role;
+        Some(new_role)
+    }
+
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binding_request_role, IceRole, IceRoleResolver, StunRole, ATTR_ICE_CONTROLLED, ATTR_ICE_CONTROLLING, STUN_MAGIC_COOKIE};
+
+    fn binding_request(role: IceRole, tie_breaker: u64) -> Vec<u8> {
+        let mut attrs = vec![];
+        // USERNAME with padding before the role attribute
+        attrs.extend_from_slice(&0x0006_u16.to_be_bytes());
+        attrs.extend_from_slice(&5_u16.to_be_bytes());
+        attrs.extend_from_slice(b"ab:cd\0\0\0");
+        let role_attr = match role {
+            IceRole::Controlling => ATTR_ICE_CONTROLLING,
+            IceRole::Controlled => ATTR_ICE_CONTROLLED,
+        };
+        attrs.extend_from_slice(&role_attr.to_be_bytes());
+        attrs.extend_from_slice(&8_u16.to_be_bytes());
+        attrs.extend_from_slice(&tie_breaker.to_be_bytes());
+
+        let mut buf = vec![];
+        buf.extend_from_slice(&0x0001_u16.to_be_bytes());
+        buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&[7; 12]);
+        buf.extend_from_slice(&attrs);
+        buf
+    }
+
+    #[test]
+    fn parse_binding_request_role() {
+        let req = binding_request(IceRole::Controlled, 42);
+        assert_eq!(
+            binding_request_role(&req),
+            Some(StunRole {
+                role: IceRole::Controlled,
+                tie_breaker: 42
+            })
+        );
+
+        let mut response = req.clone();
+        response[1] = 0x01; // binding success response
+        response[0] = 0x01;
+        assert_eq!(binding_request_role(&response), None);
+        assert_eq!(binding_request_role(&req[..req.len() - 4]), None);
+        assert_eq!(binding_request_role(&[0x80; 40]), None);
+    }
+
+    #[test]
+    fn both_controlling_resolved_by_tie_breaker() {
+        let mut server = IceRoleResolver::default();
+        let mut client = IceRoleResolver::default();
+        let mut server_role = IceRole::Controlling;
+        let mut client_role = IceRole::Controlling;
+
+        // server doesn't know its role before sending checks
+        assert_eq!(server.on_remote_packet(&binding_request(client_role, 20)), None);
+
+        // each agent sends checks, then receives checks of the other
+        for _ in 0..2 {
+            let server_req = binding_request(server_role, 10);
+            let client_req = binding_request(client_role, 20);
+            server.on_local_packet(&server_req);
+            client.on_local_packet(&client_req);
+            if let Some(role) = server.on_remote_packet(&client_req) {
+                server_role = role;
+            }
+            if let Some(role) = client.on_remote_packet(&server_req) {
+                client_role = role;
+            }
+        }
+        assert_eq!(server_role, IceRole::Controlled);
+        assert_eq!(client_role, IceRole::Controlling);
+        assert_eq!(server.conflicts(), 1);
+        assert_eq!(client.conflicts(), 1);
+
+        // both controlled, the larger tie-breaker becomes controlling
+        let mut server = IceRoleResolver::default();
+        server.on_local_packet(&binding_request(IceRole::Controlled, 30));
+        assert_eq!(server.on_remote_packet(&binding_request(IceRole::Controlled, 20)), Some(IceRole::Controlling));
+        assert_eq!(server.on_remote_packet(&binding_request(IceRole::Controlled, 20)), None);
+    }
+}
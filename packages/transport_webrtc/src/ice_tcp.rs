@@ -0,0 +1,84 @@
+//! ICE-TCP (RFC 6544) for clients in networks which block UDP. Passive tcp host candidates are advertised in answers,
+//! clients connect to them and STUN, DTLS and media are carried over the connection with RFC 4571 framing, which
+//! prefixes each packet with its 16 bits length.
+//!
+//! Tcp sockets are not handled by the sans-io runtime, so listeners are owned outside of the worker and packets are
+//! exchanged with it as [`IceTcpPacket`], a connection is identified by its local and remote addresses. Inside the
+//! worker each listen address has a virtual slot, then transports handle tcp packets same as udp ones, only the
+//! protocol which is given to str0m is different.
+
+use std::net::SocketAddr;
+
+/// Max length of a framed packet
+pub const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
+/// Virtual slots of tcp listen addresses start from it, far from slots of udp sockets which are given by the runtime
+pub(crate) const TCP_SLOT_BASE: usize = usize::MAX / 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceTcpPacket {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Frame a packet for writing to the connection, None if it is longer than [`MAX_FRAME_LEN`]
+pub fn ice_tcp_frame(data: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::try_from(data.len()).ok()?;
+    let mut buf = Vec::with_capacity(2 + data.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(data);
+    Some(buf)
+}
+
+/// Split bytes which are read from a connection into packets
+#[derive(Debug, Default)]
+pub struct IceTcpDecoder {
+    buf: Vec<u8>,
+}
+
+impl IceTcpDecoder {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Pop a complete packet, None until all of its bytes are received
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*self.buf.first()?, *self.buf.get(1)?]) as usize;
+        if self.buf.len() < 2 + len {
+            return None;
+        }
+        let data = self.buf[2..2 + len].to_vec();
+        self.buf.drain(..2 + len);
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ice_tcp_frame, IceTcpDecoder, MAX_FRAME_LEN};
+
+    #[test]
+    fn frame_and_decode_split_stream() {
+        let stream = [ice_tcp_frame(&[1, 2, 3]), ice_tcp_frame(&[]), ice_tcp_frame(&[4; 300])]
+            .into_iter()
+            .flat_map(|f| f.expect("Should frame"))
+            .collect::<Vec<_>>();
+        assert_eq!(&stream[..5], &[0, 3, 1, 2, 3]);
+        assert_eq!(ice_tcp_frame(&vec![0; MAX_FRAME_LEN + 1]), None);
+
+        // bytes arrive in arbitrary chunks
+        let mut decoder = IceTcpDecoder::default();
+        let mut packets = vec![];
+        for chunk in stream.chunks(7) {
+            decoder.push(chunk);
+            while let Some(pkt) = decoder.pop() {
+                packets.push(pkt);
+            }
+        }
+        assert_eq!(packets, vec![vec![1, 2, 3], vec![], vec![4; 300]]);
+
+        decoder.push(&[0]);
+        assert_eq!(decoder.pop(), None);
+    }
+}
@@ -1,23 +1,36 @@
+mod codec_policy;
+mod dtls_policy;
+mod ice_creds;
+mod ice_pair;
+mod ice_role;
+mod ice_tcp;
 mod media;
+mod reaper;
+mod remote_ice;
+mod rtcp_fb;
+mod rtp_extensions;
+mod sdp_bandwidth;
+mod sdp_bundle;
+mod sdp_direction;
+mod sdp_negotiated;
+mod sdp_opus;
+mod sdp_redact;
+mod sdp_session;
+mod sdp_simulcast;
+mod sdp_ssrc;
 mod shared_port;
 mod transport;
 mod worker;
 
-pub use transport::{ExtIn, ExtOut, Variant, VariantParams};
-pub use worker::{GroupInput, GroupOutput, MediaWorkerWebrtc, WebrtcSession};
-
-#[derive(num_enum::TryFromPrimitive, num_enum::IntoPrimitive, derive_more::Display)]
-#[repr(u32)]
-pub enum WebrtcError {
-    InvalidSdp = 0x2000,
-    InternalServerError = 0x2001,
-    RpcInvalidRequest = 0x2002,
-    RpcTrackNameNotFound = 0x2003,
-    RpcTrackNotAttached = 0x2004,
-    RpcTrackAlreadyAttached = 0x2005,
-    RpcEndpointNotFound = 0x2006,
-    RpcTokenInvalid = 0x2007,
-    RpcTokenRoomPeerNotMatch = 0x2008,
-    RpcTokenAppNotMatch = 0x2009,
-    RpcAlreadyDisconnected = 0x2010,
-}
+pub use codec_policy::VideoCodec;
+pub use dtls_policy::{DtlsCertPolicy, DtlsCipher, DtlsPolicy, DtlsSetup, DtlsSuites, DtlsVersion, SrtpProfile};
+pub use ice_creds::IceCredsConfig;
+pub use ice_tcp::{ice_tcp_frame, IceTcpDecoder, IceTcpPacket};
+pub use media_server_protocol::transport::webrtc::WebrtcError;
+pub use reaper::{ReapedSession, ReaperConfig};
+pub use rtcp_fb::RtcpFbPolicy;
+pub use rtp_extensions::RtpExtension;
+pub use sdp_bundle::BundlePolicy;
+pub use sdp_session::SdpSession;
+pub use transport::{ConsentConfig, ExtIn, ExtOut, OfferValidation, Variant, VariantParams};
+pub use worker::{GroupInput, GroupOutput, MediaWorkerWebrtc, WebrtcSession, WebrtcWorkerConfig};
@@ -11,22 +11,105 @@ mod h264;
 mod vp8;
 mod vp9;
 
+/// H264 payloads which we can forward, same as str0m default: (pt, rtx, packetization_mode, profile_level_id)
+const H264_PAYLOADS: [(u8, u8, bool, u32); 7] = [
+    (127, 121, true, 0x42001f),
+    (125, 107, false, 0x42001f),
+    (108, 109, true, 0x42e01f),
+    (124, 120, false, 0x42e01f),
+    (123, 119, true, 0x4d001f),
+    (35, 36, false, 0x4d001f),
+    (114, 115, true, 0x64001f),
+];
+
+/// Clock rate of opus in media packets, RFC 7587 always uses it. Sessions which negotiate a lower clock rate for
+/// telephony interop have their timestamps rescaled
+const OPUS_CLOCK_RATE: u32 = 48000;
+
+/// Ratio of opus clock rate in media packets to the negotiated one, None if it is same or not a divisor
+fn opus_ts_ratio(spec: &CodecSpec) -> Option<u32> {
+    let clock_rate = spec.clock_rate.get();
+    if spec.codec != str0m::format::Codec::Opus || clock_rate == OPUS_CLOCK_RATE {
+        return None;
+    }
+    if clock_rate == 0 || OPUS_CLOCK_RATE % clock_rate != 0 {
+        log::warn!("[MediaConvert] opus clock rate {clock_rate} is not a divisor of {OPUS_CLOCK_RATE} => timestamps are not rescaled");
+        return None;
+    }
+    Some(OPUS_CLOCK_RATE / clock_rate)
+}
+
+/// Rescale timestamps of a stream to a lower clock rate. It works on deltas so the output stays continuous when the
+/// input wraps, which a plain division doesn't
+#[derive(Debug, Default)]
+struct TsDownscale {
+    last: Option<(u32, u32)>,
+}
+
+impl TsDownscale {
+    fn generate(&mut self, ts: u32, ratio: u32) -> u32 {
+        match &mut self.last {
+            Some((input, output)) => {
+                let delta = ts.wrapping_sub(*input) as i32 / ratio as i32;
+                *input = input.wrapping_add((delta * ratio as i32) as u32);
+                *output = output.wrapping_add(delta as u32);
+                *output
+            }
+            None => {
+                let output = ts / ratio;
+                self.last = Some((ts, output));
+                output
+            }
+        }
+    }
+}
+
+/// Select H264 payloads for allowed profile-level-ids in preference order.
+/// Profiles which are not forwardable (not in H264_PAYLOADS) are ignored
+pub fn h264_payloads(profile_level_ids: &[u32]) -> Vec<(Pt, Pt, bool, u32)> {
+    let mut payloads = vec![];
+    for profile_level_id in profile_level_ids {
+        let before = payloads.len();
+        for (pt, rtx, packetization_mode, id) in H264_PAYLOADS.iter() {
+            if id == profile_level_id {
+                payloads.push(((*pt).into(), (*rtx).into(), *packetization_mode, *id));
+            }
+        }
+        if payloads.len() == before {
+            log::warn!("[MediaConvert] h264 profile-level-id {profile_level_id:06x} is not forwardable => ignore");
+        }
+    }
+    payloads
+}
+
 #[derive(Default)]
 pub struct RemoteMediaConvert {
     map: IndexMap<Pt, MediaCodec>,
+    opus_ratios: IndexMap<Pt, u32>,
     ssrcs_rid: IndexMap<Ssrc, u8>,
     ssrcs_mid: IndexMap<Ssrc, Mid>,
+    rids: IndexMap<Mid, Vec<Rid>>,
 }
 
 impl RemoteMediaConvert {
     pub fn set_config(&mut self, cfg: &CodecConfig) {
+        self.map.clear();
+        self.opus_ratios.clear();
         for param in cfg.params() {
+            if let Some(ratio) = opus_ts_ratio(&param.spec()) {
+                self.opus_ratios.insert(param.pt(), ratio);
+            }
             if let Some(codec) = str0m_codec_convert(param.spec()) {
                 self.map.insert(param.pt(), codec);
             }
         }
     }
 
+    /// Set negotiated send rids of each mid, ordered from the lowest layer. It is called again after renegotiation
+    pub fn set_simulcast_rids(&mut self, rids: Vec<(Mid, Vec<Rid>)>) {
+        self.rids = rids.into_iter().collect();
+    }
+
     pub fn get_mid(&mut self, ssrc: Ssrc, mid: Option<Mid>) -> Option<Mid> {
         if let Some(mid) = self.ssrcs_mid.get(&ssrc) {
             Some(*mid)
@@ -40,15 +123,8 @@ impl RemoteMediaConvert {
     /// This method convert rtp to internal media packet.
     /// It convert VideoLayersAllocation ext to simple layers for using for both simulcast and svc
     pub fn convert(&mut self, rtp: RtpPacket) -> Option<MediaPacket> {
-        let spatial = if let Some(rid) = rtp.header.ext_vals.rid {
-            let layer = rid_to_spatial(&rid);
-            if !self.ssrcs_rid.contains_key(&rtp.header.ssrc) {
-                self.ssrcs_rid.insert(rtp.header.ssrc, layer);
-            }
-            Some(layer)
-        } else {
-            self.ssrcs_rid.get(&rtp.header.ssrc).cloned()
-        };
+        let mid = self.get_mid(rtp.header.ssrc, rtp.header.ext_vals.mid);
+        let spatial = self.ssrc_spatial(rtp.header.ssrc, mid, rtp.header.ext_vals.rid);
 
         let codec = self.remote_pt_to_codec(rtp.header.payload_type)?;
         let (nackable, layers, meta) = match codec {
@@ -80,7 +156,7 @@ impl RemoteMediaConvert {
         };
 
         Some(MediaPacket {
-            ts: rtp.header.timestamp,
+            ts: self.convert_ts(rtp.header.payload_type, rtp.header.timestamp),
             seq: rtp.header.sequence_number,
             marker: rtp.header.marker,
             nackable,
@@ -90,21 +166,57 @@ impl RemoteMediaConvert {
         })
     }
 
+    /// Spatial layer of a stream. Layer of a rid is its position in the negotiated rids of the mid, rids which are not negotiated
+    /// fallback to legacy numeric rids "0", "1", "2". Packets without rid use the layer which is learned from their ssrc
+    fn ssrc_spatial(&mut self, ssrc: Ssrc, mid: Option<Mid>, rid: Option<Rid>) -> Option<u8> {
+        let rid = match rid {
+            Some(rid) => rid,
+            None => return self.ssrcs_rid.get(&ssrc).cloned(),
+        };
+        let negotiated = mid.and_then(|mid| self.rids.get(&mid)).and_then(|rids| rids.iter().position(|r| *r == rid));
+        let layer = match negotiated {
+            Some(index) => index as u8,
+            None => rid_to_spatial(&rid),
+        };
+        self.ssrcs_rid.entry(ssrc).or_insert(layer);
+        Some(layer)
+    }
+
+    /// Timestamp in media packets, upscale is a multiply so it keeps continuous when timestamp wraps
+    fn convert_ts(&self, pt: Pt, ts: u32) -> u32 {
+        match self.opus_ratios.get(&pt) {
+            Some(ratio) => ts.wrapping_mul(*ratio),
+            None => ts,
+        }
+    }
+
     fn remote_pt_to_codec(&self, pt: Pt) -> Option<MediaCodec> {
         self.map.get(&pt).cloned()
     }
 }
 
+/// Map codec of forwarded media to the payload type which is negotiated by this session.
+/// Publisher and subscriber can negotiate different dynamic payload types for same codec, media is forwarded as codec
+/// so the publisher pt is never leaked. RTX pt is resolved by str0m from the negotiated params of the written pt.
 #[derive(Default)]
 pub struct LocalMediaConvert {
     map: IndexMap<MediaCodec, Pt>,
+    opus_ratio: Option<u32>,
+    opus_ts: IndexMap<Mid, TsDownscale>,
 }
 
 impl LocalMediaConvert {
+    /// Set negotiated payload types, it is called again after renegotiation. The first pt of a codec is preferred
     pub fn set_config(&mut self, cfg: &CodecConfig) {
+        self.map.clear();
+        self.opus_ratio = None;
         for param in cfg.params() {
             if let Some(codec) = str0m_codec_convert(param.spec()) {
-                self.map.insert(codec, param.pt());
+                log::debug!("[MediaConvert] local codec {codec:?} => pt {}, rtx {:?}", param.pt(), param.resend());
+                if !self.map.contains_key(&codec) && codec == MediaCodec::Opus {
+                    self.opus_ratio = opus_ts_ratio(&param.spec());
+                }
+                self.map.entry(codec).or_insert(param.pt());
             }
         }
     }
@@ -113,6 +225,14 @@ impl LocalMediaConvert {
         self.map.get(&codec).cloned()
     }
 
+    /// Timestamp of a packet which is sent on the mid, in the clock rate which is negotiated for its codec
+    pub fn convert_ts(&mut self, mid: Mid, pkt: &MediaPacket) -> u32 {
+        match (&pkt.meta, self.opus_ratio) {
+            (MediaMeta::Opus { .. }, Some(ratio)) => self.opus_ts.entry(mid).or_default().generate(pkt.ts, ratio),
+            _ => pkt.ts,
+        }
+    }
+
     pub fn rewrite_pkt(&self, pkt: &mut MediaPacket) {
         match &mut pkt.meta {
             MediaMeta::Opus { .. } => {}
@@ -267,6 +387,78 @@ mod tests {
         assert_eq!(str0m_codec_convert(spec), Some(MediaCodec::H264(H264Profile::P42e01fNonInterleaved)));
     }
 
+    #[test]
+    fn rescale_opus_ts_of_lower_clock_rate() {
+        let mut config = str0m::RtcConfig::new().clear_codecs();
+        config.codec_config().add_config(
+            111.into(),
+            None,
+            str0m::format::Codec::Opus,
+            str0m::media::Frequency::new(16000).expect("Should be non zero"),
+            Some(1),
+            str0m::format::FormatParams::parse_line("minptime=10;useinbandfec=1"),
+        );
+        let mut remote = RemoteMediaConvert::default();
+        remote.set_config(config.codec_config());
+        let mut local = LocalMediaConvert::default();
+        local.set_config(config.codec_config());
+
+        assert_eq!(remote.convert_ts(111.into(), 320), 960);
+        assert_eq!(remote.convert_ts(111.into(), u32::MAX), u32::MAX.wrapping_mul(3));
+
+        // 20ms frames at 48 kHz are 320 at 16 kHz, it keeps continuous when 48 kHz timestamp wraps
+        let mid = Mid::from("0");
+        let mut pkt = MediaPacket::build_audio(u32::MAX - 959, 0, None, vec![1, 2, 3]);
+        let first = local.convert_ts(mid, &pkt);
+        pkt.ts = pkt.ts.wrapping_add(960);
+        assert_eq!(local.convert_ts(mid, &pkt), first.wrapping_add(320));
+        pkt.ts = pkt.ts.wrapping_add(960);
+        assert_eq!(local.convert_ts(mid, &pkt), first.wrapping_add(640));
+
+        // 48 kHz sessions keep timestamps
+        let default = str0m::RtcConfig::new();
+        let mut local = LocalMediaConvert::default();
+        local.set_config(default.codec_config());
+        assert_eq!(local.convert_ts(mid, &pkt), pkt.ts);
+    }
+
+    #[test]
+    fn test_remap_payload_type() {
+        let mut publisher = str0m::RtcConfig::new().clear_codecs();
+        publisher.codec_config().add_h264(96.into(), Some(97.into()), true, 0x42e01f);
+        let mut subscriber = str0m::RtcConfig::new().clear_codecs();
+        subscriber.codec_config().add_h264(100.into(), Some(101.into()), true, 0x42e01f);
+        subscriber.codec_config().add_h264(102.into(), Some(103.into()), true, 0x42e01f);
+
+        let mut remote = RemoteMediaConvert::default();
+        remote.set_config(publisher.codec_config());
+        let mut local = LocalMediaConvert::default();
+        local.set_config(subscriber.codec_config());
+
+        let codec = remote.remote_pt_to_codec(96.into()).expect("Should have codec");
+        assert_eq!(codec, MediaCodec::H264(H264Profile::P42e01fNonInterleaved));
+        assert_eq!(local.convert_codec(codec.clone()), Some(100.into()));
+
+        // renegotiated config replaces old payload types
+        let mut renegotiated = str0m::RtcConfig::new().clear_codecs();
+        renegotiated.codec_config().add_h264(104.into(), Some(105.into()), true, 0x42e01f);
+        local.set_config(renegotiated.codec_config());
+        assert_eq!(local.convert_codec(codec), Some(104.into()));
+        assert_eq!(local.convert_codec(MediaCodec::Vp8), None);
+    }
+
+    #[test]
+    fn test_h264_payloads() {
+        let expected: Vec<(Pt, Pt, bool, u32)> = vec![
+            (108.into(), 109.into(), true, 0x42e01f),
+            (124.into(), 120.into(), false, 0x42e01f),
+            (127.into(), 121.into(), true, 0x42001f),
+            (125.into(), 107.into(), false, 0x42001f),
+        ];
+        assert_eq!(h264_payloads(&[0x42e01f, 0x123456, 0x42001f]), expected);
+        assert!(h264_payloads(&[0x640032]).is_empty());
+    }
+
     #[test]
     fn test_rid_to_spatial() {
         let rid0 = Rid::from_array([b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0']);
@@ -283,6 +475,30 @@ mod tests {
         assert_eq!(rid_to_spatial(&rid3), 0);
     }
 
+    #[test]
+    fn test_simulcast_rids_tagging() {
+        let mut remote = RemoteMediaConvert::default();
+        let mid = Mid::from("1");
+        remote.set_simulcast_rids(vec![(mid, vec![Rid::from("q"), Rid::from("h"), Rid::from("f")])]);
+
+        // vp8 descriptor with picture id and temporal layer, then a key frame payload
+        let payload = [0x90, 0xA0, 0x01, 0x20, 0x00, 0x00, 0x00];
+        for (index, (ssrc, rid)) in [(1000, "q"), (1001, "h"), (1002, "f")].into_iter().enumerate() {
+            let ssrc: Ssrc = ssrc.into();
+            let mid = remote.get_mid(ssrc, Some(mid));
+            let spatial = remote.ssrc_spatial(ssrc, mid, Some(Rid::from(rid)));
+            let meta = vp8::parse_rtp(&payload, spatial, None).expect("Should parse");
+            assert!(matches!(meta, MediaMeta::Vp8 { sim: Some(sim), .. } if sim.spatial == index as u8), "rid {rid} should be layer {index}");
+
+            // later packets without mid and rid extensions keep the layer of the ssrc
+            let mid = remote.get_mid(ssrc, None);
+            assert_eq!(remote.ssrc_spatial(ssrc, mid, None), Some(index as u8));
+        }
+
+        // rids which are not negotiated fallback to legacy numeric rids
+        assert_eq!(remote.ssrc_spatial(2000.into(), Some(Mid::from("2")), Some(Rid::from("2"))), Some(2));
+    }
+
     #[test]
     fn test_from_webrtc_orientation() {
         assert_eq!(from_webrtc_orientation(VideoOrientation::Deg0), MediaOrientation::Deg0);
@@ -0,0 +1,45 @@
+//! Safety net for sessions which are stuck in a state, e.g. half-open DTLS or ICE which never completes. Transports
+//! already fail sessions with consent and connect timeouts, the reaper only catches sessions which are missed by them.
+//! The worker scans its sessions periodically, sessions in a state longer than its threshold are force removed and
+//! reported as disconnected with reason `Reaped`, they are also counted as [`ReapedSession`] in `/api/metrics/counts`.
+
+use std::time::{Duration, Instant};
+
+/// Marker type for counting sessions which are force removed by the reaper
+pub struct ReapedSession;
+
+/// State of a session which can be stuck, from the last peer event of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StuckState {
+    Connecting,
+    Reconnecting,
+}
+
+/// Thresholds are None for disabling the check of that state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReaperConfig {
+    pub interval: Duration,
+    pub connecting: Option<Duration>,
+    pub reconnecting: Option<Duration>,
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            connecting: Some(Duration::from_secs(60)),
+            reconnecting: Some(Duration::from_secs(120)),
+        }
+    }
+}
+
+impl ReaperConfig {
+    /// True if a session which is in `state` since `since` should be reaped
+    pub fn is_stuck(&self, now: Instant, state: StuckState, since: Instant) -> bool {
+        let threshold = match state {
+            StuckState::Connecting => self.connecting,
+            StuckState::Reconnecting => self.reconnecting,
+        };
+        threshold.is_some_and(|threshold| now.saturating_duration_since(since) >= threshold)
+    }
+}
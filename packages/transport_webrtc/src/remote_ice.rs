@@ -0,0 +1,146 @@
+//! Remote candidates of a session. Clients can re-send the same candidate when retrying, str0m would create duplicated pairs
+//! for it, so identical candidates are only added once. A submission is a single candidate line or a trickle-ice sdpfrag,
+//! sdp attributes other than candidates (ufrag, mid, end-of-candidates) are ignored.
+//!
+//! Number of candidates of a session is capped, so a client can't exhaust memory by flooding candidates. Submissions
+//! which would exceed the cap are rejected as a whole, candidates which are already added keep working.
+
+use std::collections::HashSet;
+
+use str0m::Candidate;
+
+/// Default max number of remote candidates of a session, far more than a client with many interfaces and relays gathers
+pub const DEFAULT_MAX_REMOTE_CANDIDATES: usize = 256;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemoteIceError {
+    Malformed(String),
+    /// Number of candidates after the submission and the max
+    TooMany(usize, usize),
+}
+
+impl std::fmt::Display for RemoteIceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(e) => write!(f, "{e}"),
+            Self::TooMany(count, max) => write!(f, "too many remote candidates {count}, max {max}"),
+        }
+    }
+}
+
+pub struct RemoteCandidates {
+    seen: HashSet<String>,
+    duplicated: u64,
+    max: usize,
+}
+
+impl Default for RemoteCandidates {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_REMOTE_CANDIDATES)
+    }
+}
+
+impl RemoteCandidates {
+    pub fn new(max: usize) -> Self {
+        Self {
+            seen: HashSet::new(),
+            duplicated: 0,
+            max,
+        }
+    }
+
+    /// Return candidates which are not seen before, or Err if any candidate is malformed or the session would have more
+    /// candidates than the max. Nothing is added on error
+    pub fn on_candidates(&mut self, ices: Vec<String>) -> Result<Vec<String>, RemoteIceError> {
+        let mut added = vec![];
+        let mut added_set = HashSet::new();
+        let mut duplicated = 0;
+        for ice in &ices {
+            for line in ice.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                let is_sdp_line = line.as_bytes().get(1) == Some(&b'=');
+                let line = line.strip_prefix("a=").unwrap_or(line);
+                if !line.starts_with("candidate:") {
+                    if is_sdp_line {
+                        continue;
+                    }
+                    return Err(RemoteIceError::Malformed(format!("malformed candidate {line}")));
+                }
+                if self.seen.contains(line) || added_set.contains(line) {
+                    duplicated += 1;
+                    continue;
+                }
+                if let Err(e) = Candidate::from_sdp_string(line) {
+                    return Err(RemoteIceError::Malformed(format!("malformed candidate {line}: {e}")));
+                }
+                added_set.insert(line);
+                added.push(line.to_string());
+                // stop at the first candidate over the cap, so a large submission is not fully parsed
+                let count = self.seen.len() + added.len();
+                if count > self.max {
+                    return Err(RemoteIceError::TooMany(count, self.max));
+                }
+            }
+        }
+        self.seen.extend(added.iter().cloned());
+        if duplicated > 0 {
+            self.duplicated += duplicated;
+            log::info!("[RemoteCandidates] ignored {duplicated} duplicated candidates, total {}", self.duplicated);
+        }
+        Ok(added)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RemoteCandidates, RemoteIceError};
+
+    const HOST: &str = "candidate:1 1 udp 2122260223 192.168.1.10 50000 typ host generation 0";
+    const SRFLX: &str = "candidate:2 1 udp 1686052607 1.2.3.4 50001 typ srflx raddr 192.168.1.10 rport 50000 generation 0";
+
+    #[test]
+    fn dedup_candidates() {
+        let mut remote = RemoteCandidates::default();
+        assert_eq!(remote.on_candidates(vec![HOST.to_string()]), Ok(vec![HOST.to_string()]));
+        assert_eq!(remote.on_candidates(vec![format!("a={HOST}"), SRFLX.to_string()]), Ok(vec![SRFLX.to_string()]));
+        assert_eq!(remote.on_candidates(vec![HOST.to_string(), HOST.to_string()]), Ok(vec![]));
+    }
+
+    #[test]
+    fn sdpfrag_candidates() {
+        let mut remote = RemoteCandidates::default();
+        let frag = format!("a=ice-ufrag:abcd\r\na=ice-pwd:efgh\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na={HOST}\r\na=end-of-candidates\r\n");
+        assert_eq!(remote.on_candidates(vec![frag]), Ok(vec![HOST.to_string()]));
+    }
+
+    #[test]
+    fn reject_malformed_candidates() {
+        let mut remote = RemoteCandidates::default();
+        assert!(remote.on_candidates(vec![SRFLX.to_string(), "candidate:1 1 udp".to_string()]).is_err());
+        assert!(remote.on_candidates(vec!["not a candidate".to_string()]).is_err());
+        // nothing is added when rejected
+        assert_eq!(remote.on_candidates(vec![SRFLX.to_string()]), Ok(vec![SRFLX.to_string()]));
+    }
+
+    #[test]
+    fn reject_candidates_over_max() {
+        let mut remote = RemoteCandidates::new(2);
+        assert_eq!(remote.on_candidates(vec![HOST.to_string()]), Ok(vec![HOST.to_string()]));
+        let relay = "candidate:3 1 udp 41885439 5.6.7.8 50002 typ relay raddr 1.2.3.4 rport 50001 generation 0";
+        assert_eq!(remote.on_candidates(vec![SRFLX.to_string(), relay.to_string()]), Err(RemoteIceError::TooMany(3, 2)));
+        // duplicated candidates are not counted, and nothing is added by the rejected submission
+        assert_eq!(remote.on_candidates(vec![HOST.to_string(), SRFLX.to_string()]), Ok(vec![SRFLX.to_string()]));
+        assert_eq!(remote.on_candidates(vec![relay.to_string()]), Err(RemoteIceError::TooMany(3, 2)));
+        assert_eq!(remote.on_candidates(vec![HOST.to_string()]), Ok(vec![]));
+    }
+
+    #[test]
+    fn stop_large_submission_at_max() {
+        let mut remote = RemoteCandidates::new(2);
+        let candidates = (0..100)
+            .map(|i| format!("candidate:{i} 1 udp 2122260223 192.168.1.10 {} typ host generation 0", 50000 + i))
+            .collect::<Vec<_>>();
+        // checking stops at the first candidate over the max, so count is not of the whole submission
+        assert_eq!(remote.on_candidates(candidates), Err(RemoteIceError::TooMany(3, 2)));
+        assert_eq!(remote.on_candidates(vec![HOST.to_string()]), Ok(vec![HOST.to_string()]));
+    }
+}
@@ -0,0 +1,133 @@
+//! RTCP feedback (RFC 4585) toward clients. Some legacy clients don't offer `a=rtcp-fb` for NACK or PLI, sending
+//! these feedbacks to them does nothing, so with `auto` policy they are only sent when the client offers them:
+//! - NACK: `a=rtcp-fb:<pt> nack`
+//! - key-frame request: `a=rtcp-fb:<pt> nack pli` or `a=rtcp-fb:<pt> ccm fir`, without it we rely on key-frame interval of client
+//!
+//! `enabled` and `disabled` policies ignore the offer. Transport-cc and REMB are not affected.
+
+use std::{fmt::Display, str::FromStr};
+
+const RTCP_FB: &str = "a=rtcp-fb:";
+const NACK: &str = "nack";
+const KEY_FRAME_REQUESTS: [&str; 2] = ["nack pli", "ccm fir"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RtcpFbPolicy {
+    #[default]
+    Auto,
+    Enabled,
+    Disabled,
+}
+
+impl FromStr for RtcpFbPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "enabled" => Ok(Self::Enabled),
+            "disabled" => Ok(Self::Disabled),
+            _ => Err(format!("unsupported rtcp-fb policy {s}")),
+        }
+    }
+}
+
+impl Display for RtcpFbPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Enabled => write!(f, "enabled"),
+            Self::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+/// Feedbacks which the server sends to a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcpFeedback {
+    pub nack: bool,
+    pub key_frame_request: bool,
+}
+
+/// Values of all `a=rtcp-fb` lines of offer, without payload type
+fn offer_rtcp_fb(offer: &str) -> impl Iterator<Item = &str> {
+    offer
+        .lines()
+        .filter_map(|line| line.trim_end().strip_prefix(RTCP_FB))
+        .filter_map(|fb| fb.split_once(' ').map(|(_, value)| value.trim()))
+}
+
+pub fn rtcp_fb_negotiated(offer: &str, policy: RtcpFbPolicy) -> RtcpFeedback {
+    match policy {
+        RtcpFbPolicy::Enabled => RtcpFeedback { nack: true, key_frame_request: true },
+        RtcpFbPolicy::Disabled => RtcpFeedback {
+            nack: false,
+            key_frame_request: false,
+        },
+        RtcpFbPolicy::Auto => RtcpFeedback {
+            nack: offer_rtcp_fb(offer).any(|value| value == NACK),
+            key_frame_request: offer_rtcp_fb(offer).any(|value| KEY_FRAME_REQUESTS.contains(&value)),
+        },
+    }
+}
+
+/// Remove `a=rtcp-fb` lines of feedbacks which are not sent, so answer matches server behavior
+pub fn answer_rtcp_fb(answer: &str, fb: RtcpFeedback) -> String {
+    if fb.nack && fb.key_frame_request {
+        return answer.to_string();
+    }
+    let mut out = String::with_capacity(answer.len());
+    for line in answer.split_inclusive('\n') {
+        let value = line.trim_end().strip_prefix(RTCP_FB).and_then(|fb| fb.split_once(' ')).map(|(_, value)| value.trim());
+        let removed = match value {
+            Some(NACK) => !fb.nack,
+            Some(value) => KEY_FRAME_REQUESTS.contains(&value) && !fb.key_frame_request,
+            None => false,
+        };
+        if !removed {
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{answer_rtcp_fb, rtcp_fb_negotiated, RtcpFbPolicy, RtcpFeedback};
+
+    const OFFER: &str = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\na=rtcp-fb:96 transport-cc\r\na=rtcp-fb:96 nack\r\na=rtcp-fb:96 nack pli\r\n";
+    const LEGACY_OFFER: &str = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n";
+
+    #[test]
+    fn negotiate_rtcp_fb() {
+        let all = RtcpFeedback { nack: true, key_frame_request: true };
+        let none = RtcpFeedback {
+            nack: false,
+            key_frame_request: false,
+        };
+        assert_eq!(rtcp_fb_negotiated(OFFER, RtcpFbPolicy::Auto), all);
+        assert_eq!(rtcp_fb_negotiated(LEGACY_OFFER, RtcpFbPolicy::Auto), none);
+        assert_eq!(
+            rtcp_fb_negotiated("v=0\r\na=rtcp-fb:96 ccm fir\r\n", RtcpFbPolicy::Auto),
+            RtcpFeedback { nack: false, key_frame_request: true }
+        );
+        assert_eq!(rtcp_fb_negotiated(LEGACY_OFFER, RtcpFbPolicy::Enabled), all);
+        assert_eq!(rtcp_fb_negotiated(OFFER, RtcpFbPolicy::Disabled), none);
+        assert_eq!("Disabled".parse(), Ok(RtcpFbPolicy::Disabled));
+        assert!("off".parse::<RtcpFbPolicy>().is_err());
+    }
+
+    #[test]
+    fn answer_remove_disabled_feedback() {
+        let answer = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtcp-fb:96 transport-cc\r\na=rtcp-fb:96 ccm fir\r\na=rtcp-fb:96 nack\r\na=rtcp-fb:96 nack pli\r\n";
+        let fb = RtcpFeedback { nack: true, key_frame_request: true };
+        assert_eq!(answer_rtcp_fb(answer, fb), answer);
+        let fb = RtcpFeedback { nack: false, key_frame_request: true };
+        assert_eq!(answer_rtcp_fb(answer, fb), answer.replace("a=rtcp-fb:96 nack\r\n", ""));
+        let fb = RtcpFeedback {
+            nack: false,
+            key_frame_request: false,
+        };
+        assert_eq!(answer_rtcp_fb(answer, fb), "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtcp-fb:96 transport-cc\r\n");
+    }
+}
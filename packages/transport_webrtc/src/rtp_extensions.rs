@@ -0,0 +1,101 @@
+//! RTP header extensions which are used for congestion control. Both are accepted by default, but some clients
+//! misbehave with them so each one can be disabled by config. A disabled extension is never in the answer.
+
+use std::{fmt::Display, str::FromStr};
+
+use str0m::rtp::Extension;
+
+const ABS_SEND_TIME_URI: &str = "http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time";
+const TRANSPORT_CC_URI: &str = "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RtpExtension {
+    AbsSendTime,
+    TransportCc,
+}
+
+impl RtpExtension {
+    pub fn uri(&self) -> &'static str {
+        match self {
+            Self::AbsSendTime => ABS_SEND_TIME_URI,
+            Self::TransportCc => TRANSPORT_CC_URI,
+        }
+    }
+
+    /// Id is same as str0m default extension map
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Self::AbsSendTime => 2,
+            Self::TransportCc => 3,
+        }
+    }
+
+    pub(crate) fn extension(&self) -> Extension {
+        match self {
+            Self::AbsSendTime => Extension::AbsoluteSendTime,
+            Self::TransportCc => Extension::TransportSequenceNumber,
+        }
+    }
+}
+
+impl FromStr for RtpExtension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "abs-send-time" => Ok(Self::AbsSendTime),
+            "transport-cc" => Ok(Self::TransportCc),
+            _ => Err(format!("unsupported rtp extension {s}")),
+        }
+    }
+}
+
+impl Display for RtpExtension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AbsSendTime => f.write_str("abs-send-time"),
+            Self::TransportCc => f.write_str("transport-cc"),
+        }
+    }
+}
+
+/// Same extensions as str0m default map, without the disabled ones
+pub(crate) fn extension_map(disabled: &[RtpExtension]) -> Vec<(u8, Extension)> {
+    let mut exts = vec![(1, Extension::AudioLevel)];
+    for ext in [RtpExtension::AbsSendTime, RtpExtension::TransportCc] {
+        if !disabled.contains(&ext) {
+            exts.push((ext.id(), ext.extension()));
+        }
+    }
+    exts.push((4, Extension::RtpMid));
+    exts.push((10, Extension::RtpStreamId));
+    exts.push((11, Extension::RepairedRtpStreamId));
+    exts.push((13, Extension::VideoOrientation));
+    exts
+}
+
+/// Check if offer has `a=extmap:<id>[/direction] <uri>` line for the extension
+pub fn offer_has_extension(offer: &str, ext: RtpExtension) -> bool {
+    offer
+        .lines()
+        .filter_map(|line| line.strip_prefix("a=extmap:"))
+        .any(|extmap| extmap.split_whitespace().nth(1) == Some(ext.uri()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{offer_has_extension, RtpExtension};
+
+    #[test]
+    fn parse_extension_config_and_offer() {
+        assert_eq!("transport-cc".parse::<RtpExtension>(), Ok(RtpExtension::TransportCc));
+        assert_eq!("Abs-Send-Time".parse::<RtpExtension>(), Ok(RtpExtension::AbsSendTime));
+        assert!("toffset".parse::<RtpExtension>().is_err());
+
+        let offer = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+            a=extmap:3 http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01\r\n\
+            a=extmap:4/sendrecv urn:ietf:params:rtp-hdrext:sdes:mid\r\n";
+        assert!(offer_has_extension(offer, RtpExtension::TransportCc));
+        assert!(!offer_has_extension(offer, RtpExtension::AbsSendTime));
+    }
+}
@@ -0,0 +1,74 @@
+//! Bandwidth lines in offer. Clients can signal the max bitrate they want to receive with `b=AS` (kbps)
+//! or `b=TIAS` (bps), we use it for limiting the egress of the session.
+
+/// Max receive bitrate in bps which is signaled in offer, None if offer dont have any bandwidth line.
+///
+/// Session level line is used if present, otherwise it is the sum of media level lines.
+/// When both AS and TIAS are present at same level, TIAS is preferred because it is more precise.
+pub fn offer_max_bitrate(offer: &str) -> Option<u64> {
+    let mut session = Bandwidth::default();
+    let mut medias: Vec<Bandwidth> = vec![];
+    for line in offer.lines() {
+        if line.starts_with("m=") {
+            medias.push(Bandwidth::default());
+        } else if let Some(bandwidth) = line.strip_prefix("b=") {
+            let level = medias.last_mut().unwrap_or(&mut session);
+            if let Some(kbps) = bandwidth.strip_prefix("AS:") {
+                level.as_bps = kbps.trim().parse::<u64>().ok().map(|kbps| kbps * 1000);
+            } else if let Some(bps) = bandwidth.strip_prefix("TIAS:") {
+                level.tias_bps = bps.trim().parse::<u64>().ok();
+            }
+        }
+    }
+
+    if let Some(bitrate) = session.bitrate() {
+        return Some(bitrate);
+    }
+    medias.iter().filter_map(Bandwidth::bitrate).reduce(|a, b| a + b)
+}
+
+/// Egress cap of session, client request is only used to lower the server configured max
+pub fn egress_bitrate_cap(offer: &str, server_max: u64) -> u64 {
+    match offer_max_bitrate(offer) {
+        // zero means client dont want to receive anything, it is invalid for us so we ignore it
+        Some(bitrate) if bitrate > 0 => bitrate.min(server_max),
+        _ => server_max,
+    }
+}
+
+#[derive(Default)]
+struct Bandwidth {
+    as_bps: Option<u64>,
+    tias_bps: Option<u64>,
+}
+
+impl Bandwidth {
+    fn bitrate(&self) -> Option<u64> {
+        self.tias_bps.or(self.as_bps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{egress_bitrate_cap, offer_max_bitrate};
+
+    #[test]
+    fn parse_session_and_media_level() {
+        assert_eq!(offer_max_bitrate("v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\n"), None);
+        assert_eq!(offer_max_bitrate("v=0\r\nb=AS:500\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\nb=AS:300\r\n"), Some(500_000));
+        assert_eq!(offer_max_bitrate("v=0\r\nb=AS:500\r\nb=TIAS:480000\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n"), Some(480_000));
+        assert_eq!(
+            offer_max_bitrate("v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\nb=TIAS:64000\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\nb=AS:300\r\n"),
+            Some(364_000)
+        );
+    }
+
+    #[test]
+    fn egress_cap_from_offer() {
+        let offer = "v=0\r\nb=AS:500\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n";
+        assert_eq!(egress_bitrate_cap(offer, 2_500_000), 500_000);
+        assert_eq!(egress_bitrate_cap(offer, 300_000), 300_000);
+        assert_eq!(egress_bitrate_cap("v=0\r\nb=AS:0\r\n", 2_500_000), 2_500_000);
+        assert_eq!(egress_bitrate_cap("v=0\r\n", 2_500_000), 2_500_000);
+    }
+}
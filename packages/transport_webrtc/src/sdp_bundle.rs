@@ -0,0 +1,208 @@
+//! Bundle policy of answers. The server uses a single ICE/DTLS transport per session, so every m-line which carries media
+//! must be inside the BUNDLE group:
+//! - `max-bundle`: the offer must put all used m-lines into a single BUNDLE group, other offers are rejected.
+//! - `balanced`: m-lines which are offered outside the first BUNDLE group are answered as `inactive` and kept out of the
+//!   answer group. Offers without any BUNDLE group are negotiated as before.
+//!
+//! An m-line with port 0 is rejected by the offerer, except when it is marked `a=bundle-only`.
+
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BundlePolicy {
+    #[default]
+    Balanced,
+    MaxBundle,
+}
+
+impl FromStr for BundlePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "balanced" => Ok(Self::Balanced),
+            "max-bundle" => Ok(Self::MaxBundle),
+            _ => Err(format!("unsupported bundle policy {s}")),
+        }
+    }
+}
+
+impl Display for BundlePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Balanced => f.write_str("balanced"),
+            Self::MaxBundle => f.write_str("max-bundle"),
+        }
+    }
+}
+
+const DIRECTIONS: [&str; 4] = ["a=sendrecv", "a=sendonly", "a=recvonly", "a=inactive"];
+
+struct Section {
+    lines: Vec<String>,
+    mid: Option<String>,
+    used: bool,
+}
+
+fn parse_sections(sdp: &str) -> (Vec<Vec<String>>, Vec<Section>) {
+    let mut groups = vec![];
+    let mut sections: Vec<Section> = vec![Section {
+        lines: vec![],
+        mid: None,
+        used: false,
+    }];
+    for line in sdp.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            sections.push(Section {
+                lines: vec![],
+                mid: None,
+                used: media.split_whitespace().nth(1) != Some("0"),
+            });
+        }
+        let section = sections.last_mut().expect("Should have section");
+        if let Some(group) = line.strip_prefix("a=group:BUNDLE") {
+            groups.push(group.split_whitespace().map(|mid| mid.to_string()).collect::<Vec<_>>());
+        } else if let Some(mid) = line.strip_prefix("a=mid:") {
+            section.mid = Some(mid.trim().to_string());
+        } else if line == "a=bundle-only" {
+            section.used = true;
+        }
+        section.lines.push(line.to_string());
+    }
+    (groups, sections)
+}
+
+/// Check offer with the bundle policy and rewrite unbundled m-lines as inactive.
+/// Return the rewritten offer and mids which are allowed in the answer BUNDLE group, None if the offer has no BUNDLE group
+pub fn offer_bundle(offer: &str, policy: BundlePolicy) -> Result<(String, Option<Vec<String>>), String> {
+    let (groups, sections) = parse_sections(offer);
+    let unbundled = |group: &[String]| {
+        sections
+            .iter()
+            .skip(1)
+            .filter(|section| section.used && !section.mid.as_ref().is_some_and(|mid| group.contains(mid)))
+            .map(|section| section.mid.clone().unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+
+    match policy {
+        BundlePolicy::MaxBundle => {
+            if groups.len() != 1 {
+                return Err(format!("bundle policy {policy} requires one BUNDLE group, offered {}", groups.len()));
+            }
+            let unbundled = unbundled(&groups[0]);
+            if !unbundled.is_empty() {
+                return Err(format!("bundle policy {policy} requires all m-lines in BUNDLE group, {unbundled:?} are not"));
+            }
+            Ok((offer.to_string(), groups.into_iter().next()))
+        }
+        BundlePolicy::Balanced => {
+            let group = match groups.into_iter().next() {
+                Some(group) => group,
+                None => return Ok((offer.to_string(), None)),
+            };
+            let unbundled = unbundled(&group);
+            if unbundled.is_empty() {
+                return Ok((offer.to_string(), Some(group)));
+            }
+            log::info!("[SdpBundle] m-lines {unbundled:?} are not in BUNDLE group {group:?} => answer inactive");
+            let mut out = String::with_capacity(offer.len() + 32);
+            for mut section in sections {
+                if section.used && section.mid.as_ref().is_some_and(|mid| unbundled.contains(mid)) {
+                    match section.lines.iter().position(|line| DIRECTIONS.contains(&line.as_str())) {
+                        Some(index) => section.lines[index] = "a=inactive".to_string(),
+                        None => section.lines.push("a=inactive".to_string()),
+                    }
+                }
+                for line in section.lines {
+                    out.push_str(&line);
+                    out.push_str("\r\n");
+                }
+            }
+            Ok((out, Some(group)))
+        }
+    }
+}
+
+/// Keep only mids which are allowed by the offer in answer BUNDLE group, other lines are kept as is
+pub fn answer_bundle(answer: &str, bundled: Option<&[String]>) -> String {
+    let bundled = match bundled {
+        Some(bundled) => bundled,
+        None => return answer.to_string(),
+    };
+    let mut out = String::with_capacity(answer.len());
+    for line in answer.split_inclusive('\n') {
+        match line.trim_end().strip_prefix("a=group:BUNDLE") {
+            Some(group) => {
+                out.push_str("a=group:BUNDLE");
+                for mid in group.split_whitespace().filter(|mid| bundled.iter().any(|bundled| bundled == mid)) {
+                    out.push(' ');
+                    out.push_str(mid);
+                }
+                out.push_str("\r\n");
+            }
+            None => out.push_str(line),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{answer_bundle, offer_bundle, BundlePolicy};
+
+    fn offer(group: Option<&str>, mids: &[(&str, u16)]) -> String {
+        let mut sdp = "v=0\r\ns=-\r\nt=0 0\r\n".to_string();
+        if let Some(group) = group {
+            sdp.push_str(&format!("a=group:BUNDLE {group}\r\n"));
+        }
+        for (mid, port) in mids {
+            sdp.push_str(&format!("m=audio {port} UDP/TLS/RTP/SAVPF 111\r\na=mid:{mid}\r\na=sendrecv\r\n"));
+        }
+        sdp
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!("max-bundle".parse(), Ok(BundlePolicy::MaxBundle));
+        assert_eq!("Balanced".parse(), Ok(BundlePolicy::Balanced));
+        assert!("max-compat".parse::<BundlePolicy>().is_err());
+        assert_eq!(BundlePolicy::MaxBundle.to_string(), "max-bundle");
+    }
+
+    #[test]
+    fn max_bundle_require_single_group() {
+        let bundled = offer(Some("0 1"), &[("0", 9), ("1", 0)]);
+        let (sdp, group) = offer_bundle(&bundled.replace("a=mid:1\r\n", "a=mid:1\r\na=bundle-only\r\n"), BundlePolicy::MaxBundle).expect("Should accept");
+        assert!(sdp.contains("a=bundle-only"));
+        assert_eq!(group, Some(vec!["0".to_string(), "1".to_string()]));
+
+        assert!(offer_bundle(&offer(None, &[("0", 9)]), BundlePolicy::MaxBundle).is_err());
+        assert!(offer_bundle(&offer(Some("0"), &[("0", 9), ("1", 9)]), BundlePolicy::MaxBundle).is_err());
+        // rejected m-line doesn't need to be bundled
+        assert!(offer_bundle(&offer(Some("0"), &[("0", 9), ("1", 0)]), BundlePolicy::MaxBundle).is_ok());
+    }
+
+    #[test]
+    fn balanced_answer_unbundled_inactive() {
+        let (sdp, group) = offer_bundle(&offer(Some("0"), &[("0", 9), ("1", 9)]), BundlePolicy::Balanced).expect("Should accept");
+        assert_eq!(
+            sdp,
+            "v=0\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=sendrecv\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:1\r\na=inactive\r\n"
+        );
+        assert_eq!(group, Some(vec!["0".to_string()]));
+
+        let legacy = offer(None, &[("0", 9)]);
+        assert_eq!(offer_bundle(&legacy, BundlePolicy::Balanced), Ok((legacy.clone(), None)));
+    }
+
+    #[test]
+    fn answer_group_follow_offer() {
+        let answer = "v=0\r\na=group:BUNDLE 0 1\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n";
+        assert_eq!(answer_bundle(answer, None), answer);
+        assert_eq!(
+            answer_bundle(answer, Some(&["0".to_string()])),
+            "v=0\r\na=group:BUNDLE 0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n"
+        );
+    }
+}
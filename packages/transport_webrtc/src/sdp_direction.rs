@@ -0,0 +1,160 @@
+//! Direction of offered m-lines. The answer direction is the complement of the offer direction, so an m-line which
+//! the server doesn't handle in that direction is rewritten before negotiation:
+//! - Whip only receives from client: `sendrecv` is answered as `recvonly`, `recvonly` is answered as `inactive`.
+//! - Whep only sends to client: `sendrecv` is answered as `sendonly`, `sendonly` is answered as `inactive`.
+//! - SDK publishes `sendonly` m-lines and subscribes `recvonly` m-lines, `sendrecv` is treated as publishing like before.
+//!
+//! An m-line without direction attribute is `sendrecv` by RFC 8866.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfferRole {
+    Whip,
+    Whep,
+    Sdk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OfferDirection {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl OfferDirection {
+    fn parse(line: &str) -> Option<Self> {
+        match line {
+            "a=sendrecv" => Some(Self::SendRecv),
+            "a=sendonly" => Some(Self::SendOnly),
+            "a=recvonly" => Some(Self::RecvOnly),
+            "a=inactive" => Some(Self::Inactive),
+            _ => None,
+        }
+    }
+
+    fn as_line(&self) -> &'static str {
+        match self {
+            Self::SendRecv => "a=sendrecv",
+            Self::SendOnly => "a=sendonly",
+            Self::RecvOnly => "a=recvonly",
+            Self::Inactive => "a=inactive",
+        }
+    }
+
+    /// Direction which the server accepts for the offered direction
+    fn accepted(self, role: OfferRole) -> Self {
+        match (role, self) {
+            (OfferRole::Whip, Self::SendRecv) => Self::SendOnly,
+            (OfferRole::Whip, Self::RecvOnly) => Self::Inactive,
+            (OfferRole::Whep, Self::SendRecv) => Self::RecvOnly,
+            (OfferRole::Whep, Self::SendOnly) => Self::Inactive,
+            (OfferRole::Sdk, Self::SendRecv) => Self::SendOnly,
+            (_, direction) => direction,
+        }
+    }
+}
+
+struct Section {
+    lines: Vec<String>,
+    is_media: bool,
+    direction: Option<usize>,
+}
+
+/// Rewrite direction of audio and video m-lines by what the role handles, other lines are kept as is
+pub fn offer_directions(offer: &str, role: OfferRole) -> String {
+    let mut sections: Vec<Section> = vec![Section {
+        lines: vec![],
+        is_media: false,
+        direction: None,
+    }];
+    for line in offer.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            sections.push(Section {
+                lines: vec![],
+                is_media: media.starts_with("audio") || media.starts_with("video"),
+                direction: None,
+            });
+        }
+        let section = sections.last_mut().expect("Should have section");
+        if section.is_media && OfferDirection::parse(line).is_some() {
+            section.direction = Some(section.lines.len());
+        }
+        section.lines.push(line.to_string());
+    }
+
+    let mut out = String::with_capacity(offer.len() + 32);
+    for mut section in sections {
+        if section.is_media {
+            let offered = section.direction.and_then(|index| OfferDirection::parse(&section.lines[index])).unwrap_or(OfferDirection::SendRecv);
+            let accepted = offered.accepted(role);
+            if accepted != offered {
+                log::info!("[SdpDirection] {role:?} rewrite offered {} of {} => {}", offered.as_line(), section.lines[0], accepted.as_line());
+                match section.direction {
+                    Some(index) => section.lines[index] = accepted.as_line().to_string(),
+                    None => section.lines.push(accepted.as_line().to_string()),
+                }
+            }
+        }
+        for line in section.lines {
+            out.push_str(&line);
+            out.push_str("\r\n");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{offer_directions, OfferRole};
+
+    fn offer(directions: &[Option<&str>]) -> String {
+        let mut sdp = "v=0\r\ns=-\r\nt=0 0\r\n".to_string();
+        for (index, direction) in directions.iter().enumerate() {
+            sdp.push_str(&format!("m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:{index}\r\n"));
+            if let Some(direction) = direction {
+                sdp.push_str(&format!("a={direction}\r\n"));
+            }
+            sdp.push_str("a=rtpmap:111 opus/48000/2\r\n");
+        }
+        sdp.push_str("m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=mid:data\r\n");
+        sdp
+    }
+
+    fn directions(sdp: &str) -> Vec<String> {
+        sdp.split("m=")
+            .skip(1)
+            .filter(|section| !section.starts_with("application"))
+            .map(|section| {
+                section
+                    .lines()
+                    .find(|line| ["a=sendrecv", "a=sendonly", "a=recvonly", "a=inactive"].contains(line))
+                    .unwrap_or("none")
+                    .to_string()
+            })
+            .collect()
+    }
+
+    const OFFERED: [Option<&str>; 5] = [Some("sendrecv"), Some("sendonly"), Some("recvonly"), Some("inactive"), None];
+
+    #[test]
+    fn whip_only_receive() {
+        let sdp = offer_directions(&offer(&OFFERED), OfferRole::Whip);
+        assert_eq!(directions(&sdp), vec!["a=sendonly", "a=sendonly", "a=inactive", "a=inactive", "a=sendonly"]);
+        assert!(sdp.ends_with("m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=mid:data\r\n"));
+    }
+
+    #[test]
+    fn whep_only_send() {
+        let sdp = offer_directions(&offer(&OFFERED), OfferRole::Whep);
+        assert_eq!(directions(&sdp), vec!["a=recvonly", "a=inactive", "a=recvonly", "a=inactive", "a=recvonly"]);
+    }
+
+    #[test]
+    fn sdk_keep_explicit_directions() {
+        let sdp = offer_directions(&offer(&OFFERED), OfferRole::Sdk);
+        assert_eq!(directions(&sdp), vec!["a=sendonly", "a=sendonly", "a=recvonly", "a=inactive", "a=sendonly"]);
+
+        let explicit = offer(&[Some("sendonly"), Some("recvonly")]);
+        assert_eq!(offer_directions(&explicit, OfferRole::Sdk), explicit);
+    }
+}
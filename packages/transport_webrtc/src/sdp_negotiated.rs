@@ -0,0 +1,93 @@
+//! Negotiated session summary for diagnostics. Codecs are read from the answer, the first payload of an
+//! accepted m-section is the one which is used. Simulcast layers are only signaled in the offer.
+
+use media_server_core::transport::TransportNegotiated;
+use media_server_protocol::endpoint::TrackEncoding;
+
+/// Build summary from final answer and simulcast encodings of the offer
+pub fn answer_negotiated(answer: &str, encodings: &[TrackEncoding]) -> TransportNegotiated {
+    let max_resolution = encodings
+        .iter()
+        .filter_map(|encoding| Some((encoding.max_width?, encoding.max_height?)))
+        .max_by_key(|(width, height)| width * height);
+    TransportNegotiated {
+        audio_codec: answer_codec(answer, "audio"),
+        video_codec: answer_codec(answer, "video"),
+        max_resolution,
+        layers: encodings.iter().map(|encoding| encoding.rid.clone()).collect(),
+    }
+}
+
+/// Codec name of the first accepted m-section of the kind, rejected sections have port 0
+fn answer_codec(answer: &str, kind: &str) -> Option<String> {
+    let mut payload: Option<&str> = None;
+    for line in answer.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            if payload.is_some() {
+                break;
+            }
+            let mut parts = media.split_whitespace();
+            if parts.next() == Some(kind) && parts.next() != Some("0") {
+                // skip proto, the first format is the selected payload
+                payload = parts.nth(1);
+            }
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            let (pt, codec) = match rtpmap.split_once(' ') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            if payload == Some(pt) {
+                return codec.split('/').next().map(|codec| codec.to_string());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_core::transport::TransportNegotiated;
+    use media_server_protocol::endpoint::TrackEncoding;
+
+    use super::answer_negotiated;
+
+    fn encoding(rid: &str, max_width: Option<u32>, max_height: Option<u32>) -> TrackEncoding {
+        TrackEncoding {
+            rid: rid.to_string(),
+            max_width,
+            max_height,
+            max_fps: None,
+        }
+    }
+
+    #[test]
+    fn negotiated_codecs_and_layers() {
+        let answer = "v=0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=rtpmap:111 opus/48000/2\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 98 99\r\na=mid:1\r\na=rtpmap:96 VP8/90000\r\na=rtpmap:98 VP9/90000\r\na=rtpmap:99 rtx/90000\r\n";
+        let encodings = vec![encoding("q", Some(320), Some(180)), encoding("h", Some(640), Some(360)), encoding("f", None, None)];
+        assert_eq!(
+            answer_negotiated(answer, &encodings),
+            TransportNegotiated {
+                audio_codec: Some("opus".to_string()),
+                video_codec: Some("VP9".to_string()),
+                max_resolution: Some((640, 360)),
+                layers: vec!["q".to_string(), "h".to_string(), "f".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn rejected_section_has_no_codec() {
+        let answer = "v=0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=rtpmap:111 opus/48000/2\r\n\
+            m=video 0 UDP/TLS/RTP/SAVPF 96\r\na=mid:1\r\na=rtpmap:96 VP8/90000\r\n";
+        assert_eq!(
+            answer_negotiated(answer, &[]),
+            TransportNegotiated {
+                audio_codec: Some("opus".to_string()),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Opus quality of answers. `maxaveragebitrate` (RFC 7587) in the answer fmtp is the max bitrate which we want to receive,
+//! so clients encode opus at the bitrate which is configured for their app. Encoder complexity has no SDP parameter.
+//!
+//! RFC 7587 requires `opus/48000/2` in rtpmap, but some telephony systems negotiate opus with other clock rate or
+//! channels. str0m is built with the configured format, so only offered opus of the same format is answered, and
+//! media packets keep 48 kHz timestamps which are rescaled at the session (see `media`). The answered rtpmap is checked
+//! against the offer, which must have the configured format.
+
+use media_server_core::endpoint::OpusParams;
+
+const MAX_AVERAGE_BITRATE: &str = "maxaveragebitrate";
+
+/// Set `maxaveragebitrate` in fmtp of all opus payloads of answer, existing value is replaced.
+/// A fmtp line is added after rtpmap for payloads which dont have one
+pub fn answer_opus(answer: &str, params: OpusParams) -> String {
+    let bitrate = match params.max_average_bitrate {
+        Some(bitrate) => bitrate,
+        None => return answer.to_string(),
+    };
+    let opus_pts = answer
+        .lines()
+        .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+        .filter_map(|rtpmap| rtpmap.split_once(' '))
+        .filter(|(_, codec)| codec.split('/').next().is_some_and(|name| name.eq_ignore_ascii_case("opus")))
+        .map(|(pt, _)| pt.to_string())
+        .collect::<Vec<_>>();
+    if opus_pts.is_empty() {
+        return answer.to_string();
+    }
+    let has_fmtp = |pt: &str| {
+        answer
+            .lines()
+            .any(|line| line.strip_prefix("a=fmtp:").and_then(|fmtp| fmtp.split_once(' ')).is_some_and(|(p, _)| p == pt))
+    };
+
+    let mut out = String::with_capacity(answer.len() + 64 * opus_pts.len());
+    for line in answer.split_inclusive('\n') {
+        let content = line.trim_end();
+        let ending = &line[content.len()..];
+        if let Some((pt, params)) = content.strip_prefix("a=fmtp:").and_then(|fmtp| fmtp.split_once(' ')) {
+            if opus_pts.iter().any(|opus| opus == pt) {
+                let mut params = params
+                    .split(';')
+                    .filter(|param| !param.trim().starts_with(MAX_AVERAGE_BITRATE) && !param.trim().is_empty())
+                    .collect::<Vec<_>>();
+                let bitrate = format!("{MAX_AVERAGE_BITRATE}={bitrate}");
+                params.push(&bitrate);
+                out.push_str(&format!("a=fmtp:{pt} {}{ending}", params.join(";")));
+                continue;
+            }
+        }
+        out.push_str(line);
+        if let Some((pt, _)) = content.strip_prefix("a=rtpmap:").and_then(|rtpmap| rtpmap.split_once(' ')) {
+            if opus_pts.iter().any(|opus| opus == pt) && !has_fmtp(pt) {
+                let ending = if ending.is_empty() {
+                    "\r\n"
+                } else {
+                    ending
+                };
+                out.push_str(&format!("a=fmtp:{pt} {MAX_AVERAGE_BITRATE}={bitrate}{ending}"));
+            }
+        }
+    }
+    out
+}
+
+/// (payload type, clock rate, channels) of opus rtpmaps, channels is 1 when it is omitted
+fn opus_rtpmaps(sdp: &str) -> Vec<(&str, u32, u8)> {
+    sdp.lines()
+        .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+        .filter_map(|rtpmap| rtpmap.split_once(' '))
+        .filter_map(|(pt, codec)| {
+            let mut parts = codec.trim().split('/');
+            if !parts.next()?.eq_ignore_ascii_case("opus") {
+                return None;
+            }
+            let clock_rate = parts.next()?.parse().ok()?;
+            let channels = parts.next().map_or(Some(1), |channels| channels.parse().ok())?;
+            Some((pt, clock_rate, channels))
+        })
+        .collect()
+}
+
+/// Set clock rate and channels of opus rtpmaps of answer. Err if the offer doesn't have the configured format for an
+/// answered opus payload
+pub fn answer_opus_rtpmap(offer: &str, answer: &str, params: OpusParams) -> Result<String, String> {
+    let offered = opus_rtpmaps(offer);
+    let answered = opus_rtpmaps(answer);
+    if answered.is_empty() {
+        return Ok(answer.to_string());
+    }
+    for (pt, _, _) in &answered {
+        match offered.iter().find(|(offer_pt, _, _)| offer_pt == pt) {
+            Some((_, clock_rate, channels)) if *clock_rate == params.clock_rate && *channels == params.channels => {}
+            Some((_, clock_rate, channels)) => {
+                return Err(format!("offer opus {pt} is {clock_rate}/{channels}, configured {}/{}", params.clock_rate, params.channels));
+            }
+            None => return Err(format!("offer doesn't have opus {pt}")),
+        }
+    }
+
+    let mut out = String::with_capacity(answer.len());
+    for line in answer.split_inclusive('\n') {
+        let content = line.trim_end();
+        let ending = &line[content.len()..];
+        match content.strip_prefix("a=rtpmap:").and_then(|rtpmap| rtpmap.split_once(' ')) {
+            Some((pt, _)) if answered.iter().any(|(opus, _, _)| *opus == pt) => {
+                out.push_str(&format!("a=rtpmap:{pt} opus/{}/{}{ending}", params.clock_rate, params.channels));
+            }
+            _ => out.push_str(line),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_core::endpoint::OpusParams;
+
+    use super::{answer_opus, answer_opus_rtpmap};
+
+    const ANSWER: &str = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\na=rtpmap:111 opus/48000/2\r\na=fmtp:111 minptime=10;useinbandfec=1\r\na=rtpmap:0 PCMU/8000\r\n";
+
+    fn params(bitrate: Option<u32>) -> OpusParams {
+        OpusParams {
+            max_average_bitrate: bitrate,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn set_max_average_bitrate() {
+        assert_eq!(answer_opus(ANSWER, params(None)), ANSWER);
+        assert_eq!(
+            answer_opus(ANSWER, params(Some(128_000))),
+            "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\na=rtpmap:111 opus/48000/2\r\na=fmtp:111 minptime=10;useinbandfec=1;maxaveragebitrate=128000\r\na=rtpmap:0 PCMU/8000\r\n"
+        );
+        // existing value is replaced
+        let answer = answer_opus(ANSWER, params(Some(128_000)));
+        assert_eq!(answer_opus(&answer, params(Some(16_000))), answer.replace("128000", "16000"));
+    }
+
+    #[test]
+    fn add_fmtp_when_missing() {
+        let answer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\na=rtcp-fb:111 transport-cc\r\n";
+        assert_eq!(
+            answer_opus(answer, params(Some(24_000))),
+            "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\na=fmtp:111 maxaveragebitrate=24000\r\na=rtcp-fb:111 transport-cc\r\n"
+        );
+    }
+
+    #[test]
+    fn configured_clock_rate_in_rtpmap() {
+        assert_eq!(answer_opus_rtpmap(ANSWER, ANSWER, params(None)), Ok(ANSWER.to_string()));
+
+        let telephony = OpusParams {
+            clock_rate: 16000,
+            channels: 1,
+            ..Default::default()
+        };
+        let offer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\na=rtpmap:111 opus/16000\r\na=rtpmap:0 PCMU/8000\r\n";
+        let answer = answer_opus_rtpmap(offer, ANSWER, telephony).expect("Should answer");
+        assert!(answer.contains("a=rtpmap:111 opus/16000/1\r\n"));
+        assert!(answer.contains("a=rtpmap:0 PCMU/8000\r\n"));
+
+        // offer must have the configured format
+        assert_eq!(answer_opus_rtpmap(ANSWER, ANSWER, telephony), Err("offer opus 111 is 48000/2, configured 16000/1".to_string()));
+    }
+}
@@ -0,0 +1,35 @@
+//! Redaction of SDPs which are returned by diagnostic APIs. The ICE password is enough for sending STUN checks in the
+//! name of a session, so it is removed. Other lines are kept because they are needed for debugging negotiation.
+
+const REDACTED_PREFIXES: [&str; 1] = ["a=ice-pwd:"];
+
+/// Replace values of sensitive attributes with `<redacted>`, line endings are kept as is
+pub fn redact_sdp(sdp: &str) -> String {
+    let mut out = String::with_capacity(sdp.len());
+    for line in sdp.split_inclusive('\n') {
+        let content = line.trim_end();
+        match REDACTED_PREFIXES.iter().find(|prefix| content.starts_with(*prefix)) {
+            Some(prefix) => {
+                out.push_str(prefix);
+                out.push_str("<redacted>");
+                out.push_str(&line[content.len()..]);
+            }
+            None => out.push_str(line),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact_sdp;
+
+    #[test]
+    fn redact_ice_pwd() {
+        let sdp = "v=0\r\na=ice-ufrag:S5hk\r\na=ice-pwd:0zV/Yu3y8aDzbHgqWhnVQhqP\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=ice-pwd:other\n";
+        assert_eq!(
+            redact_sdp(sdp),
+            "v=0\r\na=ice-ufrag:S5hk\r\na=ice-pwd:<redacted>\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=ice-pwd:<redacted>\n"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Session-level lines of the answer. Some SIP/RTP gateways check the `o=` and `s=` lines, so the origin username,
+//! session name and session `a=tool` attribute can be set by config. Unset fields keep the lines generated by str0m.
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SdpSession {
+    /// Username of `o=` line, spaces are not allowed by RFC 8866 so they are replaced with `-`
+    pub origin_username: Option<String>,
+    /// Value of `s=` line
+    pub session_name: Option<String>,
+    /// Value of session-level `a=tool:` attribute
+    pub tool: Option<String>,
+}
+
+impl SdpSession {
+    fn is_default(&self) -> bool {
+        self.origin_username.is_none() && self.session_name.is_none() && self.tool.is_none()
+    }
+}
+
+/// Rewrite session-level lines of answer by config, media sections are kept as is
+pub fn answer_sdp_session(answer: &str, session: &SdpSession) -> String {
+    if session.is_default() {
+        return answer.to_string();
+    }
+    let mut out = String::with_capacity(answer.len() + 64);
+    let mut in_session = true;
+    let mut tool_written = session.tool.is_none();
+    for line in answer.split_inclusive('\n') {
+        let content = line.trim_end();
+        if in_session {
+            if !tool_written && (content.starts_with("a=") || content.starts_with("m=")) {
+                if let Some(tool) = &session.tool {
+                    out.push_str(&format!("a=tool:{tool}\r\n"));
+                }
+                tool_written = true;
+            }
+            if content.starts_with("m=") {
+                in_session = false;
+            } else if let (Some(origin), Some(username)) = (content.strip_prefix("o="), &session.origin_username) {
+                let rest = origin.split_once(' ').map(|(_, rest)| rest).unwrap_or_default();
+                out.push_str(&format!("o={} {rest}\r\n", username.replace(char::is_whitespace, "-")));
+                continue;
+            } else if let (true, Some(name)) = (content.starts_with("s="), &session.session_name) {
+                let name = if name.is_empty() {
+                    "-"
+                } else {
+                    name.as_str()
+                };
+                out.push_str(&format!("s={name}\r\n"));
+                continue;
+            } else if content.starts_with("a=tool:") && session.tool.is_some() {
+                continue;
+            }
+        }
+        out.push_str(line);
+    }
+    if !tool_written {
+        if let Some(tool) = &session.tool {
+            out.push_str(&format!("a=tool:{tool}\r\n"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{answer_sdp_session, SdpSession};
+
+    const ANSWER: &str = "v=0\r\no=- 123456 2 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=tool:other\r\n";
+
+    #[test]
+    fn default_keep_answer() {
+        assert_eq!(answer_sdp_session(ANSWER, &SdpSession::default()), ANSWER);
+    }
+
+    #[test]
+    fn rewrite_session_lines() {
+        let session = SdpSession {
+            origin_username: Some("media server".to_string()),
+            session_name: Some("Media Session".to_string()),
+            tool: Some("atm0s".to_string()),
+        };
+        assert_eq!(
+            answer_sdp_session(ANSWER, &session),
+            "v=0\r\no=media-server 123456 2 IN IP4 0.0.0.0\r\ns=Media Session\r\nt=0 0\r\na=tool:atm0s\r\na=group:BUNDLE 0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=tool:other\r\n"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Simulcast encodings in offer. Publisher declares each encoding with `a=rid:<rid> send [restrictions]`,
+//! restrictions are optional so resolution and fps are only known when the client signals them.
+//! The `a=simulcast:send` attribute lists the rids of an m-section from the lowest to the highest layer, the position of a
+//! rid in that list is the spatial layer of its stream.
+
+use media_server_protocol::endpoint::TrackEncoding;
+use str0m::media::{Mid, Rid};
+
+/// Send encodings of the first video m-section which has rids, in order of appearance
+pub fn offer_video_encodings(offer: &str) -> Vec<TrackEncoding> {
+    let mut encodings = vec![];
+    let mut in_video = false;
+    for line in offer.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            if !encodings.is_empty() {
+                break;
+            }
+            in_video = media.starts_with("video");
+        } else if let Some(rid) = line.strip_prefix("a=rid:") {
+            if !in_video {
+                continue;
+            }
+            let mut parts = rid.split_whitespace();
+            let (rid, direction) = match (parts.next(), parts.next()) {
+                (Some(rid), Some(direction)) => (rid, direction),
+                _ => continue,
+            };
+            if direction != "send" {
+                continue;
+            }
+            let mut encoding = TrackEncoding {
+                rid: rid.to_string(),
+                max_width: None,
+                max_height: None,
+                max_fps: None,
+            };
+            for restriction in parts.next().unwrap_or_default().split(';') {
+                let (key, value) = match restriction.split_once('=') {
+                    Some(pair) => pair,
+                    None => continue,
+                };
+                // max-fps is allowed to be decimal, we only need integer precision
+                let value = value.trim().parse::<f32>().ok().map(|v| v as u32);
+                match key.trim() {
+                    "max-width" => encoding.max_width = value,
+                    "max-height" => encoding.max_height = value,
+                    "max-fps" => encoding.max_fps = value,
+                    _ => {}
+                }
+            }
+            encodings.push(encoding);
+        }
+    }
+    encodings
+}
+
+struct RidSection {
+    mid: Option<String>,
+    rids: Vec<(String, Option<u32>)>,
+    simulcast: Option<Vec<String>>,
+}
+
+impl RidSection {
+    /// Rids in layer order: the simulcast attribute order, or the `a=rid` order when it is missing.
+    /// When every rid declares max-width the order is sorted by it, so clients which list the highest layer first are still mapped
+    fn ordered(self) -> Option<(Mid, Vec<Rid>)> {
+        let mid = self.mid?;
+        let mut rids = match self.simulcast {
+            Some(order) => order.into_iter().filter_map(|rid| self.rids.iter().find(|(declared, _)| *declared == rid).cloned()).collect::<Vec<_>>(),
+            None => self.rids,
+        };
+        if rids.is_empty() {
+            return None;
+        }
+        if rids.iter().all(|(_, width)| width.is_some()) {
+            rids.sort_by_key(|(_, width)| *width);
+        }
+        Some((Mid::from(mid.as_str()), rids.iter().map(|(rid, _)| Rid::from(rid.as_str())).collect()))
+    }
+}
+
+/// Send rids of each m-section with mid, ordered from the lowest to the highest spatial layer
+pub fn offer_simulcast_rids(offer: &str) -> Vec<(Mid, Vec<Rid>)> {
+    let mut sections = vec![];
+    let mut section: Option<RidSection> = None;
+    for line in offer.lines() {
+        if line.starts_with("m=") {
+            sections.extend(section.take().and_then(RidSection::ordered));
+            section = Some(RidSection {
+                mid: None,
+                rids: vec![],
+                simulcast: None,
+            });
+        }
+        let section = match section.as_mut() {
+            Some(section) => section,
+            None => continue,
+        };
+        if let Some(mid) = line.strip_prefix("a=mid:") {
+            section.mid = Some(mid.trim().to_string());
+        } else if let Some(rid) = line.strip_prefix("a=rid:") {
+            let mut parts = rid.split_whitespace();
+            if let (Some(rid), Some("send")) = (parts.next(), parts.next()) {
+                let max_width = parts
+                    .next()
+                    .unwrap_or_default()
+                    .split(';')
+                    .find_map(|restriction| restriction.trim().strip_prefix("max-width="))
+                    .and_then(|value| value.trim().parse::<u32>().ok());
+                section.rids.push((rid.to_string(), max_width));
+            }
+        } else if let Some(simulcast) = line.strip_prefix("a=simulcast:") {
+            let mut parts = simulcast.split_whitespace();
+            while let (Some(direction), Some(streams)) = (parts.next(), parts.next()) {
+                if direction != "send" {
+                    continue;
+                }
+                // each stream can have alternatives separated by comma, only the first one is used, `~` marks a paused stream
+                let order = streams
+                    .split(';')
+                    .filter_map(|stream| stream.split(',').next())
+                    .map(|rid| rid.trim_start_matches('~').to_string())
+                    .collect();
+                section.simulcast = Some(order);
+            }
+        }
+    }
+    sections.extend(section.and_then(RidSection::ordered));
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use media_server_protocol::endpoint::TrackEncoding;
+    use str0m::media::{Mid, Rid};
+
+    use super::{offer_simulcast_rids, offer_video_encodings};
+
+    fn encoding(rid: &str, max_width: Option<u32>, max_height: Option<u32>, max_fps: Option<u32>) -> TrackEncoding {
+        TrackEncoding {
+            rid: rid.to_string(),
+            max_width,
+            max_height,
+            max_fps,
+        }
+    }
+
+    #[test]
+    fn parse_three_layers_simulcast() {
+        let offer = "v=0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\na=rtpmap:111 opus/48000/2\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:1\r\na=rtpmap:96 VP8/90000\r\n\
+            a=rid:q send max-width=320;max-height=180;max-fps=15\r\n\
+            a=rid:h send max-width=640;max-height=360;max-fps=29.97\r\n\
+            a=rid:f send\r\n\
+            a=simulcast:send q;h;f\r\n";
+        assert_eq!(
+            offer_video_encodings(offer),
+            vec![
+                encoding("q", Some(320), Some(180), Some(15)),
+                encoding("h", Some(640), Some(360), Some(29)),
+                encoding("f", None, None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignore_non_simulcast_and_recv_rids() {
+        assert_eq!(offer_video_encodings("v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n"), vec![]);
+        assert_eq!(offer_video_encodings("v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rid:h recv\r\n"), vec![]);
+    }
+
+    #[test]
+    fn simulcast_rids_layer_order() {
+        let offer = "v=0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:1\r\n\
+            a=rid:f send\r\na=rid:h send\r\na=rid:q send\r\n\
+            a=simulcast:send q;h,x;~f\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:2\r\n\
+            a=rid:hi send max-width=1280\r\na=rid:lo send max-width=320\r\n";
+        assert_eq!(
+            offer_simulcast_rids(offer),
+            vec![
+                (Mid::from("1"), vec![Rid::from("q"), Rid::from("h"), Rid::from("f")]),
+                (Mid::from("2"), vec![Rid::from("lo"), Rid::from("hi")]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! SSRC declarations and groups (RFC 5576). A sent stream with RTX (RFC 4588) must be declared with
+//! `a=ssrc-group:FID <media> <rtx>`, without a valid group the receiver cannot map retransmissions to the media stream.
+//!
+//! Clients publish simulcast in two ways:
+//! - rid-based: `a=rid:<rid> send` and `a=simulcast:send`, SSRCs are learned from RTP packets
+//! - SSRC-based: `a=ssrc-group:SIM <low> <mid> <high>`, which is used by legacy clients with munged SDP
+//!
+//! Only rid-based simulcast is supported, so an SSRC-based one is reduced to its highest layer (with its RTX) and
+//! published as a single stream.
+
+const SSRC: &str = "a=ssrc:";
+const SSRC_GROUP: &str = "a=ssrc-group:";
+
+#[derive(Default)]
+struct SsrcSection {
+    mid: String,
+    ssrcs: Vec<u32>,
+    /// (semantics, ssrcs)
+    groups: Vec<(String, Vec<u32>)>,
+    rtx: bool,
+    rids: bool,
+}
+
+impl SsrcSection {
+    fn fid_groups(&self) -> impl Iterator<Item = &Vec<u32>> {
+        self.groups.iter().filter(|(semantics, _)| semantics == "FID").map(|(_, ssrcs)| ssrcs)
+    }
+}
+
+fn parse_group(group: &str) -> (String, Vec<u32>) {
+    let mut parts = group.split_whitespace();
+    let semantics = parts.next().unwrap_or_default().to_string();
+    (semantics, parts.filter_map(|ssrc| ssrc.parse().ok()).collect())
+}
+
+fn ssrc_sections(sdp: &str) -> Vec<SsrcSection> {
+    let mut sections: Vec<SsrcSection> = vec![];
+    for line in sdp.lines().map(str::trim_end) {
+        if line.starts_with("m=") {
+            sections.push(SsrcSection::default());
+            continue;
+        }
+        let section = match sections.last_mut() {
+            Some(section) => section,
+            None => continue,
+        };
+        if let Some(mid) = line.strip_prefix("a=mid:") {
+            section.mid = mid.to_string();
+        } else if let Some(ssrc) = line.strip_prefix(SSRC) {
+            if let Some(ssrc) = ssrc.split_whitespace().next().and_then(|ssrc| ssrc.parse().ok()) {
+                if !section.ssrcs.contains(&ssrc) {
+                    section.ssrcs.push(ssrc);
+                }
+            }
+        } else if let Some(group) = line.strip_prefix(SSRC_GROUP) {
+            section.groups.push(parse_group(group));
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            section.rtx |= rtpmap.split(' ').nth(1).is_some_and(|codec| codec.to_ascii_lowercase().starts_with("rtx/"));
+        } else if line.starts_with("a=rid:") {
+            section.rids = true;
+        }
+    }
+    sections
+}
+
+/// All FID groups as (media, rtx) SSRC pairs
+pub fn fid_groups(sdp: &str) -> Vec<(u32, u32)> {
+    ssrc_sections(sdp)
+        .iter()
+        .flat_map(|section| section.fid_groups().filter(|ssrcs| ssrcs.len() == 2).map(|ssrcs| (ssrcs[0], ssrcs[1])).collect::<Vec<_>>())
+        .collect()
+}
+
+/// Check SSRC groups of answer: grouped SSRCs must be declared, a FID group links two different SSRCs, and when RTX is
+/// negotiated in a section which declares SSRCs, each of them must be in a FID group
+pub fn check_answer_ssrc(answer: &str) -> Result<(), String> {
+    for section in ssrc_sections(answer) {
+        let mid = &section.mid;
+        for (semantics, ssrcs) in &section.groups {
+            if let Some(ssrc) = ssrcs.iter().find(|ssrc| !section.ssrcs.contains(ssrc)) {
+                return Err(format!("ssrc-group:{semantics} of mid {mid} has undeclared ssrc {ssrc}"));
+            }
+        }
+        for ssrcs in section.fid_groups() {
+            if ssrcs.len() != 2 || ssrcs[0] == ssrcs[1] {
+                return Err(format!("ssrc-group:FID of mid {mid} should link media and rtx ssrc, got {ssrcs:?}"));
+            }
+        }
+        if section.rtx {
+            if let Some(ssrc) = section.ssrcs.iter().find(|ssrc| !section.fid_groups().any(|group| group.contains(ssrc))) {
+                return Err(format!("ssrc {ssrc} of mid {mid} is not in a ssrc-group:FID while rtx is negotiated"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reduce SSRC-based simulcast of offer to its highest layer. Sections with rids are not changed, because
+/// rid-based simulcast is used when a client declares both
+pub fn offer_ssrc_simulcast(offer: &str) -> String {
+    let sections = ssrc_sections(offer);
+    let mut out = String::with_capacity(offer.len());
+    let mut index = None;
+    let mut keep: Option<Vec<u32>> = None;
+    for line in offer.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if trimmed.starts_with("m=") {
+            let current = index.map_or(0, |i| i + 1);
+            index = Some(current);
+            keep = sections.get(current).filter(|section| !section.rids).and_then(|section| {
+                let (_, layers) = section.groups.iter().find(|(semantics, ssrcs)| semantics == "SIM" && ssrcs.len() > 1)?;
+                let highest = *layers.last()?;
+                let mut keep = vec![highest];
+                keep.extend(section.fid_groups().filter(|ssrcs| ssrcs.first() == Some(&highest)).flat_map(|ssrcs| ssrcs.iter().skip(1).copied()));
+                log::info!("[SdpSsrc] mid {} has ssrc-based simulcast {layers:?} => only highest layer {highest} is published", section.mid);
+                Some(keep)
+            });
+        } else if let Some(keep) = &keep {
+            let removed = if let Some(ssrc) = trimmed.strip_prefix(SSRC) {
+                ssrc.split_whitespace().next().and_then(|ssrc| ssrc.parse::<u32>().ok()).is_some_and(|ssrc| !keep.contains(&ssrc))
+            } else if let Some(group) = trimmed.strip_prefix(SSRC_GROUP) {
+                let (semantics, ssrcs) = parse_group(group);
+                semantics == "SIM" || ssrcs.iter().any(|ssrc| !keep.contains(ssrc))
+            } else {
+                false
+            };
+            if removed {
+                continue;
+            }
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_answer_ssrc, fid_groups, offer_ssrc_simulcast};
+
+    const SECTION: &str = "m=video 9 UDP/TLS/RTP/SAVPF 96 97\r\na=mid:0\r\na=rtpmap:96 VP8/90000\r\na=rtpmap:97 rtx/90000\r\na=fmtp:97 apt=96\r\n";
+
+    #[test]
+    fn check_answer_fid_group() {
+        let valid = format!("v=0\r\n{SECTION}a=ssrc-group:FID 1000 1001\r\na=ssrc:1000 cname:a\r\na=ssrc:1001 cname:a\r\n");
+        assert_eq!(check_answer_ssrc(&valid), Ok(()));
+        assert_eq!(fid_groups(&valid), vec![(1000, 1001)]);
+
+        // receive only section doesn't declare ssrc
+        assert_eq!(check_answer_ssrc(&format!("v=0\r\n{SECTION}")), Ok(()));
+
+        let missing_group = format!("v=0\r\n{SECTION}a=ssrc:1000 cname:a\r\na=ssrc:1001 cname:a\r\n");
+        assert!(check_answer_ssrc(&missing_group).is_err());
+        let undeclared = format!("v=0\r\n{SECTION}a=ssrc-group:FID 1000 1001\r\na=ssrc:1000 cname:a\r\n");
+        assert!(check_answer_ssrc(&undeclared).is_err());
+        let same = format!("v=0\r\n{SECTION}a=ssrc-group:FID 1000 1000\r\na=ssrc:1000 cname:a\r\n");
+        assert!(check_answer_ssrc(&same).is_err());
+    }
+
+    #[test]
+    fn reduce_ssrc_simulcast_to_highest_layer() {
+        let offer = format!(
+            "v=0\r\n{SECTION}a=ssrc-group:SIM 1 2 3\r\na=ssrc-group:FID 1 11\r\na=ssrc-group:FID 2 12\r\na=ssrc-group:FID 3 13\r\n\
+a=ssrc:1 cname:a\r\na=ssrc:11 cname:a\r\na=ssrc:2 cname:a\r\na=ssrc:12 cname:a\r\na=ssrc:3 cname:a\r\na=ssrc:13 cname:a\r\n"
+        );
+        assert_eq!(
+            offer_ssrc_simulcast(&offer),
+            format!("v=0\r\n{SECTION}a=ssrc-group:FID 3 13\r\na=ssrc:3 cname:a\r\na=ssrc:13 cname:a\r\n")
+        );
+
+        // rid-based simulcast is kept
+        let rid_offer = format!("{offer}a=rid:h send\r\na=rid:l send\r\na=simulcast:send l;h\r\n");
+        assert_eq!(offer_ssrc_simulcast(&rid_offer), rid_offer);
+        let single = format!("v=0\r\n{SECTION}a=ssrc-group:FID 1 11\r\na=ssrc:1 cname:a\r\na=ssrc:11 cname:a\r\n");
+        assert_eq!(offer_ssrc_simulcast(&single), single);
+    }
+}
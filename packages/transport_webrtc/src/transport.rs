@@ -1,4 +1,5 @@
 use std::{
+    collections::HashSet,
     marker::PhantomData,
     net::{IpAddr, SocketAddr},
     ops::Deref,
@@ -8,18 +9,22 @@ use std::{
 
 use indexmap::IndexMap;
 use media_server_core::{
-    endpoint::{EndpointEvent, EndpointReqId, EndpointRes},
-    transport::{Transport, TransportInput, TransportOutput},
+    endpoint::{EndpointEvent, EndpointReqId, EndpointRes, OpusConfig, OpusParams, DEFAULT_OPUS_CHANNELS, DEFAULT_OPUS_CLOCK_RATE},
+    transport::{Transport, TransportEvent, TransportInput, TransportOutput},
 };
 use media_server_protocol::{
-    endpoint::{PeerId, RoomId},
-    media::MediaPacket,
+    endpoint::{ClusterConnId, PeerId, RoomId},
+    media::{MediaKind, MediaPacket},
     multi_tenancy::AppContext,
-    protobuf::gateway::ConnectRequest,
-    transport::{RpcError, RpcResult},
+    protobuf::gateway::{ConnectRequest, ValidateOfferResponse},
+    transport::{
+        webrtc::{SessionDump, WebrtcMigrateTicket},
+        whep::WhepEvent,
+        RpcError, RpcResult,
+    },
 };
 use media_server_secure::MediaEdgeSecure;
-use media_server_utils::{Count, IndexMap2d, RtpSeqExtend};
+use media_server_utils::{is_rtp, Count, IndexMap2d, RtpIngest, RtpIngestGuard, RtpIngestPolicy, RtpSeqExtend};
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
     collections::DynamicDeque,
@@ -27,25 +32,52 @@ use sans_io_runtime::{
 };
 use str0m::{
     bwe::Bitrate,
-    change::{DtlsCert, SdpOffer},
+    change::{DtlsCert, SdpAnswer, SdpOffer, SdpPendingOffer},
     channel::{ChannelConfig, ChannelId},
-    format::CodecConfig,
+    format::{Codec, CodecConfig, FormatParams},
     ice::IceCreds,
-    media::{KeyframeRequestKind, Mid},
+    media::{Direction, Frequency, KeyframeRequestKind, Mid, Rid},
     net::{Protocol, Receive},
-    Candidate, Rtc,
+    rtp::Ssrc,
+    Candidate, IceConnectionState, Rtc, RtcConfig,
 };
 
 use crate::{
-    media::{to_webrtc_extensions, LocalMediaConvert},
-    WebrtcError,
+    codec_policy::{sdp_media_codecs, supported_media_codecs},
+    dtls_policy::{check_answer_setup, DtlsPolicy, HelloReassembly},
+    ice_creds::IceCredsConfig,
+    ice_pair::{IceHint, IcePairs},
+    ice_role::{IceRole, IceRoleResolver},
+    media::{h264_payloads, to_webrtc_extensions, LocalMediaConvert},
+    remote_ice::{RemoteCandidates, RemoteIceError},
+    rtcp_fb::{answer_rtcp_fb, rtcp_fb_negotiated, RtcpFbPolicy, RtcpFeedback},
+    rtp_extensions::{extension_map, offer_has_extension, RtpExtension},
+    sdp_bundle::{answer_bundle, offer_bundle, BundlePolicy},
+    sdp_direction::{offer_directions, OfferRole},
+    sdp_negotiated::answer_negotiated,
+    sdp_opus::{answer_opus, answer_opus_rtpmap},
+    sdp_redact::redact_sdp,
+    sdp_session::{answer_sdp_session, SdpSession},
+    sdp_simulcast::{offer_simulcast_rids, offer_video_encodings},
+    sdp_ssrc::{check_answer_ssrc, offer_ssrc_simulcast},
+    VideoCodec, WebrtcError,
 };
 
+use self::pacer::EgressPacer;
+
 mod bwe_state;
+mod pacer;
+mod state;
 mod webrtc;
 mod whep;
 mod whip;
 
+/// Max time an events request is held without events, it is answered empty after that so the stream checks the session again
+pub(crate) const EVENTS_LONG_POLL: Duration = Duration::from_secs(10);
+
+/// Payload type of opus which str0m uses by default, it is kept when opus is configured with other clock rate
+const OPUS_PT: u8 = 111;
+
 #[allow(clippy::large_enum_variant)]
 pub enum VariantParams<ES> {
     Whip(RoomId, PeerId, Option<String>, bool),
@@ -66,6 +98,15 @@ pub enum ExtIn {
     /// Last option<string>, bool is extra_data and record flag
     RestartIce(u64, AppContext, Variant, IpAddr, String, ConnectRequest, Option<String>, bool),
     Disconnect(u64, Variant),
+    /// Close from server side, used by bulk close, no response is sent back
+    Close,
+    /// Ask client to move to other node with the provided conn id and ticket, only supported by sdk sessions
+    Migrate(u64, ClusterConnId, WebrtcMigrateTicket),
+    /// Take buffered session events, bool is true for the first request of a stream. Other requests are held until
+    /// events are buffered. Only supported by whep sessions
+    Events(u64, bool),
+    /// Diagnostic state of the session, supported by all variants
+    Dump(u64),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -74,15 +115,27 @@ pub enum ExtOut {
     /// response is (ice_lite, answer_sdp)
     RestartIce(u64, Variant, RpcResult<(bool, String)>),
     Disconnect(u64, Variant, RpcResult<()>),
+    Migrate(u64, RpcResult<ClusterConnId>),
+    Events(u64, RpcResult<Vec<WhepEvent>>),
+    Dump(u64, RpcResult<SessionDump>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum InternalRpcReq {
     SetRemoteSdp(String),
+    /// Server-initiated offer which adds a send-only m-line for each kind
+    CreateOffer(Vec<MediaKind>),
+    /// Answer of the pending server offer
+    SetRemoteAnswer(String),
+    /// Drop the pending server offer which is not answered, its changes are never applied. It isn't responded
+    CancelOffer,
 }
 
 enum InternalRpcRes {
     SetRemoteSdp(String),
+    /// None when str0m has nothing to offer
+    CreateOffer(Option<String>),
+    SetRemoteAnswer,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -99,62 +152,432 @@ enum InternalOutput {
 
 trait TransportWebrtcInternal {
     fn on_codec_config(&mut self, cfg: &CodecConfig);
+    /// Called with negotiated send rids of each mid after an offer is accepted, ordered from the lowest spatial layer
+    fn on_simulcast_rids(&mut self, rids: Vec<(Mid, Vec<Rid>)>);
     fn on_tick(&mut self, now: Instant);
     fn on_rpc_res(&mut self, req_id: u32, res: RpcResult<InternalRpcRes>);
     fn on_transport_rpc_res(&mut self, now: Instant, req_id: EndpointReqId, res: EndpointRes);
     fn on_endpoint_event(&mut self, now: Instant, input: EndpointEvent);
     fn on_str0m_event(&mut self, now: Instant, event: str0m::Event);
     fn is_empty(&self) -> bool;
+    /// Called when remote peer did not send anything in consent timeout, transport should switch to failed state
+    fn on_consent_failed(&mut self, now: Instant);
     fn on_shutdown(&mut self, now: Instant);
+    /// Ask client to reconnect with `conn_id`, the ticket is completed with the session state and signed for the client.
+    /// Return false if the variant dont have any way to signal client
+    fn on_migrate(&mut self, now: Instant, conn_id: String, ticket: WebrtcMigrateTicket) -> bool;
+    /// Take buffered events for the server to client event stream, None if the variant doesn't stream events
+    fn pop_events(&mut self) -> Option<Vec<WhepEvent>>;
     fn pop_output(&mut self, now: Instant) -> Option<InternalOutput>;
 }
 
+/// Consent freshness config (RFC 7675).
+/// Keepalive STUN checks are sent by str0m, here we only check when was the last time remote sent anything to us,
+/// if it is longer than the timeout the session is failed and destroyed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsentConfig {
+    /// Timeout applied while ICE is not connected yet
+    pub timeout: Duration,
+    /// Timeout applied after ICE connected
+    pub established_timeout: Duration,
+    /// Interval of keepalive checks on the nominated pair, it is the max STUN retransmit timeout of str0m.
+    /// Only full ICE agents send checks, with ice-lite the keepalives are sent by the client
+    pub keepalive_interval: Duration,
+}
+
+impl Default for ConsentConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            established_timeout: Duration::from_secs(30),
+            keepalive_interval: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Settings of the worker which every session is negotiated with, the addresses are the bound udp sockets and ICE-TCP
+/// listeners with their slots
+pub struct TransportWebrtcConfig {
+    pub dtls_cert: DtlsCert,
+    pub dtls_policy: DtlsPolicy,
+    pub rtp_ingest: RtpIngestPolicy,
+    pub addrs: Vec<(SocketAddr, usize)>,
+    pub addrs_alt: Vec<SocketAddr>,
+    pub tcp_addrs: Vec<(SocketAddr, usize)>,
+    pub ice_lite: bool,
+    pub ice_creds: IceCredsConfig,
+    pub consent: ConsentConfig,
+    pub candidate_order: Vec<IpAddr>,
+    pub h264_profiles: Vec<u32>,
+    pub disabled_extensions: Vec<RtpExtension>,
+    pub rtcp_fb: RtcpFbPolicy,
+    pub sdp_session: SdpSession,
+    pub bundle_policy: BundlePolicy,
+    pub opus: OpusConfig,
+    pub max_candidates: Option<usize>,
+    pub max_media_sections: usize,
+    pub max_remote_candidates: usize,
+}
+
 pub struct TransportWebrtc<ES> {
     _c: Count<Self>,
     next_tick: Option<Instant>,
     rtc: Rtc,
     rtc_ice_lite: bool,
+    /// Last negotiated offer and answer, kept for diagnostics
+    offer: String,
+    answer: String,
+    ice_state: IceConnectionState,
+    consent: ConsentConfig,
+    ice_established: bool,
+    last_recv: Option<Instant>,
+    consent_failed: bool,
+    dtls_policy: DtlsPolicy,
+    /// Hello reassembly of received and sent packets, hellos may be fragmented
+    hello_in: HelloReassembly,
+    hello_out: HelloReassembly,
+    dtls_rejected: bool,
+    rtp_ingest: RtpIngestGuard,
+    rtp_abusive: bool,
+    bundle_policy: BundlePolicy,
+    opus: OpusParams,
+    rtcp_fb: RtcpFeedback,
+    /// Incoming streams which NACK is already suppressed for, when client doesn't accept NACK
+    nack_suppressed: HashSet<Ssrc>,
+    max_media_sections: usize,
+    ice_pairs: IcePairs,
+    ice_role: IceRoleResolver,
+    remote_candidates: RemoteCandidates,
+    /// Server candidates in sdp attribute form, they are sent again to each new event stream
+    local_candidates: Vec<String>,
+    /// Held events request of the event stream and its deadline, it is answered as soon as events are buffered
+    events_waiter: Option<(u64, Instant)>,
+    offer_role: OfferRole,
+    pending_offer: Option<SdpPendingOffer>,
     internal: Box<dyn TransportWebrtcInternal>,
     ports: IndexMap2d<SocketAddr, usize>,
+    /// Virtual slots of ICE-TCP listen addresses, packets of them are given to str0m as tcp
+    tcp_slots: Vec<usize>,
     local_convert: LocalMediaConvert,
     seq_extends: IndexMap<Mid, RtpSeqExtend>,
+    pacer: EgressPacer,
     queue: DynamicDeque<TransportOutput<ExtOut>, 4>,
     _tmp: PhantomData<ES>,
 }
 
+/// Result of running an offer through negotiation without creating any session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OfferValidation {
+    pub answer: String,
+    /// Codecs which are selected in answer, in order of appearance
+    pub codecs: Vec<String>,
+    /// Mids of m-lines which are rejected (port 0 in answer)
+    pub rejected_mids: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl From<OfferValidation> for ValidateOfferResponse {
+    fn from(value: OfferValidation) -> Self {
+        Self {
+            sdp: value.answer,
+            codecs: value.codecs,
+            rejected_mids: value.rejected_mids,
+            warnings: value.warnings,
+        }
+    }
+}
+
+/// Create an offer from server side which adds a send-only m-line for each kind, the answer must be applied with the pending offer
+fn renegotiate_offer(rtc: &mut Rtc, kinds: &[MediaKind]) -> Option<(SdpOffer, SdpPendingOffer)> {
+    let mut api = rtc.sdp_api();
+    for kind in kinds {
+        let kind = if kind.is_video() {
+            str0m::media::MediaKind::Video
+        } else {
+            str0m::media::MediaKind::Audio
+        };
+        api.add_media(kind, Direction::SendOnly, None, None, None);
+    }
+    api.apply()
+}
+
+/// `cfg.h264_profiles` is list of allowed profile-level-id in preference order, empty for all forwardable profiles.
+/// `video_codec` is the only video codec which is enabled, None for all.
+/// `cfg.disabled_extensions` are removed from answer, bwe is only enabled when `twcc` is negotiated.
+/// Opus is enabled with the clock rate and channels of `opus`, str0m only matches offered opus of the same format.
+fn rtc_builder(cfg: &TransportWebrtcConfig, ice_creds: IceCreds, video_codec: Option<VideoCodec>, twcc: bool, opus: OpusParams) -> RtcConfig {
+    let h264_profiles = &cfg.h264_profiles;
+    let allow = |codec: VideoCodec| video_codec.map_or(true, |c| c == codec);
+    let mut config = Rtc::builder()
+        .set_rtp_mode(true)
+        .set_ice_lite(cfg.ice_lite)
+        .set_dtls_cert(cfg.dtls_cert.clone())
+        .set_local_ice_credentials(ice_creds)
+        .set_stats_interval(Some(Duration::from_secs(1)))
+        .clear_extension_map();
+    for (id, ext) in extension_map(&cfg.disabled_extensions) {
+        config = config.set_extension(id, ext);
+    }
+    let mut config = config
+        .set_extension(
+            9,
+            str0m::rtp::Extension::with_serializer("http://www.webrtc.org/experiments/rtp-hdrext/video-layers-allocation00", str0m::rtp::vla::Serializer),
+        )
+        .enable_vp8(allow(VideoCodec::Vp8))
+        .enable_vp9(allow(VideoCodec::Vp9))
+        .enable_h264(h264_profiles.is_empty() && allow(VideoCodec::H264))
+        .enable_opus(opus.clock_rate == DEFAULT_OPUS_CLOCK_RATE && opus.channels == DEFAULT_OPUS_CHANNELS)
+        .enable_bwe(twcc.then(|| Bitrate::kbps(3000)));
+    if opus.clock_rate != DEFAULT_OPUS_CLOCK_RATE || opus.channels != DEFAULT_OPUS_CHANNELS {
+        match Frequency::new(opus.clock_rate) {
+            Some(clock_rate) => config.codec_config().add_config(
+                OPUS_PT.into(),
+                None,
+                Codec::Opus,
+                clock_rate,
+                Some(opus.channels),
+                FormatParams::parse_line("minptime=10;useinbandfec=1"),
+            ),
+            None => log::warn!("[TransportWebrtc] invalid opus clock rate {} => opus disabled", opus.clock_rate),
+        }
+    }
+    if allow(VideoCodec::H264) {
+        for (pt, rtx, packetization_mode, profile_level_id) in h264_payloads(h264_profiles) {
+            config.codec_config().add_h264(pt, Some(rtx), packetization_mode, profile_level_id);
+        }
+    }
+    config
+}
+
+/// Order advertised addresses, ips in `preferred` come first in configured order, others keep original order
+fn order_candidates(addrs: impl Iterator<Item = SocketAddr>, preferred: &[IpAddr]) -> Vec<SocketAddr> {
+    let mut addrs = addrs.collect::<Vec<_>>();
+    addrs.sort_by_key(|addr| preferred.iter().position(|ip| *ip == addr.ip()).unwrap_or(preferred.len()));
+    addrs
+}
+
+/// Host candidate priority (RFC 8445 5.1.2.1), the local preference is decreased by index so the first address is preferred
+fn host_candidate(addr: SocketAddr, index: usize) -> Candidate {
+    const HOST_TYPE_PREFERENCE: u32 = 126;
+    let local_pref = 65535_u32.saturating_sub(index as u32);
+    let prio = (HOST_TYPE_PREFERENCE << 24) + (local_pref << 8) + (256 - 1);
+    Candidate::from_sdp_string(&format!("candidate:{} 1 udp {prio} {} {} typ host", index + 1, addr.ip(), addr.port())).unwrap_or_else(|e| {
+        log::warn!("[TransportWebrtc] build host candidate {addr} with prio {prio} error {e}, fallback to default prio");
+        Candidate::host(addr, Protocol::Udp).expect("Should add local candidate")
+    })
+}
+
+/// Passive tcp host candidate (RFC 6544 4.2), the direction preference makes it lower priority than udp host candidates,
+/// so tcp is only selected when udp doesn't work
+fn tcp_host_candidate(addr: SocketAddr, index: usize) -> Candidate {
+    const HOST_TYPE_PREFERENCE: u32 = 126;
+    const PASSIVE_DIRECTION_PREFERENCE: u32 = 4;
+    let local_pref = (PASSIVE_DIRECTION_PREFERENCE << 13) + 8191_u32.saturating_sub(index as u32);
+    let prio = (HOST_TYPE_PREFERENCE << 24) + (local_pref << 8) + (256 - 1);
+    Candidate::from_sdp_string(&format!("candidate:{} 1 tcp {prio} {} {} typ host tcptype passive", index + 1, addr.ip(), addr.port())).unwrap_or_else(|e| {
+        log::warn!("[TransportWebrtc] build tcp candidate {addr} with prio {prio} error {e}, fallback to default prio");
+        Candidate::host(addr, Protocol::Tcp).expect("Should add local candidate")
+    })
+}
+
+/// Transport-cc is used for bwe only when it is enabled in config and client also offers it
+fn twcc_negotiated(offer: &str, disabled_extensions: &[RtpExtension]) -> bool {
+    !disabled_extensions.contains(&RtpExtension::TransportCc) && offer_has_extension(offer, RtpExtension::TransportCc)
+}
+
+/// Offer is rejected if its fingerprints are not allowed by the dtls cert policy
+fn check_offer_fingerprint(offer: &str, dtls_policy: &DtlsPolicy) -> RpcResult<()> {
+    dtls_policy.check_offer(offer).map_err(|hash| {
+        log::warn!("[TransportWebrtc] reject offer with fingerprint hash {hash}, cert policy {}", dtls_policy.cert);
+        RpcError::new(WebrtcError::DtlsPolicyRejected, &format!("fingerprint hash {hash} is not allowed"))
+    })
+}
+
+/// Offer is rejected if it has audio or video but none of its codecs can be answered.
+/// The answer is checked too because a codec name can match but its parameters not, e.g. H264 profiles
+fn check_offer_codecs(offer: &str, answer: Option<&str>, h264_profiles: &[u32], video_codec: Option<VideoCodec>) -> RpcResult<()> {
+    let offered = sdp_media_codecs(offer);
+    if offered.is_empty() {
+        return Ok(());
+    }
+    let supported = supported_media_codecs(h264_profiles.is_empty() || !h264_payloads(h264_profiles).is_empty(), video_codec);
+    let compatible = match answer {
+        Some(answer) => !sdp_media_codecs(answer).is_empty(),
+        None => offered.iter().any(|codec| supported.iter().any(|s| s.eq_ignore_ascii_case(codec))),
+    };
+    if compatible {
+        return Ok(());
+    }
+    let message = format!("no compatible codec, offered [{}], supported [{}]", offered.join(", "), supported.join(", "));
+    log::warn!("[TransportWebrtc] reject offer: {message}");
+    Err(RpcError::new(WebrtcError::NoCompatibleCodec, &message))
+}
+
+/// Opus rtpmap of answer is rewritten with the configured clock rate and channels, which the offer must have
+fn check_answer_opus(offer: &str, answer: &str, opus: OpusParams) -> RpcResult<String> {
+    answer_opus_rtpmap(offer, answer, opus).map_err(|e| {
+        log::warn!("[TransportWebrtc] reject offer: {e}");
+        RpcError::new(WebrtcError::NoCompatibleCodec, &e)
+    })
+}
+
+/// Count m-lines before the offer is parsed by str0m, which builds media and codec state for each of them
+fn check_offer_media_sections(offer: &str, max_media_sections: usize) -> RpcResult<()> {
+    let count = offer.lines().filter(|line| line.starts_with("m=")).count();
+    if count > max_media_sections {
+        log::warn!("[TransportWebrtc] reject offer with {count} m-lines, max {max_media_sections}");
+        return Err(RpcError::new(WebrtcError::TooManyMediaSections, &format!("offer has {count} m-lines, max {max_media_sections}")));
+    }
+    Ok(())
+}
+
+/// Rewrite offer setup with the DTLS role policy
+fn check_offer_setup(offer: &str, dtls_policy: &DtlsPolicy) -> RpcResult<String> {
+    dtls_policy.offer_setup(offer).map_err(|e| {
+        log::warn!("[TransportWebrtc] reject offer with invalid setup: {e}");
+        RpcError::new(WebrtcError::InvalidSdp, &e)
+    })
+}
+
+/// A wrong answer setup would only show up as a DTLS handshake timeout, so it is reported before the answer is sent
+fn check_answer_role(offer: &str, answer: &str) -> RpcResult<()> {
+    check_answer_setup(offer, answer).map_err(|e| {
+        log::error!("[TransportWebrtc] {e}");
+        RpcError::new(WebrtcError::InternalServerError, &e)
+    })
+}
+
+/// A wrong SSRC declaration breaks RTX of the session, so it is rejected instead of answered
+fn check_answer_ssrc_groups(answer: &str) -> RpcResult<()> {
+    check_answer_ssrc(answer).map_err(|e| {
+        log::error!("[TransportWebrtc] {e}");
+        RpcError::new(WebrtcError::InternalServerError, &e)
+    })
+}
+
+/// Run offer through the same negotiation logic as a real session, but without binding sockets or spawning endpoint.
+pub fn validate_offer(offer: &str, cfg: &TransportWebrtcConfig, video_codec: Option<VideoCodec>) -> RpcResult<OfferValidation> {
+    check_offer_media_sections(offer, cfg.max_media_sections)?;
+    check_offer_fingerprint(offer, &cfg.dtls_policy)?;
+    let (bundle_offer, bundled) = offer_bundle(offer, cfg.bundle_policy).map_err(|e| RpcError::new(WebrtcError::InvalidSdp, &e))?;
+    let bundle_offer = offer_ssrc_simulcast(&check_offer_setup(&bundle_offer, &cfg.dtls_policy)?);
+    let twcc = twcc_negotiated(offer, &cfg.disabled_extensions);
+    let fb = rtcp_fb_negotiated(offer, cfg.rtcp_fb);
+    let offer = SdpOffer::from_sdp_string(&bundle_offer).map_err(|e| RpcError::new(WebrtcError::InvalidSdp, &e.to_string()))?;
+    let mut rtc = rtc_builder(cfg, IceCreds::new(), video_codec, twcc, OpusParams::default()).build();
+    let answer = rtc
+        .sdp_api()
+        .accept_offer(offer)
+        .map_err(|e| RpcError::new(WebrtcError::InternalServerError, &e.to_string()))?
+        .to_sdp_string();
+    check_answer_role(&bundle_offer, &answer)?;
+    check_answer_ssrc_groups(&answer)?;
+    let answer = answer_rtcp_fb(&answer, fb);
+    let answer = answer_sdp_session(&answer, &cfg.sdp_session);
+    let answer = answer_bundle(&answer, bundled.as_deref());
+
+    let mut codecs = vec![];
+    let mut rejected_mids = vec![];
+    let mut warnings = vec![];
+    // (kind, rejected, mid)
+    let mut medias: Vec<(String, bool, Option<String>)> = vec![];
+    for line in answer.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            let mut parts = media.split(' ');
+            let kind = parts.next().unwrap_or_default().to_string();
+            let rejected = parts.next() == Some("0");
+            medias.push((kind, rejected, None));
+        } else if let Some(mid) = line.strip_prefix("a=mid:") {
+            if let Some(last) = medias.last_mut() {
+                last.2 = Some(mid.to_string());
+            }
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            let codec = rtpmap.split(' ').nth(1).and_then(|c| c.split('/').next()).unwrap_or_default().to_string();
+            if !codec.is_empty() && !codecs.contains(&codec) {
+                codecs.push(codec);
+            }
+        }
+    }
+    for (kind, rejected, mid) in &medias {
+        if *rejected {
+            let mid = mid.clone().unwrap_or_default();
+            warnings.push(format!("m-line {mid} ({kind}) rejected"));
+            rejected_mids.push(mid);
+        }
+    }
+    if medias.is_empty() {
+        warnings.push("offer without any m-line".to_string());
+    } else if codecs.is_empty() && medias.iter().any(|(kind, _, _)| kind == "audio" || kind == "video") {
+        warnings.push("no compatible media codec".to_string());
+    }
+
+    Ok(OfferValidation {
+        answer,
+        codecs,
+        rejected_mids,
+        warnings,
+    })
+}
+
 impl<ES: 'static + MediaEdgeSecure> TransportWebrtc<ES> {
-    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        cfg: &TransportWebrtcConfig,
         app: AppContext,
         remote: IpAddr,
         variant: VariantParams<ES>,
+        migrate: Option<WebrtcMigrateTicket>,
         offer: &str,
-        dtls_cert: DtlsCert,
-        local_addrs: &[(SocketAddr, usize)],
-        addrs_alt: &[SocketAddr],
-        rtc_ice_lite: bool,
+        video_codec: Option<VideoCodec>,
     ) -> RpcResult<(Self, String, String)> {
-        let offer = SdpOffer::from_sdp_string(offer).map_err(|_e| RpcError::new2(WebrtcError::InvalidSdp))?;
-        let rtc_config = Rtc::builder()
-            .set_rtp_mode(true)
-            .set_ice_lite(rtc_ice_lite)
-            .set_dtls_cert(dtls_cert)
-            .set_local_ice_credentials(IceCreds::new())
-            .set_stats_interval(Some(Duration::from_secs(1)))
-            .set_extension(
-                9,
-                str0m::rtp::Extension::with_serializer("http://www.webrtc.org/experiments/rtp-hdrext/video-layers-allocation00", str0m::rtp::vla::Serializer),
-            )
-            .enable_vp8(true)
-            .enable_vp9(true)
-            .enable_h264(true)
-            .enable_opus(true)
-            .enable_bwe(Some(Bitrate::kbps(3000)));
+        let TransportWebrtcConfig {
+            dtls_policy,
+            rtp_ingest,
+            addrs: ref local_addrs,
+            ref addrs_alt,
+            ref tcp_addrs,
+            ice_lite: rtc_ice_lite,
+            ice_creds,
+            consent,
+            ref candidate_order,
+            ref h264_profiles,
+            ref disabled_extensions,
+            rtcp_fb,
+            ref sdp_session,
+            bundle_policy,
+            max_candidates,
+            max_media_sections,
+            max_remote_candidates,
+            ..
+        } = *cfg;
+        let opus = cfg.opus.params(&app.app);
+        check_offer_media_sections(offer, max_media_sections)?;
+        check_offer_fingerprint(offer, &dtls_policy)?;
+        check_offer_codecs(offer, None, h264_profiles, video_codec)?;
+        let (bundle_offer, bundled) = offer_bundle(offer, bundle_policy).map_err(|e| RpcError::new(WebrtcError::InvalidSdp, &e))?;
+        let bundle_offer = offer_ssrc_simulcast(&check_offer_setup(&bundle_offer, &dtls_policy)?);
+        let video_encodings = offer_video_encodings(offer);
+        let twcc = twcc_negotiated(offer, disabled_extensions);
+        let fb = rtcp_fb_negotiated(offer, rtcp_fb);
+        let ice_hint = match &variant {
+            VariantParams::Webrtc(_, req, ..) => req.ice_hint.as_deref().unwrap_or_default().parse().unwrap_or_else(|e| {
+                log::warn!("[TransportWebrtc] ignore ice hint: {e}");
+                IceHint::None
+            }),
+            _ => IceHint::None,
+        };
+        let offer_role = match &variant {
+            VariantParams::Whip(..) => OfferRole::Whip,
+            VariantParams::Whep(..) => OfferRole::Whep,
+            VariantParams::Webrtc(..) => OfferRole::Sdk,
+        };
+        let mut ice_pairs = IcePairs::new(ice_hint);
+        let sdp_offer = SdpOffer::from_sdp_string(&ice_pairs.filter_offer(&offer_directions(&bundle_offer, offer_role))).map_err(|_e| RpcError::new2(WebrtcError::InvalidSdp))?;
+        let rtc_config = rtc_builder(cfg, ice_creds.generate(), video_codec, twcc, opus).set_max_stun_rto(consent.keepalive_interval);
         let ice_ufrag = rtc_config.local_ice_credentials().as_ref().expect("should have ice credentials").ufrag.clone();
 
         let mut rtc = rtc_config.build();
         let mut internal: Box<dyn TransportWebrtcInternal> = match variant {
-            VariantParams::Whip(room, peer, extra_data, _record) => Box::new(whip::TransportWebrtcWhip::new(room, peer, extra_data, remote)),
+            VariantParams::Whip(room, peer, extra_data, _record) => Box::new(whip::TransportWebrtcWhip::new(room, peer, extra_data, remote, video_encodings.clone())),
             VariantParams::Whep(room, peer, extra_data) => Box::new(whep::TransportWebrtcWhep::new(room, peer, extra_data, remote)),
             VariantParams::Webrtc(_user_agent, req, extra_data, _record, secure) => {
                 // after first release we switched to channel_id 0 for resolving problem with firefox
@@ -171,23 +594,62 @@ impl<ES: 'static + MediaEdgeSecure> TransportWebrtc<ES> {
                 // we need to start sctp as client side for handling restart-ice in new server
                 // if not, datachannel will not connect successful after reconnect to new server
                 rtc.direct_api().start_sctp(true);
-                Box::new(webrtc::TransportWebrtcSdk::new(app, req, extra_data, secure, remote))
+                Box::new(webrtc::TransportWebrtcSdk::new(app, req, extra_data, secure, remote, migrate))
             }
         };
 
-        rtc.direct_api().enable_twcc_feedback();
+        if twcc {
+            rtc.direct_api().enable_twcc_feedback();
+        } else {
+            log::info!("[TransportWebrtc] transport-cc is not negotiated => bwe disabled");
+        }
         let mut ports = IndexMap2d::default();
-        for (local_addr, slot) in local_addrs {
+        for (local_addr, slot) in local_addrs.iter().chain(tcp_addrs.iter()) {
             ports.insert(*local_addr, *slot);
-            rtc.add_local_candidate(Candidate::host(*local_addr, Protocol::Udp).expect("Should add local candidate"));
         }
-        for addr in addrs_alt {
-            rtc.add_local_candidate(Candidate::host(*addr, Protocol::Udp).expect("Should add local candidate"));
+        let mut candidate_order = candidate_order.to_vec();
+        if let IceHint::PreferInterface(ip) = ice_hint {
+            if local_addrs.iter().map(|(addr, _)| addr).chain(addrs_alt.iter()).any(|addr| addr.ip() == ip) {
+                log::info!("[TransportWebrtc] ice hint prefer interface {ip}");
+                candidate_order.insert(0, ip);
+            } else {
+                log::warn!("[TransportWebrtc] ice hint interface {ip} is not a local address => ignored");
+            }
+        }
+        let candidates = order_candidates(local_addrs.iter().map(|(addr, _)| *addr).chain(addrs_alt.iter().copied()), &candidate_order);
+        // candidates are sorted by priority, so when capped we only keep the highest priority ones
+        let mut local_candidates = vec![];
+        for (index, addr) in candidates.into_iter().take(max_candidates.unwrap_or(usize::MAX)).enumerate() {
+            let candidate = host_candidate(addr, index);
+            local_candidates.push(candidate.to_sdp_string());
+            rtc.add_local_candidate(candidate);
         }
-        let answer = rtc.sdp_api().accept_offer(offer).map_err(|_e| RpcError::new2(WebrtcError::InternalServerError))?;
+        // tcp candidates are lower priority, they only take the remaining room of the cap
+        let tcp_candidates = order_candidates(tcp_addrs.iter().map(|(addr, _)| *addr), &candidate_order);
+        let remain = max_candidates.unwrap_or(usize::MAX).saturating_sub(local_candidates.len());
+        for (index, addr) in tcp_candidates.into_iter().take(remain).enumerate() {
+            let candidate = tcp_host_candidate(addr, local_candidates.len() + index);
+            local_candidates.push(candidate.to_sdp_string());
+            rtc.add_local_candidate(candidate);
+        }
+        let answer = rtc.sdp_api().accept_offer(sdp_offer).map_err(|_e| RpcError::new2(WebrtcError::InternalServerError))?.to_sdp_string();
+        check_offer_codecs(offer, Some(&answer), h264_profiles, video_codec)?;
+        check_answer_role(&bundle_offer, &answer)?;
+        check_answer_ssrc_groups(&answer)?;
+        if !fb.nack || !fb.key_frame_request {
+            log::info!("[TransportWebrtc] rtcp feedback {:?} with policy {rtcp_fb} => disable missing feedbacks toward client", fb);
+        }
+        let answer = answer_rtcp_fb(&answer, fb);
+        let answer = answer_sdp_session(&answer, sdp_session);
+        let answer = answer_bundle(&answer, bundled.as_deref());
+        let answer = answer_opus(&answer, opus);
+        let answer = check_answer_opus(offer, &answer, opus)?;
         let mut local_convert = LocalMediaConvert::default();
         internal.on_codec_config(rtc.codec_config());
+        internal.on_simulcast_rids(offer_simulcast_rids(offer));
         local_convert.set_config(rtc.codec_config());
+        let mut queue = DynamicDeque::default();
+        queue.push_back(TransportOutput::Event(TransportEvent::Negotiated(answer_negotiated(&answer, &video_encodings))));
 
         Ok((
             Self {
@@ -196,20 +658,156 @@ impl<ES: 'static + MediaEdgeSecure> TransportWebrtc<ES> {
                 internal,
                 rtc,
                 rtc_ice_lite,
+                offer: offer.to_string(),
+                answer: answer.clone(),
+                ice_state: IceConnectionState::New,
+                consent,
+                ice_established: false,
+                last_recv: None,
+                consent_failed: false,
+                dtls_policy,
+                hello_in: HelloReassembly::default(),
+                hello_out: HelloReassembly::default(),
+                dtls_rejected: false,
+                rtp_ingest: RtpIngestGuard::new(rtp_ingest),
+                rtp_abusive: false,
+                bundle_policy,
+                opus,
+                rtcp_fb: fb,
+                nack_suppressed: Default::default(),
+                max_media_sections,
+                ice_pairs,
+                ice_role: Default::default(),
+                remote_candidates: RemoteCandidates::new(max_remote_candidates),
+                local_candidates,
+                events_waiter: None,
+                offer_role,
+                pending_offer: None,
                 ports,
+                tcp_slots: tcp_addrs.iter().map(|(_, slot)| *slot).collect(),
                 local_convert,
                 seq_extends: Default::default(),
-                queue: Default::default(),
+                pacer: Default::default(),
+                queue,
                 _tmp: Default::default(),
             },
             ice_ufrag,
-            answer.to_sdp_string(),
+            answer,
         ))
     }
 
+    fn transport_state(&self) -> &'static str {
+        if self.dtls_rejected {
+            "dtls_rejected"
+        } else if self.rtp_abusive {
+            "rtp_abusive"
+        } else if self.consent_failed {
+            "consent_failed"
+        } else if !self.rtc.is_alive() {
+            "closed"
+        } else if self.rtc.is_connected() {
+            "connected"
+        } else {
+            "connecting"
+        }
+    }
+
+    fn dump(&self) -> SessionDump {
+        SessionDump {
+            offer: redact_sdp(&self.offer),
+            answer: redact_sdp(&self.answer),
+            ice_state: format!("{:?}", self.ice_state).to_lowercase(),
+            local_candidates: self.local_candidates.clone(),
+            remote_candidates: self.ice_pairs.remote_candidates().to_vec(),
+            selected_pair: self.ice_pairs.selected().map(|pair| pair.to_string()),
+            codecs: sdp_media_codecs(&self.answer),
+            transport_state: self.transport_state().to_string(),
+            bad_rtp_packets: self.rtp_ingest.bad_packets(),
+        }
+    }
+
+    fn check_consent(&mut self, now: Instant) {
+        if self.consent_failed {
+            return;
+        }
+        let last_recv = *self.last_recv.get_or_insert(now);
+        let timeout = if self.ice_established {
+            self.consent.established_timeout
+        } else {
+            self.consent.timeout
+        };
+        if now - last_recv >= timeout {
+            log::warn!(
+                "[TransportWebrtc] consent failed, no packet from remote after {:?}, established {}",
+                now - last_recv,
+                self.ice_established
+            );
+            self.consent_failed = true;
+            self.internal.on_consent_failed(now);
+            self.rtc.disconnect();
+        }
+    }
+
+    /// Refresh payload type maps of both directions from negotiated codecs, str0m remaps pts on every accepted offer or answer
+    fn refresh_codec_config(&mut self) {
+        self.internal.on_codec_config(self.rtc.codec_config());
+        self.local_convert.set_config(self.rtc.codec_config());
+    }
+
+    /// Answer the held events request when events are buffered, or without events at the deadline so the stream knows the session
+    /// is still alive. It is rejected when the session is closed, then the stream ends
+    fn answer_events_waiter(&mut self, now: Instant) {
+        let (req_id, deadline) = return_if_none!(self.events_waiter);
+        let res = if self.internal.is_empty() {
+            Err(RpcError::new2(WebrtcError::RpcEndpointNotFound))
+        } else {
+            let events = self.internal.pop_events().unwrap_or_default();
+            if events.is_empty() && now < deadline {
+                return;
+            }
+            Ok(events)
+        };
+        self.events_waiter = None;
+        self.queue.push_back(TransportOutput::Ext(ExtOut::Events(req_id, res)));
+    }
+
+    /// Release media which is allowed by pacer
+    fn send_paced_media(&mut self, now: Instant) {
+        while let Some((mid, pkt)) = self.pacer.pop(now) {
+            self.write_media(now, mid, pkt);
+        }
+    }
+
+    fn write_media(&mut self, now: Instant, mid: Mid, mut pkt: MediaPacket) {
+        let seq_extend = self.seq_extends.entry(mid).or_default();
+        let pt = return_if_none!(self.local_convert.convert_codec(pkt.meta.codec()));
+        let seq2 = return_if_none!(seq_extend.generate(pkt.seq));
+        let ts = self.local_convert.convert_ts(mid, &pkt);
+        self.local_convert.rewrite_pkt(&mut pkt);
+        log::trace!(
+            "[TransportWebrtc] sending media meta {:?} => pt {pt} seq {} ts {} marker {} payload: {}",
+            pkt.meta,
+            pkt.seq,
+            pkt.ts,
+            pkt.marker,
+            pkt.data.len(),
+        );
+        let mut api = self.rtc.direct_api();
+        let tx = return_if_none!(api.stream_tx_by_mid(mid, None));
+
+        let ext = to_webrtc_extensions(&pkt);
+        if let Err(e) = tx.write_rtp(pt, seq2.into(), ts, now, pkt.marker, ext, pkt.nackable, pkt.data) {
+            log::error!("[TransportWebrtc] write rtp error {e}");
+        }
+    }
+
     fn process_internal_output(&mut self, now: Instant, out: InternalOutput) {
         match out {
             InternalOutput::Str0mKeyframe(mid, kind) => {
+                if !self.rtcp_fb.key_frame_request {
+                    log::debug!("[TransportWebrtc] client doesn't accept key-frame request => rely on key-frame interval of track {mid}");
+                    return;
+                }
                 let mut api = self.rtc.direct_api();
                 let rx = return_if_none!(api.stream_rx_by_mid(mid, None));
                 rx.request_keyframe(kind);
@@ -225,27 +823,11 @@ impl<ES: 'static + MediaEdgeSecure> TransportWebrtc<ES> {
                 let mut bwe = self.rtc.bwe();
                 bwe.set_current_bitrate(current.into());
                 bwe.set_desired_bitrate(desired.into());
+                self.pacer.set_target(now, current);
             }
-            InternalOutput::Str0mSendMedia(mid, mut pkt) => {
-                let seq_extend = self.seq_extends.entry(mid).or_default();
-                let pt = return_if_none!(self.local_convert.convert_codec(pkt.meta.codec()));
-                let seq2 = return_if_none!(seq_extend.generate(pkt.seq));
-                self.local_convert.rewrite_pkt(&mut pkt);
-                log::trace!(
-                    "[TransportWebrtc] sending media meta {:?} => pt {pt} seq {} ts {} marker {} payload: {}",
-                    pkt.meta,
-                    pkt.seq,
-                    pkt.ts,
-                    pkt.marker,
-                    pkt.data.len(),
-                );
-                let mut api = self.rtc.direct_api();
-                let tx = return_if_none!(api.stream_tx_by_mid(mid, None));
-
-                let ext = to_webrtc_extensions(&pkt);
-                if let Err(e) = tx.write_rtp(pt, seq2.into(), pkt.ts, now, pkt.marker, ext, pkt.nackable, pkt.data) {
-                    log::error!("[TransportWebrtc] write rtp error {e}");
-                }
+            InternalOutput::Str0mSendMedia(mid, pkt) => {
+                self.pacer.push(now, mid, pkt);
+                self.send_paced_media(now);
             }
             InternalOutput::Str0mSendData(channel, data) => {
                 let mut channel = return_if_none!(self.rtc.channel(channel));
@@ -262,9 +844,26 @@ impl<ES: 'static + MediaEdgeSecure> TransportWebrtc<ES> {
             }
             InternalOutput::RpcReq(req_id, req) => match req {
                 InternalRpcReq::SetRemoteSdp(offer) => {
-                    if let Ok(offer) = SdpOffer::from_sdp_string(&offer) {
+                    if let Err(e) = check_offer_media_sections(&offer, self.max_media_sections) {
+                        return self.internal.on_rpc_res(req_id, Err(e));
+                    }
+                    let rids = offer_simulcast_rids(&offer);
+                    let offer_sdp = offer.clone();
+                    let offer = match self.dtls_policy.offer_setup(&offer) {
+                        Ok(offer) => offer,
+                        Err(e) => return self.internal.on_rpc_res(req_id, Err(RpcError::new(WebrtcError::InvalidSdp, &e))),
+                    };
+                    if let Ok(offer) = SdpOffer::from_sdp_string(&offer_directions(&offer_ssrc_simulcast(&offer), self.offer_role)) {
                         if let Ok(answer) = self.rtc.sdp_api().accept_offer(offer) {
-                            self.internal.on_rpc_res(req_id, Ok(InternalRpcRes::SetRemoteSdp(answer.to_sdp_string())));
+                            self.refresh_codec_config();
+                            self.internal.on_simulcast_rids(rids);
+                            let answer = match check_answer_opus(&offer_sdp, &answer_opus(&answer.to_sdp_string(), self.opus), self.opus) {
+                                Ok(answer) => answer,
+                                Err(e) => return self.internal.on_rpc_res(req_id, Err(e)),
+                            };
+                            self.answer = answer;
+                            self.offer = offer_sdp;
+                            self.internal.on_rpc_res(req_id, Ok(InternalRpcRes::SetRemoteSdp(self.answer.clone())));
                         } else {
                             self.internal.on_rpc_res(req_id, Err(RpcError::new2(WebrtcError::InternalServerError)));
                         }
@@ -272,6 +871,36 @@ impl<ES: 'static + MediaEdgeSecure> TransportWebrtc<ES> {
                         self.internal.on_rpc_res(req_id, Err(RpcError::new2(WebrtcError::InvalidSdp)));
                     }
                 }
+                InternalRpcReq::CreateOffer(kinds) => match renegotiate_offer(&mut self.rtc, &kinds) {
+                    Some((offer, pending)) => {
+                        log::info!("[TransportWebrtc] created renegotiate offer for {kinds:?}");
+                        self.pending_offer = Some(pending);
+                        self.internal.on_rpc_res(req_id, Ok(InternalRpcRes::CreateOffer(Some(offer.to_sdp_string()))));
+                    }
+                    None => self.internal.on_rpc_res(req_id, Ok(InternalRpcRes::CreateOffer(None))),
+                },
+                InternalRpcReq::CancelOffer => {
+                    if self.pending_offer.take().is_some() {
+                        log::info!("[TransportWebrtc] cancelled pending renegotiate offer");
+                    }
+                }
+                InternalRpcReq::SetRemoteAnswer(answer) => {
+                    let answer = match SdpAnswer::from_sdp_string(&answer) {
+                        Ok(answer) => answer,
+                        Err(_) => return self.internal.on_rpc_res(req_id, Err(RpcError::new2(WebrtcError::InvalidSdp))),
+                    };
+                    let pending = match self.pending_offer.take() {
+                        Some(pending) => pending,
+                        None => return self.internal.on_rpc_res(req_id, Err(RpcError::new2(WebrtcError::RpcInvalidRequest))),
+                    };
+                    if let Err(e) = self.rtc.sdp_api().accept_answer(pending, answer) {
+                        log::warn!("[TransportWebrtc] accept renegotiate answer error {e}");
+                        self.internal.on_rpc_res(req_id, Err(RpcError::new(WebrtcError::InvalidSdp, &e.to_string())));
+                    } else {
+                        self.refresh_codec_config();
+                        self.internal.on_rpc_res(req_id, Ok(InternalRpcRes::SetRemoteAnswer));
+                    }
+                }
             },
         }
     }
@@ -288,7 +917,16 @@ impl<ES: 'static + MediaEdgeSecure> Transport<ExtIn, ExtOut> for TransportWebrtc
             }
         }
 
+        for ice in self.ice_pairs.on_tick(now) {
+            if let Ok(candidate) = Candidate::from_sdp_string(&ice) {
+                self.rtc.add_remote_candidate(candidate);
+            }
+        }
+
+        self.check_consent(now);
+        self.send_paced_media(now);
         self.internal.on_tick(now);
+        self.answer_events_waiter(now);
     }
 
     /// Note: Str0m only stop single incoming packet and we need to pop_output immediate
@@ -300,9 +938,49 @@ impl<ES: 'static + MediaEdgeSecure> Transport<ExtIn, ExtOut> for TransportWebrtc
         match input {
             TransportInput::Net(net) => match net {
                 BackendIncoming::UdpPacket { slot, from, data } => {
+                    if let Err(version) = self.dtls_policy.check_packet(&data) {
+                        if !self.dtls_rejected {
+                            log::warn!(
+                                "[TransportWebrtc] reject dtls handshake from {from} with version {:?}, min version is {} => close",
+                                version,
+                                self.dtls_policy.min_version
+                            );
+                            self.dtls_rejected = true;
+                            self.internal.on_shutdown(now);
+                            self.rtc.disconnect();
+                        }
+                        return;
+                    }
+                    match self.dtls_policy.check_suites(&mut self.hello_in, &data) {
+                        Ok(Some(suites)) => log::info!("[TransportWebrtc] dtls suites from {from} {suites}"),
+                        Ok(None) => {}
+                        Err(reason) => {
+                            if !self.dtls_rejected {
+                                log::warn!("[TransportWebrtc] reject dtls handshake from {from}, {reason} => close");
+                                self.dtls_rejected = true;
+                                self.internal.on_shutdown(now);
+                                self.rtc.disconnect();
+                            }
+                            return;
+                        }
+                    }
+                    // packet is not authenticated yet, so a bad one is only dropped, it may be spoofed
+                    if is_rtp(&data) && self.rtp_ingest.on_unverified_rtp(now, &data) != RtpIngest::Accept {
+                        return;
+                    }
+                    self.last_recv = Some(now);
                     let destination = *return_if_none!(self.ports.get2(&slot));
                     log::trace!("[TransportWebrtc] recv udp from {} to {}, len {}", from, destination, data.len());
-                    let recv = return_if_err!(Receive::new(Protocol::Udp, from, destination, data.deref()));
+                    if let Some(role) = self.ice_role.on_remote_packet(&data) {
+                        log::warn!("[TransportWebrtc] ice role conflict {} with {from} => switch to {role:?}", self.ice_role.conflicts());
+                        self.rtc.direct_api().set_ice_controlling(role == IceRole::Controlling);
+                    }
+                    let proto = if self.tcp_slots.contains(&slot) {
+                        Protocol::Tcp
+                    } else {
+                        Protocol::Udp
+                    };
+                    let recv = return_if_err!(Receive::new(proto, from, destination, data.deref()));
                     if let Err(e) = self.rtc.handle_input(str0m::Input::Receive(now, recv)) {
                         log::error!("[TransportWebrtc] handle recv error {}", e);
                     }
@@ -316,22 +994,56 @@ impl<ES: 'static + MediaEdgeSecure> Transport<ExtIn, ExtOut> for TransportWebrtc
                 self.internal.on_transport_rpc_res(now, req_id, res);
             }
             TransportInput::Ext(ext) => match ext {
-                ExtIn::RemoteIce(req_id, variant, ices) => {
-                    let mut success_count = 0;
-                    for ice in ices {
-                        if let Ok(candidate) = Candidate::from_sdp_string(&ice) {
-                            success_count += 1;
-                            self.rtc.add_remote_candidate(candidate);
+                ExtIn::RemoteIce(req_id, variant, ices) => match self.remote_candidates.on_candidates(ices) {
+                    Ok(ices) => {
+                        // candidates which are held by ice hint are still counted as accepted
+                        let success_count = ices.len() as u32;
+                        for ice in self.ice_pairs.on_remote_candidates(ices) {
+                            if let Ok(candidate) = Candidate::from_sdp_string(&ice) {
+                                self.rtc.add_remote_candidate(candidate);
+                            }
                         }
+                        self.queue.push_back(TransportOutput::Ext(ExtOut::RemoteIce(req_id, variant, Ok(success_count))));
                     }
-                    self.queue.push_back(TransportOutput::Ext(ExtOut::RemoteIce(req_id, variant, Ok(success_count))));
-                }
+                    Err(e) => {
+                        log::warn!("[TransportWebrtc] reject remote ice: {e}");
+                        let code = match e {
+                            RemoteIceError::Malformed(_) => WebrtcError::InvalidIceCandidate,
+                            RemoteIceError::TooMany(..) => WebrtcError::TooManyIceCandidates,
+                        };
+                        self.queue.push_back(TransportOutput::Ext(ExtOut::RemoteIce(req_id, variant, Err(RpcError::new(code, &e.to_string())))));
+                    }
+                },
                 ExtIn::RestartIce(req_id, _app, variant, _ip, _useragent, req, _extra_data, _record) => {
-                    if let Ok(offer) = SdpOffer::from_sdp_string(&req.sdp) {
-                        if let Ok(answer) = self.rtc.sdp_api().accept_offer(offer) {
-                            self.internal.on_codec_config(self.rtc.codec_config());
+                    if let Err(e) = check_offer_media_sections(&req.sdp, self.max_media_sections) {
+                        self.queue.push_back(TransportOutput::Ext(ExtOut::RestartIce(req_id, variant, Err(e))));
+                        return;
+                    }
+                    let dtls_policy = self.dtls_policy;
+                    let (sdp, bundled) = match offer_bundle(&req.sdp, self.bundle_policy).and_then(|(sdp, bundled)| Ok((dtls_policy.offer_setup(&sdp)?, bundled))) {
+                        Ok(res) => res,
+                        Err(e) => {
                             self.queue
-                                .push_back(TransportOutput::Ext(ExtOut::RestartIce(req_id, variant, Ok((self.rtc_ice_lite, answer.to_sdp_string())))));
+                                .push_back(TransportOutput::Ext(ExtOut::RestartIce(req_id, variant, Err(RpcError::new(WebrtcError::InvalidSdp, &e)))));
+                            return;
+                        }
+                    };
+                    if let Ok(offer) = SdpOffer::from_sdp_string(&self.ice_pairs.filter_offer(&offer_directions(&offer_ssrc_simulcast(&sdp), self.offer_role))) {
+                        if let Ok(answer) = self.rtc.sdp_api().accept_offer(offer) {
+                            self.refresh_codec_config();
+                            self.internal.on_simulcast_rids(offer_simulcast_rids(&req.sdp));
+                            let answer = answer_bundle(&answer.to_sdp_string(), bundled.as_deref());
+                            let answer = match check_answer_opus(&req.sdp, &answer_opus(&answer, self.opus), self.opus) {
+                                Ok(answer) => answer,
+                                Err(e) => {
+                                    self.queue.push_back(TransportOutput::Ext(ExtOut::RestartIce(req_id, variant, Err(e))));
+                                    return;
+                                }
+                            };
+                            let answer = answer_rtcp_fb(&answer, self.rtcp_fb);
+                            self.offer = req.sdp.clone();
+                            self.answer = answer.clone();
+                            self.queue.push_back(TransportOutput::Ext(ExtOut::RestartIce(req_id, variant, Ok((self.rtc_ice_lite, answer)))));
                         } else {
                             self.queue
                                 .push_back(TransportOutput::Ext(ExtOut::RestartIce(req_id, variant, Err(RpcError::new2(WebrtcError::InternalServerError)))));
@@ -345,12 +1057,55 @@ impl<ES: 'static + MediaEdgeSecure> Transport<ExtIn, ExtOut> for TransportWebrtc
                     self.internal.on_shutdown(now);
                     self.queue.push_back(TransportOutput::Ext(ExtOut::Disconnect(req_id, variant, Ok(()))));
                 }
+                ExtIn::Close => {
+                    log::info!("[TransportWebrtc] close request from server");
+                    self.internal.on_shutdown(now);
+                    self.rtc.disconnect();
+                }
+                ExtIn::Migrate(req_id, conn, ticket) => {
+                    log::info!("[TransportWebrtc] migrate request to node {}", conn.node);
+                    if self.internal.on_migrate(now, conn.to_string(), ticket) {
+                        self.queue.push_back(TransportOutput::Ext(ExtOut::Migrate(req_id, Ok(conn))));
+                    } else {
+                        self.queue
+                            .push_back(TransportOutput::Ext(ExtOut::Migrate(req_id, Err(RpcError::new2(WebrtcError::RpcMigrateNotSupported)))));
+                    }
+                }
+                ExtIn::Events(req_id, initial) => match self.internal.pop_events() {
+                    Some(events) if initial || !events.is_empty() => {
+                        let events = if initial {
+                            // new stream can be opened after the previous one is broken, so candidates are sent again
+                            let candidates = self.local_candidates.iter().map(|candidate| WhepEvent::Candidate(candidate.clone()));
+                            candidates.chain([WhepEvent::EndOfCandidates]).chain(events).collect()
+                        } else {
+                            events
+                        };
+                        self.queue.push_back(TransportOutput::Ext(ExtOut::Events(req_id, Ok(events))));
+                    }
+                    Some(_) => {
+                        // nothing buffered, the request is held so the event stream is pushed instead of polling
+                        if let Some((old_req_id, _)) = self.events_waiter.replace((req_id, now + EVENTS_LONG_POLL)) {
+                            self.queue.push_back(TransportOutput::Ext(ExtOut::Events(old_req_id, Ok(vec![]))));
+                        }
+                    }
+                    None => {
+                        self.queue
+                            .push_back(TransportOutput::Ext(ExtOut::Events(req_id, Err(RpcError::new2(WebrtcError::RpcEventsNotSupported)))));
+                    }
+                },
+                ExtIn::Dump(req_id) => {
+                    self.queue.push_back(TransportOutput::Ext(ExtOut::Dump(req_id, Ok(self.dump()))));
+                }
             },
         }
     }
 
     fn on_shutdown(&mut self, now: Instant) {
         log::info!("[TransportWebrtc] shutdown request");
+        // media which is still held by the pacer is written before disconnect, so it is not lost with the session
+        for (mid, pkt) in self.pacer.flush() {
+            self.write_media(now, mid, pkt);
+        }
         self.internal.on_shutdown(now);
         self.rtc.disconnect();
     }
@@ -364,7 +1119,7 @@ impl<ES: 'static + MediaEdgeSecure> TaskSwitcherChild<TransportOutput<ExtOut>> f
     }
 
     fn is_empty(&self) -> bool {
-        self.queue.is_empty() && self.internal.is_empty()
+        self.queue.is_empty() && self.internal.is_empty() && self.pacer.is_empty() && self.events_waiter.is_none()
     }
 
     fn pop_output(&mut self, now: Instant) -> Option<TransportOutput<ExtOut>> {
@@ -382,6 +1137,26 @@ impl<ES: 'static + MediaEdgeSecure> TaskSwitcherChild<TransportOutput<ExtOut>> f
                 }
                 str0m::Output::Transmit(out) => {
                     log::trace!("[TransportWebrtc] send udp from {} to {}, len {}", out.source, out.destination, out.contents.len());
+                    // str0m selects suites itself, a hello which selects a disallowed suite is not sent
+                    match self.dtls_policy.check_suites(&mut self.hello_out, &out.contents) {
+                        Ok(Some(suites)) => log::info!("[TransportWebrtc] dtls suites to {} {suites}", out.destination),
+                        Ok(None) => {}
+                        Err(reason) => {
+                            if !self.dtls_rejected {
+                                log::warn!("[TransportWebrtc] reject dtls handshake to {}, {reason} => close", out.destination);
+                                self.dtls_rejected = true;
+                                self.internal.on_shutdown(now);
+                                self.rtc.disconnect();
+                            }
+                            continue;
+                        }
+                    }
+                    self.ice_role.on_local_packet(&out.contents);
+                    if self.ice_established {
+                        if let Some(pair) = self.ice_pairs.on_transmit(out.source, out.destination) {
+                            log::info!("[TransportWebrtc] selected ice pair {pair}");
+                        }
+                    }
                     let from = self.ports.get1(&out.source)?;
                     return Some(TransportOutput::Net(BackendOutgoing::UdpPacket {
                         slot: *from,
@@ -390,6 +1165,43 @@ impl<ES: 'static + MediaEdgeSecure> TaskSwitcherChild<TransportOutput<ExtOut>> f
                     }));
                 }
                 str0m::Output::Event(e) => {
+                    if let str0m::Event::IceConnectionStateChange(state) = &e {
+                        self.ice_state = *state;
+                        if matches!(state, IceConnectionState::Connected | IceConnectionState::Completed) {
+                            self.ice_established = true;
+                        }
+                    }
+                    if let str0m::Event::MediaEgressStats(stats) = &e {
+                        if let Some(rtt) = stats.rtt {
+                            if let Some(pair) = self.ice_pairs.take_rtt_report() {
+                                log::info!("[TransportWebrtc] selected ice pair {pair}, rtt {rtt:?}");
+                            }
+                            if let Some(rtt_ms) = self.ice_pairs.on_rtt(rtt) {
+                                self.queue.push_back(TransportOutput::Event(TransportEvent::Rtt(rtt_ms)));
+                            }
+                        }
+                    }
+                    if let str0m::Event::RtpPacket(pkt) = &e {
+                        // str0m only emits SRTP packets which are authenticated, so they count toward abusive close
+                        match self.rtp_ingest.on_verified_size(now, pkt.header.header_len + pkt.payload.len()) {
+                            RtpIngest::Accept => {}
+                            RtpIngest::Drop => continue,
+                            RtpIngest::Abusive => {
+                                if !self.rtp_abusive {
+                                    log::warn!("[TransportWebrtc] {} bad rtp packets from peer => close as abusive", self.rtp_ingest.bad_packets());
+                                    self.rtp_abusive = true;
+                                    self.internal.on_shutdown(now);
+                                    self.rtc.disconnect();
+                                }
+                                continue;
+                            }
+                        }
+                        if !self.rtcp_fb.nack && self.nack_suppressed.insert(pkt.header.ssrc) {
+                            if let Some(rx) = self.rtc.direct_api().stream_rx(&pkt.header.ssrc) {
+                                rx.suppress_nack(true);
+                            }
+                        }
+                    }
                     self.internal.on_str0m_event(now, e);
                 }
             }
@@ -398,3 +1210,141 @@ impl<ES: 'static + MediaEdgeSecure> TaskSwitcherChild<TransportOutput<ExtOut>> f
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    };
+
+    use media_server_protocol::media::{MediaCodec, MediaKind};
+    use str0m::{
+        change::SdpOffer,
+        media::Direction,
+        net::{Protocol, Receive},
+        rtp::ExtensionValues,
+        Candidate, Event, Input, Output, Rtc,
+    };
+
+    use super::renegotiate_offer;
+    use crate::media::{LocalMediaConvert, RemoteMediaConvert};
+
+    /// Poll `from` until idle, its packets are delivered to `to` and its events are returned
+    fn poll_to(from: &mut Rtc, to: &mut Rtc, now: Instant) -> Vec<Event> {
+        let mut events = vec![];
+        loop {
+            match from.poll_output().expect("Should poll") {
+                Output::Timeout(_) => return events,
+                Output::Transmit(out) => {
+                    let recv = Receive::new(Protocol::Udp, out.source, out.destination, &out.contents).expect("Should parse packet");
+                    to.handle_input(Input::Receive(now, recv)).expect("Should handle packet");
+                }
+                Output::Event(event) => events.push(event),
+            }
+        }
+    }
+
+    /// Run both peers for `duration`, events of each peer are returned
+    fn run_pair(a: &mut Rtc, b: &mut Rtc, now: &mut Instant, duration: Duration) -> (Vec<Event>, Vec<Event>) {
+        let (mut events_a, mut events_b) = (vec![], vec![]);
+        let end = *now + duration;
+        while *now < end {
+            *now += Duration::from_millis(10);
+            a.handle_input(Input::Timeout(*now)).expect("Should handle timeout");
+            b.handle_input(Input::Timeout(*now)).expect("Should handle timeout");
+            events_a.extend(poll_to(a, b, *now));
+            events_b.extend(poll_to(b, a, *now));
+        }
+        (events_a, events_b)
+    }
+
+    fn new_rtc(addr: SocketAddr, h264_pt: u8) -> Rtc {
+        let mut config = Rtc::builder().set_rtp_mode(true).clear_codecs().enable_opus(true);
+        config.codec_config().add_h264(h264_pt.into(), Some((h264_pt + 1).into()), true, 0x42e01f);
+        let mut rtc = config.build();
+        rtc.add_local_candidate(Candidate::host(addr, Protocol::Udp).expect("Should create candidate"));
+        rtc
+    }
+
+    #[test]
+    fn renegotiated_video_is_forwarded_with_negotiated_pt() {
+        let mut now = Instant::now();
+        let mut client = new_rtc(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 20000), 110);
+        let mut server = new_rtc(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000), 96);
+
+        let mut api = client.sdp_api();
+        api.add_media(str0m::media::MediaKind::Audio, Direction::SendRecv, None, None, None);
+        let (offer, pending) = api.apply().expect("Should create offer");
+        let answer = server.sdp_api().accept_offer(offer).expect("Should accept offer");
+        client.sdp_api().accept_answer(pending, answer).expect("Should accept answer");
+        run_pair(&mut client, &mut server, &mut now, Duration::from_secs(2));
+        assert!(client.is_connected() && server.is_connected());
+
+        // converts are created with the first negotiation, which doesn't have video
+        let mut remote = RemoteMediaConvert::default();
+        let mut local = LocalMediaConvert::default();
+        remote.set_config(server.codec_config());
+        local.set_config(server.codec_config());
+
+        let mut api = client.sdp_api();
+        let mid = api.add_media(str0m::media::MediaKind::Video, Direction::SendRecv, None, None, None);
+        let (offer, pending) = api.apply().expect("Should create offer");
+        let answer = server.sdp_api().accept_offer(offer).expect("Should accept renegotiate offer");
+        client.sdp_api().accept_answer(pending, answer).expect("Should accept renegotiate answer");
+        // same as TransportWebrtc::refresh_codec_config after renegotiation
+        remote.set_config(server.codec_config());
+        local.set_config(server.codec_config());
+        run_pair(&mut client, &mut server, &mut now, Duration::from_millis(500));
+
+        let mut api = client.direct_api();
+        let tx = api.stream_tx_by_mid(mid, None).expect("Should have client video stream");
+        tx.write_rtp(110.into(), 1.into(), 1000, now, true, ExtensionValues::default(), true, vec![0x67, 0x42, 0xe0, 0x1f])
+            .expect("Should write rtp");
+        let (_, server_events) = run_pair(&mut client, &mut server, &mut now, Duration::from_millis(100));
+        let rtp = server_events
+            .into_iter()
+            .find_map(|e| match e {
+                Event::RtpPacket(rtp) => Some(rtp),
+                _ => None,
+            })
+            .expect("Should receive video at server");
+        let pkt = remote.convert(rtp).expect("Should convert pt of renegotiated video");
+        assert!(matches!(pkt.meta.codec(), MediaCodec::H264(_)));
+
+        // forward back to client as subscriber, it must be written with the pt which is negotiated by client
+        let pt = local.convert_codec(pkt.meta.codec()).expect("Should have local pt");
+        assert_eq!(*pt, 110);
+        let mut api = server.direct_api();
+        let tx = api.stream_tx_by_mid(mid, None).expect("Should have server video stream");
+        tx.write_rtp(pt, 1.into(), pkt.ts, now, pkt.marker, ExtensionValues::default(), pkt.nackable, pkt.data)
+            .expect("Should write rtp");
+        let (client_events, _) = run_pair(&mut client, &mut server, &mut now, Duration::from_millis(100));
+        assert!(client_events.iter().any(|e| matches!(e, Event::RtpPacket(rtp) if *rtp.header.payload_type == 110)));
+    }
+
+    #[test]
+    fn renegotiate_offer_add_mline() {
+        let mut client = Rtc::new();
+        let mut server = Rtc::new();
+
+        let mut api = client.sdp_api();
+        api.add_media(str0m::media::MediaKind::Audio, Direction::SendRecv, None, None, None);
+        let (offer, pending) = api.apply().expect("Should create offer");
+        let answer = server.sdp_api().accept_offer(offer).expect("Should accept offer");
+        client.sdp_api().accept_answer(pending, answer).expect("Should accept answer");
+
+        let (offer, pending) = renegotiate_offer(&mut server, &[MediaKind::Video]).expect("Should create renegotiate offer");
+        let offer = offer.to_sdp_string();
+        assert_eq!(offer.matches("m=").count(), 2);
+        assert!(offer.contains("m=video"));
+        assert!(offer.contains("a=sendonly"));
+
+        let answer = client
+            .sdp_api()
+            .accept_offer(SdpOffer::from_sdp_string(&offer).expect("Should parse"))
+            .expect("Should accept renegotiate offer");
+        assert!(answer.to_sdp_string().contains("m=video"));
+        assert!(server.sdp_api().accept_answer(pending, answer).is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+//! Egress pacer. Forwarded media is released with a leaky bucket at a rate derived from the bwe estimate,
+//! instead of being written to str0m as fast as it arrives from cluster. This avoids self-inflicted loss
+//! on constrained downlinks when a big key-frame or a burst of relayed packets arrives at once.
+//!
+//! Audio is small and latency sensitive, so it is always sent first but still consumes the budget.
+
+use std::{collections::VecDeque, time::Instant};
+
+use media_server_protocol::media::MediaPacket;
+use str0m::media::Mid;
+
+/// Pacing rate is higher than target for draining the queue after bursts, same factor as libwebrtc
+const PACING_FACTOR_PERCENT: u64 = 250;
+const MIN_TARGET_BPS: u64 = 50_000;
+/// Max bytes can be accumulated when idle, in ms of pacing rate
+const MAX_BUDGET_MS: u64 = 20;
+/// Packet which waited longer than this is sent immediately, we prefer burst than too high latency
+const MAX_QUEUE_MS: u128 = 500;
+
+#[derive(Debug, Default)]
+pub struct EgressPacer {
+    /// Bytes per second, None when bwe is not configured yet, in that case packets are not paced
+    rate: Option<u64>,
+    budget: i64,
+    last_refill: Option<Instant>,
+    audio: VecDeque<(Mid, MediaPacket)>,
+    video: VecDeque<(Instant, Mid, MediaPacket)>,
+}
+
+impl EgressPacer {
+    /// Update target bitrate from bwe
+    pub fn set_target(&mut self, now: Instant, target_bps: u64) {
+        self.refill(now);
+        let rate = target_bps.max(MIN_TARGET_BPS) * PACING_FACTOR_PERCENT / 100 / 8;
+        log::debug!("[EgressPacer] set target {target_bps} bps => pacing {rate} Bps");
+        self.rate = Some(rate);
+    }
+
+    pub fn push(&mut self, now: Instant, mid: Mid, pkt: MediaPacket) {
+        if pkt.meta.is_audio() {
+            self.audio.push_back((mid, pkt));
+        } else {
+            self.video.push_back((now, mid, pkt));
+        }
+    }
+
+    pub fn pop(&mut self, now: Instant) -> Option<(Mid, MediaPacket)> {
+        self.refill(now);
+        if let Some((mid, pkt)) = self.audio.pop_front() {
+            self.budget -= pkt.data.len() as i64;
+            return Some((mid, pkt));
+        }
+
+        let (queued_at, _, _) = self.video.front()?;
+        if self.rate.is_some() && self.budget < 0 && now.duration_since(*queued_at).as_millis() < MAX_QUEUE_MS {
+            return None;
+        }
+        let (_, mid, pkt) = self.video.pop_front()?;
+        self.budget -= pkt.data.len() as i64;
+        Some((mid, pkt))
+    }
+
+    /// Take all queued packets without pacing, audio first, used when the transport is closing
+    pub fn flush(&mut self) -> Vec<(Mid, MediaPacket)> {
+        let audio = self.audio.drain(..);
+        let video = self.video.drain(..).map(|(_, mid, pkt)| (mid, pkt));
+        audio.chain(video).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.audio.is_empty() && self.video.is_empty()
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let last = self.last_refill.replace(now);
+        let (rate, last) = match (self.rate, last) {
+            (Some(rate), Some(last)) => (rate, last),
+            _ => return,
+        };
+        let elapsed_us = now.saturating_duration_since(last).as_micros() as u64;
+        let max_budget = (rate * MAX_BUDGET_MS / 1000) as i64;
+        self.budget = (self.budget + (rate * elapsed_us / 1_000_000) as i64).min(max_budget);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use media_server_protocol::media::{MediaMeta, MediaPacket};
+    use str0m::media::Mid;
+
+    use super::EgressPacer;
+
+    fn pkt(seq: u16, meta: MediaMeta, size: usize) -> MediaPacket {
+        MediaPacket {
+            ts: 0,
+            seq,
+            marker: false,
+            nackable: true,
+            layers: None,
+            meta,
+            data: vec![0; size],
+        }
+    }
+
+    fn video(seq: u16) -> MediaPacket {
+        pkt(
+            seq,
+            MediaMeta::Vp8 {
+                key: false,
+                sim: None,
+                rotation: None,
+            },
+            1000,
+        )
+    }
+
+    #[test]
+    fn not_paced_without_target() {
+        let mut pacer = EgressPacer::default();
+        let now = Instant::now();
+        let mid = Mid::from("0");
+        for seq in 0..10 {
+            pacer.push(now, mid, video(seq));
+        }
+        for seq in 0..10 {
+            assert_eq!(pacer.pop(now).map(|(_, p)| p.seq), Some(seq));
+        }
+        assert_eq!(pacer.pop(now), None);
+    }
+
+    #[test]
+    fn paced_with_low_target() {
+        let mut pacer = EgressPacer::default();
+        let t0 = Instant::now();
+        let mid = Mid::from("0");
+        // 100kbps => pacing at 250kbps => 1000 bytes each 32ms
+        pacer.set_target(t0, 100_000);
+        for seq in 0..10 {
+            pacer.push(t0, mid, video(seq));
+        }
+
+        let mut sent_at = vec![];
+        for ms in 0..400 {
+            let now = t0 + Duration::from_millis(ms);
+            while let Some((_, pkt)) = pacer.pop(now) {
+                sent_at.push((pkt.seq, ms));
+            }
+        }
+
+        assert_eq!(sent_at.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+        for pair in sent_at.windows(2) {
+            let gap = pair[1].1 - pair[0].1;
+            assert!((30..=34).contains(&gap), "packets should be paced, gap {gap} ms");
+        }
+    }
+
+    #[test]
+    fn audio_first_and_long_queue_flushed() {
+        let mut pacer = EgressPacer::default();
+        let t0 = Instant::now();
+        let mid = Mid::from("0");
+        pacer.set_target(t0, 100_000);
+        pacer.push(t0, mid, video(0));
+        pacer.push(t0, mid, video(1));
+        pacer.push(t0, mid, pkt(2, MediaMeta::Opus { audio_level: None }, 100));
+
+        assert_eq!(pacer.pop(t0).map(|(_, p)| p.seq), Some(2));
+        assert_eq!(pacer.pop(t0), None);
+
+        // video packets which waited too long are flushed even budget is empty
+        let later = t0 + Duration::from_millis(500);
+        assert_eq!(pacer.pop(later).map(|(_, p)| p.seq), Some(0));
+        assert_eq!(pacer.pop(later).map(|(_, p)| p.seq), Some(1));
+        assert_eq!(pacer.pop(later), None);
+    }
+
+    #[test]
+    fn flush_all_queued() {
+        let mut pacer = EgressPacer::default();
+        let t0 = Instant::now();
+        let mid = Mid::from("0");
+        pacer.set_target(t0, 100_000);
+        assert!(pacer.is_empty());
+        pacer.push(t0, mid, video(0));
+        pacer.push(t0, mid, video(1));
+        pacer.push(t0, mid, pkt(2, MediaMeta::Opus { audio_level: None }, 100));
+        assert_eq!(pacer.pop(t0).map(|(_, p)| p.seq), Some(2));
+        assert_eq!(pacer.pop(t0).map(|(_, p)| p.seq), Some(0));
+        assert_eq!(pacer.pop(t0), None);
+        assert!(!pacer.is_empty());
+
+        assert_eq!(pacer.flush().into_iter().map(|(_, p)| p.seq).collect::<Vec<_>>(), vec![1]);
+        assert!(pacer.is_empty());
+    }
+}
@@ -0,0 +1,64 @@
+//! Connection state which is shared by all transport variants
+
+use std::time::Instant;
+
+use media_server_core::transport::{TransportError, TransportState};
+
+#[derive(Debug)]
+pub(super) enum State {
+    New,
+    Connecting { at: Instant },
+    ConnectError(TransportWebrtcError),
+    Connected,
+    Reconnecting { at: Instant },
+    Disconnected,
+}
+
+#[derive(Debug)]
+pub(super) enum TransportWebrtcError {
+    Timeout,
+}
+
+impl State {
+    pub fn is_shutdown(&self) -> bool {
+        matches!(self, State::ConnectError(_) | State::Disconnected)
+    }
+
+    /// Switch to failed state when remote didn't refresh consent, return the transport state which should be reported.
+    /// Before connected it is a connect error, after that it is a disconnect with timeout, closed states are kept.
+    pub fn on_consent_failed(&mut self) -> Option<TransportState> {
+        match self {
+            State::New | State::Connecting { .. } => {
+                *self = State::ConnectError(TransportWebrtcError::Timeout);
+                Some(TransportState::ConnectError(TransportError::Timeout))
+            }
+            State::Connected | State::Reconnecting { .. } => {
+                *self = State::Disconnected;
+                Some(TransportState::Disconnected(Some(TransportError::Timeout)))
+            }
+            State::ConnectError(_) | State::Disconnected => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use media_server_core::transport::{TransportError, TransportState};
+
+    use super::State;
+
+    #[test]
+    fn consent_failed_by_state() {
+        let mut state = State::Connecting { at: Instant::now() };
+        assert_eq!(state.on_consent_failed(), Some(TransportState::ConnectError(TransportError::Timeout)));
+        assert!(state.is_shutdown());
+        assert_eq!(state.on_consent_failed(), None);
+
+        let mut state = State::Reconnecting { at: Instant::now() };
+        assert_eq!(state.on_consent_failed(), Some(TransportState::Disconnected(Some(TransportError::Timeout))));
+        assert!(matches!(state, State::Disconnected));
+        assert_eq!(state.on_consent_failed(), None);
+    }
+}
@@ -5,6 +5,7 @@ use std::{
 };
 
 use media_server_core::{
+    cluster::{ClusterJoinRejectReason, RoomConfigPatch},
     endpoint::{
         EndpointAudioMixerReq, EndpointEvent, EndpointLocalTrackEvent, EndpointLocalTrackReq, EndpointMessageChannelReq, EndpointMessageChannelRes, EndpointRemoteTrackReq, EndpointReq, EndpointReqId,
         EndpointRes, MessageChannelLabel,
@@ -12,21 +13,22 @@ use media_server_core::{
     transport::{LocalTrackEvent, LocalTrackId, RemoteTrackEvent, RemoteTrackId, TransportError, TransportEvent, TransportOutput, TransportState},
 };
 use media_server_protocol::{
-    endpoint::{AudioMixerConfig, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe},
+    endpoint::{AudioMixerConfig, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackKindFilter, TrackName},
+    media::MediaKind,
     multi_tenancy::AppContext,
     protobuf::{
         self,
         features::{
             mixer::{
-                server_event::{Event as ProtoFeatureMixerEvent2, SlotSet, SlotUnset},
-                ServerEvent as ProtoFeatureMixerEvent,
+                server_event::{ConfigConflict, Event as ProtoFeatureMixerEvent2, SlotSet, SlotUnset},
+                Mode as ProtoMixerMode, ServerEvent as ProtoFeatureMixerEvent,
             },
             server_event::Event as ProtoFeaturesEvent2,
             ServerEvent as ProtoFeaturesEvent,
         },
         gateway::ConnectRequest,
         session::{
-            request::message_channel as MessageChannelControlReq,
+            request::{message_channel as MessageChannelControlReq, room as RoomControlReq},
             response::{
                 message_channel::{Publish, Response as MessageChannelResponse, StartPublish, StopPublish, Subscribe, Unsubscribe},
                 MessageChannel,
@@ -34,16 +36,22 @@ use media_server_protocol::{
             server_event::{
                 message_channel::{Event as ProtoMessageChannelEvent, Message as MessageChannelMessageEvent},
                 receiver::{Event as ProtoReceiverEvent, State as ProtoReceiverState, VoiceActivity as ProtoReceiverVoiceActivity},
-                room::{Event as ProtoRoomEvent2, PeerJoined, PeerLeaved, TrackStarted, TrackStopped},
-                sender::{Event as ProtoSenderEvent, State as ProtoSenderState},
+                room::{ConfigChanged, Event as ProtoRoomEvent2, JoinPending, Paused, PeerJoined, PeerLeaved, TrackMuted, TrackStarted, TrackStopped},
+                sender::{Event as ProtoSenderEvent, LayerBitrates as ProtoSenderLayerBitrates, State as ProtoSenderState},
+                session::{Event as ProtoSessionEvent2, GoAway as ProtoGoAway, ReceiverReused as ProtoReceiverReused, Renegotiate as ProtoRenegotiate},
                 Event as ProtoServerEvent, MessageChannel as ProtoMessageChannelContainerEvent, Receiver as ProtoReceiverEventContainer, Room as ProtoRoomEvent, Sender as ProtoSenderEventContainer,
+                Session as ProtoSessionEvent,
             },
-            ClientEvent,
+            ClientEvent, RoomJoin as ProtoRoomJoin,
+        },
+        shared::{
+            receiver::{Source as ProtoReceiverSource, State as ProtoReceiverTrackState, Status as ProtoReceiverStatus},
+            sender::Status as ProtoSenderStatus,
+            Kind, Receiver as ProtoReceiver,
         },
-        shared::{receiver::Source as ProtoReceiverSource, sender::Status as ProtoSenderStatus, Kind},
     },
     tokens::WebrtcToken,
-    transport::{RpcError, RpcResult},
+    transport::{webrtc::WebrtcMigrateTicket, whep::WhepEvent, RpcError, RpcResult},
 };
 use media_server_secure::MediaEdgeSecure;
 use prost::Message;
@@ -52,7 +60,7 @@ use str0m::{
     bwe::BweKind,
     channel::ChannelId,
     format::CodecConfig,
-    media::{Direction, KeyframeRequestKind, MediaAdded, Mid},
+    media::{Direction, KeyframeRequestKind, MediaAdded, Mid, Rid},
     Event as Str0mEvent, IceConnectionState,
 };
 
@@ -60,32 +68,39 @@ use crate::{media::RemoteMediaConvert, transport::InternalRpcReq, WebrtcError};
 
 use self::{local_track::LocalTrack, remote_track::RemoteTrack};
 
-use super::{bwe_state::BweState, InternalOutput, InternalRpcRes, TransportWebrtcInternal};
+use super::{
+    bwe_state::BweState,
+    state::{State, TransportWebrtcError},
+    InternalOutput, InternalRpcRes, TransportWebrtcInternal,
+};
 
 const TIMEOUT_SEC: u64 = 10;
+/// Time for client to reconnect to new node after migrate go-away, the ticket expires after that. Old session is closed by
+/// gateway as soon as the new one is created, this timeout only closes it when client never reconnects
+const MIGRATE_GO_AWAY_SEC: u64 = WebrtcMigrateTicket::TTL_SECONDS;
+/// Internal request id of server offers, they are not client requests so they must not share req_id of client requests
+const RENEGOTIATE_REQ_ID: u32 = u32::MAX;
+/// Time for client to answer a server offer, the offer is cancelled and its receivers are rolled back after that
+const RENEGOTIATE_TIMEOUT: Duration = Duration::from_secs(10);
 
 mod local_track;
 mod remote_track;
 
-#[derive(Debug)]
-enum State {
-    New,
-    Connecting { at: Instant },
-    ConnectError(TransportWebrtcError),
-    Connected,
-    Reconnecting { at: Instant },
-    Disconnected,
-}
-
-impl State {
-    fn is_shutdown(&self) -> bool {
-        matches!(self, State::ConnectError(_) | State::Disconnected)
-    }
-}
-
-#[derive(Debug)]
-enum TransportWebrtcError {
-    Timeout,
+/// Server-initiated renegotiation, only enabled when client can answer it. Each new room track gets a receiver
+/// named `peer/track`, receivers are offered in a single offer and only one offer is in flight at a time.
+/// Receivers whose source stopped are kept idle and reused for new room tracks of same kind, so m-lines don't grow.
+#[derive(Default)]
+struct Renegotiation {
+    enabled: bool,
+    next_id: u32,
+    waiting: Vec<ProtoReceiver>,
+    pending: Option<(u32, Vec<ProtoReceiver>)>,
+    /// Deadline of the pending offer, it is set in the first tick after the offer is created
+    deadline: Option<Instant>,
+    /// Client req_id and receivers of the answer which is being applied, receivers are rolled back if it is rejected
+    answering: Option<(u32, Vec<ProtoReceiver>)>,
+    /// Negotiated receivers whose source stopped
+    idle: Vec<String>,
 }
 
 pub struct TransportWebrtcSdk<ES> {
@@ -93,6 +108,12 @@ pub struct TransportWebrtcSdk<ES> {
     remote: IpAddr,
     extra_data: Option<String>,
     join: Option<(RoomId, PeerId, Option<String>, RoomInfoPublish, RoomInfoSubscribe)>,
+    /// Room which is joined or joining, room control requests need an admin token of this room
+    room: Option<RoomId>,
+    /// Last requested join, it is handed over to the new node when the session is migrated
+    current_join: Option<ProtoRoomJoin>,
+    /// Receivers which were attached in the old node of a migrated session, they are attached again after join
+    handover: Vec<String>,
     state: State,
     queue: DynamicDeque<InternalOutput, 4>,
     channel: Option<ChannelId>,
@@ -103,18 +124,40 @@ pub struct TransportWebrtcSdk<ES> {
     media_convert: RemoteMediaConvert,
     bwe_state: BweState,
     secure: Arc<ES>,
+    migrate_deadline: Option<Instant>,
+    renegotiation: Renegotiation,
 }
 
 impl<ES> TransportWebrtcSdk<ES> {
-    pub fn new(app: AppContext, req: ConnectRequest, extra_data: Option<String>, secure: Arc<ES>, remote: IpAddr) -> Self {
+    /// Migrate ticket is verified by worker, join and receivers of the request are replaced with the handed over ones
+    pub fn new(app: AppContext, mut req: ConnectRequest, extra_data: Option<String>, secure: Arc<ES>, remote: IpAddr, migrate: Option<WebrtcMigrateTicket>) -> Self {
+        let handover = migrate.map(|ticket| ticket.apply(&mut req)).unwrap_or_default();
+        let current_join = req.join.clone();
+        let renegotiation = Renegotiation {
+            enabled: req.renegotiation,
+            ..Default::default()
+        };
         let tracks = req.tracks.unwrap_or_default();
-        let local_tracks: Vec<LocalTrack> = tracks.receivers.into_iter().enumerate().map(|(index, r)| LocalTrack::new((index as u16).into(), r)).collect();
+        let local_tracks: Vec<LocalTrack> = tracks
+            .receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, r)| {
+                let state = r.state.clone().filter(|_| handover.contains(&r.name));
+                let mut track = LocalTrack::new((index as u16).into(), r);
+                track.set_state(state);
+                track
+            })
+            .collect();
         let remote_tracks: Vec<RemoteTrack> = tracks.senders.into_iter().enumerate().map(|(index, s)| RemoteTrack::new((index as u16).into(), s)).collect();
         if let Some(j) = req.join {
             Self {
                 app,
                 remote,
                 extra_data,
+                room: Some(j.room.clone().into()),
+                current_join,
+                handover,
                 join: Some((j.room.into(), j.peer.into(), j.metadata, j.publish.unwrap_or_default().into(), j.subscribe.unwrap_or_default().into())),
                 state: State::New,
                 audio_mixer: j.features.and_then(|f| {
@@ -136,6 +179,8 @@ impl<ES> TransportWebrtcSdk<ES> {
                 media_convert: RemoteMediaConvert::default(),
                 bwe_state: BweState::default(),
                 secure,
+                migrate_deadline: None,
+                renegotiation,
             }
         } else {
             Self {
@@ -143,6 +188,9 @@ impl<ES> TransportWebrtcSdk<ES> {
                 remote,
                 extra_data,
                 join: None,
+                room: None,
+                current_join,
+                handover: vec![],
                 state: State::New,
                 local_tracks,
                 remote_tracks,
@@ -153,6 +201,8 @@ impl<ES> TransportWebrtcSdk<ES> {
                 media_convert: RemoteMediaConvert::default(),
                 bwe_state: BweState::default(),
                 secure,
+                migrate_deadline: None,
+                renegotiation,
             }
         }
     }
@@ -197,6 +247,104 @@ impl<ES> TransportWebrtcSdk<ES> {
         let response = protobuf::session::response::Response::Error(err.into());
         self.send_event(protobuf::session::server_event::Event::Response(protobuf::session::Response { req_id, response: Some(response) }))
     }
+
+    /// Add a receiver for the room track and offer it to client, do nothing if renegotiation is not supported
+    fn renegotiate_add_receiver(&mut self, peer: &PeerId, track: &TrackName, kind: MediaKind) {
+        if !self.renegotiation.enabled {
+            return;
+        }
+        let name = format!("{peer}/{track}");
+        if self.local_track_by_name(&name).is_some() {
+            return;
+        }
+        let receiver = ProtoReceiver {
+            kind: Kind::from(kind) as i32,
+            name,
+            state: Some(ProtoReceiverTrackState {
+                config: None,
+                source: Some(ProtoReceiverSource {
+                    peer: peer.to_string(),
+                    track: track.to_string(),
+                }),
+            }),
+        };
+        let local_tracks = &self.local_tracks;
+        let idle = self.renegotiation.idle.iter().position(|idle| local_tracks.iter().any(|t| t.name() == idle && t.kind() == kind));
+        if let Some(index) = idle {
+            let previous = self.renegotiation.idle.remove(index);
+            let track = return_if_none!(self.local_track_by_name(&previous));
+            track.set_name(receiver.name.clone().into());
+            log::info!("[TransportWebrtcSdk] reused idle local track {previous} for {}", receiver.name);
+            self.send_event(ProtoServerEvent::Session(ProtoSessionEvent {
+                event: Some(ProtoSessionEvent2::Reused(ProtoReceiverReused { previous, receiver: Some(receiver) })),
+            }));
+            return;
+        }
+        log::info!("[TransportWebrtcSdk] added new local track {} for renegotiation", receiver.name);
+        // ids are not reused because rolled back tracks are removed from the list
+        let id = self.local_tracks.iter().map(|t| *t.id() + 1).max().unwrap_or(0);
+        self.local_tracks.push(LocalTrack::new(id.into(), receiver.clone()));
+        self.renegotiation.waiting.push(receiver);
+        self.renegotiate_next();
+    }
+
+    /// Release receiver of a stopped room track, it is dropped if not offered yet or kept idle for reuse if negotiated
+    fn renegotiate_remove_receiver(&mut self, peer: &PeerId, track: &TrackName) {
+        if !self.renegotiation.enabled {
+            return;
+        }
+        let name = format!("{peer}/{track}");
+        if let Some(index) = self.renegotiation.waiting.iter().position(|r| r.name == name) {
+            log::info!("[TransportWebrtcSdk] removed waiting local track {name} because source stopped");
+            self.renegotiation.waiting.remove(index);
+            self.local_tracks.retain(|t| t.name() != name);
+        } else if self.local_tracks.iter().any(|t| t.name() == name && t.mid().is_some()) && !self.renegotiation.idle.contains(&name) {
+            log::info!("[TransportWebrtcSdk] local track {name} is idle because source stopped");
+            self.renegotiation.idle.push(name);
+        }
+    }
+
+    /// Remove local tracks of receivers whose offer failed, they never get a m-line
+    fn renegotiate_rollback(&mut self, receivers: Vec<ProtoReceiver>) {
+        for receiver in receivers {
+            log::warn!("[TransportWebrtcSdk] rollback local track {} of failed renegotiation", receiver.name);
+            self.local_tracks.retain(|t| t.name() != receiver.name || t.mid().is_some());
+        }
+        self.renegotiate_next();
+    }
+
+    /// Attach receivers of a migrated session to the sources which they were attached to in the old node.
+    /// Endpoint only accepts attach after the room is joined, so it is called after join is answered
+    fn attach_handover(&mut self) {
+        for name in std::mem::take(&mut self.handover) {
+            let (track_id, state) = match self.local_track_by_name(&name) {
+                Some(track) => (track.id(), track.state().cloned().unwrap_or_default()),
+                None => continue,
+            };
+            let source = match state.source {
+                Some(source) => source,
+                None => continue,
+            };
+            log::info!("[TransportWebrtcSdk] attach handed over receiver {name} to {}/{}", source.peer, source.track);
+            self.queue.push_back(InternalOutput::TransportOutput(TransportOutput::RpcReq(
+                0.into(),
+                EndpointReq::LocalTrack(track_id, EndpointLocalTrackReq::Attach(source.into(), state.config.unwrap_or_default().into())),
+            )));
+        }
+    }
+
+    fn renegotiate_next(&mut self) {
+        if self.renegotiation.pending.is_some() || self.renegotiation.waiting.is_empty() {
+            return;
+        }
+        let id = self.renegotiation.next_id;
+        self.renegotiation.next_id += 1;
+        let receivers = std::mem::take(&mut self.renegotiation.waiting);
+        let kinds = receivers.iter().map(|r| r.kind().into()).collect::<Vec<_>>();
+        self.renegotiation.pending = Some((id, receivers));
+        self.renegotiation.deadline = None;
+        self.queue.push_back(InternalOutput::RpcReq(RENEGOTIATE_REQ_ID, InternalRpcReq::CreateOffer(kinds)));
+    }
 }
 
 impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
@@ -204,6 +352,10 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
         self.media_convert.set_config(cfg);
     }
 
+    fn on_simulcast_rids(&mut self, rids: Vec<(Mid, Vec<Rid>)>) {
+        self.media_convert.set_simulcast_rids(rids);
+    }
+
     fn is_empty(&self) -> bool {
         self.state.is_shutdown() && self.queue.is_empty()
     }
@@ -241,6 +393,22 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
             }
             _ => {}
         }
+
+        if self.renegotiation.pending.is_some() && now >= *self.renegotiation.deadline.get_or_insert(now + RENEGOTIATE_TIMEOUT) {
+            let (id, receivers) = return_if_none!(self.renegotiation.pending.take());
+            log::warn!("[TransportWebrtcSdk] renegotiate offer {id} is not answered after {RENEGOTIATE_TIMEOUT:?} => cancel it");
+            self.queue.push_back(InternalOutput::RpcReq(RENEGOTIATE_REQ_ID, InternalRpcReq::CancelOffer));
+            self.renegotiate_rollback(receivers);
+        }
+
+        if let Some(deadline) = self.migrate_deadline {
+            if now >= deadline && !self.state.is_shutdown() {
+                log::info!("[TransportWebrtcSdk] migrate go-away timeout => switched to Disconnected");
+                self.state = State::Disconnected;
+                self.queue
+                    .push_back(InternalOutput::TransportOutput(TransportOutput::Event(TransportEvent::State(TransportState::Disconnected(None)))));
+            }
+        }
     }
 
     fn on_rpc_res(&mut self, req_id: u32, res: RpcResult<InternalRpcRes>) {
@@ -252,15 +420,60 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                         response: Some(protobuf::session::response::session::Response::Sdp(protobuf::session::response::session::UpdateSdp { sdp: answer })),
                     }),
                 ),
+                InternalRpcRes::CreateOffer(offer) => {
+                    let (id, receivers) = return_if_none!(self.renegotiation.pending.clone());
+                    if let Some(sdp) = offer {
+                        log::info!("[TransportWebrtcSdk] send renegotiate offer {id} with {} receivers", receivers.len());
+                        self.send_event(ProtoServerEvent::Session(ProtoSessionEvent {
+                            event: Some(ProtoSessionEvent2::Renegotiate(ProtoRenegotiate { id, sdp, receivers })),
+                        }));
+                    } else {
+                        log::warn!("[TransportWebrtcSdk] renegotiate offer {id} is not created");
+                        self.renegotiation.pending = None;
+                        self.renegotiate_rollback(receivers);
+                    }
+                }
+                InternalRpcRes::SetRemoteAnswer => {
+                    self.renegotiation.answering = None;
+                    self.send_rpc_res(
+                        req_id,
+                        protobuf::session::response::Response::Session(protobuf::session::response::Session {
+                            response: Some(protobuf::session::response::session::Response::Renegotiate(protobuf::session::response::session::RenegotiateAnswer {})),
+                        }),
+                    );
+                    self.renegotiate_next();
+                }
             },
             Err(err) => {
+                if req_id == RENEGOTIATE_REQ_ID {
+                    log::warn!("[TransportWebrtcSdk] renegotiate offer error {err:?}");
+                    let (_, receivers) = return_if_none!(self.renegotiation.pending.take());
+                    return self.renegotiate_rollback(receivers);
+                }
+                if let Some((_, receivers)) = self.renegotiation.answering.take_if(|(answer_req_id, _)| *answer_req_id == req_id) {
+                    self.renegotiate_rollback(receivers);
+                }
                 self.send_rpc_res_err(req_id, err);
+                self.renegotiate_next();
             }
         }
     }
 
     fn on_endpoint_event(&mut self, now: Instant, event: EndpointEvent) {
         match event {
+            EndpointEvent::JoinRejected(peer, reason) => {
+                // sdk protocol dont have join rejected event yet, client only see it as not in room
+                log::warn!("[TransportWebrtcSdk] join as {peer} rejected with reason {reason:?}");
+                if reason != ClusterJoinRejectReason::AlreadyInRoom {
+                    self.room = None;
+                }
+            }
+            EndpointEvent::JoinPending(peer) => {
+                log::info!("[TransportWebrtcSdk] peer {peer} waiting for admit");
+                self.send_event(ProtoServerEvent::Room(ProtoRoomEvent {
+                    event: Some(ProtoRoomEvent2::JoinPending(JoinPending { peer: peer.into() })),
+                }));
+            }
             EndpointEvent::PeerJoined(peer, meta) => {
                 log::info!("[TransportWebrtcSdk] peer {peer} joined");
                 self.send_event(ProtoServerEvent::Room(ProtoRoomEvent {
@@ -281,20 +494,32 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                 log::info!("[TransportWebrtcSdk] peer {peer} track {track} started");
                 self.send_event(ProtoServerEvent::Room(ProtoRoomEvent {
                     event: Some(ProtoRoomEvent2::TrackStarted(TrackStarted {
-                        peer: peer.into(),
-                        track: track.into(),
+                        peer: peer.to_string(),
+                        track: track.to_string(),
                         kind: Kind::from(meta.kind) as i32,
                         metadata: meta.metadata,
                     })),
-                }))
+                }));
+                self.renegotiate_add_receiver(&peer, &track, meta.kind);
             }
             EndpointEvent::PeerTrackStopped(peer, track, meta) => {
                 log::info!("[TransportWebrtcSdk] peer {peer} track {track} stopped");
                 self.send_event(ProtoServerEvent::Room(ProtoRoomEvent {
                     event: Some(ProtoRoomEvent2::TrackStopped(TrackStopped {
+                        peer: peer.to_string(),
+                        track: track.to_string(),
+                        kind: Kind::from(meta.kind) as i32,
+                    })),
+                }));
+                self.renegotiate_remove_receiver(&peer, &track);
+            }
+            EndpointEvent::PeerTrackMuted(peer, track, muted) => {
+                log::info!("[TransportWebrtcSdk] peer {peer} track {track} muted {muted}");
+                self.send_event(ProtoServerEvent::Room(ProtoRoomEvent {
+                    event: Some(ProtoRoomEvent2::TrackMuted(TrackMuted {
                         peer: peer.into(),
                         track: track.into(),
-                        kind: Kind::from(meta.kind) as i32,
+                        muted,
                     })),
                 }));
             }
@@ -321,6 +546,17 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                         })),
                     }))
                 }
+                media_server_core::endpoint::EndpointAudioMixerEvent::ConfigConflict(mode, outputs) => {
+                    log::warn!("[TransportWebrtcSdk] audio mixer ignored, room mixer is {mode:?} with {outputs} outputs");
+                    self.send_event(ProtoServerEvent::Features(ProtoFeaturesEvent {
+                        event: Some(ProtoFeaturesEvent2::Mixer(ProtoFeatureMixerEvent {
+                            event: Some(ProtoFeatureMixerEvent2::ConfigConflict(ConfigConflict {
+                                mode: ProtoMixerMode::from(mode) as i32,
+                                outputs: outputs as u32,
+                            })),
+                        })),
+                    }))
+                }
             },
             EndpointEvent::RemoteMediaTrack(track_id, event) => match event {
                 media_server_core::endpoint::EndpointRemoteTrackEvent::RequestKeyFrame => {
@@ -329,7 +565,18 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                     log::info!("[TransportWebrtcSdk] request key-frame");
                     self.queue.push_back(InternalOutput::Str0mKeyframe(mid, KeyframeRequestKind::Fir));
                 }
-                media_server_core::endpoint::EndpointRemoteTrackEvent::LimitBitrateBps { min, max } => {
+                media_server_core::endpoint::EndpointRemoteTrackEvent::LimitBitrateBps { min, max, layers } => {
+                    // REMB limits the whole track, so layer targets are sent to the client which configures each encoding
+                    if let Some(layers) = layers {
+                        let name = return_if_none!(self.remote_track(track_id)).name().to_string();
+                        log::debug!("[TransportWebrtcSdk] sender {name} layer targets {layers:?}");
+                        self.send_event(ProtoServerEvent::Sender(ProtoSenderEventContainer {
+                            name,
+                            event: Some(ProtoSenderEvent::LayerBitrates(ProtoSenderLayerBitrates {
+                                layers: layers.iter().map(|layer| layer.unwrap_or(0)).collect(),
+                            })),
+                        }));
+                    }
                     let track = return_if_none!(self.remote_track(track_id));
                     let mid = return_if_none!(track.mid());
                     let bitrate = track.calc_limit_bitrate(min, max);
@@ -376,18 +623,79 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                     event: Some(ProtoMessageChannelEvent::Message(MessageChannelMessageEvent { peer: from.into(), message })),
                 }));
             }
-            EndpointEvent::GoAway(_, _) => {}
+            EndpointEvent::ChannelError(label, err) => {
+                log::warn!("[TransportWebrtcSdk] datachannel {} error {err}", label.0);
+                self.send_event(ProtoServerEvent::MessageChannel(ProtoMessageChannelContainerEvent {
+                    label: label.0,
+                    event: Some(ProtoMessageChannelEvent::Error(err.into())),
+                }));
+            }
+            EndpointEvent::GoAway(seconds, reason) => {
+                log::info!("[TransportWebrtcSdk] go away after {seconds} seconds, reason {reason:?}");
+                self.send_event(ProtoServerEvent::Session(ProtoSessionEvent {
+                    event: Some(ProtoSessionEvent2::Goway(ProtoGoAway {
+                        reason: reason.unwrap_or_default(),
+                        remain_seconds: seconds as u32,
+                        conn_id: None,
+                        migrate_token: None,
+                    })),
+                }));
+            }
+            EndpointEvent::RoomPaused(paused) => {
+                log::info!("[TransportWebrtcSdk] room paused {paused}");
+                self.send_event(ProtoServerEvent::Room(ProtoRoomEvent {
+                    event: Some(ProtoRoomEvent2::Paused(Paused { paused })),
+                }));
+            }
+            EndpointEvent::RoomConfigChanged(config) => {
+                log::info!("[TransportWebrtcSdk] room config changed {config:?}");
+                self.send_event(ProtoServerEvent::Room(ProtoRoomEvent {
+                    event: Some(ProtoRoomEvent2::ConfigChanged(ConfigChanged {
+                        max_bitrate: config.max_bitrate,
+                        max_spatial: config.max_spatial.map(|spatial| spatial as u32),
+                    })),
+                }));
+            }
+            EndpointEvent::RemoteMediaTrackRejected(track_id) => {
+                // sender is detached so client can attach it again later, its requests are rejected by endpoint until then
+                let track = return_if_none!(self.remote_track(track_id));
+                log::warn!("[TransportWebrtcSdk] remote track {} rejected by track limit => detach", track.name());
+                track.del_source();
+                let name = track.name().to_string();
+                self.send_event(ProtoServerEvent::Sender(ProtoSenderEventContainer {
+                    name,
+                    event: Some(ProtoSenderEvent::State(ProtoSenderState {
+                        status: ProtoSenderStatus::Inactive as i32,
+                    })),
+                }));
+            }
+            EndpointEvent::LocalMediaTrackRejected(track_id) => {
+                // attach requests of this receiver are rejected by endpoint, the stored source is dropped so it is not handed over
+                let track = return_if_none!(self.local_track(track_id));
+                log::warn!("[TransportWebrtcSdk] local track {} rejected by track limit => detach", track.name());
+                track.set_state(None);
+                let name = track.name().to_string();
+                self.send_event(ProtoServerEvent::Receiver(ProtoReceiverEventContainer {
+                    name,
+                    event: Some(ProtoReceiverEvent::State(ProtoReceiverState {
+                        status: ProtoReceiverStatus::Inactive as i32,
+                    })),
+                }));
+            }
         }
     }
 
     fn on_transport_rpc_res(&mut self, _now: Instant, req_id: EndpointReqId, res: EndpointRes) {
         match res {
-            EndpointRes::JoinRoom(Ok(_)) => self.send_rpc_res(
-                req_id.0,
-                protobuf::session::response::Response::Session(protobuf::session::response::Session {
-                    response: Some(protobuf::session::response::session::Response::Join(protobuf::session::response::session::Join {})),
-                }),
-            ),
+            EndpointRes::JoinRoom(Ok(_)) => {
+                self.send_rpc_res(
+                    req_id.0,
+                    protobuf::session::response::Response::Session(protobuf::session::response::Session {
+                        response: Some(protobuf::session::response::session::Response::Join(protobuf::session::response::session::Join {})),
+                    }),
+                );
+                self.attach_handover();
+            }
             EndpointRes::JoinRoom(Err(err)) => self.send_rpc_res_err(req_id.0, err),
             EndpointRes::LeaveRoom(Ok(_)) => self.send_rpc_res(
                 req_id.0,
@@ -396,8 +704,62 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                 }),
             ),
             EndpointRes::LeaveRoom(Err(err)) => self.send_rpc_res_err(req_id.0, err),
-            EndpointRes::SubscribePeer(_) => todo!(),
-            EndpointRes::UnsubscribePeer(_) => todo!(),
+            EndpointRes::SubscribePeer(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::Subscribe(protobuf::session::response::room::SubscribePeer {})),
+                }),
+            ),
+            EndpointRes::SubscribePeer(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+            EndpointRes::UnsubscribePeer(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::Unsubscribe(protobuf::session::response::room::UnsubscribePeer {})),
+                }),
+            ),
+            EndpointRes::UnsubscribePeer(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+            EndpointRes::PauseRoom(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::Pause(protobuf::session::response::room::Pause {})),
+                }),
+            ),
+            EndpointRes::PauseRoom(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+            EndpointRes::SetRoomLocked(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::Lock(protobuf::session::response::room::Lock {})),
+                }),
+            ),
+            EndpointRes::SetRoomLocked(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+            EndpointRes::AdmitPeer(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::Admit(protobuf::session::response::room::Admit {})),
+                }),
+            ),
+            EndpointRes::AdmitPeer(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+            EndpointRes::UpdateRoomConfig(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::Config(protobuf::session::response::room::UpdateConfig {})),
+                }),
+            ),
+            EndpointRes::UpdateRoomConfig(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+            EndpointRes::BatchTrackSubscriptions(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::BatchTracks(protobuf::session::response::room::BatchTracks {})),
+                }),
+            ),
+            EndpointRes::BatchTrackSubscriptions(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+            EndpointRes::SetPeerKindFilter(Ok(_)) => self.send_rpc_res(
+                req_id.0,
+                protobuf::session::response::Response::Room(protobuf::session::response::Room {
+                    response: Some(protobuf::session::response::room::Response::KindFilter(protobuf::session::response::room::KindFilter {})),
+                }),
+            ),
+            EndpointRes::SetPeerKindFilter(Err(err)) => self.send_rpc_res_err(req_id.0, err),
             EndpointRes::RemoteTrack(_track_id, res) => match res {
                 media_server_core::endpoint::EndpointRemoteTrackRes::Config(Ok(_)) => self.send_rpc_res(
                     req_id.0,
@@ -406,8 +768,15 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                     }),
                 ),
                 media_server_core::endpoint::EndpointRemoteTrackRes::Config(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+                media_server_core::endpoint::EndpointRemoteTrackRes::Mute(Ok(_)) => self.send_rpc_res(
+                    req_id.0,
+                    protobuf::session::response::Response::Sender(protobuf::session::response::Sender {
+                        response: Some(protobuf::session::response::sender::Response::Mute(protobuf::session::response::sender::Mute {})),
+                    }),
+                ),
+                media_server_core::endpoint::EndpointRemoteTrackRes::Mute(Err(err)) => self.send_rpc_res_err(req_id.0, err),
             },
-            EndpointRes::LocalTrack(_track_id, res) => match res {
+            EndpointRes::LocalTrack(track_id, res) => match res {
                 media_server_core::endpoint::EndpointLocalTrackRes::Attach(Ok(_)) => self.send_rpc_res(
                     req_id.0,
                     protobuf::session::response::Response::Receiver(protobuf::session::response::Receiver {
@@ -426,7 +795,12 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
                         response: Some(protobuf::session::response::receiver::Response::Config(protobuf::session::response::receiver::Config {})),
                     }),
                 ),
-                media_server_core::endpoint::EndpointLocalTrackRes::Attach(Err(err)) => self.send_rpc_res_err(req_id.0, err),
+                media_server_core::endpoint::EndpointLocalTrackRes::Attach(Err(err)) => {
+                    if let Some(track) = self.local_track(track_id) {
+                        track.set_state(None);
+                    }
+                    self.send_rpc_res_err(req_id.0, err)
+                }
                 media_server_core::endpoint::EndpointLocalTrackRes::Detach(Err(err)) => self.send_rpc_res_err(req_id.0, err),
                 media_server_core::endpoint::EndpointLocalTrackRes::Config(Err(err)) => self.send_rpc_res_err(req_id.0, err),
             },
@@ -595,6 +969,13 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
         }
     }
 
+    fn on_consent_failed(&mut self, _now: Instant) {
+        if let Some(state) = self.state.on_consent_failed() {
+            log::info!("[TransportWebrtcSdk] consent failed => switched to {state:?}");
+            self.queue.push_back(InternalOutput::TransportOutput(TransportOutput::Event(TransportEvent::State(state))));
+        }
+    }
+
     fn on_shutdown(&mut self, _now: Instant) {
         if !self.state.is_shutdown() {
             log::info!("[TransportWebrtcSdk] switched to disconnected with close action");
@@ -606,6 +987,33 @@ impl<ES: MediaEdgeSecure> TransportWebrtcInternal for TransportWebrtcSdk<ES> {
         }
     }
 
+    fn on_migrate(&mut self, now: Instant, conn_id: String, mut ticket: WebrtcMigrateTicket) -> bool {
+        log::info!("[TransportWebrtcSdk] migrate to conn {conn_id}, close after {MIGRATE_GO_AWAY_SEC} seconds");
+        if let Some(join) = &self.current_join {
+            ticket.set_join(join);
+        }
+        for track in self.local_tracks.iter() {
+            if let Some(state) = track.state() {
+                ticket.add_receiver(track.name(), state);
+            }
+        }
+        let token = self.secure.encode_migrate_ticket(ticket);
+        self.send_event(ProtoServerEvent::Session(ProtoSessionEvent {
+            event: Some(ProtoSessionEvent2::Goway(ProtoGoAway {
+                reason: "migrate".to_string(),
+                remain_seconds: MIGRATE_GO_AWAY_SEC as u32,
+                conn_id: Some(conn_id),
+                migrate_token: Some(token),
+            })),
+        }));
+        self.migrate_deadline = Some(now + Duration::from_secs(MIGRATE_GO_AWAY_SEC));
+        true
+    }
+
+    fn pop_events(&mut self) -> Option<Vec<WhepEvent>> {
+        None
+    }
+
     fn pop_output(&mut self, _now: Instant) -> Option<InternalOutput> {
         self.queue.pop_front()
     }
@@ -676,7 +1084,7 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
                 }
             }
             Direction::Inactive => {
-                log::warn!("[TransportWebrtcSdk] unsupported direct Inactive");
+                log::info!("[TransportWebrtcSdk] mid {} is inactive => not publish or subscribe", media.mid);
             }
         }
     }
@@ -698,7 +1106,7 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
                     None => self.send_rpc_res_err(req.req_id, RpcError::new2(WebrtcError::RpcInvalidRequest)),
                 },
                 Some(protobuf::session::request::Request::Room(room)) => match room.request {
-                    Some(room_req) => self.on_room_req(req.req_id, room_req),
+                    Some(room_req) => self.on_room_req(req.req_id, room.admin_token, room_req),
                     None => self.send_rpc_res_err(req.req_id, RpcError::new2(WebrtcError::RpcInvalidRequest)),
                 },
                 Some(protobuf::session::request::Request::MessageChannel(channel)) => match channel.request {
@@ -725,6 +1133,7 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
         match req {
             protobuf::session::request::session::Request::Join(req) => {
                 let info = req.info.unwrap_or_default();
+                let join = info.clone();
                 let meta = PeerMeta {
                     metadata: info.metadata,
                     extra_data: self.extra_data.clone(),
@@ -733,6 +1142,8 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
                     if ctx.app != self.app.app {
                         self.send_rpc_res_err(req_id, RpcError::new2(WebrtcError::RpcTokenAppNotMatch));
                     } else if token.room == Some(info.room.clone()) && token.peer == Some(info.peer.clone()) {
+                        self.room = Some(info.room.clone().into());
+                        self.current_join = Some(join);
                         let mixer_cfg = info.features.and_then(|f| {
                             f.mixer.map(|m| AudioMixerConfig {
                                 mode: m.mode().into(),
@@ -755,7 +1166,11 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
                     self.send_rpc_res_err(req_id, RpcError::new2(WebrtcError::RpcTokenInvalid));
                 }
             }
-            protobuf::session::request::session::Request::Leave(_req) => self.queue.push_back(build_req(EndpointReq::LeaveRoom)),
+            protobuf::session::request::session::Request::Leave(_req) => {
+                self.room = None;
+                self.current_join = None;
+                self.queue.push_back(build_req(EndpointReq::LeaveRoom));
+            }
             protobuf::session::request::session::Request::Sdp(req) => {
                 let tracks = req.tracks.unwrap_or_default();
                 for (index, s) in tracks.senders.into_iter().enumerate() {
@@ -773,6 +1188,15 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
                 }
                 self.queue.push_back(InternalOutput::RpcReq(req_id, InternalRpcReq::SetRemoteSdp(req.sdp)));
             }
+            protobuf::session::request::session::Request::Renegotiate(req) => {
+                if self.renegotiation.pending.as_ref().is_some_and(|(id, _)| *id == req.id) {
+                    self.renegotiation.answering = self.renegotiation.pending.take().map(|(_, receivers)| (req_id, receivers));
+                    self.queue.push_back(InternalOutput::RpcReq(req_id, InternalRpcReq::SetRemoteAnswer(req.sdp)));
+                } else {
+                    log::warn!("[TransportWebrtcSdk] renegotiate answer {} is not pending", req.id);
+                    self.send_rpc_res_err(req_id, RpcError::new2(WebrtcError::RpcInvalidRequest));
+                }
+            }
             protobuf::session::request::session::Request::Disconnect(_req) => {
                 if !matches!(self.state, State::Disconnected) {
                     log::info!("[TransportWebrtcSdk] switched to disconnected with close action from client");
@@ -842,6 +1266,7 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
                 }
             }
             protobuf::session::request::sender::Request::Config(config) => self.queue.push_back(build_req(EndpointReq::RemoteTrack(track_id, EndpointRemoteTrackReq::Config(config.into())))),
+            protobuf::session::request::sender::Request::Mute(mute) => self.queue.push_back(build_req(EndpointReq::RemoteTrack(track_id, EndpointRemoteTrackReq::Mute(mute.muted)))),
         }
     }
 
@@ -857,15 +1282,21 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
 
         match req {
             protobuf::session::request::receiver::Request::Attach(attach) => {
+                track.set_state(Some(ProtoReceiverTrackState {
+                    config: attach.config,
+                    source: attach.source.clone(),
+                }));
                 self.queue.push_back(build_req(EndpointLocalTrackReq::Attach(
                     attach.source.unwrap_or_default().into(),
                     attach.config.unwrap_or_default().into(),
                 )));
             }
             protobuf::session::request::receiver::Request::Detach(_) => {
+                track.set_state(None);
                 self.queue.push_back(build_req(EndpointLocalTrackReq::Detach()));
             }
             protobuf::session::request::receiver::Request::Config(config) => {
+                track.set_config(config);
                 self.queue.push_back(build_req(EndpointLocalTrackReq::Config(config.into())));
             }
         }
@@ -902,8 +1333,47 @@ impl<ES: MediaEdgeSecure> TransportWebrtcSdk<ES> {
             .push_back(InternalOutput::TransportOutput(TransportOutput::RpcReq(req_id.into(), EndpointReq::MessageChannel(label, req))));
     }
 
-    fn on_room_req(&mut self, _req_id: u32, _req: protobuf::session::request::room::Request) {
-        todo!()
+    fn on_room_req(&mut self, req_id: u32, admin_token: Option<String>, req: protobuf::session::request::room::Request) {
+        let req = match req {
+            RoomControlReq::Request::Subscribe(req) => EndpointReq::SubscribePeer(req.peer.into()),
+            RoomControlReq::Request::Unsubscribe(req) => EndpointReq::UnsubscribePeer(req.peer.into()),
+            RoomControlReq::Request::Pause(req) => EndpointReq::PauseRoom(req.paused),
+            RoomControlReq::Request::Lock(req) => EndpointReq::SetRoomLocked(req.locked),
+            RoomControlReq::Request::Admit(req) => EndpointReq::AdmitPeer(req.peer.into(), req.admit),
+            RoomControlReq::Request::Config(req) => EndpointReq::UpdateRoomConfig(RoomConfigPatch {
+                max_bitrate: req.max_bitrate,
+                max_spatial: req.max_spatial.map(|spatial| spatial.min(u8::MAX as u32) as u8),
+                record: req.record,
+            }),
+            RoomControlReq::Request::KindFilter(req) => EndpointReq::SetPeerKindFilter(req.peer.into(), TrackKindFilter { audio: req.audio, video: req.video }),
+            RoomControlReq::Request::BatchTracks(req) => EndpointReq::BatchTrackSubscriptions {
+                subscribe: req.subscribe.into_iter().map(|t| (t.peer.into(), t.track.into())).collect(),
+                unsubscribe: req.unsubscribe.into_iter().map(|t| (t.peer.into(), t.track.into())).collect(),
+            },
+        };
+        if !matches!(
+            req,
+            EndpointReq::SubscribePeer(_) | EndpointReq::UnsubscribePeer(_) | EndpointReq::SetPeerKindFilter(..) | EndpointReq::BatchTrackSubscriptions { .. }
+        ) {
+            if let Err(err) = self.check_room_admin(admin_token.as_deref()) {
+                log::warn!("[TransportWebrtcSdk] room control {req:?} without room admin token => reject");
+                self.send_rpc_res_err(req_id, RpcError::new2(err));
+                return;
+            }
+        }
+        self.queue.push_back(InternalOutput::TransportOutput(TransportOutput::RpcReq(req_id.into(), req)));
+    }
+
+    /// Pause, lock, admit and config requests need a token which is issued with room admin permission for the current room
+    fn check_room_admin(&self, admin_token: Option<&str>) -> Result<(), WebrtcError> {
+        let (ctx, token) = self.secure.decode_token::<WebrtcToken>(admin_token.unwrap_or_default()).ok_or(WebrtcError::RpcTokenInvalid)?;
+        if ctx.app != self.app.app {
+            return Err(WebrtcError::RpcTokenAppNotMatch);
+        }
+        if !token.room_admin || self.room.is_none() || token.room.map(RoomId::from) != self.room {
+            return Err(WebrtcError::RpcTokenNotRoomAdmin);
+        }
+        Ok(())
     }
 }
 
@@ -916,11 +1386,12 @@ mod tests {
     };
 
     use media_server_core::{
-        endpoint::EndpointReq,
+        endpoint::{EndpointEvent, EndpointLocalTrackReq, EndpointRemoteTrackEvent, EndpointReq, EndpointRes},
         transport::{TransportError, TransportEvent, TransportOutput, TransportState},
     };
     use media_server_protocol::{
-        endpoint::{PeerMeta, RoomInfoPublish, RoomInfoSubscribe},
+        endpoint::{ClusterConnId, PeerMeta, RoomInfoPublish, RoomInfoSubscribe, TrackKindFilter, TrackMeta},
+        media::MediaKind,
         multi_tenancy::{AppContext, AppId},
         protobuf::{
             self, gateway,
@@ -928,17 +1399,20 @@ mod tests {
             shared,
         },
         tokens::WebrtcToken,
-        transport::RpcError,
+        transport::{webrtc::WebrtcMigrateTicket, RpcError},
     };
     use media_server_secure::{
         jwt::{MediaEdgeSecureJwt, MediaGatewaySecureJwt},
-        DumpAppStorage, MediaGatewaySecure,
+        DumpAppStorage, MediaEdgeSecure, MediaGatewaySecure,
     };
     use prost::Message;
-    use str0m::channel::ChannelId;
+    use str0m::{channel::ChannelId, media::Mid};
 
     use crate::{
-        transport::{webrtc::TIMEOUT_SEC, InternalOutput, TransportWebrtcInternal},
+        transport::{
+            webrtc::{RENEGOTIATE_REQ_ID, RENEGOTIATE_TIMEOUT, TIMEOUT_SEC},
+            InternalOutput, InternalRpcReq, InternalRpcRes, TransportWebrtcInternal,
+        },
         WebrtcError,
     };
 
@@ -969,7 +1443,7 @@ mod tests {
         let now = Instant::now();
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
-        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip);
+        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip, None);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -1014,7 +1488,7 @@ mod tests {
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let gateway_jwt = MediaGatewaySecureJwt::new(b"1234".as_slice(), Arc::new(DumpAppStorage::default()));
         let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
-        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip);
+        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip, None);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -1037,6 +1511,7 @@ mod tests {
                 peer: Some("peer1".to_string()),
                 record: false,
                 extra_data: Some("extra_data".to_string()),
+                room_admin: false,
             },
             10000,
         );
@@ -1091,7 +1566,7 @@ mod tests {
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let gateway_jwt = MediaGatewaySecureJwt::new(b"1234".as_slice(), Arc::new(DumpAppStorage::default()));
         let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
-        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip);
+        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip, None);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -1114,6 +1589,7 @@ mod tests {
                 peer: Some("peer1".to_string()),
                 record: false,
                 extra_data: Some("extra_data".to_string()),
+                room_admin: false,
             },
             10000,
         );
@@ -1153,7 +1629,7 @@ mod tests {
         let now = Instant::now();
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
-        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip);
+        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip, None);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -1181,7 +1657,7 @@ mod tests {
         let now = Instant::now();
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
-        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip);
+        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip, None);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -1209,7 +1685,7 @@ mod tests {
         let now = Instant::now();
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
-        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip);
+        let mut transport = TransportWebrtcSdk::new(app, req, Some("extra_data".to_string()), secure_jwt.clone(), ip, None);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_str0m_event(now, str0m::Event::ChannelOpen(channel_id, "data".to_string()));
@@ -1227,6 +1703,641 @@ mod tests {
         assert!(transport.is_empty());
     }
 
+    fn server_event(out: Option<InternalOutput>) -> session::server_event::Event {
+        match out {
+            Some(InternalOutput::Str0mSendData(_, data)) => session::ServerEvent::decode(data.as_slice()).expect("Should decode").event.expect("Should have event"),
+            _ => panic!("Should be server event, got {out:?}"),
+        }
+    }
+
+    #[test]
+    fn track_started_renegotiate_receiver() {
+        let req = gateway::ConnectRequest {
+            renegotiation: true,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), req, None, secure_jwt, ip, None);
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(create_channel_id(), "data".to_string()));
+        assert!(transport.pop_output(now).is_some());
+
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "video_main".into(), TrackMeta::default_video()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        assert_eq!(
+            transport.pop_output(now),
+            Some(InternalOutput::RpcReq(RENEGOTIATE_REQ_ID, InternalRpcReq::CreateOffer(vec![MediaKind::Video])))
+        );
+
+        // only one offer in flight, next track waits for the answer
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "audio_main".into(), TrackMeta::default_audio()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        assert_eq!(transport.pop_output(now), None);
+
+        transport.on_rpc_res(RENEGOTIATE_REQ_ID, Ok(InternalRpcRes::CreateOffer(Some("offer".to_string()))));
+        match server_event(transport.pop_output(now)) {
+            session::server_event::Event::Session(session::server_event::Session {
+                event: Some(session::server_event::session::Event::Renegotiate(offer)),
+            }) => {
+                assert_eq!(offer.id, 0);
+                assert_eq!(offer.sdp, "offer");
+                assert_eq!(offer.receivers.len(), 1);
+                assert_eq!(offer.receivers[0].name, "peer2/video_main");
+                assert_eq!(offer.receivers[0].kind(), shared::Kind::Video);
+            }
+            event => panic!("Should be renegotiate event, got {event:?}"),
+        }
+
+        transport.on_str0m_channel_event(ClientEvent {
+            seq: 0,
+            event: Some(client_event::Event::Request(session::Request {
+                req_id: 2,
+                request: Some(session::request::Request::Session(session::request::Session {
+                    request: Some(session::request::session::Request::Renegotiate(session::request::session::RenegotiateAnswer {
+                        id: 0,
+                        sdp: "answer".to_string(),
+                    })),
+                })),
+            })),
+        });
+        assert_eq!(transport.pop_output(now), Some(InternalOutput::RpcReq(2, InternalRpcReq::SetRemoteAnswer("answer".to_string()))));
+
+        transport.on_rpc_res(2, Ok(InternalRpcRes::SetRemoteAnswer));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Response(_)));
+        assert_eq!(
+            transport.pop_output(now),
+            Some(InternalOutput::RpcReq(RENEGOTIATE_REQ_ID, InternalRpcReq::CreateOffer(vec![MediaKind::Audio])))
+        );
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    fn renegotiate_answer(req_id: u32, id: u32) -> ClientEvent {
+        ClientEvent {
+            seq: 0,
+            event: Some(client_event::Event::Request(session::Request {
+                req_id,
+                request: Some(session::request::Request::Session(session::request::Session {
+                    request: Some(session::request::session::Request::Renegotiate(session::request::session::RenegotiateAnswer {
+                        id,
+                        sdp: "answer".to_string(),
+                    })),
+                })),
+            })),
+        }
+    }
+
+    fn renegotiate_transport(now: Instant) -> TransportWebrtcSdk<MediaEdgeSecureJwt> {
+        let req = gateway::ConnectRequest {
+            renegotiation: true,
+            ..Default::default()
+        };
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), req, None, secure_jwt, IpAddr::V4(Ipv4Addr::LOCALHOST), None);
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(create_channel_id(), "data".to_string()));
+        assert!(transport.pop_output(now).is_some());
+        transport
+    }
+
+    //Receivers of an offer which is not answered in time or rejected are rolled back, late answer is rejected
+    #[test]
+    fn renegotiate_rollback_on_timeout_and_rejected_answer() {
+        let now = Instant::now();
+        let mut transport = renegotiate_transport(now);
+        transport.on_tick(now);
+        while transport.pop_output(now).is_some() {}
+
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "video_main".into(), TrackMeta::default_video()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        assert_eq!(
+            transport.pop_output(now),
+            Some(InternalOutput::RpcReq(RENEGOTIATE_REQ_ID, InternalRpcReq::CreateOffer(vec![MediaKind::Video])))
+        );
+        transport.on_rpc_res(RENEGOTIATE_REQ_ID, Ok(InternalRpcRes::CreateOffer(Some("offer".to_string()))));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Session(_)));
+
+        // client req_id same as internal offer id is still a client request
+        transport.on_rpc_res(0, Err(RpcError::new2(WebrtcError::InvalidSdp)));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Response(_)));
+        assert!(transport.local_track_by_name("peer2/video_main").is_some());
+
+        transport.on_tick(now);
+        assert_eq!(transport.pop_output(now), None);
+        // connecting is also timed out at the same time, only renegotiation outputs are checked
+        transport.on_tick(now + RENEGOTIATE_TIMEOUT);
+        let outputs = std::iter::from_fn(|| transport.pop_output(now)).collect::<Vec<_>>();
+        assert!(outputs.contains(&InternalOutput::RpcReq(RENEGOTIATE_REQ_ID, InternalRpcReq::CancelOffer)));
+        assert!(!outputs.iter().any(|out| matches!(out, InternalOutput::RpcReq(_, InternalRpcReq::CreateOffer(_)))));
+        assert!(transport.local_track_by_name("peer2/video_main").is_none());
+
+        // late answer of cancelled offer
+        transport.on_str0m_channel_event(renegotiate_answer(1, 0));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Response(_)));
+        assert_eq!(transport.pop_output(now), None);
+
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "audio_main".into(), TrackMeta::default_audio()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        assert_eq!(
+            transport.pop_output(now),
+            Some(InternalOutput::RpcReq(RENEGOTIATE_REQ_ID, InternalRpcReq::CreateOffer(vec![MediaKind::Audio])))
+        );
+        transport.on_rpc_res(RENEGOTIATE_REQ_ID, Ok(InternalRpcRes::CreateOffer(Some("offer".to_string()))));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Session(_)));
+        transport.on_str0m_channel_event(renegotiate_answer(2, 1));
+        assert_eq!(transport.pop_output(now), Some(InternalOutput::RpcReq(2, InternalRpcReq::SetRemoteAnswer("answer".to_string()))));
+        transport.on_rpc_res(2, Err(RpcError::new2(WebrtcError::InvalidSdp)));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Response(_)));
+        assert!(transport.local_track_by_name("peer2/audio_main").is_none());
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    //Negotiated receiver whose source stopped is reused for next track of same kind without new offer
+    #[test]
+    fn renegotiate_reuse_idle_receiver() {
+        let now = Instant::now();
+        let mut transport = renegotiate_transport(now);
+
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "video_main".into(), TrackMeta::default_video()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        assert!(transport.pop_output(now).is_some());
+        transport.on_rpc_res(RENEGOTIATE_REQ_ID, Ok(InternalRpcRes::CreateOffer(Some("offer".to_string()))));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Session(_)));
+        transport.on_str0m_channel_event(renegotiate_answer(1, 0));
+        assert!(transport.pop_output(now).is_some());
+        transport.local_track_by_name("peer2/video_main").expect("Should have local track").set_mid(Mid::from("1"));
+        transport.on_rpc_res(1, Ok(InternalRpcRes::SetRemoteAnswer));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Response(_)));
+
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStopped("peer2".into(), "video_main".into(), TrackMeta::default_video()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer3".into(), "video_main".into(), TrackMeta::default_video()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        match server_event(transport.pop_output(now)) {
+            session::server_event::Event::Session(session::server_event::Session {
+                event: Some(session::server_event::session::Event::Reused(reused)),
+            }) => {
+                assert_eq!(reused.previous, "peer2/video_main");
+                assert_eq!(reused.receiver.expect("Should have receiver").name, "peer3/video_main");
+            }
+            event => panic!("Should be reused event, got {event:?}"),
+        }
+        assert_eq!(transport.pop_output(now), None);
+        assert_eq!(transport.local_tracks.len(), 1);
+        assert_eq!(transport.local_track_by_name("peer3/video_main").and_then(|t| t.mid()), Some(Mid::from("1")));
+    }
+
+    #[test]
+    fn track_started_without_renegotiation() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), gateway::ConnectRequest::default(), None, secure_jwt, ip, None);
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(create_channel_id(), "data".to_string()));
+        assert!(transport.pop_output(now).is_some());
+
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "video_main".into(), TrackMeta::default_video()));
+        assert!(matches!(server_event(transport.pop_output(now)), session::server_event::Event::Room(_)));
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    #[test]
+    fn track_limit_rejected_tracks_detached() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let req = gateway::ConnectRequest {
+            tracks: Some(shared::Tracks {
+                receivers: vec![shared::Receiver {
+                    state: Some(shared::receiver::State {
+                        config: None,
+                        source: Some(shared::receiver::Source {
+                            peer: "peer2".to_string(),
+                            track: "video_main".to_string(),
+                        }),
+                    }),
+                    ..video_receiver("video_0")
+                }],
+                senders: vec![shared::Sender {
+                    kind: shared::Kind::Audio as i32,
+                    name: "audio_main".to_string(),
+                    state: Some(shared::sender::State {
+                        config: None,
+                        source: Some(shared::sender::Source {
+                            id: "mic".to_string(),
+                            screen: false,
+                            metadata: None,
+                        }),
+                    }),
+                }],
+            }),
+            ..Default::default()
+        };
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), req, None, secure_jwt, ip, None);
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(create_channel_id(), "data".to_string()));
+        while transport.pop_output(now).is_some() {}
+
+        transport.on_endpoint_event(now, EndpointEvent::RemoteMediaTrackRejected(0.into()));
+        assert_eq!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Sender(session::server_event::Sender {
+                name: "audio_main".to_string(),
+                event: Some(session::server_event::sender::Event::State(session::server_event::sender::State {
+                    status: shared::sender::Status::Inactive as i32,
+                })),
+            })
+        );
+        assert!(!transport.remote_track(0.into()).expect("Should have remote track").has_source());
+
+        transport.on_endpoint_event(now, EndpointEvent::LocalMediaTrackRejected(0.into()));
+        assert_eq!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Receiver(session::server_event::Receiver {
+                name: "video_0".to_string(),
+                event: Some(session::server_event::receiver::Event::State(session::server_event::receiver::State {
+                    status: shared::receiver::Status::Inactive as i32,
+                })),
+            })
+        );
+        assert_eq!(transport.local_track(0.into()).expect("Should have local track").state(), None);
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    //Simulcast layer targets are sent to the client as sender event, single-encoding limits are not
+    #[test]
+    fn layer_targets_sent_to_client() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let req = gateway::ConnectRequest {
+            tracks: Some(shared::Tracks {
+                receivers: vec![],
+                senders: vec![shared::Sender {
+                    kind: shared::Kind::Video as i32,
+                    name: "video_main".to_string(),
+                    state: None,
+                }],
+            }),
+            ..Default::default()
+        };
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), req, None, secure_jwt, ip, None);
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(create_channel_id(), "data".to_string()));
+        while transport.pop_output(now).is_some() {}
+
+        transport.on_endpoint_event(
+            now,
+            EndpointEvent::RemoteMediaTrack(
+                0.into(),
+                EndpointRemoteTrackEvent::LimitBitrateBps {
+                    min: 100_000,
+                    max: 1_500_000,
+                    layers: Some([Some(150_000), Some(500_000), None]),
+                },
+            ),
+        );
+        assert_eq!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Sender(session::server_event::Sender {
+                name: "video_main".to_string(),
+                event: Some(session::server_event::sender::Event::LayerBitrates(session::server_event::sender::LayerBitrates {
+                    layers: vec![150_000, 500_000, 0],
+                })),
+            })
+        );
+        assert_eq!(transport.pop_output(now), None);
+
+        transport.on_endpoint_event(
+            now,
+            EndpointEvent::RemoteMediaTrack(
+                0.into(),
+                EndpointRemoteTrackEvent::LimitBitrateBps {
+                    min: 100_000,
+                    max: 1_500_000,
+                    layers: None,
+                },
+            ),
+        );
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    //Room control requests need an admin token of the joined room, waiting peers are sent to the client
+    #[test]
+    fn room_control_require_admin_token() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let req = gateway::ConnectRequest {
+            join: Some(session::RoomJoin {
+                room: "demo".to_string(),
+                peer: "owner".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let gateway_jwt = MediaGatewaySecureJwt::new(b"1234".as_slice(), Arc::new(DumpAppStorage::default()));
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), req, None, secure_jwt, ip, None);
+        let channel_id = create_channel_id();
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(channel_id, "data".to_string()));
+        while transport.pop_output(now).is_some() {}
+
+        let token = |room: &str, room_admin: bool| {
+            gateway_jwt.encode_token(
+                &AppContext::root_app(),
+                WebrtcToken {
+                    room: Some(room.to_string()),
+                    peer: None,
+                    record: false,
+                    extra_data: None,
+                    room_admin,
+                },
+                10000,
+            )
+        };
+        let pause = |req_id: u32, admin_token: Option<String>| ClientEvent {
+            seq: req_id,
+            event: Some(client_event::Event::Request(session::Request {
+                req_id,
+                request: Some(session::request::Request::Room(session::request::Room {
+                    admin_token,
+                    request: Some(session::request::room::Request::Pause(session::request::room::Pause { paused: true })),
+                })),
+            })),
+        };
+        let rpc_error = |out: Option<InternalOutput>| match server_event(out) {
+            session::server_event::Event::Response(session::Response {
+                response: Some(session::response::Response::Error(err)),
+                ..
+            }) => err.code,
+            event => panic!("Should be error response, got {event:?}"),
+        };
+
+        transport.on_str0m_channel_event(pause(1, None));
+        assert_eq!(rpc_error(transport.pop_output(now)), WebrtcError::RpcTokenInvalid as u32);
+        transport.on_str0m_channel_event(pause(2, Some(token("demo", false))));
+        assert_eq!(rpc_error(transport.pop_output(now)), WebrtcError::RpcTokenNotRoomAdmin as u32);
+        transport.on_str0m_channel_event(pause(3, Some(token("other", true))));
+        assert_eq!(rpc_error(transport.pop_output(now)), WebrtcError::RpcTokenNotRoomAdmin as u32);
+        assert_eq!(transport.pop_output(now), None);
+
+        transport.on_str0m_channel_event(pause(4, Some(token("demo", true))));
+        assert_eq!(
+            transport.pop_output(now),
+            Some(InternalOutput::TransportOutput(TransportOutput::RpcReq(4.into(), EndpointReq::PauseRoom(true))))
+        );
+        transport.on_transport_rpc_res(now, 4.into(), EndpointRes::PauseRoom(Ok(())));
+        assert!(matches!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Response(session::Response {
+                req_id: 4,
+                response: Some(session::response::Response::Room(_)),
+            })
+        ));
+
+        transport.on_endpoint_event(now, EndpointEvent::JoinPending("guest".into()));
+        assert_eq!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Room(session::server_event::Room {
+                event: Some(session::server_event::room::Event::JoinPending(session::server_event::room::JoinPending { peer: "guest".to_string() })),
+            })
+        );
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    #[test]
+    fn room_kind_filter_request() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let req = gateway::ConnectRequest {
+            join: Some(session::RoomJoin {
+                room: "demo".to_string(),
+                peer: "viewer".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), req, None, secure_jwt, ip, None);
+        let channel_id = create_channel_id();
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(channel_id, "data".to_string()));
+        while transport.pop_output(now).is_some() {}
+
+        // kind filter only affects what the peer itself receives, so it doesn't need room admin token
+        transport.on_str0m_channel_event(ClientEvent {
+            seq: 1,
+            event: Some(client_event::Event::Request(session::Request {
+                req_id: 1,
+                request: Some(session::request::Request::Room(session::request::Room {
+                    admin_token: None,
+                    request: Some(session::request::room::Request::KindFilter(session::request::room::KindFilter {
+                        peer: "speaker".to_string(),
+                        audio: true,
+                        video: false,
+                    })),
+                })),
+            })),
+        });
+        assert_eq!(
+            transport.pop_output(now),
+            Some(InternalOutput::TransportOutput(TransportOutput::RpcReq(
+                1.into(),
+                EndpointReq::SetPeerKindFilter("speaker".into(), TrackKindFilter::audio_only())
+            )))
+        );
+
+        transport.on_transport_rpc_res(now, 1.into(), EndpointRes::SetPeerKindFilter(Ok(())));
+        assert_eq!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Response(session::Response {
+                req_id: 1,
+                response: Some(session::response::Response::Room(session::response::Room {
+                    response: Some(session::response::room::Response::KindFilter(session::response::room::KindFilter {})),
+                })),
+            })
+        );
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    #[test]
+    fn room_batch_tracks_request() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let req = gateway::ConnectRequest {
+            join: Some(session::RoomJoin {
+                room: "demo".to_string(),
+                peer: "viewer".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let secure_jwt = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let mut transport = TransportWebrtcSdk::new(AppContext::root_app(), req, None, secure_jwt, ip, None);
+        let channel_id = create_channel_id();
+        transport.on_str0m_event(now, str0m::Event::ChannelOpen(channel_id, "data".to_string()));
+        while transport.pop_output(now).is_some() {}
+
+        let track = |peer: &str, track: &str| session::request::room::TrackRef {
+            peer: peer.to_string(),
+            track: track.to_string(),
+        };
+        // subscriptions only affect what the peer itself receives, so it doesn't need room admin token
+        transport.on_str0m_channel_event(ClientEvent {
+            seq: 1,
+            event: Some(client_event::Event::Request(session::Request {
+                req_id: 1,
+                request: Some(session::request::Request::Room(session::request::Room {
+                    admin_token: None,
+                    request: Some(session::request::room::Request::BatchTracks(session::request::room::BatchTracks {
+                        subscribe: vec![track("peer1", "audio"), track("peer2", "video")],
+                        unsubscribe: vec![track("peer1", "video")],
+                    })),
+                })),
+            })),
+        });
+        assert_eq!(
+            transport.pop_output(now),
+            Some(InternalOutput::TransportOutput(TransportOutput::RpcReq(
+                1.into(),
+                EndpointReq::BatchTrackSubscriptions {
+                    subscribe: vec![("peer1".into(), "audio".into()), ("peer2".into(), "video".into())],
+                    unsubscribe: vec![("peer1".into(), "video".into())],
+                }
+            )))
+        );
+
+        transport.on_transport_rpc_res(now, 1.into(), EndpointRes::BatchTrackSubscriptions(Ok(())));
+        assert_eq!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Response(session::Response {
+                req_id: 1,
+                response: Some(session::response::Response::Room(session::response::Room {
+                    response: Some(session::response::room::Response::BatchTracks(session::response::room::BatchTracks {})),
+                })),
+            })
+        );
+
+        transport.on_transport_rpc_res(now, 2.into(), EndpointRes::BatchTrackSubscriptions(Err(RpcError::new(1_u32, "not in room"))));
+        assert!(matches!(
+            server_event(transport.pop_output(now)),
+            session::server_event::Event::Response(session::Response {
+                req_id: 2,
+                response: Some(session::response::Response::Error(_)),
+            })
+        ));
+        assert_eq!(transport.pop_output(now), None);
+    }
+
+    fn video_receiver(name: &str) -> shared::Receiver {
+        shared::Receiver {
+            kind: shared::Kind::Video as i32,
+            name: name.to_string(),
+            state: None,
+        }
+    }
+
+    #[test]
+    fn migrate_hand_over_join_and_receivers_to_other_node() {
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        // both nodes share the cluster secret
+        let node1_secure = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let node2_secure = Arc::new(MediaEdgeSecureJwt::from(b"1234".as_slice()));
+        let source = shared::receiver::Source {
+            peer: "peer2".to_string(),
+            track: "video_main".to_string(),
+        };
+        let config = shared::receiver::Config {
+            priority: 10,
+            max_spatial: 2,
+            max_temporal: 2,
+            ..Default::default()
+        };
+
+        let req = gateway::ConnectRequest {
+            join: Some(session::RoomJoin {
+                room: "demo".to_string(),
+                peer: "peer1".to_string(),
+                ..Default::default()
+            }),
+            tracks: Some(shared::Tracks {
+                receivers: vec![video_receiver("video_0"), video_receiver("video_1")],
+                senders: vec![],
+            }),
+            ..Default::default()
+        };
+        let mut old = TransportWebrtcSdk::new(AppContext::root_app(), req, None, node1_secure, ip, None);
+        let channel_id = create_channel_id();
+        old.on_str0m_event(now, str0m::Event::ChannelOpen(channel_id, "data".to_string()));
+        while old.pop_output(now).is_some() {}
+
+        old.on_str0m_channel_event(ClientEvent {
+            seq: 1,
+            event: Some(client_event::Event::Request(session::Request {
+                req_id: 1,
+                request: Some(session::request::Request::Receiver(session::request::Receiver {
+                    name: "video_0".to_string(),
+                    request: Some(session::request::receiver::Request::Attach(session::request::receiver::Attach {
+                        source: Some(source.clone()),
+                        config: Some(config),
+                    })),
+                })),
+            })),
+        });
+        while old.pop_output(now).is_some() {}
+
+        let conn = ClusterConnId::migrate(2, 1000);
+        assert!(old.on_migrate(now, conn.to_string(), WebrtcMigrateTicket::new(1000, 1, 2)));
+        let token = match server_event(old.pop_output(now)) {
+            session::server_event::Event::Session(session::server_event::Session {
+                event: Some(session::server_event::session::Event::Goway(go_away)),
+            }) => {
+                assert_eq!(go_away.conn_id, Some(conn.to_string()));
+                go_away.migrate_token.expect("Should have migrate token")
+            }
+            event => panic!("Should be go-away, got {event:?}"),
+        };
+
+        // client sends other room and no receiver state, the new node only uses what is handed over in the ticket
+        let ticket = node2_secure.decode_migrate_ticket(&token).expect("Should verify ticket");
+        assert_eq!((ticket.session_id, ticket.from_node, ticket.dest_node), (1000, 1, 2));
+        let req = gateway::ConnectRequest {
+            join: Some(session::RoomJoin {
+                room: "other".to_string(),
+                peer: "peer1".to_string(),
+                ..Default::default()
+            }),
+            tracks: Some(shared::Tracks {
+                receivers: vec![video_receiver("video_0"), video_receiver("video_1")],
+                senders: vec![],
+            }),
+            migrate_token: Some(token),
+            ..Default::default()
+        };
+        let mut new = TransportWebrtcSdk::new(AppContext::root_app(), req, None, node2_secure, ip, Some(ticket));
+        new.on_str0m_event(now, str0m::Event::ChannelOpen(channel_id, "data".to_string()));
+        assert_eq!(
+            new.pop_output(now),
+            Some(InternalOutput::TransportOutput(TransportOutput::Event(TransportEvent::State(TransportState::Connected(ip)))))
+        );
+        match new.pop_output(now) {
+            Some(InternalOutput::TransportOutput(TransportOutput::RpcReq(_, EndpointReq::JoinRoom(room, peer, ..)))) => {
+                assert_eq!((room, peer), ("demo".into(), "peer1".into()));
+            }
+            out => panic!("Should join handed over room, got {out:?}"),
+        }
+        assert_eq!(new.pop_output(now), None);
+
+        // receivers are attached only after the room is joined
+        new.on_transport_rpc_res(now, 0.into(), EndpointRes::JoinRoom(Ok(())));
+        server_event(new.pop_output(now));
+        assert_eq!(
+            new.pop_output(now),
+            Some(InternalOutput::TransportOutput(TransportOutput::RpcReq(
+                0.into(),
+                EndpointReq::LocalTrack(0.into(), EndpointLocalTrackReq::Attach(source.into(), config.into()))
+            )))
+        );
+        assert_eq!(new.pop_output(now), None);
+    }
+
     //TODO test remote track non-source
     //TODO test remote track with source
     //TODO test remote track attach, detach
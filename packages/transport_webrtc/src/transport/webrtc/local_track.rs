@@ -1,5 +1,12 @@
 use media_server_core::transport::LocalTrackId;
-use media_server_protocol::{endpoint::TrackName, media::MediaKind, protobuf};
+use media_server_protocol::{
+    endpoint::TrackName,
+    media::MediaKind,
+    protobuf::{
+        self,
+        shared::receiver::{Config, State},
+    },
+};
 use str0m::media::Mid;
 
 pub struct LocalTrack {
@@ -7,6 +14,8 @@ pub struct LocalTrack {
     name: TrackName,
     kind: MediaKind,
     mid: Option<Mid>,
+    /// Source and config which the track is attached to, handed over when the session is migrated
+    state: Option<State>,
 }
 
 impl LocalTrack {
@@ -17,6 +26,7 @@ impl LocalTrack {
             name: cfg.name.clone().into(),
             kind: cfg.kind().into(),
             mid: None,
+            state: None,
         }
     }
 
@@ -28,6 +38,12 @@ impl LocalTrack {
         self.name.as_ref()
     }
 
+    /// Rename a track which is reused for another source
+    pub fn set_name(&mut self, name: TrackName) {
+        log::info!("[TransportWebrcSdk/LocalTrack] rename {}/{} => {}", self.id, self.name, name);
+        self.name = name;
+    }
+
     pub fn kind(&self) -> MediaKind {
         self.kind
     }
@@ -41,4 +57,18 @@ impl LocalTrack {
         assert_eq!(self.mid, None, "LocalTrack mid {:?} already configured", self.mid);
         self.mid = Some(mid);
     }
+
+    pub fn state(&self) -> Option<&State> {
+        self.state.as_ref()
+    }
+
+    pub fn set_state(&mut self, state: Option<State>) {
+        self.state = state;
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        if let Some(state) = &mut self.state {
+            state.config = Some(config);
+        }
+    }
 }
@@ -81,6 +81,8 @@ impl RemoteTrack {
             scaling: self.scaling,
             control: self.config.bitrate().into(),
             metadata: self.source.as_ref().and_then(|s| s.metadata.clone()),
+            muted: false,
+            encodings: vec![],
         }
     }
 
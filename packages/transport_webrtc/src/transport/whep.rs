@@ -10,7 +10,8 @@ use media_server_core::{
 };
 use media_server_protocol::{
     endpoint::{PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackMeta, TrackName, TrackPriority, TrackSource},
-    media::MediaKind,
+    media::{MediaKind, MediaMeta},
+    transport::{webrtc::WebrtcMigrateTicket, whep::WhepEvent},
 };
 use sans_io_runtime::{collections::DynamicDeque, return_if_none};
 use str0m::{
@@ -19,34 +20,54 @@ use str0m::{
     Event as Str0mEvent, IceConnectionState,
 };
 
-use super::{bwe_state::BweState, InternalOutput, TransportWebrtcInternal};
+use super::{
+    bwe_state::BweState,
+    state::{State, TransportWebrtcError},
+    InternalOutput, TransportWebrtcInternal,
+};
 
 const TIMEOUT_SEC: u64 = 10;
 const AUDIO_TRACK: LocalTrackId = LocalTrackId::build(0);
 const VIDEO_TRACK: LocalTrackId = LocalTrackId::build(1);
 const DEFAULT_PRIORITY: TrackPriority = TrackPriority::build(1);
+/// Events are dropped from oldest when no event stream is polling
+const MAX_EVENTS: usize = 64;
+/// Layer and stats events are sent at most once per interval
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
 
-#[derive(Debug)]
-enum State {
-    New,
-    Connecting { at: Instant },
-    ConnectError(TransportWebrtcError),
-    Connected,
-    Reconnecting { at: Instant },
-    Disconnected,
+/// Highest layers of forwarded video inside the current window, temporal layers are interleaved so a single packet is not enough
+#[derive(Default, Debug)]
+struct LayerWindow {
+    started: Option<Instant>,
+    max: Option<(u8, u8)>,
+    reported: Option<(u8, u8)>,
 }
 
-impl State {
-    fn is_shutdown(&self) -> bool {
-        matches!(self, State::ConnectError(_) | State::Disconnected)
+impl LayerWindow {
+    /// Return the layers when they are changed at the end of a window
+    fn on_video(&mut self, now: Instant, meta: &MediaMeta) -> Option<(u8, u8)> {
+        let (spatial, temporal) = match meta {
+            MediaMeta::H264 { sim: Some(sim), .. } => (sim.spatial, 0),
+            MediaMeta::Vp8 { sim: Some(sim), .. } => (sim.spatial, sim.temporal),
+            MediaMeta::Vp9 { svc: Some(svc), .. } => (svc.spatial, svc.temporal),
+            _ => (0, 0),
+        };
+        let started = *self.started.get_or_insert(now);
+        self.max = Some(self.max.map_or((spatial, temporal), |(s, t)| (s.max(spatial), t.max(temporal))));
+        if now < started + EVENT_INTERVAL {
+            return None;
+        }
+        let layers = self.max.take();
+        self.started = Some(now);
+        if layers != self.reported {
+            self.reported = layers;
+            layers
+        } else {
+            None
+        }
     }
 }
 
-#[derive(Debug)]
-enum TransportWebrtcError {
-    Timeout,
-}
-
 #[derive(Default, Debug)]
 struct SubscribeStreams {
     peer: Option<PeerId>,
@@ -65,7 +86,12 @@ pub struct TransportWebrtcWhep {
     subscribed: SubscribeStreams,
     audio_subscribe_waits: VecDeque<(PeerId, TrackName, TrackMeta)>,
     video_subscribe_waits: VecDeque<(PeerId, TrackName, TrackMeta)>,
+    /// Local tracks which are rejected by track limits, they are never subscribed
+    rejected_tracks: Vec<LocalTrackId>,
     bwe_state: BweState,
+    events: VecDeque<WhepEvent>,
+    layers: LayerWindow,
+    last_stats: Option<Instant>,
     queue: DynamicDeque<InternalOutput, 2>,
 }
 
@@ -83,14 +109,27 @@ impl TransportWebrtcWhep {
             queue: Default::default(),
             audio_subscribe_waits: VecDeque::new(),
             video_subscribe_waits: VecDeque::new(),
+            rejected_tracks: Vec::new(),
             bwe_state: Default::default(),
+            events: VecDeque::new(),
+            layers: Default::default(),
+            last_stats: None,
         }
     }
+
+    fn push_event(&mut self, event: WhepEvent) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
 }
 
 impl TransportWebrtcInternal for TransportWebrtcWhep {
     fn on_codec_config(&mut self, _cfg: &str0m::format::CodecConfig) {}
 
+    fn on_simulcast_rids(&mut self, _rids: Vec<(Mid, Vec<str0m::media::Rid>)>) {}
+
     fn is_empty(&self) -> bool {
         self.state.is_shutdown() && self.queue.is_empty()
     }
@@ -136,9 +175,20 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
 
     fn on_endpoint_event(&mut self, now: Instant, event: EndpointEvent) {
         match event {
+            EndpointEvent::JoinRejected(..) => {}
+            EndpointEvent::JoinPending(_) => {}
             EndpointEvent::PeerJoined(_, _) => {}
             EndpointEvent::PeerLeaved(_, _) => {}
             EndpointEvent::PeerTrackStarted(peer, track, meta) => {
+                let local_track = if meta.kind.is_audio() {
+                    AUDIO_TRACK
+                } else {
+                    VIDEO_TRACK
+                };
+                if self.rejected_tracks.contains(&local_track) {
+                    log::debug!("[TransportWebrtcWhep] local track {local_track} is rejected => skip subscribe {peer} {track}");
+                    return;
+                }
                 if self.audio_mid.is_none() && meta.kind.is_audio() {
                     log::info!("[TransportWebrtcWhep] waiting local audio track => push Subscribe candidate to waits");
                     self.audio_subscribe_waits.push_back((peer, track, meta));
@@ -152,6 +202,9 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
                 self.try_subscribe(peer, track, meta);
             }
             EndpointEvent::PeerTrackStopped(peer, track, _meta) => self.try_unsubscribe(peer, track),
+            EndpointEvent::PeerTrackMuted(_, _, _) => {}
+            EndpointEvent::RoomPaused(_) => {}
+            EndpointEvent::RoomConfigChanged(_) => {}
             EndpointEvent::LocalMediaTrack(_track, event) => match event {
                 EndpointLocalTrackEvent::Media(pkt) => {
                     let mid = if pkt.meta.is_audio() {
@@ -159,6 +212,9 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
                     } else {
                         let mid = return_if_none!(self.video_mid);
                         self.bwe_state.on_send_video(now);
+                        if let Some((spatial, temporal)) = self.layers.on_video(now, &pkt.meta) {
+                            self.push_event(WhepEvent::LayerChanged { spatial, temporal });
+                        }
                         mid
                     };
                     self.queue.push_back(InternalOutput::Str0mSendMedia(mid, pkt));
@@ -167,6 +223,24 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
                 EndpointLocalTrackEvent::VoiceActivity(_) => {}
             },
             EndpointEvent::RemoteMediaTrack(_track, _event) => {}
+            EndpointEvent::RemoteMediaTrackRejected(track) => {
+                log::warn!("[TransportWebrtcWhep] unexpected remote track {track} rejected, whep don't have remote tracks");
+            }
+            EndpointEvent::LocalMediaTrackRejected(track) => {
+                // pending attach is answered with error by endpoint, here the track is closed so it is not subscribed again
+                log::warn!("[TransportWebrtcWhep] local track {track} rejected by track limit => close");
+                self.rejected_tracks.push(track);
+                if track == AUDIO_TRACK {
+                    self.subscribed.audio = None;
+                    self.audio_subscribe_waits.clear();
+                } else {
+                    self.subscribed.video = None;
+                    self.video_subscribe_waits.clear();
+                }
+                if self.subscribed.audio.is_none() && self.subscribed.video.is_none() {
+                    self.subscribed.peer = None;
+                }
+            }
             EndpointEvent::BweConfig { current, desired } => {
                 let (current, desired) = self.bwe_state.filter_bwe_config(current, desired);
                 self.queue.push_back(InternalOutput::Str0mBwe(current, desired));
@@ -174,6 +248,7 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
             EndpointEvent::GoAway(_seconds, _reason) => {}
             EndpointEvent::AudioMixer(_) => {}
             EndpointEvent::ChannelMessage(..) => {}
+            EndpointEvent::ChannelError(..) => {}
         }
     }
 
@@ -213,6 +288,10 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
             Str0mEvent::EgressBitrateEstimate(BweKind::Remb(_, bitrate)) | Str0mEvent::EgressBitrateEstimate(BweKind::Twcc(bitrate)) => {
                 let bitrate2 = self.bwe_state.filter_bwe(bitrate.as_u64());
                 log::debug!("[TransportWebrtcWhep] on rewrite bwe {bitrate} => {bitrate2} bps");
+                if self.last_stats.map_or(true, |last| now >= last + EVENT_INTERVAL) {
+                    self.last_stats = Some(now);
+                    self.push_event(WhepEvent::Stats { egress_bitrate: bitrate2 });
+                }
                 self.queue
                     .push_back(InternalOutput::TransportOutput(TransportOutput::Event(TransportEvent::EgressBitrateEstimate(bitrate2))));
             }
@@ -227,6 +306,13 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
         }
     }
 
+    fn on_consent_failed(&mut self, _now: Instant) {
+        if let Some(state) = self.state.on_consent_failed() {
+            log::info!("[TransportWebrtcWhep] consent failed => switched to {state:?}");
+            self.queue.push_back(InternalOutput::TransportOutput(TransportOutput::Event(TransportEvent::State(state))));
+        }
+    }
+
     fn on_shutdown(&mut self, _now: Instant) {
         if !matches!(self.state, State::Disconnected) {
             log::info!("[TransportWebrtcWhep] switched to disconnected with close action");
@@ -238,6 +324,15 @@ impl TransportWebrtcInternal for TransportWebrtcWhep {
         }
     }
 
+    fn on_migrate(&mut self, _now: Instant, _conn_id: String, _ticket: WebrtcMigrateTicket) -> bool {
+        // WHEP dont have signaling channel after connected, so client cannot be asked to move
+        false
+    }
+
+    fn pop_events(&mut self) -> Option<Vec<WhepEvent>> {
+        Some(self.events.drain(..).collect())
+    }
+
     fn pop_output(&mut self, _now: Instant) -> Option<InternalOutput> {
         self.queue.pop_front()
     }
@@ -324,6 +419,8 @@ impl TransportWebrtcWhep {
                                 max_temporal: 2,
                                 min_spatial: None,
                                 min_temporal: None,
+                                bitrate_priority: Default::default(),
+                                smooth_playout: false,
                             },
                         ),
                     ),
@@ -347,6 +444,8 @@ impl TransportWebrtcWhep {
                                 max_temporal: 2,
                                 min_spatial: None,
                                 min_temporal: None,
+                                bitrate_priority: Default::default(),
+                                smooth_playout: false,
                             },
                         ),
                     ),
@@ -485,4 +584,64 @@ mod tests {
         assert_eq!(transport.pop_output(now), None);
         assert!(transport.is_empty());
     }
+
+    #[test]
+    fn rejected_local_track_not_subscribed() {
+        let now = Instant::now();
+        let mut transport = TransportWebrtcWhep::new("room".into(), "peer".into(), None, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        transport.audio_mid = Some(Mid::from("0"));
+        transport.video_mid = Some(Mid::from("1"));
+
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "audio_main".into(), TrackMeta::default_audio()));
+        assert!(matches!(
+            transport.pop_output(now),
+            Some(InternalOutput::TransportOutput(TransportOutput::RpcReq(
+                _,
+                EndpointReq::LocalTrack(AUDIO_TRACK, EndpointLocalTrackReq::Attach(..))
+            )))
+        ));
+
+        // rejected audio track is closed, a new audio source is not subscribed but video still is
+        transport.on_endpoint_event(now, EndpointEvent::LocalMediaTrackRejected(AUDIO_TRACK));
+        assert_eq!(transport.subscribed.audio, None);
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "audio_second".into(), TrackMeta::default_audio()));
+        assert_eq!(transport.pop_output(now), None);
+        transport.on_endpoint_event(now, EndpointEvent::PeerTrackStarted("peer2".into(), "video_main".into(), TrackMeta::default_video()));
+        assert!(matches!(
+            transport.pop_output(now),
+            Some(InternalOutput::TransportOutput(TransportOutput::RpcReq(
+                _,
+                EndpointReq::LocalTrack(VIDEO_TRACK, EndpointLocalTrackReq::Attach(..))
+            )))
+        ));
+    }
+
+    #[test]
+    fn layer_window_report_changed_layers() {
+        use media_server_protocol::media::Vp8Sim;
+
+        let vp8 = |spatial, temporal| MediaMeta::Vp8 {
+            key: false,
+            sim: Some(Vp8Sim {
+                picture_id: None,
+                tl0_pic_idx: None,
+                spatial,
+                temporal,
+                layer_sync: false,
+            }),
+            rotation: None,
+        };
+        let now = Instant::now();
+        let mut window = LayerWindow::default();
+        assert_eq!(window.on_video(now, &vp8(1, 0)), None);
+        assert_eq!(window.on_video(now + Duration::from_millis(500), &vp8(1, 2)), None);
+        assert_eq!(window.on_video(now + Duration::from_millis(1000), &vp8(1, 1)), Some((1, 2)));
+
+        // same layers in next window is not reported again
+        assert_eq!(window.on_video(now + Duration::from_millis(1500), &vp8(1, 2)), None);
+        assert_eq!(window.on_video(now + Duration::from_millis(2000), &vp8(1, 0)), None);
+
+        assert_eq!(window.on_video(now + Duration::from_millis(2500), &vp8(0, 1)), None);
+        assert_eq!(window.on_video(now + Duration::from_millis(3000), &vp8(0, 0)), Some((0, 1)));
+    }
 }
@@ -9,19 +9,23 @@ use media_server_core::{
     transport::{RemoteTrackEvent, RemoteTrackId, TransportError, TransportEvent, TransportOutput, TransportState},
 };
 use media_server_protocol::{
-    endpoint::{BitrateControlMode, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackMeta, TrackPriority},
+    endpoint::{BitrateControlMode, PeerId, PeerMeta, RoomId, RoomInfoPublish, RoomInfoSubscribe, TrackEncoding, TrackMeta, TrackPriority},
     media::{MediaKind, MediaScaling},
+    transport::{webrtc::WebrtcMigrateTicket, whep::WhepEvent},
 };
 use sans_io_runtime::return_if_none;
 use str0m::{
     format::CodecConfig,
-    media::{Direction, KeyframeRequestKind, MediaAdded, Mid},
+    media::{Direction, KeyframeRequestKind, MediaAdded, Mid, Rid},
     Event as Str0mEvent, IceConnectionState,
 };
 
 use crate::media::RemoteMediaConvert;
 
-use super::{InternalOutput, TransportWebrtcInternal};
+use super::{
+    state::{State, TransportWebrtcError},
+    InternalOutput, TransportWebrtcInternal,
+};
 
 const TIMEOUT_SEC: u64 = 10;
 const AUDIO_TRACK: RemoteTrackId = RemoteTrackId::build(0);
@@ -29,27 +33,6 @@ const AUDIO_NAME: &str = "audio_main";
 const VIDEO_TRACK: RemoteTrackId = RemoteTrackId::build(1);
 const VIDEO_NAME: &str = "video_main";
 
-#[derive(Debug)]
-enum State {
-    New,
-    Connecting { at: Instant },
-    ConnectError(TransportWebrtcError),
-    Connected,
-    Reconnecting { at: Instant },
-    Disconnected,
-}
-
-impl State {
-    fn is_shutdown(&self) -> bool {
-        matches!(self, State::ConnectError(_) | State::Disconnected)
-    }
-}
-
-#[derive(Debug)]
-enum TransportWebrtcError {
-    Timeout,
-}
-
 pub struct TransportWebrtcWhip {
     remote: IpAddr,
     room: RoomId,
@@ -59,12 +42,14 @@ pub struct TransportWebrtcWhip {
     audio_mid: Option<Mid>,
     ///mid and simulcast flag
     video_mid: Option<(Mid, bool)>,
+    /// simulcast encodings declared in offer, advertised with video track meta
+    video_encodings: Vec<TrackEncoding>,
     queue: VecDeque<InternalOutput>,
     media_convert: RemoteMediaConvert,
 }
 
 impl TransportWebrtcWhip {
-    pub fn new(room: RoomId, peer: PeerId, extra_data: Option<String>, remote: IpAddr) -> Self {
+    pub fn new(room: RoomId, peer: PeerId, extra_data: Option<String>, remote: IpAddr, video_encodings: Vec<TrackEncoding>) -> Self {
         Self {
             remote,
             room,
@@ -73,6 +58,7 @@ impl TransportWebrtcWhip {
             state: State::New,
             audio_mid: None,
             video_mid: None,
+            video_encodings,
             queue: VecDeque::new(),
             media_convert: RemoteMediaConvert::default(),
         }
@@ -84,6 +70,10 @@ impl TransportWebrtcInternal for TransportWebrtcWhip {
         self.media_convert.set_config(cfg);
     }
 
+    fn on_simulcast_rids(&mut self, rids: Vec<(Mid, Vec<Rid>)>) {
+        self.media_convert.set_simulcast_rids(rids);
+    }
+
     fn is_empty(&self) -> bool {
         self.state.is_shutdown() && self.queue.is_empty()
     }
@@ -125,17 +115,23 @@ impl TransportWebrtcInternal for TransportWebrtcWhip {
 
     fn on_endpoint_event(&mut self, _now: Instant, event: EndpointEvent) {
         match event {
+            EndpointEvent::JoinRejected(..) => {}
+            EndpointEvent::JoinPending(_) => {}
             EndpointEvent::PeerJoined(_, _) => {}
             EndpointEvent::PeerLeaved(_, _) => {}
             EndpointEvent::PeerTrackStarted(_, _, _) => {}
             EndpointEvent::PeerTrackStopped(_, _, _) => {}
+            EndpointEvent::PeerTrackMuted(_, _, _) => {}
+            EndpointEvent::RoomPaused(_) => {}
+            EndpointEvent::RoomConfigChanged(_) => {}
             EndpointEvent::RemoteMediaTrack(_, event) => match event {
                 media_server_core::endpoint::EndpointRemoteTrackEvent::RequestKeyFrame => {
                     let mid = return_if_none!(self.video_mid).0;
                     log::info!("[TransportWebrtcWhip] request key-frame");
                     self.queue.push_back(InternalOutput::Str0mKeyframe(mid, KeyframeRequestKind::Fir));
                 }
-                media_server_core::endpoint::EndpointRemoteTrackEvent::LimitBitrateBps { min, max } => {
+                // whip has no signaling channel after the answer, so layer targets can't reach the publisher
+                media_server_core::endpoint::EndpointRemoteTrackEvent::LimitBitrateBps { min, max, .. } => {
                     let (mid, sim) = return_if_none!(self.video_mid);
                     let bitrate = if sim {
                         max
@@ -147,10 +143,23 @@ impl TransportWebrtcInternal for TransportWebrtcWhip {
                 }
             },
             EndpointEvent::LocalMediaTrack(_, _) => {}
+            EndpointEvent::RemoteMediaTrackRejected(track) => {
+                // whip can't renegotiate, so the track is closed by dropping its media here
+                log::warn!("[TransportWebrtcWhip] remote track {track} rejected by track limit => close");
+                if track == AUDIO_TRACK {
+                    self.audio_mid = None;
+                } else {
+                    self.video_mid = None;
+                }
+            }
+            EndpointEvent::LocalMediaTrackRejected(track) => {
+                log::warn!("[TransportWebrtcWhip] unexpected local track {track} rejected, whip don't have local tracks");
+            }
             EndpointEvent::BweConfig { .. } => {}
             EndpointEvent::GoAway(_, _) => {}
             EndpointEvent::AudioMixer(_) => {}
             EndpointEvent::ChannelMessage(..) => {}
+            EndpointEvent::ChannelError(..) => {}
         }
     }
 
@@ -179,11 +188,14 @@ impl TransportWebrtcInternal for TransportWebrtcWhip {
             Str0mEvent::IceConnectionStateChange(state) => self.on_str0m_state(now, state),
             Str0mEvent::MediaAdded(media) => self.on_str0m_media_added(now, media),
             Str0mEvent::RtpPacket(pkt) => {
-                let track = if *pkt.header.payload_type == 111 {
-                    AUDIO_TRACK
+                let (track, opened) = if *pkt.header.payload_type == 111 {
+                    (AUDIO_TRACK, self.audio_mid.is_some())
                 } else {
-                    VIDEO_TRACK
+                    (VIDEO_TRACK, self.video_mid.is_some())
                 };
+                if !opened {
+                    return;
+                }
                 let pkt = return_if_none!(self.media_convert.convert(pkt));
                 log::trace!(
                     "[TransportWebrtcWhip] incoming pkt codec {:?}, seq {} ts {}, marker {}, payload {}",
@@ -209,6 +221,13 @@ impl TransportWebrtcInternal for TransportWebrtcWhip {
         }
     }
 
+    fn on_consent_failed(&mut self, _now: Instant) {
+        if let Some(state) = self.state.on_consent_failed() {
+            log::info!("[TransportWebrtcWhip] consent failed => switched to {state:?}");
+            self.queue.push_back(InternalOutput::TransportOutput(TransportOutput::Event(TransportEvent::State(state))));
+        }
+    }
+
     fn on_shutdown(&mut self, _now: Instant) {
         if !matches!(self.state, State::Disconnected) {
             log::info!("[TransportWebrtcWhip] switched to disconnected with close action");
@@ -220,6 +239,15 @@ impl TransportWebrtcInternal for TransportWebrtcWhip {
         }
     }
 
+    fn on_migrate(&mut self, _now: Instant, _conn_id: String, _ticket: WebrtcMigrateTicket) -> bool {
+        // WHIP dont have signaling channel after connected, so client cannot be asked to move
+        false
+    }
+
+    fn pop_events(&mut self) -> Option<Vec<WhepEvent>> {
+        None
+    }
+
     fn pop_output(&mut self, _now: Instant) -> Option<InternalOutput> {
         self.queue.pop_front()
     }
@@ -273,6 +301,8 @@ impl TransportWebrtcWhip {
                         scaling: MediaScaling::None,
                         control: BitrateControlMode::MaxBitrate,
                         metadata: None,
+                        muted: false,
+                        encodings: vec![],
                     },
                     priority: TrackPriority::from(1),
                 },
@@ -296,6 +326,12 @@ impl TransportWebrtcWhip {
                         },
                         control: BitrateControlMode::MaxBitrate,
                         metadata: None,
+                        muted: false,
+                        encodings: if media.simulcast.is_some() {
+                            self.video_encodings.clone()
+                        } else {
+                            vec![]
+                        },
                     },
                     priority: TrackPriority::from(1),
                 },
@@ -334,7 +370,7 @@ mod tests {
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let now = Instant::now();
 
-        let mut transport = TransportWebrtcWhip::new("room".into(), "peer".into(), None, ip);
+        let mut transport = TransportWebrtcWhip::new("room".into(), "peer".into(), None, ip, vec![]);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -361,7 +397,7 @@ mod tests {
         let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
         let now = Instant::now();
 
-        let mut transport = TransportWebrtcWhip::new("room".into(), "peer".into(), None, ip);
+        let mut transport = TransportWebrtcWhip::new("room".into(), "peer".into(), None, ip, vec![]);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -387,7 +423,7 @@ mod tests {
         let room: RoomId = "room".into();
         let peer: PeerId = "peer".into();
 
-        let mut transport = TransportWebrtcWhip::new(room.clone(), peer.clone(), None, ip);
+        let mut transport = TransportWebrtcWhip::new(room.clone(), peer.clone(), None, ip, vec![]);
         assert_eq!(transport.pop_output(now), None);
 
         transport.on_tick(now);
@@ -1,22 +1,24 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use media_server_core::{
-    cluster::{ClusterEndpointControl, ClusterEndpointEvent, ClusterRoomHash},
-    endpoint::{Endpoint, EndpointCfg, EndpointInput, EndpointOutput},
+    cluster::{ClusterEndpointControl, ClusterEndpointEvent, ClusterRoomHash, RoomVideoCodecs},
+    endpoint::{Endpoint, EndpointCfg, EndpointInput, EndpointOutput, MultiRoomPolicy, OpusConfig, PlayoutConfig, RelayGraceConfig, SessionMaxDurationConfig, TrackLimits},
 };
 use media_server_protocol::{
     cluster::gen_cluster_session_id,
+    endpoint::{ClusterConnId, RoomId},
     multi_tenancy::{AppContext, AppId},
-    protobuf::cluster_connector::peer_event,
+    protobuf::cluster_connector::peer_event::{self, disconnected::Reason},
     record::SessionRecordEvent,
-    transport::{RpcError, RpcResult},
+    transport::{webrtc::WebrtcMigrateTicket, RpcError, RpcResult},
 };
 use media_server_secure::MediaEdgeSecure;
+use media_server_utils::{node_egress_budget, Count, LoopMetrics, LoopMetricsRecorder, RtpIngestPolicy, StartingGuard};
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
     group_owner_type, return_if_none, return_if_some, TaskGroup, TaskGroupOutput, TaskSwitcherChild,
@@ -24,16 +26,42 @@ use sans_io_runtime::{
 use str0m::change::DtlsCert;
 
 use crate::{
+    codec_policy::{offer_video_codecs, select_video_codec},
+    ice_creds::IceCredsConfig,
+    ice_tcp::{IceTcpPacket, TCP_SLOT_BASE},
+    reaper::{ReapedSession, ReaperConfig, StuckState},
+    remote_ice::DEFAULT_MAX_REMOTE_CANDIDATES,
+    sdp_bandwidth::egress_bitrate_cap,
     shared_port::SharedUdpPort,
-    transport::{ExtIn, ExtOut, TransportWebrtc, VariantParams},
-    WebrtcError,
+    transport::{validate_offer, ConsentConfig, ExtIn, ExtOut, OfferValidation, TransportWebrtc, TransportWebrtcConfig, VariantParams},
+    BundlePolicy, DtlsPolicy, RtcpFbPolicy, RtpExtension, SdpSession, VideoCodec, WebrtcError,
 };
 
 group_owner_type!(WebrtcSession);
 
+/// Which app and room a session belongs to, used for bulk closing
+struct SessionSlot {
+    app: AppId,
+    session_id: u64,
+    room: Option<ClusterRoomHash>,
+    /// Time of the first peer event of the session, used for reporting duration of reaped sessions
+    started: Option<Instant>,
+    closing: bool,
+    /// True until connected or failed, used for limiting concurrent handshakes
+    connecting: bool,
+    /// True after the session is asked to move to other node
+    migrated: bool,
+    /// Negotiated video codec when video codec policy is enabled, it is acquired in the pinned codecs of the room
+    video_codec: Option<VideoCodec>,
+    /// State which can be stuck and since when, used by the reaper
+    stuck: Option<(StuckState, Instant)>,
+}
+
 #[allow(clippy::large_enum_variant)]
 pub enum GroupInput {
     Net(BackendIncoming),
+    /// Packet from an ICE-TCP connection, after it is unframed
+    IceTcp(IceTcpPacket),
     Cluster(WebrtcSession, ClusterEndpointEvent),
     Ext(WebrtcSession, ExtIn),
 }
@@ -41,6 +69,8 @@ pub enum GroupInput {
 #[derive(Debug)]
 pub enum GroupOutput {
     Net(BackendOutgoing),
+    /// Packet to an ICE-TCP connection, it must be framed before writing
+    IceTcp(IceTcpPacket),
     Cluster(WebrtcSession, ClusterRoomHash, ClusterEndpointControl),
     PeerEvent(WebrtcSession, AppId, u64, Instant, peer_event::Event),
     RecordEvent(WebrtcSession, u64, Instant, SessionRecordEvent),
@@ -49,104 +79,601 @@ pub enum GroupOutput {
     Continue,
 }
 
+/// Settings of a webrtc worker, they are applied to all sessions of the worker
+pub struct WebrtcWorkerConfig {
+    /// Id of this node, migrate tickets are issued from and verified against it
+    pub node_id: u32,
+    /// Udp addresses which are bound by the runtime
+    pub addrs: Vec<SocketAddr>,
+    /// Extra addresses which are only advertised as candidates, for example public ips of a NAT
+    pub addrs_alt: Vec<SocketAddr>,
+    /// Addresses of ICE-TCP listeners which are owned by caller, they are advertised as passive tcp candidates
+    pub ice_tcp_addrs: Vec<SocketAddr>,
+    pub ice_lite: bool,
+    /// Length of generated ICE ufrag and pwd, unset lengths keep str0m defaults
+    pub ice_creds: IceCredsConfig,
+    pub consent: ConsentConfig,
+    /// Preferred ips, candidates with these ips are advertised with higher priority
+    pub candidate_order: Vec<IpAddr>,
+    /// Allowed H264 profile-level-id in preference order, empty for all forwardable profiles
+    pub h264_profiles: Vec<u32>,
+    /// Video codec preference, only one codec is answered and it is kept same for sessions of a room
+    pub video_codecs: Vec<VideoCodec>,
+    /// Pinned video codec of rooms, shared with other workers and the media cluster so a room uses the same codec in all of them
+    pub room_video_codecs: Arc<RoomVideoCodecs>,
+    /// Rtp header extensions which are never answered, bwe is disabled without transport-cc
+    pub disabled_extensions: Vec<RtpExtension>,
+    /// Min DTLS version and fingerprint policy, handshakes and offers which violate it are rejected
+    pub dtls_policy: DtlsPolicy,
+    /// Drops malformed or oversized RTP from clients, sessions which send too many of them can be closed
+    pub rtp_ingest: RtpIngestPolicy,
+    /// Whether NACK and key-frame requests are sent to clients which don't offer them
+    pub rtcp_fb: RtcpFbPolicy,
+    /// Overrides origin username, session name and tool of answers, unset fields keep str0m defaults
+    pub sdp_session: SdpSession,
+    /// How offers with m-lines outside the BUNDLE group are answered
+    pub bundle_policy: BundlePolicy,
+    /// Buffer of subscribed media while relay path is changing
+    pub relay_grace: RelayGraceConfig,
+    /// Target delay of subscribed media per kind, for subscribers which choose smooth playout
+    pub playout: PlayoutConfig,
+    /// Number of published and subscribed tracks of each session, excess tracks are rejected
+    pub track_limits: TrackLimits,
+    /// Whether a session which is in a room can join other room, by leaving the current one
+    pub multi_room: MultiRoomPolicy,
+    /// Opus quality per app, which is signaled to clients in the answer fmtp
+    pub opus: OpusConfig,
+    /// Max duration of sessions per app, clients are warned before their session is closed
+    pub max_duration: SessionMaxDurationConfig,
+    /// Max number of candidates in answer for bounding SDP size, highest priority ones are kept. None is unlimited
+    pub max_candidates: Option<usize>,
+    /// Max number of m-lines of offers, offers over it are rejected before negotiation
+    pub max_media_sections: usize,
+    /// Max number of remote ICE candidates of each session, submissions over it are rejected
+    pub max_remote_candidates: usize,
+    /// Max number of sessions which are handshaking at the same time, new sessions over it are rejected. None is unlimited
+    pub max_connecting: Option<usize>,
+    /// Thresholds of stuck sessions which are force removed, a safety net beside transport timeouts
+    pub reaper: ReaperConfig,
+    /// Enables timing metrics for the worker and all of its endpoints
+    pub loop_metrics: bool,
+}
+
+impl Default for WebrtcWorkerConfig {
+    fn default() -> Self {
+        Self {
+            node_id: 0,
+            addrs: vec![],
+            addrs_alt: vec![],
+            ice_tcp_addrs: vec![],
+            ice_lite: false,
+            ice_creds: IceCredsConfig::default(),
+            consent: ConsentConfig::default(),
+            candidate_order: vec![],
+            h264_profiles: vec![],
+            video_codecs: vec![],
+            room_video_codecs: Default::default(),
+            disabled_extensions: vec![],
+            dtls_policy: DtlsPolicy::default(),
+            rtp_ingest: RtpIngestPolicy::default(),
+            rtcp_fb: RtcpFbPolicy::default(),
+            sdp_session: SdpSession::default(),
+            bundle_policy: BundlePolicy::default(),
+            relay_grace: RelayGraceConfig::default(),
+            playout: PlayoutConfig::default(),
+            track_limits: TrackLimits::default(),
+            multi_room: MultiRoomPolicy::default(),
+            opus: OpusConfig::default(),
+            max_duration: SessionMaxDurationConfig::default(),
+            max_candidates: None,
+            max_media_sections: 64,
+            max_remote_candidates: DEFAULT_MAX_REMOTE_CANDIDATES,
+            max_connecting: None,
+            reaper: ReaperConfig::default(),
+            loop_metrics: false,
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub struct MediaWorkerWebrtc<ES: 'static + MediaEdgeSecure> {
-    ice_lite: bool,
-    addrs_alt: Vec<SocketAddr>,
+    node_id: u32,
+    /// Negotiation settings of sessions, its addrs are filled when udp sockets are bound. ICE-TCP listeners get virtual slots
+    transport: TransportWebrtcConfig,
+    video_codecs: Vec<VideoCodec>,
+    room_video_codecs: Arc<RoomVideoCodecs>,
+    relay_grace: RelayGraceConfig,
+    playout: PlayoutConfig,
+    track_limits: TrackLimits,
+    multi_room: MultiRoomPolicy,
+    max_duration: SessionMaxDurationConfig,
+    max_connecting: Option<usize>,
+    reaper: ReaperConfig,
+    next_reap: Option<Instant>,
     shared_port: SharedUdpPort<usize>,
-    dtls_cert: DtlsCert,
     endpoints: TaskGroup<EndpointInput<ExtIn>, EndpointOutput<ExtOut>, Endpoint<TransportWebrtc<ES>, ExtIn, ExtOut>, 16>,
-    addrs: Vec<(SocketAddr, usize)>,
+    sessions: HashMap<usize, SessionSlot>,
+    /// Number of queued udp listens which are not answered yet, the worker is not ready for sessions until it is zero
+    pending_binds: usize,
+    /// Udp listens which are answered with error, the worker is never ready when any of them failed
+    failed_binds: Vec<SocketAddr>,
+    starting: StartingGuard,
     queue: VecDeque<GroupOutput>,
     secure: Arc<ES>,
+    metrics: Option<LoopMetricsRecorder>,
+    draining_until: Option<Instant>,
     shutdown: bool,
 }
 
 impl<ES: MediaEdgeSecure> MediaWorkerWebrtc<ES> {
-    pub fn new(addrs: Vec<SocketAddr>, addrs_alt: Vec<SocketAddr>, ice_lite: bool, secure: Arc<ES>) -> Self {
-        Self {
-            ice_lite,
+    /// The DTLS cert is generated here, but sockets are bound by the runtime, so the worker rejects sessions as not ready
+    /// until a bind result is received for each of `cfg.addrs`.
+    pub fn new(cfg: WebrtcWorkerConfig, secure: Arc<ES>) -> Self {
+        let WebrtcWorkerConfig {
+            node_id,
+            addrs,
             addrs_alt,
+            ice_tcp_addrs,
+            ice_lite,
+            ice_creds,
+            consent,
+            candidate_order,
+            h264_profiles,
+            video_codecs,
+            room_video_codecs,
+            disabled_extensions,
+            dtls_policy,
+            rtp_ingest,
+            rtcp_fb,
+            sdp_session,
+            bundle_policy,
+            relay_grace,
+            playout,
+            track_limits,
+            multi_room,
+            opus,
+            max_duration,
+            max_candidates,
+            max_media_sections,
+            max_remote_candidates,
+            max_connecting,
+            reaper,
+            loop_metrics,
+        } = cfg;
+        let mut worker = Self {
+            node_id,
+            transport: TransportWebrtcConfig {
+                dtls_cert: DtlsCert::new_openssl(),
+                dtls_policy,
+                rtp_ingest,
+                addrs: vec![],
+                addrs_alt,
+                tcp_addrs: ice_tcp_addrs.into_iter().enumerate().map(|(i, addr)| (addr, TCP_SLOT_BASE + i)).collect(),
+                ice_lite,
+                ice_creds,
+                consent,
+                candidate_order,
+                h264_profiles,
+                disabled_extensions,
+                rtcp_fb,
+                sdp_session,
+                bundle_policy,
+                opus,
+                max_candidates,
+                max_media_sections,
+                max_remote_candidates,
+            },
+            video_codecs,
+            room_video_codecs,
+            relay_grace,
+            playout,
+            track_limits,
+            multi_room,
+            max_duration,
+            max_connecting,
+            reaper,
+            next_reap: None,
             shared_port: SharedUdpPort::default(),
-            dtls_cert: DtlsCert::new_openssl(),
             endpoints: TaskGroup::default(),
-            addrs: vec![],
+            sessions: HashMap::new(),
+            pending_binds: addrs.len(),
+            failed_binds: vec![],
+            starting: StartingGuard::new(),
             queue: VecDeque::from(addrs.iter().map(|addr| GroupOutput::Net(BackendOutgoing::UdpListen { addr: *addr, reuse: false })).collect::<Vec<_>>()),
             secure,
+            metrics: loop_metrics.then(|| LoopMetricsRecorder::new("webrtc_worker")),
+            draining_until: None,
             shutdown: false,
-        }
+        };
+        worker.check_ready();
+        worker
     }
 
     pub fn spawn(&mut self, app: AppContext, remote: IpAddr, session_id: u64, variant: VariantParams<ES>, offer: &str) -> RpcResult<(bool, String, usize)> {
-        let cfg = match &variant {
+        let _span = tracing::info_span!("webrtc_worker", session_id, app = %app.app, remote = %remote).entered();
+        if self.draining_until.is_some() || self.shutdown {
+            tracing::warn!("[TransportWebrtc] worker is shutting down => reject");
+            return Err(RpcError::new2(WebrtcError::WorkerShuttingDown));
+        }
+        if !self.starting.is_ready() {
+            if self.failed_binds.is_empty() {
+                tracing::warn!("[TransportWebrtc] worker is binding {} udp sockets => reject as not ready", self.pending_binds);
+            } else {
+                tracing::warn!("[TransportWebrtc] worker failed to bind udp sockets {:?} => reject as not ready", self.failed_binds);
+            }
+            return Err(RpcError::new2(WebrtcError::WorkerNotReady));
+        }
+        if let Some(max_connecting) = self.max_connecting {
+            let connecting = self.connecting();
+            if connecting >= max_connecting {
+                tracing::warn!(connecting, max_connecting, "[TransportWebrtc] too many connecting sessions => reject");
+                return Err(RpcError::new2(WebrtcError::ConnectOverloaded));
+            }
+        }
+        let migrate = match &variant {
+            VariantParams::Webrtc(_, req, ..) => match req.migrate_token.as_deref() {
+                Some(token) => Some(self.verify_migrate_ticket(session_id, token)?),
+                None => None,
+            },
+            _ => None,
+        };
+        let max_duration = self.max_duration.max_duration(&app.app);
+        let mut cfg = match &variant {
             VariantParams::Whip(_, _, _, record) => EndpointCfg {
                 app: app.clone(),
                 max_ingress_bitrate: 2_500_000,
                 max_egress_bitrate: 2_500_000,
                 record: *record,
+                metrics: self.metrics.is_some(),
+                relay_grace: self.relay_grace,
+                playout: self.playout,
+                track_limits: self.track_limits,
+                multi_room: self.multi_room,
+                egress_budget: node_egress_budget(),
+                max_duration,
+                max_duration_warning: self.max_duration.warning,
             },
             VariantParams::Whep(_, _, _) => EndpointCfg {
                 app: app.clone(),
                 max_ingress_bitrate: 2_500_000,
                 max_egress_bitrate: 2_500_000,
                 record: false,
+                metrics: self.metrics.is_some(),
+                relay_grace: self.relay_grace,
+                playout: self.playout,
+                track_limits: self.track_limits,
+                multi_room: self.multi_room,
+                egress_budget: node_egress_budget(),
+                max_duration,
+                max_duration_warning: self.max_duration.warning,
             },
             VariantParams::Webrtc(_, _, _, record, _) => EndpointCfg {
                 app: app.clone(),
                 max_ingress_bitrate: 2_500_000,
                 max_egress_bitrate: 2_500_000,
                 record: *record,
+                metrics: self.metrics.is_some(),
+                relay_grace: self.relay_grace,
+                playout: self.playout,
+                track_limits: self.track_limits,
+                multi_room: self.multi_room,
+                egress_budget: node_egress_budget(),
+                max_duration,
+                max_duration_warning: self.max_duration.warning,
             },
         };
-        let (tran, ufrag, sdp) = TransportWebrtc::new(app, remote, variant, offer, self.dtls_cert.clone(), &self.addrs, &self.addrs_alt, self.ice_lite)?;
-        log::info!("[TransportWebrtc] create endpoint with config {:?}", cfg);
+        let room = match &variant {
+            VariantParams::Whip(room, ..) | VariantParams::Whep(room, ..) => Some(ClusterRoomHash::generate(&app, room)),
+            VariantParams::Webrtc(_, req, ..) => match &migrate {
+                Some(ticket) => ticket.join(),
+                None => req.join.clone(),
+            }
+            .map(|j| ClusterRoomHash::generate(&app, &RoomId::from(j.room))),
+        };
+        let egress_cap = egress_bitrate_cap(offer, cfg.max_egress_bitrate);
+        if egress_cap < cfg.max_egress_bitrate {
+            tracing::info!(egress_cap, "[TransportWebrtc] offer bandwidth is lower than server max => limit egress");
+            cfg.max_egress_bitrate = egress_cap;
+        }
+        let offered_codecs = offer_video_codecs(offer);
+        let room_codec = room.and_then(|room| self.room_video_codecs.get(room)).and_then(|codec| codec.parse::<VideoCodec>().ok());
+        let video_codec = select_video_codec(&offered_codecs, &self.video_codecs, room_codec);
+        if video_codec.is_some() && room_codec.is_some() && video_codec != room_codec {
+            tracing::warn!(?room_codec, ?video_codec, "[TransportWebrtc] offer dont support room video codec => fallback");
+        }
+        let slot = SessionSlot {
+            app: app.app.clone(),
+            session_id,
+            room,
+            started: None,
+            closing: false,
+            connecting: true,
+            migrated: false,
+            // sessions without video dont pin the room codec
+            video_codec: video_codec.filter(|_| !offered_codecs.is_empty()),
+            stuck: None,
+        };
+        let (tran, ufrag, sdp) = TransportWebrtc::new(&self.transport, app, remote, variant, migrate, offer, video_codec)?;
+        tracing::info!(cfg = ?cfg, "[TransportWebrtc] create endpoint");
         let endpoint = Endpoint::new(session_id, cfg, tran);
         let index = self.endpoints.add_task(endpoint);
         self.shared_port.add_ufrag(ufrag, index);
-        Ok((self.ice_lite, sdp, index))
+        if let (Some(room), Some(codec)) = (slot.room, slot.video_codec) {
+            self.room_video_codecs.acquire(room, &codec.to_string());
+        }
+        self.sessions.insert(index, slot);
+        tracing::info!(index, "[TransportWebrtc] endpoint created");
+        Ok((self.transport.ice_lite, sdp, index))
+    }
+
+    /// Migrated sessions are spawned with the ticket which is signed by the old node, it must be issued for this node
+    /// and the session id which is carried in the migrate conn id, so a ticket can't be reused for other sessions.
+    /// Its nonce is already consumed by the node rpc handler, so it is redeemed once whichever worker is selected
+    fn verify_migrate_ticket(&self, session_id: u64, token: &str) -> RpcResult<WebrtcMigrateTicket> {
+        let ticket = self.secure.decode_migrate_ticket(token).ok_or(RpcError::new2(WebrtcError::RpcMigrateTicketInvalid))?;
+        if ticket.session_id != session_id || ticket.dest_node != self.node_id {
+            tracing::warn!(
+                ticket_session = ticket.session_id,
+                ticket_dest = ticket.dest_node,
+                "[TransportWebrtc] migrate ticket is not issued for this session => reject"
+            );
+            return Err(RpcError::new2(WebrtcError::RpcMigrateTicketInvalid));
+        }
+        tracing::info!(from_node = ticket.from_node, "[TransportWebrtc] session is migrated from other node");
+        Ok(ticket)
+    }
+
+    fn release_video_codec(&self, slot: &SessionSlot) {
+        if let (Some(room), Some(_)) = (slot.room, slot.video_codec) {
+            self.room_video_codecs.release(room);
+        }
+    }
+
+    /// Dry-run an offer for checking client compatibility, no socket or endpoint is created
+    pub fn validate_offer(&self, offer: &str) -> RpcResult<OfferValidation> {
+        let video_codec = select_video_codec(&offer_video_codecs(offer), &self.video_codecs, None);
+        validate_offer(offer, &self.transport, video_codec)
+    }
+
+    /// Close all sessions of the app, or only sessions inside a room if it is provided. With `migrated_session` only the session
+    /// which is migrated to other node is closed, a session which is migrated to this node has the same id and it is kept.
+    /// Sessions which are already closing are skipped, so calling it multiple times is safe.
+    /// Return number of sessions which are closed by this call
+    pub fn close_sessions(&mut self, now: Instant, app: &AppId, room: Option<ClusterRoomHash>, migrated_session: Option<u64>) -> usize {
+        let mut indexes = vec![];
+        for (index, slot) in self.sessions.iter_mut() {
+            let other_session = migrated_session.is_some_and(|session| slot.session_id != session || !slot.migrated);
+            if slot.closing || slot.app != *app || (room.is_some() && slot.room != room) || other_session {
+                continue;
+            }
+            slot.closing = true;
+            indexes.push(*index);
+        }
+        for index in indexes.iter() {
+            log::info!("[MediaWorkerWebrtc] bulk close session {index}");
+            self.endpoints.on_event(now, *index, EndpointInput::Ext(ExtIn::Close));
+        }
+        indexes.len()
+    }
+
+    /// Ask session to move to `dest` node. Client is given a conn id which carries the session id and a ticket which
+    /// is signed for the destination node, so the endpoint created by its restart-ice there keeps the same session id,
+    /// room and subscriptions. Dest node must be selected by gateway before reaching here.
+    pub fn migrate_session(&mut self, now: Instant, index: usize, req_id: u64, dest: Option<u32>) {
+        let dest = match dest {
+            Some(dest) => dest,
+            None => {
+                self.queue
+                    .push_back(GroupOutput::Ext(index.into(), ExtOut::Migrate(req_id, Err(RpcError::new2(WebrtcError::RpcInvalidRequest)))));
+                return;
+            }
+        };
+        match self.sessions.get_mut(&index) {
+            Some(slot) if !slot.closing => {
+                slot.migrated = true;
+                let conn = ClusterConnId::migrate(dest, slot.session_id);
+                let ticket = WebrtcMigrateTicket::new(slot.session_id, self.node_id, dest);
+                log::info!("[MediaWorkerWebrtc] migrate session {} in {index} to node {dest}", slot.session_id);
+                self.endpoints.on_event(now, index, EndpointInput::Ext(ExtIn::Migrate(req_id, conn, ticket)));
+            }
+            _ => {
+                self.queue
+                    .push_back(GroupOutput::Ext(index.into(), ExtOut::Migrate(req_id, Err(RpcError::new2(WebrtcError::RpcEndpointNotFound)))));
+            }
+        }
     }
 
     fn process_output(&mut self, index: usize, out: EndpointOutput<ExtOut>) -> GroupOutput {
         match out {
-            EndpointOutput::Net(net) => GroupOutput::Net(net),
-            EndpointOutput::Cluster(room, control) => GroupOutput::Cluster(WebrtcSession(index), room, control),
-            EndpointOutput::PeerEvent(app, session_id, ts, event) => GroupOutput::PeerEvent(WebrtcSession(index), app, session_id, ts, event),
+            EndpointOutput::Net(net) => self.process_net_output(net),
+            EndpointOutput::Cluster(room, control) => {
+                if let Some(slot) = self.sessions.get_mut(&index) {
+                    slot.room = if matches!(control, ClusterEndpointControl::Leave) {
+                        None
+                    } else {
+                        Some(room)
+                    };
+                }
+                GroupOutput::Cluster(WebrtcSession(index), room, control)
+            }
+            EndpointOutput::PeerEvent(app, session_id, ts, event) => {
+                if let Some(slot) = self.sessions.get_mut(&index) {
+                    slot.started.get_or_insert(ts);
+                    match &event {
+                        peer_event::Event::Connecting(_) => slot.stuck = Some((StuckState::Connecting, ts)),
+                        peer_event::Event::Reconnect(_) => slot.stuck = Some((StuckState::Reconnecting, ts)),
+                        peer_event::Event::Connected(_) | peer_event::Event::ConnectError(_) => {
+                            slot.connecting = false;
+                            slot.stuck = None;
+                        }
+                        peer_event::Event::Reconnected(_) => slot.stuck = None,
+                        _ => {}
+                    }
+                }
+                GroupOutput::PeerEvent(WebrtcSession(index), app, session_id, ts, event)
+            }
             EndpointOutput::RecordEvent(session_id, ts, event) => GroupOutput::RecordEvent(WebrtcSession(index), session_id, ts, event),
             EndpointOutput::OnResourceEmpty => {
                 log::info!("[TransportWebrtc] destroy endpoint {index}");
                 self.endpoints.remove_task(index);
                 self.shared_port.remove_task(index);
+                if let Some(slot) = self.sessions.remove(&index) {
+                    self.release_video_codec(&slot);
+                }
                 GroupOutput::Continue
             }
             EndpointOutput::Ext(ext) => GroupOutput::Ext(WebrtcSession(index), ext),
             EndpointOutput::Continue => GroupOutput::Continue,
         }
     }
+
+    /// Packets from virtual slots are sent to their ICE-TCP connection
+    fn process_net_output(&self, net: BackendOutgoing) -> GroupOutput {
+        if let BackendOutgoing::UdpPacket { slot, to, data } = &net {
+            if let Some((local, _)) = self.transport.tcp_addrs.iter().find(|(_, tcp_slot)| tcp_slot == slot) {
+                return GroupOutput::IceTcp(IceTcpPacket {
+                    local: *local,
+                    remote: *to,
+                    data: data.to_vec(),
+                });
+            }
+        }
+        GroupOutput::Net(net)
+    }
 }
 
 impl<ES: MediaEdgeSecure> MediaWorkerWebrtc<ES> {
+    /// True when all udp sockets are bound, sessions are rejected before it. A worker with a failed bind is never ready
+    pub fn is_ready(&self) -> bool {
+        self.starting.is_ready()
+    }
+
+    fn check_ready(&mut self) {
+        if self.pending_binds > 0 || self.starting.is_ready() {
+            return;
+        }
+        if !self.failed_binds.is_empty() {
+            log::error!("[MediaWorkerWebrtc] failed to bind udp sockets {:?} => stay not ready", self.failed_binds);
+        } else {
+            log::info!("[MediaWorkerWebrtc] all udp sockets are bound => ready");
+            self.starting.set_ready();
+        }
+    }
+
     pub fn tasks(&self) -> usize {
         self.endpoints.tasks()
     }
 
+    /// Number of sessions which are not connected or failed yet
+    pub fn connecting(&self) -> usize {
+        self.sessions.values().filter(|slot| slot.connecting).count()
+    }
+
+    /// Metrics which are not reported yet, only available when loop metrics is enabled
+    pub fn metrics(&self) -> Option<&LoopMetrics> {
+        self.metrics.as_ref().map(|m| m.metrics())
+    }
+
+    /// Drain up to `max` outputs into `buf` in one call, same order as repeated [`TaskSwitcherChild::pop_output`].
+    /// Return number of appended outputs, less than `max` means the worker is drained.
+    pub fn pop_outputs_into(&mut self, now: Instant, buf: &mut Vec<GroupOutput>, max: usize) -> usize {
+        let start = buf.len();
+        while buf.len() - start < max {
+            match self.pop_output(now) {
+                Some(out) => buf.push(out),
+                None => break,
+            }
+        }
+        buf.len() - start
+    }
+
     pub fn on_tick(&mut self, now: Instant) {
+        let started = self.metrics.is_some().then(Instant::now);
+        if let Some(deadline) = self.draining_until {
+            if now >= deadline && !self.shutdown {
+                log::warn!("[MediaWorkerWebrtc] graceful shutdown timeout with {} sessions remain => force shutdown", self.endpoints.tasks());
+                self.shutdown(now);
+            }
+        }
+        if !self.next_reap.is_some_and(|next| now < next) {
+            self.next_reap = Some(now + self.reaper.interval);
+            self.reap_stuck_sessions(now);
+        }
         self.endpoints.on_tick(now);
+        if let (Some(metrics), Some(started)) = (self.metrics.as_mut(), started) {
+            metrics.on_tick(started.elapsed());
+            metrics.report(now);
+        }
+    }
+
+    /// Force remove sessions which are stuck longer than reaper thresholds. They are removed without waiting for endpoint
+    /// close flow, so leaving room and disconnected event are emitted here. It runs before endpoints are ticked, when
+    /// their outputs are already drained
+    fn reap_stuck_sessions(&mut self, now: Instant) {
+        let indexes = self
+            .sessions
+            .iter()
+            .filter(|(_, slot)| slot.stuck.is_some_and(|(state, since)| self.reaper.is_stuck(now, state, since)))
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        for index in indexes {
+            let slot = self.sessions.remove(&index).expect("Should have session slot");
+            self.release_video_codec(&slot);
+            let (state, since) = slot.stuck.expect("Should have stuck state");
+            tracing::warn!(index, session_id = slot.session_id, ?state, stuck = ?now - since, "[MediaWorkerWebrtc] session is stuck => reap");
+            Count::<ReapedSession>::event();
+            self.endpoints.remove_task(index);
+            self.shared_port.remove_task(index);
+            // endpoint joins the room after connected, so only reconnecting sessions are in it
+            if let Some(room) = slot.room.filter(|_| state == StuckState::Reconnecting) {
+                self.queue.push_back(GroupOutput::Cluster(WebrtcSession(index), room, ClusterEndpointControl::Leave));
+            }
+            let event = peer_event::Event::Disconnected(peer_event::Disconnected {
+                duration_ms: slot.started.map(|started| (now - started).as_millis() as u64).unwrap_or(0),
+                reason: Reason::Reaped as i32,
+            });
+            self.queue.push_back(GroupOutput::PeerEvent(WebrtcSession(index), slot.app, slot.session_id, now, event));
+        }
     }
 
     pub fn on_event(&mut self, now: Instant, input: GroupInput) {
+        let started = self.metrics.is_some().then(Instant::now);
+        self.process_input(now, input);
+        if let (Some(metrics), Some(started)) = (self.metrics.as_mut(), started) {
+            metrics.on_event(started.elapsed());
+        }
+    }
+
+    fn process_input(&mut self, now: Instant, input: GroupInput) {
         match input {
             GroupInput::Net(BackendIncoming::UdpListenResult { bind, result }) => {
                 if let Ok((addr, slot)) = result {
                     log::info!("[MediaWorkerWebrtc] successful bind udp port {addr}, slot {slot}");
-                    self.addrs.push((addr, slot));
+                    self.transport.addrs.push((addr, slot));
                 } else {
                     log::warn!("[MediaWorkerWebrtc] unsuccessful bind {bind}");
+                    self.failed_binds.push(bind);
                 }
+                self.pending_binds = self.pending_binds.saturating_sub(1);
+                self.check_ready();
             }
             GroupInput::Net(BackendIncoming::UdpPacket { slot, from, data }) => {
                 let index = return_if_none!(self.shared_port.map_remote(from, &data));
                 self.endpoints.on_event(now, index, EndpointInput::Net(BackendIncoming::UdpPacket { slot, from, data }));
             }
+            GroupInput::IceTcp(pkt) => {
+                let slot = return_if_none!(self.transport.tcp_addrs.iter().find(|(addr, _)| *addr == pkt.local)).1;
+                let index = return_if_none!(self.shared_port.map_remote(pkt.remote, &pkt.data));
+                self.endpoints.on_event(
+                    now,
+                    index,
+                    EndpointInput::Net(BackendIncoming::UdpPacket {
+                        slot,
+                        from: pkt.remote,
+                        data: pkt.data.into(),
+                    }),
+                );
+            }
             GroupInput::Cluster(owner, event) => {
                 self.endpoints.on_event(now, owner.index(), EndpointInput::Cluster(event));
             }
@@ -174,12 +701,45 @@ impl<ES: MediaEdgeSecure> MediaWorkerWebrtc<ES> {
                             self.queue
                                 .push_back(GroupOutput::Ext(owner, ExtOut::Disconnect(req_id, variant, Err(RpcError::new2(WebrtcError::RpcEndpointNotFound)))));
                         }
+                        ExtIn::Migrate(req_id, ..) => {
+                            self.queue
+                                .push_back(GroupOutput::Ext(owner, ExtOut::Migrate(req_id, Err(RpcError::new2(WebrtcError::RpcEndpointNotFound)))));
+                        }
+                        ExtIn::Events(req_id, _) => {
+                            self.queue
+                                .push_back(GroupOutput::Ext(owner, ExtOut::Events(req_id, Err(RpcError::new2(WebrtcError::RpcEndpointNotFound)))));
+                        }
+                        ExtIn::Dump(req_id) => {
+                            self.queue
+                                .push_back(GroupOutput::Ext(owner, ExtOut::Dump(req_id, Err(RpcError::new2(WebrtcError::RpcEndpointNotFound)))));
+                        }
+                        ExtIn::Close => {}
                     }
                 }
             }
         }
     }
 
+    /// First phase of shutdown: stop accepting new sessions and close all sessions like a server side close,
+    /// so final peer and record events are still emitted. Sessions which are alive after `grace` are force removed.
+    pub fn graceful_shutdown(&mut self, now: Instant, grace: Duration) {
+        if self.draining_until.is_some() || self.shutdown {
+            return;
+        }
+        log::info!("[MediaWorkerWebrtc] graceful shutdown request, grace {grace:?}");
+        self.draining_until = Some(now + grace);
+        let mut indexes = vec![];
+        for (index, slot) in self.sessions.iter_mut() {
+            if !slot.closing {
+                slot.closing = true;
+                indexes.push(*index);
+            }
+        }
+        for index in indexes {
+            self.endpoints.on_event(now, index, EndpointInput::Ext(ExtIn::Close));
+        }
+    }
+
     pub fn shutdown(&mut self, now: Instant) {
         if !self.shutdown {
             log::info!("[MediaWorkerWebrtc] shutdown request");
@@ -197,10 +757,20 @@ impl<ES: MediaEdgeSecure> TaskSwitcherChild<GroupOutput> for MediaWorkerWebrtc<E
     }
 
     fn is_empty(&self) -> bool {
-        self.shutdown && self.queue.is_empty() && self.endpoints.is_empty()
+        (self.shutdown || self.draining_until.is_some()) && self.queue.is_empty() && self.endpoints.is_empty()
     }
 
     fn pop_output(&mut self, now: Instant) -> Option<GroupOutput> {
+        let out = self.pop_inner_output(now);
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.on_pop(out.is_some());
+        }
+        out
+    }
+}
+
+impl<ES: MediaEdgeSecure> MediaWorkerWebrtc<ES> {
+    fn pop_inner_output(&mut self, now: Instant) -> Option<GroupOutput> {
         return_if_some!(self.queue.pop_front());
         let (index, out) = match self.endpoints.pop_output(now)? {
             TaskGroupOutput::TaskOutput(index, out) => (index, out),
@@ -209,3 +779,1657 @@ impl<ES: MediaEdgeSecure> TaskSwitcherChild<GroupOutput> for MediaWorkerWebrtc<E
         Some(self.process_output(index, out))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        ops::Deref,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use media_server_core::{
+        cluster::{ClusterRoomHash, RoomVideoCodecs},
+        endpoint::{OpusConfig, OpusParams},
+    };
+    use media_server_protocol::{
+        endpoint::{ClusterConnId, RoomId},
+        multi_tenancy::{AppContext, AppId},
+        protobuf::{
+            cluster_connector::peer_event::{self, disconnected::Reason},
+            gateway::ConnectRequest,
+        },
+        transport::webrtc::WebrtcMigrateTicket,
+    };
+    use media_server_secure::{jwt::MediaEdgeSecureJwt, MediaEdgeSecure};
+    use media_server_utils::get_all_counts;
+    use sans_io_runtime::{
+        backend::{BackendIncoming, BackendOutgoing},
+        TaskSwitcherChild,
+    };
+    use str0m::{
+        change::SdpAnswer,
+        format::{Codec, FormatParams},
+        media::{Direction, Frequency, MediaKind},
+        net::{Protocol, Receive},
+        Candidate, Rtc,
+    };
+
+    use crate::{
+        BundlePolicy, ConsentConfig, DtlsCipher, DtlsPolicy, DtlsSetup, DtlsSuites, ExtIn, ExtOut, ReapedSession, ReaperConfig, RtcpFbPolicy, RtpExtension, SdpSession, SrtpProfile, Variant,
+        VariantParams, VideoCodec, WebrtcError,
+    };
+
+    use super::{GroupInput, GroupOutput, MediaWorkerWebrtc, WebrtcSession, WebrtcWorkerConfig};
+    use crate::dtls_policy::HelloReassembly;
+    use crate::ice_role::{IceRole, IceRoleResolver};
+    use crate::ice_tcp::IceTcpPacket;
+    use crate::remote_ice::DEFAULT_MAX_REMOTE_CANDIDATES;
+    use crate::sdp_redact::redact_sdp;
+    use crate::sdp_ssrc::{check_answer_ssrc, fid_groups};
+    use crate::transport::EVENTS_LONG_POLL;
+
+    const AUDIO_OFFER: &str = "v=0\r\n\
+o=- 4215775240449105457 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0\r\n\
+a=msid-semantic: WMS\r\n\
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=rtcp:9 IN IP4 0.0.0.0\r\n\
+a=ice-ufrag:S5hk\r\n\
+a=ice-pwd:0zV/Yu3y8aDzbHgqWhnVQhqP\r\n\
+a=ice-options:trickle\r\n\
+a=fingerprint:sha-256 8C:64:ED:03:76:D0:3D:B4:C1:5A:E1:8E:B6:B0:84:C2:DC:4C:5E:E7:3F:91:7A:95:45:4A:9C:7E:F2:4E:BA:C0\r\n\
+a=setup:actpass\r\n\
+a=mid:0\r\n\
+a=sendonly\r\n\
+a=msid:- 7f56a1fa-1ee6-4c65-9b18-d6e4b4fa9d7c\r\n\
+a=rtcp-mux\r\n\
+a=rtpmap:111 opus/48000/2\r\n\
+a=rtcp-fb:111 transport-cc\r\n\
+a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+a=ssrc:3948621874 cname:bJ0vVnzym6S2IxyA\r\n";
+
+    /// Video offer with H264 payloads, each is (pt, profile-level-id)
+    fn h264_offer(payloads: &[(u8, &str)]) -> String {
+        let pts = payloads.iter().map(|(pt, _)| pt.to_string()).collect::<Vec<_>>().join(" ");
+        let mut sdp = video_offer_header(&pts);
+        for (pt, profile) in payloads {
+            sdp.push_str(&format!(
+                "a=rtpmap:{pt} H264/90000\r\na=fmtp:{pt} level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={profile}\r\n"
+            ));
+        }
+        sdp.push_str("a=ssrc:3948621875 cname:bJ0vVnzym6S2IxyA\r\n");
+        sdp
+    }
+
+    /// Video offer with mixed codecs, each is (pt, codec name), H264 is always baseline
+    fn video_offer(payloads: &[(u8, &str)]) -> String {
+        let pts = payloads.iter().map(|(pt, _)| pt.to_string()).collect::<Vec<_>>().join(" ");
+        let mut sdp = video_offer_header(&pts);
+        for (pt, codec) in payloads {
+            sdp.push_str(&format!("a=rtpmap:{pt} {codec}/90000\r\n"));
+            if *codec == "H264" {
+                sdp.push_str(&format!("a=fmtp:{pt} level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\n"));
+            }
+        }
+        sdp.push_str("a=ssrc:3948621875 cname:bJ0vVnzym6S2IxyA\r\n");
+        sdp
+    }
+
+    fn video_offer_header(pts: &str) -> String {
+        format!(
+            "v=0\r\n\
+o=- 4215775240449105458 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0\r\n\
+a=msid-semantic: WMS\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF {pts}\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=rtcp:9 IN IP4 0.0.0.0\r\n\
+a=ice-ufrag:S5hk\r\n\
+a=ice-pwd:0zV/Yu3y8aDzbHgqWhnVQhqP\r\n\
+a=fingerprint:sha-256 8C:64:ED:03:76:D0:3D:B4:C1:5A:E1:8E:B6:B0:84:C2:DC:4C:5E:E7:3F:91:7A:95:45:4A:9C:7E:F2:4E:BA:C0\r\n\
+a=setup:actpass\r\n\
+a=mid:0\r\n\
+a=sendonly\r\n\
+a=msid:- 7f56a1fa-1ee6-4c65-9b18-d6e4b4fa9d7d\r\n\
+a=rtcp-mux\r\n"
+        )
+    }
+
+    fn answer_codecs(answer: &str) -> Vec<String> {
+        answer
+            .lines()
+            .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+            .filter_map(|rtpmap| rtpmap.split(' ').nth(1).and_then(|c| c.split('/').next()))
+            .filter(|codec| *codec != "rtx")
+            .map(|codec| codec.to_string())
+            .collect()
+    }
+
+    fn create_h264_worker(h264_profiles: Vec<u32>) -> MediaWorkerWebrtc<MediaEdgeSecureJwt> {
+        MediaWorkerWebrtc::new(WebrtcWorkerConfig { h264_profiles, ..Default::default() }, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())))
+    }
+
+    fn create_worker(consent: ConsentConfig) -> MediaWorkerWebrtc<MediaEdgeSecureJwt> {
+        MediaWorkerWebrtc::new(WebrtcWorkerConfig { consent, ..Default::default() }, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())))
+    }
+
+    /// Return (prio, ip) of candidates in answer, sorted by highest priority first
+    fn answer_candidates(answer: &str) -> Vec<(u32, IpAddr)> {
+        // candidate:<foundation> <component> <proto> <prio> <ip> <port> typ host
+        let mut candidates = answer
+            .lines()
+            .filter_map(|line| line.strip_prefix("a=candidate:"))
+            .map(|line| {
+                let parts = line.split(' ').collect::<Vec<_>>();
+                (parts[3].parse::<u32>().expect("Should parse prio"), parts[4].parse::<IpAddr>().expect("Should parse ip"))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(prio, _)| std::cmp::Reverse(*prio));
+        candidates
+    }
+
+    /// Pop all outputs and return true if any connect error peer event found
+    fn has_connect_error(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, now: Instant) -> bool {
+        let mut found = false;
+        while let Some(out) = worker.pop_output(now) {
+            if matches!(out, GroupOutput::PeerEvent(_, _, _, _, peer_event::Event::ConnectError(_))) {
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Pop all outputs and return number of them
+    fn count_outputs(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, now: Instant) -> usize {
+        let mut count = 0;
+        while worker.pop_output(now).is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    /// Pop all outputs and return migrate results sorted by req_id, error is returned as error code
+    fn migrate_results(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, now: Instant) -> Vec<(u64, Result<ClusterConnId, u32>)> {
+        let mut results = vec![];
+        while let Some(out) = worker.pop_output(now) {
+            if let GroupOutput::Ext(_, ExtOut::Migrate(req_id, res)) = out {
+                results.push((req_id, res.map_err(|e| e.code)));
+            }
+        }
+        results.sort_by_key(|(req_id, _)| *req_id);
+        results
+    }
+
+    /// Pop all outputs and return number of disconnected peer events
+    fn count_disconnected(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, now: Instant) -> usize {
+        let mut count = 0;
+        while let Some(out) = worker.pop_output(now) {
+            if matches!(out, GroupOutput::PeerEvent(_, _, _, _, peer_event::Event::Disconnected(_))) {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn validate_valid_offer() {
+        let worker = create_worker(ConsentConfig::default());
+        let res = worker.validate_offer(AUDIO_OFFER).expect("Should validate");
+        assert!(res.answer.starts_with("v=0"));
+        assert_eq!(res.codecs, vec!["opus".to_string()]);
+        assert_eq!(res.rejected_mids, Vec::<String>::new());
+        assert_eq!(worker.tasks(), 0);
+    }
+
+    #[test]
+    fn validate_invalid_offer() {
+        let worker = create_worker(ConsentConfig::default());
+        let err = worker.validate_offer("invalid sdp").expect_err("Should reject");
+        assert_eq!(err.code, WebrtcError::InvalidSdp as u32);
+        assert_eq!(worker.tasks(), 0);
+    }
+
+    #[test]
+    fn spawn_rejects_invalid_offer() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let err = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                "invalid sdp",
+            )
+            .expect_err("Should reject");
+        assert_eq!(err.code, WebrtcError::InvalidSdp as u32);
+        assert_eq!(worker.tasks(), 0);
+    }
+
+    #[test]
+    fn consent_timeout_should_fail_session() {
+        let consent = ConsentConfig {
+            timeout: Duration::from_millis(1000),
+            established_timeout: Duration::from_millis(2000),
+            ..Default::default()
+        };
+        let mut worker = create_worker(consent);
+        let now = Instant::now();
+        worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+
+        worker.on_tick(now);
+        assert!(!has_connect_error(&mut worker, now));
+
+        // remote dont send anything but still inside consent window
+        let now2 = now + Duration::from_millis(500);
+        worker.on_tick(now2);
+        assert!(!has_connect_error(&mut worker, now2));
+
+        // connect timeout is 10s, so failed at this point is caused by consent timeout
+        let now3 = now + Duration::from_millis(1000);
+        worker.on_tick(now3);
+        assert!(has_connect_error(&mut worker, now3));
+    }
+
+    #[test]
+    fn candidate_priority_follow_configured_order() {
+        let management: IpAddr = "10.0.0.1".parse().expect("Should parse ip");
+        let public: IpAddr = "1.2.3.4".parse().expect("Should parse ip");
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                addrs_alt: vec![SocketAddr::new(management, 10000), SocketAddr::new(public, 10000)],
+                candidate_order: vec![public],
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let (_, answer, _) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+
+        let candidates = answer_candidates(&answer);
+        assert_eq!(candidates.iter().map(|(_, ip)| *ip).collect::<Vec<_>>(), vec![public, management]);
+        assert_ne!(candidates[0].0, candidates[1].0);
+    }
+
+    #[test]
+    fn max_candidates_keep_highest_priority() {
+        let ips: Vec<IpAddr> = ["10.0.0.1", "10.0.0.2", "1.2.3.4", "1.2.3.5"].iter().map(|ip| ip.parse().expect("Should parse ip")).collect();
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                addrs_alt: ips.iter().map(|ip| SocketAddr::new(*ip, 10000)).collect(),
+                candidate_order: vec![ips[2], ips[3]],
+                max_candidates: Some(2),
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let (_, answer, _) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+
+        let candidates = answer_candidates(&answer);
+        assert_eq!(candidates.iter().map(|(_, ip)| *ip).collect::<Vec<_>>(), vec![ips[2], ips[3]]);
+    }
+
+    #[test]
+    fn connecting_limit_reject_over_limit() {
+        let consent = ConsentConfig {
+            timeout: Duration::from_millis(1000),
+            established_timeout: Duration::from_millis(2000),
+            ..Default::default()
+        };
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                consent,
+                max_connecting: Some(2),
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let now = Instant::now();
+        let spawn = |worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, session_id: u64| {
+            worker.spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                session_id,
+                VariantParams::Whip("room".into(), format!("peer{session_id}").as_str().into(), None, false),
+                AUDIO_OFFER,
+            )
+        };
+
+        assert!(spawn(&mut worker, 1).is_ok());
+        assert!(spawn(&mut worker, 2).is_ok());
+        assert_eq!(worker.connecting(), 2);
+        let err = spawn(&mut worker, 3).expect_err("Should reject over limit");
+        assert_eq!(err.code, WebrtcError::ConnectOverloaded as u32);
+        assert_eq!(worker.tasks(), 2);
+        worker.on_tick(now);
+        assert!(!has_connect_error(&mut worker, now));
+
+        // after handshakes finished (here is failed by consent timeout), new connects are accepted
+        let now2 = now + Duration::from_millis(1000);
+        worker.on_tick(now2);
+        assert!(has_connect_error(&mut worker, now2));
+        assert_eq!(worker.connecting(), 0);
+        assert!(spawn(&mut worker, 4).is_ok());
+    }
+
+    #[test]
+    fn connect_before_bind_not_ready() {
+        let addrs = vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000), SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10001)];
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                addrs: addrs.clone(),
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let now = Instant::now();
+        let spawn = |worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, session_id: u64| {
+            worker.spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                session_id,
+                VariantParams::Whip("room".into(), format!("peer{session_id}").as_str().into(), None, false),
+                AUDIO_OFFER,
+            )
+        };
+        let bind_result = |worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, addr: SocketAddr, slot: usize| {
+            worker.on_event(now, GroupInput::Net(BackendIncoming::UdpListenResult { bind: addr, result: Ok((addr, slot)) }));
+        };
+
+        assert!(!worker.is_ready());
+        let err = spawn(&mut worker, 1).expect_err("Should reject before any bind");
+        assert_eq!(err.code, WebrtcError::WorkerNotReady as u32);
+
+        bind_result(&mut worker, addrs[0], 1);
+        assert!(!worker.is_ready());
+        let err = spawn(&mut worker, 2).expect_err("Should reject before all binds");
+        assert_eq!(err.code, WebrtcError::WorkerNotReady as u32);
+        assert_eq!(worker.tasks(), 0);
+
+        bind_result(&mut worker, addrs[1], 2);
+        assert!(worker.is_ready());
+        assert!(spawn(&mut worker, 3).is_ok());
+    }
+
+    #[test]
+    fn failed_bind_stay_not_ready() {
+        let addrs = vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000), SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10001)];
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                addrs: addrs.clone(),
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let now = Instant::now();
+
+        worker.on_event(
+            now,
+            GroupInput::Net(BackendIncoming::UdpListenResult {
+                bind: addrs[0],
+                result: Ok((addrs[0], 1)),
+            }),
+        );
+        worker.on_event(
+            now,
+            GroupInput::Net(BackendIncoming::UdpListenResult {
+                bind: addrs[1],
+                result: Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, "in use")),
+            }),
+        );
+        assert!(!worker.is_ready());
+        let err = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect_err("Should reject when a bind failed");
+        assert_eq!(err.code, WebrtcError::WorkerNotReady as u32);
+    }
+
+    #[test]
+    fn graceful_shutdown_close_sessions_before_empty() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let now = Instant::now();
+        let spawn = |worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, session_id: u64| {
+            worker.spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                session_id,
+                VariantParams::Whip("room".into(), format!("peer{session_id}").as_str().into(), None, false),
+                AUDIO_OFFER,
+            )
+        };
+        assert!(spawn(&mut worker, 1).is_ok());
+        assert!(spawn(&mut worker, 2).is_ok());
+        count_outputs(&mut worker, now);
+
+        worker.graceful_shutdown(now, Duration::from_secs(5));
+        assert!(!worker.is_empty());
+        let err = spawn(&mut worker, 3).expect_err("Should reject while shutting down");
+        assert_eq!(err.code, WebrtcError::WorkerShuttingDown as u32);
+
+        // all sessions are closed with final events, only after that the worker is empty
+        assert_eq!(count_disconnected(&mut worker, now), 2);
+        assert_eq!(worker.tasks(), 0);
+        assert!(worker.is_empty());
+    }
+
+    #[test]
+    fn close_sessions_of_room() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let now = Instant::now();
+        let app1 = AppContext { app: AppId::from("app1") };
+        let sessions = [
+            (AppContext::root_app(), "room1", "peer1"),
+            (AppContext::root_app(), "room1", "peer2"),
+            (AppContext::root_app(), "room2", "peer3"),
+            (app1.clone(), "room1", "peer4"),
+        ];
+        for (session_id, (app, room, peer)) in sessions.into_iter().enumerate() {
+            worker
+                .spawn(
+                    app,
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    session_id as u64,
+                    VariantParams::Whip(room.into(), peer.into(), None, false),
+                    AUDIO_OFFER,
+                )
+                .expect("Should spawn");
+        }
+        assert_eq!(count_disconnected(&mut worker, now), 0);
+
+        let room1 = ClusterRoomHash::generate(&AppContext::root_app(), &RoomId::from("room1"));
+        assert_eq!(worker.close_sessions(now, &AppId::root_app(), Some(room1), None), 2);
+        assert_eq!(count_disconnected(&mut worker, now), 2);
+
+        // closing again dont touch already closed sessions
+        assert_eq!(worker.close_sessions(now, &AppId::root_app(), Some(room1), None), 0);
+        assert_eq!(count_disconnected(&mut worker, now), 0);
+
+        // close whole app only affect sessions of that app
+        assert_eq!(worker.close_sessions(now, &AppId::root_app(), None, None), 1);
+        assert_eq!(worker.close_sessions(now, &app1.app, None, None), 1);
+        assert_eq!(count_disconnected(&mut worker, now), 2);
+    }
+
+    #[test]
+    fn migrate_session_keep_session_id() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let now = Instant::now();
+        let req = ConnectRequest {
+            sdp: AUDIO_OFFER.to_string(),
+            ..Default::default()
+        };
+        let secure = Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice()));
+        let (_, _, sdk) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1000,
+                VariantParams::Webrtc("agent".to_string(), req, None, false, secure),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+        let (_, _, whip) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1001,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+        count_outputs(&mut worker, now);
+
+        worker.migrate_session(now, sdk, 1, Some(5));
+        worker.migrate_session(now, whip, 2, Some(5));
+        worker.migrate_session(now, 100, 3, Some(5));
+        worker.migrate_session(now, sdk, 4, None);
+        assert_eq!(
+            migrate_results(&mut worker, now),
+            vec![
+                (1, Ok(ClusterConnId::migrate(5, 1000))),
+                (2, Err(WebrtcError::RpcMigrateNotSupported as u32)),
+                (3, Err(WebrtcError::RpcEndpointNotFound as u32)),
+                (4, Err(WebrtcError::RpcInvalidRequest as u32)),
+            ]
+        );
+
+        // closing by session id only touches the session which is migrated away, not one which has the id in this node
+        worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1002,
+                VariantParams::Whip("room".into(), "peer2".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+        assert_eq!(worker.close_sessions(now, &AppId::root_app(), None, Some(1002)), 0);
+        assert_eq!(worker.close_sessions(now, &AppId::root_app(), None, Some(1000)), 1);
+    }
+
+    #[test]
+    fn migrated_session_needs_ticket_for_session_and_node() {
+        let secure = Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice()));
+        let mut worker = MediaWorkerWebrtc::new(WebrtcWorkerConfig { node_id: 2, ..Default::default() }, secure.clone());
+        let spawn = |worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, session_id: u64, token: String| {
+            let req = ConnectRequest {
+                sdp: AUDIO_OFFER.to_string(),
+                migrate_token: Some(token),
+                ..Default::default()
+            };
+            worker
+                .spawn(
+                    AppContext::root_app(),
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    session_id,
+                    VariantParams::Webrtc("agent".to_string(), req, None, false, secure.clone()),
+                    AUDIO_OFFER,
+                )
+                .map(|_| ())
+                .map_err(|e| e.code)
+        };
+        let invalid = Err(WebrtcError::RpcMigrateTicketInvalid as u32);
+
+        // issued by node 1 for moving session 1000 to this node
+        let token = secure.encode_migrate_ticket(WebrtcMigrateTicket::new(1000, 1, 2));
+        assert_eq!(spawn(&mut worker, 1001, token.clone()), invalid);
+        assert_eq!(spawn(&mut worker, 1000, token), Ok(()));
+
+        let other_node = secure.encode_migrate_ticket(WebrtcMigrateTicket::new(1000, 1, 3));
+        assert_eq!(spawn(&mut worker, 1000, other_node), invalid);
+
+        let forged = MediaEdgeSecureJwt::from(b"other".as_slice()).encode_migrate_ticket(WebrtcMigrateTicket::new(1000, 1, 2));
+        assert_eq!(spawn(&mut worker, 1000, forged), invalid);
+    }
+
+    #[test]
+    fn loop_metrics_updated_on_processing() {
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                loop_metrics: true,
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let now = Instant::now();
+        worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+
+        worker.on_tick(now);
+        worker.on_event(
+            now,
+            GroupInput::Net(BackendIncoming::UdpListenResult {
+                bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                result: Ok((SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000), 1)),
+            }),
+        );
+        let outputs = count_outputs(&mut worker, now);
+
+        let metrics = worker.metrics().expect("Should have metrics");
+        assert_eq!(metrics.tick.count(), 1);
+        assert_eq!(metrics.event.count(), 1);
+        assert_eq!(metrics.max_queue, outputs);
+        assert!(metrics.tick.percentile_us(99) <= metrics.tick.max_us());
+    }
+
+    #[test]
+    fn loop_metrics_disabled() {
+        let mut worker = create_worker(ConsentConfig::default());
+        worker.on_tick(Instant::now());
+        assert!(worker.metrics().is_none());
+    }
+
+    #[test]
+    fn h264_answer_follow_configured_profile() {
+        let offer = h264_offer(&[(102, "42001f"), (106, "42e01f"), (112, "4d001f")]);
+        let mut worker = create_h264_worker(vec![0x42e01f]);
+        let (_, answer, _) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &offer,
+            )
+            .expect("Should spawn");
+        let fmtps = answer.lines().filter(|line| line.starts_with("a=fmtp:")).collect::<Vec<_>>();
+        assert!(!fmtps.is_empty());
+        assert!(fmtps.iter().all(|line| line.contains("profile-level-id=42e01f")), "{fmtps:?}");
+    }
+
+    #[test]
+    fn answer_extmap_follow_config() {
+        let offer = format!(
+            "{}a=extmap:2 {}\r\na=extmap:3 {}\r\n",
+            video_offer(&[(96, "VP8")]),
+            RtpExtension::AbsSendTime.uri(),
+            RtpExtension::TransportCc.uri()
+        );
+        let answer_extmaps = |disabled_extensions: Vec<RtpExtension>| {
+            let worker = MediaWorkerWebrtc::new(
+                WebrtcWorkerConfig {
+                    disabled_extensions,
+                    ..Default::default()
+                },
+                Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+            );
+            let res = worker.validate_offer(&offer).expect("Should validate");
+            res.answer.lines().filter(|line| line.starts_with("a=extmap:")).map(|line| line.to_string()).collect::<Vec<_>>()
+        };
+
+        let extmaps = answer_extmaps(vec![]);
+        assert!(extmaps.contains(&format!("a=extmap:2 {}", RtpExtension::AbsSendTime.uri())), "{extmaps:?}");
+        assert!(extmaps.contains(&format!("a=extmap:3 {}", RtpExtension::TransportCc.uri())), "{extmaps:?}");
+
+        let extmaps = answer_extmaps(vec![RtpExtension::TransportCc]);
+        assert!(extmaps.contains(&format!("a=extmap:2 {}", RtpExtension::AbsSendTime.uri())), "{extmaps:?}");
+        assert!(!extmaps.iter().any(|line| line.ends_with(RtpExtension::TransportCc.uri())), "{extmaps:?}");
+    }
+
+    #[test]
+    fn reject_offer_over_max_media_sections() {
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                max_media_sections: 1,
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let over = format!("{AUDIO_OFFER}m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:1\r\na=sendonly\r\na=rtpmap:111 opus/48000/2\r\n");
+        let err = worker.validate_offer(&over).expect_err("Should reject");
+        assert_eq!(err.code, WebrtcError::TooManyMediaSections as u32);
+        let err = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &over,
+            )
+            .expect_err("Should reject");
+        assert_eq!(err.code, WebrtcError::TooManyMediaSections as u32);
+        assert_eq!(worker.tasks(), 0);
+
+        // offer at the limit is accepted
+        worker.validate_offer(AUDIO_OFFER).expect("Should validate");
+        worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                2,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+        assert_eq!(worker.tasks(), 1);
+    }
+
+    #[test]
+    fn legacy_offer_without_rtcp_fb_not_nacked() {
+        let legacy = video_offer(&[(96, "VP8")]);
+        let offer = format!("{legacy}a=rtcp-fb:96 nack\r\na=rtcp-fb:96 nack pli\r\n");
+        let answer = |rtcp_fb: RtcpFbPolicy, offer: &str| {
+            let mut worker = MediaWorkerWebrtc::new(WebrtcWorkerConfig { rtcp_fb, ..Default::default() }, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())));
+            let (_, answer, _) = worker
+                .spawn(
+                    AppContext::root_app(),
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    1,
+                    VariantParams::Whip("room".into(), "peer".into(), None, false),
+                    offer,
+                )
+                .expect("Should spawn");
+            answer
+        };
+        let has_nack = |answer: &str| answer.lines().any(|line| line.starts_with("a=rtcp-fb:") && line.ends_with(" nack"));
+        let has_pli = |answer: &str| answer.lines().any(|line| line.starts_with("a=rtcp-fb:") && line.ends_with(" nack pli"));
+
+        assert!(has_nack(&answer(RtcpFbPolicy::Auto, &offer)));
+        assert!(has_pli(&answer(RtcpFbPolicy::Auto, &offer)));
+        // the client doesn't offer rtcp-fb, so we don't signal or send NACK and PLI to it
+        let legacy_answer = answer(RtcpFbPolicy::Auto, &legacy);
+        assert!(!has_nack(&legacy_answer));
+        assert!(!has_pli(&legacy_answer));
+        let disabled_answer = answer(RtcpFbPolicy::Disabled, &offer);
+        assert!(!has_nack(&disabled_answer));
+        assert!(!has_pli(&disabled_answer));
+    }
+
+    #[test]
+    fn legacy_client_not_sent_nacks() {
+        // number of NACKs which the publishing client receives for a sequence gap
+        let received_nacks = |rtcp_fb: RtcpFbPolicy, legacy: bool| -> u64 {
+            let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000);
+            let mut now = Instant::now();
+            let mut worker = MediaWorkerWebrtc::new(WebrtcWorkerConfig { rtcp_fb, ..Default::default() }, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())));
+            worker.on_event(
+                now,
+                GroupInput::Net(BackendIncoming::UdpListenResult {
+                    bind: server,
+                    result: Ok((server, 1)),
+                }),
+            );
+            count_outputs(&mut worker, now);
+
+            let mut client = Rtc::builder().set_rtp_mode(true).set_stats_interval(Some(Duration::from_millis(100))).build();
+            client.add_local_candidate(Candidate::host(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 20000), Protocol::Udp).expect("Should create candidate"));
+            let mut api = client.sdp_api();
+            let mid = api.add_media(MediaKind::Video, Direction::SendOnly, None, None, None);
+            let (offer, pending) = api.apply().expect("Should create offer");
+            let mut offer = offer.to_sdp_string();
+            if legacy {
+                offer = offer.split_inclusive('\n').filter(|line| !(line.starts_with("a=rtcp-fb:") && line.contains(" nack"))).collect();
+            }
+            let (_, answer, _) = worker
+                .spawn(
+                    AppContext::root_app(),
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    1,
+                    VariantParams::Whip("room".into(), "peer".into(), None, false),
+                    &offer,
+                )
+                .expect("Should spawn");
+            deliver_to_client(&mut worker, &mut client, server, now);
+            client
+                .sdp_api()
+                .accept_answer(pending, SdpAnswer::from_sdp_string(&answer).expect("Should parse answer"))
+                .expect("Should accept answer");
+            run_with_client(&mut worker, &mut client, server, &mut now, Duration::from_secs(2));
+            assert!(client.is_connected());
+
+            let pt = client.codec_config().find(|p| p.spec().codec == str0m::format::Codec::Vp8).expect("Should have vp8").pt();
+            // packets 4..10 are lost
+            for seq in [1u64, 2, 3, 10, 11, 12] {
+                let mut api = client.direct_api();
+                let tx = api.stream_tx_by_mid(mid, None).expect("Should have client video stream");
+                tx.write_rtp(pt, seq.into(), seq as u32 * 3000, now, true, Default::default(), true, vec![0x10, 0x00, 0x00, 0x00])
+                    .expect("Should write rtp");
+                run_with_client(&mut worker, &mut client, server, &mut now, Duration::from_millis(20));
+            }
+            run_with_client(&mut worker, &mut client, server, &mut now, Duration::from_secs(1))
+                .into_iter()
+                .filter_map(|event| match event {
+                    str0m::Event::MediaEgressStats(stats) => Some(stats.nacks),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0)
+        };
+
+        assert!(received_nacks(RtcpFbPolicy::Auto, false) > 0, "client which offers nack should be sent NACKs");
+        assert_eq!(received_nacks(RtcpFbPolicy::Auto, true), 0);
+        assert_eq!(received_nacks(RtcpFbPolicy::Disabled, false), 0);
+    }
+
+    #[test]
+    fn answer_dtls_setup_follow_offer() {
+        let answer_setup = |setup: DtlsSetup, offer: &str| {
+            let worker = MediaWorkerWebrtc::new(
+                WebrtcWorkerConfig {
+                    dtls_policy: DtlsPolicy { setup, ..Default::default() },
+                    ..Default::default()
+                },
+                Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+            );
+            let answer = worker.validate_offer(offer).expect("Should validate").answer;
+            answer.lines().find_map(|line| line.strip_prefix("a=setup:")).map(|setup| setup.to_string())
+        };
+
+        let actpass = video_offer(&[(96, "VP8")]);
+        let active = actpass.replace("a=setup:actpass", "a=setup:active");
+        let passive = actpass.replace("a=setup:actpass", "a=setup:passive");
+        assert_eq!(answer_setup(DtlsSetup::Passive, &actpass).as_deref(), Some("passive"));
+        assert_eq!(answer_setup(DtlsSetup::Active, &actpass).as_deref(), Some("active"));
+        let auto = answer_setup(DtlsSetup::Auto, &actpass);
+        assert!(matches!(auto.as_deref(), Some("active" | "passive")), "{auto:?}");
+
+        // offers with fixed role are answered with the opposite role, whatever the policy
+        for setup in [DtlsSetup::Auto, DtlsSetup::Active, DtlsSetup::Passive] {
+            assert_eq!(answer_setup(setup, &active).as_deref(), Some("passive"));
+            assert_eq!(answer_setup(setup, &passive).as_deref(), Some("active"));
+        }
+    }
+
+    #[test]
+    fn answer_sdp_session_follow_config() {
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                sdp_session: SdpSession {
+                    origin_username: Some("gateway".to_string()),
+                    session_name: Some("media".to_string()),
+                    tool: Some("atm0s-media-server".to_string()),
+                },
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let (_, answer, _) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+
+        let session = answer.split("m=").next().expect("Should have session part");
+        assert!(session.lines().any(|line| line.starts_with("o=gateway ") && line.split(' ').count() == 6), "{answer}");
+        assert!(session.lines().any(|line| line == "s=media"), "{answer}");
+        assert!(session.lines().any(|line| line == "a=tool:atm0s-media-server"), "{answer}");
+    }
+
+    #[test]
+    fn answer_bundle_follow_policy() {
+        let validate = |policy: BundlePolicy, group: &str| {
+            let media = &AUDIO_OFFER[AUDIO_OFFER.find("m=audio").expect("Should have media")..];
+            let offer = format!(
+                "{}{}",
+                AUDIO_OFFER.replace("a=group:BUNDLE 0", group),
+                media.replace("a=mid:0", "a=mid:1").replace("3948621874", "3948621875")
+            );
+            let worker = MediaWorkerWebrtc::new(
+                WebrtcWorkerConfig {
+                    bundle_policy: policy,
+                    ..Default::default()
+                },
+                Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+            );
+            worker.validate_offer(&offer)
+        };
+
+        for policy in [BundlePolicy::Balanced, BundlePolicy::MaxBundle] {
+            let answer = validate(policy, "a=group:BUNDLE 0 1").expect("Should validate").answer;
+            assert!(answer.contains("a=group:BUNDLE 0 1\r\n"), "{answer}");
+        }
+
+        // balanced answers unbundled m-line as inactive and keeps it out of the group
+        let answer = validate(BundlePolicy::Balanced, "a=group:BUNDLE 0").expect("Should validate").answer;
+        assert!(answer.contains("a=group:BUNDLE 0\r\n"), "{answer}");
+        let unbundled = answer.split("m=").find(|section| section.contains("a=mid:1\r\n")).expect("Should have mid 1");
+        assert!(unbundled.contains("a=inactive"), "{answer}");
+
+        let err = validate(BundlePolicy::MaxBundle, "a=group:BUNDLE 0").expect_err("Should reject");
+        assert_eq!(err.code, WebrtcError::InvalidSdp as u32);
+    }
+
+    #[test]
+    fn answer_direction_follow_variant() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let mut session_id = 0;
+        let mut answer_direction = |variant: VariantParams<MediaEdgeSecureJwt>, offered: &str| {
+            session_id += 1;
+            let offer = AUDIO_OFFER.replace("a=sendonly\r\n", &format!("a={offered}\r\n"));
+            let (_, answer, _) = worker
+                .spawn(AppContext::root_app(), IpAddr::V4(Ipv4Addr::LOCALHOST), session_id, variant, &offer)
+                .expect("Should spawn");
+            answer
+                .lines()
+                .find(|line| ["a=sendrecv", "a=sendonly", "a=recvonly", "a=inactive"].contains(line))
+                .expect("Should have direction")
+                .to_string()
+        };
+
+        // whip client only publishes
+        assert_eq!(answer_direction(VariantParams::Whip("room".into(), "peer".into(), None, false), "sendonly"), "a=recvonly");
+        assert_eq!(answer_direction(VariantParams::Whip("room".into(), "peer".into(), None, false), "sendrecv"), "a=recvonly");
+        assert_eq!(answer_direction(VariantParams::Whip("room".into(), "peer".into(), None, false), "recvonly"), "a=inactive");
+
+        // whep client only subscribes
+        assert_eq!(answer_direction(VariantParams::Whep("room".into(), "peer".into(), None), "recvonly"), "a=sendonly");
+        assert_eq!(answer_direction(VariantParams::Whep("room".into(), "peer".into(), None), "sendrecv"), "a=sendonly");
+        assert_eq!(answer_direction(VariantParams::Whep("room".into(), "peer".into(), None), "sendonly"), "a=inactive");
+        assert_eq!(answer_direction(VariantParams::Whep("room".into(), "peer".into(), None), "inactive"), "a=inactive");
+    }
+
+    #[test]
+    fn answer_declare_rtx_fid_group() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let offer = format!(
+            "{}a=rtpmap:96 VP8/90000\r\na=rtcp-fb:96 nack\r\na=rtpmap:97 rtx/90000\r\na=fmtp:97 apt=96\r\n",
+            video_offer_header("96 97").replace("a=sendonly\r\n", "a=recvonly\r\n")
+        );
+        let (_, answer, _) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whep("room".into(), "peer".into(), None),
+                &offer,
+            )
+            .expect("Should spawn");
+
+        assert!(answer.contains("a=rtpmap:97 rtx/90000"));
+        let groups = fid_groups(&answer);
+        assert_eq!(groups.len(), 1, "answer should have one FID group for the sent video stream");
+        let (media, rtx) = groups[0];
+        assert_ne!(media, rtx);
+        assert!(answer.contains(&format!("a=ssrc:{media} ")));
+        assert!(answer.contains(&format!("a=ssrc:{rtx} ")));
+        assert_eq!(check_answer_ssrc(&answer), Ok(()));
+    }
+
+    #[test]
+    fn offer_without_compatible_codec_rejected() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let offer = AUDIO_OFFER.replace("m=audio 9 UDP/TLS/RTP/SAVPF 111", "m=audio 9 UDP/TLS/RTP/SAVPF 9").replace(
+            "a=rtpmap:111 opus/48000/2\r\na=rtcp-fb:111 transport-cc\r\na=fmtp:111 minptime=10;useinbandfec=1\r\n",
+            "a=rtpmap:9 G722/8000\r\n",
+        );
+        let err = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &offer,
+            )
+            .expect_err("Should reject");
+        assert_eq!(err.code, WebrtcError::NoCompatibleCodec as u32);
+        assert_eq!(err.message, "no compatible codec, offered [G722], supported [opus, VP8, VP9, H264]");
+        assert_eq!(worker.tasks(), 0);
+    }
+
+    /// Pop all outputs and return remote ice results, error is returned as error code
+    fn remote_ice_results(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, now: Instant) -> Vec<(u64, Result<u32, u32>)> {
+        let mut results = vec![];
+        while let Some(out) = worker.pop_output(now) {
+            if let GroupOutput::Ext(_, ExtOut::RemoteIce(req_id, _, res)) = out {
+                results.push((req_id, res.map_err(|e| e.code)));
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn duplicated_remote_ice_added_once() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let now = Instant::now();
+        let (_, _, index) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+        count_outputs(&mut worker, now);
+
+        let candidate = "candidate:1 1 udp 2122260223 192.168.1.10 50000 typ host generation 0".to_string();
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::RemoteIce(1, Variant::Whip, vec![candidate.clone()])));
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::RemoteIce(2, Variant::Whip, vec![candidate])));
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::RemoteIce(3, Variant::Whip, vec!["candidate:1 1 udp".to_string()])));
+        assert_eq!(remote_ice_results(&mut worker, now), vec![(1, Ok(1)), (2, Ok(0)), (3, Err(WebrtcError::InvalidIceCandidate as u32))]);
+    }
+
+    #[test]
+    fn remote_ice_over_max_rejected() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let now = Instant::now();
+        let (_, _, index) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+        count_outputs(&mut worker, now);
+
+        let candidate = |port: usize| format!("candidate:1 1 udp 2122260223 192.168.1.10 {} typ host generation 0", 10000 + port);
+        let first = (0..DEFAULT_MAX_REMOTE_CANDIDATES - 1).map(candidate).collect::<Vec<_>>();
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::RemoteIce(1, Variant::Whip, first)));
+        // flood over the max is rejected as a whole
+        let flood = (DEFAULT_MAX_REMOTE_CANDIDATES - 1..DEFAULT_MAX_REMOTE_CANDIDATES + 1).map(candidate).collect::<Vec<_>>();
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::RemoteIce(2, Variant::Whip, flood)));
+        // session stays healthy, candidates within the max are still accepted
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::RemoteIce(3, Variant::Whip, vec![candidate(0)])));
+        worker.on_event(
+            now,
+            GroupInput::Ext(WebrtcSession(index), ExtIn::RemoteIce(4, Variant::Whip, vec![candidate(DEFAULT_MAX_REMOTE_CANDIDATES)])),
+        );
+        assert_eq!(
+            remote_ice_results(&mut worker, now),
+            vec![
+                (1, Ok(DEFAULT_MAX_REMOTE_CANDIDATES as u32 - 1)),
+                (2, Err(WebrtcError::TooManyIceCandidates as u32)),
+                (3, Ok(0)),
+                (4, Ok(1))
+            ]
+        );
+        assert_eq!(worker.tasks(), 1);
+    }
+
+    #[test]
+    fn h264_unsupported_profile_only_offer() {
+        let offer = h264_offer(&[(112, "4d001f")]);
+        let worker = create_h264_worker(vec![0x42e01f]);
+        let res = worker.validate_offer(&offer).expect("Should validate");
+        assert!(!res.codecs.contains(&"H264".to_string()));
+        assert!(!res.warnings.is_empty());
+
+        // default config still accepts all forwardable profiles
+        let res = create_h264_worker(vec![]).validate_offer(&offer).expect("Should validate");
+        assert_eq!(res.codecs, vec!["H264".to_string()]);
+    }
+
+    #[test]
+    fn video_codec_policy_converge_in_room() {
+        // workers of a node share pinned codecs of rooms
+        let room_video_codecs = Arc::new(RoomVideoCodecs::default());
+        let new_worker = || {
+            MediaWorkerWebrtc::new(
+                WebrtcWorkerConfig {
+                    video_codecs: vec![VideoCodec::H264, VideoCodec::Vp9, VideoCodec::Vp8],
+                    room_video_codecs: room_video_codecs.clone(),
+                    ..Default::default()
+                },
+                Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+            )
+        };
+        let mut worker1 = new_worker();
+        let mut worker2 = new_worker();
+        let spawn = |worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, peer: &str, offer: &str| {
+            let (_, answer, _) = worker
+                .spawn(
+                    AppContext::root_app(),
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    1,
+                    VariantParams::Whip("room".into(), peer.into(), None, false),
+                    offer,
+                )
+                .expect("Should spawn");
+            answer_codecs(&answer)
+        };
+
+        // first peer dont support H264, so VP9 is selected and pinned for the room
+        assert_eq!(spawn(&mut worker1, "peer1", &video_offer(&[(96, "VP8"), (98, "VP9")])), vec!["VP9".to_string()]);
+        // second peer in other worker prefers H264 by server policy, but converges on room codec
+        assert_eq!(spawn(&mut worker2, "peer2", &video_offer(&[(98, "VP9"), (102, "H264")])), vec!["VP9".to_string()]);
+
+        // codec which is pinned first by other node is applied by the cluster room, new peers follow it
+        let room = ClusterRoomHash::generate(&AppContext::root_app(), &"room".into());
+        room_video_codecs.update(room, "H264");
+        assert_eq!(spawn(&mut worker1, "peer3", &video_offer(&[(98, "VP9"), (102, "H264")])), vec!["H264".to_string()]);
+    }
+
+    #[test]
+    fn opus_quality_per_app() {
+        let opus = OpusConfig {
+            default: OpusParams::default(),
+            apps: HashMap::from([(
+                AppId::from("podcast"),
+                OpusParams {
+                    max_average_bitrate: Some(128_000),
+                    complexity: Some(10),
+                    ..Default::default()
+                },
+            )]),
+        };
+        let mut worker = MediaWorkerWebrtc::new(WebrtcWorkerConfig { opus, ..Default::default() }, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())));
+        let mut spawn = |app: &str| {
+            let (_, answer, _) = worker
+                .spawn(
+                    AppContext { app: app.into() },
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    1,
+                    VariantParams::Whip("room".into(), "peer".into(), None, false),
+                    AUDIO_OFFER,
+                )
+                .expect("Should spawn");
+            answer.lines().filter(|line| line.starts_with("a=fmtp:")).map(|line| line.to_string()).collect::<Vec<_>>()
+        };
+
+        let fmtps = spawn("podcast");
+        assert_eq!(fmtps.len(), 1);
+        assert!(fmtps[0].ends_with(";maxaveragebitrate=128000"));
+        assert!(spawn("other").iter().all(|line| !line.contains("maxaveragebitrate")));
+    }
+
+    #[test]
+    fn opus_clock_rate_with_real_client() {
+        let telephony = OpusParams {
+            clock_rate: 16000,
+            channels: 1,
+            ..Default::default()
+        };
+        let opus = OpusConfig {
+            default: telephony,
+            apps: HashMap::new(),
+        };
+        let mut worker = MediaWorkerWebrtc::new(WebrtcWorkerConfig { opus, ..Default::default() }, Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())));
+        let mut spawn = |client: &mut Rtc| {
+            let mut api = client.sdp_api();
+            api.add_media(MediaKind::Audio, Direction::SendOnly, None, None, None);
+            let (offer, pending) = api.apply().expect("Should create offer");
+            let res = worker.spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &offer.to_sdp_string(),
+            );
+            res.map(|(_, answer, _)| (answer, pending))
+        };
+
+        let mut config = Rtc::builder().clear_codecs();
+        config.codec_config().add_config(
+            111.into(),
+            None,
+            Codec::Opus,
+            Frequency::new(16000).expect("Should be non zero"),
+            Some(1),
+            FormatParams::parse_line("minptime=10;useinbandfec=1"),
+        );
+        let mut client = config.build();
+        let (answer, pending) = spawn(&mut client).expect("Should answer telephony opus");
+        assert!(answer.contains("a=rtpmap:111 opus/16000/1\r\n"));
+        assert!(!answer.contains("opus/48000"));
+        client
+            .sdp_api()
+            .accept_answer(pending, SdpAnswer::from_sdp_string(&answer).expect("Should parse answer"))
+            .expect("Should accept answer");
+        let negotiated = client.codec_config().find(|p| p.spec().codec == Codec::Opus).expect("Should negotiate opus");
+        assert_eq!(negotiated.spec().clock_rate.get(), 16000);
+
+        // browser client only offers opus/48000/2
+        assert!(spawn(&mut Rtc::new()).is_err());
+    }
+
+    /// Deliver packets of worker to the client, the worker must be popped right after each input
+    fn deliver_to_client(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, client: &mut Rtc, server: SocketAddr, now: Instant) {
+        while let Some(out) = worker.pop_output(now) {
+            if let GroupOutput::Net(BackendOutgoing::UdpPacket { to, data, .. }) = out {
+                let recv = Receive::new(Protocol::Udp, server, to, data.deref()).expect("Should parse packet");
+                client.handle_input(str0m::Input::Receive(now, recv)).expect("Should handle packet");
+            }
+        }
+    }
+
+    /// Run client and worker over udp slot 1 for `duration`, events of client are returned
+    fn run_with_client(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, client: &mut Rtc, server: SocketAddr, now: &mut Instant, duration: Duration) -> Vec<str0m::Event> {
+        let mut events = vec![];
+        let end = *now + duration;
+        while *now < end {
+            *now += Duration::from_millis(10);
+            client.handle_input(str0m::Input::Timeout(*now)).expect("Should handle timeout");
+            worker.on_tick(*now);
+            deliver_to_client(worker, client, server, *now);
+            loop {
+                match client.poll_output().expect("Should poll client") {
+                    str0m::Output::Timeout(_) => break,
+                    str0m::Output::Transmit(out) => {
+                        worker.on_event(
+                            *now,
+                            GroupInput::Net(BackendIncoming::UdpPacket {
+                                slot: 1,
+                                from: out.source,
+                                data: out.contents.to_vec().into(),
+                            }),
+                        );
+                        deliver_to_client(worker, client, server, *now);
+                    }
+                    str0m::Output::Event(event) => events.push(event),
+                }
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn dump_live_session_has_answer_and_selected_pair() {
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 20000);
+        let mut now = Instant::now();
+        let mut worker = create_worker(ConsentConfig::default());
+        worker.on_event(
+            now,
+            GroupInput::Net(BackendIncoming::UdpListenResult {
+                bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                result: Ok((server, 1)),
+            }),
+        );
+        count_outputs(&mut worker, now);
+
+        let mut client = Rtc::new();
+        client.add_local_candidate(Candidate::host(client_addr, Protocol::Udp).expect("Should create candidate"));
+        let mut api = client.sdp_api();
+        api.add_media(MediaKind::Audio, Direction::SendOnly, None, None, None);
+        let (offer, pending) = api.apply().expect("Should create offer");
+
+        let (_, answer, index) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &offer.to_sdp_string(),
+            )
+            .expect("Should spawn");
+        deliver_to_client(&mut worker, &mut client, server, now);
+        client
+            .sdp_api()
+            .accept_answer(pending, SdpAnswer::from_sdp_string(&answer).expect("Should parse answer"))
+            .expect("Should accept answer");
+
+        run_with_client(&mut worker, &mut client, server, &mut now, Duration::from_secs(2));
+        assert!(client.is_connected());
+
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::Dump(1)));
+        let dump = std::iter::from_fn(|| worker.pop_output(now))
+            .find_map(|out| match out {
+                GroupOutput::Ext(_, ExtOut::Dump(1, res)) => Some(res),
+                _ => None,
+            })
+            .expect("Should reply dump")
+            .expect("Should dump live session");
+
+        let pwd = answer.lines().find_map(|l| l.strip_prefix("a=ice-pwd:")).expect("Should have ice-pwd");
+        assert_eq!(dump.answer, redact_sdp(&answer));
+        assert!(dump.answer.contains("a=candidate:"));
+        assert!(!dump.answer.contains(pwd));
+        assert_eq!(dump.selected_pair, Some(format!("{server} -> {client_addr} (host)")));
+        assert_eq!(dump.transport_state, "connected");
+        assert!(dump.codecs.iter().any(|c| c.eq_ignore_ascii_case("opus")));
+
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index + 1), ExtIn::Dump(2)));
+        assert!(std::iter::from_fn(|| worker.pop_output(now)).any(|out| matches!(out, GroupOutput::Ext(_, ExtOut::Dump(2, Err(_))))));
+    }
+
+    /// Server is the DTLS server and only allows the AES-GCM profile, a real client offers more and the ServerHello
+    /// which is sent must select the allowed one
+    #[test]
+    fn dtls_suites_negotiated_with_real_client() {
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 20000);
+        let mut now = Instant::now();
+        let policy = DtlsPolicy {
+            setup: DtlsSetup::Passive,
+            suites: DtlsSuites::new(&[SrtpProfile::AeadAes128Gcm], &[DtlsCipher::EcdheEcdsaAes128GcmSha256, DtlsCipher::EcdheRsaAes128GcmSha256]),
+            ..Default::default()
+        };
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                dtls_policy: policy,
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        worker.on_event(
+            now,
+            GroupInput::Net(BackendIncoming::UdpListenResult {
+                bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                result: Ok((server, 1)),
+            }),
+        );
+        count_outputs(&mut worker, now);
+
+        let mut client = Rtc::new();
+        client.add_local_candidate(Candidate::host(client_addr, Protocol::Udp).expect("Should create candidate"));
+        let mut api = client.sdp_api();
+        api.add_media(MediaKind::Audio, Direction::SendOnly, None, None, None);
+        let (offer, pending) = api.apply().expect("Should create offer");
+        let (_, answer, _) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &offer.to_sdp_string(),
+            )
+            .expect("Should spawn");
+        client
+            .sdp_api()
+            .accept_answer(pending, SdpAnswer::from_sdp_string(&answer).expect("Should parse answer"))
+            .expect("Should accept answer");
+
+        // packets of worker are inspected on the way to the client with the same policy
+        let mut hellos = HelloReassembly::default();
+        let mut negotiated = None;
+        let end = now + Duration::from_secs(3);
+        while now < end && !client.is_connected() {
+            now += Duration::from_millis(10);
+            client.handle_input(str0m::Input::Timeout(now)).expect("Should handle timeout");
+            worker.on_tick(now);
+            loop {
+                while let Some(out) = worker.pop_output(now) {
+                    if let GroupOutput::Net(BackendOutgoing::UdpPacket { to, data, .. }) = out {
+                        if let Some(suites) = policy.check_suites(&mut hellos, &data).expect("Should send allowed hello") {
+                            negotiated = Some(suites);
+                        }
+                        let recv = Receive::new(Protocol::Udp, server, to, data.deref()).expect("Should parse packet");
+                        client.handle_input(str0m::Input::Receive(now, recv)).expect("Should handle packet");
+                    }
+                }
+                match client.poll_output().expect("Should poll client") {
+                    str0m::Output::Timeout(_) => break,
+                    str0m::Output::Transmit(out) => {
+                        worker.on_event(
+                            now,
+                            GroupInput::Net(BackendIncoming::UdpPacket {
+                                slot: 1,
+                                from: out.source,
+                                data: out.contents.to_vec().into(),
+                            }),
+                        );
+                    }
+                    str0m::Output::Event(_) => {}
+                }
+            }
+        }
+
+        assert!(client.is_connected());
+        let negotiated = negotiated.expect("Should send ServerHello");
+        assert_eq!(negotiated.profile, Some(0x0007));
+        assert!([0xc02b, 0xc02f].contains(&negotiated.cipher), "{negotiated}");
+    }
+
+    /// Server runs full ICE (not ice-lite), so both agents send checks. After the answer the client is forced to
+    /// controlled, same as the server, and only the tie-breaker resolution lets one of them nominate. The client is a
+    /// well-behaved agent which resolves the conflict from the other side with the same rule.
+    #[test]
+    fn ice_role_conflict_resolved_with_real_client() {
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10000);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 20000);
+        let mut now = Instant::now();
+        let mut worker = create_worker(ConsentConfig::default());
+        worker.on_event(
+            now,
+            GroupInput::Net(BackendIncoming::UdpListenResult {
+                bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                result: Ok((server, 1)),
+            }),
+        );
+        count_outputs(&mut worker, now);
+
+        let mut client = Rtc::new();
+        client.add_local_candidate(Candidate::host(client_addr, Protocol::Udp).expect("Should create candidate"));
+        let mut api = client.sdp_api();
+        api.add_media(MediaKind::Audio, Direction::SendOnly, None, None, None);
+        let (offer, pending) = api.apply().expect("Should create offer");
+        let (ice_lite, answer, _index) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &offer.to_sdp_string(),
+            )
+            .expect("Should spawn");
+        assert!(!ice_lite);
+        assert!(!answer.contains("a=ice-lite"));
+        deliver_to_client(&mut worker, &mut client, server, now);
+        client
+            .sdp_api()
+            .accept_answer(pending, SdpAnswer::from_sdp_string(&answer).expect("Should parse answer"))
+            .expect("Should accept answer");
+        client.direct_api().set_ice_controlling(false);
+
+        let mut client_role = IceRoleResolver::default();
+        let end = now + Duration::from_secs(3);
+        while now < end && !client.is_connected() {
+            now += Duration::from_millis(10);
+            client.handle_input(str0m::Input::Timeout(now)).expect("Should handle timeout");
+            worker.on_tick(now);
+            loop {
+                while let Some(out) = worker.pop_output(now) {
+                    if let GroupOutput::Net(BackendOutgoing::UdpPacket { to, data, .. }) = out {
+                        if let Some(role) = client_role.on_remote_packet(&data) {
+                            client.direct_api().set_ice_controlling(role == IceRole::Controlling);
+                        }
+                        let recv = Receive::new(Protocol::Udp, server, to, data.deref()).expect("Should parse packet");
+                        client.handle_input(str0m::Input::Receive(now, recv)).expect("Should handle packet");
+                    }
+                }
+                match client.poll_output().expect("Should poll client") {
+                    str0m::Output::Timeout(_) => break,
+                    str0m::Output::Transmit(out) => {
+                        client_role.on_local_packet(&out.contents);
+                        worker.on_event(
+                            now,
+                            GroupInput::Net(BackendIncoming::UdpPacket {
+                                slot: 1,
+                                from: out.source,
+                                data: out.contents.to_vec().into(),
+                            }),
+                        );
+                    }
+                    str0m::Output::Event(_) => {}
+                }
+            }
+        }
+
+        // both agents were controlled, a check of the other side was seen as a conflict and the connection proceeded
+        assert!(client.is_connected());
+        assert!(client_role.conflicts() > 0);
+    }
+
+    /// Deliver ICE-TCP packets of worker to the client, same as [`deliver_to_client`] after unframing
+    fn deliver_tcp_to_client(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, client: &mut Rtc, now: Instant) {
+        while let Some(out) = worker.pop_output(now) {
+            if let GroupOutput::IceTcp(pkt) = out {
+                let recv = Receive::new(Protocol::Tcp, pkt.local, pkt.remote, &pkt.data).expect("Should parse packet");
+                client.handle_input(str0m::Input::Receive(now, recv)).expect("Should handle packet");
+            }
+        }
+    }
+
+    #[test]
+    fn ice_tcp_candidate_and_connect() {
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10443);
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 20000);
+        let mut now = Instant::now();
+        // no udp socket, as in a network which blocks udp
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                ice_tcp_addrs: vec![server],
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        count_outputs(&mut worker, now);
+
+        let mut client = Rtc::new();
+        client.add_local_candidate(Candidate::host(client_addr, Protocol::Tcp).expect("Should create candidate"));
+        let mut api = client.sdp_api();
+        api.add_media(MediaKind::Audio, Direction::SendOnly, None, None, None);
+        let (offer, pending) = api.apply().expect("Should create offer");
+
+        let (_, answer, _) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer".into(), None, false),
+                &offer.to_sdp_string(),
+            )
+            .expect("Should spawn");
+        let tcp_candidate = format!("{} {} typ host", server.ip(), server.port());
+        assert!(answer.lines().any(|line| line.starts_with("a=candidate:") && line.contains(" tcp ") && line.contains(&tcp_candidate)));
+        deliver_tcp_to_client(&mut worker, &mut client, now);
+        client
+            .sdp_api()
+            .accept_answer(pending, SdpAnswer::from_sdp_string(&answer).expect("Should parse answer"))
+            .expect("Should accept answer");
+
+        for _ in 0..200 {
+            now += Duration::from_millis(10);
+            client.handle_input(str0m::Input::Timeout(now)).expect("Should handle timeout");
+            worker.on_tick(now);
+            deliver_tcp_to_client(&mut worker, &mut client, now);
+            loop {
+                match client.poll_output().expect("Should poll client") {
+                    str0m::Output::Timeout(_) => break,
+                    str0m::Output::Transmit(out) => {
+                        assert_eq!(out.proto, Protocol::Tcp);
+                        worker.on_event(
+                            now,
+                            GroupInput::IceTcp(IceTcpPacket {
+                                local: out.destination,
+                                remote: out.source,
+                                data: out.contents.to_vec(),
+                            }),
+                        );
+                        deliver_tcp_to_client(&mut worker, &mut client, now);
+                    }
+                    str0m::Output::Event(_) => {}
+                }
+            }
+        }
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn stuck_connecting_session_reaped() {
+        let reaped_count = || get_all_counts().get(std::any::type_name::<ReapedSession>()).copied().unwrap_or(0);
+        // consent timeout is longer than reaper threshold, so only the reaper can remove the session
+        let consent = ConsentConfig {
+            timeout: Duration::from_secs(3600),
+            established_timeout: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let reaper = ReaperConfig {
+            interval: Duration::from_millis(500),
+            connecting: Some(Duration::from_secs(2)),
+            reconnecting: None,
+        };
+        let mut worker = MediaWorkerWebrtc::new(
+            WebrtcWorkerConfig {
+                consent,
+                reaper,
+                ..Default::default()
+            },
+            Arc::new(MediaEdgeSecureJwt::from(b"secret".as_slice())),
+        );
+        let now = Instant::now();
+        let (_, _, index) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whip("room".into(), "peer1".into(), None, false),
+                AUDIO_OFFER,
+            )
+            .expect("Should spawn");
+        worker.on_tick(now);
+        count_outputs(&mut worker, now);
+        assert_eq!(worker.connecting(), 1);
+
+        // not reaped before threshold
+        let now = now + Duration::from_millis(1500);
+        worker.on_tick(now);
+        count_outputs(&mut worker, now);
+        assert_eq!(worker.tasks(), 1);
+
+        let before = reaped_count();
+        let now = now + Duration::from_millis(500);
+        worker.on_tick(now);
+        let mut outputs = vec![];
+        while let Some(out) = worker.pop_output(now) {
+            outputs.push(out);
+        }
+        let reaped = outputs.iter().any(|out| {
+            matches!(out, GroupOutput::PeerEvent(session, _, 1, _, peer_event::Event::Disconnected(disconnected))
+                if session.index() == index && disconnected.reason == Reason::Reaped as i32 && disconnected.duration_ms == 2000)
+        });
+        assert!(reaped, "Should report reaped session");
+        // session didn't join the room before connected, so it doesn't leave
+        assert!(!outputs.iter().any(|out| matches!(out, GroupOutput::Cluster(..))));
+        assert!(reaped_count() > before);
+        assert_eq!(worker.tasks(), 0);
+        assert_eq!(worker.connecting(), 0);
+    }
+
+    /// Pop all outputs and return events results, error is returned as error code
+    fn events_results(worker: &mut MediaWorkerWebrtc<MediaEdgeSecureJwt>, now: Instant) -> Vec<(u64, Result<usize, u32>)> {
+        let mut results = vec![];
+        while let Some(out) = worker.pop_output(now) {
+            if let GroupOutput::Ext(_, ExtOut::Events(req_id, res)) = out {
+                results.push((req_id, res.map(|events| events.len()).map_err(|e| e.code)));
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn whep_events_request_held_until_deadline() {
+        let mut worker = create_worker(ConsentConfig::default());
+        let now = Instant::now();
+        let offer = AUDIO_OFFER.replace("a=sendonly\r\n", "a=recvonly\r\n");
+        let (_, _, index) = worker
+            .spawn(
+                AppContext::root_app(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                1,
+                VariantParams::Whep("room".into(), "peer".into(), None),
+                &offer,
+            )
+            .expect("Should spawn");
+        count_outputs(&mut worker, now);
+
+        // first request of a stream is answered at once with server candidates
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::Events(1, true)));
+        let results = events_results(&mut worker, now);
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], (1, Ok(count)) if count > 0));
+
+        // next request has nothing to take, so it is held instead of answered empty
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::Events(2, false)));
+        assert_eq!(events_results(&mut worker, now), vec![]);
+        worker.on_tick(now + Duration::from_secs(1));
+        assert_eq!(events_results(&mut worker, now + Duration::from_secs(1)), vec![]);
+
+        // a newer request replaces the held one, which is answered empty
+        worker.on_event(now, GroupInput::Ext(WebrtcSession(index), ExtIn::Events(3, false)));
+        assert_eq!(events_results(&mut worker, now), vec![(2, Ok(0))]);
+
+        let deadline = now + EVENTS_LONG_POLL;
+        worker.on_tick(deadline);
+        assert_eq!(events_results(&mut worker, deadline), vec![(3, Ok(0))]);
+    }
+}